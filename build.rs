@@ -0,0 +1,16 @@
+fn main() {
+    // The code of the gRPC service is generated from its protobuf definition, with the protoc that comes with the
+    // build dependencies rather than one installed on the machine.
+    #[cfg(feature = "grpc")]
+    {
+        let protoc =
+            protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored for this platform");
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(protoc);
+        tonic_prost_build::configure()
+            .build_client(true)
+            .build_server(true)
+            .compile_with_config(config, &["proto/payments_engine.proto"], &["proto"])
+            .expect("The protobuf definition of the gRPC service compiles");
+    }
+}
@@ -0,0 +1,248 @@
+//! A small payment intake service built on the library API of the engine, to show how it's embedded in another
+//! process.
+//!
+//! Payments are submitted with `POST /payments` and get a transaction id from an `IdAllocator`, or are submitted with
+//! their own id with `PUT /payments/{id}`. A payment is a deposit to the account of the client: it's checked with a
+//! `TransactionBuilder` and submitted to a `ShardedEngine`, which applies it to the account. The payments are also
+//! kept in a `TransactionCache`, which holds the recent payments in memory and moves the older ones to its SQLite
+//! backing store, so they can be looked up with `GET /payments/{id}`. The balances of a client are read from the
+//! engine with `GET /accounts/{client}`. The service stops gracefully on Ctrl-C: the requests in flight are finished,
+//! the engine applies the submitted payments and the allocated ids are reported.
+//!
+//! ```text
+//! cargo run --example axum_service -- 127.0.0.1:8080 /tmp/intake-ids
+//! curl -X POST localhost:8080/payments -H 'content-type: application/json' -d '{"client":1,"amount":"12.5"}'
+//! curl localhost:8080/payments/4000000000
+//! curl localhost:8080/accounts/1
+//! ```
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use payments_engine::{
+    engine::{AccountBalance, EngineError, ShardedEngine},
+    id_allocator::{IdAllocator, IdAllocatorError, IdRange},
+    transaction::{NewTransaction, TransactionBuilder, TransactionError, TransactionType},
+    transactions_cache::{CacheError, SqliteKvStore, TransactionCache},
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+// Number of payments kept in memory before the least recently used ones are moved to the backing store.
+const IN_MEMORY: usize = 1024;
+// Number of workers of the engine.
+const WORKERS: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Payment {
+    client: u16,
+    /// Kept as the decimal string it was submitted as.
+    amount: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Submitted {
+    id: u32,
+    #[serde(flatten)]
+    payment: Payment,
+}
+
+struct Intake {
+    ids: IdAllocator,
+    payments: TransactionCache<SqliteKvStore, u32, Payment, IN_MEMORY>,
+}
+
+#[derive(Clone)]
+struct Service {
+    intake: Arc<Mutex<Intake>>,
+    engine: ShardedEngine,
+}
+
+enum ApiError {
+    Duplicate(u32),
+    NotFound(u32),
+    InvalidAmount(String),
+    Invalid(TransactionError),
+    Ids(IdAllocatorError),
+    Store(CacheError),
+    Engine(EngineError),
+}
+
+impl From<IdAllocatorError> for ApiError {
+    fn from(err: IdAllocatorError) -> Self {
+        ApiError::Ids(err)
+    }
+}
+
+impl From<TransactionError> for ApiError {
+    fn from(err: TransactionError) -> Self {
+        ApiError::Invalid(err)
+    }
+}
+
+impl From<CacheError> for ApiError {
+    fn from(err: CacheError) -> Self {
+        ApiError::Store(err)
+    }
+}
+
+impl From<EngineError> for ApiError {
+    fn from(err: EngineError) -> Self {
+        ApiError::Engine(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::Duplicate(id) => (
+                StatusCode::CONFLICT,
+                format!("Payment {} already exists.", id),
+            )
+                .into_response(),
+            ApiError::NotFound(id) => {
+                (StatusCode::NOT_FOUND, format!("No payment {}.", id)).into_response()
+            }
+            ApiError::InvalidAmount(amount) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("'{}' is not an amount.", amount),
+            )
+                .into_response(),
+            ApiError::Invalid(err) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()).into_response()
+            }
+            ApiError::Ids(err) => {
+                (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response()
+            }
+            ApiError::Store(err) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            }
+            ApiError::Engine(err) => {
+                (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response()
+            }
+        }
+    }
+}
+
+// The cache and the allocator are synchronous, so they're used behind a plain mutex that is never held across an
+// await: the payment is stored under the lock and submitted to the engine once it's released.
+fn store(intake: &mut Intake, id: u32, payment: &Payment) -> Result<NewTransaction, ApiError> {
+    let amount: Decimal = payment
+        .amount
+        .parse()
+        .map_err(|_| ApiError::InvalidAmount(payment.amount.clone()))?;
+    let deposit = TransactionBuilder::new(TransactionType::Deposit, payment.client, id)
+        .with_amount(amount)
+        .build()?;
+    if intake.payments.contains_key(&id)? {
+        return Err(ApiError::Duplicate(id));
+    }
+    intake.payments.put(id, payment.clone())?;
+    Ok(deposit)
+}
+
+async fn submit(
+    State(service): State<Service>,
+    Json(payment): Json<Payment>,
+) -> Result<(StatusCode, Json<Submitted>), ApiError> {
+    let (id, deposit) = {
+        let mut intake = service.intake.lock().unwrap();
+        let id = intake.ids.allocate()?;
+        (id, store(&mut intake, id, &payment)?)
+    };
+    service.engine.submit(deposit).await?;
+    Ok((StatusCode::CREATED, Json(Submitted { id, payment })))
+}
+
+async fn submit_with_id(
+    State(service): State<Service>,
+    Path(id): Path<u32>,
+    Json(payment): Json<Payment>,
+) -> Result<(StatusCode, Json<Submitted>), ApiError> {
+    let deposit = {
+        let mut intake = service.intake.lock().unwrap();
+        // The allocated ids are reserved for this service.
+        if intake.ids.range().contains(id) {
+            return Err(ApiError::Duplicate(id));
+        }
+        store(&mut intake, id, &payment)?
+    };
+    service.engine.submit(deposit).await?;
+    Ok((StatusCode::CREATED, Json(Submitted { id, payment })))
+}
+
+async fn lookup(
+    State(service): State<Service>,
+    Path(id): Path<u32>,
+) -> Result<Json<Submitted>, ApiError> {
+    let mut intake = service.intake.lock().unwrap();
+    let payment = intake
+        .payments
+        .get_mut(&id)?
+        .cloned()
+        .ok_or(ApiError::NotFound(id))?;
+    Ok(Json(Submitted { id, payment }))
+}
+
+// The balances once the payments submitted before the request are applied. A client without payments has no account.
+async fn balances(
+    State(service): State<Service>,
+    Path(client): Path<u16>,
+) -> Result<Json<Vec<AccountBalance>>, ApiError> {
+    Ok(Json(service.engine.query(client).await?))
+}
+
+fn router(service: Service) -> Router {
+    Router::new()
+        .route("/payments", post(submit))
+        .route("/payments/{id}", get(lookup).put(submit_with_id))
+        .route("/accounts/{client}", get(balances))
+        .with_state(service)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let address: SocketAddr = args
+        .next()
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string())
+        .parse()?;
+    // Without a file the ids start over on every run.
+    let ids = match args.next() {
+        Some(path) => IdAllocator::open(path, IdRange::default())?,
+        None => IdAllocator::new(IdRange::default()),
+    };
+    let service = Service {
+        intake: Arc::new(Mutex::new(Intake {
+            ids,
+            payments: TransactionCache::new()?,
+        })),
+        engine: ShardedEngine::start(WORKERS),
+    };
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    axum::serve(listener, router(service.clone()))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    service.engine.shutdown().await?;
+    let intake = service.intake.lock().unwrap();
+    eprintln!(
+        "Stopped with {} ids of {} left.",
+        intake.ids.remaining(),
+        intake.ids.range()
+    );
+    Ok(())
+}
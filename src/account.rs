@@ -1,11 +1,26 @@
-use serde::{Deserialize, Serialize, ser::SerializeStruct};
-
-use payments_engine::transactions_cache::{self, SqliteKvStore, TransactionCache};
-
-use crate::transaction_types::{Amount, ClientId, TransactionId};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    path::Path,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    dispute::{DisputeEvent, DisputeState, DisputeStateMachine},
+    dispute_policy::{DisputePolicy, DisputeRule},
+    transaction_types::{
+        AccountName, Amount, ClientId, DisputeSource, EscrowParty, TransactionId, TransactionType,
+    },
+    transactions_cache::{self, BackingStore, SqliteKvStore, TransactionCache},
+};
 use thiserror::Error;
 
-// A error describing why the account operation failed.
+// A error describing why the account operation failed. Most of them reject the transaction for a business reason, and
+// the transaction would be rejected again if it was sent again. `Internal` errors are failures of the engine rather
+// than of the transaction, and the transaction can be processed once they are fixed.
 #[derive(Error, Debug)]
 pub(crate) enum AccountError {
     #[error("Account is locked. No transaction can be performed.")]
@@ -14,10 +29,18 @@ pub(crate) enum AccountError {
     InsufficientFunds,
     #[error("Cannot deposit because the limit was reached.")]
     DepositLimitReached,
+    #[error("Account total would exceed the maximum balance of {0}.")]
+    BalanceLimitExceeded(Amount),
+    #[error("Account is in withdrawal-only mode. Deposits are suspended.")]
+    DepositsSuspended,
     #[error("There is no transaction matching this id.")]
     TransactionMissing,
+    #[error("There is no account for this client.")]
+    UnknownClient,
     #[error("This transaction can no longer be disputed.")]
     TransactionCannotBeDisputed,
+    #[error("Transaction was applied more than {0} days ago and can no longer be disputed.")]
+    DisputeWindowExpired(i64),
     #[error("Withdrawal dispute is not implemented yet.")]
     WithdrawalDisputeNotSupported,
     #[error("Transaction is not disputed.")]
@@ -26,25 +49,65 @@ pub(crate) enum AccountError {
     DisputeAlreadyResolved,
     #[error("Dispute was already resolved through chargeback.")]
     TransactionWasChargedBack,
+    #[error("Transaction was not charged back.")]
+    TransactionNotChargedBack,
+    #[error("Chargeback was already reversed.")]
+    ChargebackAlreadyReversed,
     #[error("This transaction already exists.")]
     DuplicateTransaction,
+    #[error("Transaction id {0} was already used by a transaction of a previous run.")]
+    TransactionIdReused(TransactionId),
     #[error("Specified ammount is invalid.")]
     InvalidAmount,
-    #[error("Transaction cache error: {0}")]
-    TransactionCache(#[from] transactions_cache::CacheError),
+    #[error(
+        "Dispute state changed since it was read: expected version {expected}, current version {current}."
+    )]
+    StaleDisputeState { expected: u32, current: u32 },
+    #[error("Account balance would be out of the supported range.")]
+    BalanceOutOfRange,
+    #[error("A move needs a destination sub-account that is different from the source.")]
+    InvalidMove,
+    #[error("Transaction is not an escrow hold.")]
+    TransactionNotInEscrow,
+    #[error("Escrow was already released.")]
+    EscrowAlreadyReleased,
+    #[error("An escrow release needs the party that receives the funds.")]
+    EscrowPartyRequired,
+    #[error("Disputes, resolves and chargebacks need a source.")]
+    DisputeSourceRequired,
+    #[error("Dispute was opened by {opened} and cannot be closed by {closing}.")]
+    DisputeSourceMismatch {
+        opened: DisputeSource,
+        closing: DisputeSource,
+    },
+    #[error(
+        "Too many disputes of transactions that are not in memory: at most {0} per minute are loaded from disk for a client."
+    )]
+    DiskLookupThrottled(usize),
+    #[error(transparent)]
+    Internal(#[from] InternalError),
 }
 
-// Transaction dispute state.
-#[derive(Debug, Serialize, Deserialize)]
-enum DisputeState {
-    // This transaction was never disputed.
-    None,
-    // There was a dispute initiated for this transaction.
-    DisputeInitiated,
-    // The dispute was resolved in favor of the merchant.
-    DisputeResolved,
-    // The dispute was resolved through a charge-back.
-    ChargedBack,
+impl AccountError {
+    /// Whether the error is a failure of the engine rather than a rejection of the transaction.
+    pub(crate) fn is_internal(&self) -> bool {
+        matches!(self, AccountError::Internal(_))
+    }
+}
+
+impl From<transactions_cache::CacheError> for AccountError {
+    fn from(err: transactions_cache::CacheError) -> Self {
+        AccountError::Internal(err.into())
+    }
+}
+
+/// A failure of the engine that prevented a transaction from being processed. The transaction can be retried.
+#[derive(Error, Debug)]
+pub(crate) enum InternalError {
+    #[error("Processing is paused by an operator.")]
+    ProcessingPaused,
+    #[error("Transaction cache error: {0}")]
+    TransactionCache(#[from] transactions_cache::CacheError),
 }
 
 // The type of processed transaction.
@@ -52,6 +115,163 @@ enum DisputeState {
 enum FundingType {
     Deposit,
     Withdrawal,
+    // Funds moved out to another sub-account of the client. Moves are recorded only by the source account.
+    Move,
+    // Funds put aside in escrow.
+    Escrow(EscrowState),
+}
+
+impl FundingType {
+    // The dispute transitions of the transactions of this type under a dispute policy.
+    fn disputes(&self, policy: &DisputePolicy) -> DisputeStateMachine {
+        match self {
+            FundingType::Deposit => DisputeStateMachine::DEPOSIT.with_rule(&policy.deposit),
+            FundingType::Withdrawal => {
+                DisputeStateMachine::WITHDRAWAL.with_rule(&policy.withdrawal)
+            }
+            FundingType::Move | FundingType::Escrow(_) => DisputeStateMachine::INTERNAL,
+        }
+    }
+
+    // The dispute rule of the transactions of this type, if they are covered by the dispute policy.
+    fn rule<'a>(&self, policy: &'a DisputePolicy) -> Option<&'a DisputeRule> {
+        match self {
+            FundingType::Deposit => Some(&policy.deposit),
+            FundingType::Withdrawal => Some(&policy.withdrawal),
+            FundingType::Move | FundingType::Escrow(_) => None,
+        }
+    }
+
+    fn transaction_type(&self) -> TransactionType {
+        match self {
+            FundingType::Deposit => TransactionType::Deposit,
+            FundingType::Withdrawal => TransactionType::Withdrawal,
+            FundingType::Move => TransactionType::Move,
+            FundingType::Escrow(_) => TransactionType::EscrowHold,
+        }
+    }
+}
+
+// The state of an escrow hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum EscrowState {
+    Held,
+    Released(EscrowParty),
+}
+
+// Funds of deposits that are withheld until a given time: the pending funds of the deposits that didn't settle yet, or
+// the rolling reserve. The funds are released in the order they were withheld.
+#[derive(Debug, Default)]
+struct Withheld {
+    amount: Amount,
+    deposits: VecDeque<WithheldDeposit>,
+}
+
+#[derive(Debug)]
+struct WithheldDeposit {
+    // None for the funds that were already withheld when the account was loaded from a snapshot, whose deposits are
+    // not known.
+    transaction_id: Option<TransactionId>,
+    amount: Amount,
+    until: DateTime<Utc>,
+}
+
+// The withheld funds of a deposit that a dispute takes, and where they are in the queue.
+type Taken = Option<(usize, Amount)>;
+
+impl Withheld {
+    // The funds withheld by an account of a snapshot. They are not released before `schedule_loaded` runs.
+    fn loaded(amount: Amount) -> Self {
+        let mut withheld = Self::default();
+        withheld.push(
+            amount,
+            WithheldDeposit {
+                transaction_id: None,
+                amount,
+                until: DateTime::<Utc>::MAX_UTC,
+            },
+        );
+        withheld
+    }
+
+    // Release the funds withheld by an account of a snapshot at the given time.
+    fn schedule_loaded(&mut self, until: DateTime<Utc>) {
+        for deposit in &mut self.deposits {
+            if deposit.transaction_id.is_none() {
+                deposit.until = until;
+            }
+        }
+    }
+
+    // The withheld amount with more funds.
+    fn added(&self, amount: Amount) -> Result<Amount, AccountError> {
+        self.amount
+            .checked_add(amount)
+            .ok_or(AccountError::BalanceOutOfRange)
+    }
+
+    // Record the funds of a deposit that were withheld, once the new withheld amount was checked.
+    fn push(&mut self, withheld: Amount, deposit: WithheldDeposit) {
+        self.amount = withheld;
+        if !deposit.amount.is_zero() {
+            self.deposits.push_back(deposit);
+        }
+    }
+
+    // The withheld funds of a deposit that a dispute of `amount` takes.
+    fn taken(&self, transaction_id: TransactionId, amount: Amount) -> Taken {
+        self.deposits
+            .iter()
+            .position(|deposit| deposit.transaction_id == Some(transaction_id))
+            .map(|index| (index, amount.min(self.deposits[index].amount)))
+    }
+
+    // The withheld amount without the funds a dispute takes.
+    fn without(&self, taken: Taken) -> Result<Amount, AccountError> {
+        match taken {
+            Some((_, amount)) => self
+                .amount
+                .checked_sub(amount)
+                .ok_or(AccountError::BalanceOutOfRange),
+            None => Ok(self.amount),
+        }
+    }
+
+    // Take the funds of a dispute, once the new withheld amount was checked.
+    fn take(&mut self, withheld: Amount, taken: Taken) {
+        self.amount = withheld;
+        if let Some((index, amount)) = taken {
+            let deposit = &mut self.deposits[index];
+            deposit.amount = deposit.amount.checked_sub(amount).unwrap_or_default();
+            if deposit.amount.is_zero() {
+                self.deposits.remove(index);
+            }
+        }
+    }
+
+    // The withheld amount without the first funds, if they are due.
+    fn due(&self, now: DateTime<Utc>) -> Option<Amount> {
+        let deposit = self
+            .deposits
+            .front()
+            .filter(|deposit| deposit.until <= now)?;
+        self.amount.checked_sub(deposit.amount)
+    }
+
+    // Release the first funds, once the new withheld amount was checked.
+    fn release(&mut self, withheld: Amount) {
+        self.amount = withheld;
+        self.deposits.pop_front();
+    }
+}
+
+/// The share of every deposit that is withheld in a rolling reserve, e.g. for high-risk clients, and how long each
+/// share is withheld.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RollingReserve {
+    /// The percentage of the deposits that is withheld, between 0 and 100.
+    pub(crate) percent: Decimal,
+    pub(crate) period: TimeDelta,
 }
 
 // An already processed transaction.
@@ -60,6 +280,19 @@ pub(crate) struct FundingLogEntry {
     funding_type: FundingType,
     amount: Amount,
     state: DisputeState,
+    // Incremented on every dispute state change. Used to detect concurrent changes to the dispute.
+    version: u32,
+    // Who opened the dispute, if it was given.
+    dispute_source: Option<DisputeSource>,
+    // The amount held by the last dispute, which is less than the amount of the transaction for partial disputes.
+    disputed: Option<Amount>,
+    // The position of the transaction in the log of the account, which orders the transactions of the account whatever
+    // order the store keeps them in.
+    seq: u64,
+    // When the transaction was added to the log and when it last changed (a dispute or an escrow release), according
+    // to the clock of the account, in milliseconds since the epoch.
+    created_at: i64,
+    updated_at: i64,
 }
 
 impl FundingLogEntry {
@@ -68,6 +301,12 @@ impl FundingLogEntry {
             funding_type: FundingType::Deposit,
             amount,
             state: DisputeState::None,
+            version: 0,
+            dispute_source: None,
+            disputed: None,
+            seq: 0,
+            created_at: 0,
+            updated_at: 0,
         }
     }
 
@@ -76,6 +315,40 @@ impl FundingLogEntry {
             funding_type: FundingType::Withdrawal,
             amount,
             state: DisputeState::None,
+            version: 0,
+            dispute_source: None,
+            disputed: None,
+            seq: 0,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn new_escrow_hold(amount: Amount) -> Self {
+        Self {
+            funding_type: FundingType::Escrow(EscrowState::Held),
+            amount,
+            state: DisputeState::None,
+            version: 0,
+            dispute_source: None,
+            disputed: None,
+            seq: 0,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn new_move(amount: Amount) -> Self {
+        Self {
+            funding_type: FundingType::Move,
+            amount,
+            state: DisputeState::None,
+            version: 0,
+            dispute_source: None,
+            disputed: None,
+            seq: 0,
+            created_at: 0,
+            updated_at: 0,
         }
     }
 
@@ -83,73 +356,532 @@ impl FundingLogEntry {
         self.amount
     }
 
-    // A transaction can be disputed only if it was not already disputed before.
-    fn can_be_disputed(&self) -> bool {
-        match self.state {
-            DisputeState::None => true,
-            DisputeState::DisputeResolved
-            | DisputeState::DisputeInitiated
-            | DisputeState::ChargedBack => false,
+    fn disputed_amount(&self) -> Amount {
+        self.disputed.unwrap_or(self.amount)
+    }
+
+    /// Set the position of the transaction in the log and the time it was added.
+    pub(crate) fn stamped(mut self, seq: u64, now: DateTime<Utc>) -> Self {
+        self.seq = seq;
+        self.created_at = now.timestamp_millis();
+        self.updated_at = self.created_at;
+        self
+    }
+
+    pub(crate) fn created_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.created_at).unwrap_or_default()
+    }
+
+    pub(crate) fn updated_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.updated_at).unwrap_or_default()
+    }
+
+    // The state the dispute of the transaction would go to on an event.
+    fn transition(
+        &self,
+        event: DisputeEvent,
+        policy: &DisputePolicy,
+    ) -> Result<DisputeState, AccountError> {
+        self.funding_type
+            .disputes(policy)
+            .transition(self.state, event)
+    }
+
+    fn set_state(&mut self, state: DisputeState, now: DateTime<Utc>) {
+        self.state = state;
+        self.version += 1;
+        self.updated_at = now.timestamp_millis();
+    }
+
+    // Whether nothing can change the transaction anymore, except for a new dispute.
+    // Open disputes and escrow holds have to stay in the log until they are settled.
+    fn is_settled(&self) -> bool {
+        !self.state.is_open()
+            && !matches!(self.funding_type, FundingType::Escrow(EscrowState::Held))
+    }
+
+    fn archive(&self, transaction_id: TransactionId) -> ArchivedTransaction {
+        let (kind, released_to) = match self.funding_type {
+            FundingType::Deposit => ("deposit", None),
+            FundingType::Withdrawal => ("withdrawal", None),
+            FundingType::Move => ("move", None),
+            FundingType::Escrow(EscrowState::Held) => ("escrow_hold", None),
+            FundingType::Escrow(EscrowState::Released(party)) => ("escrow_hold", Some(party)),
+        };
+        ArchivedTransaction {
+            transaction_id,
+            kind,
+            amount: self.amount,
+            state: self.state,
+            released_to,
+            seq: self.seq,
+            created_at: self.created_at(),
+            updated_at: self.updated_at(),
         }
     }
+
+    // A dispute can only be closed by whoever opened it, when both sides are known.
+    fn check_dispute_source(&self, closing: Option<DisputeSource>) -> Result<(), AccountError> {
+        match (self.dispute_source, closing) {
+            (Some(opened), Some(closing)) if opened != closing => {
+                Err(AccountError::DisputeSourceMismatch { opened, closing })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The funds of a transaction that a dispute, a resolve or a chargeback applied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DisputedFunds {
+    /// The disputed amount, which is the whole amount of the transaction unless the dispute was partial.
+    pub(crate) amount: Amount,
+    /// The type of the disputed transaction.
+    pub(crate) disputed: TransactionType,
+}
+
+impl From<DisputedFunds> for (Amount, Option<TransactionType>) {
+    fn from(funds: DisputedFunds) -> Self {
+        (funds.amount, Some(funds.disputed))
+    }
 }
 
+/// The dispute state of a transaction together with its version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DisputeStatus {
+    pub(crate) state: DisputeState,
+    pub(crate) version: u32,
+}
+
+/// A transaction that was moved out of the transaction log of an account into the history archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ArchivedTransaction {
+    pub(crate) transaction_id: TransactionId,
+    /// The type of the transaction as named in the input, e.g. `deposit`.
+    pub(crate) kind: &'static str,
+    pub(crate) amount: Amount,
+    pub(crate) state: DisputeState,
+    /// The party that received the funds of a released escrow hold.
+    pub(crate) released_to: Option<EscrowParty>,
+    /// The position of the transaction in the log of its account.
+    pub(crate) seq: u64,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
+
+/// The transactions of an account that fell out of the dispute window, together with a checkpoint of the balances.
 #[derive(Debug)]
-pub(crate) struct Account {
+pub(crate) struct Compaction {
+    /// The transactions to archive, oldest first.
+    pub(crate) transactions: Vec<ArchivedTransaction>,
+    /// The balances after the last applied transaction.
+    pub(crate) checkpoint: AccountSnapshot,
+    pub(crate) last_transaction: TransactionId,
+}
+
+// The order in which the transactions were added to the log of an account. Only kept when the transaction log is
+// compacted: the most recent `window` transactions are kept and the settled transactions older than that are archived.
+#[derive(Debug)]
+struct History {
+    window: usize,
+    recent: VecDeque<TransactionId>,
+    // Transactions older than the window that had to be kept at the last compaction, e.g. because they are disputed.
+    retained: usize,
+    // The ids of the archived transactions, which are still rejected as duplicates.
+    archived: ArchivedIds,
+}
+
+// A set of transaction ids kept as ranges of consecutive ids, so that the archived ids of an account take little memory:
+// a few bytes per range, however many transactions the range covers.
+#[derive(Debug, Default)]
+struct ArchivedIds {
+    // The last id of each range, by its first id. The ranges don't overlap and don't touch.
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl ArchivedIds {
+    fn insert(&mut self, transaction_id: TransactionId) {
+        let id = u32::from(transaction_id);
+        if self.contains(transaction_id) {
+            return;
+        }
+        let start = match self.ranges.range(..id).next_back() {
+            Some((&start, &end)) if end + 1 == id => start,
+            _ => id,
+        };
+        let end = id
+            .checked_add(1)
+            .and_then(|next| self.ranges.remove(&next))
+            .unwrap_or(id);
+        self.ranges.insert(start, end);
+    }
+
+    fn contains(&self, transaction_id: TransactionId) -> bool {
+        let id = u32::from(transaction_id);
+        self.ranges
+            .range(..=id)
+            .next_back()
+            .is_some_and(|(_, &end)| id <= end)
+    }
+}
+
+/// A point in time copy of the account balances.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct AccountSnapshot {
+    pub(crate) client: ClientId,
+    pub(crate) account: AccountName,
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+    pub(crate) escrow: Amount,
+    pub(crate) pending: Amount,
+    pub(crate) reserve: Amount,
+    pub(crate) total: Amount,
+    pub(crate) locked: bool,
+}
+
+/// The operations that are still allowed on a locked account. By default a locked account allows none, which freezes
+/// its funds until an operator steps in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LockedOperations(u8);
+
+impl LockedOperations {
+    pub(crate) fn allowing(operations: impl IntoIterator<Item = TransactionType>) -> Self {
+        Self(
+            operations
+                .into_iter()
+                .fold(0, |allowed, operation| allowed | 1 << operation as u8),
+        )
+    }
+
+    pub(crate) fn allows(&self, operation: TransactionType) -> bool {
+        self.0 & 1 << operation as u8 != 0
+    }
+}
+
+/// Number of logged transactions an account keeps in memory, the older ones are in the backing store. It's a const
+/// generic of the cache, so it's set here rather than by an option; `tune-cache` shows how an input does with others.
+pub(crate) const CACHE_CAPACITY: usize = 128;
+
+// Every operation validates and computes the new state of the account before changing anything,
+// so that an error (e.g. a failure of the transaction store) leaves the account as it was.
+// Balances that would overflow reject the transaction instead of panicking the worker.
+#[derive(Debug)]
+pub(crate) struct Account<S: BackingStore = SqliteKvStore> {
     client_id: ClientId,
+    /// The sub-account of the client
+    name: AccountName,
     /// The total funds that are held for dispute. This should be equal to total - available amounts
     held: Amount,
-    /// The total funds that are available or held. This should be equal to available + held
+    /// The total funds that are held in escrow until they are released to one of the parties
+    escrow: Amount,
+    /// The funds of the deposits that are not settled yet. They count towards the total but are not available
+    pending: Withheld,
+    /// How long after they were applied the deposits settle. They are available right away if not set
+    settlement_delay: Option<TimeDelta>,
+    /// The share of the deposits that is withheld in the rolling reserve. It counts towards the total but is not
+    /// available
+    reserve: Withheld,
+    /// How much of the deposits is withheld in the rolling reserve, if any
+    rolling_reserve: Option<RollingReserve>,
+    /// The total funds that are available or held. This should be equal to available + held + escrow + pending +
+    /// reserve
     total: Amount,
+    /// The total funds that are available. Kept up to date with every change of held or total
+    available: Amount,
     /// Whether the account is locked. An account is locked if a charge back occurs
     locked: bool,
+    /// The operations that are still allowed once the account is locked
+    locked_operations: LockedOperations,
+    /// Which transactions can be disputed and how
+    dispute_policy: DisputePolicy,
+    /// Whether deposits are suspended, e.g. because the chargeback rate of the account is too high
+    withdrawal_only: bool,
+    /// The chargebacks that locked the account since it was created or loaded and were not reversed
+    chargebacks: u32,
+    /// Whether the account was already locked when it was loaded, for a reason the engine doesn't know
+    locked_on_load: bool,
+    /// When the last chargeback was applied, according to the clock of the account
+    last_chargeback: Option<DateTime<Utc>>,
     /// A log of transactions that were processed for this account.
-    transactions: TransactionCache<SqliteKvStore, TransactionId, FundingLogEntry, 128>, //HashMap<TransactionId, FundingLogEntry>,
-}
-
-// Custom serializer for the Account structure to be written to CSV.
-// Mainly needed because we don't store the available field which is calculated on the fly.
-// We also skip serializing the transaction log.
-impl Serialize for Account {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let mut account = serializer.serialize_struct("Account", 5)?;
-        account.serialize_field("client", &self.client_id)?;
-        account.serialize_field("available", &self.available())?;
-        account.serialize_field("held", &self.held)?;
-        account.serialize_field("total", &self.total)?;
-        account.serialize_field("locked", &self.locked)?;
-        account.end()
-    }
+    transactions: TransactionCache<S, TransactionId, FundingLogEntry, CACHE_CAPACITY>, //HashMap<TransactionId, FundingLogEntry>,
+    /// The order of the logged transactions, if the log is compacted
+    history: Option<History>,
+    /// The maximum total of the account, if it's limited below the range of the amounts
+    max_total: Option<Amount>,
+    /// The number of transactions added to the log, which is the position of the next one
+    logged: u64,
+    /// Where the times of the logged transactions come from
+    clock: SharedClock,
 }
 
 impl Account {
     pub(crate) fn new(client_id: ClientId) -> Result<Self, AccountError> {
         Ok(Self {
             client_id,
+            name: AccountName::default(),
             held: Amount::zero(),
+            escrow: Amount::zero(),
+            pending: Withheld::default(),
+            settlement_delay: None,
+            reserve: Withheld::default(),
+            rolling_reserve: None,
             total: Amount::zero(),
+            available: Amount::zero(),
             locked: false,
+            locked_operations: LockedOperations::default(),
+            dispute_policy: DisputePolicy::default(),
+            withdrawal_only: false,
+            chargebacks: 0,
+            locked_on_load: false,
+            last_chargeback: None,
             transactions: TransactionCache::new()?,
+            history: None,
+            max_total: None,
+            logged: 0,
+            clock: SystemClock::shared(),
         })
     }
 
+    /// Keep the transactions of the account in a store that was restored from a backup, in which `logged` transactions
+    /// were logged.
+    pub(crate) fn with_restored_store(
+        mut self,
+        path: &Path,
+        logged: u64,
+    ) -> Result<Self, AccountError> {
+        let store = SqliteKvStore::new(path).map_err(transactions_cache::CacheError::from)?;
+        self.transactions = TransactionCache::with_store(store)?;
+        self.logged = logged;
+        Ok(self)
+    }
+
+    /// Create an account with existing balances, e.g. the closing balances of a previous run. The pending funds and the
+    /// reserve stay withheld until `withhold_loaded_funds` says when they are released.
+    pub(crate) fn from_snapshot(
+        client_id: ClientId,
+        held: Amount,
+        escrow: Amount,
+        pending: Amount,
+        reserve: Amount,
+        total: Amount,
+        locked: bool,
+    ) -> Result<Self, AccountError> {
+        Ok(Self {
+            client_id,
+            name: AccountName::default(),
+            held,
+            escrow,
+            pending: Withheld::loaded(pending),
+            settlement_delay: None,
+            reserve: Withheld::loaded(reserve),
+            rolling_reserve: None,
+            total,
+            available: available(held, escrow, pending, reserve, total)?,
+            locked,
+            locked_operations: LockedOperations::default(),
+            dispute_policy: DisputePolicy::default(),
+            withdrawal_only: false,
+            chargebacks: 0,
+            locked_on_load: locked,
+            last_chargeback: None,
+            transactions: TransactionCache::new()?,
+            history: None,
+            max_total: None,
+            logged: 0,
+            clock: SystemClock::shared(),
+        })
+    }
+}
+
+impl<S: BackingStore> Account<S> {
     pub(crate) fn client(&self) -> ClientId {
         self.client_id
     }
 
+    /// Make this account a sub-account of the client other than the main one.
+    pub(crate) fn with_name(mut self, name: AccountName) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub(crate) fn name(&self) -> &AccountName {
+        &self.name
+    }
+
+    /// Keep track of the order of the transactions so that the log can be compacted to the `window` most recent ones.
+    pub(crate) fn with_dispute_window(mut self, window: Option<usize>) -> Self {
+        self.history = window.map(|window| History {
+            window,
+            recent: VecDeque::new(),
+            retained: 0,
+            archived: ArchivedIds::default(),
+        });
+        self
+    }
+
+    /// Take the times of the logged transactions from another clock than the system clock.
+    pub(crate) fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reject the deposits and the moves that would bring the total above `max_total`. Holds don't change the total,
+    /// so the funds held by disputes and in escrow count towards the limit until they leave the account.
+    pub(crate) fn with_max_total(mut self, max_total: Option<Amount>) -> Self {
+        self.max_total = max_total;
+        self
+    }
+
+    /// Credit the deposits to the total right away but only make them available `delay` after they were applied, like
+    /// the card and ACH payments that settle days later. The funds become available once `release_due_funds` runs after
+    /// that.
+    pub(crate) fn with_settlement_delay(mut self, delay: Option<TimeDelta>) -> Self {
+        self.settlement_delay = delay;
+        self
+    }
+
+    /// Keep the pending funds of an account loaded from a snapshot for a whole settlement delay from now, and its reserve
+    /// for a whole reserve period, since the snapshot doesn't say when their deposits were made. Without a settlement
+    /// delay or a rolling reserve they are available right away, like the deposits of the run. Runs once the settlement
+    /// delay, the rolling reserve and the clock were set.
+    pub(crate) fn withhold_loaded_funds(mut self) -> Self {
+        let now = self.clock.now();
+        self.pending
+            .schedule_loaded(now + self.settlement_delay.unwrap_or_default());
+        let period = self.rolling_reserve.map(|reserve| reserve.period);
+        self.reserve
+            .schedule_loaded(now + period.unwrap_or_default());
+        self.release_due_funds();
+        self
+    }
+
+    /// Withhold a share of every deposit in a rolling reserve, which is released once its period is over. The reserve is
+    /// released by `release_due_funds`, like the deposits that settle.
+    pub(crate) fn with_rolling_reserve(mut self, rolling_reserve: Option<RollingReserve>) -> Self {
+        self.rolling_reserve = rolling_reserve;
+        self
+    }
+
+    /// Dispute the transactions according to another policy than the default one, which only lets deposits be disputed.
+    pub(crate) fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    /// Keep allowing some operations once the account is locked, e.g. the resolves of the disputes that are open.
+    pub(crate) fn with_locked_operations(mut self, locked_operations: LockedOperations) -> Self {
+        self.locked_operations = locked_operations;
+        self
+    }
+
+    pub(crate) fn held(&self) -> Amount {
+        self.held
+    }
+
+    pub(crate) fn total(&self) -> Amount {
+        self.total
+    }
+
+    pub(crate) fn escrow(&self) -> Amount {
+        self.escrow
+    }
+
+    pub(crate) fn pending(&self) -> Amount {
+        self.pending.amount
+    }
+
+    pub(crate) fn reserve(&self) -> Amount {
+        self.reserve.amount
+    }
+
+    pub(crate) fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Whether a transaction can be looked up without loading it from the transaction store on disk. Transactions that
+    /// don't exist are not in memory either, finding out means asking the store.
+    pub(crate) fn is_in_memory(&self, transaction_id: TransactionId) -> bool {
+        self.transactions.is_in_memory(&transaction_id)
+    }
+
+    /// Whether a funding transaction with the id was applied to the account, including the archived ones. Also used to
+    /// check the ids across the sub-accounts of the client.
+    pub(crate) fn has_transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<bool, AccountError> {
+        let archived = self
+            .history
+            .as_ref()
+            .is_some_and(|history| history.archived.contains(transaction_id));
+        Ok(archived || self.transactions.contains_key(&transaction_id)?)
+    }
+
+    /// Check that the transaction store of the account can be written to.
+    pub(crate) fn check_store(&self) -> Result<(), AccountError> {
+        Ok(self.transactions.check_writable()?)
+    }
+
+    /// The number of transactions that were added to the log of the account.
+    pub(crate) fn logged(&self) -> u64 {
+        self.logged
+    }
+
+    /// Write a copy of the transaction store of the account to a path that doesn't exist yet.
+    pub(crate) fn back_up_store(&self, path: &Path) -> Result<(), AccountError> {
+        Ok(self.transactions.backup(path)?)
+    }
+
+    pub(crate) fn snapshot(&self) -> AccountSnapshot {
+        AccountSnapshot {
+            client: self.client_id,
+            account: self.name.clone(),
+            available: self.available(),
+            held: self.held,
+            escrow: self.escrow,
+            pending: self.pending.amount,
+            reserve: self.reserve.amount,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
+
+    /// The dispute state of a previous transaction.
+    pub(crate) fn dispute_status(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<DisputeStatus, AccountError> {
+        let transaction = self
+            .transactions
+            .get_mut(&transaction_id)?
+            .ok_or(AccountError::TransactionMissing)?;
+        Ok(DisputeStatus {
+            state: transaction.state,
+            version: transaction.version,
+        })
+    }
+
+    /// Suspend deposits. Withdrawals and disputes are still processed.
+    pub(crate) fn set_withdrawal_only(&mut self) {
+        self.withdrawal_only = true;
+    }
+
     pub(crate) fn lock(&mut self) {
         self.locked = true;
     }
 
+    // Locked accounts only accept the operations that they still allow.
+    fn check_unlocked(&self, operation: TransactionType) -> Result<(), AccountError> {
+        if self.locked && !self.locked_operations.allows(operation) {
+            return Err(AccountError::AccountLocked);
+        }
+        Ok(())
+    }
+
     /// The total funds that are available for trading, staking, withdrawal, etc.
     /// This should be equal to the total - held amounts
     pub(crate) fn available(&self) -> Amount {
-        self.total
-            .checked_sub(self.held)
-            .expect("Programmer error.")
+        self.available
     }
 
     /// Deposit funds to the account.
@@ -158,13 +890,15 @@ impl Account {
         amount: Amount,
         transaction_id: TransactionId,
     ) -> Result<(), AccountError> {
-        // Don't allow deposits to locked accounts.
-        if self.locked {
-            return Err(AccountError::AccountLocked);
+        // Don't allow deposits to locked accounts, unless their policy does.
+        self.check_unlocked(TransactionType::Deposit)?;
+
+        if self.withdrawal_only {
+            return Err(AccountError::DepositsSuspended);
         }
 
         // Don't re-play the same transaction twice.
-        if self.transactions.contains_key(&transaction_id)? {
+        if self.has_transaction(transaction_id)? {
             return Err(AccountError::DuplicateTransaction);
         }
 
@@ -173,28 +907,77 @@ impl Account {
             return Err(AccountError::InvalidAmount);
         }
 
-        // Increase the total ammount and store the tx.
-        self.total = self
-            .total
-            .checked_add(amount)
-            .ok_or(AccountError::DepositLimitReached)?;
-        self.transactions
-            .put(transaction_id, FundingLogEntry::new_deposit(amount))?;
+        // Increase the total ammount and store the tx. The total is updated only once the tx is stored.
+        // The share of the rolling reserve is withheld for its period, and with a settlement delay the rest of the
+        // funds is pending until the deposit settles.
+        let total = self.increased_total(amount)?;
+        let now = self.clock.now();
+        let reserved = match self.rolling_reserve {
+            Some(rolling_reserve) => amount.percent(rolling_reserve.percent),
+            None => Amount::zero(),
+        };
+        let unsettled = match self.settlement_delay {
+            Some(_) => amount
+                .checked_sub(reserved)
+                .ok_or(AccountError::BalanceOutOfRange)?,
+            None => Amount::zero(),
+        };
+        let reserve = self.reserve.added(reserved)?;
+        let pending = self.pending.added(unsettled)?;
+        let available = available(self.held, self.escrow, pending, reserve, total)?;
+        self.log(transaction_id, FundingLogEntry::new_deposit(amount))?;
+        let withheld = |amount: Amount, period: Option<TimeDelta>| WithheldDeposit {
+            transaction_id: Some(transaction_id),
+            amount,
+            until: now + period.unwrap_or_default(),
+        };
+        self.reserve.push(
+            reserve,
+            withheld(reserved, self.rolling_reserve.map(|reserve| reserve.period)),
+        );
+        self.pending
+            .push(pending, withheld(unsettled, self.settlement_delay));
+        self.total = total;
+        self.available = available;
 
         Ok(())
     }
 
+    /// Record a deposit or a withdrawal of a previous run that is still disputed, so that its dispute can be resolved
+    /// or charged back. Its funds must already be part of the held balance of the account, which doesn't change.
+    pub(crate) fn carry_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction_type: TransactionType,
+        amount: Amount,
+        source: Option<DisputeSource>,
+    ) -> Result<(), AccountError> {
+        if self.has_transaction(transaction_id)? {
+            return Err(AccountError::DuplicateTransaction);
+        }
+        if amount <= Amount::zero() {
+            return Err(AccountError::InvalidAmount);
+        }
+        let mut entry = match transaction_type {
+            TransactionType::Deposit => FundingLogEntry::new_deposit(amount),
+            TransactionType::Withdrawal => FundingLogEntry::new_withdrawal(amount),
+            _ => return Err(AccountError::TransactionCannotBeDisputed),
+        };
+        entry.dispute_source = source;
+        entry.disputed = Some(amount);
+        entry.set_state(DisputeState::DisputeInitiated, self.clock.now());
+        self.log(transaction_id, entry)
+    }
+
     /// Withdraw funds from the account.
     pub(crate) fn withdraw(
         &mut self,
         amount: Amount,
         transaction_id: TransactionId,
     ) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::AccountLocked);
-        }
+        self.check_unlocked(TransactionType::Withdrawal)?;
 
-        if self.transactions.contains_key(&transaction_id)? {
+        if self.has_transaction(transaction_id)? {
             return Err(AccountError::DuplicateTransaction);
         }
 
@@ -207,58 +990,338 @@ impl Account {
             return Err(AccountError::InvalidAmount);
         }
 
-        self.total = self
+        let total = self
             .total
             .checked_sub(amount)
             .ok_or(AccountError::InsufficientFunds)?;
-        self.transactions
-            .put(transaction_id, FundingLogEntry::new_withdrawal(amount))?;
+        let available = available(
+            self.held,
+            self.escrow,
+            self.pending.amount,
+            self.reserve.amount,
+            total,
+        )?;
+        self.log(transaction_id, FundingLogEntry::new_withdrawal(amount))?;
+        self.total = total;
+        self.available = available;
 
         Ok(())
     }
 
-    /// Dispute a previous deposit.
-    pub(crate) fn dispute(&mut self, transaction_id: TransactionId) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::AccountLocked);
-        }
+    /// Dispute a previous deposit or, if the dispute policy allows it, withdrawal. The whole amount of the transaction
+    /// is disputed, unless the policy allows partial disputes and a smaller `amount` is given.
+    pub(crate) fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Option<Amount>,
+        source: Option<DisputeSource>,
+    ) -> Result<DisputedFunds, AccountError> {
+        self.check_unlocked(TransactionType::Dispute)?;
+        let now = self.clock.now();
 
         // Check if the referenced transaction exists.
+        let transaction = self
+            .transactions
+            .get_mut(&transaction_id)?
+            .ok_or(AccountError::TransactionMissing)?;
+        let state = transaction.transition(DisputeEvent::Open, &self.dispute_policy)?;
+        let rule = transaction.funding_type.rule(&self.dispute_policy);
+        if let Some(max_age) = rule.and_then(|rule| rule.max_age)
+            && now - transaction.created_at() > max_age
+        {
+            return Err(AccountError::DisputeWindowExpired(max_age.num_days()));
+        }
+        let amount = match amount {
+            Some(amount) if rule.is_some_and(|rule| rule.partial) => {
+                if amount == Amount::zero() || amount > transaction.amount() {
+                    return Err(AccountError::InvalidAmount);
+                }
+                amount
+            }
+            _ => transaction.amount(),
+        };
+
+        // The funds of a disputed deposit are held until the dispute is closed. The funds of a disputed withdrawal are
+        // credited back but held, and only become available if the withdrawal is charged back.
+        let held = self
+            .held
+            .checked_add(amount)
+            .ok_or(AccountError::BalanceOutOfRange)?;
+        let total = match transaction.funding_type {
+            FundingType::Withdrawal => self
+                .total
+                .checked_add(amount)
+                .ok_or(AccountError::BalanceOutOfRange)?,
+            _ => self.total,
+        };
+        // The disputed funds of a deposit that didn't settle yet, then those of its reserve, are held instead of
+        // withheld. The rest of a partially disputed deposit is still released when it's due.
+        let unsettled = self.pending.taken(transaction_id, amount);
+        let reserved = self.reserve.taken(
+            transaction_id,
+            amount
+                .checked_sub(unsettled.map_or(Amount::zero(), |(_, taken)| taken))
+                .unwrap_or_default(),
+        );
+        let pending = self.pending.without(unsettled)?;
+        let reserve = self.reserve.without(reserved)?;
+        let available = available(held, self.escrow, pending, reserve, total)?;
+        transaction.set_state(state, now);
+        transaction.dispute_source = source;
+        transaction.disputed = Some(amount);
+        self.pending.take(pending, unsettled);
+        self.reserve.take(reserve, reserved);
+        self.held = held;
+        self.total = total;
+        self.available = available;
+        Ok(DisputedFunds {
+            amount,
+            disputed: transaction.funding_type.transaction_type(),
+        })
+    }
+
+    /// Put funds aside in escrow. The funds are no longer available but are kept apart from the funds held for disputes.
+    pub(crate) fn escrow_hold(
+        &mut self,
+        amount: Amount,
+        transaction_id: TransactionId,
+    ) -> Result<(), AccountError> {
+        self.check_unlocked(TransactionType::EscrowHold)?;
+
+        if self.has_transaction(transaction_id)? {
+            return Err(AccountError::DuplicateTransaction);
+        }
+
+        if self.available() < amount {
+            return Err(AccountError::InsufficientFunds);
+        }
+
+        if amount == Amount::zero() {
+            return Err(AccountError::InvalidAmount);
+        }
+
+        let escrow = self
+            .escrow
+            .checked_add(amount)
+            .ok_or(AccountError::BalanceOutOfRange)?;
+        let available = available(
+            self.held,
+            escrow,
+            self.pending.amount,
+            self.reserve.amount,
+            self.total,
+        )?;
+        self.log(transaction_id, FundingLogEntry::new_escrow_hold(amount))?;
+        self.escrow = escrow;
+        self.available = available;
+
+        Ok(())
+    }
+
+    /// Release the funds of an escrow hold. Returns the released amount.
+    /// Funds released to the client become available again while funds released to the beneficiary leave the account.
+    pub(crate) fn escrow_release(
+        &mut self,
+        transaction_id: TransactionId,
+        party: EscrowParty,
+    ) -> Result<Amount, AccountError> {
+        self.check_unlocked(TransactionType::EscrowRelease)?;
+
         let transaction = self
             .transactions
             .get_mut(&transaction_id)?
             .ok_or(AccountError::TransactionMissing)?;
         let amount = transaction.amount();
 
-        // Only dispute if it was not disputed before.
-        if transaction.can_be_disputed() {
-            match transaction.funding_type {
-                FundingType::Deposit => {
-                    self.held = self
-                        .held
-                        .checked_add(amount)
-                        .expect("Programmer error. Held amount should not exceed total, and there is a deposit limit on total.");
-                    transaction.state = DisputeState::DisputeInitiated;
-                }
-                // We don't allow disputes for withdrawals. From what I can reasearch it's in line with what other processors like Stripe or Paypal do.
-                // There may be situations where it makes sense to dispute a withdrawal but not supporting in for now.
-                FundingType::Withdrawal => return Err(AccountError::WithdrawalDisputeNotSupported),
+        match transaction.funding_type {
+            FundingType::Escrow(EscrowState::Held) => {
+                let escrow = self
+                    .escrow
+                    .checked_sub(amount)
+                    .ok_or(AccountError::BalanceOutOfRange)?;
+                let total = match party {
+                    EscrowParty::Client => self.total,
+                    EscrowParty::Beneficiary => self
+                        .total
+                        .checked_sub(amount)
+                        .ok_or(AccountError::BalanceOutOfRange)?,
+                };
+                let available = available(
+                    self.held,
+                    escrow,
+                    self.pending.amount,
+                    self.reserve.amount,
+                    total,
+                )?;
+                transaction.funding_type = FundingType::Escrow(EscrowState::Released(party));
+                transaction.updated_at = self.clock.now().timestamp_millis();
+                self.escrow = escrow;
+                self.total = total;
+                self.available = available;
+                Ok(amount)
+            }
+            FundingType::Escrow(EscrowState::Released(_)) => {
+                Err(AccountError::EscrowAlreadyReleased)
+            }
+            FundingType::Deposit | FundingType::Withdrawal | FundingType::Move => {
+                Err(AccountError::TransactionNotInEscrow)
             }
-            Ok(())
-        } else {
-            Err(AccountError::TransactionCannotBeDisputed)
         }
     }
 
-    // A dispute resolution in favor of the merchant.
-    pub(crate) fn resolve_dispute(
+    /// Move funds to another sub-account of the same client. Both accounts are checked before either of them changes.
+    pub(crate) fn move_to(
         &mut self,
+        destination: &mut Account<S>,
+        amount: Amount,
         transaction_id: TransactionId,
     ) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::AccountLocked);
+        self.check_unlocked(TransactionType::Move)?;
+        destination.check_unlocked(TransactionType::Move)?;
+
+        if self.has_transaction(transaction_id)? {
+            return Err(AccountError::DuplicateTransaction);
+        }
+
+        if self.available() < amount {
+            return Err(AccountError::InsufficientFunds);
         }
 
+        if amount == Amount::zero() {
+            return Err(AccountError::InvalidAmount);
+        }
+
+        let total = self
+            .total
+            .checked_sub(amount)
+            .ok_or(AccountError::InsufficientFunds)?;
+        let source_available = available(
+            self.held,
+            self.escrow,
+            self.pending.amount,
+            self.reserve.amount,
+            total,
+        )?;
+        let destination_total = destination.increased_total(amount)?;
+        let destination_available = available(
+            destination.held,
+            destination.escrow,
+            destination.pending.amount,
+            destination.reserve.amount,
+            destination_total,
+        )?;
+        self.log(transaction_id, FundingLogEntry::new_move(amount))?;
+        self.total = total;
+        self.available = source_available;
+        destination.total = destination_total;
+        destination.available = destination_available;
+
+        Ok(())
+    }
+
+    // The total after funds came into the account, if it stays within the limits.
+    fn increased_total(&self, amount: Amount) -> Result<Amount, AccountError> {
+        let total = self
+            .total
+            .checked_add(amount)
+            .ok_or(AccountError::DepositLimitReached)?;
+        match self.max_total {
+            Some(max_total) if total > max_total => {
+                Err(AccountError::BalanceLimitExceeded(max_total))
+            }
+            _ => Ok(total),
+        }
+    }
+
+    // Add a transaction to the log, stamped with its position in the log and the current time.
+    fn log(
+        &mut self,
+        transaction_id: TransactionId,
+        entry: FundingLogEntry,
+    ) -> Result<(), AccountError> {
+        let entry = entry.stamped(self.logged, self.clock.now());
+        self.transactions.put(transaction_id, entry)?;
+        self.logged += 1;
+        self.record_history(transaction_id);
+        Ok(())
+    }
+
+    fn record_history(&mut self, transaction_id: TransactionId) {
+        if let Some(history) = &mut self.history {
+            history.recent.push_back(transaction_id);
+        }
+    }
+
+    /// The settled transactions that are older than the dispute window. The log is compacted periodically: nothing is
+    /// returned until a whole window of new transactions was added since the last compaction.
+    /// Transactions that are still disputed or held in escrow are kept in the log.
+    pub(crate) fn compaction(&mut self) -> Result<Option<Compaction>, AccountError> {
+        let Some(history) = &mut self.history else {
+            return Ok(None);
+        };
+        if history.recent.len() < 2 * history.window + history.retained {
+            return Ok(None);
+        }
+        let Some(&last_transaction) = history.recent.back() else {
+            return Ok(None);
+        };
+
+        let old = history.recent.len() - history.window;
+        let mut transactions = Vec::new();
+        let mut missing = HashSet::new();
+        for transaction_id in history.recent.iter().take(old) {
+            match self.transactions.get_mut(transaction_id)? {
+                Some(entry) if entry.is_settled() => {
+                    transactions.push(entry.archive(*transaction_id))
+                }
+                Some(_) => {}
+                // Already removed by a compaction that failed half way.
+                None => {
+                    missing.insert(*transaction_id);
+                }
+            }
+        }
+        history.recent.retain(|id| !missing.contains(id));
+        history.retained = old - missing.len() - transactions.len();
+        // The archive follows the order of the log rather than the order the ids were tracked in.
+        transactions.sort_by_key(|transaction| transaction.seq);
+
+        let checkpoint = self.snapshot();
+        Ok(Some(Compaction {
+            transactions,
+            checkpoint,
+            last_transaction,
+        }))
+    }
+
+    /// Remove the transactions of a compaction from the log once they were archived.
+    pub(crate) fn compact(&mut self, compaction: &Compaction) -> Result<(), AccountError> {
+        let Some(history) = &mut self.history else {
+            return Ok(());
+        };
+        let mut removed = HashSet::new();
+        let mut result = Ok(());
+        for transaction in &compaction.transactions {
+            if let Err(err) = self.transactions.remove(&transaction.transaction_id) {
+                result = Err(err.into());
+                break;
+            }
+            removed.insert(transaction.transaction_id);
+            history.archived.insert(transaction.transaction_id);
+        }
+        history.recent.retain(|id| !removed.contains(id));
+        result
+    }
+
+    // A dispute resolution in favor of the merchant. Returns the released funds.
+    // The funds of a deposit become available again, the funds credited back by a withdrawal dispute leave the account.
+    pub(crate) fn resolve_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        source: Option<DisputeSource>,
+    ) -> Result<DisputedFunds, AccountError> {
+        self.check_unlocked(TransactionType::Resolve)?;
+
         // Check if the referenced transaction exists.
         let transaction = self
             .transactions
@@ -266,61 +1329,359 @@ impl Account {
             .ok_or(AccountError::TransactionMissing)?;
 
         // Check the correct state transition. Only allow resolution if dispute was started.
-        match transaction.state {
-            DisputeState::None => Err(AccountError::TransactionNotDisputed),
-            DisputeState::DisputeInitiated => {
-                self.held = self
-                    .held
-                    .checked_sub(transaction.amount())
-                    .expect("Programmer error.");
-                transaction.state = DisputeState::DisputeResolved;
-                Ok(())
-            }
-            DisputeState::DisputeResolved => Err(AccountError::DisputeAlreadyResolved),
-            DisputeState::ChargedBack => Err(AccountError::TransactionWasChargedBack),
-        }
+        let state = transaction.transition(DisputeEvent::Resolve, &self.dispute_policy)?;
+        transaction.check_dispute_source(source)?;
+        let amount = transaction.disputed_amount();
+        let held = self
+            .held
+            .checked_sub(amount)
+            .ok_or(AccountError::BalanceOutOfRange)?;
+        let total = match transaction.funding_type {
+            FundingType::Withdrawal => self
+                .total
+                .checked_sub(amount)
+                .ok_or(AccountError::BalanceOutOfRange)?,
+            _ => self.total,
+        };
+        let available = available(
+            held,
+            self.escrow,
+            self.pending.amount,
+            self.reserve.amount,
+            total,
+        )?;
+        transaction.set_state(state, self.clock.now());
+        self.held = held;
+        self.total = total;
+        self.available = available;
+        Ok(DisputedFunds {
+            amount,
+            disputed: transaction.funding_type.transaction_type(),
+        })
     }
 
-    // A dispute resolution in favor of the client.
-    pub(crate) fn chargeback(&mut self, transaction_id: TransactionId) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::AccountLocked);
-        }
+    // A dispute resolution in favor of the client. Returns the charged back funds.
+    // The funds of a deposit leave the account, the funds credited back by a withdrawal dispute become available.
+    pub(crate) fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        source: Option<DisputeSource>,
+    ) -> Result<DisputedFunds, AccountError> {
+        self.check_unlocked(TransactionType::Chargeback)?;
 
         let transaction = self
             .transactions
             .get_mut(&transaction_id)?
             .ok_or(AccountError::TransactionMissing)?;
-        let amount = transaction.amount();
+        let state = transaction.transition(DisputeEvent::Chargeback, &self.dispute_policy)?;
+        transaction.check_dispute_source(source)?;
+        let amount = transaction.disputed_amount();
+        let disputed = transaction.funding_type.transaction_type();
+        let held = self
+            .held
+            .checked_sub(amount)
+            .ok_or(AccountError::BalanceOutOfRange)?;
+        let total = match transaction.funding_type {
+            FundingType::Withdrawal => self.total,
+            _ => self
+                .total
+                .checked_sub(amount)
+                .ok_or(AccountError::BalanceOutOfRange)?,
+        };
+        let available = available(
+            held,
+            self.escrow,
+            self.pending.amount,
+            self.reserve.amount,
+            total,
+        )?;
+        transaction.set_state(state, self.clock.now());
+        self.held = held;
+        self.total = total;
+        self.available = available;
+        self.lock();
+        self.chargebacks += 1;
+        self.last_chargeback = Some(self.clock.now());
+        Ok(DisputedFunds { amount, disputed })
+    }
 
-        match transaction.state {
-            DisputeState::None => Err(AccountError::TransactionNotDisputed),
-            DisputeState::DisputeInitiated => {
-                self.held = self.held.checked_sub(amount).unwrap();
-                self.total = self.total.checked_sub(amount).unwrap();
-                transaction.state = DisputeState::ChargedBack;
-                self.lock();
-                Ok(())
-            }
-            DisputeState::DisputeResolved => Err(AccountError::DisputeAlreadyResolved),
-            DisputeState::ChargedBack => Err(AccountError::TransactionWasChargedBack),
+    /// Make the funds of the deposits whose settlement delay is over, and the reserve whose period is over, available.
+    /// Returns the number of deposits and reserves that were released.
+    pub(crate) fn release_due_funds(&mut self) -> usize {
+        let now = self.clock.now();
+        let mut released = 0;
+        // Balances out of range keep the funds withheld rather than losing track of them.
+        while let Some(pending) = self.pending.due(now)
+            && let Ok(available) = available(
+                self.held,
+                self.escrow,
+                pending,
+                self.reserve.amount,
+                self.total,
+            )
+        {
+            self.pending.release(pending);
+            self.available = available;
+            released += 1;
+        }
+        while let Some(reserve) = self.reserve.due(now)
+            && let Ok(available) = available(
+                self.held,
+                self.escrow,
+                self.pending.amount,
+                reserve,
+                self.total,
+            )
+        {
+            self.reserve.release(reserve);
+            self.available = available;
+            released += 1;
+        }
+        released
+    }
+
+    /// Unlock the account if it was locked by chargebacks and there was no chargeback for `clean_period`. Returns the
+    /// time of the last chargeback if the account was unlocked. Accounts that were already locked when they were
+    /// loaded stay locked.
+    pub(crate) fn unlock_if_clean(&mut self, clean_period: TimeDelta) -> Option<DateTime<Utc>> {
+        let last_chargeback = self.last_chargeback?;
+        if !self.locked || self.locked_on_load || self.clock.now() - last_chargeback < clean_period
+        {
+            return None;
+        }
+        self.locked = false;
+        self.chargebacks = 0;
+        Some(last_chargeback)
+    }
+
+    /// Reverse a chargeback after the merchant won the representment. Returns the restored funds.
+    /// The charged back funds of a deposit come back to the account and the funds that a withdrawal chargeback credited
+    /// back leave it again, even if that makes the balances negative. Reversals are applied to locked accounts, since
+    /// the chargeback locked them. With `unlock`, the account is unlocked once none of the chargebacks that locked it
+    /// stands, unless it was already locked when it was loaded.
+    pub(crate) fn reverse_chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        source: Option<DisputeSource>,
+        unlock: bool,
+    ) -> Result<DisputedFunds, AccountError> {
+        let transaction = self
+            .transactions
+            .get_mut(&transaction_id)?
+            .ok_or(AccountError::TransactionMissing)?;
+        let state =
+            transaction.transition(DisputeEvent::ReverseChargeback, &self.dispute_policy)?;
+        transaction.check_dispute_source(source)?;
+        let amount = transaction.disputed_amount();
+        let disputed = transaction.funding_type.transaction_type();
+        let total = match transaction.funding_type {
+            FundingType::Withdrawal => self.total.checked_sub(amount),
+            _ => self.total.checked_add(amount),
         }
+        .ok_or(AccountError::BalanceOutOfRange)?;
+        let available = available(
+            self.held,
+            self.escrow,
+            self.pending.amount,
+            self.reserve.amount,
+            total,
+        )?;
+        transaction.set_state(state, self.clock.now());
+        self.total = total;
+        self.available = available;
+        // A chargeback of a previous run is not counted, and its account was locked when it was loaded.
+        self.chargebacks = self.chargebacks.saturating_sub(1);
+        if unlock && self.chargebacks == 0 && !self.locked_on_load {
+            self.locked = false;
+        }
+        Ok(DisputedFunds { amount, disputed })
     }
 }
 
+// The available funds for the given balances, if they can be represented.
+fn available(
+    held: Amount,
+    escrow: Amount,
+    pending: Amount,
+    reserve: Amount,
+    total: Amount,
+) -> Result<Amount, AccountError> {
+    total
+        .checked_sub(held)
+        .and_then(|available| available.checked_sub(escrow))
+        .and_then(|available| available.checked_sub(pending))
+        .and_then(|available| available.checked_sub(reserve))
+        .ok_or(AccountError::BalanceOutOfRange)
+}
+
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+    use rust_decimal::Decimal;
+
     use super::*;
 
     impl Account {
         fn new_with_funds(client_id: ClientId, initial_amount: Amount) -> Self {
             let mut account = Self::new(client_id).unwrap();
             account.total = initial_amount;
+            account.available = initial_amount;
 
             account
         }
     }
 
+    #[test]
+    fn should_stamp_logged_transactions_with_their_position_and_times() {
+        let clock = crate::clock::ManualClock::at("2024-03-01T12:00:00Z");
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_clock(clock.shared());
+        assert!(account.deposit(5.0.into(), 7.into()).is_ok());
+        clock.advance(chrono::Duration::minutes(1));
+        assert!(account.deposit(1.0.into(), 3.into()).is_ok());
+        clock.advance(chrono::Duration::minutes(1));
+        assert!(account.dispute(7.into(), None, None).is_ok());
+
+        let first = account.transactions.get_mut(&7.into()).unwrap().unwrap();
+        assert_eq!(first.seq, 0);
+        assert_eq!(first.created_at().to_rfc3339(), "2024-03-01T12:00:00+00:00");
+        assert_eq!(first.updated_at().to_rfc3339(), "2024-03-01T12:02:00+00:00");
+        let second = account.transactions.get_mut(&3.into()).unwrap().unwrap();
+        assert_eq!(second.seq, 1);
+        assert_eq!(second.created_at(), second.updated_at());
+    }
+
+    #[test]
+    fn should_compact_settled_transactions_outside_the_window() {
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_dispute_window(Some(2));
+
+        for tx in 1..=3 {
+            assert!(account.deposit(1.0.into(), tx.into()).is_ok());
+        }
+        assert!(account.compaction().unwrap().is_none());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.deposit(1.0.into(), 4.into()).is_ok());
+
+        // The disputed transaction stays in the log.
+        let compaction = account.compaction().unwrap().unwrap();
+        let archived: Vec<_> = compaction
+            .transactions
+            .iter()
+            .map(|transaction| transaction.transaction_id)
+            .collect();
+        assert_eq!(archived, vec![2.into()]);
+        assert_eq!(compaction.last_transaction, 4.into());
+        assert_eq!(compaction.checkpoint.held, 1.0.into());
+
+        assert!(account.compact(&compaction).is_ok());
+        assert!(matches!(
+            account.dispute(2.into(), None, None),
+            Err(AccountError::TransactionMissing)
+        ));
+        // A replay of an archived transaction is still a duplicate.
+        assert!(matches!(
+            account.deposit(1.0.into(), 2.into()),
+            Err(AccountError::DuplicateTransaction)
+        ));
+        assert!(account.chargeback(1.into(), None).is_ok());
+        assert_eq!(account.total, 3.0.into());
+    }
+
+    #[test]
+    fn should_merge_the_archived_ids_into_ranges() {
+        let mut archived = ArchivedIds::default();
+        for id in [5u32, 7, 3, 6, 4, 10, 4, u32::MAX] {
+            archived.insert(id.into());
+        }
+
+        assert_eq!(
+            archived.ranges,
+            BTreeMap::from([(3, 7), (10, 10), (u32::MAX, u32::MAX)])
+        );
+        assert!(
+            [3u32, 5, 7, 10, u32::MAX]
+                .into_iter()
+                .all(|id| archived.contains(id.into()))
+        );
+        assert!(
+            ![0u32, 2, 8, 9, 11]
+                .into_iter()
+                .any(|id| archived.contains(id.into()))
+        );
+    }
+
+    #[test]
+    fn should_release_escrow_to_client() {
+        let mut account = Account::new_with_funds(1u16.into(), 10.0.into());
+
+        assert!(account.escrow_hold(4.0.into(), 1.into()).is_ok());
+        assert_eq!(account.escrow, 4.0.into());
+        assert_eq!(account.available(), 6.0.into());
+        assert_eq!(account.total, 10.0.into());
+        assert!(matches!(
+            account.withdraw(7.0.into(), 2.into()),
+            Err(AccountError::InsufficientFunds)
+        ));
+
+        assert_eq!(
+            account
+                .escrow_release(1.into(), EscrowParty::Client)
+                .unwrap(),
+            4.0.into()
+        );
+        assert_eq!(account.escrow, 0.0.into());
+        assert_eq!(account.available(), 10.0.into());
+        assert_eq!(account.total, 10.0.into());
+    }
+
+    #[test]
+    fn should_release_escrow_to_beneficiary() {
+        let mut account = Account::new_with_funds(1u16.into(), 10.0.into());
+
+        assert!(account.escrow_hold(4.0.into(), 1.into()).is_ok());
+        assert!(
+            account
+                .escrow_release(1.into(), EscrowParty::Beneficiary)
+                .is_ok()
+        );
+        assert_eq!(account.escrow, 0.0.into());
+        assert_eq!(account.available(), 6.0.into());
+        assert_eq!(account.total, 6.0.into());
+    }
+
+    #[test]
+    fn should_reject_invalid_escrow_operations() {
+        let mut account = Account::new_with_funds(1u16.into(), 10.0.into());
+
+        assert!(matches!(
+            account.escrow_hold(11.0.into(), 1.into()),
+            Err(AccountError::InsufficientFunds)
+        ));
+        assert!(account.deposit(1.0.into(), 2.into()).is_ok());
+        assert!(matches!(
+            account.escrow_release(2.into(), EscrowParty::Client),
+            Err(AccountError::TransactionNotInEscrow)
+        ));
+        assert!(account.escrow_hold(4.0.into(), 3.into()).is_ok());
+        assert!(matches!(
+            account.dispute(3.into(), None, None),
+            Err(AccountError::TransactionCannotBeDisputed)
+        ));
+        assert!(
+            account
+                .escrow_release(3.into(), EscrowParty::Client)
+                .is_ok()
+        );
+        assert!(matches!(
+            account.escrow_release(3.into(), EscrowParty::Beneficiary),
+            Err(AccountError::EscrowAlreadyReleased)
+        ));
+        assert_eq!(account.available(), 11.0.into());
+    }
+
     #[test]
     fn should_deposit_successfully() {
         let mut account = Account::new(1u16.into()).unwrap();
@@ -391,6 +1752,324 @@ mod tests {
         assert_eq!(account.total, 0.0.into());
     }
 
+    #[test]
+    fn should_apply_the_operations_allowed_on_locked_accounts() {
+        let mut account =
+            Account::new(1u16.into())
+                .unwrap()
+                .with_locked_operations(LockedOperations::allowing([
+                    TransactionType::Deposit,
+                    TransactionType::Resolve,
+                ]));
+        account.deposit(10.0.into(), 1.into()).unwrap();
+        account.dispute(1.into(), None, None).unwrap();
+        account.lock();
+
+        account.deposit(5.0.into(), 2.into()).unwrap();
+        assert!(matches!(
+            account.withdraw(1.0.into(), 3.into()),
+            Err(AccountError::AccountLocked)
+        ));
+        assert!(matches!(
+            account.chargeback(1.into(), None),
+            Err(AccountError::AccountLocked)
+        ));
+        account.resolve_dispute(1.into(), None).unwrap();
+
+        assert_eq!(account.available(), 15.0.into());
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn should_credit_back_disputed_withdrawals_when_charged_back() {
+        let policy = DisputePolicy {
+            withdrawal: DisputeRule {
+                disputable: true,
+                ..DisputePolicy::default().deposit
+            },
+            ..DisputePolicy::default()
+        };
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_dispute_policy(policy);
+        account.deposit(100.0.into(), 1.into()).unwrap();
+        account.withdraw(40.0.into(), 2.into()).unwrap();
+        account.withdraw(10.0.into(), 3.into()).unwrap();
+
+        let disputed = account.dispute(2.into(), None, None).unwrap();
+        assert_eq!(disputed.disputed, TransactionType::Withdrawal);
+        assert_eq!(
+            (account.available(), account.held, account.total),
+            (50.0.into(), 40.0.into(), 90.0.into())
+        );
+        account.dispute(3.into(), None, None).unwrap();
+        account.resolve_dispute(3.into(), None).unwrap();
+        account.chargeback(2.into(), None).unwrap();
+
+        assert_eq!(
+            (account.available(), account.held, account.total),
+            (90.0.into(), Amount::zero(), 90.0.into())
+        );
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn should_restore_the_funds_and_unlock_once_every_chargeback_is_reversed() {
+        let mut account =
+            Account::new(1u16.into())
+                .unwrap()
+                .with_locked_operations(LockedOperations::allowing([
+                    TransactionType::Dispute,
+                    TransactionType::Chargeback,
+                ]));
+        account.deposit(100.0.into(), 1.into()).unwrap();
+        account.deposit(30.0.into(), 2.into()).unwrap();
+        for transaction_id in [1, 2] {
+            account.dispute(transaction_id.into(), None, None).unwrap();
+            account.chargeback(transaction_id.into(), None).unwrap();
+        }
+        assert!(matches!(
+            account.reverse_chargeback(3.into(), None, true),
+            Err(AccountError::TransactionMissing)
+        ));
+
+        let reversed = account.reverse_chargeback(1.into(), None, true).unwrap();
+        assert_eq!(reversed.amount, 100.0.into());
+        assert_eq!(
+            (account.available(), account.held, account.total),
+            (100.0.into(), Amount::zero(), 100.0.into())
+        );
+        // The other chargeback still stands.
+        assert!(account.is_locked());
+        assert!(matches!(
+            account.reverse_chargeback(1.into(), None, true),
+            Err(AccountError::ChargebackAlreadyReversed)
+        ));
+        assert!(matches!(
+            account.dispute(1.into(), None, None),
+            Err(AccountError::TransactionCannotBeDisputed)
+        ));
+
+        account.reverse_chargeback(2.into(), None, true).unwrap();
+        assert_eq!(account.total, 130.0.into());
+        assert!(!account.is_locked());
+
+        // Without unlocking, or if the account was locked when it was loaded, the account stays locked.
+        let mut kept = Account::new(2u16.into()).unwrap();
+        kept.deposit(10.0.into(), 1.into()).unwrap();
+        kept.dispute(1.into(), None, None).unwrap();
+        kept.chargeback(1.into(), None).unwrap();
+        kept.reverse_chargeback(1.into(), None, false).unwrap();
+        assert!(kept.is_locked());
+        let mut loaded = Account::from_snapshot(
+            3u16.into(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            true,
+        )
+        .unwrap()
+        .with_locked_operations(LockedOperations::allowing([
+            TransactionType::Deposit,
+            TransactionType::Dispute,
+            TransactionType::Chargeback,
+        ]));
+        loaded.deposit(10.0.into(), 1.into()).unwrap();
+        loaded.dispute(1.into(), None, None).unwrap();
+        loaded.chargeback(1.into(), None).unwrap();
+        loaded.reverse_chargeback(1.into(), None, true).unwrap();
+        assert!(loaded.is_locked());
+    }
+
+    #[test]
+    fn should_unlock_after_a_clean_period_without_chargebacks() {
+        let clock = crate::clock::ManualClock::at("2024-03-01T12:00:00Z");
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_clock(clock.shared())
+            .with_locked_operations(LockedOperations::allowing([
+                TransactionType::Dispute,
+                TransactionType::Chargeback,
+            ]));
+        let clean_period = TimeDelta::days(30);
+        account.deposit(10.0.into(), 1.into()).unwrap();
+        account.deposit(10.0.into(), 2.into()).unwrap();
+        assert_eq!(account.unlock_if_clean(clean_period), None);
+        account.dispute(1.into(), None, None).unwrap();
+        account.chargeback(1.into(), None).unwrap();
+
+        clock.advance(TimeDelta::days(20));
+        account.dispute(2.into(), None, None).unwrap();
+        account.chargeback(2.into(), None).unwrap();
+        // The clean period starts over with every chargeback.
+        clock.advance(TimeDelta::days(29));
+        assert_eq!(account.unlock_if_clean(clean_period), None);
+        assert!(account.is_locked());
+        clock.advance(TimeDelta::days(1));
+        assert_eq!(
+            account.unlock_if_clean(clean_period),
+            Some("2024-03-21T12:00:00Z".parse().unwrap())
+        );
+        assert!(!account.is_locked());
+
+        let mut loaded = Account::from_snapshot(
+            2u16.into(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            true,
+        )
+        .unwrap()
+        .with_clock(clock.shared());
+        clock.advance(TimeDelta::days(365));
+        assert_eq!(loaded.unlock_if_clean(clean_period), None);
+        assert!(loaded.is_locked());
+    }
+
+    #[test]
+    fn should_only_make_deposits_available_once_they_settled() {
+        let clock = crate::clock::ManualClock::at("2024-03-01T12:00:00Z");
+        let policy = DisputePolicy {
+            deposit: DisputeRule {
+                partial: true,
+                ..DisputePolicy::default().deposit
+            },
+            ..DisputePolicy::default()
+        };
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_clock(clock.shared())
+            .with_dispute_policy(policy)
+            .with_settlement_delay(Some(TimeDelta::days(2)));
+        account.deposit(10.0.into(), 1.into()).unwrap();
+        assert_eq!(
+            (account.available(), account.pending(), account.total),
+            (Amount::zero(), 10.0.into(), 10.0.into())
+        );
+        assert!(matches!(
+            account.withdraw(1.0.into(), 2.into()),
+            Err(AccountError::InsufficientFunds)
+        ));
+
+        clock.advance(TimeDelta::days(1));
+        account.deposit(5.0.into(), 3.into()).unwrap();
+        assert_eq!(account.release_due_funds(), 0);
+        // The disputed part of an unsettled deposit is held instead of pending, the rest settles with the deposit.
+        account.dispute(3.into(), Some(2.0.into()), None).unwrap();
+        assert_eq!(
+            (account.available(), account.held, account.pending()),
+            (Amount::zero(), 2.0.into(), 13.0.into())
+        );
+
+        clock.advance(TimeDelta::days(1));
+        assert_eq!(account.release_due_funds(), 1);
+        assert_eq!(
+            (account.available(), account.pending()),
+            (10.0.into(), 3.0.into())
+        );
+        clock.advance(TimeDelta::days(1));
+        assert_eq!(account.release_due_funds(), 1);
+        let snapshot = account.snapshot();
+        assert_eq!(
+            (
+                snapshot.available,
+                snapshot.held,
+                snapshot.pending,
+                snapshot.total
+            ),
+            (13.0.into(), 2.0.into(), Amount::zero(), 15.0.into())
+        );
+    }
+
+    #[test]
+    fn should_withhold_a_rolling_reserve_of_the_deposits() {
+        let clock = crate::clock::ManualClock::at("2024-03-01T12:00:00Z");
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_clock(clock.shared())
+            .with_rolling_reserve(Some(RollingReserve {
+                percent: Decimal::TEN,
+                period: TimeDelta::days(90),
+            }));
+        account.deposit(100.0.into(), 1.into()).unwrap();
+        assert_eq!(
+            (account.available(), account.reserve(), account.total),
+            (90.0.into(), 10.0.into(), 100.0.into())
+        );
+
+        clock.advance(TimeDelta::days(30));
+        account.deposit(50.0.into(), 2.into()).unwrap();
+        // The reserve of a disputed deposit is held instead of withheld.
+        account.dispute(1.into(), None, None).unwrap();
+        assert_eq!(
+            (account.available(), account.held, account.reserve()),
+            (45.0.into(), 100.0.into(), 5.0.into())
+        );
+
+        clock.advance(TimeDelta::days(60));
+        assert_eq!(account.release_due_funds(), 0);
+        clock.advance(TimeDelta::days(30));
+        assert_eq!(account.release_due_funds(), 1);
+        let snapshot = account.snapshot();
+        assert_eq!(
+            (snapshot.available, snapshot.reserve, snapshot.total),
+            (50.0.into(), Amount::zero(), 150.0.into())
+        );
+    }
+
+    #[test]
+    fn should_apply_the_age_partial_and_reopen_rules_of_the_policy() {
+        let clock = crate::clock::ManualClock::at("2024-03-01T12:00:00Z");
+        let policy = DisputePolicy {
+            deposit: DisputeRule {
+                disputable: true,
+                max_age: Some(chrono::TimeDelta::days(30)),
+                partial: true,
+                reopen: true,
+            },
+            ..DisputePolicy::default()
+        };
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_dispute_policy(policy)
+            .with_clock(clock.shared());
+        account.deposit(100.0.into(), 1.into()).unwrap();
+        account.deposit(50.0.into(), 2.into()).unwrap();
+        assert!(matches!(
+            account
+                .withdraw(1.0.into(), 3.into())
+                .and(account.dispute(3.into(), None, None)),
+            Err(AccountError::WithdrawalDisputeNotSupported)
+        ));
+
+        assert!(matches!(
+            account.dispute(1.into(), Some(101.0.into()), None),
+            Err(AccountError::InvalidAmount)
+        ));
+        account.dispute(1.into(), Some(30.0.into()), None).unwrap();
+        assert_eq!(account.held, 30.0.into());
+        assert_eq!(
+            account.resolve_dispute(1.into(), None).unwrap().amount,
+            30.0.into()
+        );
+        account.dispute(1.into(), None, None).unwrap();
+        assert_eq!(account.held, 100.0.into());
+
+        clock.advance(chrono::Duration::days(31));
+        assert!(matches!(
+            account.dispute(2.into(), None, None),
+            Err(AccountError::DisputeWindowExpired(30))
+        ));
+        assert_eq!(
+            account.chargeback(1.into(), None).unwrap().amount,
+            100.0.into()
+        );
+    }
+
     #[test]
     fn should_not_deposit_zero_amount() {
         let mut account = Account::new(1u16.into()).unwrap();
@@ -413,6 +2092,66 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn should_count_held_funds_towards_the_maximum_total() {
+        let mut account = Account::new(1u16.into())
+            .unwrap()
+            .with_max_total(Some(100.0.into()));
+
+        assert!(account.deposit(60.0.into(), 1.into()).is_ok());
+        assert!(matches!(
+            account.deposit(50.0.into(), 2.into()),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+        assert!(account.deposit(40.0.into(), 3.into()).is_ok());
+        assert_eq!(account.total, 100.0.into());
+
+        // Disputed funds are still part of the total, until they are charged back.
+        assert!(account.dispute(3.into(), None, None).is_ok());
+        assert!(matches!(
+            account.deposit(1.0.into(), 4.into()),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+        assert!(account.resolve_dispute(3.into(), None).is_ok());
+        assert!(matches!(
+            account.deposit(1.0.into(), 4.into()),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+
+        // Funds in escrow too, until they are released to the beneficiary.
+        assert!(account.escrow_hold(30.0.into(), 5.into()).is_ok());
+        assert!(matches!(
+            account.deposit(1.0.into(), 4.into()),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+        assert!(
+            account
+                .escrow_release(5.into(), EscrowParty::Beneficiary)
+                .is_ok()
+        );
+        assert!(account.deposit(30.0.into(), 4.into()).is_ok());
+        assert_eq!(account.total, 100.0.into());
+        assert_eq!(account.available(), 100.0.into());
+    }
+
+    #[test]
+    fn should_limit_the_total_of_the_destination_of_a_move() {
+        let mut main = Account::new(1u16.into()).unwrap();
+        let mut savings = Account::new(1u16.into())
+            .unwrap()
+            .with_name("savings".parse().unwrap())
+            .with_max_total(Some(5.0.into()));
+        assert!(main.deposit(10.0.into(), 1.into()).is_ok());
+
+        assert!(matches!(
+            main.move_to(&mut savings, 6.0.into(), 2.into()),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+        assert_eq!(main.total, 10.0.into());
+        assert!(main.move_to(&mut savings, 5.0.into(), 2.into()).is_ok());
+        assert_eq!(savings.total, 5.0.into());
+    }
+
     #[test]
     fn should_not_withdraw_when_locked() {
         let mut account = Account::new(1u16.into()).unwrap();
@@ -484,7 +2223,7 @@ mod tests {
         let mut account = Account::new(1u16.into()).unwrap();
 
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
-        assert!(account.dispute(1.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
 
         assert_eq!(account.total, 100.0.into());
         assert_eq!(account.available(), Amount::zero());
@@ -500,8 +2239,8 @@ mod tests {
         assert!(account.deposit(200.0.into(), 2.into()).is_ok());
         assert!(account.deposit(300.0.into(), 3.into()).is_ok());
 
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.dispute(3.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.dispute(3.into(), None, None).is_ok());
 
         assert_eq!(account.total, 600.0.into());
         assert_eq!(account.available(), 200.0.into());
@@ -515,7 +2254,7 @@ mod tests {
 
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
         assert!(matches!(
-            account.dispute(2.into()),
+            account.dispute(2.into(), None, None),
             Err(AccountError::TransactionMissing)
         ));
 
@@ -530,8 +2269,8 @@ mod tests {
         let mut account = Account::new(1u16.into()).unwrap();
 
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.resolve_dispute(1.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.resolve_dispute(1.into(), None).is_ok());
 
         assert_eq!(account.total, 100.0.into());
         assert_eq!(account.available(), 100.0.into());
@@ -547,10 +2286,10 @@ mod tests {
         assert!(account.deposit(200.0.into(), 2.into()).is_ok());
         assert!(account.deposit(300.0.into(), 3.into()).is_ok());
 
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.dispute(3.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.dispute(3.into(), None, None).is_ok());
 
-        assert!(account.resolve_dispute(1.into()).is_ok());
+        assert!(account.resolve_dispute(1.into(), None).is_ok());
 
         assert_eq!(account.total, 600.0.into());
         assert_eq!(account.available(), 300.0.into());
@@ -564,7 +2303,7 @@ mod tests {
 
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
         assert!(matches!(
-            account.resolve_dispute(1.into()),
+            account.resolve_dispute(1.into(), None),
             Err(AccountError::TransactionNotDisputed)
         ));
 
@@ -582,8 +2321,8 @@ mod tests {
         assert!(account.deposit(200.0.into(), 2.into()).is_ok());
         assert!(account.withdraw(300.0.into(), 4.into()).is_ok());
 
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.dispute(2.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.dispute(2.into(), None, None).is_ok());
 
         assert_eq!(account.total, Amount::zero());
         assert_eq!(account.available(), (-300.0).into());
@@ -598,11 +2337,11 @@ mod tests {
         assert!(account.deposit(200.0.into(), 2.into()).is_ok());
         assert!(account.withdraw(300.0.into(), 4.into()).is_ok());
 
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.dispute(2.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.dispute(2.into(), None, None).is_ok());
 
-        assert!(account.resolve_dispute(1.into()).is_ok());
-        assert!(account.resolve_dispute(2.into()).is_ok());
+        assert!(account.resolve_dispute(1.into(), None).is_ok());
+        assert!(account.resolve_dispute(2.into(), None).is_ok());
 
         assert_eq!(account.total, Amount::zero());
         assert_eq!(account.available(), Amount::zero());
@@ -618,11 +2357,11 @@ mod tests {
         assert!(account.deposit(200.0.into(), 2.into()).is_ok());
         assert!(account.withdraw(300.0.into(), 4.into()).is_ok());
 
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.dispute(2.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.dispute(2.into(), None, None).is_ok());
 
-        assert!(account.resolve_dispute(1.into()).is_ok());
-        assert!(account.chargeback(2.into()).is_ok());
+        assert!(account.resolve_dispute(1.into(), None).is_ok());
+        assert!(account.chargeback(2.into(), None).is_ok());
 
         assert_eq!(account.total, (-200.0).into());
         assert_eq!(account.available(), (-200.0).into());
@@ -635,8 +2374,8 @@ mod tests {
         let mut account = Account::new(1u16.into()).unwrap();
 
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
-        assert!(account.dispute(1.into()).is_ok());
-        assert!(account.chargeback(1.into()).is_ok());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.chargeback(1.into(), None).is_ok());
 
         assert_eq!(account.total, Amount::zero());
         assert_eq!(account.available(), Amount::zero());
@@ -650,7 +2389,7 @@ mod tests {
 
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
         assert!(matches!(
-            account.chargeback(1.into()),
+            account.chargeback(1.into(), None),
             Err(AccountError::TransactionNotDisputed)
         ));
 
@@ -667,7 +2406,7 @@ mod tests {
         assert!(account.deposit(100.0.into(), 1.into()).is_ok());
         assert!(account.deposit(200.0.into(), 2.into()).is_ok());
 
-        assert!(account.dispute(2.into()).is_ok());
+        assert!(account.dispute(2.into(), None, None).is_ok());
 
         assert!(matches!(
             account.withdraw(200.0.into(), 3.into()),
@@ -680,6 +2419,388 @@ mod tests {
         assert!(!account.locked)
     }
 
+    #[test]
+    fn should_only_allow_withdrawals_in_withdrawal_only_mode() {
+        let mut account = Account::new_with_funds(1u16.into(), 100.0.into());
+        account.set_withdrawal_only();
+
+        assert!(matches!(
+            account.deposit(10.0.into(), 1.into()),
+            Err(AccountError::DepositsSuspended)
+        ));
+        assert!(account.withdraw(10.0.into(), 2.into()).is_ok());
+        assert_eq!(account.total, 90.0.into());
+    }
+
+    #[test]
+    fn should_bump_dispute_version_on_state_change() {
+        let mut account = Account::new(1u16.into()).unwrap();
+
+        assert!(account.deposit(100.0.into(), 1.into()).is_ok());
+        assert_eq!(
+            account.dispute_status(1.into()).unwrap(),
+            DisputeStatus {
+                state: DisputeState::None,
+                version: 0
+            }
+        );
+
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert!(account.resolve_dispute(1.into(), None).is_ok());
+        assert_eq!(
+            account.dispute_status(1.into()).unwrap(),
+            DisputeStatus {
+                state: DisputeState::DisputeResolved,
+                version: 2
+            }
+        );
+        assert!(matches!(
+            account.dispute_status(2.into()),
+            Err(AccountError::TransactionMissing)
+        ));
+    }
+
+    // An in-memory transaction store that can be made to fail on demand.
+    #[derive(Default)]
+    struct FailingStore {
+        entries: std::sync::Mutex<std::collections::HashMap<Vec<u8>, Vec<u8>>>,
+        failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl FailingStore {
+        fn check(&self) -> Result<(), transactions_cache::BackingStoreError> {
+            if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                Err(transactions_cache::BackingStoreError::InternalError(
+                    "Injected failure.".to_string(),
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl BackingStore for FailingStore {
+        fn new<P: AsRef<std::path::Path>>(
+            _path: P,
+        ) -> Result<Self, transactions_cache::BackingStoreError> {
+            Ok(Self::default())
+        }
+
+        fn get(
+            &self,
+            key: &[u8],
+        ) -> Result<Option<Vec<u8>>, transactions_cache::BackingStoreError> {
+            self.check()?;
+            Ok(self.entries.lock().unwrap().get(key).cloned())
+        }
+
+        fn put(
+            &self,
+            key: &[u8],
+            value: &[u8],
+        ) -> Result<(), transactions_cache::BackingStoreError> {
+            self.check()?;
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key.to_vec(), value.to_vec());
+            Ok(())
+        }
+
+        fn contains_key(&self, key: &[u8]) -> Result<bool, transactions_cache::BackingStoreError> {
+            self.check()?;
+            Ok(self.entries.lock().unwrap().contains_key(key))
+        }
+
+        fn delete(&self, key: &[u8]) -> Result<(), transactions_cache::BackingStoreError> {
+            self.check()?;
+            self.entries.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn backup<P: AsRef<std::path::Path>>(
+            &self,
+            _path: P,
+        ) -> Result<(), transactions_cache::BackingStoreError> {
+            self.check()
+        }
+    }
+
+    // An account with a full in-memory transaction log so that the next transaction has to evict to the store.
+    fn account_with_full_cache() -> (
+        Account<FailingStore>,
+        std::sync::Arc<std::sync::atomic::AtomicBool>,
+    ) {
+        let store = FailingStore::default();
+        let failing = store.failing.clone();
+        let mut account = Account {
+            client_id: 1u16.into(),
+            name: AccountName::default(),
+            held: Amount::zero(),
+            escrow: Amount::zero(),
+            pending: Withheld::default(),
+            settlement_delay: None,
+            reserve: Withheld::default(),
+            rolling_reserve: None,
+            total: Amount::zero(),
+            available: Amount::zero(),
+            locked: false,
+            locked_operations: LockedOperations::default(),
+            dispute_policy: DisputePolicy::default(),
+            withdrawal_only: false,
+            chargebacks: 0,
+            locked_on_load: false,
+            last_chargeback: None,
+            transactions: TransactionCache::with_store(store).unwrap(),
+            history: None,
+            max_total: None,
+            logged: 0,
+            clock: SystemClock::shared(),
+        };
+        for id in 0..128 {
+            account.deposit(1.0.into(), id.into()).unwrap();
+        }
+        (account, failing)
+    }
+
+    fn fail_store(failing: &std::sync::atomic::AtomicBool, fail: bool) {
+        failing.store(fail, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn should_leave_account_unchanged_when_store_fails_on_deposit() {
+        let (mut account, failing) = account_with_full_cache();
+
+        fail_store(&failing, true);
+        assert!(matches!(
+            account.deposit(10.0.into(), 1000.into()),
+            Err(AccountError::Internal(InternalError::TransactionCache(_)))
+        ));
+        assert_eq!(account.total, 128.0.into());
+
+        // The deposit can be retried once the store recovers, nothing was recorded by the failed attempt.
+        fail_store(&failing, false);
+        assert!(account.deposit(10.0.into(), 1000.into()).is_ok());
+        assert_eq!(account.total, 138.0.into());
+
+        // The transaction that was evicted by the failed attempt is still there.
+        assert!(account.dispute(0.into(), None, None).is_ok());
+        assert_eq!(account.held, 1.0.into());
+    }
+
+    #[test]
+    fn should_leave_account_unchanged_when_store_fails_on_withdrawal() {
+        let (mut account, failing) = account_with_full_cache();
+
+        fail_store(&failing, true);
+        assert!(matches!(
+            account.withdraw(10.0.into(), 1000.into()),
+            Err(AccountError::Internal(InternalError::TransactionCache(_)))
+        ));
+        assert_eq!(account.total, 128.0.into());
+        assert_eq!(account.available(), 128.0.into());
+    }
+
+    #[test]
+    fn should_leave_dispute_lifecycle_unchanged_when_store_fails() {
+        let (mut account, failing) = account_with_full_cache();
+        // Push the first deposit out of memory.
+        account.deposit(1.0.into(), 1000.into()).unwrap();
+
+        fail_store(&failing, true);
+        assert!(matches!(
+            account.dispute(0.into(), None, None),
+            Err(AccountError::Internal(InternalError::TransactionCache(_)))
+        ));
+        assert_eq!(account.held, Amount::zero());
+
+        fail_store(&failing, false);
+        assert!(account.dispute(0.into(), None, None).is_ok());
+        assert_eq!(account.held, 1.0.into());
+
+        // Push the disputed deposit out of memory again and fail the chargeback.
+        for id in 2000..2128 {
+            account.deposit(1.0.into(), id.into()).unwrap();
+        }
+        fail_store(&failing, true);
+        assert!(matches!(
+            account.chargeback(0.into(), None),
+            Err(AccountError::Internal(InternalError::TransactionCache(_)))
+        ));
+        assert_eq!(account.held, 1.0.into());
+        assert_eq!(account.total, 257.0.into());
+        assert!(!account.locked);
+
+        fail_store(&failing, false);
+        assert!(account.chargeback(0.into(), None).is_ok());
+        assert_eq!(account.held, Amount::zero());
+        assert_eq!(account.total, 256.0.into());
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn should_reject_dispute_that_overflows_held_funds() {
+        let mut account = Account::from_snapshot(
+            1u16.into(),
+            Decimal::MAX.into(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            false,
+        )
+        .unwrap();
+        assert!(account.deposit(1.0.into(), 2.into()).is_ok());
+
+        assert!(matches!(
+            account.dispute(2.into(), None, None),
+            Err(AccountError::BalanceOutOfRange)
+        ));
+        assert_eq!(account.held, Decimal::MAX.into());
+        assert_eq!(account.available(), (Decimal::ONE - Decimal::MAX).into());
+        assert_eq!(
+            account.dispute_status(2.into()).unwrap().state,
+            DisputeState::None
+        );
+    }
+
+    #[test]
+    fn should_move_funds_between_sub_accounts() {
+        let mut main = Account::new(1u16.into()).unwrap();
+        let mut savings = Account::new(1u16.into())
+            .unwrap()
+            .with_name("savings".parse().unwrap());
+        assert!(main.deposit(10.0.into(), 1.into()).is_ok());
+
+        assert!(main.move_to(&mut savings, 4.0.into(), 2.into()).is_ok());
+        assert_eq!(main.total, 6.0.into());
+        assert_eq!(main.available(), 6.0.into());
+        assert_eq!(savings.total, 4.0.into());
+        assert_eq!(savings.available(), 4.0.into());
+
+        // A move can't be replayed, can't overdraw the source and can't be disputed.
+        assert!(matches!(
+            main.move_to(&mut savings, 1.0.into(), 2.into()),
+            Err(AccountError::DuplicateTransaction)
+        ));
+        assert!(matches!(
+            main.move_to(&mut savings, 7.0.into(), 3.into()),
+            Err(AccountError::InsufficientFunds)
+        ));
+        assert!(matches!(
+            main.dispute(2.into(), None, None),
+            Err(AccountError::TransactionCannotBeDisputed)
+        ));
+        assert_eq!(main.total, 6.0.into());
+        assert_eq!(savings.total, 4.0.into());
+    }
+
+    #[test]
+    fn should_not_move_funds_to_locked_sub_account() {
+        let mut main = Account::new(1u16.into()).unwrap();
+        let mut savings = Account::from_snapshot(
+            1u16.into(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            true,
+        )
+        .unwrap()
+        .with_name("savings".parse().unwrap());
+        assert!(main.deposit(10.0.into(), 1.into()).is_ok());
+
+        assert!(matches!(
+            main.move_to(&mut savings, 4.0.into(), 2.into()),
+            Err(AccountError::AccountLocked)
+        ));
+        assert_eq!(main.total, 10.0.into());
+        assert_eq!(savings.total, Amount::zero());
+    }
+
+    #[test]
+    fn should_reject_snapshot_with_unrepresentable_available_funds() {
+        assert!(matches!(
+            Account::from_snapshot(
+                1u16.into(),
+                Decimal::MAX.into(),
+                Amount::zero(),
+                Amount::zero(),
+                Amount::zero(),
+                Decimal::MIN.into(),
+                false
+            ),
+            Err(AccountError::BalanceOutOfRange)
+        ));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Operation {
+        Deposit(Amount, u32),
+        Withdraw(Amount, u32),
+        Dispute(u32),
+        Resolve(u32),
+        Chargeback(u32),
+    }
+
+    // Amounts ranging from the smallest unit to the largest representable values.
+    fn any_amount() -> impl Strategy<Value = Amount> {
+        prop_oneof![
+            (0i64..10_000_000).prop_map(|units| Decimal::new(units, 4).into()),
+            any::<u64>().prop_map(|units| Decimal::from(units).into()),
+            (0u32..4).prop_map(|offset| (Decimal::MAX - Decimal::from(offset)).into()),
+        ]
+    }
+
+    fn any_operation() -> impl Strategy<Value = Operation> {
+        // A small id space so that operations often reference previous transactions.
+        let id = 0u32..16;
+        prop_oneof![
+            (any_amount(), id.clone()).prop_map(|(amount, id)| Operation::Deposit(amount, id)),
+            (any_amount(), id.clone()).prop_map(|(amount, id)| Operation::Withdraw(amount, id)),
+            id.clone().prop_map(Operation::Dispute),
+            id.clone().prop_map(Operation::Resolve),
+            id.prop_map(Operation::Chargeback),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn should_never_panic_on_random_operations(
+            held in any_amount(),
+            total in prop_oneof![
+                any_amount(),
+                any_amount().prop_map(|amount| Amount::zero().checked_sub(amount).unwrap()),
+            ],
+            operations in proptest::collection::vec(any_operation(), 0..64),
+        ) {
+            let Ok(mut account) = Account::from_snapshot(1u16.into(), held, Amount::zero(), Amount::zero(), Amount::zero(), total, false) else {
+                return Ok(());
+            };
+
+            for operation in operations {
+                let before = (account.held, account.total, account.available());
+                let result = match operation {
+                    Operation::Deposit(amount, id) => account.deposit(amount, id.into()),
+                    Operation::Withdraw(amount, id) => account.withdraw(amount, id.into()),
+                    Operation::Dispute(id) => account.dispute(id.into(), None, None).map(|_| ()),
+                    Operation::Resolve(id) => account.resolve_dispute(id.into(), None).map(|_| ()),
+                    Operation::Chargeback(id) => account.chargeback(id.into(), None).map(|_| ()),
+                };
+
+                // A rejected operation leaves the balances as they were.
+                if result.is_err() {
+                    prop_assert_eq!(before, (account.held, account.total, account.available()));
+                }
+                prop_assert_eq!(
+                    Some(account.available()),
+                    account.total.checked_sub(account.held)
+                );
+            }
+        }
+    }
+
     /*
     #[test]
     fn should_create_negative_balance_on_withdrawal_disputes() {
@@ -694,7 +2815,7 @@ mod tests {
         assert_eq!(account.available(), 100.0.into());
         assert_eq!(account.held, Amount::zero());
 
-        assert!(account.dispute(3.into()).is_ok());
+        assert!(account.dispute(3.into(), None, None).is_ok());
     }
     */
 }
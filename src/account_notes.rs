@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Notes that operators attach to the accounts of clients, e.g. "client contacted 2024-05-02 about chargeback on tx
+// 991". The notes are appended to a CSV file and are never changed or removed, so the file is also an audit trail of
+// what was recorded about an account and when. The engine itself doesn't act on them.
+
+#[derive(Debug, Error)]
+pub enum AccountNotesError {
+    #[error("Cannot access the notes file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot read or write the notes file: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("A note can't be empty.")]
+    EmptyNote,
+}
+
+/// A note about an account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountNote {
+    pub client: u16,
+    /// The sub-account the note is about, e.g. `main`.
+    pub account: String,
+    /// Who wrote the note, if they said.
+    pub author: Option<String>,
+    /// When the note was added, in RFC 3339 format.
+    pub created_at: String,
+    pub note: String,
+}
+
+/// The notes of all the accounts. They are read when the file is opened and appended to it as they are added.
+#[derive(Debug)]
+pub struct AccountNotes {
+    path: PathBuf,
+    notes: BTreeMap<u16, Vec<AccountNote>>,
+}
+
+impl AccountNotes {
+    /// Open a notes file. The file is created when the first note is added.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, AccountNotesError> {
+        let path = path.as_ref().to_path_buf();
+        let mut notes: BTreeMap<u16, Vec<AccountNote>> = BTreeMap::new();
+        if path.exists() {
+            let mut reader = csv::Reader::from_path(&path)?;
+            for note in reader.deserialize::<AccountNote>() {
+                let note = note?;
+                notes.entry(note.client).or_default().push(note);
+            }
+        }
+        Ok(Self { path, notes })
+    }
+
+    /// Add a note to an account. The note is written to the file before it's returned.
+    pub fn add(
+        &mut self,
+        client: u16,
+        account: &str,
+        author: Option<&str>,
+        note: &str,
+        at: DateTime<Utc>,
+    ) -> Result<&AccountNote, AccountNotesError> {
+        let note = note.trim();
+        if note.is_empty() {
+            return Err(AccountNotesError::EmptyNote);
+        }
+        let note = AccountNote {
+            client,
+            account: account.to_string(),
+            author: author.map(str::to_string),
+            created_at: at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            note: note.to_string(),
+        };
+
+        let new_file = !self.path.exists();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(new_file)
+            .from_writer(file);
+        writer.serialize(&note)?;
+        writer.flush()?;
+
+        let notes = self.notes.entry(client).or_default();
+        notes.push(note);
+        Ok(notes.last().expect("The note was just added."))
+    }
+
+    /// The notes of a client, from the oldest to the newest.
+    pub fn of_client(&self, client: u16) -> &[AccountNote] {
+        self.notes
+            .get(&client)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_keep_the_notes_between_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.csv");
+        let at: DateTime<Utc> = "2024-05-02T09:30:00Z".parse().unwrap();
+
+        let mut notes = AccountNotes::open(&path).unwrap();
+        notes
+            .add(
+                1,
+                "main",
+                Some("alice"),
+                "Client contacted about the chargeback on tx 991, \"will send proof\".",
+                at,
+            )
+            .unwrap();
+        notes.add(2, "savings", None, "Closing soon.", at).unwrap();
+        notes.add(1, "main", None, "Proof received.\n", at).unwrap();
+        assert!(matches!(
+            notes.add(1, "main", None, "  ", at),
+            Err(AccountNotesError::EmptyNote)
+        ));
+
+        let reopened = AccountNotes::open(&path).unwrap();
+        let client = reopened.of_client(1);
+        assert_eq!(client.len(), 2);
+        assert_eq!(
+            client[0],
+            AccountNote {
+                client: 1,
+                account: "main".to_string(),
+                author: Some("alice".to_string()),
+                created_at: "2024-05-02T09:30:00.000Z".to_string(),
+                note: "Client contacted about the chargeback on tx 991, \"will send proof\"."
+                    .to_string(),
+            }
+        );
+        assert_eq!(client[1].note, "Proof received.");
+        assert_eq!(reopened.of_client(2)[0].account, "savings");
+        assert!(reopened.of_client(3).is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::SecondsFormat;
+use serde::Serialize;
+
+use crate::{
+    engine::WorkerId,
+    events::{AppliedEvent, EventSink},
+    provenance,
+    transaction_types::{AccountName, Amount, AmountFormat, ClientId},
+};
+
+// A stream of the account balances, written while the transactions are processed instead of once at the end. Every
+// applied transaction appends a row with the new balances of the account it changed, so the latest row of an account
+// is its current state. The rows are flushed as they are written, so the file can be tailed.
+//
+// Loaders that copy the rows to a database or a topic need a key that is the same when the same input is processed
+// again, e.g. after a crash, so they can upsert rather than duplicate the rows. `seq` depends on how the workers
+// interleave, but the updates of an account are applied by a single worker in the order of the input, so with a run
+// id the rows are keyed by the run id and the position of the update among the updates of its account.
+
+/// A row of the stream. Unlike the final output the columns are fixed, since they are written before it's known
+/// whether any client has sub-accounts.
+#[derive(Debug, Serialize)]
+struct UpdateRow<'a> {
+    /// Increases by one with every row, across all the workers.
+    seq: u64,
+    /// When the transaction was applied, in UTC.
+    timestamp: String,
+    client: ClientId,
+    account: &'a AccountName,
+    available: Amount,
+    held: Amount,
+    escrow: Amount,
+    total: Amount,
+    locked: bool,
+    /// The worker that applied the transaction, e.g. `worker-2`.
+    worker: WorkerId,
+    /// The run id, if one was given. Only written with a run id, like `account_seq`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<&'a str>,
+    /// Increases by one with every row of the account, starting at 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_seq: Option<u64>,
+    /// The rolling reserve of the account. Only written when clients have a rolling reserve, for the statements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserve: Option<Amount>,
+}
+
+struct UpdatesWriter {
+    writer: csv::Writer<Box<dyn Write + Send>>,
+    seq: u64,
+    run_id: Option<String>,
+    reserve: bool,
+    // The number of rows of every account, when the rows are keyed by a run id.
+    account_seqs: HashMap<(ClientId, AccountName), u64>,
+}
+
+/// Writes the account updates as CSV rows. Clones write to the same file so that every worker can have its own clone.
+#[derive(Clone)]
+pub(crate) struct AccountUpdates {
+    writer: Arc<Mutex<UpdatesWriter>>,
+}
+
+impl AccountUpdates {
+    /// With a run id, every row also gets the run id and its position among the rows of the account.
+    pub(crate) fn create<P: AsRef<Path>>(path: P, run_id: Option<String>) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        provenance::write_comment(&mut file, "#")?;
+        Ok(Self::new(Box::new(file), run_id))
+    }
+
+    fn new(writer: Box<dyn Write + Send>, run_id: Option<String>) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(UpdatesWriter {
+                writer: csv::Writer::from_writer(writer),
+                seq: 0,
+                run_id,
+                reserve: false,
+                account_seqs: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Adds the rolling reserve of the account to every row.
+    pub(crate) fn with_reserve(self) -> Self {
+        self.writer
+            .lock()
+            .expect("Account updates lock is never poisoned.")
+            .reserve = true;
+        self
+    }
+
+    fn write(&self, event: &AppliedEvent) -> Result<(), csv::Error> {
+        let mut guard = self
+            .writer
+            .lock()
+            .expect("Account updates lock is never poisoned.");
+        let updates = &mut *guard;
+        updates.seq += 1;
+        let account = &event.account;
+        let account_seq = match updates.run_id {
+            Some(_) => {
+                let seq = updates
+                    .account_seqs
+                    .entry((account.client, account.account.clone()))
+                    .or_default();
+                *seq += 1;
+                Some(*seq)
+            }
+            None => None,
+        };
+        let row = UpdateRow {
+            seq: updates.seq,
+            timestamp: event
+                .applied_at
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            client: account.client,
+            account: &account.account,
+            available: account.available,
+            held: account.held,
+            escrow: account.escrow,
+            total: account.total,
+            locked: account.locked,
+            worker: event.worker,
+            run_id: updates.run_id.as_deref(),
+            account_seq,
+            reserve: updates.reserve.then_some(account.reserve),
+        };
+        AmountFormat::output(|| updates.writer.serialize(row))?;
+        Ok(updates.writer.flush()?)
+    }
+}
+
+impl EventSink for AccountUpdates {
+    fn publish(&mut self, event: &AppliedEvent) {
+        if let Err(err) = self.write(event) {
+            eprintln!(
+                "Cannot write the update of transaction {} to the account updates: {}",
+                event.transaction_id, err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{account::AccountSnapshot, transaction_types::TransactionType};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_append_a_numbered_row_per_update() {
+        let buffer = SharedBuffer::default();
+        let updates = AccountUpdates::new(Box::new(buffer.clone()), None);
+        let event = |client: u16, total: f64| AppliedEvent {
+            transaction_type: TransactionType::Deposit,
+            transaction_id: 1.into(),
+            amount: total.into(),
+            release_to: None,
+            disputed: None,
+            worker: WorkerId::default(),
+            account: AccountSnapshot {
+                client: client.into(),
+                account: AccountName::default(),
+                available: total.into(),
+                held: Amount::zero(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: total.into(),
+                locked: false,
+            },
+            period: 1,
+            applied_at: "2024-03-01T12:00:00.250Z".parse().unwrap(),
+        };
+
+        // Each worker publishes to its own clone.
+        updates.clone().publish(&event(1, 1.5));
+        updates.clone().publish(&event(2, 3.0));
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let rows: Vec<Vec<&str>> = output
+            .lines()
+            .map(|line| line.split(',').collect())
+            .collect();
+        assert_eq!(
+            rows[0],
+            [
+                "seq",
+                "timestamp",
+                "client",
+                "account",
+                "available",
+                "held",
+                "escrow",
+                "total",
+                "locked",
+                "worker"
+            ]
+        );
+        assert_eq!(rows[1][0], "1");
+        assert_eq!(
+            rows[1][2..],
+            ["1", "main", "1.5", "0", "0", "1.5", "false", "worker-0"]
+        );
+        assert_eq!(rows[2][0], "2");
+        assert_eq!(
+            rows[2][2..],
+            ["2", "main", "3", "0", "0", "3", "false", "worker-0"]
+        );
+        assert_eq!(rows[2][1], "2024-03-01T12:00:00.250Z");
+    }
+
+    #[test]
+    fn should_key_rows_by_run_id_and_position_in_the_account() {
+        let buffer = SharedBuffer::default();
+        let updates = AccountUpdates::new(Box::new(buffer.clone()), Some("batch-7".to_string()));
+        let event = |client: u16| AppliedEvent {
+            transaction_type: TransactionType::Deposit,
+            transaction_id: 1.into(),
+            amount: 1.0.into(),
+            release_to: None,
+            disputed: None,
+            worker: WorkerId::default(),
+            account: AccountSnapshot {
+                client: client.into(),
+                account: AccountName::default(),
+                available: 1.0.into(),
+                held: Amount::zero(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: 1.0.into(),
+                locked: false,
+            },
+            period: 1,
+            applied_at: "2024-03-01T12:00:00Z".parse().unwrap(),
+        };
+
+        updates.clone().publish(&event(1));
+        updates.clone().publish(&event(2));
+        updates.clone().publish(&event(1));
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let keys: Vec<Vec<&str>> = output
+            .lines()
+            .map(|line| {
+                let fields: Vec<_> = line.split(',').collect();
+                vec![fields[0], fields[2], fields[10], fields[11]]
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ["seq", "client", "run_id", "account_seq"],
+                ["1", "1", "batch-7", "1"],
+                ["2", "2", "batch-7", "1"],
+                ["3", "1", "batch-7", "2"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use rust_decimal::{Decimal, prelude::ToPrimitive};
+use serde::{Serialize, Serializer};
+
+use crate::transaction_types::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+
+// The distribution of the amounts of the applied transactions, for the summary of a run. Keeping every amount to
+// compute exact percentiles would take as much memory as the transactions, so the amounts are counted in a histogram
+// whose buckets are 1% of a power of ten apart on a log scale, and the percentiles are the upper bound of their bucket
+// to 3 significant digits (within about 3% of the exact value). The mean and the standard deviation are kept as running values, which also
+// give the z-score of a deposit or withdrawal against the ones the worker applied before it. Deposits and withdrawals
+// that are far above the usual amounts of their type, or above an absolute amount, are flagged for a manual review.
+
+// Buckets of the histogram per power of ten.
+const BUCKETS_PER_DECADE: f64 = 100.0;
+// The number of amounts of a type below which the z-score of an amount is not meaningful.
+const MIN_Z_SCORE_SAMPLES: u64 = 30;
+// The most outliers that are listed in the summary printed on stderr. The run manifest has them all.
+const DISPLAYED_OUTLIERS: usize = 20;
+
+/// Which transactions are listed and flagged in the summary.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AmountReview {
+    /// The number of largest transactions that are listed.
+    pub(crate) largest: usize,
+    /// Flag the deposits and withdrawals whose z-score is at least this much.
+    pub(crate) z_score: Option<f64>,
+    /// Flag the deposits and withdrawals above this amount.
+    pub(crate) above: Option<Amount>,
+}
+
+/// The distribution of the amounts of a type of transaction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct AmountStats {
+    count: u64,
+    min: Amount,
+    max: Amount,
+    sum: Amount,
+    // The running mean and sum of the squared differences from the mean (Welford), for the standard deviation.
+    mean: f64,
+    squares: f64,
+    // The number of amounts by bucket of the log scale.
+    histogram: BTreeMap<i32, u64>,
+}
+
+impl AmountStats {
+    fn record(&mut self, amount: Amount) {
+        if self.count == 0 || amount < self.min {
+            self.min = amount;
+        }
+        if self.count == 0 || amount > self.max {
+            self.max = amount;
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(amount);
+        let value = to_f64(amount);
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squares += delta * (value - self.mean);
+        *self.histogram.entry(bucket(value)).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: &AmountStats) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            self.clone_from(other);
+            return;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum = self.sum.saturating_add(other.sum);
+        let count = (self.count + other.count) as f64;
+        let delta = other.mean - self.mean;
+        self.squares +=
+            other.squares + delta * delta * self.count as f64 * other.count as f64 / count;
+        self.mean += delta * other.count as f64 / count;
+        self.count += other.count;
+        for (bucket, count) in &other.histogram {
+            *self.histogram.entry(*bucket).or_default() += count;
+        }
+    }
+
+    fn mean(&self) -> Amount {
+        if self.count == 0 {
+            return Amount::zero();
+        }
+        rounded(Decimal::from(self.sum) / Decimal::from(self.count))
+    }
+
+    fn standard_deviation(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        (self.squares / (self.count - 1) as f64).sqrt()
+    }
+
+    // How many standard deviations the amount is above the mean, once there are enough amounts to tell.
+    fn z_score(&self, amount: Amount) -> Option<f64> {
+        let deviation = self.standard_deviation();
+        (self.count >= MIN_Z_SCORE_SAMPLES && deviation > 0.0)
+            .then(|| (to_f64(amount) - self.mean) / deviation)
+    }
+
+    /// The amount below which `percent` percent of the amounts are, rounded up to the bound of its bucket.
+    pub(crate) fn percentile(&self, percent: u32) -> Amount {
+        if percent == 0 || self.count == 0 {
+            return self.min;
+        }
+        let rank = (self.count * u64::from(percent)).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in &self.histogram {
+            seen += count;
+            if seen >= rank {
+                let bound = 10f64.powf(f64::from(bucket + 1) / BUCKETS_PER_DECADE);
+                // The bucket is only precise to about 3 significant digits.
+                let bound = Decimal::from_f64_retain(bound)
+                    .and_then(|bound| bound.round_sf(3))
+                    .map_or(self.max, rounded);
+                return bound.clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl Serialize for AmountStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Stats {
+            count: u64,
+            min: Amount,
+            p50: Amount,
+            p90: Amount,
+            p99: Amount,
+            max: Amount,
+            mean: Amount,
+            standard_deviation: f64,
+        }
+        Stats {
+            count: self.count,
+            min: self.min,
+            p50: self.percentile(50),
+            p90: self.percentile(90),
+            p99: self.percentile(99),
+            max: self.max,
+            mean: self.mean(),
+            standard_deviation: (self.standard_deviation() * 100.0).round() / 100.0,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A transaction listed in the summary for its amount.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct NotableAmount {
+    #[serde(rename = "type")]
+    pub(crate) transaction_type: TransactionType,
+    pub(crate) client: ClientId,
+    pub(crate) tx: TransactionId,
+    pub(crate) amount: Amount,
+    /// How unusual the amount was for its type when it was applied, for the flagged transactions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) z_score: Option<f64>,
+}
+
+impl Display for NotableAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} (client {}, tx {}",
+            self.transaction_type, self.amount, self.client, self.tx
+        )?;
+        if let Some(z_score) = self.z_score {
+            write!(f, ", z-score {:.1}", z_score)?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// The amounts of the applied transactions: their distribution by type, the largest ones and the flagged ones.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct AmountSummary {
+    pub(crate) by_type: BTreeMap<TransactionType, AmountStats>,
+    /// The largest transactions, largest first.
+    pub(crate) largest: Vec<NotableAmount>,
+    /// The deposits and withdrawals flagged for a manual review, in the order they were applied by each worker.
+    pub(crate) outliers: Vec<NotableAmount>,
+    // The number of largest transactions that are kept.
+    #[serde(skip)]
+    limit: usize,
+}
+
+impl AmountSummary {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.by_type.is_empty()
+    }
+
+    /// Count the amount of an applied transaction. The transaction is returned if it's flagged for a review.
+    pub(crate) fn record(
+        &mut self,
+        transaction: &Transaction,
+        review: &AmountReview,
+    ) -> Option<NotableAmount> {
+        let amount = transaction.amount()?;
+        let transaction_type = transaction.transaction_type();
+        let stats = self.by_type.entry(transaction_type).or_default();
+        let z_score = stats.z_score(amount);
+        stats.record(amount);
+        let mut notable = NotableAmount {
+            transaction_type,
+            client: transaction.client(),
+            tx: transaction.id(),
+            amount,
+            z_score: None,
+        };
+
+        self.limit = review.largest;
+        if self.limit > 0 {
+            self.keep_largest(notable.clone());
+        }
+
+        let reviewed = matches!(
+            transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        let unusual = review
+            .z_score
+            .zip(z_score)
+            .is_some_and(|(threshold, z_score)| z_score >= threshold);
+        let too_large = review.above.is_some_and(|above| amount > above);
+        if !reviewed || !(unusual || too_large) {
+            return None;
+        }
+        notable.z_score = z_score.map(|z_score| (z_score * 100.0).round() / 100.0);
+        self.outliers.push(notable.clone());
+        Some(notable)
+    }
+
+    // Add a transaction to the largest ones if it's large enough.
+    fn keep_largest(&mut self, notable: NotableAmount) {
+        let position = self
+            .largest
+            .partition_point(|largest| largest.amount >= notable.amount);
+        if position < self.limit {
+            self.largest.insert(position, notable);
+            self.largest.truncate(self.limit);
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &AmountSummary) {
+        for (transaction_type, stats) in &other.by_type {
+            self.by_type
+                .entry(*transaction_type)
+                .or_default()
+                .merge(stats);
+        }
+        self.limit = self.limit.max(other.limit);
+        for notable in &other.largest {
+            self.keep_largest(notable.clone());
+        }
+        self.outliers.extend(other.outliers.iter().cloned());
+    }
+}
+
+impl Display for AmountSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let by_type = self
+            .by_type
+            .iter()
+            .map(|(transaction_type, stats)| {
+                format!(
+                    "{} {} (min {}, p50 {}, p90 {}, p99 {}, max {}, mean {})",
+                    transaction_type,
+                    stats.count,
+                    stats.min,
+                    stats.percentile(50),
+                    stats.percentile(90),
+                    stats.percentile(99),
+                    stats.max,
+                    stats.mean()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "Amounts: {}", by_type)?;
+        if !self.largest.is_empty() {
+            write!(f, "\nLargest transactions: {}", list(&self.largest))?;
+        }
+        if !self.outliers.is_empty() {
+            write!(
+                f,
+                "\n!!! AMOUNT OUTLIERS: {} deposits and withdrawals need a manual review: {}",
+                self.outliers.len(),
+                list(&self.outliers[..self.outliers.len().min(DISPLAYED_OUTLIERS)])
+            )?;
+            if self.outliers.len() > DISPLAYED_OUTLIERS {
+                write!(f, " and {} more", self.outliers.len() - DISPLAYED_OUTLIERS)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn list(amounts: &[NotableAmount]) -> String {
+    amounts
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The amounts of the summary have the precision of the input.
+fn rounded(value: Decimal) -> Amount {
+    Amount::from(value.round_dp(4))
+}
+
+fn to_f64(amount: Amount) -> f64 {
+    Decimal::from(amount).to_f64().unwrap_or_default()
+}
+
+// The bucket of the histogram of an amount. Zero has a bucket of its own, below all the others.
+fn bucket(value: f64) -> i32 {
+    if value <= 0.0 {
+        return i32::MIN;
+    }
+    (value.log10() * BUCKETS_PER_DECADE).floor() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(tx: u32, amount: f64) -> Transaction {
+        Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            tx.into(),
+            Some(amount.into()),
+        )
+    }
+
+    #[test]
+    fn should_compute_the_distribution_of_the_amounts() {
+        let review = AmountReview::default();
+        let mut summary = AmountSummary::default();
+        let mut other = AmountSummary::default();
+        for tx in 1..=100 {
+            let half = if tx % 2 == 0 {
+                &mut summary
+            } else {
+                &mut other
+            };
+            half.record(&deposit(tx, f64::from(tx)), &review);
+        }
+        summary.merge(&other);
+
+        let stats = &summary.by_type[&TransactionType::Deposit];
+        assert_eq!(stats.count, 100);
+        assert_eq!((stats.min, stats.max), (1.0.into(), 100.0.into()));
+        assert_eq!(stats.mean(), 50.5.into());
+        assert!((stats.standard_deviation() - 29.0115).abs() < 0.001);
+        // The percentiles are within a bucket of the exact values.
+        let p90 = to_f64(stats.percentile(90));
+        assert!((90.0..=90.0 * 1.024).contains(&p90), "p90 is {}", p90);
+        assert_eq!(stats.percentile(100), 100.0.into());
+        assert_eq!(stats.percentile(0), 1.0.into());
+    }
+
+    #[test]
+    fn should_list_the_largest_and_flag_the_outliers() {
+        let review = AmountReview {
+            largest: 2,
+            z_score: Some(4.0),
+            above: Some(500.0.into()),
+        };
+        let mut summary = AmountSummary::default();
+        for tx in 1..=40 {
+            assert_eq!(
+                summary.record(&deposit(tx, 10.0 + f64::from(tx % 3)), &review),
+                None
+            );
+        }
+        let flagged = summary.record(&deposit(41, 400.0), &review).unwrap();
+        assert!(flagged.z_score.unwrap() > 4.0);
+        // Flagged by the absolute amount, however usual it became.
+        assert!(summary.record(&deposit(42, 600.0), &review).is_some());
+
+        assert_eq!(
+            summary
+                .largest
+                .iter()
+                .map(|notable| u32::from(notable.tx))
+                .collect::<Vec<_>>(),
+            vec![42, 41]
+        );
+        assert_eq!(summary.outliers.len(), 2);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+};
+
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+use sha2::{Digest, Sha256};
+
+use crate::transaction_types::ClientId;
+
+// Anonymization of input files, so that a file that shows a problem can be shared with support without sharing the
+// clients it's about. The client ids are replaced by pseudonyms with a keyed permutation of the client ids: the same key
+// gives the same pseudonym to a client in every file and two clients never get the same pseudonym, so the file still
+// reproduces the problem. Without the key, the pseudonyms can't be traced back to the clients. The amounts can be
+// scaled by a factor too, which keeps the balances in the same proportions.
+//
+// Everything else is copied as it is, including the header, the columns the engine doesn't know and the records that
+// can't be parsed, since they may be what causes the problem.
+
+// Number of rounds of the Feistel network. Every bit of a pseudonym depends on every bit of the id after three.
+const ROUNDS: u8 = 4;
+
+/// Maps client ids to pseudonyms with a keyed permutation of all the client ids.
+pub(crate) struct Pseudonymizer {
+    key: Vec<u8>,
+}
+
+impl Pseudonymizer {
+    pub(crate) fn new(key: &str) -> Self {
+        Self {
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    /// The pseudonym of a client. A balanced Feistel network over the two bytes of the id, so every id has its own
+    /// pseudonym.
+    pub(crate) fn client(&self, client: ClientId) -> ClientId {
+        let [mut left, mut right] = u16::from(client).to_be_bytes();
+        for round in 0..ROUNDS {
+            (left, right) = (right, left ^ self.round(round, right));
+        }
+        u16::from_be_bytes([left, right]).into()
+    }
+
+    fn round(&self, round: u8, half: u8) -> u8 {
+        let digest = Sha256::new()
+            .chain_update(&self.key)
+            .chain_update([round, half])
+            .finalize();
+        digest[0]
+    }
+}
+
+/// What was rewritten in a file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct AnonymizeStats {
+    pub(crate) records: u64,
+    /// Records without a valid client id, which were copied unchanged.
+    pub(crate) unchanged: u64,
+}
+
+impl Display for AnonymizeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Anonymized {} records", self.records)?;
+        if self.unchanged > 0 {
+            write!(
+                f,
+                ", {} records without a valid client id were copied unchanged and should be checked before sharing the file",
+                self.unchanged
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy an input file, replacing the client ids by their pseudonyms and multiplying the amounts by `scale`, if given.
+/// The columns are found by the header, or by their position in files without a header.
+pub(crate) fn anonymize<R: Read, W: Write>(
+    input: R,
+    output: W,
+    pseudonymizer: &Pseudonymizer,
+    scale: Option<Decimal>,
+) -> Result<AnonymizeStats, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(input);
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(output);
+
+    let (mut client_field, mut amount_field) = (1, 3);
+    let mut stats = AnonymizeStats::default();
+    let mut record = ByteRecord::new();
+    let mut first = true;
+    while reader.read_byte_record(&mut record)? {
+        if std::mem::take(&mut first)
+            && let Some(fields) = header(&record)
+        {
+            (client_field, amount_field) = fields;
+            writer.write_byte_record(&record)?;
+            continue;
+        }
+
+        stats.records += 1;
+        let Some(client) =
+            field(&record, client_field).and_then(|client| client.parse::<u16>().ok())
+        else {
+            stats.unchanged += 1;
+            writer.write_byte_record(&record)?;
+            continue;
+        };
+        let client = pseudonymizer.client(client.into()).to_string();
+        let amount = scale.and_then(|scale| {
+            let amount: Decimal = field(&record, amount_field)?.parse().ok()?;
+            Some(
+                amount
+                    .checked_mul(scale)?
+                    .round_dp(4)
+                    .normalize()
+                    .to_string(),
+            )
+        });
+
+        let rewritten: ByteRecord = record
+            .iter()
+            .enumerate()
+            .map(|(index, value)| match index {
+                index if index == client_field => client.as_bytes(),
+                index if index == amount_field && amount.is_some() => {
+                    amount.as_deref().unwrap_or_default().as_bytes()
+                }
+                _ => value,
+            })
+            .collect();
+        writer.write_byte_record(&rewritten)?;
+    }
+    writer.flush()?;
+    Ok(stats)
+}
+
+// The positions of the client and amount columns if the record is a header.
+fn header(record: &ByteRecord) -> Option<(usize, usize)> {
+    let position = |column: &str| {
+        record.iter().position(|name| {
+            std::str::from_utf8(name).is_ok_and(|name| name.trim().eq_ignore_ascii_case(column))
+        })
+    };
+    let client = position("client")?;
+    // A header without an amount column has no amounts to scale.
+    Some((client, position("amount").unwrap_or(usize::MAX)))
+}
+
+fn field(record: &ByteRecord, index: usize) -> Option<&str> {
+    record
+        .get(index)
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn run(input: &str, key: &str, scale: Option<Decimal>) -> (String, AnonymizeStats) {
+        let mut output = Vec::new();
+        let stats = anonymize(
+            input.as_bytes(),
+            &mut output,
+            &Pseudonymizer::new(key),
+            scale,
+        )
+        .unwrap();
+        (String::from_utf8(output).unwrap(), stats)
+    }
+
+    #[test]
+    fn should_map_every_client_to_its_own_pseudonym() {
+        let pseudonymizer = Pseudonymizer::new("secret");
+        let pseudonyms: HashSet<_> = (0..=u16::MAX)
+            .map(|client| pseudonymizer.client(client.into()))
+            .collect();
+
+        assert_eq!(pseudonyms.len(), 1 << 16);
+        assert_ne!(
+            pseudonymizer.client(1.into()),
+            Pseudonymizer::new("other").client(1.into())
+        );
+    }
+
+    #[test]
+    fn should_rewrite_clients_and_amounts_consistently() {
+        let pseudonymizer = Pseudonymizer::new("secret");
+        let (one, two) = (
+            pseudonymizer.client(1.into()),
+            pseudonymizer.client(2.into()),
+        );
+
+        let (output, stats) = run(
+            "Type, Amount, Client, TX, note\ndeposit, 1.5, 1, 1, a\ndispute,,1,1\nwithdrawal,2.0001,2,2\nbogus,1,x,3\n",
+            "secret",
+            Some(Decimal::new(3, 0)),
+        );
+
+        assert_eq!(
+            output,
+            format!(
+                "Type, Amount, Client, TX, note\ndeposit,4.5,{one}, 1, a\ndispute,,{one},1\nwithdrawal,6.0003,{two},2\nbogus,1,x,3\n"
+            )
+        );
+        assert_eq!(
+            stats,
+            AnonymizeStats {
+                records: 4,
+                unchanged: 1
+            }
+        );
+    }
+
+    #[test]
+    fn should_rewrite_files_without_header_by_position() {
+        let client = Pseudonymizer::new("secret").client(7.into());
+
+        let (output, _) = run("deposit,7,1,10\n", "secret", None);
+
+        assert_eq!(output, format!("deposit,{},1,10\n", client));
+    }
+}
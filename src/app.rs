@@ -0,0 +1,1001 @@
+use std::{
+    error::Error,
+    ffi::OsString,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{Local, TimeDelta};
+use clap::Parser as _;
+use rust_decimal::Decimal;
+
+use tokio::{
+    sync::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    NUM_WORKERS,
+    account::LockedOperations,
+    account_updates::AccountUpdates,
+    amount_stats::AmountReview,
+    anonymize::{self, Pseudonymizer},
+    archive::HistoryArchive,
+    backup,
+    blocklist::Blocklist,
+    bootstrap, cache_tuning,
+    cli::{ArchiveCommand, Cli, Command, ConfigCommand, SnapshotCommand},
+    client_trace::ClientTrace,
+    clock::SystemClock,
+    cluster::{Cluster, ShardMap},
+    cold_storage, config,
+    daemon::{self, DaemonOptions, EngineParts},
+    db_input,
+    dispute_policy::DisputePolicy,
+    engine::{Shard, ShardedEngine, WorkerId},
+    enrichment::{CurrencyNormalizer, Enrichers},
+    grpc_input,
+    id_history::CollisionGuard,
+    ingest::{self, Ingress, ReaderOptions},
+    input_profile::{self, ProfileConfig},
+    json::JsonAmounts,
+    kafka_input,
+    ledger::{LedgerSink, LedgerWriter},
+    logging::{self, LogSettings, Verbosity},
+    memory::MemoryReport,
+    merge,
+    metrics::{Metrics, MetricsExport},
+    monitoring::{AlertExport, ChargebackAlertPolicy, ChargebackMonitor},
+    object_input::ObjectInput,
+    output::{AccountFilter, AccountWriter, OutputColumns},
+    period::Periods,
+    pipeline::{
+        ActivityLimits, CurrencyValidator, DisputeRateValidator, MaxAmountValidator,
+        OrderingValidator, ReservedIdValidator, ShardValidator, ValidatorChain,
+        WithdrawalLimitValidator,
+    },
+    profiling::Profiler,
+    provenance::{self, Provenance},
+    registry::AccountRegistry,
+    rejects::RejectsReport,
+    reorder::ReorderWindow,
+    run_manifest::RunManifest,
+    settlement::Settlement,
+    snapshot,
+    spans::{SpanExport, Tracer},
+    state::{self, StateDir},
+    summary::Summary,
+    supervisor::{Escalation, Supervisor},
+    tcp_input,
+    transaction_processor::{
+        BalanceLimits, ProcessorMessage, ProcessorOptions, ReservePolicy, TransactionProcessor,
+    },
+    transaction_types::AmountFormat,
+    verify,
+    watch_dir::{self, AccountReport, ReportOptions},
+    ws_input,
+};
+
+// Exit status of a run in which some transactions failed because of internal errors, e.g. a failing transaction store.
+// These transactions can be processed again, unlike the rejected ones.
+const INTERNAL_ERRORS_EXIT_CODE: i32 = 3;
+// Exit status of a run that was stopped because a critical component failed, see `Supervisor`.
+const ESCALATION_EXIT_CODE: i32 = 4;
+
+// Stop the process because a critical component failed and could not be restarted.
+fn escalate(escalation: Escalation) -> ! {
+    eprintln!("Error: {}", escalation);
+    std::process::exit(ESCALATION_EXIT_CODE);
+}
+
+// A profiler for a task if profiling was requested, otherwise a profiler that does nothing.
+fn profiler(cli: &Cli, name: &str) -> Profiler {
+    match cli.profile {
+        Some(_) => Profiler::new(name),
+        None => Profiler::disabled(),
+    }
+}
+
+// Build the validator chain of a worker from the command line options.
+fn build_validator_chain(
+    cli: &Cli,
+    blocklist: &Blocklist,
+    dispute_policy: &DisputePolicy,
+) -> ValidatorChain {
+    let mut chain =
+        ValidatorChain::with_builtin_validators(blocklist.clone(), dispute_policy.allows_partial());
+    if let Some(max) = cli.max_transaction_amount {
+        chain = chain.with(MaxAmountValidator::new(max));
+    }
+    if let Some(currency) = &cli.currency {
+        chain = chain.with(CurrencyValidator::new(currency.clone()));
+    }
+    if let Some(shards) = shard_map(cli) {
+        chain = chain.with(ShardValidator::new(shards));
+    }
+    if cli.strict_ordering {
+        chain = chain.with(OrderingValidator::default());
+    }
+    if let Some(reserved) = cli.synthetic_ids {
+        chain = chain.with(ReservedIdValidator::new(reserved));
+    }
+    chain
+}
+
+// The limits on the recent activity of the clients, which are checked by the workers right before applying the
+// transactions.
+fn activity_limits(cli: &Cli) -> ActivityLimits {
+    let mut limits = ActivityLimits::new().with_window(cli.validation_window);
+    if let Some(max) = cli.max_withdrawn {
+        limits = limits.with(WithdrawalLimitValidator::new(max));
+    }
+    if let Some(max) = cli.max_disputes {
+        limits = limits.with(DisputeRateValidator::new(max));
+    }
+    limits
+}
+
+// The enrichers of the transactions, for the validators that rely on derived data.
+fn enrichers(cli: &Cli) -> Enrichers {
+    let mut enrichers = Enrichers::default();
+    if cli.currency.is_some() {
+        enrichers = enrichers.with(CurrencyNormalizer);
+    }
+    enrichers
+}
+
+// The shards of this node and of its peers in cluster mode.
+fn shard_map(cli: &Cli) -> Option<ShardMap> {
+    let (count, owned) = cli.shard_count.zip(cli.shards.clone())?;
+    Some(ShardMap::new(count, owned, cli.peers.clone()))
+}
+
+// The tasks that process transactions. A worker can handle transactions from multiple clients.
+// Each worker has a validation stage that feeds into an apply stage.
+struct Worker {
+    id: WorkerId,
+    validation_handle: JoinHandle<ValidatorChain>,
+    handle: JoinHandle<TransactionProcessor>,
+    tx: Sender<ProcessorMessage>,
+}
+
+// The end of stream barrier: wait until every worker applied all the transactions queued before and flushed its sinks
+// and reports.
+async fn flush_workers(workers: &[Worker]) -> std::io::Result<()> {
+    let mut replies = Vec::new();
+    for worker in workers {
+        let (reply, flushed) = oneshot::channel();
+        worker
+            .tx
+            .send(ProcessorMessage::Flush(reply))
+            .await
+            .map_err(|_| {
+                std::io::Error::other(format!(
+                    "{} stopped before the end of the input.",
+                    worker.id
+                ))
+            })?;
+        replies.push((worker.id, flushed));
+    }
+    for (id, flushed) in replies {
+        flushed.await.map_err(|_| {
+            std::io::Error::other(format!("{} stopped before it was flushed.", id))
+        })??;
+    }
+    Ok(())
+}
+
+fn run_archive_command(command: &ArchiveCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        ArchiveCommand::Export {
+            history,
+            dir,
+            retention,
+        } => {
+            cold_storage::export(history, dir, retention.policy())?;
+        }
+        ArchiveCommand::Import { history, archives } => {
+            for path in archives {
+                if path.is_dir() {
+                    for archive in cold_storage::archive_files(path)? {
+                        cold_storage::import(&archive, history)?;
+                    }
+                } else {
+                    cold_storage::import(path, history)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_config_command(command: &ConfigCommand) {
+    match command {
+        ConfigCommand::Check { options } => {
+            let program = OsString::from(env!("CARGO_PKG_NAME"));
+            let cli = Cli::try_parse_from(std::iter::once(program).chain(options.iter().cloned()))
+                .unwrap_or_else(|err| err.exit());
+            match config::check(&cli) {
+                Ok(()) => println!("The configuration is valid."),
+                Err(report) => {
+                    eprintln!("{}", report);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Run the engine with the options of the command line.
+pub async fn run() -> Result<(), Box<dyn Error>> {
+    let mut cli = Cli::parse();
+
+    LogSettings::set_global(LogSettings {
+        verbosity: if cli.quiet {
+            Verbosity::Quiet
+        } else if cli.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        },
+        sample_every: cli.log_sample,
+    })
+    .expect("Log settings are set only once.");
+    if let Some(scale) = cli.output_scale {
+        AmountFormat::set_global(AmountFormat::FixedScale(scale))
+            .expect("Amount format is set only once.");
+    }
+    JsonAmounts::set_global(cli.json_amounts).expect("JSON amount style is set only once.");
+    if cli.provenance {
+        Provenance::of(&cli)
+            .set_global()
+            .expect("Provenance is set only once.");
+    }
+    match &cli.command {
+        Some(Command::Anonymize {
+            input,
+            key,
+            scale_amounts,
+        }) => {
+            let stats = anonymize::anonymize(
+                std::fs::File::open(input)?,
+                std::io::stdout(),
+                &Pseudonymizer::new(key),
+                *scale_amounts,
+            )?;
+            eprintln!("{}", stats);
+            return Ok(());
+        }
+        Some(Command::Archive(command)) => return run_archive_command(command),
+        Some(Command::Snapshot(SnapshotCommand::Convert { input, output, to })) => {
+            let accounts = bootstrap::read_snapshots(input)?;
+            snapshot::convert(&accounts, output, *to)?;
+            eprintln!(
+                "Converted {} accounts to {}.",
+                accounts.len(),
+                output.display()
+            );
+            return Ok(());
+        }
+        Some(Command::Config(command)) => {
+            run_config_command(command);
+            return Ok(());
+        }
+        Some(Command::Merge {
+            outputs,
+            on_duplicate,
+        }) => {
+            let totals = merge::merge_outputs(outputs, *on_duplicate, std::io::stdout())?;
+            eprintln!("{}", totals);
+            return Ok(());
+        }
+        Some(Command::TuneCache { input, sample }) => {
+            let tuning = cache_tuning::tune(
+                input,
+                cli.encoding,
+                cli.compression,
+                cli.lenient_amounts,
+                *sample,
+            )?;
+            println!("{}", tuning);
+            return Ok(());
+        }
+        Some(Command::ProfileInput {
+            input,
+            sample,
+            options,
+        }) => {
+            let program = OsString::from(env!("CARGO_PKG_NAME"));
+            // The options are parsed as the options of a run of the input.
+            let arguments = [program, input.clone().into_os_string()];
+            let run = Cli::try_parse_from(arguments.into_iter().chain(options.iter().cloned()))
+                .unwrap_or_else(|err| err.exit());
+            let config = ProfileConfig {
+                workers: NUM_WORKERS,
+                partitioner: run.partition,
+                dispute_window: run.dispute_window,
+            };
+            let profile = input_profile::profile(
+                input,
+                run.encoding,
+                run.compression,
+                run.lenient_amounts,
+                *sample,
+                config,
+            )?;
+            println!("{}", profile);
+            return Ok(());
+        }
+        Some(Command::Verify { input, output }) => {
+            let verification = verify::verify(input, output)?;
+            println!("{}", verification);
+            if !verification.matches() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    // Report the problems of the options before anything is started, rather than failing in the middle of the run.
+    if let Err(report) = config::check(&cli) {
+        eprintln!("{}", report);
+        std::process::exit(1);
+    }
+    // Without a command, the input is either files or a database table. The patterns of the files are expanded once,
+    // so the run manifest lists the files that are read.
+    cli.transactions_files = ingest::expand_inputs(&cli.transactions_files)?;
+
+    // All the time dependent parts of the engine take the time from the same clock.
+    let clock = SystemClock::shared();
+    let mut run_manifest = match &cli.run_manifest {
+        Some(_) => Some(RunManifest::start(&cli, NUM_WORKERS, &clock)?),
+        None => None,
+    };
+    // The phases follow each other, but the transactions are applied while the input is parsed. The processing phase
+    // is what is left to apply once the input is read, and the daemon if it runs.
+    let mut memory = cli.report_memory.then(|| MemoryReport::start("startup"));
+
+    let processor_options = ProcessorOptions {
+        reject_unknown_clients: cli.reject_unknown_clients,
+        dispute_window: cli.dispute_window,
+        pause_policy: cli.pause_policy,
+        balance_limits: BalanceLimits {
+            default: cli.max_account_total,
+            clients: cli.client_max_totals.iter().copied().collect(),
+        },
+        require_dispute_source: cli.require_dispute_source,
+        max_disk_lookups: cli.max_disk_lookups,
+        locked_operations: LockedOperations::allowing(cli.locked_allow.iter().copied()),
+        unlock_on_chargeback_reversal: cli.unlock_on_chargeback_reversal,
+        auto_unlock_after: cli
+            .auto_unlock_after_days
+            .map(|days| TimeDelta::days(days.into())),
+        settlement_delay: cli
+            .settlement_delay_days
+            .map(|days| TimeDelta::days(days.into())),
+        reserves: ReservePolicy {
+            clients: cli.client_reserves.iter().copied().collect(),
+            period: TimeDelta::days(cli.reserve_days.into()),
+        },
+        dispute_reorder: cli.reorder_disputes.map(|transactions| ReorderWindow {
+            transactions,
+            timeout: cli
+                .reorder_timeout
+                .map(|seconds| TimeDelta::seconds(seconds.into())),
+        }),
+        amount_review: AmountReview {
+            largest: cli.largest_transactions,
+            z_score: cli.review_z_score,
+            above: cli.review_above,
+        },
+        dispute_policy: match &cli.dispute_policy {
+            Some(path) => DisputePolicy::from_path(path)?,
+            None => DisputePolicy::default(),
+        },
+    };
+    // In daemon mode, the workers keep the engine-wide totals of the accounts up to date for the API. The slots are
+    // added before bootstrapping so the bootstrapped accounts are counted.
+    let registry = cli.listen.map(|_| AccountRegistry::default());
+    let mut payment_workers: Vec<_> = (0..NUM_WORKERS)
+        .map(|index| {
+            let processor = TransactionProcessor::new(processor_options.clone())
+                .with_shard(Shard::new(index, NUM_WORKERS, cli.partition))
+                .with_clock(clock.clone());
+            match &registry {
+                Some(registry) => processor.with_registry(registry.worker()),
+                None => processor,
+            }
+        })
+        .collect();
+
+    // Start from the closing balances of a previous run if requested.
+    if let Some(bootstrap_file) = &cli.bootstrap {
+        let mut accounts = bootstrap::load_accounts(bootstrap_file)?;
+        if let Some(disputes) = &cli.bootstrap_disputes {
+            bootstrap::carry_disputes(&mut accounts, disputes)?;
+        }
+        for account in accounts {
+            payment_workers[Shard::of(account.client(), NUM_WORKERS, cli.partition).index()]
+                .insert_account(account);
+        }
+    }
+    // Or from a backup of a daemon, with the transactions of the accounts.
+    if let Some(backup) = &cli.restore_from
+        && let Some(state_dir) = &cli.state_dir
+    {
+        let restored = backup::restore(backup, state_dir).unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        });
+        eprintln!("{}", restored);
+        for account in restored.accounts {
+            payment_workers[Shard::of(account.client(), NUM_WORKERS, cli.partition).index()]
+                .insert_account(account);
+        }
+    }
+
+    let blocklist = match &cli.blocklist {
+        Some(path) => Blocklist::from_path(path)?,
+        None => Blocklist::default(),
+    };
+
+    // In daemon mode, the balance updates of the processors can be watched.
+    let watchers = cli.listen.map(|_| daemon::Watchers::new());
+
+    // The alerts are also written to the alert log and posted to the webhook, by a task of their own.
+    let (alerts, alert_export) = match (&cli.alert_log, &cli.alert_webhook) {
+        (None, None) => (None, None),
+        (log, webhook) => {
+            let (sink, export) = AlertExport::start(log.clone(), webhook.clone());
+            (Some(sink), Some(export))
+        }
+    };
+    let chargeback_monitor = cli.chargeback_alert_rate.map(|percent| {
+        let monitor = ChargebackMonitor::new(ChargebackAlertPolicy {
+            threshold: percent / Decimal::ONE_HUNDRED,
+            window: cli.chargeback_window,
+            withdrawal_only: cli.withdrawal_only_on_alert,
+        })
+        .with_clock(clock.clone());
+        match alerts {
+            Some(sink) => monitor.with_alerts(sink),
+            None => monitor,
+        }
+    });
+
+    // The ledger export is shared by all the workers.
+    let ledger = match &cli.ledger_export {
+        Some(path) => Some(Arc::new(Mutex::new(LedgerWriter::create(
+            path,
+            cli.ledger_format,
+            clock.now().with_timezone(&Local).date_naive(),
+            cli.ledger_commodity.clone(),
+        )?))),
+        None => None,
+    };
+
+    let account_updates = match &cli.account_updates {
+        Some(path) => {
+            let updates = AccountUpdates::create(path, cli.run_id.clone())?;
+            Some(match cli.client_reserves.is_empty() {
+                true => updates,
+                false => updates.with_reserve(),
+            })
+        }
+        None => None,
+    };
+
+    let history_archive = match &cli.history_archive {
+        Some(path) => Some(HistoryArchive::open(path)?),
+        None => None,
+    };
+
+    let rejects = match &cli.rejects {
+        Some(path) => Some(RejectsReport::create(path, cli.rejects_response_codes)?),
+        None => None,
+    };
+    let dead_letters = match &cli.dead_letters {
+        Some(path) => Some(RejectsReport::create(path, cli.rejects_response_codes)?),
+        None => None,
+    };
+
+    if let Some(dir) = &cli.profile {
+        std::fs::create_dir_all(dir)?;
+    }
+    // The trace is shared by the stages of the worker of the client.
+    let trace = match cli.trace_client {
+        Some(client) => Some(ClientTrace::create(
+            &cli.trace_file,
+            client.into(),
+            clock.clone(),
+        )?),
+        None => None,
+    };
+
+    let state = match &cli.state_dir {
+        Some(dir) => Some(StateDir::open(dir)?),
+        None => None,
+    };
+    // Check the ids allocated by the previous runs for the entries posted by the engine and report how many are left.
+    let synthetic_ids = match (&state, cli.synthetic_ids) {
+        (Some(state), Some(range)) => {
+            let synthetic_ids = state.synthetic_ids(range)?;
+            logging::log_event(
+                "synthetic_ids",
+                &[
+                    ("range", &synthetic_ids.range()),
+                    ("remaining", &synthetic_ids.remaining()),
+                ],
+            );
+            Some(Arc::new(Mutex::new(synthetic_ids)))
+        }
+        _ => None,
+    };
+    // The ids of the funding transactions of the previous runs, to check the transactions of this run against.
+    let id_history = match (&state, cli.id_collision_policy) {
+        (Some(state), Some(_)) => Some(Arc::new(state.id_history()?)),
+        _ => None,
+    };
+    // Continue the period numbering of the previous runs.
+    let periods = Arc::new(tokio::sync::Mutex::new(Periods::new(match &state {
+        Some(state) => Some(state.period_snapshots()?),
+        None => None,
+    })));
+    let period = periods.lock().await.current();
+
+    // We create a task for each worker. The workers own the accounts, so the supervisor never restarts them and stops
+    // the engine if one of them fails.
+    let supervisor = Supervisor::new();
+    // The metrics of the stages are pushed to the exporters while the engine runs.
+    let (metrics, metrics_export) = if cli.metrics_export.is_empty() {
+        (Metrics::default(), None)
+    } else {
+        let metrics = Metrics::recorded();
+        let export = MetricsExport::start(
+            metrics.clone(),
+            cli.metrics_export.clone(),
+            Duration::from_secs(cli.metrics_interval),
+            clock.clone(),
+        );
+        (metrics, Some(export))
+    };
+    // In daemon mode, the spans of the traced requests are exported.
+    let (tracer, span_export) = match &cli.span_export {
+        Some(exporter) => {
+            let (tracer, export) = SpanExport::start(exporter.clone());
+            (tracer, Some(export))
+        }
+        None => (Tracer::default(), None),
+    };
+    let mut workers = Vec::new();
+    for mut payment_worker in payment_workers {
+        payment_worker = payment_worker
+            .with_period(period)
+            .with_metrics(metrics.clone())
+            .with_tracer(tracer.clone());
+        if let Some(watchers) = &watchers {
+            payment_worker = payment_worker.with_sink(watchers.sink());
+        }
+        if let Some(monitor) = &chargeback_monitor {
+            payment_worker = payment_worker.with_chargeback_monitor(monitor.clone());
+        }
+        if let Some(ledger) = &ledger {
+            payment_worker = payment_worker.with_sink(LedgerSink::new(Arc::clone(ledger)));
+        }
+        if let Some(updates) = &account_updates {
+            payment_worker = payment_worker.with_sink(updates.clone());
+        }
+        if let Some(archive) = &history_archive {
+            payment_worker = payment_worker.with_history_archive(archive.clone());
+        }
+        if let (Some(policy), Some(history)) = (cli.id_collision_policy, &id_history) {
+            payment_worker = payment_worker.with_id_guard(CollisionGuard::new(
+                policy,
+                Arc::clone(history),
+                synthetic_ids.clone(),
+            ));
+        }
+        let (tx, rx) = mpsc::channel(1024); //TODO: fine-tune the size of the channel
+        let (validated_tx, validated_rx) = mpsc::channel(1024);
+        let id = payment_worker.worker();
+        let name = id.to_string();
+        payment_worker = payment_worker.with_profiler(profiler(&cli, &name));
+        let mut validator_chain =
+            build_validator_chain(&cli, &blocklist, &processor_options.dispute_policy)
+                .with_worker(id)
+                .with_profiler(profiler(&cli, &name))
+                .with_metrics(metrics.clone());
+        if let Some(rejects) = &rejects {
+            validator_chain = validator_chain.with_rejects(rejects.clone());
+            payment_worker = payment_worker.with_rejects(rejects.clone());
+        }
+        if let Some(dead_letters) = &dead_letters {
+            payment_worker = payment_worker.with_dead_letters(dead_letters.clone());
+        }
+        let limits = activity_limits(&cli);
+        if !limits.is_empty() {
+            payment_worker = payment_worker.with_limits(limits);
+        }
+        if let Some(trace) = &trace {
+            validator_chain = validator_chain.with_trace(trace.clone());
+            payment_worker = payment_worker.with_trace(trace.clone());
+        }
+        let worker = Worker {
+            id,
+            validation_handle: tokio::spawn(supervisor.watch(
+                format!("validator-{}", workers.len()),
+                true,
+                validator_chain.run(rx, validated_tx),
+            )),
+            handle: tokio::spawn(supervisor.watch(name, true, payment_worker.run(validated_rx))),
+            tx,
+        };
+        workers.push(worker);
+    }
+
+    // Skip the input files that were already processed according to the manifest of the state directory. A file that
+    // is given twice, e.g. by two overlapping patterns, is processed once. The objects of an object store are not
+    // downloaded twice to hash them, so they are always processed.
+    let mut manifest = match &state {
+        Some(state) => Some(state.manifest()?),
+        None => None,
+    };
+    let mut inputs: Vec<(&PathBuf, Option<String>, bool)> = Vec::new();
+    for file in &cli.transactions_files {
+        let digest = match &manifest {
+            Some(_) if ObjectInput::from_path(file).is_none() => Some(state::file_digest(file)?),
+            _ => None,
+        };
+        let already_processed = match (&manifest, &digest) {
+            (Some(manifest), Some(digest)) => {
+                manifest.contains(digest)
+                    || inputs
+                        .iter()
+                        .any(|(_, other, _)| other.as_ref() == Some(digest))
+            }
+            _ => false,
+        };
+        inputs.push((file, digest, already_processed));
+    }
+
+    // All the input goes through the fan-in of the input sources, which feeds the workers.
+    let engine = ShardedEngine::new(workers.iter().map(|worker| worker.tx.clone()).collect())
+        .with_partitioner(cli.partition);
+    // The transactions that can't be delivered to their peer are written with the ones that failed in the workers.
+    let cluster = shard_map(&cli).map(|shards| Cluster {
+        shards,
+        dead_letters: dead_letters.clone().or_else(|| rejects.clone()),
+    });
+    let (ingress, dispatcher) = Ingress::start(engine.clone(), cluster, enrichers(&cli));
+    let reader_options = ReaderOptions {
+        encoding: cli.encoding,
+        compression: cli.compression,
+        lenient_amounts: cli.lenient_amounts,
+        parse_workers: cli.parse_workers,
+        rate: cli.rate,
+    };
+
+    if let Some(memory) = &mut memory {
+        memory.next_phase("parsing");
+    }
+    let mut summary = Summary::default();
+    if let Some(input) = &cli.input {
+        let source = ingress.source("table");
+        if let Err(err) = db_input::ingest_table(input, &source).await {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        source.finish().await;
+    } else if let Some(input) = &cli.kafka {
+        // Consumed until the operator stops the engine, then the outputs are written as for a file.
+        let source = ingress.source("kafka");
+        let stop = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+        let consumed = tokio::select! {
+            consumed = kafka_input::ingest_topic(input, source, stop) => consumed,
+            escalation = supervisor.escalated() => escalate(escalation),
+        };
+        if let Err(err) = consumed {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(address) = cli.tcp_listen {
+        // Served until the operator stops the engine, then the outputs are written as for a file.
+        let stop = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+        let served = tokio::select! {
+            served = tcp_input::serve(address, &ingress, engine.clone(), cli.lenient_amounts, stop) => served,
+            escalation = supervisor.escalated() => escalate(escalation),
+        };
+        if let Err(err) = served {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(address) = cli.grpc_listen {
+        // Served until the operator stops the engine, then the outputs are written as for a file.
+        let stop = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+        let served = tokio::select! {
+            served = grpc_input::serve(address, &ingress, engine.clone(), stop) => served,
+            escalation = supervisor.escalated() => escalate(escalation),
+        };
+        if let Err(err) = served {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(address) = cli.ws_listen {
+        // Served until the operator stops the engine, then the outputs are written as for a file.
+        let stop = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+        let served = tokio::select! {
+            served = ws_input::serve(address, &ingress, cli.lenient_amounts, stop) => served,
+            escalation = supervisor.escalated() => escalate(escalation),
+        };
+        if let Err(err) = served {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    } else if !inputs.is_empty() {
+        // The files share a source, so their transactions reach the workers in the order of the files.
+        let source = ingress.source("file");
+        let mut profiler = profiler(&cli, "reader");
+        for (file, digest, already_processed) in &inputs {
+            if *already_processed && !cli.force {
+                logging::log_event(
+                    "file_skipped",
+                    &[
+                        ("path", &file.display()),
+                        ("sha256", &digest.as_deref().unwrap_or_default()),
+                        ("reason", &"already processed"),
+                    ],
+                );
+                continue;
+            }
+            // An input file that cannot be read is reported with its reason rather than the debug output of the error.
+            if let Err(err) =
+                ingest::ingest_file(file, reader_options, &source, &mut profiler).await
+            {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        // The period is closed, and the daemon started, only once all the transactions of the files are queued.
+        source.finish().await;
+        if let Some(dir) = &cli.profile {
+            profiler.write_to_dir(dir, "reader")?;
+        }
+    }
+
+    if let Some(memory) = &mut memory {
+        memory.next_phase("processing");
+    }
+    if cli.close_period {
+        periods.lock().await.close(&engine).await?;
+    }
+
+    let account_filter = AccountFilter {
+        omit_empty: cli.omit_empty_accounts,
+        only_locked: cli.only_locked,
+        only_negative: cli.only_negative,
+    };
+    let watch_report = cli.watch_report.clone().map(|path| ReportOptions {
+        path,
+        schema: cli.output_schema.clone(),
+        extended: cli.extended_report,
+        pending: cli.settlement_delay_days.is_some(),
+        reserve: !cli.client_reserves.is_empty(),
+        filter: account_filter,
+    });
+
+    // Without the daemon, the watched directory is ingested until the operator stops the engine, then the outputs
+    // are written as for a file.
+    if cli.listen.is_none()
+        && let Some(dir) = &cli.watch_dir
+    {
+        let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            let _ = tokio::signal::ctrl_c().await;
+            let _ = shutdown_tx.send(true);
+        });
+        let report = watch_report
+            .clone()
+            .map(|report| AccountReport::new(report, engine.clone()));
+        let source = ingress.source("watch-dir");
+        tokio::select! {
+            _ = watch_dir::watch(dir.clone(), cli.watch_concurrency, reader_options, source, shutdown, report) => {}
+            escalation = supervisor.escalated() => escalate(escalation),
+        }
+    }
+
+    // In daemon mode, keep the workers running and serve requests until the operator stops the engine.
+    if let Some(address) = cli.listen
+        && let Some(watchers) = &watchers
+        && let Some(registry) = &registry
+    {
+        let options = DaemonOptions {
+            address,
+            readiness_timeout: Duration::from_millis(cli.readiness_timeout),
+            watch_dir: cli.watch_dir.clone(),
+            watch_concurrency: cli.watch_concurrency,
+            watch_report,
+            backup_dir: cli.backup_dir.clone(),
+            reader: reader_options,
+            max_in_flight: cli.max_in_flight,
+        };
+        let parts = EngineParts {
+            blocklist: blocklist.clone(),
+            periods: Arc::clone(&periods),
+            ingress: ingress.clone(),
+            registry: registry.clone(),
+            supervisor: supervisor.clone(),
+            notes: match &state {
+                Some(state) => Some(state.notes()?),
+                None => None,
+            },
+            clock: clock.clone(),
+            tracer: tracer.clone(),
+            metrics: metrics.clone(),
+        };
+        tokio::select! {
+            served = daemon::serve(&options, engine, watchers, parts) => served?,
+            escalation = supervisor.escalated() => escalate(escalation),
+        }
+    }
+
+    // Wait for the transactions that are still queued by the input sources.
+    summary.parse_errors += ingress.parse_errors();
+    drop(ingress);
+    // The transactions that could not be forwarded are in the dead letters, to be processed again.
+    summary.internal += dispatcher.await?;
+    if let Some(escalation) = supervisor.escalation() {
+        escalate(escalation);
+    }
+
+    // Nothing is written out before every worker confirmed that all it received was applied and flushed.
+    flush_workers(&workers).await?;
+
+    // Finished reading all the transactions. Signal all workers to stop gracefully.
+    for worker in workers.iter() {
+        if let Err(e) = worker.tx.send(ProcessorMessage::shutdown()).await {
+            eprintln!("{}: Could not stop worker: error {}", worker.id, e);
+        }
+    }
+
+    // Wait for workers to finish and write out the results to stdout.
+    let mut payment_workers = Vec::new();
+    for worker in workers {
+        let mut profiler = Profiler::disabled();
+        match worker.validation_handle.await {
+            Ok(mut validator_chain) => {
+                summary.merge(validator_chain.summary());
+                profiler.merge(validator_chain.take_profiler());
+            }
+            Err(e) => eprintln!(
+                "{}: Validation stage encountered an error: {}",
+                worker.id, e
+            ),
+        }
+        match worker.handle.await {
+            Ok(mut payment_worker) => {
+                summary.merge(payment_worker.summary());
+                profiler.merge(payment_worker.take_profiler());
+                payment_workers.push(payment_worker);
+            }
+            Err(e) => eprintln!("{}: Payment worker encountered an error: {}", worker.id, e),
+        }
+        if let Some(dir) = &cli.profile {
+            profiler.write_to_dir(dir, &worker.id.to_string())?;
+        }
+    }
+    // The accounts of a failed worker are missing, so the outputs are not written.
+    if let Some(escalation) = supervisor.escalation() {
+        escalate(escalation);
+    }
+
+    if let Some(memory) = &mut memory {
+        memory.next_phase("output");
+    }
+    // With the v1 schema the sub-account column is only written when some client has a sub-account, so the output
+    // doesn't change otherwise.
+    let columns = cli.output_schema.columns(&OutputColumns {
+        account: payment_workers
+            .iter()
+            .any(|worker| worker.has_sub_accounts()),
+        escrow: cli.extended_report,
+        pending: cli.settlement_delay_days.is_some(),
+        reserve: cli.extended_report && !cli.client_reserves.is_empty(),
+    });
+    provenance::write_comment(&mut std::io::stdout(), "#")?;
+    let mut csv_writer = AccountWriter::new(std::io::stdout(), columns);
+    for payment_worker in &payment_workers {
+        payment_worker.write_csv_records(&mut csv_writer, &account_filter);
+    }
+
+    // Compare the closing balances with the ones of the previous run and keep them for the next run.
+    if let Some(state) = &state {
+        let accounts: Vec<_> = payment_workers
+            .iter()
+            .flat_map(TransactionProcessor::snapshots)
+            .collect();
+        let mut baseline = state.drift_baseline()?;
+        summary.drift = Some(baseline.update(&accounts, cli.drift_threshold));
+        baseline.write()?;
+    }
+
+    eprintln!("{}", summary);
+
+    if let Some(export) = metrics_export {
+        metrics.count("records.parse_errors", &[], summary.parse_errors);
+        let (accounts, locked) = payment_workers
+            .iter()
+            .flat_map(TransactionProcessor::snapshots)
+            .fold((0, 0), |(accounts, locked), account| {
+                (accounts + 1, locked + usize::from(account.locked))
+            });
+        metrics.gauge("accounts", &[], accounts as f64);
+        metrics.gauge("accounts.locked", &[], locked as f64);
+        export.finish().await;
+    }
+    if let Some(export) = span_export {
+        export.finish().await;
+    }
+    if let Some(export) = alert_export {
+        export.finish().await;
+    }
+
+    if let Some(path) = &cli.settlement_report {
+        let mut settlement = Settlement::default();
+        for payment_worker in &payment_workers {
+            settlement.merge(&payment_worker.settlement());
+        }
+        settlement.write_to_file(path)?;
+    }
+
+    // Keep the ids of the funding transactions of this run for the next runs.
+    if let Some(history) = &id_history {
+        for payment_worker in &mut payment_workers {
+            history.append(&payment_worker.take_applied_ids())?;
+        }
+    }
+
+    if let Some(manifest) = &mut manifest {
+        for (file, digest, already_processed) in &inputs {
+            if let Some(digest) = digest
+                && !already_processed
+            {
+                manifest.record(digest, file, clock.now())?;
+            }
+        }
+    }
+
+    // The workers are done with the history archive, so the compactions that are not retained can be moved out.
+    drop(history_archive);
+    if let (Some(history), Some(dir)) = (&cli.history_archive, &cli.archive_dir) {
+        cold_storage::export(history, dir, cli.retention.policy())?;
+    }
+
+    // Tell the scheduler that some transactions are worth retrying, unlike the ones that were rejected.
+    let exit_code = if summary.internal > 0 {
+        INTERNAL_ERRORS_EXIT_CODE
+    } else {
+        0
+    };
+    if let (Some(manifest), Some(path)) = (&mut run_manifest, &cli.run_manifest) {
+        if let Some(memory) = memory {
+            manifest.record_memory(memory.finish());
+        }
+        manifest.finish(path, &summary, exit_code, &clock)?;
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
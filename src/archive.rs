@@ -0,0 +1,252 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::{
+    account::{ArchivedTransaction, Compaction},
+    dispute::DisputeState,
+    transaction_types::{AccountName, Amount, ClientId, EscrowParty, TransactionId},
+};
+
+// The cold storage of the transactions that fell out of the dispute window of their account.
+// Every compaction appends the archived transactions of an account followed by a checkpoint of the account balances,
+// so that the history of an account can be rebuilt from the archive and the transactions that are still in the log.
+// The transactions of a compaction are in the order they were added to the log, which their `seq` tells as well.
+
+// The columns of the archive. An archive with other columns was written by an older version of the engine and can't be
+// appended to.
+const HEADER: &str = "record,client,account,tx,type,amount,dispute_state,released_to,available,held,escrow,total,seq,created_at,updated_at";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordKind {
+    Transaction,
+    Checkpoint,
+}
+
+// A line of the archive. Transactions fill in the transaction columns and checkpoints the balance columns.
+#[derive(Debug, Serialize)]
+struct ArchiveRecord<'a> {
+    record: RecordKind,
+    client: ClientId,
+    account: &'a AccountName,
+    /// The archived transaction, or the last transaction included in the balances of a checkpoint.
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    kind: Option<&'static str>,
+    amount: Option<Amount>,
+    dispute_state: Option<DisputeState>,
+    released_to: Option<EscrowParty>,
+    available: Option<Amount>,
+    held: Option<Amount>,
+    escrow: Option<Amount>,
+    total: Option<Amount>,
+    seq: Option<u64>,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+}
+
+impl<'a> ArchiveRecord<'a> {
+    fn transaction(
+        client: ClientId,
+        account: &'a AccountName,
+        transaction: &ArchivedTransaction,
+    ) -> Self {
+        Self {
+            record: RecordKind::Transaction,
+            client,
+            account,
+            tx: transaction.transaction_id,
+            kind: Some(transaction.kind),
+            amount: Some(transaction.amount),
+            dispute_state: Some(transaction.state),
+            released_to: transaction.released_to,
+            available: None,
+            held: None,
+            escrow: None,
+            total: None,
+            seq: Some(transaction.seq),
+            created_at: Some(timestamp(transaction.created_at)),
+            updated_at: Some(timestamp(transaction.updated_at)),
+        }
+    }
+
+    fn checkpoint(compaction: &'a Compaction) -> Self {
+        let checkpoint = &compaction.checkpoint;
+        Self {
+            record: RecordKind::Checkpoint,
+            client: checkpoint.client,
+            account: &checkpoint.account,
+            tx: compaction.last_transaction,
+            kind: None,
+            amount: None,
+            dispute_state: None,
+            released_to: None,
+            available: Some(checkpoint.available),
+            held: Some(checkpoint.held),
+            escrow: Some(checkpoint.escrow),
+            total: Some(checkpoint.total),
+            seq: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// A CSV file where the compacted transactions are archived. The file is appended to, so it can be kept between runs.
+/// Clones of the archive write to the same file so that every worker can have its own clone.
+#[derive(Clone)]
+pub(crate) struct HistoryArchive {
+    writer: Arc<Mutex<csv::Writer<Box<dyn Write + Send>>>>,
+}
+
+impl HistoryArchive {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let new_file = !path.as_ref().exists();
+        if !new_file {
+            check_header(path.as_ref())?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(Box::new(file), new_file))
+    }
+
+    fn new(writer: Box<dyn Write + Send>, header: bool) -> Self {
+        let writer = csv::WriterBuilder::new()
+            .has_headers(header)
+            .from_writer(writer);
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    /// Append the transactions of a compaction and the checkpoint of the balances.
+    /// The transactions can be removed from the log once this returns successfully.
+    pub(crate) fn record(&self, compaction: &Compaction) -> Result<(), csv::Error> {
+        let checkpoint = &compaction.checkpoint;
+        let mut writer = self.writer.lock().expect("Archive lock is never poisoned.");
+        for transaction in &compaction.transactions {
+            writer.serialize(ArchiveRecord::transaction(
+                checkpoint.client,
+                &checkpoint.account,
+                transaction,
+            ))?;
+        }
+        writer.serialize(ArchiveRecord::checkpoint(compaction))?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Check that an existing archive has the columns of the records that are appended to it. An empty file gets no header,
+// like a file that already has one.
+fn check_header(path: &Path) -> io::Result<()> {
+    let mut header = String::new();
+    io::BufReader::new(File::open(path)?).read_line(&mut header)?;
+    let header = header.trim_end();
+    if header.is_empty() || header == HEADER {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The history archive {} has other columns than `{}`. Archive to a new file.",
+                path.display(),
+                HEADER
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::account::AccountSnapshot;
+
+    use super::*;
+
+    // A writer that can be inspected after the archive was written.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_write_transactions_followed_by_checkpoint() {
+        let buffer = SharedBuffer::default();
+        let archive = HistoryArchive::new(Box::new(buffer.clone()), true);
+        let compaction = Compaction {
+            transactions: vec![ArchivedTransaction {
+                transaction_id: 1.into(),
+                kind: "deposit",
+                amount: 10.0.into(),
+                state: DisputeState::DisputeResolved,
+                released_to: None,
+                seq: 3,
+                created_at: "2024-03-01T12:00:00Z".parse().unwrap(),
+                updated_at: "2024-03-01T12:30:00.250Z".parse().unwrap(),
+            }],
+            checkpoint: AccountSnapshot {
+                client: 2.into(),
+                account: AccountName::default(),
+                available: 4.0.into(),
+                held: Amount::zero(),
+                escrow: 1.0.into(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: 5.0.into(),
+                locked: false,
+            },
+            last_transaction: 9.into(),
+        };
+
+        archive.record(&compaction).unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            format!(
+                "{}
+transaction,2,main,1,deposit,10,dispute_resolved,,,,,,3,2024-03-01T12:00:00.000Z,2024-03-01T12:30:00.250Z
+checkpoint,2,main,9,,,,,4,0,1,5,,,
+",
+                HEADER
+            )
+        );
+    }
+
+    #[test]
+    fn should_only_append_to_archives_with_the_same_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = dir.path().join("current.csv");
+        std::fs::write(&current, format!("{}\n", HEADER)).unwrap();
+        let older = dir.path().join("older.csv");
+        std::fs::write(
+            &older,
+            "record,client,account,tx,type,amount,dispute_state,released_to,available,held,escrow,total\n",
+        )
+        .unwrap();
+
+        assert!(HistoryArchive::open(&current).is_ok());
+        assert!(HistoryArchive::open(dir.path().join("new.csv")).is_ok());
+        assert_eq!(
+            HistoryArchive::open(&older).err().map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::{
+    account::{Account, AccountError},
+    bootstrap::{self, BootstrapError},
+    engine::{ShardedEngine, WorkerId},
+    logging::log_event,
+    snapshot::{self, SnapshotError},
+    state,
+    transaction_processor::ProcessorMessage,
+    transaction_types::{AccountName, ClientId},
+};
+
+// A copy of the state of a running daemon, taken without stopping it. The workers are asked for their copy one after
+// the other, through their queues: a worker copies the transaction stores and the balances of its accounts at that
+// point of its stream and applies nothing else until it's done, so only one worker at a time holds back its writes and
+// only for as long as its own copy takes. A client is only ever processed by one worker, so the copies are consistent
+// per client, but the workers are copied at slightly different points of the input.
+//
+// Layout of a backup:
+//   backup-<timestamp>/backup.json                       the version, when it was taken and the files of each worker
+//   backup-<timestamp>/<worker>/accounts.csv             the balances, in the format of the period snapshots
+//   backup-<timestamp>/<worker>/<client>-<account>.db    the transaction store of each account
+//
+// The manifest is written last, so a backup without one was interrupted. It has the SHA-256 hash of every file, which
+// is checked before a backup is restored. Restoring copies the backup to the state directory and opens the transaction
+// stores of the accounts there, so the transactions of the backup can still be disputed.
+
+const VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "backup.json";
+const ACCOUNTS_FILE: &str = "accounts.csv";
+// The subdirectory of the state directory where a backup is restored.
+const RESTORED_DIR: &str = "restored";
+
+#[derive(Debug, Error)]
+pub(crate) enum BackupError {
+    #[error("Cannot write the backup: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot back up the transactions of client {client}: {source}")]
+    Store {
+        client: ClientId,
+        source: AccountError,
+    },
+    #[error("Cannot write the accounts of the backup: {0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("The engine is shutting down.")]
+    Unavailable,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum RestoreError {
+    #[error("Cannot restore the backup: {0}")]
+    Io(#[from] io::Error),
+    #[error("The backup {path} is incomplete: {reason}.")]
+    Incomplete { path: PathBuf, reason: String },
+    #[error("The manifest of the backup is invalid: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("Unsupported version {0} of the backup.")]
+    UnsupportedVersion(u32),
+    #[error("The file {0} of the backup is corrupted: its checksum doesn't match.")]
+    ChecksumMismatch(PathBuf),
+    #[error("Cannot read the accounts of the backup: {0}")]
+    Accounts(#[from] BootstrapError),
+    #[error("Cannot open the transaction store of client {client}: {source}")]
+    Store {
+        client: ClientId,
+        source: AccountError,
+    },
+}
+
+/// The request sent through the worker queues to copy the state of a worker to a directory.
+#[derive(Debug)]
+pub(crate) struct BackupRequest {
+    pub(crate) dir: PathBuf,
+    pub(crate) reply: oneshot::Sender<Result<WorkerBackup, BackupError>>,
+}
+
+/// A file of a backup, relative to the backup directory.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BackupFile {
+    pub(crate) path: PathBuf,
+    pub(crate) sha256: String,
+}
+
+/// The transaction store of an account in a backup.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StoreBackup {
+    pub(crate) client: ClientId,
+    pub(crate) account: AccountName,
+    /// The number of transactions that were logged by the account.
+    pub(crate) logged: u64,
+    #[serde(flatten)]
+    pub(crate) file: BackupFile,
+}
+
+/// What a worker copied.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct WorkerBackup {
+    pub(crate) worker: String,
+    pub(crate) accounts: BackupFile,
+    pub(crate) stores: Vec<StoreBackup>,
+}
+
+impl WorkerBackup {
+    fn files(&self) -> impl Iterator<Item = &BackupFile> {
+        self.stores
+            .iter()
+            .map(|store| &store.file)
+            .chain([&self.accounts])
+    }
+}
+
+/// A backup of all the workers, as written to its manifest.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Backup {
+    pub(crate) version: u32,
+    pub(crate) path: PathBuf,
+    pub(crate) started_at: String,
+    pub(crate) workers: Vec<WorkerBackup>,
+}
+
+/// Copy the transaction stores and the balances of the accounts of a worker to its own subdirectory of `dir`.
+pub(crate) fn back_up_accounts<'a>(
+    worker: WorkerId,
+    accounts: impl Iterator<Item = &'a Account>,
+    dir: &Path,
+) -> Result<WorkerBackup, BackupError> {
+    let worker = worker.to_string();
+    fs::create_dir(dir.join(&worker))?;
+    let file = |path: PathBuf| -> io::Result<BackupFile> {
+        Ok(BackupFile {
+            sha256: state::file_digest(dir.join(&path))?,
+            path,
+        })
+    };
+    let mut snapshots = Vec::new();
+    let mut stores = Vec::new();
+    for account in accounts {
+        let path = Path::new(&worker).join(store_file(account.client(), account.name()));
+        account
+            .back_up_store(&dir.join(&path))
+            .map_err(|source| BackupError::Store {
+                client: account.client(),
+                source,
+            })?;
+        stores.push(StoreBackup {
+            client: account.client(),
+            account: account.name().clone(),
+            logged: account.logged(),
+            file: file(path)?,
+        });
+        snapshots.push(account.snapshot());
+    }
+    snapshots.sort_by(|a, b| (a.client, &a.account).cmp(&(b.client, &b.account)));
+    let accounts = Path::new(&worker).join(ACCOUNTS_FILE);
+    snapshot::write_csv(dir.join(&accounts), &snapshots)?;
+    Ok(WorkerBackup {
+        worker,
+        accounts: file(accounts)?,
+        stores,
+    })
+}
+
+// The file of the transaction store of an account, e.g. `17-main.db`.
+fn store_file(client: ClientId, account: &AccountName) -> String {
+    format!("{}-{}.db", client, account)
+}
+
+/// Back up the workers one after the other to a new `backup-<timestamp>` subdirectory of `dir`. The request is queued
+/// behind the transactions that were already sent to each worker.
+pub(crate) async fn back_up(
+    workers: &ShardedEngine,
+    dir: &Path,
+    now: DateTime<Utc>,
+) -> Result<Backup, BackupError> {
+    let path = dir.join(format!("backup-{}", now.format("%Y%m%dT%H%M%S%.3fZ")));
+    fs::create_dir_all(dir)?;
+    fs::create_dir(&path)?;
+
+    let mut backups = Vec::new();
+    for worker in workers.worker_ids() {
+        let (reply, backup) = oneshot::channel();
+        workers
+            .send_to_worker(
+                worker,
+                ProcessorMessage::Backup(BackupRequest {
+                    dir: path.clone(),
+                    reply,
+                }),
+            )
+            .await
+            .map_err(|_| BackupError::Unavailable)?;
+        backups.push(backup.await.map_err(|_| BackupError::Unavailable)??);
+    }
+
+    let backup = Backup {
+        version: VERSION,
+        path,
+        started_at: now.to_rfc3339_opts(SecondsFormat::Millis, true),
+        workers: backups,
+    };
+    let mut writer = BufWriter::new(File::create(backup.path.join(MANIFEST_FILE))?);
+    serde_json::to_writer_pretty(&mut writer, &backup).map_err(io::Error::from)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    log_event(
+        "backup_written",
+        &[
+            ("path", &backup.path.display()),
+            (
+                "accounts",
+                &backup
+                    .workers
+                    .iter()
+                    .map(|worker| worker.stores.len())
+                    .sum::<usize>(),
+            ),
+        ],
+    );
+    Ok(backup)
+}
+
+/// What was restored from a backup.
+#[derive(Debug)]
+pub(crate) struct Restored {
+    pub(crate) accounts: Vec<Account>,
+    /// Where the backup was copied to.
+    pub(crate) path: PathBuf,
+    pub(crate) started_at: String,
+    pub(crate) workers: usize,
+    pub(crate) files: usize,
+}
+
+impl Display for Restored {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Restored the backup taken at {}:", self.started_at)?;
+        writeln!(f, "  Accounts: {}", self.accounts.len())?;
+        writeln!(f, "  Workers: {}", self.workers)?;
+        writeln!(f, "  Files checked: {}", self.files)?;
+        write!(f, "  Copied to: {}", self.path.display())
+    }
+}
+
+/// Check a backup and copy it to the state directory, replacing the backup restored before, if any. The accounts are
+/// loaded with their balances and the copies of their transaction stores. Nothing is copied if a file of the backup is
+/// missing or corrupted.
+pub(crate) fn restore(backup: &Path, state_dir: &Path) -> Result<Restored, RestoreError> {
+    let incomplete = |reason: String| RestoreError::Incomplete {
+        path: backup.to_path_buf(),
+        reason,
+    };
+    let manifest = match fs::read_to_string(backup.join(MANIFEST_FILE)) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(incomplete(format!(
+                "{} is missing, the backup was interrupted",
+                MANIFEST_FILE
+            )));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // The version is checked first, another version may have another layout.
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+    let Version { version } = serde_json::from_str(&manifest)?;
+    if version != VERSION {
+        return Err(RestoreError::UnsupportedVersion(version));
+    }
+    let manifest: Backup = serde_json::from_str(&manifest)?;
+
+    let mut files = 0;
+    let mut snapshots = Vec::new();
+    let mut stores = HashMap::new();
+    for worker in &manifest.workers {
+        for file in worker.files() {
+            match state::file_digest(backup.join(&file.path)) {
+                Ok(sha256) if sha256 == file.sha256 => files += 1,
+                Ok(_) => return Err(RestoreError::ChecksumMismatch(file.path.clone())),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(incomplete(format!("{} is missing", file.path.display())));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        stores.extend(
+            worker
+                .stores
+                .iter()
+                .map(|store| ((store.client, store.account.clone()), store)),
+        );
+        snapshots.extend(bootstrap::read_snapshots(
+            backup.join(&worker.accounts.path),
+        )?);
+    }
+    if let Some(snapshot) = snapshots
+        .iter()
+        .find(|snapshot| !stores.contains_key(&(snapshot.client, snapshot.account.clone())))
+    {
+        return Err(incomplete(format!(
+            "the transaction store of account {} of client {} is missing",
+            snapshot.account, snapshot.client
+        )));
+    }
+
+    // The backup is copied next to the one restored before and replaces it once it's complete.
+    let path = state_dir.join(RESTORED_DIR);
+    let temporary = state_dir.join(format!("{}.tmp", RESTORED_DIR));
+    if temporary.exists() {
+        fs::remove_dir_all(&temporary)?;
+    }
+    fs::create_dir_all(&temporary)?;
+    for worker in &manifest.workers {
+        fs::create_dir(temporary.join(&worker.worker))?;
+        for file in worker.files() {
+            fs::copy(backup.join(&file.path), temporary.join(&file.path))?;
+        }
+    }
+    fs::copy(backup.join(MANIFEST_FILE), temporary.join(MANIFEST_FILE))?;
+    if path.exists() {
+        fs::remove_dir_all(&path)?;
+    }
+    fs::rename(&temporary, &path)?;
+
+    let mut accounts = Vec::with_capacity(snapshots.len());
+    for snapshot in snapshots {
+        let store = stores[&(snapshot.client, snapshot.account.clone())];
+        let account = Account::from_snapshot(
+            snapshot.client,
+            snapshot.held,
+            snapshot.escrow,
+            snapshot.pending,
+            snapshot.reserve,
+            snapshot.total,
+            snapshot.locked,
+        )
+        .and_then(|account| {
+            account
+                .with_name(snapshot.account)
+                .with_restored_store(&path.join(&store.file.path), store.logged)
+        })
+        .map_err(|source| RestoreError::Store {
+            client: snapshot.client,
+            source,
+        })?;
+        accounts.push(account);
+    }
+    log_event(
+        "backup_restored",
+        &[
+            ("backup", &backup.display()),
+            ("path", &path.display()),
+            ("accounts", &accounts.len()),
+        ],
+    );
+    Ok(Restored {
+        accounts,
+        path,
+        started_at: manifest.started_at,
+        workers: manifest.workers.len(),
+        files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transactions_cache::{BackingStore, SqliteKvStore, TransactionCache};
+    use chrono::TimeDelta;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        account::FundingLogEntry,
+        clock::ManualClock,
+        transaction_processor::{ProcessorOptions, TransactionProcessor},
+        transaction_types::{Amount, Transaction, TransactionId, TransactionType},
+    };
+
+    use super::*;
+
+    fn deposit(id: u32) -> ProcessorMessage {
+        ProcessorMessage::process_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            id.into(),
+            Some(1.0.into()),
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_back_up_the_accounts_as_of_the_queued_transactions() {
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(TransactionProcessor::new(ProcessorOptions::default()).run(rx));
+        tx.send(deposit(1)).await.unwrap();
+        // The transactions held while paused are not part of the backup.
+        tx.send(ProcessorMessage::Pause).await.unwrap();
+        tx.send(deposit(2)).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let now = ManualClock::at("2024-03-01T12:00:00Z").shared().now();
+
+        let backup = back_up(&ShardedEngine::new(vec![tx.clone()]), dir.path(), now)
+            .await
+            .unwrap();
+
+        assert_eq!(backup.path, dir.path().join("backup-20240301T120000.000Z"));
+        assert_eq!(backup.workers.len(), 1);
+        assert_eq!(backup.workers[0].stores.len(), 1);
+        assert_eq!(backup.workers[0].stores[0].logged, 1);
+        let accounts = fs::read_to_string(backup.path.join("worker-0/accounts.csv")).unwrap();
+        assert_eq!(accounts.lines().nth(1).unwrap(), "1,main,1,0,0,0,0,1,false");
+        let store = SqliteKvStore::new(backup.path.join("worker-0/1-main.db")).unwrap();
+        let transactions =
+            TransactionCache::<_, TransactionId, FundingLogEntry, 1>::with_store(store).unwrap();
+        assert!(transactions.contains_key(&1.into()).unwrap());
+        assert!(!transactions.contains_key(&2.into()).unwrap());
+        assert!(backup.path.join("backup.json").exists());
+
+        // A second backup at the same time can't overwrite the first one.
+        assert!(matches!(
+            back_up(&ShardedEngine::new(vec![tx.clone()]), dir.path(), now).await,
+            Err(BackupError::Io(_))
+        ));
+
+        tx.send(ProcessorMessage::Shutdown).await.unwrap();
+        let processor = worker.await.unwrap();
+        assert_eq!(processor.snapshots()[0].total, 2.0.into());
+    }
+
+    #[tokio::test]
+    async fn should_restore_a_complete_backup_only() {
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(TransactionProcessor::new(ProcessorOptions::default()).run(rx));
+        tx.send(deposit(1)).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let now = ManualClock::at("2024-03-01T12:00:00Z").shared().now();
+        let backup = back_up(&ShardedEngine::new(vec![tx.clone()]), dir.path(), now)
+            .await
+            .unwrap();
+        tx.send(ProcessorMessage::Shutdown).await.unwrap();
+        worker.await.unwrap();
+        let state_dir = dir.path().join("state");
+
+        let mut restored = restore(&backup.path, &state_dir).unwrap();
+
+        assert_eq!(restored.accounts.len(), 1);
+        assert_eq!(restored.files, 2);
+        assert_eq!(restored.path, state_dir.join("restored"));
+        // The transactions of the backup can be disputed.
+        let account = &mut restored.accounts[0];
+        assert_eq!(account.total(), 1.0.into());
+        assert!(account.dispute(1.into(), None, None).is_ok());
+        assert_eq!(account.held(), 1.0.into());
+
+        let store = backup.path.join("worker-0/1-main.db");
+        let bytes = fs::read(&store).unwrap();
+        fs::write(&store, b"corrupted").unwrap();
+        assert!(matches!(
+            restore(&backup.path, &state_dir),
+            Err(RestoreError::ChecksumMismatch(_))
+        ));
+        fs::remove_file(&store).unwrap();
+        assert!(matches!(
+            restore(&backup.path, &state_dir),
+            Err(RestoreError::Incomplete { .. })
+        ));
+        fs::write(&store, bytes).unwrap();
+        let manifest = backup.path.join("backup.json");
+        let contents = fs::read_to_string(&manifest).unwrap();
+        fs::write(
+            &manifest,
+            contents.replace("\"version\": 1", "\"version\": 2"),
+        )
+        .unwrap();
+        assert!(matches!(
+            restore(&backup.path, &state_dir),
+            Err(RestoreError::UnsupportedVersion(2))
+        ));
+        fs::remove_file(&manifest).unwrap();
+        assert!(matches!(
+            restore(&backup.path, &state_dir),
+            Err(RestoreError::Incomplete { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_restore_the_pending_funds_as_pending() {
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let options = || ProcessorOptions {
+            settlement_delay: Some(TimeDelta::days(2)),
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(
+            TransactionProcessor::new(options())
+                .with_clock(clock.shared())
+                .run(rx),
+        );
+        tx.send(deposit(1)).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let now = clock.shared().now();
+        let backup = back_up(&ShardedEngine::new(vec![tx.clone()]), dir.path(), now)
+            .await
+            .unwrap();
+        tx.send(ProcessorMessage::Shutdown).await.unwrap();
+        worker.await.unwrap();
+
+        let restored = restore(&backup.path, &dir.path().join("state")).unwrap();
+        let mut processor = TransactionProcessor::new(options()).with_clock(clock.shared());
+        for account in restored.accounts {
+            processor.insert_account(account);
+        }
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(processor.run(rx));
+        let engine = ShardedEngine::new(vec![tx]);
+        let withdraw = async |id: u32| {
+            let withdrawal = Transaction::new(
+                TransactionType::Withdrawal,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            );
+            engine
+                .send(1.into(), ProcessorMessage::process_transaction(withdrawal))
+                .await
+                .unwrap();
+            let accounts = engine.query_accounts(None).await;
+            (accounts[0].available, accounts[0].pending)
+        };
+
+        // The deposit of the backup didn't settle yet.
+        assert_eq!(withdraw(2).await, (Amount::zero(), 1.0.into()));
+        clock.advance(TimeDelta::days(2));
+        assert_eq!(withdraw(3).await, (Amount::zero(), Amount::zero()));
+        engine.shutdown().await.unwrap();
+        worker.await.unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use thiserror::Error;
+
+use crate::transaction_types::ClientId;
+
+#[derive(Debug, Error)]
+pub(crate) enum BlocklistError {
+    #[error("Cannot read the blocklist file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid client id '{value}' on line {line} of the blocklist file.")]
+    InvalidClient { line: usize, value: String },
+}
+
+/// The clients whose transactions are rejected, e.g. because of a sanctions screening.
+/// Clones share the same list so it can be changed while the engine is running.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Blocklist {
+    clients: Arc<RwLock<HashSet<ClientId>>>,
+}
+
+impl Blocklist {
+    /// Load the blocklist from a file with one client id per line. Empty lines and lines starting with `#` are ignored.
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, BlocklistError> {
+        let contents = fs::read_to_string(path)?;
+
+        let mut clients = HashSet::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let client = line
+                .parse::<u16>()
+                .map_err(|_| BlocklistError::InvalidClient {
+                    line: index + 1,
+                    value: line.to_string(),
+                })?;
+            clients.insert(client.into());
+        }
+
+        Ok(Self {
+            clients: Arc::new(RwLock::new(clients)),
+        })
+    }
+
+    pub(crate) fn contains(&self, client: ClientId) -> bool {
+        self.clients
+            .read()
+            .expect("Blocklist lock is never poisoned.")
+            .contains(&client)
+    }
+
+    /// Returns true if the client was not blocked before.
+    pub(crate) fn insert(&self, client: ClientId) -> bool {
+        self.clients
+            .write()
+            .expect("Blocklist lock is never poisoned.")
+            .insert(client)
+    }
+
+    /// Returns true if the client was blocked.
+    pub(crate) fn remove(&self, client: ClientId) -> bool {
+        self.clients
+            .write()
+            .expect("Blocklist lock is never poisoned.")
+            .remove(&client)
+    }
+
+    /// The blocked clients in ascending order.
+    pub(crate) fn clients(&self) -> Vec<ClientId> {
+        let mut clients: Vec<_> = self
+            .clients
+            .read()
+            .expect("Blocklist lock is never poisoned.")
+            .iter()
+            .copied()
+            .collect();
+        clients.sort();
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn should_load_blocklist_ignoring_comments() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"# sanctioned clients\n7\n\n 12 \n")
+            .unwrap();
+        file.flush().unwrap();
+
+        let blocklist = Blocklist::from_path(file.path()).unwrap();
+
+        assert_eq!(blocklist.clients(), vec![7.into(), 12.into()]);
+        assert!(!blocklist.contains(1.into()));
+    }
+
+    #[test]
+    fn should_reject_invalid_client_ids() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"7\nclient 8\n").unwrap();
+        file.flush().unwrap();
+
+        assert!(matches!(
+            Blocklist::from_path(file.path()),
+            Err(BlocklistError::InvalidClient { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn should_share_changes_between_clones() {
+        let blocklist = Blocklist::default();
+        let clone = blocklist.clone();
+
+        assert!(clone.insert(3.into()));
+        assert!(blocklist.contains(3.into()));
+        assert!(blocklist.remove(3.into()));
+        assert!(!clone.contains(3.into()));
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    account::{Account, AccountError, AccountSnapshot},
+    snapshot::{self, BinarySnapshot, SnapshotError},
+    transaction_types::{
+        AccountName, Amount, ClientId, DisputeSource, TransactionId, TransactionType,
+        deserialize_balance,
+    },
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum BootstrapError {
+    #[error("Cannot read the bootstrap file: {0}")]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error("Account {1} of client {0} appears more than once in the bootstrap file.")]
+    DuplicateClient(ClientId, AccountName),
+    #[error(
+        "Balances of client {0} are inconsistent: available should be equal to total - held - escrow - pending - reserve."
+    )]
+    InconsistentBalances(ClientId),
+    #[error("Cannot create the account of client {0}: {1}")]
+    Account(ClientId, AccountError),
+    #[error(
+        "Disputed transaction {2} is for account {1} of client {0}, which is not in the bootstrap file."
+    )]
+    UnknownAccount(ClientId, AccountName, TransactionId),
+    #[error("Cannot carry over disputed transaction {1} of client {0}: {2}")]
+    Dispute(ClientId, TransactionId, AccountError),
+    #[error(
+        "Held funds of account {1} of client {0} don't match its disputed transactions: {2} held, {3} disputed."
+    )]
+    HeldMismatch(ClientId, AccountName, Amount, Amount),
+}
+
+/// A row of the accounts CSV output. Balances may be negative as a result of chargebacks.
+#[derive(Debug, Deserialize)]
+struct AccountRecord {
+    client: ClientId,
+    /// The sub-account. The column is only present if the previous run had sub-accounts.
+    #[serde(default)]
+    account: AccountName,
+    #[serde(deserialize_with = "deserialize_balance")]
+    available: Amount,
+    #[serde(deserialize_with = "deserialize_balance")]
+    held: Amount,
+    /// The escrow column is only present in the extended report and in the period snapshots.
+    #[serde(default = "Amount::zero", deserialize_with = "deserialize_balance")]
+    escrow: Amount,
+    /// The pending column is only present when the previous run had a settlement delay.
+    #[serde(default = "Amount::zero", deserialize_with = "deserialize_balance")]
+    pending: Amount,
+    /// The reserve column is only present when the previous run had rolling reserves.
+    #[serde(default = "Amount::zero", deserialize_with = "deserialize_balance")]
+    reserve: Amount,
+    #[serde(deserialize_with = "deserialize_balance")]
+    total: Amount,
+    locked: bool,
+}
+
+/// A transaction of a previous run whose dispute is still open, listed in the disputes file of `--bootstrap-disputes`.
+#[derive(Debug, Deserialize)]
+struct DisputeRecord {
+    client: ClientId,
+    #[serde(default)]
+    account: AccountName,
+    tx: TransactionId,
+    /// `deposit` or `withdrawal`, deposits when the column is not there.
+    #[serde(default, rename = "type")]
+    disputed: DisputedType,
+    /// The disputed amount, which is part of the held funds of the account.
+    amount: Amount,
+    /// Who opened the dispute, if it's known.
+    #[serde(default)]
+    source: Option<DisputeSource>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DisputedType {
+    #[default]
+    Deposit,
+    Withdrawal,
+}
+
+/// Load the accounts from the output of a previous run so that processing continues from the closing balances.
+/// The transaction history is not part of the output so transactions from previous runs can't be disputed, except for
+/// those that were still disputed, see [`carry_disputes`].
+pub(crate) fn load_accounts<P: AsRef<Path>>(path: P) -> Result<Vec<Account>, BootstrapError> {
+    read_snapshots(path)?
+        .into_iter()
+        .map(|snapshot| {
+            Account::from_snapshot(
+                snapshot.client,
+                snapshot.held,
+                snapshot.escrow,
+                snapshot.pending,
+                snapshot.reserve,
+                snapshot.total,
+                snapshot.locked,
+            )
+            .map(|account| account.with_name(snapshot.account))
+            .map_err(|err| BootstrapError::Account(snapshot.client, err))
+        })
+        .collect()
+}
+
+/// Carry over the transactions of a previous run that are still disputed so that their held funds can be released by a
+/// resolve or a chargeback. The disputed amounts of every account must add up to its held funds, otherwise some held
+/// funds wouldn't be linked to a transaction and would stay held forever.
+pub(crate) fn carry_disputes<P: AsRef<Path>>(
+    accounts: &mut [Account],
+    path: P,
+) -> Result<(), BootstrapError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_path(path)?;
+
+    let index: HashMap<_, _> = accounts
+        .iter()
+        .enumerate()
+        .map(|(index, account)| ((account.client(), account.name().clone()), index))
+        .collect();
+    let mut disputed = vec![Amount::zero(); accounts.len()];
+    for record in reader.deserialize::<DisputeRecord>() {
+        let record = record?;
+        let Some(&index) = index.get(&(record.client, record.account.clone())) else {
+            return Err(BootstrapError::UnknownAccount(
+                record.client,
+                record.account,
+                record.tx,
+            ));
+        };
+        let transaction_type = match record.disputed {
+            DisputedType::Deposit => TransactionType::Deposit,
+            DisputedType::Withdrawal => TransactionType::Withdrawal,
+        };
+        accounts[index]
+            .carry_dispute(record.tx, transaction_type, record.amount, record.source)
+            .map_err(|err| BootstrapError::Dispute(record.client, record.tx, err))?;
+        disputed[index] =
+            disputed[index]
+                .checked_add(record.amount)
+                .ok_or(BootstrapError::Dispute(
+                    record.client,
+                    record.tx,
+                    AccountError::BalanceOutOfRange,
+                ))?;
+    }
+
+    for (account, disputed) in accounts.iter().zip(disputed) {
+        if account.held() != disputed {
+            return Err(BootstrapError::HeldMismatch(
+                account.client(),
+                account.name().clone(),
+                account.held(),
+                disputed,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read the balances of the accounts from an output or a snapshot, in CSV or in the binary snapshot format, checking
+/// that every account is there once and that its balances add up.
+pub(crate) fn read_snapshots<P: AsRef<Path>>(
+    path: P,
+) -> Result<Vec<AccountSnapshot>, BootstrapError> {
+    let snapshots = if snapshot::is_binary(&path).map_err(SnapshotError::from)? {
+        BinarySnapshot::open(path)?
+            .accounts()
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        read_csv(path)?
+    };
+
+    let mut clients = HashSet::new();
+    for snapshot in &snapshots {
+        if !clients.insert((snapshot.client, &snapshot.account)) {
+            return Err(BootstrapError::DuplicateClient(
+                snapshot.client,
+                snapshot.account.clone(),
+            ));
+        }
+
+        if snapshot
+            .total
+            .checked_sub(snapshot.held)
+            .and_then(|balance| balance.checked_sub(snapshot.escrow))
+            .and_then(|balance| balance.checked_sub(snapshot.pending))
+            .and_then(|balance| balance.checked_sub(snapshot.reserve))
+            != Some(snapshot.available)
+        {
+            return Err(BootstrapError::InconsistentBalances(snapshot.client));
+        }
+    }
+    Ok(snapshots)
+}
+
+fn read_csv<P: AsRef<Path>>(path: P) -> Result<Vec<AccountSnapshot>, BootstrapError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_path(path)?;
+
+    let mut snapshots = Vec::new();
+    for record in reader.deserialize::<AccountRecord>() {
+        let record = record?;
+        snapshots.push(AccountSnapshot {
+            client: record.client,
+            account: record.account,
+            available: record.available,
+            held: record.held,
+            escrow: record.escrow,
+            pending: record.pending,
+            reserve: record.reserve,
+            total: record.total,
+            locked: record.locked,
+        });
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use chrono::TimeDelta;
+    use tempfile::NamedTempFile;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        clock::ManualClock,
+        engine::ShardedEngine,
+        transaction_processor::{
+            ProcessorMessage, ProcessorOptions, ReservePolicy, TransactionProcessor,
+        },
+        transaction_types::Transaction,
+    };
+
+    use super::*;
+
+    fn bootstrap_file(data: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    #[test]
+    fn should_load_accounts_from_previous_output() {
+        let file = bootstrap_file(
+            "client,available,held,total,locked
+             1,1.5,0,1.5,false
+             2,-200,0,-200,true
+             3,100,50.25,150.25,false",
+        );
+
+        let accounts = load_accounts(file.path()).unwrap();
+
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].client(), 1.into());
+        assert_eq!(accounts[0].available(), 1.5.into());
+        assert_eq!(accounts[1].available(), (-200.0).into());
+        assert!(accounts[1].is_locked());
+        assert_eq!(accounts[2].available(), 100.0.into());
+        assert_eq!(accounts[2].held(), 50.25.into());
+    }
+
+    #[test]
+    fn should_reject_duplicate_clients() {
+        let file = bootstrap_file(
+            "client,available,held,total,locked
+             1,1.5,0,1.5,false
+             1,2,0,2,false",
+        );
+
+        assert!(matches!(
+            load_accounts(file.path()),
+            Err(BootstrapError::DuplicateClient(..))
+        ));
+    }
+
+    #[test]
+    fn should_load_sub_accounts() {
+        let file = bootstrap_file(
+            "client,account,available,held,total,locked
+             1,main,1.5,0,1.5,false
+             1,savings,2,0,2,false",
+        );
+
+        let accounts = load_accounts(file.path()).unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert!(accounts[0].name().is_main());
+        assert_eq!(accounts[1].name().to_string(), "savings");
+        assert_eq!(accounts[1].total(), 2.0.into());
+    }
+
+    #[test]
+    fn should_load_accounts_from_a_binary_snapshot() {
+        let file = bootstrap_file(
+            "client,account,available,held,escrow,total,locked
+             1,main,1.5,0,0,1.5,false
+             1,savings,-2,1,0,-1,true",
+        );
+        let binary = NamedTempFile::new().unwrap();
+        let snapshots = read_snapshots(file.path()).unwrap();
+        snapshot::write_binary(binary.path(), &snapshots).unwrap();
+
+        let accounts = load_accounts(binary.path()).unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].name().to_string(), "savings");
+        assert_eq!(accounts[1].available(), (-2.0).into());
+        assert!(accounts[1].is_locked());
+    }
+
+    #[test]
+    fn should_carry_over_the_open_disputes() {
+        let file = bootstrap_file(
+            "client,available,held,total,locked
+             1,10,5,15,false",
+        );
+        let disputes = bootstrap_file(
+            "client,tx,type,amount
+             1,7,deposit,3
+             1,8,withdrawal,2",
+        );
+        let mut accounts = load_accounts(file.path()).unwrap();
+
+        carry_disputes(&mut accounts, disputes.path()).unwrap();
+        accounts[0].resolve_dispute(7.into(), None).unwrap();
+        accounts[0].resolve_dispute(8.into(), None).unwrap();
+
+        assert_eq!(accounts[0].held(), Amount::zero());
+        assert_eq!(accounts[0].available(), 13.0.into());
+        assert_eq!(accounts[0].total(), 13.0.into());
+    }
+
+    #[test]
+    fn should_reject_disputes_that_dont_match_the_held_funds() {
+        let file = bootstrap_file(
+            "client,available,held,total,locked
+             1,10,5,15,false
+             2,10,0,10,false",
+        );
+        let disputes = bootstrap_file(
+            "client,tx,amount
+             1,7,3",
+        );
+        let mut accounts = load_accounts(file.path()).unwrap();
+
+        assert!(matches!(
+            carry_disputes(&mut accounts, disputes.path()),
+            Err(BootstrapError::HeldMismatch(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_pending_funds_pending_until_they_settle() {
+        let file = bootstrap_file(
+            "client,available,held,pending,total,locked
+             1,5,0,3,8,false",
+        );
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            settlement_delay: Some(TimeDelta::days(2)),
+            ..Default::default()
+        })
+        .with_clock(clock.shared());
+        for account in load_accounts(file.path()).unwrap() {
+            processor.insert_account(account);
+        }
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(processor.run(rx));
+        let engine = ShardedEngine::new(vec![tx]);
+        let withdraw = async |id: u32| {
+            let withdrawal = Transaction::new(
+                TransactionType::Withdrawal,
+                1.into(),
+                id.into(),
+                Some(7.0.into()),
+            );
+            engine
+                .send(1.into(), ProcessorMessage::process_transaction(withdrawal))
+                .await
+                .unwrap();
+            let accounts = engine.query_accounts(None).await;
+            (accounts[0].available, accounts[0].pending)
+        };
+
+        // The withdrawal needs the pending funds.
+        assert_eq!(withdraw(1).await, (5.0.into(), 3.0.into()));
+        clock.advance(TimeDelta::days(2));
+        assert_eq!(withdraw(2).await, (1.0.into(), Amount::zero()));
+        engine.shutdown().await.unwrap();
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_reserve_until_its_period_is_over() {
+        let file = bootstrap_file(
+            "client,available,held,pending,reserve,total,locked
+             1,5,0,3,2,10,false",
+        );
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            settlement_delay: Some(TimeDelta::days(2)),
+            reserves: ReservePolicy {
+                clients: HashMap::from([(1.into(), 10.into())]),
+                period: TimeDelta::days(90),
+            },
+            ..Default::default()
+        })
+        .with_clock(clock.shared());
+        for account in load_accounts(file.path()).unwrap() {
+            processor.insert_account(account);
+        }
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(processor.run(rx));
+        let engine = ShardedEngine::new(vec![tx]);
+        let withdraw = async |id: u32| {
+            let withdrawal = Transaction::new(
+                TransactionType::Withdrawal,
+                1.into(),
+                id.into(),
+                Some(9.0.into()),
+            );
+            engine
+                .send(1.into(), ProcessorMessage::process_transaction(withdrawal))
+                .await
+                .unwrap();
+            let accounts = engine.query_accounts(None).await;
+            (accounts[0].available, accounts[0].reserve)
+        };
+
+        // The withdrawal needs the reserve, which is still withheld once the pending funds settled.
+        assert_eq!(withdraw(1).await, (5.0.into(), 2.0.into()));
+        clock.advance(TimeDelta::days(2));
+        assert_eq!(withdraw(2).await, (8.0.into(), 2.0.into()));
+        clock.advance(TimeDelta::days(88));
+        assert_eq!(withdraw(3).await, (1.0.into(), Amount::zero()));
+        engine.shutdown().await.unwrap();
+        worker.await.unwrap();
+    }
+
+    #[test]
+    fn should_reject_inconsistent_balances() {
+        let file = bootstrap_file(
+            "client,available,held,total,locked
+             1,10,1,10,false",
+        );
+
+        assert!(matches!(
+            load_accounts(file.path()),
+            Err(BootstrapError::InconsistentBalances(_))
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fmt::Display,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use encoding_rs::Encoding;
+use thiserror::Error;
+
+use crate::{
+    account::{CACHE_CAPACITY, FundingLogEntry},
+    csv_reader::{Compression, CsvFileReader, ReaderError},
+    pipeline::Parser,
+    transaction_types::{ClientId, Transaction, TransactionId, TransactionType},
+    transactions_cache::{self, CacheError, SqliteKvStore, TransactionCache},
+};
+
+// Sizing of the transaction caches of the accounts. Every account keeps its most recent transactions in memory and the
+// older ones in the backing store, so the capacity of the cache trades memory for the disputes that have to look their
+// transaction up on disk. Which capacity is right depends on the input: how many clients share the memory and how far
+// back their disputes go. The tuning replays a sample of an input against caches of several capacities and recommends
+// the smallest one that finds almost every disputed transaction in memory.
+//
+// The capacity is a const generic of the cache, so it's chosen when the engine is built (see `CACHE_CAPACITY`) and
+// can't be changed by a run.
+
+// Share of the lookups that may go to the backing store at the recommended capacity, on top of the ones that go there
+// at the largest capacity.
+const TOLERANCE: f64 = 0.01;
+
+#[derive(Debug, Error)]
+pub(crate) enum TuningError {
+    #[error(transparent)]
+    Reader(#[from] ReaderError),
+    #[error("Transaction cache error: {0}")]
+    Cache(#[from] CacheError),
+}
+
+/// How a sample performed with a cache capacity.
+#[derive(Debug)]
+pub(crate) struct CapacityRun {
+    pub(crate) capacity: usize,
+    /// Lookups of logged transactions by disputes, resolves, chargebacks and escrow releases.
+    pub(crate) lookups: u64,
+    /// Lookups that had to go to the backing store.
+    pub(crate) store_lookups: u64,
+    /// Transactions in memory at the end of the sample, over all the clients.
+    pub(crate) in_memory: u64,
+    pub(crate) elapsed: Duration,
+    /// Time spent in the backing store.
+    pub(crate) store_time: Duration,
+}
+
+/// The comparison of the capacities for a sample.
+#[derive(Debug)]
+pub(crate) struct Tuning {
+    pub(crate) transactions: usize,
+    pub(crate) clients: usize,
+    pub(crate) runs: Vec<CapacityRun>,
+}
+
+impl Tuning {
+    /// The smallest capacity whose lookups go to the backing store at most `TOLERANCE` more often than with the
+    /// largest capacity.
+    pub(crate) fn recommended(&self) -> Option<usize> {
+        let largest = self.runs.last()?;
+        let allowed = largest.store_lookups + (largest.lookups as f64 * TOLERANCE).ceil() as u64;
+        self.runs
+            .iter()
+            .find(|run| run.store_lookups <= allowed)
+            .map(|run| run.capacity)
+    }
+}
+
+impl Display for Tuning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Replayed {} transactions of {} clients.",
+            self.transactions, self.clients
+        )?;
+        writeln!(
+            f,
+            "{:>8}  {:>8}  {:>13}  {:>9}  {:>10}  {:>10}",
+            "capacity", "lookups", "store lookups", "in memory", "time", "store time"
+        )?;
+        for run in &self.runs {
+            writeln!(
+                f,
+                "{:>8}  {:>8}  {:>13}  {:>9}  {:>10}  {:>10}",
+                run.capacity,
+                run.lookups,
+                run.store_lookups,
+                run.in_memory,
+                format!("{:.1?}", run.elapsed),
+                format!("{:.1?}", run.store_time)
+            )?;
+        }
+        match self.recommended() {
+            Some(capacity) => write!(
+                f,
+                "Recommended capacity: {} (the engine is built with {}).",
+                capacity, CACHE_CAPACITY
+            ),
+            None => write!(f, "No capacity was compared."),
+        }
+    }
+}
+
+/// Replay the first `sample` transactions of an input file against caches of several capacities. Records that can't
+/// be parsed are left out of the sample.
+pub(crate) fn tune<P: AsRef<Path>>(
+    path: P,
+    encoding: Option<&'static Encoding>,
+    compression: Compression,
+    lenient_amounts: bool,
+    sample: usize,
+) -> Result<Tuning, TuningError> {
+    let mut reader = CsvFileReader::from_path_with_compression(path, encoding, compression)?
+        .with_lenient_amounts(lenient_amounts);
+    let transactions: Vec<Transaction> = reader
+        .transactions()
+        .take(sample)
+        .filter_map(Result::ok)
+        .collect();
+    compare(&transactions)
+}
+
+// Replay the transactions with every capacity, from the smallest to the largest.
+fn compare(transactions: &[Transaction]) -> Result<Tuning, TuningError> {
+    let clients: HashSet<ClientId> = transactions.iter().map(Transaction::client).collect();
+    Ok(Tuning {
+        transactions: transactions.len(),
+        clients: clients.len(),
+        runs: vec![
+            replay::<32>(transactions)?,
+            replay::<64>(transactions)?,
+            replay::<128>(transactions)?,
+            replay::<256>(transactions)?,
+            replay::<512>(transactions)?,
+            replay::<1024>(transactions)?,
+        ],
+    })
+}
+
+// Replay the transactions against a cache per client, the way the accounts use it: the transactions that are logged
+// are put in the cache and the ones that refer to a logged transaction look it up. The account rules are not applied,
+// a lookup costs the same whether the transaction it refers to was logged or not.
+fn replay<const CAP: usize>(transactions: &[Transaction]) -> Result<CapacityRun, CacheError> {
+    let mut caches: HashMap<
+        ClientId,
+        TransactionCache<SqliteKvStore, TransactionId, FundingLogEntry, CAP>,
+    > = HashMap::new();
+    let mut logged: HashMap<ClientId, HashSet<TransactionId>> = HashMap::new();
+    let (mut lookups, mut store_lookups) = (0, 0);
+    let started = Instant::now();
+    let store_time = transactions_cache::store_time();
+    for transaction in transactions {
+        let cache = match caches.entry(transaction.client()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(TransactionCache::new()?),
+        };
+        match transaction.transaction_type() {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Move
+            | TransactionType::EscrowHold => {
+                cache.put(
+                    transaction.id(),
+                    FundingLogEntry::new_deposit(transaction.amount().unwrap_or_default()),
+                )?;
+                logged
+                    .entry(transaction.client())
+                    .or_default()
+                    .insert(transaction.id());
+            }
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::ChargebackReversal
+            | TransactionType::EscrowRelease => {
+                lookups += 1;
+                if !cache.is_in_memory(&transaction.id()) {
+                    store_lookups += 1;
+                }
+                cache.get_mut(&transaction.id())?;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+    let store_time = transactions_cache::store_time() - store_time;
+    let in_memory = logged
+        .values()
+        .map(|transactions| transactions.len().min(CAP) as u64)
+        .sum();
+
+    Ok(CapacityRun {
+        capacity: CAP,
+        lookups,
+        store_lookups,
+        in_memory,
+        elapsed,
+        store_time,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(client: u16, tx: u32) -> Transaction {
+        Transaction::new(
+            TransactionType::Deposit,
+            client.into(),
+            tx.into(),
+            Some(1.0.into()),
+        )
+    }
+
+    fn dispute(client: u16, tx: u32) -> Transaction {
+        Transaction::new(TransactionType::Dispute, client.into(), tx.into(), None)
+    }
+
+    #[test]
+    fn should_count_the_lookups_that_go_to_the_store() {
+        // 100 deposits, then disputes of the first and the last one.
+        let mut transactions: Vec<_> = (0..100).map(|tx| deposit(1, tx)).collect();
+        transactions.push(dispute(1, 0));
+        transactions.push(dispute(1, 99));
+
+        let small = replay::<32>(&transactions).unwrap();
+        let large = replay::<128>(&transactions).unwrap();
+
+        assert_eq!((small.lookups, small.store_lookups), (2, 1));
+        assert_eq!((large.lookups, large.store_lookups), (2, 0));
+        assert_eq!((small.in_memory, large.in_memory), (32, 100));
+    }
+
+    #[test]
+    fn should_recommend_the_smallest_capacity_close_to_the_largest() {
+        // Disputes of transactions 100 deposits back only find them in memory from a capacity of 128.
+        let mut transactions = Vec::new();
+        for client in 0..2 {
+            transactions.extend((0..100).map(|tx| deposit(client, tx)));
+            transactions.push(dispute(client, 0));
+        }
+
+        let tuning = compare(&transactions).unwrap();
+
+        assert_eq!(tuning.clients, 2);
+        assert_eq!(tuning.runs.len(), 6);
+        assert_eq!(tuning.recommended(), Some(128));
+    }
+}
@@ -0,0 +1,776 @@
+use std::{
+    ffi::OsString,
+    net::SocketAddr,
+    num::{NonZeroU16, NonZeroU32, NonZeroU64},
+    path::PathBuf,
+};
+
+use clap::{Args, Parser, Subcommand};
+use encoding_rs::Encoding;
+use rust_decimal::Decimal;
+
+use crate::{
+    cluster::{Peer, ShardSet},
+    cold_storage::RetentionPolicy,
+    csv_reader::Compression,
+    db_input::DbInput,
+    engine::Partitioner,
+    enrichment::Currency,
+    id_allocator::IdRange,
+    id_history::CollisionPolicy,
+    json::JsonAmounts,
+    kafka_input::KafkaInput,
+    ledger::LedgerFormat,
+    merge::DuplicatePolicy,
+    metrics::MetricsExporter,
+    output::OutputSchema,
+    pipeline::DEFAULT_VALIDATION_WINDOW,
+    snapshot::SnapshotFormat,
+    spans::SpanExporter,
+    transaction_processor::PausePolicy,
+    transaction_types::{Amount, ClientId, TransactionType},
+};
+
+/// Command line arguments of the payments engine.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Processes a CSV file of transactions and outputs the account balances.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
+    /// CSV files with the input transactions, or glob patterns of them (e.g. 'branches/*.csv'). The files go through
+    /// the same workers one after the other, in the order they are given, into a single account report. A file can
+    /// also be an S3 object (`s3://BUCKET/KEY`), streamed as it's read. Needs the object_store feature.
+    #[arg(
+        value_name = "TRANSACTIONS_FILE",
+        required_unless_present_any = ["input", "kafka", "tcp_listen", "ws_listen", "grpc_listen", "watch_dir"]
+    )]
+    pub(crate) transactions_files: Vec<PathBuf>,
+
+    /// Read the input transactions from a database table instead of a file, in the order of a sequence column
+    /// (e.g. `db:sqlite:/data/landed.db?table=transactions` or `db:postgres://host/db?table=transactions&sequence=id`).
+    #[arg(
+        long,
+        value_name = "db:CONNECTION?table=TABLE",
+        conflicts_with = "transactions_files"
+    )]
+    pub(crate) input: Option<DbInput>,
+
+    /// Consume the input transactions from a Kafka topic until Ctrl-C is received, as JSON messages
+    /// (e.g. `kafka://broker1:9092,broker2:9092/transactions?group=engine`). The offsets are only committed once the
+    /// transactions were handed over to the workers. Needs the rdkafka feature.
+    #[arg(
+        long,
+        value_name = "kafka://BROKERS/TOPIC",
+        conflicts_with_all = ["transactions_files", "input", "listen"]
+    )]
+    pub(crate) kafka: Option<KafkaInput>,
+
+    /// Accept transactions on TCP connections to this address until Ctrl-C is received, as lines of CSV or JSON, e.g.
+    /// to drive the engine from a test harness. A `query <CLIENT>` line is answered with the accounts of the client.
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        conflicts_with_all = ["transactions_files", "input", "kafka", "listen"]
+    )]
+    pub(crate) tcp_listen: Option<SocketAddr>,
+
+    /// Accept transactions on WebSocket connections to this address until Ctrl-C is received, as text messages of CSV
+    /// or JSON lines, e.g. to replay recorded traffic. A connection isn't read while the workers are behind.
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        conflicts_with_all = ["transactions_files", "input", "kafka", "listen", "tcp_listen"]
+    )]
+    pub(crate) ws_listen: Option<SocketAddr>,
+
+    /// Serve the gRPC ingestion service on this address until Ctrl-C is received, so other services can submit their
+    /// transactions one at a time and get the outcome of each (see `proto/payments_engine.proto`). Needs the grpc
+    /// feature.
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        conflicts_with_all = ["transactions_files", "input", "kafka", "listen", "tcp_listen", "ws_listen"]
+    )]
+    pub(crate) grpc_listen: Option<SocketAddr>,
+
+    /// Encoding of the input file (e.g. utf-16le, windows-1252). A byte order mark in the file takes precedence.
+    /// Files without a byte order mark are read as UTF-8 by default.
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    pub(crate) encoding: Option<&'static Encoding>,
+
+    /// Compression of the input files. With `auto`, gzip and zstd files are recognized by their extension (`.gz`,
+    /// `.zst`) or their first bytes.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) compression: Compression,
+
+    /// Accept amounts with a comma decimal separator or thousands separators (e.g. "1.234,56" or "1,234.56").
+    /// Ambiguous amounts like "1,234" are rejected.
+    #[arg(long)]
+    pub(crate) lenient_amounts: bool,
+
+    /// Number of tasks that parse the records of the input in parallel. The transactions are put back in the order of
+    /// the input before they are processed. With 1, the records are parsed as they are read.
+    #[arg(long, value_name = "TASKS", default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) parse_workers: usize,
+
+    /// Reject the deposits, withdrawals, moves and escrow holds whose id is not above the id of the previous one of the
+    /// client, to catch upstream systems that reorder the transactions. The rejects give the lines of both
+    /// transactions.
+    #[arg(long)]
+    pub(crate) strict_ordering: bool,
+
+    /// How the clients are assigned to the workers: by a hash of their id, or by contiguous ranges of client ids, which
+    /// keeps the workers on their own part of an input sorted by client. The ranges are listed in the run manifest.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) partition: Partitioner,
+
+    /// Queue at most this many transactions per second from every input file, including the files of a watched
+    /// directory, e.g. to replay a historical file against a staging environment at the speed of production. The
+    /// bodies of API requests and the database input are not paced.
+    #[arg(long, value_name = "TX_PER_SEC")]
+    pub(crate) rate: Option<NonZeroU32>,
+
+    /// File with the ids of blocked clients, one per line. All the transactions of blocked clients are rejected.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) blocklist: Option<PathBuf>,
+
+    /// CSV file with the dispute rules of deposits and withdrawals: whether they can be disputed, for how many days,
+    /// whether partially and whether again once resolved. Without it, only deposits can be disputed, once, for their
+    /// whole amount
+    #[arg(long, value_name = "FILE")]
+    pub(crate) dispute_policy: Option<PathBuf>,
+
+    /// Reject deposits and withdrawals with an amount above this value.
+    #[arg(long, value_name = "AMOUNT")]
+    pub(crate) max_transaction_amount: Option<Amount>,
+
+    /// The currency of the engine, e.g. `USD`. Transactions with another code in their `currency` column are
+    /// rejected. Transactions without a currency are in the currency of the engine.
+    #[arg(long, value_name = "CODE")]
+    pub(crate) currency: Option<Currency>,
+
+    /// Reject disputes, resolves and chargebacks without a `source` column (`issuer`, `internal` or `partner`), as well
+    /// as the dispute requests of the daemon API without a `source` parameter. A dispute can only be resolved or charged
+    /// back by the source that opened it, whether this is set or not.
+    #[arg(long)]
+    pub(crate) require_dispute_source: bool,
+
+    /// The maximum number of disputes, resolves and chargebacks per minute of a client whose transaction is no longer
+    /// in memory and has to be loaded from disk, e.g. disputes of old transactions. The others are rejected with the
+    /// `throttled` stage in the rejects report. Not limited by default.
+    #[arg(long, value_name = "COUNT")]
+    pub(crate) max_disk_lookups: Option<usize>,
+
+    /// Reject deposits and moves that would bring the total of an account above this amount. Funds held by disputes
+    /// and in escrow are part of the total.
+    #[arg(long, value_name = "AMOUNT")]
+    pub(crate) max_account_total: Option<Amount>,
+
+    /// The maximum total of the accounts of a client, instead of `--max-account-total` (e.g. `42=10000`). Can be
+    /// repeated.
+    #[arg(long = "client-max-total", value_name = "CLIENT=AMOUNT", value_parser = parse_client_limit)]
+    pub(crate) client_max_totals: Vec<(ClientId, Amount)>,
+
+    /// Reject disputes of a client that already has this many disputes in its last `--validation-window` transactions.
+    #[arg(long, value_name = "COUNT")]
+    pub(crate) max_disputes: Option<usize>,
+
+    /// Reject withdrawals of a client that would bring its withdrawn total in the last `--validation-window` transactions above this amount.
+    #[arg(long, value_name = "AMOUNT")]
+    pub(crate) max_withdrawn: Option<Amount>,
+
+    /// Number of recent accepted transactions of each client that are available to the validators.
+    #[arg(long, value_name = "TRANSACTIONS", default_value_t = DEFAULT_VALIDATION_WINDOW, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) validation_window: usize,
+
+    /// Raise an alert when the share of chargebacks in the recent transactions of a client or of all clients
+    /// exceeds this percentage (e.g. 1 for 1%).
+    #[arg(long, value_name = "PERCENT")]
+    pub(crate) chargeback_alert_rate: Option<Decimal>,
+
+    /// Number of recent transactions the chargeback rate is computed on.
+    #[arg(long, value_name = "TRANSACTIONS", default_value_t = 1000, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) chargeback_window: usize,
+
+    /// Stop accepting deposits on accounts whose chargeback rate exceeds `--chargeback-alert-rate`.
+    #[arg(long, requires = "chargeback_alert_rate")]
+    pub(crate) withdrawal_only_on_alert: bool,
+
+    /// Append the chargeback rate alerts to this file, one JSON object per line. The file is the audit trail of the
+    /// alerts.
+    #[arg(long, value_name = "PATH", requires = "chargeback_alert_rate")]
+    pub(crate) alert_log: Option<PathBuf>,
+
+    /// Post each chargeback rate alert as JSON to this URL.
+    #[arg(long, value_name = "URL", requires = "chargeback_alert_rate")]
+    pub(crate) alert_webhook: Option<String>,
+
+    /// Account balances from a previous run (the CSV output of the engine) used as the starting state.
+    #[arg(long, value_name = "ACCOUNTS_CSV")]
+    pub(crate) bootstrap: Option<PathBuf>,
+
+    /// The transactions of the previous run that are still disputed, so that the funds they hold in the accounts of
+    /// `--bootstrap` can be resolved or charged back: a CSV file with the `client`, `tx` and `amount` columns, and the
+    /// optional `account`, `type` (`deposit` or `withdrawal`) and `source` columns. The disputed amounts of each account
+    /// must add up to its held funds.
+    #[arg(long, value_name = "DISPUTES_CSV", requires = "bootstrap")]
+    pub(crate) bootstrap_disputes: Option<PathBuf>,
+
+    /// Start from a backup of a daemon (a `backup-<timestamp>` directory written by `POST /backup`): the balances and
+    /// the transaction stores of the accounts are checked against the manifest of the backup and copied to the state
+    /// directory. Nothing is restored and the engine doesn't start if the backup is incomplete or corrupted.
+    #[arg(
+        long,
+        value_name = "BACKUP_DIR",
+        requires = "state_dir",
+        conflicts_with = "bootstrap"
+    )]
+    pub(crate) restore_from: Option<PathBuf>,
+
+    /// Directory where the state that has to survive between runs is kept, e.g. the manifest of the processed input files.
+    /// Input files that were already processed are skipped.
+    #[arg(long, value_name = "DIR")]
+    pub(crate) state_dir: Option<PathBuf>,
+
+    /// Process the input file even if the manifest in the state directory says it was already processed.
+    #[arg(long, requires = "state_dir")]
+    pub(crate) force: bool,
+
+    /// Reserve a range of transaction ids for the entries posted by the engine itself (fees, interest, adjustments),
+    /// e.g. 4000000000-4294967295. Upstream deposits, withdrawals, moves and escrow holds with an id in the range are
+    /// rejected. With `--state-dir`, the last allocated id is kept in the state directory so the ids are never reused
+    /// by a later run. No id is reserved without it.
+    #[arg(
+        long,
+        value_name = "START-END",
+        required_if_eq("id_collision_policy", "remap")
+    )]
+    pub(crate) synthetic_ids: Option<IdRange>,
+
+    /// Check the deposits, withdrawals, moves and escrow holds against the ids of the ones applied by the previous runs
+    /// and reject them, skip them if they are identical (`accept-if-identical`) or apply them under a new id from the
+    /// range of the synthetic ids (`remap`, which requires `--synthetic-ids`) if the id was already used. The ids are
+    /// kept in the state directory.
+    #[arg(long, value_enum, value_name = "POLICY", requires = "state_dir")]
+    pub(crate) id_collision_policy: Option<CollisionPolicy>,
+
+    /// Close the accounting period once the input file was processed. The closing balances are written to the state directory
+    /// and the validation limits start over in the next period.
+    #[arg(long, requires = "state_dir")]
+    pub(crate) close_period: bool,
+
+    /// Flag the accounts whose total changed by more than this many times their typical change since the previous run.
+    /// The closing balances of each run are kept in the state directory and compared with the ones of the next run.
+    #[arg(
+        long,
+        value_name = "MULTIPLE",
+        default_value_t = 10,
+        requires = "state_dir"
+    )]
+    pub(crate) drift_threshold: u32,
+
+    /// The number of transactions with the largest amounts that are listed in the summary.
+    #[arg(long, value_name = "COUNT", default_value_t = 5)]
+    pub(crate) largest_transactions: usize,
+
+    /// Flag the deposits and withdrawals whose amount is at least this many standard deviations above the mean of the
+    /// amounts of their type for a manual review. They are listed in the summary.
+    #[arg(long, value_name = "Z")]
+    pub(crate) review_z_score: Option<f64>,
+
+    /// Flag the deposits and withdrawals above this amount for a manual review. They are listed in the summary.
+    #[arg(long, value_name = "AMOUNT")]
+    pub(crate) review_above: Option<Amount>,
+
+    /// Number of recent transactions of each account that can be disputed. Once the transaction log of an account grew
+    /// by this many transactions, the settled transactions older than the window are moved to `--history-archive`.
+    #[arg(long, value_name = "TRANSACTIONS", requires = "history_archive", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) dispute_window: Option<usize>,
+
+    /// CSV file where the transactions that fell out of the dispute window are archived, followed by a checkpoint
+    /// of the account balances. The file is appended to.
+    #[arg(long, value_name = "FILE", requires = "dispute_window")]
+    pub(crate) history_archive: Option<PathBuf>,
+
+    /// Once the run is over, move the compactions that are not retained from `--history-archive` to a compressed
+    /// archive file in this directory (see `archive export`).
+    #[arg(long, value_name = "DIR", requires = "history_archive")]
+    pub(crate) archive_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub(crate) retention: RetentionArgs,
+
+    /// Reject disputes, resolves and chargebacks for clients without an account instead of creating an empty account.
+    #[arg(long)]
+    pub(crate) reject_unknown_clients: bool,
+
+    /// Let the disputes, resolves and chargebacks that reference a transaction the account doesn't have yet wait for up
+    /// to this many later transactions of the account instead of rejecting them right away, for sources where a
+    /// dispute can overtake its deposit. Also the most operations an account can have waiting.
+    #[arg(long, value_name = "TRANSACTIONS", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) reorder_disputes: Option<usize>,
+
+    /// Also reject the waiting dispute operations once they waited this many seconds, measured with the clock of the
+    /// engine when the account gets another transaction.
+    #[arg(long, value_name = "SECONDS", requires = "reorder_disputes")]
+    pub(crate) reorder_timeout: Option<u32>,
+
+    /// Transaction types that are still applied to locked accounts, e.g. `deposit,resolve` to keep crediting a locked
+    /// account and let its open disputes be resolved. Locked accounts reject every transaction by default, except the
+    /// chargeback reversals.
+    #[arg(long, value_name = "TYPES", value_delimiter = ',', value_parser = parse_transaction_type)]
+    pub(crate) locked_allow: Vec<TransactionType>,
+
+    /// Unlock an account when the chargebacks that locked it were all reversed. Accounts that were already locked when
+    /// they were loaded, e.g. with `--bootstrap`, stay locked.
+    #[arg(long)]
+    pub(crate) unlock_on_chargeback_reversal: bool,
+
+    /// Unlock an account locked by chargebacks once its last chargeback is this many days old, measured with the clock
+    /// of the engine. The unlocks are logged as `account_unlocked` events. Accounts that were already locked when they
+    /// were loaded stay locked.
+    #[arg(long, value_name = "DAYS")]
+    pub(crate) auto_unlock_after_days: Option<u32>,
+
+    /// Settle the deposits this many days after they were applied, measured with the clock of the engine (T+N). The
+    /// deposits are credited to the total right away, but their funds are pending and only become available once they
+    /// settled. The outputs get a `pending` column.
+    #[arg(long, value_name = "DAYS")]
+    pub(crate) settlement_delay_days: Option<u32>,
+
+    /// Withhold this percentage of every deposit of a high-risk client in a rolling reserve (e.g. `42=10`). The funds
+    /// count towards the total but are only available once they were withheld for `--reserve-days`. Can be repeated.
+    #[arg(long = "client-reserve", value_name = "CLIENT=PERCENT", value_parser = parse_client_reserve)]
+    pub(crate) client_reserves: Vec<(ClientId, Decimal)>,
+
+    /// Number of days the rolling reserve of a deposit is withheld, measured with the clock of the engine.
+    #[arg(
+        long,
+        value_name = "DAYS",
+        default_value_t = 90,
+        requires = "client_reserves"
+    )]
+    pub(crate) reserve_days: u32,
+
+    /// Don't write accounts that have no funds and are not locked.
+    #[arg(long)]
+    pub(crate) omit_empty_accounts: bool,
+
+    /// Only write locked accounts.
+    #[arg(long)]
+    pub(crate) only_locked: bool,
+
+    /// Only write accounts with a negative balance.
+    #[arg(long)]
+    pub(crate) only_negative: bool,
+
+    /// Add the funds held in escrow as an extra `escrow` column of the output.
+    #[arg(long)]
+    pub(crate) extended_report: bool,
+
+    /// The columns of the output: `v1` (the default), `v2` (all the columns) or `custom(<COLUMNS>)` with a comma
+    /// separated list of the columns, e.g. `custom(client,total,locked)`.
+    #[arg(long, value_name = "SCHEMA", default_value_t)]
+    pub(crate) output_schema: OutputSchema,
+
+    /// Write the transactions that were rejected or could not be applied to this CSV file.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) rejects: Option<PathBuf>,
+
+    /// Add an ISO 8583 style response code column (e.g. 51 for insufficient funds) to the rejects report.
+    #[arg(long, requires = "rejects")]
+    pub(crate) rejects_response_codes: bool,
+
+    /// Write the transactions that failed because of an internal error (e.g. the transaction store failed) or could not
+    /// be forwarded to their peer in cluster mode to this CSV file instead of the rejects report. The file can be
+    /// processed again once the cause is fixed.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) dead_letters: Option<PathBuf>,
+
+    /// Write a JSON record of the run to this file once it's over: the command line, the input files with their
+    /// SHA-256 hashes, the outputs, the counts by transaction type and by rejection reason, the timing and the exit
+    /// status.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) run_manifest: Option<PathBuf>,
+
+    /// Add the memory used by each phase of the run to the run manifest: the peak and the end of the allocated bytes
+    /// and, on Linux, the peak resident set size.
+    #[arg(long, requires = "run_manifest")]
+    pub(crate) report_memory: bool,
+
+    /// Push the metrics of the run (the applied, rejected and failed transactions, the accounts) to a statsd daemon
+    /// (`statsd://HOST:PORT[?prefix=PREFIX]`) or an OpenTelemetry collector (`otlp://HOST:PORT[/PATH]`, OTLP JSON over
+    /// HTTP), for machines that can't be scraped. Can be repeated.
+    #[arg(long, value_name = "URL")]
+    pub(crate) metrics_export: Vec<MetricsExporter>,
+
+    /// How often the metrics are pushed while the engine runs. They are pushed a last time once the run is over.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "metrics_export"
+    )]
+    pub(crate) metrics_interval: u64,
+
+    /// Export OpenTelemetry spans of the `POST /transactions` requests of the daemon and of the transactions they carry
+    /// to a collector (`otlp://HOST:PORT[/PATH]`, OTLP JSON over HTTP). The trace of a request continues the trace of
+    /// its `traceparent` header.
+    #[arg(long, value_name = "URL", requires = "listen")]
+    pub(crate) span_export: Option<SpanExporter>,
+
+    /// Start the outputs with a comment line that records the version of the engine, its optional features and the
+    /// hash of the options of the run: the accounts, the settlement report, the account updates and the ledger export.
+    #[arg(long)]
+    pub(crate) provenance: bool,
+
+    /// Time the stages of the pipeline and write a folded stack file per worker (and one for the reader) to this
+    /// directory, e.g. to see whether the transaction store, parsing or the channels dominate on a given input.
+    #[arg(long, value_name = "DIR")]
+    pub(crate) profile: Option<PathBuf>,
+
+    /// Record everything that happens to the transactions of this client to a trace file: the transactions as they
+    /// were received, the outcome of their validation, the balances before and after they were applied and whether
+    /// the transactions they reference were in memory or on disk.
+    #[arg(long, value_name = "CLIENT")]
+    pub(crate) trace_client: Option<u16>,
+
+    /// The trace file of `--trace-client`, as JSON lines.
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = "client-trace.jsonl",
+        requires = "trace_client"
+    )]
+    pub(crate) trace_file: PathBuf,
+
+    /// Write the net position of each client and of the whole engine to this CSV file, for settling each client
+    /// with a single wire. Funds held by disputes or in escrow are reported but left out of the net.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) settlement_report: Option<PathBuf>,
+
+    /// Also write the balances of an account to this CSV file every time a transaction is applied to it, with a sequence
+    /// number and a timestamp, so the changes can be followed while the engine runs (e.g. with `tail -f`).
+    #[arg(long, value_name = "FILE")]
+    pub(crate) account_updates: Option<PathBuf>,
+
+    /// Identifies the run in the account updates and the run manifest, e.g. the name of the batch being processed.
+    /// The account updates are keyed by the run id and the position of the update among the updates of its account,
+    /// so a run of the same input with the same id gives the same keys and loaders can upsert instead of duplicating.
+    #[arg(long, value_name = "ID")]
+    pub(crate) run_id: Option<String>,
+
+    /// Also write the applied transactions as plain text accounting entries to this file.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) ledger_export: Option<PathBuf>,
+
+    /// Syntax of the ledger export.
+    #[arg(long, value_enum, default_value_t, requires = "ledger_export")]
+    pub(crate) ledger_format: LedgerFormat,
+
+    /// Commodity of the amounts in the ledger export.
+    #[arg(long, default_value = "USD", requires = "ledger_export")]
+    pub(crate) ledger_commodity: String,
+
+    /// Keep running after the input file was processed and serve the HTTP API on this address (daemon mode).
+    /// The accounts are written out when Ctrl-C is received.
+    #[arg(long, value_name = "ADDRESS")]
+    pub(crate) listen: Option<SocketAddr>,
+
+    /// Ingest the CSV files that appear in this directory, after the input files, until Ctrl-C is received, or while
+    /// the daemon is running with `--listen`. Ingested files are moved to its `ingested` subdirectory.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["kafka", "tcp_listen", "ws_listen", "grpc_listen"]
+    )]
+    pub(crate) watch_dir: Option<PathBuf>,
+
+    /// Rewrite this account report every time the files of the watched directory were applied, so it follows the
+    /// files as they are dropped. Uses the output schema and filters of the account report.
+    #[arg(long, value_name = "FILE", requires = "watch_dir")]
+    pub(crate) watch_report: Option<PathBuf>,
+
+    /// Number of files of the watched directory that are ingested at the same time when several are waiting. Only
+    /// files that have no client in common are ingested together. With 1, the files are ingested one at a time.
+    #[arg(long, value_name = "FILES", default_value_t = 4, requires = "watch_dir", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) watch_concurrency: usize,
+
+    /// Experimental cluster mode: number of shards the client ids are split into (`client % COUNT`). All the nodes of
+    /// the cluster must use the same count.
+    #[arg(long, value_name = "COUNT", requires = "shards")]
+    pub(crate) shard_count: Option<NonZeroU16>,
+
+    /// Shards owned by this node in cluster mode (e.g. `0-3,7`). The transactions of the other shards are forwarded to
+    /// the peer that owns them, or rejected if no peer does.
+    #[arg(long, value_name = "SHARDS", requires = "shard_count")]
+    pub(crate) shards: Option<ShardSet>,
+
+    /// A peer node and the shards it owns in cluster mode (e.g. `4-7=http://10.0.0.2:7002`). Can be repeated.
+    /// Peers receive the forwarded transactions through their gRPC service, so they have to run with `--grpc-listen`
+    /// and the URL is the one of the service.
+    #[arg(long = "peer", value_name = "SHARDS=URL", requires = "shards")]
+    pub(crate) peers: Vec<Peer>,
+
+    /// How long each worker has to answer the readiness probe of the daemon (`/readyz`).
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = 1000,
+        requires = "listen"
+    )]
+    pub(crate) readiness_timeout: u64,
+
+    /// Reject the transactions posted to the daemon for a client that already has this many transactions waiting to
+    /// be applied, with 429 Too Many Requests, so that one client cannot fill the queue of its worker.
+    #[arg(long, value_name = "COUNT", requires = "listen")]
+    pub(crate) max_in_flight: Option<NonZeroU32>,
+
+    /// What happens to the transactions that arrive while an operator paused the processing (`POST /pause` or SIGUSR1):
+    /// buffer them in memory until the processing is resumed, or reject them.
+    #[arg(long, value_enum, default_value_t, requires = "listen")]
+    pub(crate) pause_policy: PausePolicy,
+
+    /// Directory where the backups of the daemon state are written (`POST /backup`). Each backup is a new
+    /// `backup-<timestamp>` subdirectory with the transaction stores and the balances of the accounts.
+    #[arg(long, value_name = "DIR", requires = "listen")]
+    pub(crate) backup_dir: Option<PathBuf>,
+
+    /// Don't write anything about individual records (e.g. rejected transactions) on stderr.
+    /// The structured events and the summary are still written.
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub(crate) quiet: bool,
+
+    /// Write every rejected transaction, without sampling, and every applied transaction on stderr.
+    #[arg(short, long)]
+    pub(crate) verbose: bool,
+
+    /// Only write one in N of the rejected or unparseable records with the same reason on stderr.
+    /// The summary still has the exact counts.
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub(crate) log_sample: NonZeroU64,
+
+    /// Write amounts with exactly this many decimal places instead of removing trailing zeros.
+    #[arg(long, value_name = "DECIMAL_PLACES", value_parser = clap::value_parser!(u32).range(0..=28))]
+    pub(crate) output_scale: Option<u32>,
+
+    /// Write the amounts of the JSON responses of the API as strings (`"1.5"`) or as numbers (`1.5`). Amounts are
+    /// accepted both ways in JSON input.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) json_amounts: JsonAmounts,
+}
+
+/// Commands that don't process transactions.
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Rewrite an input file with pseudonyms in place of the client ids, e.g. to share a file that shows a problem
+    /// with support. The same key gives the same pseudonym to a client in every file. The file is written to stdout.
+    Anonymize {
+        /// The input file.
+        input: PathBuf,
+
+        /// The secret key of the pseudonyms. Anyone with the key can tell which client a pseudonym stands for.
+        #[arg(long, value_name = "KEY")]
+        key: String,
+
+        /// Multiply the amounts by this factor, rounded to 4 decimal places.
+        #[arg(long, value_name = "FACTOR")]
+        scale_amounts: Option<Decimal>,
+    },
+    /// Move old history between the history archive and compressed archive files.
+    #[command(subcommand)]
+    Archive(ArchiveCommand),
+    /// Work with the options of a run.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Work with the snapshots of the accounts.
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// Merge the account outputs of sharded or parallel runs (e.g. the nodes of a cluster) into one output ordered by
+    /// client. The totals of the merged output are written on stderr.
+    Merge {
+        /// Account outputs of the runs.
+        #[arg(required = true)]
+        outputs: Vec<PathBuf>,
+
+        /// What to do when an account is in more than one output.
+        #[arg(long, value_enum, default_value_t)]
+        on_duplicate: DuplicatePolicy,
+    },
+    /// Replay a sample of an input against transaction caches of several capacities and recommend the capacity the
+    /// engine should be built with. The comparison is written to stdout.
+    TuneCache {
+        /// The input file.
+        input: PathBuf,
+
+        /// Number of records of the sample, from the start of the file.
+        #[arg(long, value_name = "RECORDS", default_value_t = 100_000)]
+        sample: usize,
+    },
+
+    /// Profile a sample of an input: the clients, the mix of transaction types, the dispute rate, the amounts and the
+    /// memory and disk the accounts are estimated to need with the options of the run. The profile is written to stdout.
+    ProfileInput {
+        /// The input file.
+        input: PathBuf,
+
+        /// Number of records of the sample, from the start of the file.
+        #[arg(long, value_name = "RECORDS", default_value_t = 100_000)]
+        sample: usize,
+
+        /// The options of the run, as they would be passed to `payments-engine`, e.g. `--dispute-window 1000`.
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "OPTIONS"
+        )]
+        options: Vec<OsString>,
+    },
+
+    /// Recompute the balances of an input with a simple single-threaded reference implementation and compare them
+    /// with the account output of a run. The differences are written to stdout and the exit status is 1 if there are
+    /// any. Only the outputs of runs with the default options can be verified.
+    Verify {
+        /// The input file of the run.
+        #[arg(long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// The account output of the run.
+        #[arg(long, value_name = "FILE")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ArchiveCommand {
+    /// Move the compactions that are not retained from the history archive to a compressed archive file named after
+    /// the SHA-256 hash of its contents.
+    Export {
+        /// The history archive written by `--history-archive`.
+        #[arg(long, value_name = "FILE")]
+        history: PathBuf,
+
+        /// Directory of the archive files.
+        #[arg(long, value_name = "DIR")]
+        dir: PathBuf,
+
+        #[command(flatten)]
+        retention: RetentionArgs,
+    },
+    /// Move the rows of archive files back to the history archive. The archive files are checked against their name
+    /// and removed once imported.
+    Import {
+        /// The history archive written by `--history-archive`.
+        #[arg(long, value_name = "FILE")]
+        history: PathBuf,
+
+        /// Archive files, or directories of archive files.
+        #[arg(required = true)]
+        archives: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum SnapshotCommand {
+    /// Convert an account output or snapshot, in CSV or binary, to the other format. The binary snapshots load much
+    /// faster with `--bootstrap` when there are millions of accounts.
+    Convert {
+        /// The accounts to convert, e.g. the output of a run or a period snapshot.
+        input: PathBuf,
+
+        /// The converted snapshot.
+        output: PathBuf,
+
+        /// The format of the converted snapshot.
+        #[arg(long, value_enum)]
+        to: SnapshotFormat,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ConfigCommand {
+    /// Check the options of a run (e.g. `config check input.csv --state-dir state`) and report all their problems,
+    /// without processing anything.
+    Check {
+        /// The options of the run, as they would be passed to `payments-engine`.
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "OPTIONS"
+        )]
+        options: Vec<OsString>,
+    },
+}
+
+/// How much of the history archive is kept when exporting.
+#[derive(Debug, Args)]
+pub(crate) struct RetentionArgs {
+    /// Number of most recent compactions of each account that stay in the history archive when exporting.
+    #[arg(long, value_name = "COUNT", default_value_t = 1)]
+    pub(crate) retain_compactions: usize,
+}
+
+impl RetentionArgs {
+    pub(crate) fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            retain_compactions: self.retain_compactions,
+        }
+    }
+}
+
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+// A transaction type by the name it has in the input.
+fn parse_transaction_type(name: &str) -> Result<TransactionType, String> {
+    [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Move,
+        TransactionType::EscrowHold,
+        TransactionType::EscrowRelease,
+    ]
+    .into_iter()
+    .find(|transaction_type| transaction_type.to_string() == name)
+    .ok_or_else(|| format!("unknown transaction type '{}'", name))
+}
+
+fn parse_client_reserve(value: &str) -> Result<(ClientId, Decimal), String> {
+    let (client, percent) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not a client and a percentage (e.g. 42=10)", value))?;
+    let client: u16 = client
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a client id", client))?;
+    let percent: Decimal = percent
+        .trim()
+        .parse()
+        .ok()
+        .filter(|percent| *percent > Decimal::ZERO && *percent <= Decimal::ONE_HUNDRED)
+        .ok_or_else(|| format!("'{}' is not a percentage between 0 and 100", percent))?;
+    Ok((client.into(), percent))
+}
+
+fn parse_client_limit(value: &str) -> Result<(ClientId, Amount), String> {
+    let (client, amount) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not a client and an amount (e.g. 42=10000)", value))?;
+    let client: u16 = client
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a client id", client))?;
+    let amount = amount.trim().parse().map_err(|err| format!("{}", err))?;
+    Ok((client.into(), amount))
+}
@@ -0,0 +1,292 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::SecondsFormat;
+use serde::Serialize;
+
+use crate::{
+    account::AccountSnapshot,
+    clock::SharedClock,
+    engine::WorkerId,
+    transaction_types::{AmountFormat, ClientId, Transaction, TransactionId, TransactionType},
+};
+
+// Everything that happens to the transactions of a single client, for debugging a balance that doesn't add up without
+// re-running the engine under a debugger. The validator chain and the processor of the worker of the client write to
+// the same trace: the transaction as it was received, with its line in the input, whether it passed validation, and
+// how it was applied, with the balances of the accounts it touched before and after and whether the transaction it
+// references was in memory or had to be loaded from the transaction store on disk. The events are JSON lines, in the
+// order they happened, since a client is handled by a single worker. The stages run concurrently, so the validation of
+// a transaction can come before the previous one is applied.
+
+/// What happened to a transaction of the traced client.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum TraceEvent<'a> {
+    /// The transaction got to the validator chain.
+    Received {
+        #[serde(flatten)]
+        transaction: &'a Transaction,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line: Option<u64>,
+    },
+    Validated {
+        tx: TransactionId,
+        #[serde(rename = "type")]
+        transaction_type: TransactionType,
+        /// Why the transaction was rejected, if it was.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rejected: Option<String>,
+    },
+    Applied {
+        tx: TransactionId,
+        #[serde(rename = "type")]
+        transaction_type: TransactionType,
+        /// Why the transaction could not be applied, if it couldn't.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        failed: Option<String>,
+        /// Whether the transaction a dispute operation references was in memory (`hit`) or on disk (`fault`).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache: Option<CacheLookup>,
+        before: &'a [AccountSnapshot],
+        after: &'a [AccountSnapshot],
+    },
+    /// The transaction is waiting for the transaction it references.
+    Parked {
+        tx: TransactionId,
+        #[serde(rename = "type")]
+        transaction_type: TransactionType,
+    },
+    /// The transaction was not applied before it got to the account, e.g. because the processing was paused.
+    Dropped {
+        tx: TransactionId,
+        #[serde(rename = "type")]
+        transaction_type: TransactionType,
+        reason: String,
+    },
+}
+
+/// Whether a transaction was looked up in memory or loaded from the transaction store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CacheLookup {
+    Hit,
+    Fault,
+}
+
+impl Display for CacheLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheLookup::Hit => write!(f, "hit"),
+            CacheLookup::Fault => write!(f, "fault"),
+        }
+    }
+}
+
+// A line of the trace.
+#[derive(Serialize)]
+struct TraceLine<'a> {
+    at: String,
+    worker: WorkerId,
+    #[serde(flatten)]
+    event: TraceEvent<'a>,
+}
+
+/// The trace of a client. Clones write to the same file so that the stages of the worker can have their own clone.
+#[derive(Clone)]
+pub(crate) struct ClientTrace {
+    client: ClientId,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    clock: SharedClock,
+}
+
+impl ClientTrace {
+    pub(crate) fn create<P: AsRef<Path>>(
+        path: P,
+        client: ClientId,
+        clock: SharedClock,
+    ) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::new(Box::new(file), client, clock))
+    }
+
+    fn new(writer: Box<dyn Write + Send>, client: ClientId, clock: SharedClock) -> Self {
+        Self {
+            client,
+            writer: Arc::new(Mutex::new(writer)),
+            clock,
+        }
+    }
+
+    /// Whether the transaction is one of the traced client.
+    pub(crate) fn traces(&self, transaction: &Transaction) -> bool {
+        transaction.client() == self.client
+    }
+
+    pub(crate) fn received(&self, worker: WorkerId, transaction: &Transaction) {
+        self.write(
+            worker,
+            TraceEvent::Received {
+                transaction,
+                line: transaction.line(),
+            },
+        );
+    }
+
+    pub(crate) fn validated(
+        &self,
+        worker: WorkerId,
+        transaction: &Transaction,
+        rejected: Option<String>,
+    ) {
+        self.write(
+            worker,
+            TraceEvent::Validated {
+                tx: transaction.id(),
+                transaction_type: transaction.transaction_type(),
+                rejected,
+            },
+        );
+    }
+
+    pub(crate) fn applied(
+        &self,
+        worker: WorkerId,
+        transaction: &Transaction,
+        failed: Option<String>,
+        cache: Option<CacheLookup>,
+        balances: (&[AccountSnapshot], &[AccountSnapshot]),
+    ) {
+        self.write(
+            worker,
+            TraceEvent::Applied {
+                tx: transaction.id(),
+                transaction_type: transaction.transaction_type(),
+                failed,
+                cache,
+                before: balances.0,
+                after: balances.1,
+            },
+        );
+    }
+
+    pub(crate) fn parked(
+        &self,
+        worker: WorkerId,
+        tx: TransactionId,
+        transaction_type: TransactionType,
+    ) {
+        self.write(
+            worker,
+            TraceEvent::Parked {
+                tx,
+                transaction_type,
+            },
+        );
+    }
+
+    pub(crate) fn dropped(&self, worker: WorkerId, transaction: &Transaction, reason: String) {
+        self.write(
+            worker,
+            TraceEvent::Dropped {
+                tx: transaction.id(),
+                transaction_type: transaction.transaction_type(),
+                reason,
+            },
+        );
+    }
+
+    fn write(&self, worker: WorkerId, event: TraceEvent) {
+        let line = TraceLine {
+            at: self
+                .clock
+                .now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            worker,
+            event,
+        };
+        let mut writer = self.writer.lock().expect("Trace lock is never poisoned.");
+        let written = AmountFormat::output(|| serde_json::to_writer(&mut *writer, &line))
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(writer));
+        if let Err(err) = written {
+            eprintln!(
+                "Cannot write to the trace of client {}: {}",
+                self.client, err
+            );
+        }
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .expect("Trace lock is never poisoned.")
+            .flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+
+    use super::*;
+
+    // A writer that can be inspected after the trace was written.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_write_the_events_of_the_client_as_json_lines() {
+        let buffer = SharedBuffer::default();
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let trace = ClientTrace::new(Box::new(buffer.clone()), 1.into(), clock.shared());
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            3.into(),
+            Some(2.5.into()),
+        )
+        .with_line(Some(4));
+        let worker = WorkerId::default();
+
+        assert!(trace.traces(&deposit));
+        trace.received(worker, &deposit);
+        trace.validated(worker, &deposit, None);
+        trace.applied(worker, &deposit, None, None, (&[], &[]));
+        trace.dropped(
+            worker,
+            &deposit,
+            "Processing is paused by an operator.".into(),
+        );
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["event"], "received");
+        assert_eq!(lines[0]["at"], "2024-03-01T12:00:00.000Z");
+        assert_eq!(lines[0]["amount"], "2.5");
+        assert_eq!(lines[0]["line"], 4);
+        assert_eq!(lines[1]["event"], "validated");
+        assert!(lines[1].get("rejected").is_none());
+        assert_eq!(lines[2]["before"], serde_json::json!([]));
+        assert_eq!(lines[3]["reason"], "Processing is paused by an operator.");
+    }
+}
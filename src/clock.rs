@@ -0,0 +1,79 @@
+use std::{fmt::Debug, sync::Arc};
+
+use chrono::{DateTime, Utc};
+
+// Where the engine gets the current time from. Everything that records or depends on when something happened (the
+// timestamps of the applied transactions, the date of the ledger export, the time a file was processed) asks the
+// clock it was given instead of the system, so that time dependent behaviour can be tested with a clock that only
+// moves when the test moves it. Durations measured for profiling and logging keep using `Instant`.
+
+/// A source of the current time.
+pub(crate) trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A clock shared by the tasks of the engine.
+pub(crate) type SharedClock = Arc<dyn Clock>;
+
+/// The time of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl SystemClock {
+    pub(crate) fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it's set or advanced. Clones share the same time.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct ManualClock(Arc<std::sync::Mutex<DateTime<Utc>>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new(now: DateTime<Utc>) -> Self {
+        Self(Arc::new(std::sync::Mutex::new(now)))
+    }
+
+    /// A clock set to the given RFC 3339 time.
+    pub(crate) fn at(now: &str) -> Self {
+        Self::new(now.parse().expect("Test times are valid."))
+    }
+
+    pub(crate) fn advance(&self, by: chrono::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+
+    pub(crate) fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_only_move_a_manual_clock_when_advanced() {
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let shared = clock.shared();
+        assert_eq!(shared.now(), clock.now());
+
+        clock.advance(chrono::Duration::minutes(90));
+        assert_eq!(shared.now().to_rfc3339(), "2024-03-01T13:30:00+00:00");
+    }
+}
@@ -0,0 +1,389 @@
+use std::{num::NonZeroU16, ops::RangeInclusive, str::FromStr, time::Duration};
+
+use thiserror::Error;
+use tokio::{
+    sync::mpsc::{self, Sender},
+    task::JoinHandle,
+};
+#[cfg(feature = "grpc")]
+use tonic::transport::Channel;
+
+#[cfg(feature = "grpc")]
+use crate::grpc_input::proto::{ForwardTransactionsRequest, ingestion_client::IngestionClient};
+use crate::{
+    logging::log_event,
+    rejects::{RejectStage, RejectsReport},
+    transaction_types::{ClientId, Transaction},
+};
+
+// Experimental cluster mode. The client id space is split into a fixed number of shards (`client % shard count`) and
+// every process owns a statically configured subset of them. Transactions of shards owned by a peer are forwarded to
+// the peer in batches, through the `ForwardTransactions` call of its gRPC service, and transactions of shards nobody
+// owns are rejected by the validator chain. Since every client is owned by exactly one process, the account outputs of
+// the processes can simply be merged (see `merge`).
+//
+// A batch that can't be delivered is never dropped silently: its transactions are written to the dead letters, to be
+// processed again once the peer is back, and counted as internal failures so the run ends with the exit status of the
+// runs that have transactions to retry.
+
+// Number of transactions forwarded to a peer in one request.
+const FORWARD_BATCH: usize = 1000;
+// Number of transactions queued for a peer before the dispatcher has to wait.
+const FORWARD_QUEUE: usize = 4096;
+// Number of attempts to deliver a batch to a peer before giving up on it.
+const FORWARD_ATTEMPTS: u32 = 3;
+// How long a forward request can take, and how long to wait before the next attempt.
+#[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+const FORWARD_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum ShardError {
+    #[error("'{0}' is not a shard or a range of shards (e.g. 0-3,7).")]
+    InvalidShards(String),
+    #[error("'{0}' is not a peer (e.g. 4-7=http://10.0.0.2:7002).")]
+    InvalidPeer(String),
+}
+
+/// A batch of transactions that could not be delivered to a peer.
+#[derive(Debug, Error)]
+#[error("Cannot forward the transaction to {peer}: {reason}")]
+pub(crate) struct ForwardError {
+    peer: String,
+    reason: String,
+}
+
+/// A set of shards, written as a comma separated list of shards and ranges of shards (e.g. `0-3,7`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShardSet(Vec<RangeInclusive<u16>>);
+
+impl ShardSet {
+    fn contains(&self, shard: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&shard))
+    }
+
+    /// The shards of the set, in the order they were written.
+    pub(crate) fn shards(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().flat_map(|range| range.clone())
+    }
+}
+
+impl FromStr for ShardSet {
+    type Err = ShardError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || ShardError::InvalidShards(value.to_string());
+        value
+            .split(',')
+            .map(|part| {
+                let (start, end) = part.split_once('-').unwrap_or((part, part));
+                let start = start.trim().parse().map_err(|_| invalid())?;
+                let end = end.trim().parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                Ok(start..=end)
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// A peer process and the shards it owns, written as `<SHARDS>=<URL>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Peer {
+    shards: ShardSet,
+    /// URL of the gRPC service of the peer.
+    url: String,
+}
+
+impl Peer {
+    pub(crate) fn shards(&self) -> &ShardSet {
+        &self.shards
+    }
+}
+
+impl FromStr for Peer {
+    type Err = ShardError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (shards, url) = value
+            .split_once('=')
+            .ok_or_else(|| ShardError::InvalidPeer(value.to_string()))?;
+        Ok(Self {
+            shards: shards.parse()?,
+            url: url.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// Where a transaction is processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Route {
+    /// By the workers of this process. Transactions of shards that nobody owns are rejected by the workers.
+    Local,
+    /// By the peer at the index.
+    Peer(usize),
+}
+
+/// The static assignment of the shards to this process and its peers.
+#[derive(Debug, Clone)]
+pub(crate) struct ShardMap {
+    count: NonZeroU16,
+    owned: ShardSet,
+    peers: Vec<Peer>,
+}
+
+impl ShardMap {
+    pub(crate) fn new(count: NonZeroU16, owned: ShardSet, peers: Vec<Peer>) -> Self {
+        Self {
+            count,
+            owned,
+            peers,
+        }
+    }
+
+    pub(crate) fn shard(&self, client: ClientId) -> u16 {
+        u16::from(client) % self.count
+    }
+
+    pub(crate) fn owns(&self, client: ClientId) -> bool {
+        self.owned.contains(self.shard(client))
+    }
+
+    /// The shards owned by this process take precedence over the shards of the peers.
+    pub(crate) fn route(&self, client: ClientId) -> Route {
+        if self.owns(client) {
+            return Route::Local;
+        }
+        let shard = self.shard(client);
+        self.peers
+            .iter()
+            .position(|peer| peer.shards.contains(shard))
+            .map_or(Route::Local, Route::Peer)
+    }
+}
+
+/// The cluster mode of a node: the shards of the node and of its peers, and where the transactions that could not be
+/// forwarded are written.
+pub(crate) struct Cluster {
+    pub(crate) shards: ShardMap,
+    pub(crate) dead_letters: Option<RejectsReport>,
+}
+
+/// The queues of the tasks that forward transactions to the peers.
+pub(crate) struct Forwarders {
+    queues: Vec<Sender<Transaction>>,
+    handles: Vec<JoinHandle<u64>>,
+}
+
+impl Forwarders {
+    pub(crate) fn start(cluster: &Cluster) -> Self {
+        let (queues, handles) = cluster
+            .shards
+            .peers
+            .iter()
+            .map(|peer| {
+                let (tx, rx) = mpsc::channel(FORWARD_QUEUE);
+                let forward = forward(peer.url.clone(), rx, cluster.dead_letters.clone());
+                (tx, tokio::spawn(forward))
+            })
+            .unzip();
+        Self { queues, handles }
+    }
+
+    /// Queue a transaction for a peer. Returns false if the peer task stopped.
+    pub(crate) async fn send(&self, peer: usize, transaction: Transaction) -> bool {
+        self.queues[peer].send(transaction).await.is_ok()
+    }
+
+    /// Wait until all the queued transactions were forwarded, and return the number of transactions that could not be
+    /// delivered.
+    pub(crate) async fn finish(self) -> u64 {
+        drop(self.queues);
+        let mut failed = 0;
+        for handle in self.handles {
+            match handle.await {
+                Ok(undelivered) => failed += undelivered,
+                Err(err) => eprintln!("Forwarding to a peer encountered an error: {}", err),
+            }
+        }
+        failed
+    }
+}
+
+// Forward the transactions of a queue in batches until the queue is closed. Returns the number of transactions that
+// could not be delivered.
+async fn forward(
+    url: String,
+    mut rx: mpsc::Receiver<Transaction>,
+    dead_letters: Option<RejectsReport>,
+) -> u64 {
+    let mut peer = PeerClient::new(&url);
+    let mut forwarded = 0;
+    let mut failed = 0;
+    let mut batch = Vec::with_capacity(FORWARD_BATCH);
+    while rx.recv_many(&mut batch, FORWARD_BATCH).await > 0 {
+        let rows = batch.len();
+        match send_batch(&mut peer, &batch).await {
+            Ok(()) => forwarded += rows,
+            Err(reason) => {
+                failed += rows as u64;
+                log_event(
+                    "forward_failed",
+                    &[("peer", &url), ("rows", &rows), ("error", &reason)],
+                );
+                let err = ForwardError {
+                    peer: url.clone(),
+                    reason,
+                };
+                if let Some(dead_letters) = &dead_letters {
+                    for transaction in &batch {
+                        dead_letters.record(transaction, RejectStage::Forward, &err);
+                    }
+                }
+            }
+        }
+        batch.clear();
+    }
+    log_event(
+        "peer_closed",
+        &[
+            ("peer", &url),
+            ("forwarded", &forwarded),
+            ("failed", &failed),
+        ],
+    );
+    failed
+}
+
+// Send a batch, trying again after a pause when the peer can't be reached.
+async fn send_batch(peer: &mut PeerClient, batch: &[Transaction]) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for transaction in batch {
+        writer
+            .serialize(transaction)
+            .map_err(|err| err.to_string())?;
+    }
+    let csv = writer.into_inner().map_err(|err| err.to_string())?;
+
+    let mut attempt = 1;
+    loop {
+        match peer.forward(csv.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(_) if attempt < FORWARD_ATTEMPTS => {
+                attempt += 1;
+                tokio::time::sleep(FORWARD_BACKOFF).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// The client of the gRPC service of a peer. The connection is opened on the first call and opened again after it
+// failed.
+#[cfg(feature = "grpc")]
+struct PeerClient(Result<IngestionClient<Channel>, String>);
+
+#[cfg(feature = "grpc")]
+impl PeerClient {
+    fn new(url: &str) -> Self {
+        let channel = Channel::from_shared(url.to_string())
+            .map(|endpoint| endpoint.timeout(FORWARD_TIMEOUT).connect_lazy())
+            .map_err(|err| err.to_string());
+        Self(channel.map(IngestionClient::new))
+    }
+
+    async fn forward(&mut self, csv: Vec<u8>) -> Result<(), String> {
+        let client = self.0.as_mut().map_err(|err| err.clone())?;
+        client
+            .forward_transactions(ForwardTransactionsRequest { csv })
+            .await
+            .map(|_| ())
+            .map_err(|status| status.message().to_string())
+    }
+}
+
+// Without the gRPC service nothing can be delivered to the peers. The configuration check reports it before the run.
+#[cfg(not(feature = "grpc"))]
+struct PeerClient;
+
+#[cfg(not(feature = "grpc"))]
+impl PeerClient {
+    fn new(_url: &str) -> Self {
+        Self
+    }
+
+    async fn forward(&mut self, _csv: Vec<u8>) -> Result<(), String> {
+        Err("Forwarding to peers needs the grpc feature.".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_types::TransactionType;
+
+    use super::*;
+
+    #[test]
+    fn should_route_clients_by_shard() {
+        let shards = ShardMap::new(
+            NonZeroU16::new(8).unwrap(),
+            "0-3".parse().unwrap(),
+            vec!["4-6=http://peer:7002/".parse().unwrap()],
+        );
+
+        assert_eq!(shards.route(9.into()), Route::Local);
+        assert_eq!(shards.route(12.into()), Route::Peer(0));
+        // Nobody owns shard 7, so the workers reject the transaction.
+        assert_eq!(shards.route(15.into()), Route::Local);
+        assert!(!shards.owns(15.into()));
+        assert_eq!(shards.peers[0].url, "http://peer:7002");
+        assert_eq!(
+            "3-1".parse::<ShardSet>(),
+            Err(ShardError::InvalidShards("3-1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_the_transactions_that_cannot_be_forwarded_to_the_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.csv");
+        let cluster = Cluster {
+            shards: ShardMap::new(
+                NonZeroU16::new(2).unwrap(),
+                "0".parse().unwrap(),
+                // Nothing listens on the port.
+                vec!["1=http://127.0.0.1:1".parse().unwrap()],
+            ),
+            dead_letters: Some(RejectsReport::create(&path, false).unwrap()),
+        };
+
+        let forwarders = Forwarders::start(&cluster);
+        for tx in 1..=2 {
+            let deposit = Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                tx.into(),
+                Some(1.0.into()),
+            );
+            assert!(forwarders.send(0, deposit).await);
+        }
+        assert_eq!(forwarders.finish().await, 2);
+        drop(cluster);
+
+        let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&path)
+            .unwrap()
+            .records()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows.len(), 2);
+        for (tx, row) in ["1", "2"].into_iter().zip(rows) {
+            assert_eq!(
+                (&row[0], &row[1], &row[2], &row[3], &row[4]),
+                ("deposit", "1", tx, "1", "forward")
+            );
+            assert!(row[5].starts_with("Cannot forward the transaction to http://127.0.0.1:1: "));
+        }
+    }
+}
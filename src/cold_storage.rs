@@ -0,0 +1,291 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::logging::log_event;
+
+// Long term storage of the history archive (see `archive`). Old compactions are moved out of the history archive into
+// gzip compressed files that are named after the SHA-256 hash of their contents, so an archive file is never written
+// twice and can be checked for corruption when it's imported back.
+//
+// Retention: the most recent compactions of each account stay in the history archive, everything older is exported.
+// A compaction is the archived transactions of an account followed by its checkpoint row. Rows of a compaction that
+// doesn't have a checkpoint yet always stay.
+
+const ARCHIVE_EXTENSION: &str = ".csv.gz";
+
+#[derive(Debug, Error)]
+pub(crate) enum ColdStorageError {
+    #[error("Cannot access the archive files: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot read or write the archived rows: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("The history archive has no {0} column.")]
+    MissingColumn(&'static str),
+    #[error("The archive file {0} is not named after the hash of its contents.")]
+    Corrupted(PathBuf),
+}
+
+/// How much of the history archive is kept when exporting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetentionPolicy {
+    /// The number of most recent compactions of each account that stay in the history archive.
+    pub(crate) retain_compactions: usize,
+}
+
+// The rows of the history archive.
+struct History {
+    header: csv::StringRecord,
+    rows: Vec<csv::StringRecord>,
+}
+
+impl History {
+    fn read(path: &Path) -> Result<Self, ColdStorageError> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let header = reader.headers()?.clone();
+        let rows = reader.records().collect::<Result<_, _>>()?;
+        Ok(Self { header, rows })
+    }
+
+    fn column(&self, name: &'static str) -> Result<usize, ColdStorageError> {
+        self.header
+            .iter()
+            .position(|column| column == name)
+            .ok_or(ColdStorageError::MissingColumn(name))
+    }
+
+    // Split the rows into the ones to export and the ones to keep, both in their original order.
+    fn split(
+        self,
+        policy: RetentionPolicy,
+    ) -> Result<(Vec<csv::StringRecord>, Vec<csv::StringRecord>), ColdStorageError> {
+        let record = self.column("record")?;
+        let client = self.column("client")?;
+        let account = self.column("account")?;
+        let key = |row: &csv::StringRecord| (row[client].to_string(), row[account].to_string());
+        let is_checkpoint = |row: &csv::StringRecord| &row[record] == "checkpoint";
+
+        let mut compactions: HashMap<_, usize> = HashMap::new();
+        for row in self.rows.iter().filter(|row| is_checkpoint(row)) {
+            *compactions.entry(key(row)).or_default() += 1;
+        }
+
+        // Walk the compactions of each account from the oldest one and export until only the retained ones are left.
+        let mut exported_compactions: HashMap<_, usize> = HashMap::new();
+        let mut exported = Vec::new();
+        let mut kept = Vec::new();
+        for row in self.rows {
+            let key = key(&row);
+            let done = exported_compactions.entry(key.clone()).or_default();
+            let to_export = compactions
+                .get(&key)
+                .map_or(0, |count| count.saturating_sub(policy.retain_compactions));
+            if *done < to_export {
+                if is_checkpoint(&row) {
+                    *done += 1;
+                }
+                exported.push(row);
+            } else {
+                kept.push(row);
+            }
+        }
+        Ok((exported, kept))
+    }
+}
+
+fn write_csv(
+    header: &csv::StringRecord,
+    rows: &[csv::StringRecord],
+) -> Result<Vec<u8>, ColdStorageError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(row)?;
+    }
+    writer.into_inner().map_err(|err| err.into_error().into())
+}
+
+fn fields(row: &csv::StringRecord) -> Vec<String> {
+    row.iter().map(String::from).collect()
+}
+
+fn digest(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+// Replace a file without leaving it half written if the process stops in the middle.
+fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+/// Move the compactions that are not retained from the history archive to a compressed archive file in `dir`.
+/// Returns the archive file, or nothing if there was nothing to export.
+pub(crate) fn export(
+    history: &Path,
+    dir: &Path,
+    policy: RetentionPolicy,
+) -> Result<Option<PathBuf>, ColdStorageError> {
+    let rows = History::read(history)?;
+    let header = rows.header.clone();
+    let (exported, kept) = rows.split(policy)?;
+    if exported.is_empty() {
+        return Ok(None);
+    }
+
+    let contents = write_csv(&header, &exported)?;
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}{}", digest(&contents), ARCHIVE_EXTENSION));
+    // The same contents were already exported before if the file exists.
+    if !path.exists() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&contents)?;
+        replace_file(&path, &encoder.finish()?)?;
+    }
+    // The rows are removed from the history archive only once they are safely in the archive file.
+    replace_file(history, &write_csv(&header, &kept)?)?;
+
+    log_event(
+        "archive_exported",
+        &[
+            ("file", &path.display()),
+            ("rows", &exported.len()),
+            ("kept", &kept.len()),
+        ],
+    );
+    Ok(Some(path))
+}
+
+/// The archive files in a directory, from the most recently exported one, which is the order to import them in to
+/// keep the history archive in chronological order.
+pub(crate) fn archive_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().to_string_lossy().ends_with(ARCHIVE_EXTENSION) {
+            files.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    files.sort_by(|a, b| b.cmp(a));
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Move the rows of an archive file back to the history archive. The file is checked against its name and removed
+/// once imported. An archive holds older history than the history archive, so its rows go before the rows of the
+/// history archive. Rows that are already in the history archive are not added twice.
+pub(crate) fn import(archive: &Path, history: &Path) -> Result<usize, ColdStorageError> {
+    let mut contents = Vec::new();
+    GzDecoder::new(File::open(archive)?).read_to_end(&mut contents)?;
+    let name = archive
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(ARCHIVE_EXTENSION));
+    if name != Some(digest(&contents).as_str()) {
+        return Err(ColdStorageError::Corrupted(archive.to_path_buf()));
+    }
+
+    let mut reader = csv::Reader::from_reader(contents.as_slice());
+    let header = reader.headers()?.clone();
+    let existing = if history.exists() {
+        History::read(history)?.rows
+    } else {
+        Vec::new()
+    };
+    let present: HashSet<_> = existing.iter().map(fields).collect();
+
+    let mut rows = Vec::new();
+    for row in reader.records() {
+        let row = row?;
+        if !present.contains(&fields(&row)) {
+            rows.push(row);
+        }
+    }
+    let imported = rows.len();
+    rows.extend(existing);
+    replace_file(history, &write_csv(&header, &rows)?)?;
+    fs::remove_file(archive)?;
+
+    log_event(
+        "archive_imported",
+        &[("file", &archive.display()), ("rows", &imported)],
+    );
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const HISTORY: &str =
+        "record,client,account,tx,type,amount,dispute_state,released_to,available,held,escrow,total
+transaction,1,main,1,deposit,1,none,,,,,
+checkpoint,1,main,3,,,,,3,0,0,3
+transaction,2,main,4,deposit,1,none,,,,,
+checkpoint,2,main,6,,,,,3,0,0,3
+transaction,1,main,2,deposit,1,none,,,,,
+checkpoint,1,main,5,,,,,4,0,0,4
+transaction,1,main,7,deposit,1,none,,,,,
+";
+
+    #[test]
+    fn should_export_old_compactions_and_import_them_back() {
+        let dir = TempDir::new().unwrap();
+        let history = dir.path().join("history.csv");
+        fs::write(&history, HISTORY).unwrap();
+        let policy = RetentionPolicy {
+            retain_compactions: 1,
+        };
+
+        let archive = export(&history, &dir.path().join("cold"), policy)
+            .unwrap()
+            .unwrap();
+        // Only the first compaction of client 1 is older than the retained one.
+        assert_eq!(
+            fs::read_to_string(&history).unwrap(),
+            "record,client,account,tx,type,amount,dispute_state,released_to,available,held,escrow,total
+transaction,2,main,4,deposit,1,none,,,,,
+checkpoint,2,main,6,,,,,3,0,0,3
+transaction,1,main,2,deposit,1,none,,,,,
+checkpoint,1,main,5,,,,,4,0,0,4
+transaction,1,main,7,deposit,1,none,,,,,
+"
+        );
+        assert!(
+            export(&history, &dir.path().join("cold"), policy)
+                .unwrap()
+                .is_none()
+        );
+
+        assert_eq!(import(&archive, &history).unwrap(), 2);
+        assert!(!archive.exists());
+        assert_eq!(fs::read_to_string(&history).unwrap(), HISTORY);
+    }
+
+    #[test]
+    fn should_reject_corrupted_archive_files() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir
+            .path()
+            .join(format!("{}{}", digest(b"other"), ARCHIVE_EXTENSION));
+        let mut encoder = GzEncoder::new(File::create(&archive).unwrap(), Compression::default());
+        encoder.write_all(HISTORY.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        assert!(matches!(
+            import(&archive, &dir.path().join("history.csv")),
+            Err(ColdStorageError::Corrupted(_))
+        ));
+        assert!(archive.exists());
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    cli::Cli, grpc_input, ingest, object_input::ObjectInput, output::OutputSchema,
+    transaction_types::ClientId,
+};
+
+// Checks of the combinations of options that clap can't express. They run before anything is opened or started, and
+// all the problems are reported together so they can be fixed in one go instead of finding them one run at a time.
+// `payments-engine config check <OPTIONS>` runs them without processing anything.
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum ConfigError {
+    #[error("{flag}: the file '{path}' doesn't exist.")]
+    MissingFile { flag: &'static str, path: PathBuf },
+    #[error("{flag}: '{path}' is not a directory.")]
+    NotADirectory { flag: &'static str, path: PathBuf },
+    #[error("{flag} and {other} write to the same file '{path}'.")]
+    SameFile {
+        flag: &'static str,
+        other: &'static str,
+        path: PathBuf,
+    },
+    #[error("--input: {0}")]
+    DbInput(String),
+    #[error("--kafka: {0}")]
+    KafkaInput(String),
+    #[error("--grpc-listen: {0}")]
+    GrpcInput(String),
+    #[error("<TRANSACTIONS_FILE>: {0}")]
+    InputPattern(String),
+    #[error("<TRANSACTIONS_FILE>: {0}")]
+    ObjectInput(String),
+    #[error("{flag}: shard {shard} is not below --shard-count {count}.")]
+    ShardOutOfRange {
+        flag: &'static str,
+        shard: u16,
+        count: u16,
+    },
+    #[error(
+        "--peer: transactions are forwarded to the peers over gRPC, which is not supported by this build. Build with the grpc feature."
+    )]
+    PeerUnsupported,
+    #[error("Shard {0} is owned by more than one node (--shards and --peer).")]
+    ShardOwnedTwice(u16),
+    #[error("--client-max-total is given more than once for client {0}.")]
+    DuplicateClientLimit(ClientId),
+    #[error(
+        "--extended-report only adds the escrow column to the v1 output schema, not to {0}. List the escrow column in a custom schema instead."
+    )]
+    ExtendedReportIgnored(OutputSchema),
+    #[error(
+        "--run-id is only written to --account-updates and --run-manifest, and neither is given."
+    )]
+    RunIdIgnored,
+}
+
+/// All the problems of a configuration.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) struct ConfigReport(Vec<ConfigError>);
+
+impl Display for ConfigReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The configuration has {} problem(s):", self.0.len())?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the options of a run. The files and directories are only looked at, nothing is created.
+pub(crate) fn check(cli: &Cli) -> Result<(), ConfigReport> {
+    let mut problems = Vec::new();
+
+    let transactions_files = match ingest::expand_inputs(&cli.transactions_files) {
+        Ok(files) => files,
+        Err(err) => {
+            problems.push(ConfigError::InputPattern(err.to_string()));
+            Vec::new()
+        }
+    };
+    let inputs = [
+        ("--blocklist", cli.blocklist.as_ref()),
+        ("--dispute-policy", cli.dispute_policy.as_ref()),
+        ("--bootstrap", cli.bootstrap.as_ref()),
+    ];
+    // The objects of an object store are only looked at when they are read.
+    let mut files = Vec::new();
+    for path in &transactions_files {
+        match ObjectInput::from_path(path) {
+            Some(object) => {
+                if let Err(err) = object.and_then(|object| object.check()) {
+                    problems.push(ConfigError::ObjectInput(err.to_string()));
+                }
+            }
+            None => files.push(path),
+        }
+    }
+    let inputs = files
+        .into_iter()
+        .map(|path| ("<TRANSACTIONS_FILE>", Some(path)))
+        .chain(inputs);
+    for (flag, path) in inputs {
+        if let Some(path) = path
+            && !path.is_file()
+        {
+            problems.push(ConfigError::MissingFile {
+                flag,
+                path: path.clone(),
+            });
+        }
+    }
+    if let Some(input) = &cli.input
+        && let Err(err) = input.check()
+    {
+        problems.push(ConfigError::DbInput(err.to_string()));
+    }
+    if let Some(input) = &cli.kafka
+        && let Err(err) = input.check()
+    {
+        problems.push(ConfigError::KafkaInput(err.to_string()));
+    }
+    if cli.grpc_listen.is_some()
+        && let Err(err) = grpc_input::check()
+    {
+        problems.push(ConfigError::GrpcInput(err.to_string()));
+    }
+
+    // The watched directory is listed from the start. The other directories are created when they don't exist.
+    if let Some(dir) = &cli.watch_dir
+        && !dir.is_dir()
+    {
+        problems.push(ConfigError::NotADirectory {
+            flag: "--watch-dir",
+            path: dir.clone(),
+        });
+    }
+    let dirs = [
+        ("--state-dir", &cli.state_dir),
+        ("--profile", &cli.profile),
+        ("--archive-dir", &cli.archive_dir),
+    ];
+    for (flag, dir) in dirs {
+        if let Some(dir) = dir
+            && dir.exists()
+            && !dir.is_dir()
+        {
+            problems.push(ConfigError::NotADirectory {
+                flag,
+                path: dir.clone(),
+            });
+        }
+    }
+
+    let outputs = [
+        ("--rejects", &cli.rejects),
+        ("--dead-letters", &cli.dead_letters),
+        ("--run-manifest", &cli.run_manifest),
+        ("--settlement-report", &cli.settlement_report),
+        ("--account-updates", &cli.account_updates),
+        ("--ledger-export", &cli.ledger_export),
+        ("--history-archive", &cli.history_archive),
+        ("--watch-report", &cli.watch_report),
+    ];
+    let mut written: Vec<(&'static str, &Path)> = Vec::new();
+    for (flag, path) in outputs {
+        let Some(path) = path else { continue };
+        if let Some((other, _)) = written.iter().find(|(_, other)| *other == path) {
+            problems.push(ConfigError::SameFile {
+                flag: other,
+                other: flag,
+                path: path.clone(),
+            });
+        }
+        written.push((flag, path));
+    }
+
+    if let Some(count) = cli.shard_count {
+        check_shards(cli, count.get(), &mut problems);
+    }
+    if !cli.peers.is_empty() && grpc_input::check().is_err() {
+        problems.push(ConfigError::PeerUnsupported);
+    }
+
+    let mut limited = HashSet::new();
+    for (client, _) in &cli.client_max_totals {
+        if !limited.insert(client) {
+            problems.push(ConfigError::DuplicateClientLimit(*client));
+        }
+    }
+
+    if cli.extended_report && cli.output_schema != OutputSchema::V1 {
+        problems.push(ConfigError::ExtendedReportIgnored(
+            cli.output_schema.clone(),
+        ));
+    }
+    if cli.run_id.is_some() && cli.account_updates.is_none() && cli.run_manifest.is_none() {
+        problems.push(ConfigError::RunIdIgnored);
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigReport(problems))
+    }
+}
+
+// Every shard of this node and of its peers must exist, and must be owned by a single node.
+fn check_shards(cli: &Cli, count: u16, problems: &mut Vec<ConfigError>) {
+    let nodes = cli
+        .shards
+        .iter()
+        .map(|shards| ("--shards", shards))
+        .chain(cli.peers.iter().map(|peer| ("--peer", peer.shards())));
+    let mut owned = HashSet::new();
+    for (flag, shards) in nodes {
+        for shard in shards.shards() {
+            if shard >= count {
+                problems.push(ConfigError::ShardOutOfRange { flag, shard, count });
+            } else if !owned.insert(shard) {
+                problems.push(ConfigError::ShardOwnedTwice(shard));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    fn problems(args: &[&str]) -> Vec<ConfigError> {
+        let cli = Cli::try_parse_from(["payments-engine"].iter().chain(args)).unwrap();
+        match check(&cli) {
+            Ok(()) => vec![],
+            Err(report) => report.0,
+        }
+    }
+
+    #[test]
+    fn should_accept_a_consistent_configuration() {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = input.path().to_str().unwrap();
+
+        assert_eq!(
+            problems(&[path, "--state-dir", dir.path().to_str().unwrap()]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn should_report_all_the_problems_together() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let file_path = file.path().to_str().unwrap();
+
+        let problems = problems(&[
+            "missing.csv",
+            "--state-dir",
+            file_path,
+            "--rejects",
+            "out.csv",
+            "--ledger-export",
+            "out.csv",
+            "--client-max-total",
+            "7=10",
+            "--client-max-total",
+            "7=20",
+            "--extended-report",
+            "--output-schema",
+            "v2",
+            "--run-id",
+            "batch-7",
+        ]);
+
+        assert_eq!(
+            problems,
+            vec![
+                ConfigError::MissingFile {
+                    flag: "<TRANSACTIONS_FILE>",
+                    path: "missing.csv".into()
+                },
+                ConfigError::NotADirectory {
+                    flag: "--state-dir",
+                    path: file.path().to_path_buf()
+                },
+                ConfigError::SameFile {
+                    flag: "--rejects",
+                    other: "--ledger-export",
+                    path: "out.csv".into()
+                },
+                ConfigError::DuplicateClientLimit(7.into()),
+                ConfigError::ExtendedReportIgnored(OutputSchema::V2),
+                ConfigError::RunIdIgnored,
+            ]
+        );
+    }
+
+    #[test]
+    fn should_check_the_shards_of_the_cluster() {
+        let input = tempfile::NamedTempFile::new().unwrap();
+
+        assert_eq!(
+            problems(&[
+                input.path().to_str().unwrap(),
+                "--shard-count",
+                "4",
+                "--shards",
+                "0-2",
+                "--peer",
+                "2-4=http://peer:7002",
+            ])[..2],
+            [
+                ConfigError::ShardOwnedTwice(2),
+                ConfigError::ShardOutOfRange {
+                    flag: "--peer",
+                    shard: 4,
+                    count: 4
+                },
+            ]
+        );
+    }
+}
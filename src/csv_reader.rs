@@ -1,48 +1,754 @@
-use std::{error::Error, fs::File, path::Path};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt::Display,
+    fs::File,
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use crate::transaction_types::Transaction;
-use csv::{Reader, StringRecord};
+use clap::ValueEnum;
+
+use crate::{
+    logging::log_event,
+    pipeline::Parser,
+    transaction_types::{ClientId, Transaction},
+};
+use csv::{ByteRecord, Reader, StringRecord};
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE};
+use encoding_rs_io::{DecodeReaderBytes, DecodeReaderBytesBuilder};
+use flate2::read::MultiGzDecoder;
+use thiserror::Error;
+
+// Position of the client and amount fields in a record.
+const CLIENT_FIELD: usize = 1;
+const AMOUNT_FIELD: usize = 3;
+// Number of bytes at the start of a file that are checked before reading it.
+const SNIFF_LEN: u64 = 8192;
+// The first bytes of a gzip file and of a zstd frame.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How the input files are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum Compression {
+    /// Recognize the compressed files by their extension (`.gz`, `.zst`) or their first bytes.
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // The compression of a file, from its extension or its first bytes if it's not known.
+    fn of(self, path: &Path, start: &[u8]) -> Self {
+        if self != Compression::Auto {
+            return self;
+        }
+        let extension = |expected: &str| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case(expected))
+        };
+        if start.starts_with(&GZIP_MAGIC) || extension("gz") {
+            Compression::Gzip
+        } else if start.starts_with(&ZSTD_MAGIC) || extension("zst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.to_possible_value().expect("no variant is skipped");
+        f.write_str(name.get_name())
+    }
+}
+
+/// A error describing why an input file cannot be read.
+#[derive(Debug, Error)]
+pub(crate) enum ReaderError {
+    #[error("The input file {0} does not exist.")]
+    NotFound(PathBuf),
+    #[error("The input file {0} cannot be read: permission denied.")]
+    PermissionDenied(PathBuf),
+    #[error("The input file {0} is not a regular file.")]
+    NotAFile(PathBuf),
+    #[error("The input file {0} is not a CSV file: it contains binary data. Is it compressed?")]
+    NotCsv(PathBuf),
+    #[error(
+        "The input file {0} is not valid UTF-8. Pass --encoding with the encoding of the file."
+    )]
+    Encoding(PathBuf),
+    #[error("The input file {path} is not a valid {compression} file: {source}")]
+    Decompression {
+        path: PathBuf,
+        compression: Compression,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot read the input file {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl ReaderError {
+    pub(crate) fn io(path: &Path, source: io::Error) -> Self {
+        let path = path.to_path_buf();
+        match source.kind() {
+            io::ErrorKind::NotFound => ReaderError::NotFound(path),
+            io::ErrorKind::PermissionDenied => ReaderError::PermissionDenied(path),
+            io::ErrorKind::IsADirectory => ReaderError::NotAFile(path),
+            _ => ReaderError::Io { path, source },
+        }
+    }
+}
+
+// Check the start of a file so that a file that is obviously not a CSV file in the expected encoding fails with a clear
+// reason instead of a parse error on every record.
+fn sniff(
+    path: &Path,
+    start: &[u8],
+    encoding: Option<&'static Encoding>,
+) -> Result<(), ReaderError> {
+    let encoding = Encoding::for_bom(start)
+        .map(|(encoding, _)| encoding)
+        .or(encoding)
+        .unwrap_or(UTF_8);
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        return Ok(());
+    }
+    if start.contains(&0) {
+        return Err(ReaderError::NotCsv(path.to_path_buf()));
+    }
+    // A character can be cut at the end of the checked bytes, which is not an error.
+    if encoding == UTF_8
+        && let Err(err) = std::str::from_utf8(start)
+        && err.error_len().is_some()
+    {
+        return Err(ReaderError::Encoding(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// A error describing why a record could not be turned into a transaction.
+#[derive(Debug, Error)]
+pub(crate) enum RecordError {
+    #[error("{0}")]
+    Csv(#[from] csv::Error),
+    #[error("Line {0} is not valid UTF-8.")]
+    InvalidUtf8(u64),
+    #[error("Amount '{amount}' on line {line} is ambiguous: {reason}")]
+    AmbiguousAmount {
+        amount: String,
+        line: u64,
+        reason: &'static str,
+    },
+}
+
+/// Metadata about an input file that is collected while it is read.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FileMetadata {
+    pub(crate) path: PathBuf,
+    /// Size of the file in bytes.
+    pub(crate) size: u64,
+    /// The header row that was skipped, if any.
+    pub(crate) header: Option<String>,
+    /// Number of data rows read from the file (including the rows that fail to parse).
+    pub(crate) rows: u64,
+}
+
+// Counts the bytes read from a compressed file, since the position of the CSV reader is in the decompressed input.
+struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
 
 /// A parser for the input CSV files.
 pub(crate) struct CsvFileReader {
-    reader: Reader<File>,
+    reader: Reader<DecodeReaderBytes<Box<dyn Read + Send>, Vec<u8>>>,
+    // The bytes read from the file if it's compressed.
+    compressed_read: Option<Arc<AtomicU64>>,
+    /// Accept amounts with comma decimal separators and thousands separators.
+    lenient_amounts: bool,
+    metadata: FileMetadata,
+    // How the records map to transactions, known once the first record was read.
+    layout: Arc<RecordLayout>,
+    first_record: bool,
 }
 
 impl CsvFileReader {
-    /// Initialize the parser from a specified file.
-    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+    /// Initialize the parser from a specified file that uses the given encoding.
+    /// A byte order mark always takes precedence over the encoding and is stripped from the input.
+    /// Files without a byte order mark are assumed to be UTF-8 if no encoding is specified.
+    /// Gzip and zstd files, recognized by their extension or their first bytes, are decompressed as they are read.
+    pub(crate) fn from_path_with_encoding<P: AsRef<Path>>(
+        path: P,
+        encoding: Option<&'static Encoding>,
+    ) -> Result<Self, ReaderError> {
+        Self::from_path_with_compression(path, encoding, Compression::Auto)
+    }
+
+    /// Initialize the parser from a specified file that uses the given encoding and compression.
+    pub(crate) fn from_path_with_compression<P: AsRef<Path>>(
+        path: P,
+        encoding: Option<&'static Encoding>,
+        compression: Compression,
+    ) -> Result<Self, ReaderError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| ReaderError::io(path, err))?;
+        let file_metadata = file.metadata().map_err(|err| ReaderError::io(path, err))?;
+        if !file_metadata.is_file() {
+            return Err(ReaderError::NotAFile(path.to_path_buf()));
+        }
+        Self::from_reader(
+            path,
+            file_metadata.len(),
+            Box::new(file),
+            encoding,
+            compression,
+        )
+    }
+
+    /// Initialize the parser from input that is streamed from somewhere else than a local file, e.g. an object store.
+    /// The input is checked and decompressed like a file, and `path` and `size` stand for the path and the size of the
+    /// file.
+    pub(crate) fn from_reader(
+        path: &Path,
+        size: u64,
+        mut file: Box<dyn Read + Send>,
+        encoding: Option<&'static Encoding>,
+        compression: Compression,
+    ) -> Result<Self, ReaderError> {
+        let mut start = Vec::new();
+        file.by_ref()
+            .take(SNIFF_LEN)
+            .read_to_end(&mut start)
+            .map_err(|err| ReaderError::io(path, err))?;
+        let metadata = FileMetadata {
+            path: path.to_path_buf(),
+            size,
+            ..Default::default()
+        };
+
+        let compression = compression.of(path, &start);
+        if compression == Compression::None {
+            sniff(path, &start, encoding)?;
+            return Ok(Self::new(
+                Box::new(Cursor::new(start).chain(file)),
+                encoding,
+                metadata,
+            ));
+        }
+
+        // The decompressed input is checked like an uncompressed file. Concatenated gzip files and zstd frames are
+        // read as one.
+        let read = Arc::new(AtomicU64::new(0));
+        let input = CountingReader {
+            inner: Cursor::new(start).chain(file),
+            read: Arc::clone(&read),
+        };
+        let invalid = |source| ReaderError::Decompression {
+            path: path.to_path_buf(),
+            compression,
+            source,
+        };
+        let mut decoder: Box<dyn Read + Send> = match compression {
+            Compression::Zstd => Box::new(zstd::Decoder::new(input).map_err(invalid)?),
+            _ => Box::new(MultiGzDecoder::new(input)),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .by_ref()
+            .take(SNIFF_LEN)
+            .read_to_end(&mut decompressed)
+            .map_err(invalid)?;
+        sniff(path, &decompressed, encoding)?;
+        let mut reader = Self::new(
+            Box::new(Cursor::new(decompressed).chain(decoder)),
+            encoding,
+            metadata,
+        );
+        reader.compressed_read = Some(read);
+        Ok(reader)
+    }
+
+    /// Initialize the parser from input that is already in memory, e.g. the body of a request.
+    /// The name is reported in place of a path.
+    pub(crate) fn from_bytes(
+        name: &str,
+        bytes: Vec<u8>,
+        encoding: Option<&'static Encoding>,
+    ) -> Self {
+        let metadata = FileMetadata {
+            path: PathBuf::from(name),
+            size: bytes.len() as u64,
+            ..Default::default()
+        };
+        Self::new(Box::new(Cursor::new(bytes)), encoding, metadata)
+    }
+
+    fn new(
+        input: Box<dyn Read + Send>,
+        encoding: Option<&'static Encoding>,
+        metadata: FileMetadata,
+    ) -> Self {
+        let decoder = DecodeReaderBytesBuilder::new()
+            .encoding(encoding)
+            .bom_override(true)
+            .build(input);
+
         let reader = csv::ReaderBuilder::new()
             .trim(csv::Trim::All) // Remove all whitespace.
             .has_headers(false) // So that we can support both headerless and inputs with headers
-            .from_path(path)?;
+            .from_reader(decoder);
 
-        Ok(CsvFileReader { reader })
+        CsvFileReader {
+            reader,
+            compressed_read: None,
+            lenient_amounts: false,
+            metadata,
+            layout: Arc::new(RecordLayout::default()),
+            first_record: true,
+        }
     }
 
-    /// Returns an iterator over the deserialized records.
-    pub(crate) fn records(&mut self) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
-        // Chech if the first record is either a header or input data.
+    /// Metadata about the file. The header and row count are complete once all the transactions were read.
+    pub(crate) fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    /// Number of bytes of the input read so far, after it was decoded to UTF-8. For a compressed file, the number of
+    /// compressed bytes instead, so it can be compared with the size of the file.
+    pub(crate) fn bytes_read(&self) -> u64 {
+        match &self.compressed_read {
+            Some(read) => read.load(Ordering::Relaxed),
+            None => self.reader.position().byte(),
+        }
+    }
+
+    /// Normalize amounts like "1.234,56" or "1,234.56" before parsing them.
+    pub(crate) fn with_lenient_amounts(mut self, lenient_amounts: bool) -> Self {
+        self.lenient_amounts = lenient_amounts;
+        self
+    }
+
+    // Check if the first record of the file is either a header or input data. Returns true if it's a header, which is
+    // recorded in the metadata and sets the layout of the records.
+    fn take_header(&mut self, record: &StringRecord) -> bool {
+        if !std::mem::take(&mut self.first_record) {
+            return false;
+        }
+        let Some((header_layout, kind)) = detect_header(record) else {
+            return false;
+        };
+        let header = record.iter().collect::<Vec<_>>().join(", ");
+        if kind != HeaderKind::Canonical {
+            log_event(
+                "header_skipped",
+                &[
+                    ("path", &self.metadata.path.display()),
+                    ("line", &record.position().map_or(1, |pos| pos.line())),
+                    ("header", &header),
+                    ("suspected", &(kind == HeaderKind::Suspected)),
+                ],
+            );
+        }
+        self.metadata.header = Some(header);
+        self.layout = Arc::new(header_layout);
+        true
+    }
+
+    /// The clients of all the records, reading only the client field. Records without a valid client are skipped
+    /// since they can't be applied to any account.
+    pub(crate) fn scan_clients(mut self) -> HashSet<ClientId> {
+        let mut clients = HashSet::new();
+        let mut record = ByteRecord::new();
+        let mut client_field = CLIENT_FIELD;
+        let mut first_record = true;
+        loop {
+            match self.reader.read_byte_record(&mut record) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) if err.is_io_error() => break,
+                Err(_) => continue,
+            }
+            if std::mem::take(&mut first_record)
+                && let Ok(first) = StringRecord::from_byte_record(record.clone())
+                && let Some((layout, _)) = detect_header(&first)
+            {
+                client_field = layout
+                    .headers
+                    .and_then(|headers| headers.iter().position(|name| name == "client"))
+                    .unwrap_or(CLIENT_FIELD);
+                continue;
+            }
+            if let Some(client) = record
+                .get(client_field)
+                .and_then(|field| std::str::from_utf8(field).ok())
+                .and_then(|field| field.parse::<u16>().ok())
+            {
+                clients.insert(client.into());
+            }
+        }
+        clients
+    }
+
+    /// Read up to `len` records without parsing them, so that they can be parsed on another thread.
+    /// Returns `None` at the end of the input.
+    pub(crate) fn read_chunk(&mut self, len: usize) -> Option<RawChunk> {
+        let mut records = Vec::with_capacity(len);
+        while records.len() < len {
+            let mut record = ByteRecord::new();
+            match self.reader.read_byte_record(&mut record) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    records.push(Err(err));
+                    continue;
+                }
+            }
+            if self.first_record
+                && let Ok(first) = StringRecord::from_byte_record(record.clone())
+                && self.take_header(&first)
+            {
+                continue;
+            }
+            self.first_record = false;
+            self.metadata.rows += 1;
+            records.push(Ok(record));
+        }
+        (!records.is_empty()).then(|| RawChunk {
+            records,
+            layout: Arc::clone(&self.layout),
+            lenient_amounts: self.lenient_amounts,
+        })
+    }
+}
+
+/// Records read from the input that are not parsed yet, with what is needed to parse them.
+pub(crate) struct RawChunk {
+    records: Vec<Result<ByteRecord, csv::Error>>,
+    layout: Arc<RecordLayout>,
+    lenient_amounts: bool,
+}
+
+impl RawChunk {
+    /// Parse the records into transactions, in order.
+    pub(crate) fn parse(self) -> Vec<Result<Transaction, RecordError>> {
+        self.records
+            .into_iter()
+            .map(|record| {
+                let record = record?;
+                let line = record.position().map_or(0, |pos| pos.line());
+                let record = StringRecord::from_byte_record(record)
+                    .map_err(|_| RecordError::InvalidUtf8(line))?;
+                parse_record(&record, &self.layout, self.lenient_amounts)
+            })
+            .collect()
+    }
+}
+
+/// Parses the records of an input that arrives one line at a time, e.g. over a socket. Like a file, the input can start
+/// with a header.
+pub(crate) struct LineParser {
+    layout: RecordLayout,
+    lenient_amounts: bool,
+    // Number of lines parsed so far.
+    lines: u64,
+}
+
+impl LineParser {
+    pub(crate) fn new(lenient_amounts: bool) -> Self {
+        Self {
+            layout: RecordLayout::default(),
+            lenient_amounts,
+            lines: 0,
+        }
+    }
+
+    /// Parse the next line. Returns nothing for the header.
+    pub(crate) fn parse(&mut self, line: &str) -> Option<Result<Transaction, RecordError>> {
+        self.lines += 1;
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .has_headers(false)
+            .from_reader(line.as_bytes());
         let mut record = StringRecord::new();
-        let pos = self.reader.position().clone();
-        if self.reader.read_record(&mut record).is_ok()
-            && record != vec!["type", "client", "tx", "amount"]
+        if let Err(err) = reader.read_record(&mut record) {
+            return Some(Err(err.into()));
+        }
+        let mut position = csv::Position::new();
+        position.set_line(self.lines);
+        record.set_position(Some(position));
+
+        if self.lines == 1
+            && let Some((layout, _)) = detect_header(&record)
         {
-            // If the record is a header, seek back to the beginning and start deserializing.
-            let _ = self.reader.seek(pos);
+            self.layout = layout;
+            return None;
+        }
+        Some(parse_record(&record, &self.layout, self.lenient_amounts))
+    }
+}
+
+// Names of the columns in the expected order.
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+// Names of all the columns a transaction can have, the optional ones included.
+const KNOWN_COLUMNS: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "account",
+    "to_account",
+    "release_to",
+    "source",
+    "currency",
+];
+
+/// Describes how the fields of a record map to the fields of a transaction.
+#[derive(Debug, Clone)]
+struct RecordLayout {
+    /// Column names used to deserialize records by name. Records are deserialized by position if there's no header.
+    headers: Option<StringRecord>,
+    /// Position of the amount field in a record.
+    amount_field: usize,
+}
+
+impl Default for RecordLayout {
+    fn default() -> Self {
+        Self {
+            headers: None,
+            amount_field: AMOUNT_FIELD,
+        }
+    }
+}
+
+/// How a header row was recognized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderKind {
+    /// Exactly `type, client, tx, amount`.
+    Canonical,
+    /// The expected column names with a different case, order or extra columns.
+    Tolerated,
+    /// A row that can't be data, with variants of the column names (e.g. `client_id`).
+    Suspected,
+}
+
+/// Check if the first record of the file is a header. Header detection is case insensitive and tolerates extra columns.
+/// A first record that has a non-numeric client id and whose fields all start with the name of a column (e.g. `Client_ID`
+/// or `amount_eur`) is treated as a header too. Any other first record is data, and is reported if it's malformed.
+fn detect_header(record: &StringRecord) -> Option<(RecordLayout, HeaderKind)> {
+    let names: StringRecord = record.iter().map(|field| field.to_lowercase()).collect();
+
+    if COLUMNS
+        .iter()
+        .all(|column| names.iter().any(|name| name == *column))
+    {
+        let kind = if *record == COLUMNS.to_vec() {
+            HeaderKind::Canonical
+        } else {
+            HeaderKind::Tolerated
+        };
+
+        let amount_field = names
+            .iter()
+            .position(|name| name == "amount")
+            .expect("Amount column is present.");
+        let layout = RecordLayout {
+            headers: Some(names),
+            amount_field,
+        };
+        return Some((layout, kind));
+    }
+
+    let client = names.get(1).unwrap_or_default();
+    let is_column_name = |name: &str| KNOWN_COLUMNS.iter().any(|column| name.starts_with(column));
+    if client.parse::<u16>().is_err()
+        && names
+            .iter()
+            .filter(|name| !name.is_empty())
+            .all(is_column_name)
+    {
+        return Some((RecordLayout::default(), HeaderKind::Suspected));
+    }
+
+    None
+}
+
+// Turn a single record into a transaction.
+fn parse_record(
+    record: &StringRecord,
+    layout: &RecordLayout,
+    lenient_amounts: bool,
+) -> Result<Transaction, RecordError> {
+    if lenient_amounts
+        && let Some(amount) = record.get(layout.amount_field)
+        && let Cow::Owned(normalized) =
+            normalize_amount(amount).map_err(|reason| RecordError::AmbiguousAmount {
+                amount: amount.to_string(),
+                line: record.position().map_or(0, |pos| pos.line()),
+                reason,
+            })?
+    {
+        let mut normalized_record: StringRecord = record
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                if idx == layout.amount_field {
+                    &normalized
+                } else {
+                    field
+                }
+            })
+            .collect();
+        normalized_record.set_position(record.position().cloned());
+        let transaction: Transaction = normalized_record.deserialize(layout.headers.as_ref())?;
+        return Ok(transaction.with_line(record.position().map(|pos| pos.line())));
+    }
+
+    let transaction: Transaction = record.deserialize(layout.headers.as_ref())?;
+    Ok(transaction.with_line(record.position().map(|pos| pos.line())))
+}
+
+// Check that the digits before the decimal separator are correctly grouped in thousands (e.g. 1,234,567).
+fn is_grouped_in_thousands(integer_part: &str, separator: char) -> bool {
+    let mut groups = integer_part.trim_start_matches('-').split(separator);
+    let first_group_valid = groups.next().is_some_and(|group| {
+        (1..=3).contains(&group.len()) && group.chars().all(|c| c.is_ascii_digit())
+    });
+    first_group_valid
+        && groups.all(|group| group.len() == 3 && group.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Normalize an amount that uses a comma decimal separator and/or thousands separators into the "1234.56" format.
+/// Returns the reason when the amount is ambiguous (e.g. "1,234" and "1.234" can be either 1234 or 1.234).
+fn normalize_amount(amount: &str) -> Result<Cow<'_, str>, &'static str> {
+    let commas = amount.matches(',').count();
+    let dots = amount.matches('.').count();
+
+    match (commas, dots) {
+        (0, 0) => Ok(Cow::Borrowed(amount)),
+        // A single dot followed by exactly 3 digits could also be a thousands separator, unless the digits before it
+        // can't start a group of thousands (e.g. "0.123" or "1234.567").
+        (0, 1) => {
+            let (integer_part, fraction) = amount.split_once('.').expect("Dot is present.");
+            if fraction.len() == 3
+                && is_grouped_in_thousands(amount, '.')
+                && !integer_part.trim_start_matches('-').starts_with('0')
+            {
+                Err("a dot followed by 3 digits can be a decimal or a thousands separator")
+            } else {
+                Ok(Cow::Borrowed(amount))
+            }
+        }
+        // Both separators: the last one is the decimal separator.
+        (_, _) if commas > 0 && dots > 0 => {
+            let (decimal, thousands) = if amount.rfind(',') > amount.rfind('.') {
+                (',', '.')
+            } else {
+                ('.', ',')
+            };
+            let (integer_part, fraction) = amount
+                .rsplit_once(decimal)
+                .expect("Decimal separator is present.");
+            if fraction.contains(thousands) || amount.matches(decimal).count() != 1 {
+                return Err("mixed up decimal and thousands separators");
+            }
+            if !is_grouped_in_thousands(integer_part, thousands) {
+                return Err("thousands separators are not grouping 3 digits");
+            }
+            Ok(Cow::Owned(format!(
+                "{}.{}",
+                integer_part.replace(thousands, ""),
+                fraction
+            )))
+        }
+        // A single comma followed by exactly 3 digits could be either a decimal or a thousands separator.
+        (1, 0) => {
+            let (integer_part, fraction) = amount.split_once(',').expect("Comma is present.");
+            if fraction.len() == 3 {
+                Err("a comma followed by 3 digits can be a decimal or a thousands separator")
+            } else {
+                Ok(Cow::Owned(format!("{}.{}", integer_part, fraction)))
+            }
         }
-        self.reader.deserialize::<Transaction>()
+        // Multiple separators of the same kind can only be thousands separators.
+        (_, 0) | (0, _) => {
+            let separator = if commas > 0 { ',' } else { '.' };
+            if !is_grouped_in_thousands(amount, separator) {
+                return Err("thousands separators are not grouping 3 digits");
+            }
+            Ok(Cow::Owned(amount.replace(separator, "")))
+        }
+        _ => unreachable!("All combinations of separators are covered."),
+    }
+}
+
+impl Parser for CsvFileReader {
+    type Error = RecordError;
+
+    /// Returns an iterator over the deserialized records.
+    fn transactions(&mut self) -> impl Iterator<Item = Result<Transaction, RecordError>> {
+        let lenient_amounts = self.lenient_amounts;
+        let mut record = StringRecord::new();
+
+        std::iter::from_fn(move || {
+            loop {
+                match self.reader.read_record(&mut record) {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(err) => return Some(Err(err.into())),
+                }
+
+                // Skip the header.
+                if self.take_header(&record) {
+                    continue;
+                }
+
+                self.metadata.rows += 1;
+                return Some(parse_record(&record, &self.layout, lenient_amounts));
+            }
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::{
         csv_reader::CsvFileReader,
+        pipeline::Parser,
         transaction_types::{Transaction, TransactionType},
     };
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    impl CsvFileReader {
+        /// Initialize the parser from a specified UTF-8 file.
+        pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ReaderError> {
+            Self::from_path_with_encoding(path, None)
+        }
+    }
+
     #[test]
     fn should_parse_file() {
         let mut transactions_csv = NamedTempFile::new().unwrap();
@@ -60,7 +766,7 @@ mod tests {
         let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
 
         let transactions: Vec<Transaction> = reader
-            .records()
+            .transactions()
             .map(|res| res.expect("Expected a valid transaction."))
             .collect();
 
@@ -73,6 +779,54 @@ mod tests {
         assert_eq!(transactions[3].id(), 4.into());
     }
 
+    #[test]
+    fn should_parse_sub_accounts() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "type, client, tx, amount, account, to_account
+                                  deposit, 1, 1, 10.0, ,
+                                  move, 1, 2, 4.0, main, savings
+                                  dispute, 1, 1, , savings,
+                                  deposit, 1, 3, 1.0, bad name,";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<_> = reader.transactions().collect();
+        let deposit = transactions[0].as_ref().unwrap();
+        assert!(deposit.account().is_main());
+        assert_eq!(deposit.to_account(), None);
+        let moved = transactions[1].as_ref().unwrap();
+        assert_eq!(moved.transaction_type(), TransactionType::Move);
+        assert!(moved.account().is_main());
+        assert_eq!(moved.to_account().unwrap().to_string(), "savings");
+        assert_eq!(
+            transactions[2].as_ref().unwrap().account().to_string(),
+            "savings"
+        );
+        assert!(transactions[3].is_err());
+    }
+
+    #[test]
+    fn should_scan_the_clients_of_a_file() {
+        let with_header = "tx, client, type, amount
+                           1, 7, deposit, 1.0
+                           2, x, deposit, 1.0
+                           3, 9, dispute,";
+        let without_header = "deposit, 3, 1, 1.0
+                              withdrawal, 4, 2, 1.0";
+
+        for (data, expected) in [(with_header, [7u16, 9]), (without_header, [3, 4])] {
+            let reader = CsvFileReader::from_bytes("scan", data.as_bytes().to_vec(), None);
+            assert_eq!(
+                reader.scan_clients(),
+                expected.iter().map(|&client| client.into()).collect()
+            );
+        }
+    }
+
     #[test]
     fn should_round_values_with_more_decimal_places() {
         let mut transactions_csv = NamedTempFile::new().unwrap();
@@ -88,7 +842,7 @@ mod tests {
         let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
 
         let transactions: Vec<Transaction> = reader
-            .records()
+            .transactions()
             .map(|res| res.expect("Expected a valid transaction."))
             .collect();
 
@@ -117,12 +871,27 @@ mod tests {
 
         let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
 
-        let transactions: Vec<_> = reader.records().collect();
+        let transactions: Vec<_> = reader.transactions().collect();
         assert!(transactions[0].is_err());
         assert!(transactions[1].is_err());
         assert!(transactions[2].is_ok());
     }
 
+    #[test]
+    fn should_parse_lines_with_a_header() {
+        let mut parser = LineParser::new(false);
+
+        assert!(parser.parse("tx, type, client, amount").is_none());
+        let deposit = parser.parse("1, deposit, 2, 1.5").unwrap().unwrap();
+        assert_eq!(
+            (deposit.id(), deposit.client(), deposit.line()),
+            (1.into(), 2.into(), Some(2))
+        );
+        assert!(parser.parse("2, deposit, x, 1.0").unwrap().is_err());
+        // Only the first line can be a header.
+        assert!(parser.parse("tx, type, client, amount").unwrap().is_err());
+    }
+
     #[test]
     fn should_parse_input_without_header() {
         let mut transactions_csv = NamedTempFile::new().unwrap();
@@ -137,7 +906,7 @@ mod tests {
         let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
 
         let transactions: Vec<Transaction> = reader
-            .records()
+            .transactions()
             .map(|res| res.expect("Expected a valid transaction."))
             .collect();
 
@@ -161,7 +930,7 @@ mod tests {
         let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
 
         let transactions: Vec<Transaction> = reader
-            .records()
+            .transactions()
             .map(|res| res.expect("Expected a valid transaction."))
             .collect();
 
@@ -182,7 +951,7 @@ mod tests {
         let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
 
         let transactions: Vec<Transaction> = reader
-            .records()
+            .transactions()
             .map(|res| res.expect("Expected a valid transaction."))
             .collect();
 
@@ -193,4 +962,353 @@ mod tests {
             TransactionType::Withdrawal
         );
     }
+
+    #[test]
+    fn should_normalize_lenient_amounts() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "type, client, tx, amount
+                                  deposit, 1, 1,\"1.234,56\"
+                                  deposit, 1, 2,\"1,234.56\"
+                                  deposit, 1, 3,\"2,5\"
+                                  deposit, 1, 4, 1.5
+                                  deposit, 1, 5,\"1,234\"";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path())
+            .unwrap()
+            .with_lenient_amounts(true);
+
+        let transactions: Vec<_> = reader.transactions().collect();
+
+        assert_eq!(
+            transactions[0].as_ref().unwrap().amount(),
+            Some(1234.56.into())
+        );
+        assert_eq!(
+            transactions[1].as_ref().unwrap().amount(),
+            Some(1234.56.into())
+        );
+        assert_eq!(transactions[2].as_ref().unwrap().amount(), Some(2.5.into()));
+        assert_eq!(transactions[3].as_ref().unwrap().amount(), Some(1.5.into()));
+        assert!(matches!(
+            transactions[4],
+            Err(RecordError::AmbiguousAmount { line: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn should_not_normalize_amounts_by_default() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "deposit, 1, 1,\"2,5\"";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<_> = reader.transactions().collect();
+        assert!(matches!(transactions[0], Err(RecordError::Csv(_))));
+    }
+
+    #[test]
+    fn normalize_amount_handles_separators() {
+        assert_eq!(normalize_amount("1234.56").unwrap(), "1234.56");
+        assert_eq!(normalize_amount("1.234.567,8").unwrap(), "1234567.8");
+        assert_eq!(normalize_amount("1,234,567.8").unwrap(), "1234567.8");
+        assert_eq!(normalize_amount("1,234,567").unwrap(), "1234567");
+        assert_eq!(normalize_amount("1.234.567").unwrap(), "1234567");
+        assert_eq!(normalize_amount("0,5").unwrap(), "0.5");
+        assert!(normalize_amount("1,234").is_err());
+        assert!(normalize_amount("1.234").is_err());
+        assert_eq!(normalize_amount("0.234").unwrap(), "0.234");
+        assert_eq!(normalize_amount("1234.567").unwrap(), "1234.567");
+        assert_eq!(normalize_amount("1.2345").unwrap(), "1.2345");
+        assert!(normalize_amount("12,34.5").is_err());
+        assert!(normalize_amount("1.2.3").is_err());
+        assert!(normalize_amount("1,5.3,2").is_err());
+    }
+
+    #[test]
+    fn should_strip_utf8_bom() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "\u{feff}type, client, tx, amount
+                                  deposit, 1, 1, 1.0";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].transaction_type(), TransactionType::Deposit);
+    }
+
+    #[test]
+    fn should_transcode_utf16_with_bom() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "type, client, tx, amount
+                                  deposit, 1, 1, 1.0
+                                  withdrawal, 1, 2, 0.5";
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(data.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+
+        transactions_csv.write_all(&bytes).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[1].amount(), Some(0.5.into()));
+    }
+
+    #[test]
+    fn should_transcode_explicit_encoding() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        // Windows-1252 text with a non-ASCII character that is not valid UTF-8.
+        let data = b"type, client, tx, amount\n\xe9deposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0";
+
+        transactions_csv.write_all(data).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path_with_encoding(
+            transactions_csv.path(),
+            Some(encoding_rs::WINDOWS_1252),
+        )
+        .unwrap();
+
+        let transactions: Vec<_> = reader.transactions().collect();
+
+        // The first record is decoded as "édeposit" which is not a valid transaction type, but it's not an encoding error.
+        assert!(
+            matches!(transactions[0], Err(RecordError::Csv(ref err)) if !matches!(err.kind(), csv::ErrorKind::Utf8 { .. }))
+        );
+        assert_eq!(transactions[1].as_ref().unwrap().amount(), Some(2.0.into()));
+    }
+
+    #[test]
+    fn should_detect_header_regardless_of_case() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "Type, Client, TX, Amount
+                                  deposit, 1, 1, 1.0";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 1);
+    }
+
+    #[test]
+    fn should_use_header_with_extra_and_reordered_columns() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "client, type, tx, amount, timestamp
+                                  1, deposit, 1, 1.0, 1700000000
+                                  2, withdrawal, 2, 2.5, 1700000001";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[1].client(), 2.into());
+        assert_eq!(
+            transactions[1].transaction_type(),
+            TransactionType::Withdrawal
+        );
+        assert_eq!(transactions[1].amount(), Some(2.5.into()));
+    }
+
+    #[test]
+    fn should_skip_suspected_header_with_variants_of_the_column_names() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "Type_Code, Client_ID, TX_ID, Amount_EUR
+                                  deposit, 1, 1, 1.0";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].client(), 1.into());
+    }
+
+    #[test]
+    fn should_report_a_malformed_first_record_with_unknown_names() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "deposit, one, 1, 1.0
+                                  deposit, 1, 2, 1.0";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+
+        let transactions: Vec<_> = reader.transactions().collect();
+
+        assert_eq!(transactions.len(), 2);
+        assert!(matches!(transactions[0], Err(RecordError::Csv(_))));
+        assert_eq!(transactions[1].as_ref().unwrap().client(), 1.into());
+    }
+
+    #[test]
+    fn should_read_gzip_compressed_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let compress = |data: &str| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        // Concatenated gzip files, without the extension.
+        let mut compressed = compress("type,client,tx,amount\ndeposit,1,1,1.0\n");
+        compressed.extend(compress("withdrawal,1,2,0.5\n"));
+        let path = dir.path().join("transactions.csv");
+        std::fs::write(&path, &compressed).unwrap();
+
+        let mut reader = CsvFileReader::from_path(&path).unwrap();
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[1].amount(), Some(0.5.into()));
+        assert_eq!(reader.metadata().size, compressed.len() as u64);
+        assert_eq!(reader.bytes_read(), compressed.len() as u64);
+    }
+
+    #[test]
+    fn should_read_zstd_compressed_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,0.5\n";
+        let compressed = zstd::encode_all(data.as_bytes(), 0).unwrap();
+        let path = dir.path().join("transactions.csv.zst");
+        std::fs::write(&path, &compressed).unwrap();
+
+        let mut reader = CsvFileReader::from_path(&path).unwrap();
+        let transactions: Vec<Transaction> = reader
+            .transactions()
+            .map(|res| res.expect("Expected a valid transaction."))
+            .collect();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(reader.bytes_read(), compressed.len() as u64);
+
+        // The compression given explicitly is used whatever the extension.
+        let misnamed = dir.path().join("transactions.csv");
+        std::fs::write(&misnamed, &compressed).unwrap();
+        assert!(matches!(
+            CsvFileReader::from_path_with_compression(&misnamed, None, Compression::None),
+            Err(ReaderError::NotCsv(_))
+        ));
+        assert!(matches!(
+            CsvFileReader::from_path_with_compression(&misnamed, None, Compression::Gzip),
+            Err(ReaderError::Decompression {
+                compression: Compression::Gzip,
+                ..
+            })
+        ));
+        assert!(
+            CsvFileReader::from_path_with_compression(&misnamed, None, Compression::Zstd).is_ok()
+        );
+    }
+
+    #[test]
+    fn should_collect_file_metadata() {
+        let mut transactions_csv = NamedTempFile::new().unwrap();
+
+        let data = "type, client, tx, amount
+                                  deposit, 1, 1, 1.0
+                                  deposit, 1, 2, -1.0";
+
+        transactions_csv.write_all(data.as_bytes()).unwrap();
+        transactions_csv.flush().unwrap();
+
+        let mut reader = CsvFileReader::from_path(transactions_csv.path()).unwrap();
+        let _: Vec<_> = reader.transactions().collect();
+
+        let metadata = reader.metadata();
+        assert_eq!(metadata.path, transactions_csv.path());
+        assert_eq!(metadata.size, data.len() as u64);
+        assert_eq!(metadata.header.as_deref(), Some("type, client, tx, amount"));
+        assert_eq!(metadata.rows, 2);
+    }
+
+    #[test]
+    fn should_report_why_a_file_cannot_be_read() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let binary = dir.path().join("transactions.zip");
+        std::fs::write(&binary, b"PK\x03\x04\x14\x00\x00\x00").unwrap();
+        let truncated = dir.path().join("transactions.csv.gz");
+        std::fs::write(&truncated, b"\x1f\x8b\x08\x00\x00\x00\x00\x00").unwrap();
+        let latin1 = dir.path().join("latin1.csv");
+        std::fs::write(&latin1, b"type,client,tx,amount\ndeposit,1,1,1.0 \xe9\n").unwrap();
+
+        assert!(matches!(
+            CsvFileReader::from_path(dir.path().join("missing.csv")),
+            Err(ReaderError::NotFound(_))
+        ));
+        assert!(matches!(
+            CsvFileReader::from_path(dir.path()),
+            Err(ReaderError::NotAFile(_))
+        ));
+        assert!(matches!(
+            CsvFileReader::from_path(&binary),
+            Err(ReaderError::NotCsv(_))
+        ));
+        assert!(matches!(
+            CsvFileReader::from_path(&truncated),
+            Err(ReaderError::Decompression {
+                compression: Compression::Gzip,
+                ..
+            })
+        ));
+        assert!(matches!(
+            CsvFileReader::from_path(&latin1),
+            Err(ReaderError::Encoding(_))
+        ));
+        assert!(
+            CsvFileReader::from_path_with_encoding(&latin1, Some(encoding_rs::WINDOWS_1252))
+                .is_ok()
+        );
+    }
 }
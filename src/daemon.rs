@@ -0,0 +1,824 @@
+use std::{
+    collections::HashSet, convert::Infallible, net::SocketAddr, num::NonZeroU32, path::PathBuf,
+    sync::Arc, time::Duration,
+};
+
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post, put},
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, broadcast, oneshot, watch};
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+
+use crate::{
+    account::{AccountError, AccountSnapshot, InternalError},
+    account_notes::{AccountNote, AccountNotes, AccountNotesError},
+    backup::{self, Backup, BackupError},
+    blocklist::Blocklist,
+    clock::SharedClock,
+    engine::{ShardedEngine, WorkerId},
+    events::{AppliedEvent, EventSink},
+    in_flight::InFlight,
+    ingest::{self, IngestError, Ingress, ReaderOptions, SourceHandle, SourceStats},
+    json::{self, Json},
+    logging::log_event,
+    metrics::Metrics,
+    period::{ClosedPeriod, PeriodError, Periods},
+    registry::{AccountRegistry, AccountTotals},
+    spans::{Span, SpanKind, TraceContext, Tracer},
+    supervisor::{ComponentHealth, RestartPolicy, Supervisor},
+    transaction_processor::{
+        AccountQuery, DisputeAction, DisputeOutcome, DisputeRequest, ProcessorMessage,
+    },
+    transaction_types::{AccountName, ClientId, DisputeSource, TransactionId},
+    watch_dir::{self, AccountReport, ReportOptions},
+};
+
+// In daemon mode the engine keeps running after the input file was processed and serves an HTTP API
+// until it receives Ctrl-C. Requests are sent to the workers through the same queues as the input transactions
+// so they are applied in order with them.
+
+// Number of balance updates that are buffered for a slow watcher before it starts missing updates.
+const WATCH_BUFFER: usize = 1024;
+// How the components of the daemon that don't own state are restarted when they fail.
+const RESTART_POLICY: RestartPolicy = RestartPolicy::OnFailure {
+    max_restarts: 5,
+    backoff: Duration::from_secs(1),
+};
+
+/// Settings of the daemon mode.
+#[derive(Debug, Clone)]
+pub(crate) struct DaemonOptions {
+    pub(crate) address: SocketAddr,
+    /// How long a worker has to answer a readiness probe.
+    pub(crate) readiness_timeout: Duration,
+    /// Directory where new input files are picked up.
+    pub(crate) watch_dir: Option<PathBuf>,
+    /// Number of files of the watched directory that can be ingested at the same time.
+    pub(crate) watch_concurrency: usize,
+    /// The report that is rewritten after the files of the watched directory were applied.
+    pub(crate) watch_report: Option<ReportOptions>,
+    /// Directory where the backups requested through the API are written.
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// How the transactions posted to the API and the files of the watched directory are read.
+    pub(crate) reader: ReaderOptions,
+    /// Number of transactions posted for a client that can wait to be applied before the next ones are rejected.
+    pub(crate) max_in_flight: Option<NonZeroU32>,
+}
+
+/// An event sink that forwards the applied transactions to the watchers of the daemon.
+pub(crate) struct WatchSink {
+    updates: broadcast::Sender<AppliedEvent>,
+}
+
+impl EventSink for WatchSink {
+    fn publish(&mut self, event: &AppliedEvent) {
+        // Don't bother cloning the event if nobody is watching.
+        if self.updates.receiver_count() > 0 {
+            let _ = self.updates.send(event.clone());
+        }
+    }
+}
+
+/// The balance updates that are streamed to the watchers of the daemon.
+pub(crate) struct Watchers {
+    updates: broadcast::Sender<AppliedEvent>,
+}
+
+impl Watchers {
+    pub(crate) fn new() -> Self {
+        let (updates, _) = broadcast::channel(WATCH_BUFFER);
+        Self { updates }
+    }
+
+    /// A sink that has to be added to every processor so that watchers receive their balance updates.
+    pub(crate) fn sink(&self) -> WatchSink {
+        WatchSink {
+            updates: self.updates.clone(),
+        }
+    }
+}
+
+// A cloneable handle used to send requests to the workers of the engine.
+#[derive(Clone)]
+struct EngineHandle {
+    workers: ShardedEngine,
+    updates: broadcast::Sender<AppliedEvent>,
+    blocklist: Blocklist,
+    periods: Arc<Mutex<Periods>>,
+    // Set when the daemon is stopping so that long lived responses can end.
+    shutdown: watch::Receiver<bool>,
+    // How long a worker has to answer a readiness probe.
+    readiness_timeout: Duration,
+    // The input source of the transactions posted to the API.
+    http_source: SourceHandle,
+    ingress: Ingress,
+    reader: ReaderOptions,
+    // Whether an operator paused the processing.
+    paused: Arc<Mutex<bool>>,
+    // The engine-wide totals of the accounts, kept up to date by the workers.
+    registry: AccountRegistry,
+    supervisor: Supervisor,
+    // The notes of the accounts, if there's a state directory to keep them in.
+    notes: Option<Arc<Mutex<AccountNotes>>>,
+    // The directory of the backups. The lock makes the backups run one at a time.
+    backup_dir: Option<Arc<Mutex<PathBuf>>>,
+    clock: SharedClock,
+    tracer: Tracer,
+}
+
+/// The parts of the engine that the daemon serves, besides the worker queues.
+pub(crate) struct EngineParts {
+    pub(crate) blocklist: Blocklist,
+    pub(crate) periods: Arc<Mutex<Periods>>,
+    pub(crate) ingress: Ingress,
+    pub(crate) registry: AccountRegistry,
+    pub(crate) supervisor: Supervisor,
+    /// The notes of the accounts, kept in the state directory.
+    pub(crate) notes: Option<AccountNotes>,
+    pub(crate) clock: SharedClock,
+    /// Where the spans of the requests are recorded.
+    pub(crate) tracer: Tracer,
+    pub(crate) metrics: Metrics,
+}
+
+impl EngineHandle {
+    async fn stopped(mut shutdown: watch::Receiver<bool>) {
+        let _ = shutdown.wait_for(|stopped| *stopped).await;
+    }
+
+    async fn manage_dispute(
+        &self,
+        action: DisputeAction,
+        client: ClientId,
+        account: AccountName,
+        transaction_id: TransactionId,
+        expected_version: Option<u32>,
+        source: Option<DisputeSource>,
+    ) -> Result<DisputeOutcome, ApiError> {
+        let (reply, outcome) = oneshot::channel();
+        let request = DisputeRequest {
+            action,
+            client,
+            account,
+            transaction_id,
+            expected_version,
+            source,
+            reply,
+        };
+
+        self.workers
+            .send(client, ProcessorMessage::ManageDispute(request))
+            .await
+            .map_err(|_| ApiError::Unavailable)?;
+        Ok(outcome.await.map_err(|_| ApiError::Unavailable)??)
+    }
+
+    // The accounts of a client, read through the queue of its worker once the transactions posted to the API before
+    // were handed over to it.
+    async fn client_accounts(&self, client: ClientId) -> Result<Vec<AccountSnapshot>, ApiError> {
+        self.http_source.settled().await;
+        let (reply, accounts) = oneshot::channel();
+        let query = AccountQuery {
+            client: Some(client),
+            reply,
+        };
+        self.workers
+            .send(client, ProcessorMessage::QueryAccounts(query))
+            .await
+            .map_err(|_| ApiError::Unavailable)?;
+        let accounts = accounts.await.map_err(|_| ApiError::Unavailable)?;
+        if accounts.is_empty() {
+            return Err(AccountError::UnknownClient.into());
+        }
+        Ok(accounts)
+    }
+
+    // The span of a request, continuing the trace of its `traceparent` header. A request without a valid header starts
+    // a new trace, and a request whose trace is not sampled by the caller is not traced.
+    fn request_span(&self, name: &'static str, headers: &HeaderMap) -> Option<Span> {
+        if !self.tracer.is_enabled() {
+            return None;
+        }
+        let parent = match headers
+            .get("traceparent")
+            .and_then(|header| header.to_str().ok())
+            .map(TraceContext::from_traceparent)
+        {
+            Some(Ok(None)) => return None,
+            Some(Ok(Some(parent))) => Some(parent),
+            Some(Err(())) | None => None,
+        };
+        Some(Span::start(
+            name,
+            SpanKind::Server,
+            parent.as_ref(),
+            self.clock.now(),
+        ))
+    }
+
+    // Pause or resume all the workers. The message is queued behind the transactions that were already sent to them.
+    async fn set_paused(&self, paused: bool, trigger: &str) -> Result<(), ApiError> {
+        let mut state = self.paused.lock().await;
+        let message = if paused {
+            || ProcessorMessage::Pause
+        } else {
+            || ProcessorMessage::Resume
+        };
+        self.workers
+            .broadcast(message)
+            .await
+            .map_err(|_| ApiError::Unavailable)?;
+        if *state != paused {
+            let event = if paused {
+                "processing_paused"
+            } else {
+                "processing_resumed"
+            };
+            log_event(event, &[("trigger", &trigger)]);
+        }
+        *state = paused;
+        Ok(())
+    }
+
+    // Probe a worker through its queue, so a worker that is stuck or too far behind on its input is not ready.
+    async fn worker_readiness(&self, worker: WorkerId) -> WorkerReadiness {
+        let probe = async {
+            let (reply, health) = oneshot::channel();
+            self.workers
+                .send_to_worker(worker, ProcessorMessage::HealthCheck(reply))
+                .await
+                .map_err(|_| ApiError::Unavailable)?;
+            Ok::<_, ApiError>(health.await.map_err(|_| ApiError::Unavailable)??)
+        };
+        let error = match tokio::time::timeout(self.readiness_timeout, probe).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some(format!(
+                "No reply within {} ms.",
+                self.readiness_timeout.as_millis()
+            )),
+        };
+        WorkerReadiness {
+            worker: worker.index(),
+            ready: error.is_none(),
+            error,
+        }
+    }
+}
+
+/// Errors returned by the HTTP API.
+#[derive(Debug, thiserror::Error)]
+enum ApiError {
+    #[error("{0}")]
+    Account(#[from] AccountError),
+    #[error("{0}")]
+    Period(#[from] PeriodError),
+    #[error("The engine is shutting down.")]
+    Unavailable,
+    #[error(
+        "Account notes are kept in the state directory, start the daemon with --state-dir to use them."
+    )]
+    NotesDisabled,
+    #[error("{0}")]
+    Notes(#[from] AccountNotesError),
+    #[error("Start the daemon with --backup-dir to take backups.")]
+    BackupsDisabled,
+    #[error("{0}")]
+    Backup(#[from] BackupError),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ApiError::Account(AccountError::UnknownClient | AccountError::TransactionMissing) => {
+                StatusCode::NOT_FOUND
+            }
+            ApiError::Account(AccountError::StaleDisputeState { .. }) => StatusCode::CONFLICT,
+            ApiError::Account(AccountError::Internal(InternalError::TransactionCache(_))) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ApiError::Account(AccountError::Internal(InternalError::ProcessingPaused)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Account(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Period(PeriodError::State(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Period(PeriodError::Unavailable) | ApiError::Unavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::NotesDisabled => StatusCode::NOT_FOUND,
+            ApiError::Notes(AccountNotesError::EmptyNote) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Notes(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::BackupsDisabled => StatusCode::NOT_FOUND,
+            ApiError::Backup(BackupError::Unavailable) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Backup(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = serde_json::json!({ "error": self.to_string() });
+        (status, Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DisputeParams {
+    expected_version: Option<u32>,
+    // Who opens or closes the dispute.
+    source: Option<DisputeSource>,
+    // The sub-account of the client. The main sub-account is used if missing.
+    #[serde(default)]
+    account: AccountName,
+}
+
+type DisputePath = Path<(ClientId, TransactionId)>;
+
+#[derive(Debug, Deserialize)]
+struct NoteParams {
+    // The sub-account of the client. The main sub-account is used if missing.
+    #[serde(default)]
+    account: AccountName,
+    // Who writes the note.
+    author: Option<String>,
+}
+
+async fn dispute_status(
+    State(engine): State<EngineHandle>,
+    Path((client, transaction_id)): DisputePath,
+    Query(params): Query<DisputeParams>,
+) -> Result<Json<DisputeOutcome>, ApiError> {
+    let outcome = engine
+        .manage_dispute(
+            DisputeAction::Status,
+            client,
+            params.account,
+            transaction_id,
+            None,
+            None,
+        )
+        .await?;
+    Ok(Json(outcome))
+}
+
+async fn open_dispute(
+    State(engine): State<EngineHandle>,
+    Path((client, transaction_id)): DisputePath,
+    Query(params): Query<DisputeParams>,
+) -> Result<Json<DisputeOutcome>, ApiError> {
+    let outcome = engine
+        .manage_dispute(
+            DisputeAction::Open,
+            client,
+            params.account,
+            transaction_id,
+            params.expected_version,
+            params.source,
+        )
+        .await?;
+    Ok(Json(outcome))
+}
+
+async fn resolve_dispute(
+    State(engine): State<EngineHandle>,
+    Path((client, transaction_id)): DisputePath,
+    Query(params): Query<DisputeParams>,
+) -> Result<Json<DisputeOutcome>, ApiError> {
+    let outcome = engine
+        .manage_dispute(
+            DisputeAction::Resolve,
+            client,
+            params.account,
+            transaction_id,
+            params.expected_version,
+            params.source,
+        )
+        .await?;
+    Ok(Json(outcome))
+}
+
+async fn chargeback(
+    State(engine): State<EngineHandle>,
+    Path((client, transaction_id)): DisputePath,
+    Query(params): Query<DisputeParams>,
+) -> Result<Json<DisputeOutcome>, ApiError> {
+    let outcome = engine
+        .manage_dispute(
+            DisputeAction::Chargeback,
+            client,
+            params.account,
+            transaction_id,
+            params.expected_version,
+            params.source,
+        )
+        .await?;
+    Ok(Json(outcome))
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchParams {
+    // Comma separated list of client ids.
+    clients: String,
+}
+
+// Stream the balance updates of a set of clients as server-sent events.
+async fn watch(
+    State(engine): State<EngineHandle>,
+    Query(params): Query<WatchParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let clients = params
+        .clients
+        .split(',')
+        .map(|client| client.trim().parse::<u16>().map(ClientId::from))
+        .collect::<Result<HashSet<_>, _>>()
+        .map_err(|err| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid client id: {}", err),
+            )
+        })?;
+
+    let updates = BroadcastStream::new(engine.updates.subscribe()).filter_map(move |update| {
+        match update {
+            Ok(update) if clients.contains(&update.account.client) => Some(Ok(Event::default()
+                .event("balance")
+                .data(json::to_string(&update).expect("Balance updates can be serialized.")))),
+            Ok(_) => None,
+            // Let the watcher know that it was too slow and some updates were dropped.
+            Err(err) => Some(Ok(Event::default().event("lagged").data(err.to_string()))),
+        }
+    });
+    let updates =
+        futures_util::StreamExt::take_until(updates, EngineHandle::stopped(engine.shutdown));
+    Ok(Sse::new(updates).keep_alive(KeepAlive::default()))
+}
+
+async fn blocked_clients(State(engine): State<EngineHandle>) -> Json<Vec<ClientId>> {
+    Json(engine.blocklist.clients())
+}
+
+async fn block_client(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<ClientId>,
+) -> StatusCode {
+    if engine.blocklist.insert(client) {
+        log_event("client_blocked", &[("client", &client)]);
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn unblock_client(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<ClientId>,
+) -> StatusCode {
+    if engine.blocklist.remove(client) {
+        log_event("client_unblocked", &[("client", &client)]);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+// The notes of the accounts of a client, from the oldest to the newest.
+async fn account_notes(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<ClientId>,
+) -> Result<Json<Vec<AccountNote>>, ApiError> {
+    let notes = engine.notes.as_ref().ok_or(ApiError::NotesDisabled)?;
+    let notes = notes.lock().await.of_client(client.into()).to_vec();
+    Ok(Json(notes))
+}
+
+// Attach the text of the body as a note to an account of the client.
+async fn add_account_note(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<ClientId>,
+    Query(params): Query<NoteParams>,
+    body: String,
+) -> Result<(StatusCode, Json<AccountNote>), ApiError> {
+    let notes = engine.notes.as_ref().ok_or(ApiError::NotesDisabled)?;
+    let note = notes
+        .lock()
+        .await
+        .add(
+            client.into(),
+            &params.account.to_string(),
+            params.author.as_deref(),
+            &body,
+            engine.clock.now(),
+        )?
+        .clone();
+    log_event(
+        "note_added",
+        &[
+            ("client", &client),
+            ("account", &note.account),
+            ("author", &note.author.as_deref().unwrap_or_default()),
+        ],
+    );
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+async fn pause(State(engine): State<EngineHandle>) -> Result<Json<serde_json::Value>, ApiError> {
+    engine.set_paused(true, "api").await?;
+    Ok(Json(serde_json::json!({ "paused": true })))
+}
+
+async fn resume(State(engine): State<EngineHandle>) -> Result<Json<serde_json::Value>, ApiError> {
+    engine.set_paused(false, "api").await?;
+    Ok(Json(serde_json::json!({ "paused": false })))
+}
+
+// Toggle the pause on SIGUSR1 until the daemon stops, for operators that can't reach the API during an incident.
+#[cfg(unix)]
+async fn pause_on_signal(engine: EngineHandle) -> Result<(), String> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = signal(SignalKind::user_defined1())
+        .map_err(|err| format!("Cannot listen for SIGUSR1: {}", err))?;
+    let stopped = EngineHandle::stopped(engine.shutdown.clone());
+    tokio::pin!(stopped);
+    loop {
+        tokio::select! {
+            _ = signals.recv() => {
+                let paused = !*engine.paused.lock().await;
+                if let Err(err) = engine.set_paused(paused, "signal").await {
+                    eprintln!("Cannot pause or resume the processing: {}", err);
+                }
+            }
+            _ = &mut stopped => break,
+        }
+    }
+    Ok(())
+}
+
+async fn current_period(State(engine): State<EngineHandle>) -> Json<serde_json::Value> {
+    let period = engine.periods.lock().await.current();
+    Json(serde_json::json!({ "period": period }))
+}
+
+// Close the current period. Transactions that are already queued are included in the snapshot.
+async fn close_period(State(engine): State<EngineHandle>) -> Result<Json<ClosedPeriod>, ApiError> {
+    let closed = engine.periods.lock().await.close(&engine.workers).await?;
+    Ok(Json(closed))
+}
+
+// Back up the transaction stores and the balances of the workers, one worker at a time. Transactions that are already
+// queued are included in the backup of their worker.
+async fn back_up(State(engine): State<EngineHandle>) -> Result<Json<Backup>, ApiError> {
+    let dir = engine
+        .backup_dir
+        .as_ref()
+        .ok_or(ApiError::BackupsDisabled)?
+        .lock()
+        .await;
+    let backup = backup::back_up(&engine.workers, &dir, engine.clock.now()).await?;
+    Ok(Json(backup))
+}
+
+// Queue the CSV transactions of the request body like the transactions of the input file. They go through the
+// validator chain. The response is sent once the transactions are queued, not once they are applied.
+async fn post_transactions(
+    State(engine): State<EngineHandle>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let Some(span) = engine.request_span("POST /transactions", &headers) else {
+        return queue_transactions(&engine, &engine.http_source, body).await;
+    };
+    // The workers record the spans of the transactions in the trace of the request.
+    let source = engine.http_source.with_trace(span.context());
+    let queued = queue_transactions(&engine, &source, body).await;
+    let span = span.with_attribute("http.request.method", &"POST");
+    let span = match &queued {
+        Ok((status, response)) => span
+            .with_attribute("http.response.status_code", &status.as_u16())
+            .with_attribute("rows", &response.0["rows"])
+            .end(engine.clock.now(), None),
+        Err((status, err)) => span
+            .with_attribute("http.response.status_code", &status.as_u16())
+            .end(engine.clock.now(), Some(err)),
+    };
+    engine.tracer.record(span);
+    queued
+}
+
+async fn queue_transactions(
+    engine: &EngineHandle,
+    source: &SourceHandle,
+    body: Bytes,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let metadata = ingest::ingest_bytes("request", body.to_vec(), engine.reader, source)
+        .await
+        .map_err(|err| match err {
+            // The transactions of the request before the rejected one stay queued.
+            IngestError::Throttled(err) => {
+                log_event(
+                    "client_throttled",
+                    &[("client", &err.client), ("limit", &err.limit)],
+                );
+                (StatusCode::TOO_MANY_REQUESTS, err.to_string())
+            }
+            err => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+        })?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "rows": metadata.rows })),
+    ))
+}
+
+// The counters of every input source.
+async fn sources(State(engine): State<EngineHandle>) -> Json<Vec<SourceStats>> {
+    Json(engine.ingress.stats())
+}
+
+// The health of the supervised components.
+async fn components(State(engine): State<EngineHandle>) -> Json<Vec<ComponentHealth>> {
+    Json(engine.supervisor.health())
+}
+
+// The balances of the sub-accounts of a client.
+async fn client_accounts(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<ClientId>,
+) -> Result<Json<Vec<AccountSnapshot>>, ApiError> {
+    Ok(Json(engine.client_accounts(client).await?))
+}
+
+// The totals of the accounts of all the workers, read without going through their queues.
+async fn account_totals(State(engine): State<EngineHandle>) -> Json<AccountTotals> {
+    Json(engine.registry.totals())
+}
+
+// The outcome of the readiness probe of a worker.
+#[derive(Debug, Serialize)]
+struct WorkerReadiness {
+    worker: usize,
+    ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Liveness: the process is up and serving requests.
+async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "alive" }))
+}
+
+// Readiness: every worker answered a probe within the timeout and could write to its transaction store.
+// The daemon is not ready anymore once it started shutting down.
+async fn readyz(State(engine): State<EngineHandle>) -> (StatusCode, Json<serde_json::Value>) {
+    let workers = join_all(
+        engine
+            .workers
+            .worker_ids()
+            .map(|worker| engine.worker_readiness(worker)),
+    )
+    .await;
+    let stopping = *engine.shutdown.borrow();
+    if !stopping && workers.iter().all(|worker| worker.ready) {
+        (
+            StatusCode::OK,
+            Json(serde_json::json!({ "status": "ready", "workers": workers })),
+        )
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(
+                serde_json::json!({ "status": "not_ready", "stopping": stopping, "workers": workers }),
+            ),
+        )
+    }
+}
+
+fn router(engine: EngineHandle) -> Router {
+    Router::new()
+        .route("/transactions", post(post_transactions))
+        .route("/sources", get(sources))
+        .route("/accounts/totals", get(account_totals))
+        .route("/accounts/{client}", get(client_accounts))
+        .route("/components", get(components))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route(
+            "/clients/{client}/transactions/{tx}/dispute",
+            get(dispute_status).post(open_dispute),
+        )
+        .route(
+            "/clients/{client}/transactions/{tx}/dispute/resolve",
+            post(resolve_dispute),
+        )
+        .route(
+            "/clients/{client}/transactions/{tx}/dispute/chargeback",
+            post(chargeback),
+        )
+        .route("/watch", get(watch))
+        .route(
+            "/clients/{client}/notes",
+            get(account_notes).post(add_account_note),
+        )
+        .route("/blocklist", get(blocked_clients))
+        .route(
+            "/blocklist/{client}",
+            put(block_client).delete(unblock_client),
+        )
+        .route("/periods/current", get(current_period))
+        .route("/periods/close", post(close_period))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/backup", post(back_up))
+        .with_state(engine)
+}
+
+/// Serve the HTTP API until Ctrl-C is received. Requests are sent to the worker queues and new transactions go
+/// through the input sources of the ingress.
+/// The watched directory and the signal handler are restarted by the supervisor when they fail.
+pub(crate) async fn serve(
+    options: &DaemonOptions,
+    workers: ShardedEngine,
+    watchers: &Watchers,
+    parts: EngineParts,
+) -> std::io::Result<()> {
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let watch_dir = options.watch_dir.clone().map(|dir| {
+        let (concurrency, reader) = (options.watch_concurrency, options.reader);
+        let (source, shutdown) = (parts.ingress.source("watch-dir"), shutdown.clone());
+        let report = options
+            .watch_report
+            .clone()
+            .map(|report| AccountReport::new(report, workers.clone()));
+        parts
+            .supervisor
+            .spawn("watch-dir", RESTART_POLICY, false, move || {
+                let watched = watch_dir::watch(
+                    dir.clone(),
+                    concurrency,
+                    reader,
+                    source.clone(),
+                    shutdown.clone(),
+                    report.clone(),
+                );
+                async move {
+                    watched.await;
+                    Ok(())
+                }
+            })
+    });
+    let engine = EngineHandle {
+        workers,
+        updates: watchers.updates.clone(),
+        blocklist: parts.blocklist,
+        periods: parts.periods,
+        shutdown,
+        readiness_timeout: options.readiness_timeout,
+        http_source: match options.max_in_flight {
+            Some(limit) => parts
+                .ingress
+                .source("http")
+                .with_in_flight(InFlight::new(limit, parts.metrics)),
+            None => parts.ingress.source("http"),
+        },
+        ingress: parts.ingress,
+        reader: options.reader,
+        paused: Arc::new(Mutex::new(false)),
+        registry: parts.registry,
+        supervisor: parts.supervisor.clone(),
+        notes: parts.notes.map(|notes| Arc::new(Mutex::new(notes))),
+        backup_dir: options
+            .backup_dir
+            .clone()
+            .map(|dir| Arc::new(Mutex::new(dir))),
+        clock: parts.clock,
+        tracer: parts.tracer,
+    };
+    // Listening for the signal fails the same way every time, so the handler is not restarted.
+    #[cfg(unix)]
+    {
+        let engine = engine.clone();
+        parts
+            .supervisor
+            .spawn("pause-signal", RestartPolicy::Never, false, move || {
+                pause_on_signal(engine.clone())
+            });
+    }
+
+    let listener = tokio::net::TcpListener::bind(options.address).await?;
+    log_event("daemon_listening", &[("address", &listener.local_addr()?)]);
+
+    axum::serve(listener, router(engine))
+        .with_graceful_shutdown(async move {
+            let _ = tokio::signal::ctrl_c().await;
+            let _ = shutdown_tx.send(true);
+        })
+        .await?;
+
+    // A file that is being read is finished before stopping.
+    if let Some(watch_dir) = watch_dir {
+        watch_dir.await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,411 @@
+use std::{path::PathBuf, str::FromStr, time::Instant};
+
+use csv::StringRecord;
+use rusqlite::{Connection, OpenFlags};
+use thiserror::Error;
+
+use crate::{
+    ingest::{IngressClosed, SourceHandle},
+    logging::{RecordLog, log_event},
+    transaction_types::Transaction,
+};
+
+// Input from a database table that some other system lands the transactions in. The rows are read in the order of a
+// sequence column, one page at a time with keyset pagination (`WHERE seq > <last seq> ORDER BY seq LIMIT <page>`),
+// so no cursor or transaction is held open on the database while a large table is read.
+//
+// The table must have the `type`, `client`, `tx` and `amount` columns of the CSV input, plus the sequence column.
+// Every column is read as text so the amounts don't go through a float, whatever their type in the table.
+
+// Number of rows read in one query, unless set with the `page` parameter.
+const DEFAULT_PAGE: usize = 1000;
+// The columns of a transaction, in the order they are selected.
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+#[derive(Debug, Error)]
+pub(crate) enum DbInputError {
+    #[error(
+        "'{0}' is not a database input (e.g. db:sqlite:/data/transactions.db?table=transactions or db:postgres://host/db?table=transactions)."
+    )]
+    InvalidInput(String),
+    #[error("'{0}' is not a valid table or column name.")]
+    InvalidName(String),
+    #[error("Cannot read the transactions table: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "tokio-postgres")]
+    #[error("Cannot read the transactions table: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[cfg(not(feature = "tokio-postgres"))]
+    #[error(
+        "Postgres input is not supported by this build. Build with the tokio-postgres feature."
+    )]
+    PostgresUnsupported,
+    #[error("The database file '{0}' doesn't exist.")]
+    MissingDatabase(PathBuf),
+    #[error(transparent)]
+    Closed(#[from] IngressClosed),
+}
+
+/// The database a table is read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Database {
+    Sqlite(PathBuf),
+    /// A libpq style connection string or URL.
+    Postgres(String),
+}
+
+/// A table of transactions, written as `db:<connection>?table=<TABLE>[&sequence=<COLUMN>][&page=<ROWS>]`.
+/// Other parameters are left in the connection string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DbInput {
+    database: Database,
+    table: String,
+    /// The column that orders the rows. Defaults to `seq`.
+    sequence: String,
+    page: usize,
+}
+
+// Table and column names are put in the queries as they are, so they are restricted to plain identifiers.
+fn identifier(name: &str) -> Result<String, DbInputError> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(name.to_string())
+    } else {
+        Err(DbInputError::InvalidName(name.to_string()))
+    }
+}
+
+impl FromStr for DbInput {
+    type Err = DbInputError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || DbInputError::InvalidInput(value.to_string());
+        let (connection, parameters) = value
+            .strip_prefix("db:")
+            .and_then(|rest| rest.rsplit_once('?'))
+            .ok_or_else(invalid)?;
+
+        let mut table = None;
+        let mut sequence = "seq".to_string();
+        let mut page = DEFAULT_PAGE;
+        let mut other = Vec::new();
+        for parameter in parameters.split('&') {
+            match parameter.split_once('=') {
+                Some(("table", name)) => table = Some(identifier(name)?),
+                Some(("sequence", name)) => sequence = identifier(name)?,
+                Some(("page", rows)) => {
+                    page = rows
+                        .parse()
+                        .ok()
+                        .filter(|rows| *rows > 0)
+                        .ok_or_else(invalid)?
+                }
+                _ => other.push(parameter),
+            }
+        }
+
+        let database = if let Some(path) = connection.strip_prefix("sqlite:") {
+            Database::Sqlite(PathBuf::from(path))
+        } else if connection.starts_with("postgres://") || connection.starts_with("postgresql://") {
+            let mut connection = connection.to_string();
+            if !other.is_empty() {
+                connection.push('?');
+                connection.push_str(&other.join("&"));
+            }
+            Database::Postgres(connection)
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Self {
+            database,
+            table: table.ok_or_else(invalid)?,
+            sequence,
+            page,
+        })
+    }
+}
+
+impl DbInput {
+    /// Check that the input can be read by this build, without connecting to the database.
+    pub(crate) fn check(&self) -> Result<(), DbInputError> {
+        match &self.database {
+            Database::Sqlite(path) if !path.is_file() => {
+                Err(DbInputError::MissingDatabase(path.clone()))
+            }
+            #[cfg(not(feature = "tokio-postgres"))]
+            Database::Postgres(_) => Err(DbInputError::PostgresUnsupported),
+            _ => Ok(()),
+        }
+    }
+
+    // The query for the page after a sequence number. The placeholder syntax is the only difference between databases.
+    fn query(&self, placeholders: (&str, &str)) -> String {
+        let columns: Vec<_> = COLUMNS
+            .iter()
+            .map(|column| format!("CAST({} AS TEXT)", column))
+            .collect();
+        format!(
+            "SELECT CAST({sequence} AS BIGINT), {columns} FROM {table} WHERE {sequence} > {after} ORDER BY {sequence} LIMIT {limit}",
+            sequence = self.sequence,
+            columns = columns.join(", "),
+            table = self.table,
+            after = placeholders.0,
+            limit = placeholders.1,
+        )
+    }
+}
+
+// A page of rows: the sequence number and the columns of the transaction, NULL being an empty field.
+type Page = Vec<(i64, StringRecord)>;
+
+// Read the page of rows after a sequence number from a SQLite database.
+fn sqlite_page(
+    connection: &Connection,
+    query: &str,
+    after: i64,
+    page: usize,
+) -> Result<Page, DbInputError> {
+    let mut statement = connection.prepare_cached(query)?;
+    let rows = statement.query_map(rusqlite::params![after, page as i64], |row| {
+        let mut record = StringRecord::new();
+        for index in 1..=COLUMNS.len() {
+            record.push_field(
+                row.get::<_, Option<String>>(index)?
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+        }
+        Ok((row.get(0)?, record))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Read the transactions of a table in the order of its sequence column and queue them on a source.
+/// Returns the number of rows read.
+pub(crate) async fn ingest_table(
+    input: &DbInput,
+    source: &SourceHandle,
+) -> Result<u64, DbInputError> {
+    let started = Instant::now();
+    let mut reader = TableReader::open(input).await?;
+    let headers = StringRecord::from(COLUMNS.to_vec());
+    let mut log = RecordLog::new();
+    let mut rows = 0;
+    let mut after = i64::MIN;
+    loop {
+        let page = reader.page(after).await?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = *last;
+        rows += page.len() as u64;
+        for (_, record) in page {
+            match record.deserialize::<Transaction>(Some(&headers)) {
+                Ok(transaction) => source.send(transaction).await?,
+                Err(err) => {
+                    if log.should_log(&err) {
+                        eprintln!("Error reading table row: {:?}", err);
+                    }
+                    source.parse_error();
+                }
+            }
+        }
+    }
+
+    log_event(
+        "table_ingested",
+        &[
+            ("table", &input.table),
+            ("rows", &rows),
+            ("last_sequence", &after),
+            ("duration_ms", &started.elapsed().as_millis()),
+        ],
+    );
+    Ok(rows)
+}
+
+// A connection to the database of a table and the query for its pages.
+enum TableReader {
+    // SQLite calls block, so the connection is moved to a blocking thread for every page.
+    Sqlite {
+        connection: Option<Connection>,
+        query: String,
+        page: usize,
+    },
+    #[cfg(feature = "tokio-postgres")]
+    Postgres {
+        client: tokio_postgres::Client,
+        query: String,
+        page: usize,
+    },
+}
+
+impl TableReader {
+    async fn open(input: &DbInput) -> Result<Self, DbInputError> {
+        match &input.database {
+            Database::Sqlite(path) => Ok(Self::Sqlite {
+                connection: Some(Connection::open_with_flags(
+                    path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY,
+                )?),
+                query: input.query(("?1", "?2")),
+                page: input.page,
+            }),
+            #[cfg(feature = "tokio-postgres")]
+            Database::Postgres(connection) => {
+                let (client, driver) =
+                    tokio_postgres::connect(connection, tokio_postgres::NoTls).await?;
+                tokio::spawn(async move {
+                    if let Err(err) = driver.await {
+                        eprintln!("Postgres connection encountered an error: {}", err);
+                    }
+                });
+                Ok(Self::Postgres {
+                    client,
+                    query: input.query(("$1", "$2")),
+                    page: input.page,
+                })
+            }
+            #[cfg(not(feature = "tokio-postgres"))]
+            Database::Postgres(_) => Err(DbInputError::PostgresUnsupported),
+        }
+    }
+
+    async fn page(&mut self, after: i64) -> Result<Page, DbInputError> {
+        match self {
+            Self::Sqlite {
+                connection,
+                query,
+                page,
+            } => {
+                let (taken, query, page) = (
+                    connection
+                        .take()
+                        .expect("The connection is returned after every page."),
+                    query.clone(),
+                    *page,
+                );
+                let (taken, rows) = tokio::task::spawn_blocking(move || {
+                    let rows = sqlite_page(&taken, &query, after, page);
+                    (taken, rows)
+                })
+                .await
+                .expect("Reading a page of the table doesn't panic.");
+                *connection = Some(taken);
+                rows
+            }
+            #[cfg(feature = "tokio-postgres")]
+            Self::Postgres {
+                client,
+                query,
+                page,
+            } => {
+                let rows = client
+                    .query(query.as_str(), &[&after, &(*page as i64)])
+                    .await?;
+                Ok(rows
+                    .iter()
+                    .map(|row| {
+                        let mut record = StringRecord::new();
+                        for index in 1..=COLUMNS.len() {
+                            record
+                                .push_field(row.get::<_, Option<&str>>(index).unwrap_or_default());
+                        }
+                        (row.get(0), record)
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        NUM_WORKERS, engine::ShardedEngine, enrichment::Enrichers, ingest::Ingress,
+        transaction_processor::ProcessorMessage, transaction_types::TransactionType,
+    };
+
+    use super::*;
+
+    #[test]
+    fn should_parse_database_inputs() {
+        let input: DbInput = "db:sqlite:/data/tx.db?table=transactions&page=50"
+            .parse()
+            .unwrap();
+        assert_eq!(input.database, Database::Sqlite("/data/tx.db".into()));
+        assert_eq!(
+            (input.table.as_str(), input.sequence.as_str(), input.page),
+            ("transactions", "seq", 50)
+        );
+
+        let input: DbInput =
+            "db:postgres://engine@db/payments?sslmode=disable&table=landed&sequence=id"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            input.database,
+            Database::Postgres("postgres://engine@db/payments?sslmode=disable".to_string())
+        );
+        assert_eq!(input.sequence, "id");
+
+        assert!(matches!(
+            "db:sqlite:tx.db?table=tx;drop".parse::<DbInput>(),
+            Err(DbInputError::InvalidName(_))
+        ));
+        assert!("db:sqlite:tx.db".parse::<DbInput>().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_read_table_in_sequence_order_one_page_at_a_time() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("landed.db");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE landed (seq INTEGER, type TEXT, client INTEGER, tx INTEGER, amount NUMERIC);
+                 INSERT INTO landed VALUES (3, 'withdrawal', 1, 3, 0.5);
+                 INSERT INTO landed VALUES (1, 'deposit', 1, 1, 1.5);
+                 INSERT INTO landed VALUES (2, 'bogus', 1, 2, NULL);
+                 INSERT INTO landed VALUES (4, 'dispute', 1, 1, NULL);",
+            )
+            .unwrap();
+
+        let (worker, mut rx) = mpsc::channel(1024);
+        let (ingress, dispatcher) = Ingress::start(
+            ShardedEngine::new(vec![worker; NUM_WORKERS]),
+            None,
+            Enrichers::default(),
+        );
+        let source = ingress.source("table");
+        let input = format!("db:sqlite:{}?table=landed&page=2", path.display())
+            .parse()
+            .unwrap();
+        assert_eq!(ingest_table(&input, &source).await.unwrap(), 4);
+        source.finish().await;
+        assert_eq!(ingress.parse_errors(), 1);
+        drop(ingress);
+        dispatcher.await.unwrap();
+
+        let mut types = Vec::new();
+        while let Ok(ProcessorMessage::ProcessTransaction(transaction)) = rx.try_recv() {
+            types.push(transaction.transaction_type());
+        }
+        assert_eq!(
+            types,
+            vec![
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute
+            ]
+        );
+    }
+}
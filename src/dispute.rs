@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{account::AccountError, dispute_policy::DisputeRule};
+
+// The life cycle of the dispute of a transaction. Every transaction starts undisputed and can be disputed once; the
+// dispute is then closed either in favor of the merchant (resolve) or of the client (chargeback), and nothing can happen
+// to it after that, unless the dispute policy lets resolved disputes be opened again or the issuer reverses the
+// chargeback because the merchant won the representment, which is final. The whole life cycle is the transition table of `DisputeStateMachine::transition`, so the accounts
+// only decide what a transition does to the balances, and a new kind of dispute (e.g. of a transfer) only needs a
+// machine of its own.
+
+/// The dispute state of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DisputeState {
+    /// This transaction was never disputed.
+    None,
+    /// There was a dispute initiated for this transaction.
+    DisputeInitiated,
+    /// The dispute was resolved in favor of the merchant.
+    DisputeResolved,
+    /// The dispute was resolved through a charge-back.
+    ChargedBack,
+    /// The charge-back was reversed in favor of the merchant.
+    ChargebackReversed,
+}
+
+impl DisputeState {
+    /// Whether the dispute was opened and not closed yet.
+    pub(crate) fn is_open(&self) -> bool {
+        *self == DisputeState::DisputeInitiated
+    }
+}
+
+/// An operation on the dispute of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DisputeEvent {
+    Open,
+    Resolve,
+    Chargeback,
+    ReverseChargeback,
+}
+
+// Whether disputes can be opened on a kind of transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opening {
+    Allowed,
+    // The transaction could be disputed, but the engine doesn't support it.
+    NotSupported,
+    // There's nothing to dispute.
+    Never,
+}
+
+/// The dispute transitions of a kind of transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DisputeStateMachine {
+    opening: Opening,
+    // Whether a resolved dispute can be opened again.
+    reopen: bool,
+}
+
+impl DisputeStateMachine {
+    /// Deposits can be disputed by the client.
+    pub(crate) const DEPOSIT: Self = Self {
+        opening: Opening::Allowed,
+        reopen: false,
+    };
+    /// We don't allow disputes for withdrawals by default. From what I can reasearch it's in line with what other
+    /// processors like Stripe or Paypal do.
+    pub(crate) const WITHDRAWAL: Self = Self {
+        opening: Opening::NotSupported,
+        reopen: false,
+    };
+    /// Moves never leave the client and escrow holds are settled by releasing them, so there's nothing to dispute.
+    pub(crate) const INTERNAL: Self = Self {
+        opening: Opening::Never,
+        reopen: false,
+    };
+
+    /// The machine of the transactions that follow a dispute rule. The rule decides whether they can be disputed and
+    /// whether their resolved disputes can be opened again.
+    pub(crate) fn with_rule(self, rule: &DisputeRule) -> Self {
+        let opening = match self.opening {
+            _ if rule.disputable => Opening::Allowed,
+            Opening::Allowed => Opening::Never,
+            opening => opening,
+        };
+        Self {
+            opening,
+            reopen: rule.reopen,
+        }
+    }
+
+    /// The state a dispute goes to on an event, or why the event is not allowed in its current state.
+    pub(crate) fn transition(
+        &self,
+        state: DisputeState,
+        event: DisputeEvent,
+    ) -> Result<DisputeState, AccountError> {
+        match (state, event) {
+            (DisputeState::None, DisputeEvent::Open) => match self.opening {
+                Opening::Allowed => Ok(DisputeState::DisputeInitiated),
+                Opening::NotSupported => Err(AccountError::WithdrawalDisputeNotSupported),
+                Opening::Never => Err(AccountError::TransactionCannotBeDisputed),
+            },
+            (DisputeState::DisputeResolved, DisputeEvent::Open) if self.reopen => {
+                Ok(DisputeState::DisputeInitiated)
+            }
+            // A transaction can be disputed only once.
+            (_, DisputeEvent::Open) => Err(AccountError::TransactionCannotBeDisputed),
+            (DisputeState::DisputeInitiated, DisputeEvent::Resolve) => {
+                Ok(DisputeState::DisputeResolved)
+            }
+            (DisputeState::DisputeInitiated, DisputeEvent::Chargeback) => {
+                Ok(DisputeState::ChargedBack)
+            }
+            (DisputeState::ChargedBack, DisputeEvent::ReverseChargeback) => {
+                Ok(DisputeState::ChargebackReversed)
+            }
+            (DisputeState::None, DisputeEvent::Resolve | DisputeEvent::Chargeback) => {
+                Err(AccountError::TransactionNotDisputed)
+            }
+            (DisputeState::DisputeResolved, DisputeEvent::Resolve | DisputeEvent::Chargeback) => {
+                Err(AccountError::DisputeAlreadyResolved)
+            }
+            (DisputeState::ChargedBack, DisputeEvent::Resolve | DisputeEvent::Chargeback) => {
+                Err(AccountError::TransactionWasChargedBack)
+            }
+            (
+                DisputeState::None | DisputeState::DisputeInitiated | DisputeState::DisputeResolved,
+                DisputeEvent::ReverseChargeback,
+            ) => Err(AccountError::TransactionNotChargedBack),
+            (DisputeState::ChargebackReversed, _) => Err(AccountError::ChargebackAlreadyReversed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [DisputeState; 5] = [
+        DisputeState::None,
+        DisputeState::DisputeInitiated,
+        DisputeState::DisputeResolved,
+        DisputeState::ChargedBack,
+        DisputeState::ChargebackReversed,
+    ];
+    const EVENTS: [DisputeEvent; 4] = [
+        DisputeEvent::Open,
+        DisputeEvent::Resolve,
+        DisputeEvent::Chargeback,
+        DisputeEvent::ReverseChargeback,
+    ];
+
+    // The outcome of a transition as the state it goes to or the name of the error.
+    fn outcome(
+        machine: DisputeStateMachine,
+        state: DisputeState,
+        event: DisputeEvent,
+    ) -> Result<DisputeState, String> {
+        machine.transition(state, event).map_err(|err| {
+            let name = format!("{:?}", err);
+            name.split(['(', ' ', '{'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+    }
+
+    #[test]
+    fn should_follow_the_transition_table_of_deposits() {
+        use DisputeEvent::*;
+        use DisputeState::*;
+
+        let expected = [
+            (None, Open, Ok(DisputeInitiated)),
+            (None, Resolve, Err("TransactionNotDisputed")),
+            (None, Chargeback, Err("TransactionNotDisputed")),
+            (None, ReverseChargeback, Err("TransactionNotChargedBack")),
+            (DisputeInitiated, Open, Err("TransactionCannotBeDisputed")),
+            (DisputeInitiated, Resolve, Ok(DisputeResolved)),
+            (DisputeInitiated, Chargeback, Ok(ChargedBack)),
+            (
+                DisputeInitiated,
+                ReverseChargeback,
+                Err("TransactionNotChargedBack"),
+            ),
+            (DisputeResolved, Open, Err("TransactionCannotBeDisputed")),
+            (DisputeResolved, Resolve, Err("DisputeAlreadyResolved")),
+            (DisputeResolved, Chargeback, Err("DisputeAlreadyResolved")),
+            (
+                DisputeResolved,
+                ReverseChargeback,
+                Err("TransactionNotChargedBack"),
+            ),
+            (ChargedBack, Open, Err("TransactionCannotBeDisputed")),
+            (ChargedBack, Resolve, Err("TransactionWasChargedBack")),
+            (ChargedBack, Chargeback, Err("TransactionWasChargedBack")),
+            (ChargedBack, ReverseChargeback, Ok(ChargebackReversed)),
+            (ChargebackReversed, Open, Err("TransactionCannotBeDisputed")),
+            (
+                ChargebackReversed,
+                Resolve,
+                Err("ChargebackAlreadyReversed"),
+            ),
+            (
+                ChargebackReversed,
+                Chargeback,
+                Err("ChargebackAlreadyReversed"),
+            ),
+            (
+                ChargebackReversed,
+                ReverseChargeback,
+                Err("ChargebackAlreadyReversed"),
+            ),
+        ];
+
+        assert_eq!(expected.len(), STATES.len() * EVENTS.len());
+        for (state, event, next) in expected {
+            assert_eq!(
+                outcome(DisputeStateMachine::DEPOSIT, state, event),
+                next.map_err(str::to_string),
+                "{:?} on {:?}",
+                event,
+                state
+            );
+        }
+    }
+
+    #[test]
+    fn should_only_differ_in_opening_between_kinds_of_transactions() {
+        for machine in [
+            DisputeStateMachine::WITHDRAWAL,
+            DisputeStateMachine::INTERNAL,
+        ] {
+            for state in STATES {
+                for event in EVENTS {
+                    let expected = match (state, event) {
+                        (DisputeState::None, DisputeEvent::Open) => continue,
+                        _ => outcome(DisputeStateMachine::DEPOSIT, state, event),
+                    };
+                    assert_eq!(outcome(machine, state, event), expected);
+                }
+            }
+        }
+
+        assert_eq!(
+            outcome(
+                DisputeStateMachine::WITHDRAWAL,
+                DisputeState::None,
+                DisputeEvent::Open
+            ),
+            Err("WithdrawalDisputeNotSupported".to_string())
+        );
+        assert_eq!(
+            outcome(
+                DisputeStateMachine::INTERNAL,
+                DisputeState::None,
+                DisputeEvent::Open
+            ),
+            Err("TransactionCannotBeDisputed".to_string())
+        );
+    }
+
+    #[test]
+    fn should_only_reopen_resolved_disputes() {
+        let machine = DisputeStateMachine::WITHDRAWAL.with_rule(&DisputeRule {
+            disputable: true,
+            max_age: None,
+            partial: false,
+            reopen: true,
+        });
+        for state in STATES {
+            for event in EVENTS {
+                let expected = match (state, event) {
+                    (DisputeState::DisputeResolved, DisputeEvent::Open) => {
+                        Ok(DisputeState::DisputeInitiated)
+                    }
+                    _ => outcome(DisputeStateMachine::DEPOSIT, state, event),
+                };
+                assert_eq!(outcome(machine, state, event), expected);
+            }
+        }
+    }
+}
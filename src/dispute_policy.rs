@@ -0,0 +1,171 @@
+use std::{io, path::Path};
+
+use chrono::TimeDelta;
+use serde::Deserialize;
+use thiserror::Error;
+
+// Which transactions can be disputed and how, e.g. to match the agreements with the card networks and the payment
+// service providers of a deployment. The policy is a matrix with a row per kind of transaction that can be disputed,
+// deposits and withdrawals: whether it can be disputed at all, for how long after it was applied, whether a dispute can
+// hold part of its amount and whether a resolved dispute can be opened again. The default policy is the behavior of the
+// engine without a policy file: deposits can be disputed once, for their whole amount, at any time, and withdrawals
+// can't be disputed.
+
+#[derive(Debug, Error)]
+pub(crate) enum DisputePolicyError {
+    #[error("Cannot read the dispute policy file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid dispute policy: {0}")]
+    Csv(#[from] csv::Error),
+    #[error(
+        "Invalid type '{value}' on line {line} of the dispute policy, expected deposit or withdrawal."
+    )]
+    InvalidType { line: u64, value: String },
+}
+
+/// How the disputes of a kind of transaction are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DisputeRule {
+    pub(crate) disputable: bool,
+    /// How long after it was applied a transaction can still be disputed. No limit if not set.
+    pub(crate) max_age: Option<TimeDelta>,
+    /// Whether a dispute can hold less than the amount of the transaction.
+    pub(crate) partial: bool,
+    /// Whether a dispute that was resolved can be opened again. Charged back transactions can never be disputed again.
+    pub(crate) reopen: bool,
+}
+
+impl DisputeRule {
+    const DISPUTABLE: Self = Self {
+        disputable: true,
+        max_age: None,
+        partial: false,
+        reopen: false,
+    };
+    const NOT_DISPUTABLE: Self = Self {
+        disputable: false,
+        ..Self::DISPUTABLE
+    };
+}
+
+/// The dispute rules of the deposits and of the withdrawals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DisputePolicy {
+    pub(crate) deposit: DisputeRule,
+    pub(crate) withdrawal: DisputeRule,
+}
+
+impl Default for DisputePolicy {
+    fn default() -> Self {
+        Self {
+            deposit: DisputeRule::DISPUTABLE,
+            withdrawal: DisputeRule::NOT_DISPUTABLE,
+        }
+    }
+}
+
+// A row of the policy file. The columns that are left empty keep the default of the type.
+#[derive(Debug, Deserialize)]
+struct PolicyRecord {
+    #[serde(rename = "type")]
+    transaction_type: String,
+    disputable: Option<bool>,
+    max_age_days: Option<u32>,
+    partial: Option<bool>,
+    reopen: Option<bool>,
+}
+
+impl DisputePolicy {
+    /// Whether any dispute can hold less than the amount of its transaction.
+    pub(crate) fn allows_partial(&self) -> bool {
+        self.deposit.partial || self.withdrawal.partial
+    }
+
+    /// Load the policy from a CSV file with the `type, disputable, max_age_days, partial, reopen` columns and a row per
+    /// type. The types without a row keep their default rule.
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, DisputePolicyError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let mut policy = Self::default();
+        for row in reader.records() {
+            let row = row?;
+            let record: PolicyRecord = row.deserialize(Some(&headers))?;
+            let rule = match record.transaction_type.as_str() {
+                "deposit" => &mut policy.deposit,
+                "withdrawal" => &mut policy.withdrawal,
+                _ => {
+                    return Err(DisputePolicyError::InvalidType {
+                        line: row.position().map_or(0, csv::Position::line),
+                        value: record.transaction_type,
+                    });
+                }
+            };
+            rule.disputable = record.disputable.unwrap_or(rule.disputable);
+            rule.max_age = record
+                .max_age_days
+                .map(|days| TimeDelta::days(days.into()))
+                .or(rule.max_age);
+            rule.partial = record.partial.unwrap_or(rule.partial);
+            rule.reopen = record.reopen.unwrap_or(rule.reopen);
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn load(contents: &str) -> Result<DisputePolicy, DisputePolicyError> {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file.flush().unwrap();
+        DisputePolicy::from_path(file.path())
+    }
+
+    #[test]
+    fn should_load_the_rules_keeping_the_defaults_of_empty_columns() {
+        let policy = load(
+            "type,disputable,max_age_days,partial,reopen\n\
+             withdrawal, true, 60, , \n\
+             deposit,,120,true,true\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            policy,
+            DisputePolicy {
+                deposit: DisputeRule {
+                    disputable: true,
+                    max_age: Some(TimeDelta::days(120)),
+                    partial: true,
+                    reopen: true,
+                },
+                withdrawal: DisputeRule {
+                    disputable: true,
+                    max_age: Some(TimeDelta::days(60)),
+                    partial: false,
+                    reopen: false,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn should_reject_the_types_that_cannot_be_disputed() {
+        assert!(matches!(
+            load("type,disputable,max_age_days,partial,reopen\nmove,true,,,\n"),
+            Err(DisputePolicyError::InvalidType { line: 2, .. })
+        ));
+        assert!(matches!(
+            load("type,disputable,max_age_days,partial,reopen\ndeposit,maybe,,,\n"),
+            Err(DisputePolicyError::Csv(_))
+        ));
+    }
+}
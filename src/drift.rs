@@ -0,0 +1,259 @@
+use std::{collections::BTreeMap, fmt::Display, fs, path::PathBuf};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::AccountSnapshot,
+    state::StateError,
+    transaction_types::{AccountName, Amount, ClientId, deserialize_balance},
+};
+
+// A first-line reconciliation alarm over the runs that share a state directory. The closing balance of every account
+// is kept in the state directory with how much it typically changes from one run to the next, the mean of the absolute
+// changes of the previous runs. Each run compares its closing balances with the ones of the previous run and flags the
+// accounts whose total changed by more than a multiple of their typical change, e.g. a client that usually moves a few
+// hundred and suddenly moved a hundred thousand. An account is only flagged once it changed in a previous run, so new
+// accounts never are, while an account that was idle until now is flagged by any change.
+
+// An account in the baseline of the next run.
+#[derive(Debug, Serialize, Deserialize)]
+struct BaselineRecord {
+    client: ClientId,
+    account: AccountName,
+    #[serde(deserialize_with = "deserialize_balance")]
+    total: Amount,
+    #[serde(deserialize_with = "deserialize_balance")]
+    typical_change: Amount,
+    // The number of changes the typical change is the mean of.
+    runs: u32,
+}
+
+/// An account whose total changed by more than the threshold since the previous run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct DriftAlert {
+    pub(crate) client: ClientId,
+    pub(crate) account: AccountName,
+    pub(crate) previous: Amount,
+    pub(crate) current: Amount,
+    /// The mean of the absolute changes of the previous runs.
+    pub(crate) typical_change: Amount,
+}
+
+/// How the closing balances changed since the previous run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Drift {
+    /// The accounts that have a closing balance of the previous run.
+    pub(crate) compared: u64,
+    /// The compared accounts whose total changed.
+    pub(crate) changed: u64,
+    /// The accounts that were not in the previous run.
+    pub(crate) new: u64,
+    /// The sum of the changes of the totals of the compared accounts.
+    pub(crate) net_change: Amount,
+    /// The multiple of the typical change above which a change is flagged.
+    pub(crate) threshold: u32,
+    pub(crate) alerts: Vec<DriftAlert>,
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Drift since the previous run: {} of {} accounts changed by {} in total, {} new accounts",
+            self.changed,
+            self.compared,
+            signed(self.net_change),
+            self.new
+        )?;
+        if !self.alerts.is_empty() {
+            let alerts = self
+                .alerts
+                .iter()
+                .map(|alert| {
+                    format!(
+                        "{} {} ({}, typically {})",
+                        alert.client,
+                        alert.account,
+                        signed(alert.current.saturating_sub(alert.previous)),
+                        alert.typical_change
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(
+                f,
+                "\n!!! DRIFT ALERTS: {} accounts changed more than {} times their typical change: {}",
+                self.alerts.len(),
+                self.threshold,
+                alerts
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A change with its sign, e.g. `+5` or `-2.5`.
+fn signed(change: Amount) -> String {
+    if change.is_negative() {
+        change.to_string()
+    } else {
+        format!("+{}", change)
+    }
+}
+
+/// The closing balances of the previous run and the typical change of each account, kept in the state directory.
+pub(crate) struct DriftBaseline {
+    path: PathBuf,
+    accounts: BTreeMap<(ClientId, AccountName), BaselineRecord>,
+}
+
+impl DriftBaseline {
+    pub(crate) fn load(path: PathBuf) -> Result<Self, StateError> {
+        let mut accounts = BTreeMap::new();
+        if path.exists() {
+            let mut reader = csv::Reader::from_path(&path)?;
+            for record in reader.deserialize::<BaselineRecord>() {
+                let record = record?;
+                accounts.insert((record.client, record.account.clone()), record);
+            }
+        }
+        Ok(Self { path, accounts })
+    }
+
+    /// Compare the closing balances of a run with the ones of the previous run, flagging the accounts that changed by
+    /// more than `threshold` times their typical change, and make them the baseline of the next run. The accounts that
+    /// are not in the run are kept as they were.
+    pub(crate) fn update(&mut self, accounts: &[AccountSnapshot], threshold: u32) -> Drift {
+        let mut drift = Drift {
+            threshold,
+            ..Default::default()
+        };
+        for account in accounts {
+            let key = (account.client, account.account.clone());
+            let Some(baseline) = self.accounts.get_mut(&key) else {
+                drift.new += 1;
+                self.accounts.insert(
+                    key,
+                    BaselineRecord {
+                        client: account.client,
+                        account: account.account.clone(),
+                        total: account.total,
+                        typical_change: Amount::zero(),
+                        runs: 0,
+                    },
+                );
+                continue;
+            };
+            drift.compared += 1;
+            let change = account.total.saturating_sub(baseline.total);
+            drift.net_change = drift.net_change.saturating_add(change);
+            let size = Decimal::from(change).abs();
+            if !size.is_zero() {
+                drift.changed += 1;
+            }
+
+            let typical = Decimal::from(baseline.typical_change);
+            let exceeded = typical
+                .checked_mul(threshold.into())
+                .is_some_and(|limit| size > limit);
+            if baseline.runs > 0 && exceeded {
+                drift.alerts.push(DriftAlert {
+                    client: account.client,
+                    account: account.account.clone(),
+                    previous: baseline.total,
+                    current: account.total,
+                    typical_change: baseline.typical_change,
+                });
+            }
+
+            // The running mean of the absolute changes.
+            let runs = baseline.runs.saturating_add(1);
+            baseline.typical_change = (typical + (size - typical) / Decimal::from(runs))
+                .round_dp(4)
+                .into();
+            baseline.runs = runs;
+            baseline.total = account.total;
+        }
+        drift
+            .alerts
+            .sort_by(|a, b| (a.client, &a.account).cmp(&(b.client, &b.account)));
+        drift
+    }
+
+    /// Write the baseline for the next run. The previous baseline is replaced only once the new one is written.
+    pub(crate) fn write(&self) -> Result<(), StateError> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for record in self.accounts.values() {
+            writer.serialize(record)?;
+        }
+        let contents = writer.into_inner().map_err(|err| err.into_error())?;
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closing(client: u16, total: f64) -> AccountSnapshot {
+        AccountSnapshot {
+            client: client.into(),
+            account: AccountName::default(),
+            available: total.into(),
+            held: Amount::zero(),
+            escrow: Amount::zero(),
+            pending: Amount::zero(),
+            reserve: Amount::zero(),
+            total: total.into(),
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn should_flag_the_changes_larger_than_the_typical_change_of_the_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("closing-balances.csv");
+        let run = |accounts: &[AccountSnapshot]| {
+            let mut baseline = DriftBaseline::load(path.clone()).unwrap();
+            let drift = baseline.update(accounts, 10);
+            baseline.write().unwrap();
+            drift
+        };
+
+        let first = run(&[closing(1, 100.0), closing(2, 50.0)]);
+        assert_eq!((first.new, first.compared), (2, 0));
+
+        // Both accounts get a typical change, the idle account 2 of zero.
+        let second = run(&[closing(1, 120.0), closing(2, 50.0)]);
+        assert_eq!((second.compared, second.changed), (2, 1));
+        assert_eq!(second.net_change, 20.0.into());
+        assert!(second.alerts.is_empty());
+
+        let third = run(&[closing(1, 300.0), closing(2, 49.0), closing(3, 1.0)]);
+        assert_eq!(third.new, 1);
+        assert_eq!(
+            third.alerts,
+            vec![DriftAlert {
+                client: 2.into(),
+                account: AccountName::default(),
+                previous: 50.0.into(),
+                current: 49.0.into(),
+                typical_change: Amount::zero(),
+            }]
+        );
+
+        // The mean of 20 and 180.
+        let fourth = run(&[closing(1, 1500.0)]);
+        assert_eq!(fourth.alerts[0].typical_change, 100.0.into());
+        assert_eq!(
+            fourth.to_string(),
+            "Drift since the previous run: 1 of 1 accounts changed by +1200 in total, 0 new accounts
+!!! DRIFT ALERTS: 1 accounts changed more than 10 times their typical change: 1 main (+1200, typically 100)"
+        );
+    }
+}
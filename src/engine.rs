@@ -0,0 +1,465 @@
+use std::{
+    fmt::{self, Display},
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    sync::{
+        mpsc::{self, Sender, error::SendError},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    account::AccountSnapshot,
+    transaction::NewTransaction,
+    transaction_processor::{
+        AccountQuery, ProcessorMessage, ProcessorOptions, TransactionProcessor,
+    },
+    transaction_types::{ClientId, Transaction},
+};
+
+// All the transactions of a client are applied by the same worker, in the order they were received: the accounts of a
+// client, the stores of its transactions and its disputes are owned by the processor of that worker and are never
+// shared with the other workers. `ShardedEngine` owns the queues of the workers and the assignment of the clients to
+// them, so the transactions and the requests about a client can only be sent to the worker of the client.
+//
+// A processor knows its own shard and, in debug builds, asserts that every client it's asked to process belongs to it.
+// A message about a client that was sent to the wrong queue is caught there instead of silently splitting the state of
+// the client between two workers.
+//
+// The clients are assigned to the shards by a hash of their id by default, which spreads the busy clients over the
+// workers. The range partitioner assigns contiguous ranges of client ids instead, the same in every run with the same
+// number of workers, so an input sorted by client keeps each worker on its own part of the file and a file can be
+// split into the inputs of the workers by client id alone.
+//
+// The worker of shard `i` is `worker-i`. The id is given when the worker is spawned and is the same in every run with
+// the same number of workers, so the logs, the profiles, the account updates and the run manifests of different runs
+// can be compared by worker.
+
+// The number of client ids.
+const CLIENT_IDS: usize = u16::MAX as usize + 1;
+// Number of messages that can wait in the queue of a worker of an engine started by `ShardedEngine::start`.
+const WORKER_QUEUE: usize = 1024;
+
+/// How the clients are assigned to the shards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Partitioner {
+    /// By a hash of the client id.
+    #[default]
+    Hash,
+    /// By contiguous ranges of client ids of the same size, e.g. clients 0 to 16383 for the first of 4 shards.
+    Range,
+}
+
+impl Partitioner {
+    /// The clients of shard `index` out of `count`, if they are a range.
+    pub(crate) fn clients(self, index: usize, count: usize) -> Option<RangeInclusive<u16>> {
+        match self {
+            Partitioner::Hash => None,
+            // The first client whose shard is `index` or above.
+            Partitioner::Range => {
+                let start = |index: usize| (index * CLIENT_IDS).div_ceil(count);
+                Some(start(index) as u16..=(start(index + 1) - 1) as u16)
+            }
+        }
+    }
+}
+
+/// The share of the clients of one worker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Shard {
+    index: usize,
+    count: usize,
+    partitioner: Partitioner,
+}
+
+impl Shard {
+    pub(crate) fn new(index: usize, count: usize, partitioner: Partitioner) -> Self {
+        Self {
+            index,
+            count,
+            partitioner,
+        }
+    }
+
+    /// The shard that processes a client, out of `count` shards.
+    pub(crate) fn of(client: ClientId, count: usize, partitioner: Partitioner) -> Self {
+        let index = match partitioner {
+            Partitioner::Hash => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                (hasher.finish() as usize) % count
+            }
+            Partitioner::Range => usize::from(u16::from(client)) * count / CLIENT_IDS,
+        };
+        Self::new(index, count, partitioner)
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The id of the worker that processes the shard.
+    pub(crate) fn worker(&self) -> WorkerId {
+        WorkerId(self.index)
+    }
+
+    pub(crate) fn owns(&self, client: ClientId) -> bool {
+        Self::of(client, self.count, self.partitioner) == *self
+    }
+
+    /// The clients of the shard, if they are a range.
+    pub(crate) fn clients(&self) -> Option<RangeInclusive<u16>> {
+        self.partitioner.clients(self.index, self.count)
+    }
+}
+
+/// The id of a worker, written as `worker-<SHARD>`. An engine without shards has a single worker, `worker-0`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct WorkerId(usize);
+
+impl WorkerId {
+    /// The index of the shard of the worker.
+    pub(crate) fn index(&self) -> usize {
+        self.0
+    }
+}
+
+impl Display for WorkerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "worker-{}", self.0)
+    }
+}
+
+impl Serialize for WorkerId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Why the engine could not take a request.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EngineError {
+    #[error("The engine is shut down.")]
+    Stopped,
+}
+
+/// The balances of an account of a client, as read by [`ShardedEngine::query`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccountBalance {
+    pub client: u16,
+    /// The sub-account, `main` for the default one.
+    pub account: String,
+    pub available: Decimal,
+    /// The funds held by open disputes.
+    pub held: Decimal,
+    pub escrow: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl From<AccountSnapshot> for AccountBalance {
+    fn from(snapshot: AccountSnapshot) -> Self {
+        Self {
+            client: snapshot.client.into(),
+            account: snapshot.account.to_string(),
+            available: snapshot.available.into(),
+            held: snapshot.held.into(),
+            escrow: snapshot.escrow.into(),
+            total: snapshot.total.into(),
+            locked: snapshot.locked,
+        }
+    }
+}
+
+/// The workers of the engine, with the routing of the clients to them. Transactions and queries are sent to the worker
+/// of their client, so the transactions of a client are always applied by the same worker and in the order they were
+/// submitted, whichever task submits them.
+///
+/// A program that embeds the engine starts one with [`ShardedEngine::start`] on its Tokio runtime:
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use payments_engine::{
+///     engine::ShardedEngine,
+///     transaction::{TransactionBuilder, TransactionType},
+/// };
+/// use rust_decimal::Decimal;
+///
+/// let engine = ShardedEngine::start(4);
+/// let deposit = TransactionBuilder::new(TransactionType::Deposit, 1, 1)
+///     .with_amount(Decimal::new(15, 1))
+///     .build()?;
+/// engine.submit(deposit).await?;
+/// let accounts = engine.query(1).await?;
+/// engine.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShardedEngine {
+    workers: Vec<Sender<ProcessorMessage>>,
+    partitioner: Partitioner,
+    // The tasks of the workers, if the engine spawned them.
+    tasks: Arc<Mutex<Vec<JoinHandle<TransactionProcessor>>>>,
+}
+
+impl fmt::Debug for ShardedEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedEngine")
+            .field("workers", &self.workers.len())
+            .field("partitioner", &self.partitioner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShardedEngine {
+    /// Start an engine with `workers` workers, each a task of the current Tokio runtime. The workers apply the
+    /// transactions with the default options of the engine: the transactions of the accounts are kept in memory and in
+    /// temporary SQLite stores, and nothing is written out.
+    ///
+    /// # Panics
+    ///
+    /// If `workers` is 0 or if it's not called from a Tokio runtime.
+    pub fn start(workers: usize) -> Self {
+        assert!(workers > 0, "An engine needs at least one worker.");
+        let partitioner = Partitioner::default();
+        let (queues, tasks) = (0..workers)
+            .map(|index| {
+                let (tx, rx) = mpsc::channel(WORKER_QUEUE);
+                let processor = TransactionProcessor::new(ProcessorOptions::default())
+                    .with_shard(Shard::new(index, workers, partitioner));
+                (tx, tokio::spawn(processor.run(rx)))
+            })
+            .unzip();
+        Self {
+            workers: queues,
+            partitioner,
+            tasks: Arc::new(Mutex::new(tasks)),
+        }
+    }
+
+    /// Queue a transaction for the worker of its client. It's applied once the transactions of the client submitted
+    /// before it are, and [`ShardedEngine::query`] only answers once the transactions submitted before the query
+    /// are applied. A transaction that can't be applied to the account (e.g. a withdrawal without enough funds) is
+    /// rejected by the worker and leaves the balances unchanged.
+    pub async fn submit(&self, transaction: NewTransaction) -> Result<(), EngineError> {
+        let transaction = Transaction::from(transaction);
+        self.send(
+            transaction.client(),
+            ProcessorMessage::process_transaction(transaction),
+        )
+        .await
+        .map_err(|_| EngineError::Stopped)
+    }
+
+    /// The balances of the accounts of a client, ordered by sub-account. Empty if the client has no account.
+    pub async fn query(&self, client: u16) -> Result<Vec<AccountBalance>, EngineError> {
+        let (reply, accounts) = oneshot::channel();
+        let client = ClientId::from(client);
+        let query = ProcessorMessage::QueryAccounts(AccountQuery {
+            client: Some(client),
+            reply,
+        });
+        self.send(client, query)
+            .await
+            .map_err(|_| EngineError::Stopped)?;
+        let accounts = accounts.await.map_err(|_| EngineError::Stopped)?;
+        Ok(accounts.into_iter().map(AccountBalance::from).collect())
+    }
+
+    /// Stop the workers once they applied the transactions that were already submitted. The clones of the engine
+    /// stop taking requests too.
+    pub async fn shutdown(&self) -> Result<(), EngineError> {
+        self.broadcast(ProcessorMessage::shutdown)
+            .await
+            .map_err(|_| EngineError::Stopped)?;
+        let tasks =
+            std::mem::take(&mut *self.tasks.lock().expect("Engine lock is never poisoned."));
+        for task in tasks {
+            task.await.map_err(|_| EngineError::Stopped)?;
+        }
+        Ok(())
+    }
+
+    /// The queue at index `i` must be the queue of the processor of shard `i`.
+    pub(crate) fn new(workers: Vec<Sender<ProcessorMessage>>) -> Self {
+        Self {
+            workers,
+            partitioner: Partitioner::default(),
+            tasks: Arc::default(),
+        }
+    }
+
+    /// Assign the clients to the workers with a partitioner other than the hash of their id.
+    pub(crate) fn with_partitioner(mut self, partitioner: Partitioner) -> Self {
+        self.partitioner = partitioner;
+        self
+    }
+
+    pub(crate) fn shard(&self, client: ClientId) -> Shard {
+        Shard::of(client, self.workers.len(), self.partitioner)
+    }
+
+    /// Queue a message about a client (a transaction or a dispute request) for the worker of the client.
+    pub(crate) async fn send(
+        &self,
+        client: ClientId,
+        message: ProcessorMessage,
+    ) -> Result<(), SendError<ProcessorMessage>> {
+        self.workers[self.shard(client).index()].send(message).await
+    }
+
+    /// The ids of all the workers.
+    pub(crate) fn worker_ids(&self) -> impl Iterator<Item = WorkerId> + use<> {
+        (0..self.workers.len()).map(WorkerId)
+    }
+
+    /// Queue a message that is not about a client (a period close, a probe, a pause) for one worker. The messages about
+    /// a client can only be sent to the worker of the client, with [`ShardedEngine::send`].
+    pub(crate) async fn send_to_worker(
+        &self,
+        worker: WorkerId,
+        message: ProcessorMessage,
+    ) -> Result<(), SendError<ProcessorMessage>> {
+        debug_assert!(
+            message.client().is_none(),
+            "A message about a client was sent to {} instead of the worker of the client.",
+            worker
+        );
+        self.workers[worker.0].send(message).await
+    }
+
+    /// Queue a message that is not about a client for every worker, e.g. `ProcessorMessage::Pause`.
+    pub(crate) async fn broadcast(
+        &self,
+        mut message: impl FnMut() -> ProcessorMessage,
+    ) -> Result<(), SendError<ProcessorMessage>> {
+        for worker in self.worker_ids() {
+            self.send_to_worker(worker, message()).await?;
+        }
+        Ok(())
+    }
+
+    /// The accounts of a client, or of all the clients, ordered by client and sub-account. The queries are queued
+    /// behind the transactions that were already sent to the workers. Workers that stopped have no accounts.
+    pub(crate) async fn query_accounts(&self, client: Option<ClientId>) -> Vec<AccountSnapshot> {
+        let mut replies = Vec::new();
+        let mut query = || {
+            let (reply, accounts) = oneshot::channel();
+            replies.push(accounts);
+            ProcessorMessage::QueryAccounts(AccountQuery { client, reply })
+        };
+        // Only the worker of the client has its accounts.
+        let _ = match client {
+            Some(client) => self.send(client, query()).await,
+            None => self.broadcast(query).await,
+        };
+        let mut accounts = Vec::new();
+        for reply in replies {
+            accounts.extend(reply.await.unwrap_or_default());
+        }
+        accounts.sort_by(|a, b| (a.client, &a.account).cmp(&(b.client, &b.account)));
+        accounts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::transaction_types::{Transaction, TransactionType};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_send_the_messages_of_a_client_to_its_worker() {
+        for partitioner in [Partitioner::Hash, Partitioner::Range] {
+            send_to_workers(partitioner).await;
+        }
+    }
+
+    async fn send_to_workers(partitioner: Partitioner) {
+        let (workers, mut receivers): (Vec<_>, Vec<_>) = (0..4).map(|_| mpsc::channel(64)).unzip();
+        let engine = ShardedEngine::new(workers).with_partitioner(partitioner);
+
+        for client in (0..32u16).chain(u16::MAX - 31..=u16::MAX) {
+            let transaction = Transaction::new(
+                TransactionType::Deposit,
+                client.into(),
+                u32::from(client).into(),
+                Some(1.0.into()),
+            );
+            engine
+                .send(
+                    client.into(),
+                    ProcessorMessage::process_transaction(transaction),
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut received = 0;
+        for (index, rx) in receivers.iter_mut().enumerate() {
+            let shard = Shard::new(index, 4, partitioner);
+            while let Ok(ProcessorMessage::ProcessTransaction(transaction)) = rx.try_recv() {
+                assert!(shard.owns(transaction.client()));
+                received += 1;
+            }
+        }
+        assert_eq!(received, 64);
+    }
+
+    #[tokio::test]
+    async fn should_apply_the_submitted_transactions_of_a_client_in_order() {
+        use crate::transaction::{TransactionBuilder, TransactionType};
+
+        let engine = ShardedEngine::start(4);
+        for (kind, tx, amount) in [
+            (TransactionType::Deposit, 1, Some(Decimal::new(30, 1))),
+            (TransactionType::Withdrawal, 2, Some(Decimal::new(10, 1))),
+            (TransactionType::Dispute, 1, None),
+        ] {
+            let builder = TransactionBuilder::new(kind, 7, tx);
+            let builder = match amount {
+                Some(amount) => builder.with_amount(amount),
+                None => builder,
+            };
+            engine.submit(builder.build().unwrap()).await.unwrap();
+        }
+
+        let accounts = engine.query(7).await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, Decimal::new(-10, 1));
+        assert_eq!(accounts[0].held, Decimal::new(30, 1));
+        assert!(engine.query(8).await.unwrap().is_empty());
+
+        engine.shutdown().await.unwrap();
+        assert_eq!(engine.query(7).await, Err(EngineError::Stopped));
+    }
+
+    #[test]
+    fn should_split_the_client_ids_into_contiguous_ranges() {
+        let ranges: Vec<_> = (0..3)
+            .map(|index| Shard::new(index, 3, Partitioner::Range).clients().unwrap())
+            .collect();
+        assert_eq!(ranges, vec![0..=21845, 21846..=43690, 43691..=65535]);
+        for (index, range) in ranges.into_iter().enumerate() {
+            for client in [*range.start(), *range.end()] {
+                assert_eq!(
+                    Shard::of(client.into(), 3, Partitioner::Range).index(),
+                    index
+                );
+            }
+        }
+        assert_eq!(Shard::new(0, 3, Partitioner::Hash).clients(), None);
+    }
+}
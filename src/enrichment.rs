@@ -0,0 +1,160 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+};
+
+use thiserror::Error;
+
+use crate::transaction_types::Transaction;
+
+// Enrichment of the transactions between parsing and dispatching. An enricher derives data from a transaction once,
+// e.g. a normalized currency, and attaches it to the extensions of the transaction, where the validators and the
+// processors find it instead of deriving it again. The enrichers run on the dispatcher of the ingress, for the
+// transactions of every source, so they must be cheap and must not block.
+//
+// The extensions are not part of the input or of any output. Transactions forwarded to a peer in cluster mode are
+// enriched again by the peer.
+
+/// Data derived from a transaction, by type. At most one value of a type is attached to a transaction.
+#[derive(Default)]
+pub(crate) struct Extensions(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl Extensions {
+    /// Attach a value, replacing the value of the same type if there was one.
+    pub(crate) fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.0.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    pub(crate) fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extensions({} values)", self.0.len())
+    }
+}
+
+/// Derives data from a transaction and attaches it to its extensions.
+pub(crate) trait Enricher: Send + Sync {
+    fn enrich(&self, transaction: &mut Transaction);
+}
+
+/// The enrichers of the ingress, run in the order they were added. Clones share the same enrichers.
+#[derive(Clone, Default)]
+pub(crate) struct Enrichers(Arc<Vec<Box<dyn Enricher>>>);
+
+impl Enrichers {
+    /// Add an enricher. Only used while the ingress is set up, before the enrichers are shared.
+    pub(crate) fn with<E: Enricher + 'static>(mut self, enricher: E) -> Self {
+        Arc::get_mut(&mut self.0)
+            .expect("Enrichers are added before they are shared.")
+            .push(Box::new(enricher));
+        self
+    }
+
+    pub(crate) fn enrich(&self, transaction: &mut Transaction) {
+        for enricher in self.0.iter() {
+            enricher.enrich(transaction);
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("'{0}' is not a currency code (e.g. USD).")]
+pub(crate) struct InvalidCurrency(pub(crate) String);
+
+/// An ISO 4217 currency code, in upper case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Currency(String);
+
+impl FromStr for Currency {
+    type Err = InvalidCurrency;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let code = value.trim();
+        if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+            Ok(Self(code.to_ascii_uppercase()))
+        } else {
+            Err(InvalidCurrency(value.to_string()))
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Attaches the `Currency` of the `currency` column (e.g. ` usd` becomes `USD`). Nothing is attached when the column
+/// is empty or doesn't hold a currency code.
+pub(crate) struct CurrencyNormalizer;
+
+impl Enricher for CurrencyNormalizer {
+    fn enrich(&self, transaction: &mut Transaction) {
+        if let Some(Ok(currency)) = transaction.currency().map(str::parse::<Currency>) {
+            transaction.extensions_mut().insert(currency);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_types::TransactionType;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Tag(&'static str);
+
+    struct Tagger(&'static str);
+
+    impl Enricher for Tagger {
+        fn enrich(&self, transaction: &mut Transaction) {
+            transaction.extensions_mut().insert(Tag(self.0));
+        }
+    }
+
+    fn deposit(currency: Option<&str>) -> Transaction {
+        Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(1.0.into()),
+        )
+        .with_currency(currency)
+    }
+
+    #[test]
+    fn should_run_the_enrichers_in_order() {
+        let enrichers = Enrichers::default()
+            .with(Tagger("first"))
+            .with(CurrencyNormalizer)
+            .with(Tagger("last"));
+        let mut transaction = deposit(Some(" eur"));
+
+        enrichers.clone().enrich(&mut transaction);
+
+        assert_eq!(transaction.extensions().get::<Tag>(), Some(&Tag("last")));
+        assert_eq!(
+            transaction.extensions().get::<Currency>(),
+            Some(&"EUR".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn should_only_attach_valid_currencies() {
+        for currency in [None, Some(""), Some("EURO"), Some("U$D")] {
+            let mut transaction = deposit(currency);
+            CurrencyNormalizer.enrich(&mut transaction);
+            assert_eq!(transaction.extensions().get::<Currency>(), None);
+        }
+    }
+}
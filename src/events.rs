@@ -0,0 +1,47 @@
+use std::io;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    account::AccountSnapshot,
+    engine::WorkerId,
+    transaction_types::{Amount, EscrowParty, TransactionId, TransactionType},
+};
+
+/// The state of an account after a transaction was successfully applied to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct AppliedEvent {
+    #[serde(rename = "type")]
+    pub(crate) transaction_type: TransactionType,
+    #[serde(rename = "tx")]
+    pub(crate) transaction_id: TransactionId,
+    /// The amount of the transaction or, for disputes, resolves and chargebacks, the disputed amount.
+    pub(crate) amount: Amount,
+    /// The party that received the funds of an escrow release.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) release_to: Option<EscrowParty>,
+    /// The type of the disputed transaction, for disputes, resolves and chargebacks.
+    #[serde(skip)]
+    pub(crate) disputed: Option<TransactionType>,
+    /// The worker that applied the transaction.
+    pub(crate) worker: WorkerId,
+    pub(crate) account: AccountSnapshot,
+    /// The accounting period in which the transaction was applied.
+    pub(crate) period: u32,
+    /// When the transaction was applied, according to the clock of the processor. Not part of the balance updates of
+    /// the daemon API.
+    #[serde(skip)]
+    pub(crate) applied_at: DateTime<Utc>,
+}
+
+/// A consumer of the events published by a transaction processor.
+/// Sinks are called on the processing task so they should not block.
+pub(crate) trait EventSink: Send {
+    fn publish(&mut self, event: &AppliedEvent);
+
+    /// Write out the events that are still buffered. Called when the end of the input is reached.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
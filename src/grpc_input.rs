@@ -0,0 +1,395 @@
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+#[cfg(feature = "grpc")]
+use rust_decimal::Decimal;
+#[cfg(feature = "grpc")]
+use tokio::net::TcpListener;
+#[cfg(feature = "grpc")]
+use tokio_stream::wrappers::TcpListenerStream;
+#[cfg(feature = "grpc")]
+use tonic::{Request, Response, Status, transport::Server};
+
+#[cfg(feature = "grpc")]
+use crate::{
+    engine::AccountBalance,
+    ingest::{self, ReaderOptions, SourceHandle},
+    logging::log_event,
+    outcome::{OutcomeReply, TransactionOutcome},
+    transaction::{
+        DisputeSource, EscrowParty, NewTransaction, TransactionBuilder, TransactionType,
+    },
+    transaction_types::Transaction,
+};
+use crate::{engine::ShardedEngine, ingest::Ingress};
+
+// Input from a gRPC service, for the services that push their transactions to the engine as they happen instead of
+// batching them into files. The service is defined in `proto/payments_engine.proto`. `SubmitTransaction` takes a
+// transaction with the fields of the CSV input and answers once the transaction was handled, with its outcome: applied,
+// rejected with the reason and the response code of the rejects report, skipped as applied by a previous run, or
+// forwarded to the peer that owns the client in cluster mode. `GetAccount` answers with the balances of the accounts of
+// a client once the transactions submitted before it were applied.
+//
+// In cluster mode the peers forward the transactions of the shards of the node with `ForwardTransactions`, in batches
+// of CSV records that are read like the body of `POST /transactions`. The call answers once the batch was queued, so
+// the peer only drops a batch that was accepted. Forwarded transactions are never forwarded again.
+//
+// The requests share a source, so the transactions of concurrent requests reach the workers in no particular order. A
+// caller that waits for the outcome of a transaction before it submits the next has them applied in order.
+
+#[cfg(feature = "grpc")]
+pub(crate) mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("payments_engine.v1");
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum GrpcInputError {
+    #[cfg(feature = "grpc")]
+    #[error("Cannot serve the gRPC service: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[cfg(feature = "grpc")]
+    #[error("Cannot listen for the gRPC service: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(not(feature = "grpc"))]
+    #[error("gRPC input is not supported by this build. Build with the grpc feature.")]
+    GrpcUnsupported,
+}
+
+/// Check that the service can be served by this build.
+pub(crate) fn check() -> Result<(), GrpcInputError> {
+    #[cfg(not(feature = "grpc"))]
+    return Err(GrpcInputError::GrpcUnsupported);
+    #[cfg(feature = "grpc")]
+    Ok(())
+}
+
+/// Serve the service on an address and queue the transactions it receives until `stop` completes. The requests in
+/// flight are answered before it returns.
+#[cfg(feature = "grpc")]
+pub(crate) async fn serve(
+    address: SocketAddr,
+    ingress: &Ingress,
+    workers: ShardedEngine,
+    stop: impl Future<Output = ()>,
+) -> Result<(), GrpcInputError> {
+    let listener = TcpListener::bind(address).await?;
+    log_event("grpc_listening", &[("address", &listener.local_addr()?)]);
+    accept(listener, ingress, workers, stop).await
+}
+
+#[cfg(feature = "grpc")]
+async fn accept(
+    listener: TcpListener,
+    ingress: &Ingress,
+    workers: ShardedEngine,
+    stop: impl Future<Output = ()>,
+) -> Result<(), GrpcInputError> {
+    let source = ingress.source("grpc");
+    let peers = ingress.peer_source();
+    let service = Ingestion {
+        source: source.clone(),
+        peers: peers.clone(),
+        workers,
+    };
+    let served = Server::builder()
+        .add_service(proto::ingestion_server::IngestionServer::new(service))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), stop)
+        .await;
+    let stats = source.finish().await;
+    let forwarded = peers.finish().await;
+    log_event(
+        "grpc_stopped",
+        &[
+            ("transactions", &stats.received),
+            ("dispatched", &stats.dispatched),
+            ("from_peers", &forwarded.received),
+        ],
+    );
+    Ok(served?)
+}
+
+/// Serve the service. Not supported by this build.
+#[cfg(not(feature = "grpc"))]
+pub(crate) async fn serve(
+    _address: SocketAddr,
+    _ingress: &Ingress,
+    _workers: ShardedEngine,
+    _stop: impl Future<Output = ()>,
+) -> Result<(), GrpcInputError> {
+    Err(GrpcInputError::GrpcUnsupported)
+}
+
+#[cfg(feature = "grpc")]
+struct Ingestion {
+    source: SourceHandle,
+    // The source of the transactions forwarded by the peers in cluster mode.
+    peers: SourceHandle,
+    workers: ShardedEngine,
+}
+
+#[cfg(feature = "grpc")]
+#[tonic::async_trait]
+impl proto::ingestion_server::Ingestion for Ingestion {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::SubmitTransactionRequest>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let transaction =
+            new_transaction(request.into_inner()).map_err(Status::invalid_argument)?;
+        let mut transaction = Transaction::from(transaction);
+        let (reply, outcome) = OutcomeReply::new();
+        transaction.extensions_mut().insert(reply);
+        self.source
+            .send(transaction)
+            .await
+            .map_err(|_| Status::unavailable("The engine is stopping."))?;
+        let outcome = outcome.await.map_err(|_| {
+            Status::unavailable("The engine stopped before the transaction was handled.")
+        })?;
+        Ok(Response::new(outcome.into()))
+    }
+
+    async fn forward_transactions(
+        &self,
+        request: Request<proto::ForwardTransactionsRequest>,
+    ) -> Result<Response<proto::ForwardTransactionsResponse>, Status> {
+        // The batches are written by the peers in UTF-8, whatever the encoding of the input files of the node.
+        let csv = request.into_inner().csv;
+        let metadata = ingest::ingest_bytes("peer", csv, ReaderOptions::default(), &self.peers)
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))?;
+        Ok(Response::new(proto::ForwardTransactionsResponse {
+            rows: metadata.rows,
+        }))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::GetAccountResponse>, Status> {
+        let client = request.into_inner().client;
+        let client = u16::try_from(client)
+            .map_err(|_| Status::invalid_argument(format!("Invalid client id: {}", client)))?;
+        // The query is queued behind the transactions that were handed over to the worker, including the ones forwarded
+        // by the peers.
+        self.source.settled().await;
+        self.peers.settled().await;
+        let accounts = self
+            .workers
+            .query(client)
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))?;
+        Ok(Response::new(proto::GetAccountResponse {
+            accounts: accounts.into_iter().map(proto::Account::from).collect(),
+        }))
+    }
+}
+
+// A transaction of a request, checked by the builder like the transactions of the programs that embed the engine.
+#[cfg(feature = "grpc")]
+fn new_transaction(request: proto::SubmitTransactionRequest) -> Result<NewTransaction, String> {
+    use proto::TransactionType as Type;
+
+    let transaction_type = match request.r#type() {
+        Type::Unspecified => return Err("The type of the transaction is missing.".into()),
+        Type::Deposit => TransactionType::Deposit,
+        Type::Withdrawal => TransactionType::Withdrawal,
+        Type::Dispute => TransactionType::Dispute,
+        Type::Resolve => TransactionType::Resolve,
+        Type::Chargeback => TransactionType::Chargeback,
+        Type::Move => TransactionType::Move,
+        Type::EscrowHold => TransactionType::EscrowHold,
+        Type::EscrowRelease => TransactionType::EscrowRelease,
+        Type::ChargebackReversal => TransactionType::ChargebackReversal,
+    };
+    let client = u16::try_from(request.client)
+        .map_err(|_| format!("Invalid client id: {}", request.client))?;
+    let mut builder = TransactionBuilder::new(transaction_type, client, request.tx);
+    if let Some(amount) = &request.amount {
+        let amount: Decimal = amount
+            .parse()
+            .map_err(|_| format!("'{}' is not an amount.", amount))?;
+        builder = builder.with_amount(amount);
+    }
+    if let Some(account) = &request.account {
+        builder = builder.with_account(account);
+    }
+    if let Some(account) = &request.to_account {
+        builder = builder.with_to_account(account);
+    }
+    if let Some(party) = &request.release_to {
+        let party = match party.as_str() {
+            "client" => EscrowParty::Client,
+            "beneficiary" => EscrowParty::Beneficiary,
+            _ => return Err(format!("'{}' is not an escrow party.", party)),
+        };
+        builder = builder.with_release_to(party);
+    }
+    if let Some(source) = &request.source {
+        let source = match source.as_str() {
+            "issuer" => DisputeSource::Issuer,
+            "internal" => DisputeSource::Internal,
+            "partner" => DisputeSource::Partner,
+            _ => return Err(format!("'{}' is not a dispute source.", source)),
+        };
+        builder = builder.with_source(source);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
+
+#[cfg(feature = "grpc")]
+impl From<TransactionOutcome> for proto::SubmitTransactionResponse {
+    fn from(outcome: TransactionOutcome) -> Self {
+        let (outcome, reason, response_code) = match outcome {
+            TransactionOutcome::Applied => (proto::Outcome::Applied, String::new(), ""),
+            TransactionOutcome::Rejected {
+                reason,
+                response_code,
+            } => (proto::Outcome::Rejected, reason, response_code),
+            TransactionOutcome::Duplicate => (proto::Outcome::Duplicate, String::new(), ""),
+            TransactionOutcome::Forwarded => (proto::Outcome::Forwarded, String::new(), ""),
+        };
+        Self {
+            outcome: outcome.into(),
+            reason,
+            response_code: response_code.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl From<AccountBalance> for proto::Account {
+    fn from(balance: AccountBalance) -> Self {
+        Self {
+            client: balance.client.into(),
+            account: balance.account,
+            available: balance.available.to_string(),
+            held: balance.held.to_string(),
+            escrow: balance.escrow.to_string(),
+            total: balance.total.to_string(),
+            locked: balance.locked,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "grpc"))]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::{
+        NUM_WORKERS,
+        enrichment::Enrichers,
+        transaction_processor::{ProcessorMessage, ProcessorOptions, TransactionProcessor},
+    };
+
+    use super::{
+        proto::{
+            ForwardTransactionsRequest, GetAccountRequest, Outcome, SubmitTransactionRequest,
+            TransactionType, ingestion_client::IngestionClient,
+        },
+        *,
+    };
+
+    fn request(
+        kind: TransactionType,
+        client: u32,
+        tx: u32,
+        amount: &str,
+    ) -> SubmitTransactionRequest {
+        SubmitTransactionRequest {
+            r#type: kind.into(),
+            client,
+            tx,
+            amount: Some(amount.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn should_answer_with_the_outcome_of_the_transactions() {
+        let mut senders = Vec::new();
+        let mut processors = Vec::new();
+        for _ in 0..NUM_WORKERS {
+            let (tx, rx) = mpsc::channel(1024);
+            senders.push(tx);
+            processors.push(tokio::spawn(
+                TransactionProcessor::new(ProcessorOptions::default()).run(rx),
+            ));
+        }
+        let workers = ShardedEngine::new(senders);
+        let (ingress, dispatcher) = Ingress::start(workers.clone(), None, Enrichers::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop_tx, stop) = oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let ingress = ingress.clone();
+            let workers = workers.clone();
+            async move {
+                let stop = async {
+                    let _ = stop.await;
+                };
+                accept(listener, &ingress, workers, stop).await
+            }
+        });
+
+        let mut client = IngestionClient::connect(format!("http://{}", address))
+            .await
+            .unwrap();
+        let applied = client
+            .submit_transaction(request(TransactionType::Deposit, 1, 1, "2.5"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(applied.outcome(), Outcome::Applied);
+        let rejected = client
+            .submit_transaction(request(TransactionType::Withdrawal, 1, 2, "3"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(rejected.outcome(), Outcome::Rejected);
+        assert_eq!(rejected.response_code, "51");
+        let invalid = client
+            .submit_transaction(request(TransactionType::Deposit, 1, 3, "-1"))
+            .await
+            .unwrap_err();
+        assert_eq!(invalid.code(), tonic::Code::InvalidArgument);
+
+        let accounts = client
+            .get_account(GetAccountRequest { client: 1 })
+            .await
+            .unwrap()
+            .into_inner()
+            .accounts;
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, "2.5");
+
+        // A batch forwarded by a peer.
+        let forwarded = client
+            .forward_transactions(ForwardTransactionsRequest {
+                csv: b"type,client,tx,amount\ndeposit,2,4,1.0\ndeposit,2,5,2.0\n".to_vec(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(forwarded.rows, 2);
+        let accounts = client
+            .get_account(GetAccountRequest { client: 2 })
+            .await
+            .unwrap()
+            .into_inner()
+            .accounts;
+        assert_eq!(accounts[0].available, "3.0");
+
+        drop(client);
+        stop_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        drop(ingress);
+        dispatcher.await.unwrap();
+        workers.broadcast(ProcessorMessage::shutdown).await.unwrap();
+        for processor in processors {
+            processor.await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+// Transaction ids for the entries that the engine posts by itself (fees, interest, adjustments). They are taken from a
+// range of ids that is reserved for the engine, so they never collide with the ids of the upstream transactions as
+// long as the upstream ids stay out of the range.
+//
+// The allocator persists a high-water mark instead of every allocated id: a block of ids is reserved in the file before
+// the first id of the block is handed out. A restart continues after the reserved block, so an id is never handed out
+// twice even if the process stops in the middle of a block. The ids left in that block are skipped.
+
+/// Number of ids reserved in the file at a time.
+const BLOCK: u32 = 1024;
+
+#[derive(Debug, Error)]
+pub enum IdAllocatorError {
+    #[error("Cannot access the allocated ids file: {0}")]
+    Io(#[from] io::Error),
+    #[error("The allocated ids file {0} does not contain a transaction id.")]
+    Corrupted(PathBuf),
+    #[error("All the transaction ids of the reserved range {0} were allocated.")]
+    Exhausted(IdRange),
+    #[error("'{0}' is not a range of transaction ids (e.g. 4000000000-4294967295).")]
+    InvalidRange(String),
+}
+
+/// An inclusive range of transaction ids, written as `<START>-<END>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    start: u32,
+    end: u32,
+}
+
+impl IdRange {
+    pub fn new(start: u32, end: u32) -> Option<Self> {
+        (start <= end).then_some(Self { start, end })
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        (self.start..=self.end).contains(&id)
+    }
+}
+
+/// The suggested range is the top of the id space, well above the ids of the upstream systems.
+impl Default for IdRange {
+    fn default() -> Self {
+        Self {
+            start: 4_000_000_000,
+            end: u32::MAX,
+        }
+    }
+}
+
+impl Display for IdRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl FromStr for IdRange {
+    type Err = IdAllocatorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || IdAllocatorError::InvalidRange(value.to_string());
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let start = start.trim().parse().map_err(|_| invalid())?;
+        let end = end.trim().parse().map_err(|_| invalid())?;
+        Self::new(start, end).ok_or_else(invalid)
+    }
+}
+
+/// Hands out the transaction ids of a reserved range in increasing order.
+#[derive(Debug)]
+pub struct IdAllocator {
+    range: IdRange,
+    // The next id to hand out. It's past the end of the range once the range is exhausted.
+    next: u64,
+    // The last id of the block reserved in the file.
+    reserved: u64,
+    path: Option<PathBuf>,
+}
+
+impl IdAllocator {
+    /// An allocator that starts from the beginning of the range every time, for runs without persistent state.
+    pub fn new(range: IdRange) -> Self {
+        Self {
+            range,
+            next: range.start.into(),
+            reserved: u64::MAX,
+            path: None,
+        }
+    }
+
+    /// An allocator that continues after the ids allocated by the previous runs, as recorded in the file. If the range
+    /// was moved up since, allocation starts from the beginning of the new range.
+    pub fn open<P: AsRef<Path>>(path: P, range: IdRange) -> Result<Self, IdAllocatorError> {
+        let path = path.as_ref().to_path_buf();
+        let mut next = u64::from(range.start);
+        if path.exists() {
+            let last: u32 = fs::read_to_string(&path)?
+                .trim()
+                .parse()
+                .map_err(|_| IdAllocatorError::Corrupted(path.clone()))?;
+            next = next.max(u64::from(last) + 1);
+        }
+        Ok(Self {
+            range,
+            next,
+            reserved: next.saturating_sub(1),
+            path: Some(path),
+        })
+    }
+
+    pub fn range(&self) -> IdRange {
+        self.range
+    }
+
+    /// Number of ids that can still be allocated.
+    pub fn remaining(&self) -> u64 {
+        (u64::from(self.range.end) + 1).saturating_sub(self.next)
+    }
+
+    /// Allocate the next id of the range.
+    pub fn allocate(&mut self) -> Result<u32, IdAllocatorError> {
+        let id = u32::try_from(self.next)
+            .ok()
+            .filter(|id| *id <= self.range.end)
+            .ok_or(IdAllocatorError::Exhausted(self.range))?;
+        if self.next > self.reserved {
+            self.reserve(id)?;
+        }
+        self.next += 1;
+        Ok(id)
+    }
+
+    // Record a new block of ids starting at `first` before any of them is handed out.
+    fn reserve(&mut self, first: u32) -> Result<(), IdAllocatorError> {
+        let last = first.saturating_add(BLOCK - 1).min(self.range.end);
+        if let Some(path) = &self.path {
+            let mut temporary = path.as_os_str().to_owned();
+            temporary.push(".tmp");
+            fs::write(&temporary, last.to_string())?;
+            fs::rename(&temporary, path)?;
+        }
+        self.reserved = last.into();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn should_never_allocate_an_id_twice_across_restarts() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("synthetic-ids");
+        let range: IdRange = "100-5000".parse().unwrap();
+
+        let mut allocator = IdAllocator::open(&path, range).unwrap();
+        assert_eq!(allocator.allocate().unwrap(), 100);
+        assert_eq!(allocator.allocate().unwrap(), 101);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1123");
+
+        // The rest of the block reserved by the previous run is skipped.
+        let mut allocator = IdAllocator::open(&path, range).unwrap();
+        assert_eq!(allocator.allocate().unwrap(), 1124);
+        assert_eq!(allocator.remaining(), 5000 - 1124);
+
+        // Moving the range up starts from its beginning.
+        let mut allocator = IdAllocator::open(&path, "3000-5000".parse().unwrap()).unwrap();
+        assert_eq!(allocator.allocate().unwrap(), 3000);
+    }
+
+    #[test]
+    fn should_fail_once_the_range_is_exhausted() {
+        let range = IdRange::new(u32::MAX - 1, u32::MAX).unwrap();
+        let mut allocator = IdAllocator::new(range);
+
+        assert_eq!(allocator.allocate().unwrap(), u32::MAX - 1);
+        assert_eq!(allocator.allocate().unwrap(), u32::MAX);
+        assert!(matches!(
+            allocator.allocate(),
+            Err(IdAllocatorError::Exhausted(_))
+        ));
+        assert!("5-1".parse::<IdRange>().is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::OpenOptions,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::AccountError,
+    engine::WorkerId,
+    id_allocator::IdAllocator,
+    logging::log_event,
+    state::StateError,
+    transaction_types::{
+        AccountName, Amount, ClientId, Transaction, TransactionId, TransactionType,
+    },
+};
+
+// Upstream systems occasionally reuse the id of a transaction of a previous day, e.g. after a counter was reset. The
+// accounts of a run that resumes the state of the previous one don't know the transactions of that run, so such a
+// transaction would be applied as a new one. With a collision policy, the ids of the deposits, withdrawals, moves and
+// escrow holds that were applied are kept in the state directory, and the funding transactions of the next runs are
+// checked against them before they are applied. A transaction whose id was already used is rejected, skipped if it's
+// the same transaction delivered again, or applied under a new id taken from the range of the synthetic ids. The
+// disputes, resolves and chargebacks of a remapped transaction that arrive later in the same run are rewritten to the
+// new id. Every collision is logged with what was done about it.
+
+/// What happens to a funding transaction whose id was used by a transaction of a previous run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum CollisionPolicy {
+    /// Reject it.
+    #[default]
+    Reject,
+    /// Skip it if it's the same transaction as the previous one (type, client, sub-account and amount), which was
+    /// already applied, and reject it otherwise.
+    AcceptIfIdentical,
+    /// Apply it under a new id from the range of the synthetic ids.
+    Remap,
+}
+
+impl Display for CollisionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.to_possible_value().expect("no variant is skipped");
+        f.write_str(name.get_name())
+    }
+}
+
+/// A funding transaction applied by a run, as it's kept in the history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HistoryRecord {
+    tx: TransactionId,
+    client: ClientId,
+    account: AccountName,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    amount: Option<Amount>,
+}
+
+impl HistoryRecord {
+    fn of(transaction: &Transaction) -> Self {
+        Self {
+            tx: transaction.id(),
+            client: transaction.client(),
+            account: transaction.account().clone(),
+            transaction_type: transaction.transaction_type(),
+            amount: transaction.amount(),
+        }
+    }
+
+    // Whether the transaction is the same as this one, delivered again.
+    fn matches(&self, transaction: &Transaction) -> bool {
+        *self == Self::of(transaction)
+    }
+}
+
+/// The ids of the funding transactions applied by the previous runs, kept in the state directory.
+pub(crate) struct IdHistory {
+    path: PathBuf,
+    records: HashMap<TransactionId, HistoryRecord>,
+}
+
+impl IdHistory {
+    pub(crate) fn load(path: PathBuf) -> Result<Self, StateError> {
+        let mut records = HashMap::new();
+        if path.exists() {
+            let mut reader = csv::Reader::from_path(&path)?;
+            for record in reader.deserialize::<HistoryRecord>() {
+                let record = record?;
+                records.insert(record.tx, record);
+            }
+        }
+        Ok(Self { path, records })
+    }
+
+    fn get(&self, id: TransactionId) -> Option<&HistoryRecord> {
+        self.records.get(&id)
+    }
+
+    /// Add the funding transactions applied by this run, for the next runs.
+    pub(crate) fn append(&self, records: &[HistoryRecord]) -> Result<(), StateError> {
+        let new_file = !self.path.exists();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(new_file)
+            .from_writer(file);
+        for record in records {
+            writer.serialize(record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// What the guard decided about a transaction.
+pub(crate) enum Guarded {
+    /// Apply the transaction, possibly under a new id.
+    Apply(Transaction),
+    /// Don't apply the transaction, it was already applied by a previous run.
+    Skip(Transaction),
+    Reject(Transaction, AccountError),
+}
+
+/// Checks the transactions of a worker against the history of the previous runs.
+pub(crate) struct CollisionGuard {
+    policy: CollisionPolicy,
+    history: Arc<IdHistory>,
+    // Where the new ids of the remapped transactions come from. Shared by the workers. Only there with
+    // `--synthetic-ids`, which remapping requires.
+    synthetic_ids: Option<Arc<Mutex<IdAllocator>>>,
+    // The new ids of the remapped transactions of this run, by client and original id.
+    remapped: HashMap<(ClientId, TransactionId), TransactionId>,
+    // The funding transactions applied by this run.
+    applied: Vec<HistoryRecord>,
+}
+
+impl CollisionGuard {
+    pub(crate) fn new(
+        policy: CollisionPolicy,
+        history: Arc<IdHistory>,
+        synthetic_ids: Option<Arc<Mutex<IdAllocator>>>,
+    ) -> Self {
+        Self {
+            policy,
+            history,
+            synthetic_ids,
+            remapped: HashMap::new(),
+            applied: Vec::new(),
+        }
+    }
+
+    /// Check a transaction before it's applied. The operations of a remapped transaction are rewritten to its new id,
+    /// and so is a remapped transaction that is delivered again, so that the account rejects it as a duplicate.
+    pub(crate) fn check(&mut self, transaction: Transaction, worker: WorkerId) -> Guarded {
+        let key = (transaction.client(), transaction.id());
+        if let Some(id) = self.remapped.get(&key) {
+            return Guarded::Apply(transaction.with_id(*id));
+        }
+        if !transaction.transaction_type().is_funding() {
+            return Guarded::Apply(transaction);
+        }
+        let Some(previous) = self.history.get(transaction.id()) else {
+            return Guarded::Apply(transaction);
+        };
+        let reused = AccountError::TransactionIdReused(transaction.id());
+        let guarded = match self.policy {
+            CollisionPolicy::Reject => Guarded::Reject(transaction, reused),
+            CollisionPolicy::AcceptIfIdentical if previous.matches(&transaction) => {
+                Guarded::Skip(transaction)
+            }
+            CollisionPolicy::AcceptIfIdentical => Guarded::Reject(transaction, reused),
+            CollisionPolicy::Remap => {
+                let allocated = self.synthetic_ids.as_ref().map(|synthetic_ids| {
+                    synthetic_ids
+                        .lock()
+                        .expect("the synthetic ids allocator is never poisoned")
+                        .allocate()
+                });
+                match allocated {
+                    Some(Ok(id)) => {
+                        let id = TransactionId::from(id);
+                        self.remapped.insert(key, id);
+                        Guarded::Apply(transaction.with_id(id))
+                    }
+                    Some(Err(err)) => {
+                        eprintln!(
+                            "{}: Cannot remap transaction {}: {}",
+                            worker,
+                            transaction.id(),
+                            err
+                        );
+                        Guarded::Reject(transaction, reused)
+                    }
+                    None => Guarded::Reject(transaction, reused),
+                }
+            }
+        };
+        self.report(&guarded, key, worker);
+        guarded
+    }
+
+    fn report(&self, guarded: &Guarded, key: (ClientId, TransactionId), worker: WorkerId) {
+        let (transaction, outcome) = match guarded {
+            Guarded::Apply(transaction) => (transaction, "remapped"),
+            Guarded::Skip(transaction) => (transaction, "skipped"),
+            Guarded::Reject(transaction, _) => (transaction, "rejected"),
+        };
+        let transaction_type = transaction.transaction_type();
+        let mut fields: Vec<(&str, &dyn Display)> = vec![
+            ("worker", &worker),
+            ("client", &key.0),
+            ("tx", &key.1),
+            ("type", &transaction_type),
+            ("policy", &self.policy),
+            ("outcome", &outcome),
+        ];
+        let new_id = transaction.id();
+        if new_id != key.1 {
+            fields.push(("new_tx", &new_id));
+        }
+        log_event("transaction_id_collision", &fields);
+    }
+
+    /// Add an applied transaction to the history of this run, if it's a funding transaction.
+    pub(crate) fn record(&mut self, transaction: &Transaction) {
+        if transaction.transaction_type().is_funding() {
+            self.applied.push(HistoryRecord::of(transaction));
+        }
+    }
+
+    /// The funding transactions applied by this run, to add to the history.
+    pub(crate) fn take_applied(&mut self) -> Vec<HistoryRecord> {
+        std::mem::take(&mut self.applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::id_allocator::IdRange;
+
+    use super::*;
+
+    fn deposit(client: u16, tx: u32, amount: f64) -> Transaction {
+        Transaction::new(
+            TransactionType::Deposit,
+            client.into(),
+            tx.into(),
+            Some(amount.into()),
+        )
+    }
+
+    fn dispute(client: u16, tx: u32) -> Transaction {
+        Transaction::new(TransactionType::Dispute, client.into(), tx.into(), None)
+    }
+
+    fn guard(policy: CollisionPolicy) -> (tempfile::TempDir, CollisionGuard) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transaction-ids.csv");
+        IdHistory::load(path.clone())
+            .unwrap()
+            .append(&[HistoryRecord::of(&deposit(1, 7, 10.0))])
+            .unwrap();
+        let history = Arc::new(IdHistory::load(path).unwrap());
+        let synthetic_ids = IdAllocator::new(IdRange::new(100, 200).unwrap());
+        let guard = CollisionGuard::new(policy, history, Some(Arc::new(Mutex::new(synthetic_ids))));
+        (dir, guard)
+    }
+
+    #[test]
+    fn should_skip_identical_transactions_and_reject_the_others() {
+        let (_dir, mut guard) = guard(CollisionPolicy::AcceptIfIdentical);
+        let worker = WorkerId::default();
+
+        assert!(matches!(
+            guard.check(deposit(1, 7, 10.0), worker),
+            Guarded::Skip(_)
+        ));
+        assert!(matches!(
+            guard.check(deposit(1, 7, 12.0), worker),
+            Guarded::Reject(_, AccountError::TransactionIdReused(_))
+        ));
+        assert!(matches!(
+            guard.check(deposit(1, 8, 10.0), worker),
+            Guarded::Apply(_)
+        ));
+    }
+
+    #[test]
+    fn should_remap_reused_ids_and_their_dispute_operations() {
+        let (_dir, mut guard) = guard(CollisionPolicy::Remap);
+        let worker = WorkerId::default();
+
+        let Guarded::Apply(remapped) = guard.check(deposit(1, 7, 12.0), worker) else {
+            panic!("the deposit should be applied");
+        };
+        assert_eq!(remapped.id(), TransactionId::from(100));
+        let Guarded::Apply(rewritten) = guard.check(dispute(1, 7), worker) else {
+            panic!("the dispute should be applied");
+        };
+        assert_eq!(rewritten.id(), TransactionId::from(100));
+        // The same id of another client only refers to the transaction of the previous run.
+        let Guarded::Apply(unchanged) = guard.check(dispute(2, 7), worker) else {
+            panic!("the dispute should be applied");
+        };
+        assert_eq!(unchanged.id(), TransactionId::from(7));
+
+        guard.record(&remapped);
+        assert_eq!(
+            guard.take_applied(),
+            vec![HistoryRecord::of(&deposit(1, 100, 12.0))]
+        );
+    }
+}
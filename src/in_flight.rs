@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+};
+
+use thiserror::Error;
+
+use crate::{metrics::Metrics, transaction_types::ClientId};
+
+// Limit of the transactions of a client that are in flight, i.e. queued on the ingress or on a worker and not yet
+// applied, for the transactions posted to the daemon. A buggy integration that floods the API with the transactions of
+// one client would otherwise fill the queue of the worker of the client, and hold back every other client of the
+// worker. The transactions beyond the limit are rejected, and the client has to retry once the workers caught up.
+//
+// Every admitted transaction carries a ticket in its extensions. The ticket is given back when the transaction is
+// dropped, which is once the worker applied it, failed it or forwarded it, so the count needs no help from the workers.
+// Transactions held back by a paused worker or waiting for the transaction they reference stay in flight.
+
+type Counts = Arc<Mutex<HashMap<ClientId, u32>>>;
+
+/// The transactions in flight per client, shared by the sources that are limited.
+#[derive(Clone)]
+pub(crate) struct InFlight {
+    limit: NonZeroU32,
+    counts: Counts,
+    metrics: Metrics,
+}
+
+/// Admission of a transaction, given back when it's dropped.
+pub(crate) struct InFlightTicket {
+    client: ClientId,
+    counts: Counts,
+}
+
+/// A client already has as many transactions in flight as allowed.
+#[derive(Debug, Error)]
+#[error(
+    "Client {client} already has {limit} transactions waiting to be applied, retry once they are applied."
+)]
+pub(crate) struct TooManyInFlight {
+    pub(crate) client: ClientId,
+    pub(crate) limit: NonZeroU32,
+}
+
+impl InFlight {
+    pub(crate) fn new(limit: NonZeroU32, metrics: Metrics) -> Self {
+        Self {
+            limit,
+            counts: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Admit a transaction of a client, unless the client is at the limit.
+    pub(crate) fn admit(&self, client: ClientId) -> Result<InFlightTicket, TooManyInFlight> {
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("In flight lock is never poisoned.");
+        let count = counts.entry(client).or_default();
+        if *count >= self.limit.get() {
+            drop(counts);
+            self.metrics.count("transactions.throttled", &[], 1);
+            return Err(TooManyInFlight {
+                client,
+                limit: self.limit,
+            });
+        }
+        *count += 1;
+        Ok(InFlightTicket {
+            client,
+            counts: Arc::clone(&self.counts),
+        })
+    }
+
+    #[cfg(test)]
+    fn count(&self, client: ClientId) -> u32 {
+        let counts = self
+            .counts
+            .lock()
+            .expect("In flight lock is never poisoned.");
+        counts.get(&client).copied().unwrap_or_default()
+    }
+}
+
+impl Drop for InFlightTicket {
+    fn drop(&mut self) {
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("In flight lock is never poisoned.");
+        // The clients without transactions in flight are forgotten, so the map only holds the busy clients.
+        if let Some(count) = counts.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_reject_the_transactions_of_a_client_beyond_the_limit() {
+        let in_flight = InFlight::new(NonZeroU32::new(2).unwrap(), Metrics::default());
+        let first = in_flight.admit(1.into()).unwrap();
+        let second = in_flight.admit(1.into()).unwrap();
+        let err = in_flight.admit(1.into()).err().unwrap();
+        assert_eq!((err.client, err.limit.get()), (1.into(), 2));
+        // The other clients are not held back.
+        let other = in_flight.admit(2.into()).unwrap();
+
+        drop(first);
+        assert_eq!(in_flight.count(1.into()), 1);
+        let third = in_flight.admit(1.into()).unwrap();
+        drop((second, third, other));
+        assert_eq!(in_flight.count(1.into()), 0);
+        assert!(in_flight.counts.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,931 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use encoding_rs::Encoding;
+use futures_util::future::select_all;
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{self, Sender, error::TryRecvError},
+        watch,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    cluster::{Cluster, Forwarders, Route},
+    csv_reader::{Compression, CsvFileReader, FileMetadata, RawChunk, ReaderError, RecordError},
+    engine::ShardedEngine,
+    enrichment::Enrichers,
+    in_flight::{InFlight, TooManyInFlight},
+    logging::{RecordLog, log_event},
+    object_input::ObjectInput,
+    outcome::{self, TransactionOutcome},
+    pipeline::Parser,
+    profiling::Profiler,
+    spans::TraceContext,
+    transaction_processor::ProcessorMessage,
+    transaction_types::Transaction,
+};
+
+// Fan-in of the input sources (the input file, the HTTP API, a watched directory) into the worker queues.
+// Every source has its own queue and the dispatcher takes turns between the sources that have transactions waiting,
+// up to a quantum of transactions each, so a large file doesn't hold back the transactions sent through the API.
+// The transactions of a source are sent to the workers in the order they were received from that source.
+// There's no ordering between the sources.
+
+// Number of transactions a source can queue before its sender has to wait.
+const SOURCE_QUEUE: usize = 1024;
+// Number of transactions taken from a source before moving on to the next one.
+const QUANTUM: usize = 64;
+// Number of records parsed at a time by a parse task.
+const PARSE_CHUNK: usize = 1024;
+// Share of a second of transactions that a paced input can send at once, see `RateLimiter`.
+const BURST: f64 = 0.1;
+// How often `SourceHandle::settled` checks whether the transactions of a source were handed over.
+const SETTLE_POLL: Duration = Duration::from_millis(1);
+
+/// How the input files are read.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ReaderOptions {
+    pub(crate) encoding: Option<&'static Encoding>,
+    pub(crate) compression: Compression,
+    pub(crate) lenient_amounts: bool,
+    /// Number of tasks that parse the records in parallel. With 0 or 1, the records are parsed as they are read.
+    pub(crate) parse_workers: usize,
+    /// Maximum number of transactions per second queued from an input, if the input is paced.
+    pub(crate) rate: Option<NonZeroU32>,
+}
+
+impl ReaderOptions {
+    /// Open an input file, or start the download of an input that is an object of an object store.
+    pub(crate) fn open(&self, path: &Path) -> Result<CsvFileReader, ReaderError> {
+        let reader = match ObjectInput::from_path(path) {
+            Some(object) => {
+                let object = object.map_err(|err| {
+                    ReaderError::io(path, io::Error::new(io::ErrorKind::InvalidInput, err))
+                })?;
+                let (size, input) = object.open().map_err(|err| ReaderError::io(path, err))?;
+                CsvFileReader::from_reader(path, size, input, self.encoding, self.compression)?
+            }
+            None => {
+                CsvFileReader::from_path_with_compression(path, self.encoding, self.compression)?
+            }
+        };
+        Ok(reader.with_lenient_amounts(self.lenient_amounts))
+    }
+}
+
+// Counters of a source, updated by the source and the dispatcher.
+#[derive(Debug, Default)]
+struct SourceMetrics {
+    name: String,
+    received: AtomicU64,
+    parse_errors: AtomicU64,
+    dispatched: AtomicU64,
+    forwarded: AtomicU64,
+    closed: AtomicBool,
+}
+
+/// The counters of a source at some point in time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct SourceStats {
+    pub(crate) source: String,
+    /// Transactions received from the source.
+    pub(crate) received: u64,
+    /// Records of the source that could not be parsed.
+    pub(crate) parse_errors: u64,
+    /// Transactions sent to the workers.
+    pub(crate) dispatched: u64,
+    /// Transactions sent to the peers that own their shard in cluster mode. The transactions that were neither
+    /// dispatched nor forwarded are still queued.
+    pub(crate) forwarded: u64,
+    pub(crate) open: bool,
+}
+
+/// The sending side of an input source. Clones send to the same source, which is closed once all of them are dropped.
+#[derive(Clone)]
+pub(crate) struct SourceHandle {
+    tx: Sender<Transaction>,
+    metrics: Arc<SourceMetrics>,
+    // Set by the dispatcher once the source is closed and all its transactions were sent to the workers.
+    drained: watch::Receiver<bool>,
+    // The trace the transactions sent through this handle belong to.
+    trace: Option<TraceContext>,
+    // The limit of the transactions in flight per client, for the records ingested through this handle.
+    in_flight: Option<InFlight>,
+}
+
+impl SourceHandle {
+    /// A handle that attaches a trace context to the transactions it sends, so the workers record their spans in it.
+    pub(crate) fn with_trace(&self, trace: TraceContext) -> Self {
+        Self {
+            trace: Some(trace),
+            ..self.clone()
+        }
+    }
+
+    /// A handle that rejects the records it ingests beyond the transactions in flight allowed per client. The
+    /// transactions sent with `send` directly are not limited.
+    pub(crate) fn with_in_flight(&self, in_flight: InFlight) -> Self {
+        Self {
+            in_flight: Some(in_flight),
+            ..self.clone()
+        }
+    }
+
+    // Attach the ticket of a transaction in flight, if the handle is limited.
+    fn admit(&self, transaction: &mut Transaction) -> Result<(), TooManyInFlight> {
+        if let Some(in_flight) = &self.in_flight {
+            let ticket = in_flight.admit(transaction.client())?;
+            transaction.extensions_mut().insert(ticket);
+        }
+        Ok(())
+    }
+
+    /// Queue a transaction. Waits when the queue of the source is full.
+    pub(crate) async fn send(&self, mut transaction: Transaction) -> Result<(), IngressClosed> {
+        if let Some(trace) = self.trace {
+            transaction.extensions_mut().insert(trace);
+        }
+        self.metrics.received.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(transaction).await.map_err(|_| IngressClosed)
+    }
+
+    /// Count a record of the source that could not be parsed.
+    pub(crate) fn parse_error(&self) {
+        self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Transactions of the source that were handed over to a worker or to a peer. The source is first in first out,
+    /// so these are the first transactions that were queued.
+    pub(crate) fn accepted(&self) -> u64 {
+        let stats = self.metrics.stats();
+        stats.dispatched + stats.forwarded
+    }
+
+    /// Wait until the transactions queued on the source so far were handed over to a worker or to a peer, so that a
+    /// request sent to a worker after them is applied after them too. Returns early if the ingress was closed.
+    pub(crate) async fn settled(&self) {
+        let queued = self.metrics.received.load(Ordering::Relaxed);
+        while self.accepted() < queued && !self.tx.is_closed() {
+            tokio::time::sleep(SETTLE_POLL).await;
+        }
+    }
+
+    /// Close the source and wait until all its transactions were sent to the workers, returning its final counters.
+    /// Waits for the clones of the handle to be dropped too.
+    pub(crate) async fn finish(self) -> SourceStats {
+        let mut drained = self.drained.clone();
+        let metrics = Arc::clone(&self.metrics);
+        drop(self);
+        let _ = drained.wait_for(|drained| *drained).await;
+        metrics.stats()
+    }
+}
+
+// Paces the transactions of an input to a rate with a token bucket. The bucket holds `BURST` of a second of
+// transactions, so a reader that was held back by a full queue catches up a little but doesn't flood the workers.
+// Taking a token from an empty bucket borrows it, and the wait pays the debt back, so the waits that are shorter than
+// the resolution of the timer still add up to the rate.
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: NonZeroU32, now: Instant) -> Self {
+        let rate = f64::from(rate.get());
+        let burst = (rate * BURST).max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    // Take a token for a transaction and return how long to wait before sending it.
+    fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// The dispatcher stopped, which only happens when the workers are gone.
+#[derive(Debug, thiserror::Error)]
+#[error("The workers are not accepting transactions anymore.")]
+pub(crate) struct IngressClosed;
+
+/// A error describing why an input could not be ingested.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum IngestError {
+    #[error(transparent)]
+    Reader(#[from] ReaderError),
+    #[error(transparent)]
+    Closed(#[from] IngressClosed),
+    #[error(transparent)]
+    Throttled(#[from] TooManyInFlight),
+}
+
+// The receiving side of a source, owned by the dispatcher.
+struct Source {
+    rx: mpsc::Receiver<Transaction>,
+    metrics: Arc<SourceMetrics>,
+    drained: watch::Sender<bool>,
+    // Whether transactions of the shards of peers are forwarded. Transactions that were forwarded by a peer are not
+    // forwarded again, so that nodes that disagree about the owner of a shard don't bounce transactions around.
+    forward: bool,
+}
+
+// Where the dispatcher sends the transactions.
+struct Targets {
+    workers: ShardedEngine,
+    // The shards and the peers in cluster mode.
+    cluster: Option<(Cluster, Forwarders)>,
+    // Run on the transactions that are sent to the workers. Forwarded transactions are enriched by their peer.
+    enrichers: Enrichers,
+}
+
+impl Source {
+    async fn dispatch(&self, mut transaction: Transaction, targets: &Targets) {
+        if self.forward
+            && let Some((cluster, forwarders)) = &targets.cluster
+            && let Route::Peer(peer) = cluster.shards.route(transaction.client())
+        {
+            outcome::report(&transaction, || TransactionOutcome::Forwarded);
+            if forwarders.send(peer, transaction).await {
+                self.metrics.forwarded.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        targets.enrichers.enrich(&mut transaction);
+        let transaction_id = transaction.id();
+        let client = transaction.client();
+        let sent = targets
+            .workers
+            .send(client, ProcessorMessage::process_transaction(transaction))
+            .await;
+        match sent {
+            Ok(()) => {
+                self.metrics.dispatched.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => eprintln!(
+                "Could not process transaction {} for client {}: worker error {}",
+                transaction_id, client, e
+            ),
+        }
+    }
+
+    fn close(&self) {
+        self.metrics.closed.store(true, Ordering::Relaxed);
+        let _ = self.drained.send(true);
+        let stats = self.metrics.stats();
+        log_event(
+            "source_closed",
+            &[
+                ("source", &stats.source),
+                ("received", &stats.received),
+                ("parse_errors", &stats.parse_errors),
+                ("dispatched", &stats.dispatched),
+                ("forwarded", &stats.forwarded),
+            ],
+        );
+    }
+}
+
+impl SourceMetrics {
+    fn stats(&self) -> SourceStats {
+        SourceStats {
+            source: self.name.clone(),
+            received: self.received.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            dispatched: self.dispatched.load(Ordering::Relaxed),
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            open: !self.closed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Registers input sources with the dispatcher. Clones register with the same dispatcher, which stops once all the
+/// clones and all the sources are dropped.
+#[derive(Clone)]
+pub(crate) struct Ingress {
+    registrations: mpsc::UnboundedSender<Source>,
+    sources: Arc<Mutex<Vec<Arc<SourceMetrics>>>>,
+}
+
+impl Ingress {
+    /// Start the dispatcher that feeds the workers, and the peers that own some of the shards in cluster mode. The
+    /// transactions sent to the workers are enriched first. The dispatcher returns the number of transactions that
+    /// could not be forwarded to their peer.
+    pub(crate) fn start(
+        workers: ShardedEngine,
+        cluster: Option<Cluster>,
+        enrichers: Enrichers,
+    ) -> (Self, JoinHandle<u64>) {
+        let (ingress, registered) = Self::new();
+        let targets = Targets {
+            workers,
+            cluster: cluster.map(|cluster| {
+                let forwarders = Forwarders::start(&cluster);
+                (cluster, forwarders)
+            }),
+            enrichers,
+        };
+        (ingress, tokio::spawn(dispatch(registered, targets)))
+    }
+
+    fn new() -> (Self, mpsc::UnboundedReceiver<Source>) {
+        let (registrations, registered) = mpsc::unbounded_channel();
+        let ingress = Self {
+            registrations,
+            sources: Arc::default(),
+        };
+        (ingress, registered)
+    }
+
+    /// Add an input source, e.g. `file` or `http`.
+    pub(crate) fn source(&self, name: &str) -> SourceHandle {
+        self.add_source(name, true)
+    }
+
+    /// Add the source of the transactions forwarded by the peers in cluster mode.
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub(crate) fn peer_source(&self) -> SourceHandle {
+        self.add_source("peer", false)
+    }
+
+    fn add_source(&self, name: &str, forward: bool) -> SourceHandle {
+        let (tx, rx) = mpsc::channel(SOURCE_QUEUE);
+        let (drained_tx, drained) = watch::channel(false);
+        let metrics = Arc::new(SourceMetrics {
+            name: name.to_string(),
+            ..Default::default()
+        });
+        self.sources
+            .lock()
+            .expect("Sources lock is never poisoned.")
+            .push(Arc::clone(&metrics));
+        let source = Source {
+            rx,
+            metrics: Arc::clone(&metrics),
+            drained: drained_tx,
+            forward,
+        };
+        // The dispatcher only stops once every clone of the ingress is dropped, so it's still running here.
+        let _ = self.registrations.send(source);
+        SourceHandle {
+            tx,
+            metrics,
+            drained,
+            trace: None,
+            in_flight: None,
+        }
+    }
+
+    /// The counters of all the sources, in the order they were added.
+    pub(crate) fn stats(&self) -> Vec<SourceStats> {
+        self.sources
+            .lock()
+            .expect("Sources lock is never poisoned.")
+            .iter()
+            .map(|metrics| metrics.stats())
+            .collect()
+    }
+
+    /// Records that could not be parsed, over all the sources.
+    pub(crate) fn parse_errors(&self) -> u64 {
+        self.stats().iter().map(|stats| stats.parse_errors).sum()
+    }
+}
+
+// Why the dispatcher woke up while no source had transactions waiting.
+enum Wakeup {
+    // A transaction of the source at the index, or nothing if the source was closed.
+    Received(Option<Transaction>, usize),
+    // A new source, or nothing if no more sources can be added.
+    Registered(Option<Source>),
+}
+
+// Take turns between the sources that have transactions waiting and sleep while none has.
+async fn dispatch(mut registered: mpsc::UnboundedReceiver<Source>, targets: Targets) -> u64 {
+    let mut sources: Vec<Source> = Vec::new();
+    let mut registering = true;
+    loop {
+        while let Ok(source) = registered.try_recv() {
+            sources.push(source);
+        }
+
+        // A round: up to a quantum of transactions from every source.
+        let mut idle = true;
+        let mut closed = Vec::new();
+        for (index, source) in sources.iter_mut().enumerate() {
+            for _ in 0..QUANTUM {
+                match source.rx.try_recv() {
+                    Ok(transaction) => {
+                        idle = false;
+                        source.dispatch(transaction, &targets).await;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        closed.push(index);
+                        break;
+                    }
+                }
+            }
+        }
+        for index in closed.into_iter().rev() {
+            sources.remove(index).close();
+        }
+        if !idle {
+            continue;
+        }
+
+        // Nothing is waiting. Sleep until a source sends something or a new source is added.
+        if sources.is_empty() {
+            if !registering {
+                break;
+            }
+            match registered.recv().await {
+                Some(source) => sources.push(source),
+                None => break,
+            }
+            continue;
+        }
+        let woken = tokio::select! {
+            (received, index, _) = select_all(sources.iter_mut().map(|source| Box::pin(source.rx.recv()))) => {
+                Wakeup::Received(received, index)
+            }
+            source = registered.recv(), if registering => Wakeup::Registered(source),
+        };
+        match woken {
+            Wakeup::Received(Some(transaction), index) => {
+                sources[index].dispatch(transaction, &targets).await
+            }
+            Wakeup::Received(None, index) => sources.remove(index).close(),
+            Wakeup::Registered(Some(source)) => sources.push(source),
+            Wakeup::Registered(None) => registering = false,
+        }
+    }
+
+    match targets.cluster {
+        Some((_, forwarders)) => forwarders.finish().await,
+        None => 0,
+    }
+}
+
+/// A pattern of the input files that cannot be expanded.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum InputPatternError {
+    #[error("'{0}' is not a valid pattern: {1}")]
+    Invalid(String, glob::PatternError),
+    #[error("'{0}' doesn't match any file.")]
+    NoMatch(String),
+    #[error("Cannot read the files of '{0}': {1}")]
+    Unreadable(String, glob::GlobError),
+}
+
+/// Expand the glob patterns of the input files, e.g. `branches/*.csv`, keeping the order of the arguments. The files
+/// of a pattern are in alphabetical order, which is the order of the days for dated file names. Paths without a
+/// wildcard are kept as they are, so a missing file is reported when it's opened.
+pub(crate) fn expand_inputs(patterns: &[PathBuf]) -> Result<Vec<PathBuf>, InputPatternError> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let text = pattern.to_string_lossy();
+        // The keys of objects are not listed, so they are taken as they are.
+        if !text.contains(['*', '?', '[']) || ObjectInput::from_path(pattern).is_some() {
+            files.push(pattern.clone());
+            continue;
+        }
+        let matches = glob::glob(&text)
+            .map_err(|err| InputPatternError::Invalid(text.to_string(), err))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| InputPatternError::Unreadable(text.to_string(), err))?;
+        if matches.is_empty() {
+            return Err(InputPatternError::NoMatch(text.to_string()));
+        }
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+/// Parse a CSV file and queue its transactions on a source. Returns the metadata of the file.
+pub(crate) async fn ingest_file(
+    path: &Path,
+    options: ReaderOptions,
+    source: &SourceHandle,
+    profiler: &mut Profiler,
+) -> Result<FileMetadata, IngestError> {
+    let file_parser = options.open(path)?;
+    ingest(file_parser, options, source, profiler).await
+}
+
+/// Parse CSV input that is already in memory and queue its transactions on a source. The input is not paced, since
+/// whoever sends it already sets its pace.
+pub(crate) async fn ingest_bytes(
+    name: &str,
+    bytes: Vec<u8>,
+    options: ReaderOptions,
+    source: &SourceHandle,
+) -> Result<FileMetadata, IngestError> {
+    let file_parser = CsvFileReader::from_bytes(name, bytes, options.encoding)
+        .with_lenient_amounts(options.lenient_amounts);
+    let options = ReaderOptions {
+        rate: None,
+        ..options
+    };
+    ingest(file_parser, options, source, &mut Profiler::disabled()).await
+}
+
+async fn ingest(
+    file_parser: CsvFileReader,
+    options: ReaderOptions,
+    source: &SourceHandle,
+    profiler: &mut Profiler,
+) -> Result<FileMetadata, IngestError> {
+    let started = Instant::now();
+    let mut pacer = options.rate.map(|rate| RateLimiter::new(rate, started));
+    let file_parser = if options.parse_workers > 1 {
+        ingest_parallel(
+            file_parser,
+            options.parse_workers,
+            source,
+            &mut pacer,
+            profiler,
+        )
+        .await?
+    } else {
+        ingest_sequential(file_parser, source, &mut pacer, profiler).await?
+    };
+
+    let metadata = file_parser.metadata().clone();
+    log_event(
+        "file_ingested",
+        &[
+            ("path", &metadata.path.display()),
+            ("size", &metadata.size),
+            ("header", &metadata.header.as_deref().unwrap_or_default()),
+            ("rows", &metadata.rows),
+            ("duration_ms", &started.elapsed().as_millis()),
+        ],
+    );
+    Ok(metadata)
+}
+
+// Queue a parsed record on a source, once the pacer lets it through, or count it as a parse error.
+async fn queue(
+    record: Result<Transaction, RecordError>,
+    source: &SourceHandle,
+    log: &mut RecordLog<RecordError>,
+    pacer: &mut Option<RateLimiter>,
+    profiler: &mut Profiler,
+) -> Result<(), IngestError> {
+    match record {
+        Ok(mut transaction) => {
+            source.admit(&mut transaction)?;
+            if let Some(pacer) = pacer {
+                let wait = pacer.take(Instant::now());
+                if !wait.is_zero() {
+                    profiler.enter("pace");
+                    tokio::time::sleep(wait).await;
+                    profiler.exit();
+                }
+            }
+            profiler.enter("send");
+            let sent = source.send(transaction).await;
+            profiler.exit();
+            Ok(sent?)
+        }
+        Err(e) => {
+            if log.should_log(&e) {
+                eprintln!("Error reading CSV record: {:?}", e);
+            }
+            source.parse_error();
+            Ok(())
+        }
+    }
+}
+
+// Parse the records as they are read.
+async fn ingest_sequential(
+    mut file_parser: CsvFileReader,
+    source: &SourceHandle,
+    pacer: &mut Option<RateLimiter>,
+    profiler: &mut Profiler,
+) -> Result<CsvFileReader, IngestError> {
+    let mut log = RecordLog::new();
+    let mut records = file_parser.transactions();
+    loop {
+        profiler.enter("parse");
+        let record = records.next();
+        profiler.exit();
+        let Some(record) = record else {
+            break;
+        };
+        queue(record, source, &mut log, pacer, profiler).await?;
+    }
+    drop(records);
+    Ok(file_parser)
+}
+
+// Parsing is the bottleneck of a single reader, so the records are read in chunks on one thread and the chunks are
+// parsed by a pool of tasks, whichever is free. The chunks are numbered as they are read and put back in that order
+// before their transactions are queued, so the transactions of every client keep the order of the input.
+async fn ingest_parallel(
+    file_parser: CsvFileReader,
+    workers: usize,
+    source: &SourceHandle,
+    pacer: &mut Option<RateLimiter>,
+    profiler: &mut Profiler,
+) -> Result<CsvFileReader, IngestError> {
+    let (chunk_tx, chunk_rx) = mpsc::channel::<(u64, RawChunk)>(workers);
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let (parsed_tx, mut parsed_rx) = mpsc::channel(workers);
+    for _ in 0..workers {
+        let (chunk_rx, parsed_tx) = (Arc::clone(&chunk_rx), parsed_tx.clone());
+        tokio::task::spawn_blocking(move || {
+            loop {
+                let next = chunk_rx
+                    .lock()
+                    .expect("Chunk queue lock is never poisoned.")
+                    .blocking_recv();
+                let Some((sequence, chunk)) = next else {
+                    break;
+                };
+                if parsed_tx.blocking_send((sequence, chunk.parse())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(parsed_tx);
+
+    // The reader stops early if the parse tasks are gone because the source was closed.
+    let reader = tokio::task::spawn_blocking(move || {
+        let mut file_parser = file_parser;
+        let mut sequence = 0;
+        while let Some(chunk) = file_parser.read_chunk(PARSE_CHUNK) {
+            if chunk_tx.blocking_send((sequence, chunk)).is_err() {
+                break;
+            }
+            sequence += 1;
+        }
+        file_parser
+    });
+
+    let mut log = RecordLog::new();
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    loop {
+        profiler.enter("parse");
+        let parsed = parsed_rx.recv().await;
+        profiler.exit();
+        let Some((sequence, records)) = parsed else {
+            break;
+        };
+        pending.insert(sequence, records);
+        while let Some(records) = pending.remove(&next) {
+            for record in records {
+                queue(record, source, &mut log, pacer, profiler).await?;
+            }
+            next += 1;
+        }
+    }
+    Ok(reader.await.expect("Reading the input doesn't panic."))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        NUM_WORKERS,
+        engine::{Partitioner, Shard},
+        enrichment::{Currency, CurrencyNormalizer},
+        transaction_types::TransactionType,
+    };
+
+    use super::*;
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            tx.into(),
+            Some(1.0.into()),
+        )
+    }
+
+    #[tokio::test]
+    async fn should_take_turns_between_sources() {
+        let mut receivers = Vec::new();
+        let mut workers = Vec::new();
+        for _ in 0..NUM_WORKERS {
+            let (tx, rx) = mpsc::channel(1024);
+            workers.push(tx);
+            receivers.push(rx);
+        }
+        let (ingress, registered) = Ingress::new();
+
+        // The file queued all its transactions before the API source sends anything.
+        let file = ingress.source("file");
+        let http = ingress.source("http");
+        for tx in 0..500 {
+            file.send(deposit(tx)).await.unwrap();
+        }
+        for tx in 1000..1010 {
+            http.send(deposit(tx)).await.unwrap();
+        }
+        drop((file, http, ingress));
+        dispatch(
+            registered,
+            Targets {
+                workers: ShardedEngine::new(workers),
+                cluster: None,
+                enrichers: Enrichers::default(),
+            },
+        )
+        .await;
+
+        // All the transactions of the client went to the same worker.
+        let rx = &mut receivers[Shard::of(1.into(), NUM_WORKERS, Partitioner::Hash).index()];
+        let mut order = Vec::new();
+        while let Ok(ProcessorMessage::ProcessTransaction(transaction)) = rx.try_recv() {
+            order.push(transaction.id().to_string().parse::<u32>().unwrap());
+        }
+        assert_eq!(order.len(), 510);
+        // The transactions of the API didn't wait for the whole file.
+        let position = order.iter().position(|tx| *tx >= 1000).unwrap();
+        assert!(position < 2 * QUANTUM);
+        // Each source kept its order.
+        assert!(order.iter().filter(|tx| **tx < 1000).is_sorted());
+        assert!(order.iter().filter(|tx| **tx >= 1000).is_sorted());
+    }
+
+    #[tokio::test]
+    async fn should_enrich_transactions_before_dispatching_them() {
+        let (worker, mut rx) = mpsc::channel(1024);
+        let (ingress, dispatcher) = Ingress::start(
+            ShardedEngine::new(vec![worker; NUM_WORKERS]),
+            None,
+            Enrichers::default().with(CurrencyNormalizer),
+        );
+        let source = ingress.source("http");
+        source
+            .send(deposit(1).with_currency(Some("eur")))
+            .await
+            .unwrap();
+        source.finish().await;
+        drop(ingress);
+        dispatcher.await.unwrap();
+
+        let Ok(ProcessorMessage::ProcessTransaction(transaction)) = rx.try_recv() else {
+            panic!("The transaction was dispatched.");
+        };
+        assert_eq!(
+            transaction.extensions().get::<Currency>(),
+            Some(&"EUR".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_count_transactions_per_source() {
+        let (worker, _rx) = mpsc::channel(1024);
+        let (ingress, dispatcher) = Ingress::start(
+            ShardedEngine::new(vec![worker; NUM_WORKERS]),
+            None,
+            Enrichers::default(),
+        );
+        let source = ingress.source("http");
+        ingest_bytes(
+            "request",
+            b"type,client,tx,amount\ndeposit,1,1,1.0\nbogus\ndeposit,2,2,1.0\n".to_vec(),
+            ReaderOptions::default(),
+            &source,
+        )
+        .await
+        .unwrap();
+        source.finish().await;
+
+        assert_eq!(
+            ingress.stats(),
+            vec![SourceStats {
+                source: "http".to_string(),
+                received: 2,
+                parse_errors: 1,
+                dispatched: 2,
+                forwarded: 0,
+                open: false,
+            }]
+        );
+        drop(ingress);
+        dispatcher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_input_order_when_parsing_in_parallel() {
+        let mut input = String::from("Type,Client,TX,Amount\n");
+        for tx in 0..5000 {
+            if tx % 700 == 0 {
+                input.push_str("bogus\n");
+            }
+            input.push_str(&format!("deposit,{},{},1.0\n", tx % 7, tx));
+        }
+
+        let mut orders = Vec::new();
+        for parse_workers in [1, 4] {
+            let (worker, mut rx) = mpsc::channel(8192);
+            let (ingress, dispatcher) = Ingress::start(
+                ShardedEngine::new(vec![worker; NUM_WORKERS]),
+                None,
+                Enrichers::default(),
+            );
+            let source = ingress.source("file");
+            let options = ReaderOptions {
+                parse_workers,
+                ..Default::default()
+            };
+            let metadata = ingest_bytes("input", input.clone().into_bytes(), options, &source)
+                .await
+                .unwrap();
+            source.finish().await;
+            assert_eq!(metadata.rows, 5000);
+            assert_eq!(metadata.header.as_deref(), Some("Type, Client, TX, Amount"));
+            assert_eq!(ingress.parse_errors(), 8);
+            drop(ingress);
+            dispatcher.await.unwrap();
+
+            let mut order = Vec::new();
+            while let Ok(ProcessorMessage::ProcessTransaction(transaction)) = rx.try_recv() {
+                order.push(transaction.id().to_string().parse::<u32>().unwrap());
+            }
+            orders.push(order);
+        }
+        assert_eq!(orders[0].len(), 5000);
+        assert_eq!(orders[0], orders[1]);
+    }
+
+    #[test]
+    fn should_pace_transactions_to_the_rate() {
+        let start = Instant::now();
+        let mut pacer = RateLimiter::new(NonZeroU32::new(100).unwrap(), start);
+
+        // A tenth of a second of transactions goes through at once, the next one waits for its token.
+        for _ in 0..10 {
+            assert_eq!(pacer.take(start), Duration::ZERO);
+        }
+        assert_eq!(pacer.take(start), Duration::from_millis(10));
+        // The waits that are not taken are owed.
+        assert_eq!(pacer.take(start), Duration::from_millis(20));
+        // Idle time refills the bucket, up to the burst.
+        let later = start + Duration::from_secs(5);
+        for _ in 0..10 {
+            assert_eq!(pacer.take(later), Duration::ZERO);
+        }
+        assert!(pacer.take(later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn should_expand_patterns_in_the_order_of_the_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "branch-b-2024-03-02.csv",
+            "branch-a-2024-03-01.csv",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let first = dir.path().join("first.csv");
+        let pattern = dir.path().join("branch-*.csv");
+
+        assert_eq!(
+            expand_inputs(&[first.clone(), pattern]).unwrap(),
+            vec![
+                first,
+                dir.path().join("branch-a-2024-03-01.csv"),
+                dir.path().join("branch-b-2024-03-02.csv"),
+            ]
+        );
+        assert!(matches!(
+            expand_inputs(&[dir.path().join("*.json")]),
+            Err(InputPatternError::NoMatch(_))
+        ));
+    }
+}
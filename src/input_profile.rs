@@ -0,0 +1,331 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    mem::size_of,
+    path::Path,
+};
+
+use encoding_rs::Encoding;
+
+use crate::{
+    account::{Account, CACHE_CAPACITY, FundingLogEntry},
+    clock::SystemClock,
+    csv_reader::{Compression, CsvFileReader, ReaderError},
+    engine::{Partitioner, Shard},
+    pipeline::Parser,
+    transaction_types::{AccountName, Amount, ClientId, TransactionId, TransactionType},
+};
+
+// A profile of an input file for sizing the engine before a real run: how many clients share the workers, what the
+// transactions are, how often they are disputed and how much memory and disk their transaction logs will take. Only a
+// sample from the start of the file is read, and the counts are extrapolated to the whole file from the share of the
+// file the sample covers. The estimates assume the clients of the sample are all the clients of the file and that they
+// keep the same share of the transactions, so they are a starting point for the sizing rather than a measure.
+
+// The LRU cache of an account keeps two links and a slot of its hash map for every entry on top of the entry itself.
+const CACHE_ENTRY_OVERHEAD: usize = 3 * size_of::<usize>();
+// Bytes of an SQLite row on top of its key and value, and of an empty transaction store.
+const STORE_ROW_OVERHEAD: u64 = 16;
+const STORE_FILE_SIZE: u64 = 8 * 1024;
+
+/// What the profile of an input is computed with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProfileConfig {
+    pub(crate) workers: usize,
+    pub(crate) partitioner: Partitioner,
+    /// The dispute window of the run, which bounds the transaction logs of the accounts.
+    pub(crate) dispute_window: Option<usize>,
+}
+
+/// The profile of a sample of an input file.
+#[derive(Debug, Default)]
+pub(crate) struct InputProfile {
+    /// Records of the sample, including the ones that could not be parsed.
+    pub(crate) records: u64,
+    pub(crate) parse_errors: u64,
+    /// The estimated number of records of the whole file.
+    pub(crate) estimated_records: u64,
+    pub(crate) clients: usize,
+    /// Share of the transactions of the sample of the client with the most of them, in percent.
+    pub(crate) busiest_client: f64,
+    pub(crate) workers: usize,
+    /// Share of the transactions of the sample of the worker with the most of them, in percent.
+    pub(crate) busiest_worker: f64,
+    pub(crate) by_type: BTreeMap<TransactionType, u64>,
+    /// Disputes per deposit or withdrawal, in percent.
+    pub(crate) dispute_rate: f64,
+    /// Chargebacks per dispute, in percent.
+    pub(crate) chargeback_rate: f64,
+    /// The amounts of the deposits and withdrawals, in ascending order.
+    amounts: Vec<Amount>,
+    pub(crate) accounts: usize,
+    /// Accounts whose transaction log is estimated to outgrow the cache.
+    pub(crate) spilling_accounts: usize,
+    pub(crate) memory_bytes: u64,
+    pub(crate) disk_bytes: u64,
+}
+
+impl InputProfile {
+    /// The amount below which `percent` percent of the amounts are, if the sample has any.
+    pub(crate) fn amount_percentile(&self, percent: usize) -> Option<Amount> {
+        let last = self.amounts.len().checked_sub(1)?;
+        self.amounts.get(last * percent / 100).copied()
+    }
+}
+
+impl Display for InputProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Sampled {} of about {} records, {} could not be parsed.",
+            self.records, self.estimated_records, self.parse_errors
+        )?;
+        writeln!(
+            f,
+            "Clients: {}, the busiest has {:.1}% of the transactions.",
+            self.clients, self.busiest_client
+        )?;
+        writeln!(
+            f,
+            "Workers: {}, the busiest gets {:.1}% of the transactions.",
+            self.workers, self.busiest_worker
+        )?;
+        let transactions: u64 = self.by_type.values().sum();
+        writeln!(f, "Transaction types:")?;
+        for (transaction_type, count) in &self.by_type {
+            writeln!(
+                f,
+                "  {:<15} {:>10}  {:>5.1}%",
+                transaction_type.to_string(),
+                count,
+                percent(*count, transactions)
+            )?;
+        }
+        writeln!(
+            f,
+            "Disputes: {:.2}% of the deposits and withdrawals, {:.1}% of them charged back.",
+            self.dispute_rate, self.chargeback_rate
+        )?;
+        match (
+            self.amount_percentile(0),
+            self.amount_percentile(50),
+            self.amount_percentile(90),
+            self.amount_percentile(99),
+            self.amount_percentile(100),
+        ) {
+            (Some(min), Some(median), Some(p90), Some(p99), Some(max)) => writeln!(
+                f,
+                "Amounts of deposits and withdrawals: min {}, median {}, p90 {}, p99 {}, max {}.",
+                min, median, p90, p99, max
+            )?,
+            _ => writeln!(f, "No deposits or withdrawals were sampled.")?,
+        }
+        writeln!(f, "Estimated for the whole file:")?;
+        writeln!(
+            f,
+            "  memory of the accounts: {} for {} accounts, at most {} transactions each in memory",
+            mebibytes(self.memory_bytes),
+            self.accounts,
+            CACHE_CAPACITY
+        )?;
+        write!(
+            f,
+            "  transaction stores: {} on disk, {} accounts outgrow the cache",
+            mebibytes(self.disk_bytes),
+            self.spilling_accounts
+        )
+    }
+}
+
+/// Profile the first `sample` records of an input file.
+pub(crate) fn profile<P: AsRef<Path>>(
+    path: P,
+    encoding: Option<&'static Encoding>,
+    compression: Compression,
+    lenient_amounts: bool,
+    sample: usize,
+    config: ProfileConfig,
+) -> Result<InputProfile, ReaderError> {
+    let mut reader = CsvFileReader::from_path_with_compression(path, encoding, compression)?
+        .with_lenient_amounts(lenient_amounts);
+    let mut profile = InputProfile {
+        workers: config.workers,
+        ..Default::default()
+    };
+    let mut clients: HashMap<ClientId, u64> = HashMap::new();
+    let mut workers: HashMap<usize, u64> = HashMap::new();
+    // Transactions that go to the log of each account.
+    let mut logged: HashMap<(ClientId, AccountName), u64> = HashMap::new();
+    let mut accounts: HashSet<(ClientId, AccountName)> = HashSet::new();
+    for transaction in reader.transactions().take(sample) {
+        profile.records += 1;
+        let Ok(transaction) = transaction else {
+            profile.parse_errors += 1;
+            continue;
+        };
+        let transaction_type = transaction.transaction_type();
+        *profile.by_type.entry(transaction_type).or_default() += 1;
+        *clients.entry(transaction.client()).or_default() += 1;
+        *workers
+            .entry(Shard::of(transaction.client(), config.workers, config.partitioner).index())
+            .or_default() += 1;
+        let account = (transaction.client(), transaction.account().clone());
+        if transaction_type.is_funding() {
+            *logged.entry(account.clone()).or_default() += 1;
+        }
+        accounts.insert(account);
+        if matches!(
+            transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && let Some(amount) = transaction.amount()
+        {
+            profile.amounts.push(amount);
+        }
+    }
+    profile.amounts.sort();
+
+    // The share of the file the sample covers, from the bytes it was read from.
+    let read = reader.bytes_read().max(1);
+    let size = reader.metadata().size.max(read);
+    let scale = size as f64 / read as f64;
+    profile.estimated_records = (profile.records as f64 * scale).round() as u64;
+
+    let transactions = profile.records - profile.parse_errors;
+    profile.clients = clients.len();
+    profile.busiest_client = percent(
+        clients.values().copied().max().unwrap_or_default(),
+        transactions,
+    );
+    profile.busiest_worker = percent(
+        workers.values().copied().max().unwrap_or_default(),
+        transactions,
+    );
+    let count = |transaction_type: TransactionType| {
+        profile
+            .by_type
+            .get(&transaction_type)
+            .copied()
+            .unwrap_or_default()
+    };
+    let disputes = count(TransactionType::Dispute);
+    profile.dispute_rate = percent(
+        disputes,
+        count(TransactionType::Deposit) + count(TransactionType::Withdrawal),
+    );
+    profile.chargeback_rate = percent(count(TransactionType::Chargeback), disputes);
+
+    // A compacted log keeps between one and two dispute windows of transactions.
+    let log_limit = config
+        .dispute_window
+        .map_or(u64::MAX, |window| 2 * window as u64);
+    let entry_memory =
+        (size_of::<(TransactionId, FundingLogEntry)>() + CACHE_ENTRY_OVERHEAD) as u64;
+    let entry_disk = stored_entry_size(profile.amount_percentile(50).unwrap_or_default());
+    profile.accounts = accounts.len();
+    profile.memory_bytes = accounts.len() as u64 * size_of::<Account>() as u64;
+    profile.disk_bytes = accounts.len() as u64 * STORE_FILE_SIZE;
+    for transactions in logged.values() {
+        let transactions = ((*transactions as f64 * scale).round() as u64).min(log_limit);
+        let in_memory = transactions.min(CACHE_CAPACITY as u64);
+        profile.memory_bytes += in_memory * entry_memory;
+        if transactions > in_memory {
+            profile.spilling_accounts += 1;
+            profile.disk_bytes += (transactions - in_memory) * entry_disk;
+        }
+    }
+    Ok(profile)
+}
+
+// Bytes of a logged transaction in the transaction store, encoded the way the cache encodes it.
+fn stored_entry_size(amount: Amount) -> u64 {
+    let entry =
+        FundingLogEntry::new_deposit(amount).stamped(u32::MAX.into(), SystemClock::shared().now());
+    let key = TransactionId::from(u32::MAX);
+    let config = bincode::config::standard();
+    let encoded = bincode::serde::encode_to_vec(&entry, config).map_or(0, |entry| entry.len())
+        + bincode::serde::encode_to_vec(key, config).map_or(0, |key| key.len());
+    encoded as u64 + STORE_ROW_OVERHEAD
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+fn mebibytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn should_profile_the_sample_and_extrapolate_to_the_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "type,client,tx,amount").unwrap();
+        for tx in 0..300 {
+            writeln!(file, "deposit,{},{},{}.5", tx % 3, tx, tx % 10).unwrap();
+        }
+        writeln!(file, "dispute,1,1,").unwrap();
+        writeln!(file, "chargeback,1,1,").unwrap();
+        writeln!(file, "deposit,2,oops,1").unwrap();
+        file.flush().unwrap();
+        let config = ProfileConfig {
+            workers: 4,
+            partitioner: Partitioner::Hash,
+            dispute_window: None,
+        };
+
+        let whole = profile(file.path(), None, Compression::Auto, false, 1000, config).unwrap();
+        assert_eq!((whole.records, whole.estimated_records), (303, 303));
+        assert_eq!(whole.parse_errors, 1);
+        assert_eq!(whole.clients, 3);
+        assert_eq!(whole.by_type[&TransactionType::Deposit], 300);
+        assert!((whole.dispute_rate - 100.0 / 300.0).abs() < 1e-9);
+        assert_eq!(whole.chargeback_rate, 100.0);
+        assert_eq!(whole.amount_percentile(0), Some(0.5.into()));
+        assert_eq!(whole.amount_percentile(100), Some(9.5.into()));
+        assert_eq!(whole.spilling_accounts, 0);
+
+        let sample = profile(file.path(), None, Compression::Auto, false, 150, config).unwrap();
+        assert_eq!(sample.records, 150);
+        assert!((280..=320).contains(&sample.estimated_records));
+        assert_eq!(sample.accounts, 3);
+    }
+
+    #[test]
+    fn should_count_the_transactions_beyond_the_cache_as_on_disk() {
+        let mut file = NamedTempFile::new().unwrap();
+        for tx in 0..(CACHE_CAPACITY + 10) {
+            writeln!(file, "deposit,1,{},1", tx).unwrap();
+        }
+        file.flush().unwrap();
+
+        let config = ProfileConfig {
+            workers: 1,
+            partitioner: Partitioner::Hash,
+            dispute_window: None,
+        };
+        let spilled = profile(file.path(), None, Compression::Auto, false, 1000, config).unwrap();
+        assert_eq!(spilled.spilling_accounts, 1);
+        assert_eq!(spilled.busiest_worker, 100.0);
+        assert!(spilled.disk_bytes > STORE_FILE_SIZE);
+
+        let compacted = ProfileConfig {
+            dispute_window: Some(10),
+            ..config
+        };
+        let compacted =
+            profile(file.path(), None, Compression::Auto, false, 1000, compacted).unwrap();
+        assert_eq!(compacted.spilling_accounts, 0);
+        assert_eq!(compacted.disk_bytes, STORE_FILE_SIZE);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{cell::Cell, sync::OnceLock};
+
+use axum::{
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use clap::ValueEnum;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::transaction_types::AmountFormat;
+
+// JSON encoding of the API responses. Amounts are written as strings in the CSV files, but JSON consumers disagree
+// about strings and numbers for money, so the amount serde layer checks whether it's running inside one of the
+// functions below and then follows `--json-amounts` (see `JsonAmounts`). Amounts are accepted both as strings and as
+// numbers when decoding JSON, whatever the style.
+
+/// How amounts are written in JSON. Either way the amount is exact: numbers are written with all their digits rather
+/// than going through a float.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum JsonAmounts {
+    /// `"amount": "1.5"`
+    #[default]
+    String,
+    /// `"amount": 1.5`
+    Number,
+}
+
+// The JSON amount style is a process wide setting, like the amount format.
+static JSON_AMOUNTS: OnceLock<JsonAmounts> = OnceLock::new();
+
+impl JsonAmounts {
+    /// Set the style used when writing amounts in JSON. Can only be set once, at startup.
+    pub(crate) fn set_global(amounts: JsonAmounts) -> Result<(), JsonAmounts> {
+        JSON_AMOUNTS.set(amounts)
+    }
+
+    fn global() -> JsonAmounts {
+        JSON_AMOUNTS.get().copied().unwrap_or_default()
+    }
+}
+
+thread_local! {
+    static AMOUNTS: Cell<Option<JsonAmounts>> = const { Cell::new(None) };
+}
+
+/// The style of the amounts if the current thread is encoding or decoding JSON.
+pub(crate) fn amounts() -> Option<JsonAmounts> {
+    AMOUNTS.with(Cell::get)
+}
+
+// Run a serde call in JSON mode, restoring the previous mode after (calls can be nested).
+fn scoped<T>(amounts: JsonAmounts, call: impl FnOnce() -> T) -> T {
+    let outer = AMOUNTS.with(|flag| flag.replace(Some(amounts)));
+    let result = call();
+    AMOUNTS.with(|flag| flag.set(outer));
+    result
+}
+
+pub(crate) fn to_vec<T: Serialize>(value: &T) -> serde_json::Result<Vec<u8>> {
+    AmountFormat::output(|| scoped(JsonAmounts::global(), || serde_json::to_vec(value)))
+}
+
+/// Decode JSON. Amounts are accepted both as strings and as numbers.
+pub(crate) fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> serde_json::Result<T> {
+    scoped(JsonAmounts::global(), || serde_json::from_slice(bytes))
+}
+
+pub(crate) fn to_string<T: Serialize>(value: &T) -> serde_json::Result<String> {
+    AmountFormat::output(|| scoped(JsonAmounts::global(), || serde_json::to_string(value)))
+}
+
+/// A JSON response of the API. Same as `axum::Json`, but amounts are written according to `--json-amounts`.
+pub(crate) struct Json<T>(pub(crate) T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match to_vec(&self.0) {
+            Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_types::Amount;
+
+    use super::*;
+
+    #[test]
+    fn should_write_amounts_as_strings_or_exact_numbers() {
+        let amounts: Vec<Amount> = [
+            "1.5",
+            "0.1",
+            "79228162514264.3375",
+            "12345678901234567.8901",
+        ]
+        .iter()
+        .map(|amount| amount.parse().unwrap())
+        .collect();
+
+        assert_eq!(
+            scoped(JsonAmounts::String, || serde_json::to_string(&amounts)).unwrap(),
+            r#"["1.5","0.1","79228162514264.3375","12345678901234567.8901"]"#
+        );
+        // A float would turn these into 0.1000000000000000055... and 12345678901234568.
+        assert_eq!(
+            scoped(JsonAmounts::Number, || serde_json::to_string(&amounts)).unwrap(),
+            "[1.5,0.1,79228162514264.3375,12345678901234567.8901]"
+        );
+    }
+
+    #[test]
+    fn should_read_amounts_from_strings_and_numbers() {
+        let read = |text: &str| {
+            scoped(JsonAmounts::String, || {
+                serde_json::from_str::<Vec<Amount>>(text)
+            })
+        };
+
+        assert_eq!(
+            read(r#"["12345678901234567.8901", 12345678901234567.8901, 0.00019]"#).unwrap(),
+            vec![
+                "12345678901234567.8901".parse::<Amount>().unwrap(),
+                "12345678901234567.8901".parse().unwrap(),
+                "0.0001".parse().unwrap()
+            ]
+        );
+        assert!(read("[-1]").is_err());
+        assert!(read("[1e3]").is_err());
+        assert!(read("[true]").is_err());
+    }
+}
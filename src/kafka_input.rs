@@ -0,0 +1,295 @@
+use std::{collections::VecDeque, str::FromStr};
+
+use thiserror::Error;
+
+#[cfg(feature = "rdkafka")]
+use rdkafka::{
+    ClientConfig, Message, Offset, TopicPartitionList,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+};
+
+use crate::ingest::{IngressClosed, SourceHandle};
+#[cfg(feature = "rdkafka")]
+use crate::{
+    json,
+    logging::{RecordLog, log_event},
+    transaction_types::Transaction,
+};
+
+// Input from a Kafka topic, for running the engine as a service that consumes the transactions as they are published
+// rather than as a batch job over a file. Every message is a transaction encoded as a JSON object with the fields of
+// the CSV input (`{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`). The messages go to the workers through
+// the same fan-in as the other sources, until the engine is stopped with Ctrl-C.
+//
+// The offsets are committed by hand, never before the dispatcher handed the transaction of the message over to its
+// worker: a message that was only received when the engine stops is consumed again by the next run. The delivery is
+// at least once, so a transaction can be applied twice after a crash, and the duplicate checks of the accounts or
+// `--id-collision-policy` are what keeps it from being counted twice.
+
+// Consumer group of the engine, unless set with the `group` parameter.
+const DEFAULT_GROUP: &str = "payments-engine";
+// How often the offsets of the accepted messages are committed while consuming.
+#[cfg(feature = "rdkafka")]
+const COMMIT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub(crate) enum KafkaInputError {
+    #[error(
+        "'{0}' is not a Kafka input (e.g. kafka://broker1:9092,broker2:9092/transactions?group=engine)."
+    )]
+    InvalidInput(String),
+    #[cfg(feature = "rdkafka")]
+    #[error("Cannot consume the transactions topic: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    #[cfg(not(feature = "rdkafka"))]
+    #[error("Kafka input is not supported by this build. Build with the rdkafka feature.")]
+    KafkaUnsupported,
+    #[error(transparent)]
+    Closed(#[from] IngressClosed),
+}
+
+/// A topic of transactions, written as `kafka://<BROKERS>/<TOPIC>[?group=<GROUP>]`, the brokers being separated by
+/// commas. Other parameters are passed to the consumer as librdkafka properties (e.g. `security.protocol=ssl`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KafkaInput {
+    brokers: String,
+    topic: String,
+    /// The consumer group whose offsets are committed. Defaults to `payments-engine`.
+    group: String,
+    properties: Vec<(String, String)>,
+}
+
+impl FromStr for KafkaInput {
+    type Err = KafkaInputError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || KafkaInputError::InvalidInput(value.to_string());
+        let rest = value.strip_prefix("kafka://").ok_or_else(invalid)?;
+        let (address, parameters) = rest.split_once('?').unwrap_or((rest, ""));
+        let (brokers, topic) = address.split_once('/').ok_or_else(invalid)?;
+        if brokers.split(',').any(str::is_empty) || topic.is_empty() || topic.contains('/') {
+            return Err(invalid());
+        }
+
+        let mut group = DEFAULT_GROUP.to_string();
+        let mut properties = Vec::new();
+        for parameter in parameters
+            .split('&')
+            .filter(|parameter| !parameter.is_empty())
+        {
+            match parameter.split_once('=') {
+                Some(("group", name)) if !name.is_empty() => group = name.to_string(),
+                Some((key, value)) if key != "group" => {
+                    properties.push((key.to_string(), value.to_string()))
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Self {
+            brokers: brokers.to_string(),
+            topic: topic.to_string(),
+            group,
+            properties,
+        })
+    }
+}
+
+impl KafkaInput {
+    /// Check that the input can be read by this build, without connecting to the brokers.
+    pub(crate) fn check(&self) -> Result<(), KafkaInputError> {
+        #[cfg(not(feature = "rdkafka"))]
+        return Err(KafkaInputError::KafkaUnsupported);
+        #[cfg(feature = "rdkafka")]
+        Ok(())
+    }
+}
+
+// The offsets of the messages that were received but whose transaction may still be waiting in the queue of the
+// source. A message is kept with the number of transactions queued on the source up to and including it, and can be
+// committed once the source accepted that many. A message that could not be parsed queues nothing, so it's committed
+// with the message before it.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "rdkafka"), allow(dead_code))]
+struct PendingOffsets {
+    messages: VecDeque<(u64, i32, i64)>,
+}
+
+#[cfg_attr(not(feature = "rdkafka"), allow(dead_code))]
+impl PendingOffsets {
+    fn received(&mut self, queued: u64, partition: i32, offset: i64) {
+        self.messages.push_back((queued, partition, offset));
+    }
+
+    // Remove the messages whose transactions were accepted and return the offsets to commit: for every partition, the
+    // offset after its last accepted message.
+    fn accepted(&mut self, accepted: u64) -> Vec<(i32, i64)> {
+        let mut next: Vec<(i32, i64)> = Vec::new();
+        while let Some(&(queued, partition, offset)) = self.messages.front()
+            && queued <= accepted
+        {
+            self.messages.pop_front();
+            match next.iter_mut().find(|(other, _)| *other == partition) {
+                Some((_, next)) => *next = offset + 1,
+                None => next.push((partition, offset + 1)),
+            }
+        }
+        next
+    }
+}
+
+/// Consume the transactions of a topic and queue them on a source until `stop` completes, committing the offsets of
+/// the messages that were handed over to the workers. Closes the source. Returns the number of messages consumed.
+#[cfg(feature = "rdkafka")]
+pub(crate) async fn ingest_topic(
+    input: &KafkaInput,
+    source: SourceHandle,
+    stop: impl Future<Output = ()>,
+) -> Result<u64, KafkaInputError> {
+    let mut config = ClientConfig::new();
+    config
+        .set("bootstrap.servers", &input.brokers)
+        .set("group.id", &input.group)
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest");
+    for (key, value) in &input.properties {
+        config.set(key, value);
+    }
+    let consumer: StreamConsumer = config.create()?;
+    consumer.subscribe(&[&input.topic])?;
+
+    let commit = |offsets: Vec<(i32, i64)>, mode: CommitMode| -> Result<(), KafkaInputError> {
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let mut list = TopicPartitionList::new();
+        for (partition, offset) in offsets {
+            list.add_partition_offset(&input.topic, partition, Offset::Offset(offset))?;
+        }
+        consumer.commit(&list, mode)?;
+        Ok(())
+    };
+
+    let mut log = RecordLog::new();
+    let mut pending = PendingOffsets::default();
+    let (mut messages, mut queued) = (0u64, 0u64);
+    let mut ticker = tokio::time::interval(COMMIT_INTERVAL);
+    let mut stop = std::pin::pin!(stop);
+    loop {
+        tokio::select! {
+            received = consumer.recv() => {
+                // The client reconnects by itself, the errors are only reported.
+                let message = match received {
+                    Ok(message) => message,
+                    Err(err) => {
+                        eprintln!("Error consuming the transactions topic: {}", err);
+                        continue;
+                    }
+                };
+                messages += 1;
+                match json::from_slice::<Transaction>(message.payload().unwrap_or_default()) {
+                    Ok(transaction) => {
+                        source.send(transaction).await?;
+                        queued += 1;
+                    }
+                    Err(err) => {
+                        if log.should_log(&err) {
+                            eprintln!(
+                                "Error reading message {} of partition {}: {}",
+                                message.offset(),
+                                message.partition(),
+                                err
+                            );
+                        }
+                        source.parse_error();
+                    }
+                }
+                pending.received(queued, message.partition(), message.offset());
+            }
+            _ = ticker.tick() => {
+                // A commit that fails, e.g. during a rebalance, is retried with the next one.
+                if let Err(err) = commit(pending.accepted(source.accepted()), CommitMode::Async) {
+                    eprintln!("{}", err);
+                }
+            }
+            () = &mut stop => break,
+        }
+    }
+
+    // Every transaction that was queued is handed over to the workers before the last commit.
+    let stats = source.finish().await;
+    commit(
+        pending.accepted(stats.dispatched + stats.forwarded),
+        CommitMode::Sync,
+    )?;
+    log_event(
+        "topic_consumed",
+        &[
+            ("topic", &input.topic),
+            ("group", &input.group),
+            ("messages", &messages),
+            ("uncommitted", &pending.messages.len()),
+        ],
+    );
+    Ok(messages)
+}
+
+/// Consume the transactions of a topic. Not supported by this build.
+#[cfg(not(feature = "rdkafka"))]
+pub(crate) async fn ingest_topic(
+    _input: &KafkaInput,
+    source: SourceHandle,
+    _stop: impl Future<Output = ()>,
+) -> Result<u64, KafkaInputError> {
+    source.finish().await;
+    Err(KafkaInputError::KafkaUnsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_kafka_inputs() {
+        let input: KafkaInput =
+            "kafka://broker1:9092,broker2:9092/transactions?group=engine&security.protocol=ssl"
+                .parse()
+                .unwrap();
+        assert_eq!(input.brokers, "broker1:9092,broker2:9092");
+        assert_eq!(
+            (input.topic.as_str(), input.group.as_str()),
+            ("transactions", "engine")
+        );
+        assert_eq!(
+            input.properties,
+            vec![("security.protocol".to_string(), "ssl".to_string())]
+        );
+
+        let input: KafkaInput = "kafka://localhost:9092/transactions".parse().unwrap();
+        assert_eq!(input.group, DEFAULT_GROUP);
+
+        assert!("kafka://localhost:9092".parse::<KafkaInput>().is_err());
+        assert!("kafka://localhost:9092,/tx".parse::<KafkaInput>().is_err());
+        assert!(
+            "kafka://localhost:9092/tx?group="
+                .parse::<KafkaInput>()
+                .is_err()
+        );
+        assert!("localhost:9092/tx".parse::<KafkaInput>().is_err());
+    }
+
+    #[test]
+    fn should_only_commit_the_offsets_of_accepted_messages() {
+        let mut pending = PendingOffsets::default();
+        pending.received(1, 0, 10);
+        pending.received(2, 1, 4);
+        // A message that could not be parsed, committed with the one before it.
+        pending.received(2, 1, 5);
+        pending.received(3, 0, 11);
+
+        assert_eq!(pending.accepted(0), vec![]);
+        assert_eq!(pending.accepted(2), vec![(0, 11), (1, 6)]);
+        assert_eq!(pending.accepted(2), vec![]);
+        assert_eq!(pending.accepted(3), vec![(0, 12)]);
+    }
+}
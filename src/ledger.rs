@@ -0,0 +1,312 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::NaiveDate;
+use clap::ValueEnum;
+
+use crate::{
+    events::{AppliedEvent, EventSink},
+    provenance,
+    transaction_types::{Amount, ClientId, EscrowParty, TransactionType},
+};
+
+// The account that represents the money coming in and going out of the engine.
+const SETTLEMENT_ACCOUNT: &str = "Assets:Settlement";
+
+/// Plain text accounting syntax of the ledger export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum LedgerFormat {
+    #[default]
+    Beancount,
+    Ledger,
+}
+
+/// Writes the applied transactions as double entry accounting records.
+/// Client funds are liabilities of the engine: each client has an available and a held account.
+pub(crate) struct LedgerWriter<W: Write> {
+    writer: W,
+    format: LedgerFormat,
+    date: NaiveDate,
+    commodity: String,
+    // Accounts that were already opened. Beancount requires an `open` directive before an account is used.
+    opened: HashSet<String>,
+}
+
+impl LedgerWriter<BufWriter<File>> {
+    pub(crate) fn create<P: AsRef<Path>>(
+        path: P,
+        format: LedgerFormat,
+        date: NaiveDate,
+        commodity: String,
+    ) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        provenance::write_comment(&mut writer, ";")?;
+        Ok(Self::new(writer, format, date, commodity))
+    }
+}
+
+impl<W: Write> LedgerWriter<W> {
+    pub(crate) fn new(writer: W, format: LedgerFormat, date: NaiveDate, commodity: String) -> Self {
+        Self {
+            writer,
+            format,
+            date,
+            commodity,
+            opened: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // Write a single entry with one posting pair: one account is debited and the other one is credited.
+    // The entry is tagged with the accounting period of the transaction.
+    // Client funds are not split by sub-account, so moves between the sub-accounts of a client are not written.
+    // The funds of a disputed withdrawal come back from the settlement account and are held until the dispute closes.
+    pub(crate) fn write_event(&mut self, event: &AppliedEvent) -> io::Result<()> {
+        let client = event.account.client;
+        let withdrawal = event.disputed == Some(TransactionType::Withdrawal);
+        let (debit, credit) = match event.transaction_type {
+            TransactionType::Dispute if withdrawal => {
+                (SETTLEMENT_ACCOUNT.to_string(), held(client))
+            }
+            TransactionType::Resolve if withdrawal => {
+                (held(client), SETTLEMENT_ACCOUNT.to_string())
+            }
+            TransactionType::Chargeback if withdrawal => (held(client), available(client)),
+            TransactionType::ChargebackReversal if withdrawal => {
+                (available(client), SETTLEMENT_ACCOUNT.to_string())
+            }
+            TransactionType::Deposit => (SETTLEMENT_ACCOUNT.to_string(), available(client)),
+            TransactionType::Withdrawal => (available(client), SETTLEMENT_ACCOUNT.to_string()),
+            TransactionType::Dispute => (available(client), held(client)),
+            TransactionType::Resolve => (held(client), available(client)),
+            TransactionType::Chargeback => (held(client), SETTLEMENT_ACCOUNT.to_string()),
+            TransactionType::ChargebackReversal => {
+                (SETTLEMENT_ACCOUNT.to_string(), available(client))
+            }
+            TransactionType::Move => return Ok(()),
+            TransactionType::EscrowHold => (available(client), escrow(client)),
+            TransactionType::EscrowRelease => match event.release_to {
+                Some(EscrowParty::Client) => (escrow(client), available(client)),
+                Some(EscrowParty::Beneficiary) | None => {
+                    (escrow(client), SETTLEMENT_ACCOUNT.to_string())
+                }
+            },
+        };
+
+        let narration = format!(
+            "{:?} of client {} tx {}",
+            event.transaction_type, client, event.transaction_id
+        );
+        match self.format {
+            LedgerFormat::Beancount => {
+                for account in [&debit, &credit] {
+                    if self.opened.insert(account.clone()) {
+                        writeln!(self.writer, "{} open {}", self.date, account)?;
+                    }
+                }
+                writeln!(self.writer, "{} * \"{}\"", self.date, narration)?;
+                writeln!(self.writer, "  period: {}", event.period)?;
+            }
+            LedgerFormat::Ledger => {
+                writeln!(
+                    self.writer,
+                    "{} * {}",
+                    self.date.format("%Y/%m/%d"),
+                    narration
+                )?;
+                writeln!(self.writer, "  ; period: {}", event.period)?;
+            }
+        }
+        self.write_posting(&debit, event.amount, false)?;
+        self.write_posting(&credit, event.amount, true)?;
+        writeln!(self.writer)
+    }
+
+    fn write_posting(&mut self, account: &str, amount: Amount, negative: bool) -> io::Result<()> {
+        let sign = if negative { "-" } else { "" };
+        writeln!(
+            self.writer,
+            "  {}  {}{} {}",
+            account, sign, amount, self.commodity
+        )
+    }
+}
+
+fn available(client: ClientId) -> String {
+    format!("Liabilities:Clients:Client{}:Available", client)
+}
+
+fn held(client: ClientId) -> String {
+    format!("Liabilities:Clients:Client{}:Held", client)
+}
+
+fn escrow(client: ClientId) -> String {
+    format!("Liabilities:Clients:Client{}:Escrow", client)
+}
+
+/// An event sink that writes to a ledger file shared between the workers.
+pub(crate) struct LedgerSink<W: Write> {
+    writer: Arc<Mutex<LedgerWriter<W>>>,
+}
+
+impl<W: Write> LedgerSink<W> {
+    pub(crate) fn new(writer: Arc<Mutex<LedgerWriter<W>>>) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write + Send> EventSink for LedgerSink<W> {
+    fn publish(&mut self, event: &AppliedEvent) {
+        let mut writer = self.writer.lock().expect("Ledger lock is never poisoned.");
+        if let Err(err) = writer.write_event(event) {
+            eprintln!(
+                "Cannot write transaction {} to the ledger: {}",
+                event.transaction_id, err
+            );
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .expect("Ledger lock is never poisoned.")
+            .flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use crate::account::AccountSnapshot;
+
+    use super::*;
+
+    fn event(transaction_type: TransactionType, amount: f64) -> AppliedEvent {
+        AppliedEvent {
+            transaction_type,
+            transaction_id: 7.into(),
+            amount: amount.into(),
+            release_to: None,
+            disputed: None,
+            worker: Default::default(),
+            account: AccountSnapshot {
+                client: 1.into(),
+                account: Default::default(),
+                available: Amount::zero(),
+                held: Amount::zero(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: Amount::zero(),
+                locked: false,
+            },
+            period: 1,
+            applied_at: DateTime::UNIX_EPOCH,
+        }
+    }
+
+    fn render(format: LedgerFormat, events: &[AppliedEvent]) -> String {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let mut writer = LedgerWriter::new(Vec::new(), format, date, "USD".to_string());
+        for event in events {
+            writer.write_event(event).unwrap();
+        }
+        String::from_utf8(writer.writer).unwrap()
+    }
+
+    #[test]
+    fn should_write_beancount_entries_with_open_directives() {
+        let output = render(
+            LedgerFormat::Beancount,
+            &[
+                event(TransactionType::Deposit, 10.5),
+                event(TransactionType::Dispute, 10.5),
+            ],
+        );
+
+        assert_eq!(
+            output,
+            "2024-03-01 open Assets:Settlement
+2024-03-01 open Liabilities:Clients:Client1:Available
+2024-03-01 * \"Deposit of client 1 tx 7\"
+  period: 1
+  Assets:Settlement  10.5 USD
+  Liabilities:Clients:Client1:Available  -10.5 USD
+
+2024-03-01 open Liabilities:Clients:Client1:Held
+2024-03-01 * \"Dispute of client 1 tx 7\"
+  period: 1
+  Liabilities:Clients:Client1:Available  10.5 USD
+  Liabilities:Clients:Client1:Held  -10.5 USD
+
+"
+        );
+    }
+
+    #[test]
+    fn should_write_escrow_releases_to_the_released_party() {
+        let to_client = AppliedEvent {
+            release_to: Some(EscrowParty::Client),
+            ..event(TransactionType::EscrowRelease, 2.0)
+        };
+        let to_beneficiary = AppliedEvent {
+            release_to: Some(EscrowParty::Beneficiary),
+            ..event(TransactionType::EscrowRelease, 2.0)
+        };
+        let output = render(
+            LedgerFormat::Ledger,
+            &[
+                event(TransactionType::EscrowHold, 2.0),
+                to_client,
+                to_beneficiary,
+            ],
+        );
+
+        assert_eq!(
+            output,
+            "2024/03/01 * EscrowHold of client 1 tx 7
+  ; period: 1
+  Liabilities:Clients:Client1:Available  2 USD
+  Liabilities:Clients:Client1:Escrow  -2 USD
+
+2024/03/01 * EscrowRelease of client 1 tx 7
+  ; period: 1
+  Liabilities:Clients:Client1:Escrow  2 USD
+  Liabilities:Clients:Client1:Available  -2 USD
+
+2024/03/01 * EscrowRelease of client 1 tx 7
+  ; period: 1
+  Liabilities:Clients:Client1:Escrow  2 USD
+  Assets:Settlement  -2 USD
+
+"
+        );
+    }
+
+    #[test]
+    fn should_write_ledger_entries() {
+        let output = render(
+            LedgerFormat::Ledger,
+            &[event(TransactionType::Withdrawal, 3.0)],
+        );
+
+        assert_eq!(
+            output,
+            "2024/03/01 * Withdrawal of client 1 tx 7
+  ; period: 1
+  Liabilities:Clients:Client1:Available  3 USD
+  Assets:Settlement  -3 USD
+
+"
+        );
+    }
+}
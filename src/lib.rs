@@ -1 +1,75 @@
+pub mod account_notes;
+pub mod engine;
+pub mod id_allocator;
+pub mod transaction;
 pub mod transactions_cache;
+
+mod account;
+mod account_updates;
+mod amount_stats;
+mod anonymize;
+mod app;
+mod archive;
+mod backup;
+mod blocklist;
+mod bootstrap;
+mod cache_tuning;
+mod cli;
+mod client_trace;
+mod clock;
+mod cluster;
+mod cold_storage;
+mod config;
+mod csv_reader;
+mod daemon;
+mod db_input;
+mod dispute;
+mod dispute_policy;
+mod drift;
+mod enrichment;
+mod events;
+mod grpc_input;
+mod id_history;
+mod in_flight;
+mod ingest;
+mod input_profile;
+mod json;
+mod kafka_input;
+mod ledger;
+mod logging;
+mod memory;
+mod merge;
+mod metrics;
+mod monitoring;
+mod object_input;
+mod outcome;
+mod output;
+mod period;
+mod pipeline;
+mod profiling;
+mod provenance;
+mod registry;
+mod rejects;
+mod reorder;
+mod run_manifest;
+mod settlement;
+#[cfg(test)]
+mod simulation;
+mod snapshot;
+mod spans;
+mod state;
+mod summary;
+mod supervisor;
+mod tcp_input;
+mod transaction_processor;
+mod transaction_types;
+mod verify;
+mod watch_dir;
+mod ws_input;
+
+#[doc(hidden)]
+pub use crate::{app::run, memory::CountingAllocator};
+
+// Number of workers to use for processing transactions. All transactions that have the same client ID are processed by
+// the same worker (see `ShardedEngine`).
+static NUM_WORKERS: usize = 4;
@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write},
+    mem::{Discriminant, discriminant},
+    num::NonZeroU64,
+    sync::OnceLock,
+};
+
+/// How much is written to stderr about individual records. The structured events and the summary are always written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub(crate) enum Verbosity {
+    /// Nothing about individual records.
+    Quiet,
+    /// The records that were rejected or could not be parsed, sampled with `LogSettings::sample_every`.
+    #[default]
+    Normal,
+    /// Every rejected record without sampling, and every applied transaction.
+    Verbose,
+}
+
+/// The process wide logging settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LogSettings {
+    pub(crate) verbosity: Verbosity,
+    /// Only one in this many records of the same kind (e.g. rejected for the same reason) is logged.
+    pub(crate) sample_every: NonZeroU64,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            verbosity: Verbosity::default(),
+            sample_every: NonZeroU64::MIN,
+        }
+    }
+}
+
+// The logging settings are set once at startup and shared by all the stages.
+static LOG_SETTINGS: OnceLock<LogSettings> = OnceLock::new();
+
+impl LogSettings {
+    /// Set the logging settings of the process. Can only be set once, at startup.
+    pub(crate) fn set_global(settings: LogSettings) -> Result<(), LogSettings> {
+        LOG_SETTINGS.set(settings)
+    }
+
+    pub(crate) fn global() -> LogSettings {
+        LOG_SETTINGS.get().copied().unwrap_or_default()
+    }
+}
+
+/// Decides which of the records of a stage are logged, e.g. the rejected transactions.
+/// Records are grouped by kind (the variant of the error) and only one in `sample_every` records of each kind is logged,
+/// starting with the first one. The exact counts are kept by the summary, not here.
+#[derive(Debug)]
+pub(crate) struct RecordLog<K> {
+    settings: LogSettings,
+    seen: HashMap<Discriminant<K>, u64>,
+}
+
+impl<K> RecordLog<K> {
+    /// A log that follows the process wide settings.
+    pub(crate) fn new() -> Self {
+        Self::with_settings(LogSettings::global())
+    }
+
+    pub(crate) fn with_settings(settings: LogSettings) -> Self {
+        Self {
+            settings,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether this record should be logged.
+    pub(crate) fn should_log(&mut self, kind: &K) -> bool {
+        match self.settings.verbosity {
+            Verbosity::Quiet => false,
+            Verbosity::Verbose => true,
+            Verbosity::Normal => {
+                let seen = self.seen.entry(discriminant(kind)).or_default();
+                *seen += 1;
+                (*seen - 1).is_multiple_of(self.settings.sample_every.get())
+            }
+        }
+    }
+
+    /// Whether the applied records should be logged as well.
+    pub(crate) fn is_verbose(&self) -> bool {
+        self.settings.verbosity == Verbosity::Verbose
+    }
+}
+
+/// Format a structured event as a single logfmt line (e.g. `event=file_ingested path="in put.csv" rows=10`).
+/// Values that contain spaces, quotes or equal signs are quoted so that the line can be parsed by log collectors.
+pub(crate) fn format_event(event: &str, fields: &[(&str, &dyn Display)]) -> String {
+    let mut line = format!("event={}", event);
+    for (key, value) in fields {
+        let value = value.to_string();
+        if value.is_empty() || value.contains([' ', '"', '=', '\\']) {
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(line, " {}=\"{}\"", key, escaped);
+        } else {
+            let _ = write!(line, " {}={}", key, value);
+        }
+    }
+    line
+}
+
+/// Write a structured event to stderr.
+pub(crate) fn log_event(event: &str, fields: &[(&str, &dyn Display)]) {
+    eprintln!("{}", format_event(event, fields));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_sample_each_kind_separately() {
+        let mut log = RecordLog::with_settings(LogSettings {
+            verbosity: Verbosity::Normal,
+            sample_every: NonZeroU64::new(3).unwrap(),
+        });
+
+        // The records are grouped by variant, whatever the values they carry.
+        let logged: Vec<_> = (0..7).map(|i| log.should_log(&Some(i))).collect();
+        assert_eq!(logged, [true, false, false, true, false, false, true]);
+        assert!(log.should_log(&None));
+        assert!(!log.should_log(&None));
+    }
+
+    #[test]
+    fn should_follow_verbosity() {
+        let mut quiet = RecordLog::with_settings(LogSettings {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        });
+        let mut verbose = RecordLog::with_settings(LogSettings {
+            verbosity: Verbosity::Verbose,
+            sample_every: NonZeroU64::new(100).unwrap(),
+        });
+
+        assert!(!quiet.should_log(&None::<u32>));
+        assert!(verbose.should_log(&None::<u32>));
+        assert!(verbose.should_log(&None::<u32>));
+        assert!(verbose.is_verbose());
+    }
+
+    #[test]
+    fn should_format_event_as_logfmt() {
+        let line = format_event("file_ingested", &[("rows", &10), ("header", &true)]);
+
+        assert_eq!(line, "event=file_ingested rows=10 header=true");
+    }
+
+    #[test]
+    fn should_quote_values_with_special_characters() {
+        let line = format_event(
+            "header_skipped",
+            &[
+                ("path", &"my input.csv"),
+                ("header", &"a=\"b\""),
+                ("x", &""),
+            ],
+        );
+
+        assert_eq!(
+            line,
+            r#"event=header_skipped path="my input.csv" header="a=\"b\"" x="""#
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use serde::Serialize;
+
+// Memory usage of a run by phase, for capacity planning without external tooling. The allocator of the engine counts
+// the bytes that are allocated, which is cheap enough to always do, and with `--report-memory` it also keeps the peak
+// of each phase. On Linux, the peak resident set size of the process is read from `/proc/self/status` at the end of
+// each phase and reset at its start, so it's the peak of the phase too. Elsewhere, or if it can't be reset, it's left
+// out or is the peak of the run so far.
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+// The peak is only kept while a report is running, so the allocations of a run without one don't contend on it.
+static TRACK_PEAK: AtomicBool = AtomicBool::new(false);
+
+/// The system allocator, counting the allocated bytes.
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn allocated(size: usize) {
+        let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+        if TRACK_PEAK.load(Ordering::Relaxed) {
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+    }
+
+    fn freed(size: usize) {
+        ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+// Safety: the allocations are all made by the system allocator, the counters don't allocate.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        Self::freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            Self::freed(layout.size());
+            Self::allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// The memory used during a phase of a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct PhaseMemory {
+    pub(crate) phase: &'static str,
+    /// The most bytes that were allocated at once during the phase.
+    pub(crate) peak_allocated_bytes: u64,
+    /// The bytes still allocated at the end of the phase.
+    pub(crate) allocated_bytes: u64,
+    /// The peak resident set size of the process during the phase, where the system reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) peak_rss_bytes: Option<u64>,
+}
+
+/// The memory used by the phases of a run, one after the other.
+#[derive(Debug)]
+pub(crate) struct MemoryReport {
+    phases: Vec<PhaseMemory>,
+    current: &'static str,
+}
+
+impl MemoryReport {
+    /// Start keeping the peaks, beginning with the given phase.
+    pub(crate) fn start(phase: &'static str) -> Self {
+        TRACK_PEAK.store(true, Ordering::Relaxed);
+        begin_phase();
+        Self {
+            phases: Vec::new(),
+            current: phase,
+        }
+    }
+
+    /// End the current phase and begin the next one.
+    pub(crate) fn next_phase(&mut self, phase: &'static str) {
+        self.end_phase();
+        begin_phase();
+        self.current = phase;
+    }
+
+    /// End the last phase and stop keeping the peaks.
+    pub(crate) fn finish(mut self) -> Vec<PhaseMemory> {
+        self.end_phase();
+        TRACK_PEAK.store(false, Ordering::Relaxed);
+        self.phases
+    }
+
+    fn end_phase(&mut self) {
+        self.phases.push(PhaseMemory {
+            phase: self.current,
+            peak_allocated_bytes: PEAK.load(Ordering::Relaxed) as u64,
+            allocated_bytes: ALLOCATED.load(Ordering::Relaxed) as u64,
+            peak_rss_bytes: peak_rss(),
+        });
+    }
+}
+
+fn begin_phase() {
+    PEAK.store(ALLOCATED.load(Ordering::Relaxed), Ordering::Relaxed);
+    // Writing 5 resets the peak resident set size to the current one. It's fine if it's not supported.
+    let _ = fs::write("/proc/self/clear_refs", "5");
+}
+
+// The peak resident set size of the process, the `VmHWM` line of its status, e.g. `VmHWM:    10240 kB`.
+fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes = line
+        .trim_start_matches("VmHWM:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The binary counts its allocations with the allocator, so do the tests.
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn should_report_the_peak_allocations_of_each_phase() {
+        let mut report = MemoryReport::start("parsing");
+        let buffer = vec![1u8; 64 << 20];
+        drop(std::hint::black_box(buffer));
+        report.next_phase("output");
+        let phases = report.finish();
+
+        assert_eq!(
+            phases.iter().map(|phase| phase.phase).collect::<Vec<_>>(),
+            vec!["parsing", "output"]
+        );
+        // Other tests allocate concurrently, so only the buffer is known to be part of the peak.
+        assert!(phases[0].peak_allocated_bytes >= 64 << 20);
+        if cfg!(target_os = "linux") {
+            assert!(phases[0].peak_rss_bytes.is_some());
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Display,
+    io::Write,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+// Merging of the account outputs of runs that processed parts of the input in parallel, e.g. the nodes of a cluster.
+// The merged output is ordered by client and the totals are recomputed over all the outputs.
+
+/// What to do when an account is in more than one output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum DuplicatePolicy {
+    /// Fail. The outputs of runs sharded by client never have an account in common.
+    #[default]
+    Reject,
+    /// Add up the balances, e.g. for runs that split the transactions of a client by time. The account is locked if
+    /// it's locked in any of the outputs.
+    Sum,
+    /// Keep the account of the output that comes last.
+    Last,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum MergeError {
+    #[error("Cannot read the account output: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Unknown column '{column}' in {path}.")]
+    UnknownColumn { column: String, path: String },
+    #[error("Column '{column}' is missing in {path}.")]
+    MissingColumn { column: &'static str, path: String },
+    #[error("Invalid {column} '{value}' in {path}.")]
+    InvalidValue {
+        column: &'static str,
+        value: String,
+        path: String,
+    },
+    #[error(
+        "Account {account} of client {client} is in more than one output. Do the shards of the runs overlap?"
+    )]
+    DuplicateAccount { client: String, account: String },
+}
+
+// The columns of the account outputs, in the order they are written.
+const COLUMNS: [&str; 7] = [
+    "client",
+    "account",
+    "available",
+    "held",
+    "escrow",
+    "total",
+    "locked",
+];
+
+// The value of a column that is only written by some of the runs.
+fn default_value(column: &str) -> Option<&'static str> {
+    match column {
+        "account" => Some("main"),
+        "escrow" => Some("0"),
+        _ => None,
+    }
+}
+
+// An account of an output.
+#[derive(Debug, Clone)]
+struct MergedAccount {
+    available: Decimal,
+    held: Decimal,
+    escrow: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl MergedAccount {
+    fn add(&mut self, other: &MergedAccount) {
+        self.available += other.available;
+        self.held += other.held;
+        self.escrow += other.escrow;
+        self.total += other.total;
+        self.locked |= other.locked;
+    }
+}
+
+/// The totals of a merged output.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct MergeTotals {
+    pub(crate) outputs: usize,
+    pub(crate) clients: usize,
+    pub(crate) accounts: usize,
+    /// Accounts that were in more than one output.
+    pub(crate) duplicates: usize,
+    pub(crate) locked: usize,
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) escrow: Decimal,
+    pub(crate) total: Decimal,
+}
+
+impl Display for MergeTotals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Merged {} accounts of {} clients from {} outputs ({} duplicates): available {}, held {}, escrow {}, total {}, {} locked",
+            self.accounts,
+            self.clients,
+            self.outputs,
+            self.duplicates,
+            self.available.normalize(),
+            self.held.normalize(),
+            self.escrow.normalize(),
+            self.total.normalize(),
+            self.locked
+        )
+    }
+}
+
+/// Merge account outputs into one output ordered by client and return the totals. Optional columns that only some of
+/// the outputs have (e.g. `account`) are filled in with their default value for the other outputs.
+pub(crate) fn merge_outputs<P: AsRef<Path>, W: Write>(
+    paths: &[P],
+    policy: DuplicatePolicy,
+    writer: W,
+) -> Result<MergeTotals, MergeError> {
+    let mut present = BTreeSet::new();
+    let mut outputs = Vec::new();
+    for path in paths {
+        let path = path.as_ref().display().to_string();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_path(&path)?;
+        let header = reader.headers()?.clone();
+        for column in &header {
+            let index = COLUMNS
+                .iter()
+                .position(|known| *known == column)
+                .ok_or_else(|| MergeError::UnknownColumn {
+                    column: column.to_string(),
+                    path: path.clone(),
+                })?;
+            present.insert(index);
+        }
+        outputs.push((path, header, reader));
+    }
+
+    // Clients are ordered by their numeric id.
+    let mut accounts: BTreeMap<(Option<u16>, String, String), MergedAccount> = BTreeMap::new();
+    let mut totals = MergeTotals {
+        outputs: outputs.len(),
+        ..Default::default()
+    };
+    for (path, header, reader) in &mut outputs {
+        for record in reader.records() {
+            let record = record?;
+            let value = |column: &'static str| -> Result<&str, MergeError> {
+                match header.iter().position(|name| name == column) {
+                    Some(position) => Ok(&record[position]),
+                    None => default_value(column).ok_or_else(|| MergeError::MissingColumn {
+                        column,
+                        path: path.clone(),
+                    }),
+                }
+            };
+            let amount = |column: &'static str| -> Result<Decimal, MergeError> {
+                let text = value(column)?;
+                text.parse().map_err(|_| MergeError::InvalidValue {
+                    column,
+                    value: text.to_string(),
+                    path: path.clone(),
+                })
+            };
+            let client = value("client")?.to_string();
+            let account = value("account")?.to_string();
+            let locked = value("locked")?;
+            let merged = MergedAccount {
+                available: amount("available")?,
+                held: amount("held")?,
+                escrow: amount("escrow")?,
+                total: amount("total")?,
+                locked: locked.parse().map_err(|_| MergeError::InvalidValue {
+                    column: "locked",
+                    value: locked.to_string(),
+                    path: path.clone(),
+                })?,
+            };
+
+            let key = (client.parse::<u16>().ok(), client, account);
+            match accounts.get_mut(&key) {
+                None => {
+                    accounts.insert(key, merged);
+                }
+                Some(existing) => {
+                    totals.duplicates += 1;
+                    match policy {
+                        DuplicatePolicy::Reject => {
+                            let (_, client, account) = key;
+                            return Err(MergeError::DuplicateAccount { client, account });
+                        }
+                        DuplicatePolicy::Sum => existing.add(&merged),
+                        DuplicatePolicy::Last => *existing = merged,
+                    }
+                }
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(present.iter().map(|&index| COLUMNS[index]))?;
+    let mut clients = HashSet::new();
+    for ((_, client, account), merged) in &accounts {
+        let row = present.iter().map(|&index| match COLUMNS[index] {
+            "client" => client.clone(),
+            "account" => account.clone(),
+            "available" => merged.available.to_string(),
+            "held" => merged.held.to_string(),
+            "escrow" => merged.escrow.to_string(),
+            "total" => merged.total.to_string(),
+            _ => merged.locked.to_string(),
+        });
+        writer.write_record(row)?;
+
+        clients.insert(client);
+        totals.accounts += 1;
+        totals.locked += usize::from(merged.locked);
+        totals.available += merged.available;
+        totals.held += merged.held;
+        totals.escrow += merged.escrow;
+        totals.total += merged.total;
+    }
+    writer.flush().map_err(csv::Error::from)?;
+    totals.clients = clients.len();
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn outputs(dir: &TempDir) -> (std::path::PathBuf, std::path::PathBuf) {
+        let first = dir.path().join("first.csv");
+        let second = dir.path().join("second.csv");
+        std::fs::write(
+            &first,
+            "client,available,held,total,locked\n10,1,0,1,false\n2,5,0,5,true\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &second,
+            "client,account,available,held,total,locked\n3,savings,2,0,2,false\n10,main,1.5,1,2.5,false\n",
+        )
+        .unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn should_merge_outputs_ordered_by_client() {
+        let dir = TempDir::new().unwrap();
+        let (first, second) = outputs(&dir);
+
+        let mut output = Vec::new();
+        let totals = merge_outputs(&[&first, &second], DuplicatePolicy::Sum, &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,account,available,held,total,locked
+2,main,5,0,5,true
+3,savings,2,0,2,false
+10,main,2.5,1,3.5,false
+"
+        );
+        assert_eq!(
+            totals,
+            MergeTotals {
+                outputs: 2,
+                clients: 3,
+                accounts: 3,
+                duplicates: 1,
+                locked: 1,
+                available: Decimal::new(95, 1),
+                held: Decimal::ONE,
+                escrow: Decimal::ZERO,
+                total: Decimal::new(105, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn should_apply_duplicate_policy() {
+        let dir = TempDir::new().unwrap();
+        let (first, second) = outputs(&dir);
+
+        assert!(matches!(
+            merge_outputs(&[&first, &second], DuplicatePolicy::Reject, Vec::new()),
+            Err(MergeError::DuplicateAccount { .. })
+        ));
+
+        let mut output = Vec::new();
+        merge_outputs(&[&first, &second], DuplicatePolicy::Last, &mut output).unwrap();
+        assert!(
+            String::from_utf8(output)
+                .unwrap()
+                .ends_with("10,main,1.5,1,2.5,false\n")
+        );
+    }
+}
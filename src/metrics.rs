@@ -0,0 +1,523 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    net::UdpSocket,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use thiserror::Error;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{clock::SharedClock, logging::log_event};
+
+// Metrics of a run that are pushed to a collector, for the machines that run the engine as a batch job and can't be
+// scraped. The instrumented code only records into `Metrics`, which doesn't know where the metrics go; the exporters
+// read everything that was recorded at an interval and push it, and push a last time when the run is over.
+//
+// Counters are kept as totals since the start of the run. statsd expects the increments since the last push, so its
+// exporter remembers what it sent, while OTLP gets the totals (cumulative temporality).
+
+// The prefix of the metric names, e.g. `payments_engine.transactions.applied`.
+const DEFAULT_PREFIX: &str = "payments_engine";
+// Where an OTLP collector receives metrics over HTTP, unless the exporter gives a path.
+const OTLP_PATH: &str = "/v1/metrics";
+// The largest statsd packet, to stay under the MTU of the usual networks.
+const STATSD_PACKET: usize = 1432;
+const OTLP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub(crate) enum MetricsError {
+    #[error(
+        "'{0}' is not a metrics exporter (e.g. statsd://localhost:8125 or otlp://localhost:4318)."
+    )]
+    InvalidExporter(String),
+}
+
+/// Where the metrics are pushed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MetricsExporter {
+    /// A statsd daemon, written as `statsd://<HOST>:<PORT>[?prefix=<PREFIX>]`. The labels are sent as DogStatsD tags.
+    Statsd { address: String, prefix: String },
+    /// An OpenTelemetry collector, written as `otlp://<HOST>:<PORT>[/<PATH>]`. The metrics are posted as OTLP JSON
+    /// over HTTP, to `/v1/metrics` by default.
+    Otlp { url: String },
+}
+
+impl FromStr for MetricsExporter {
+    type Err = MetricsError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || MetricsError::InvalidExporter(value.to_string());
+        if let Some(rest) = value.strip_prefix("statsd://") {
+            let (address, parameters) = rest.split_once('?').unwrap_or((rest, ""));
+            if address.is_empty() || address.contains('/') {
+                return Err(invalid());
+            }
+            let prefix = match parameters {
+                "" => DEFAULT_PREFIX,
+                _ => parameters.strip_prefix("prefix=").ok_or_else(invalid)?,
+            };
+            Ok(Self::Statsd {
+                address: address.to_string(),
+                prefix: prefix.to_string(),
+            })
+        } else if value.starts_with("otlp://") {
+            let url = otlp_url(value, OTLP_PATH).ok_or_else(invalid)?;
+            Ok(Self::Otlp { url })
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl Display for MetricsExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricsExporter::Statsd { address, .. } => write!(f, "statsd://{}", address),
+            MetricsExporter::Otlp { url } => write!(f, "{}", url),
+        }
+    }
+}
+
+// A metric with the values of its labels.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct MetricKey {
+    name: &'static str,
+    labels: Vec<(&'static str, String)>,
+}
+
+impl MetricKey {
+    fn new(name: &'static str, labels: &[(&'static str, &dyn Display)]) -> Self {
+        Self {
+            name,
+            labels: labels
+                .iter()
+                .map(|(label, value)| (*label, value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetricValue {
+    /// A total since the start of the run.
+    Counter(u64),
+    Gauge(f64),
+}
+
+/// The metrics recorded by the stages of the engine. Clones record to the same metrics. The default records nothing,
+/// so the stages don't have to check whether the metrics are exported.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Metrics(Option<Arc<Mutex<BTreeMap<MetricKey, MetricValue>>>>);
+
+impl Metrics {
+    /// Metrics that are recorded, for the exporters to push.
+    pub(crate) fn recorded() -> Self {
+        Self(Some(Arc::default()))
+    }
+
+    /// Add to a counter, e.g. `metrics.count("transactions.applied", &[("type", &transaction_type)], 1)`.
+    pub(crate) fn count(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &dyn Display)],
+        n: u64,
+    ) {
+        let Some(metrics) = &self.0 else {
+            return;
+        };
+        let mut metrics = metrics.lock().expect("Metrics lock is never poisoned.");
+        let value = metrics
+            .entry(MetricKey::new(name, labels))
+            .or_insert(MetricValue::Counter(0));
+        match value {
+            MetricValue::Counter(total) => *total += n,
+            MetricValue::Gauge(_) => *value = MetricValue::Counter(n),
+        }
+    }
+
+    /// Set a gauge.
+    pub(crate) fn gauge(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &dyn Display)],
+        value: f64,
+    ) {
+        if let Some(metrics) = &self.0 {
+            metrics
+                .lock()
+                .expect("Metrics lock is never poisoned.")
+                .insert(MetricKey::new(name, labels), MetricValue::Gauge(value));
+        }
+    }
+
+    fn snapshot(&self) -> Vec<(MetricKey, MetricValue)> {
+        match &self.0 {
+            Some(metrics) => metrics
+                .lock()
+                .expect("Metrics lock is never poisoned.")
+                .iter()
+                .map(|(key, value)| (key.clone(), *value))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Pushes the metrics to the exporters at an interval until the run is over.
+pub(crate) struct MetricsExport {
+    stop: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsExport {
+    pub(crate) fn start(
+        metrics: Metrics,
+        exporters: Vec<MetricsExporter>,
+        interval: Duration,
+        clock: SharedClock,
+    ) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let handle = tokio::spawn(export(metrics, exporters, interval, clock, stopped));
+        Self { stop, handle }
+    }
+
+    /// Push the metrics a last time and stop.
+    pub(crate) async fn finish(self) {
+        let _ = self.stop.send(true);
+        let _ = self.handle.await;
+    }
+}
+
+async fn export(
+    metrics: Metrics,
+    exporters: Vec<MetricsExporter>,
+    interval: Duration,
+    clock: SharedClock,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let started = clock.now();
+    let mut targets: Vec<_> = exporters.into_iter().map(Target::new).collect();
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick is immediate, and there's nothing to push yet.
+    ticker.tick().await;
+    loop {
+        let last = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = stopped.wait_for(|stopped| *stopped) => true,
+        };
+        let (snapshot, now) = (metrics.snapshot(), clock.now());
+        let pushed = tokio::task::spawn_blocking(move || {
+            for target in &mut targets {
+                // The run goes on without its metrics, and the next push sends them again.
+                if let Err(err) = target.push(&snapshot, started, now) {
+                    log_event(
+                        "metrics_export_failed",
+                        &[("exporter", &target.exporter), ("error", &err)],
+                    );
+                }
+            }
+            targets
+        })
+        .await;
+        match pushed {
+            Ok(pushed) if !last => targets = pushed,
+            _ => break,
+        }
+    }
+}
+
+// An exporter with what it needs between two pushes.
+struct Target {
+    exporter: MetricsExporter,
+    // The counter totals that were sent to statsd.
+    sent: HashMap<MetricKey, u64>,
+    agent: ureq::Agent,
+}
+
+impl Target {
+    fn new(exporter: MetricsExporter) -> Self {
+        let agent = ureq::Agent::new_with_config(
+            ureq::Agent::config_builder()
+                .timeout_global(Some(OTLP_TIMEOUT))
+                .build(),
+        );
+        Self {
+            exporter,
+            sent: HashMap::new(),
+            agent,
+        }
+    }
+
+    fn push(
+        &mut self,
+        snapshot: &[(MetricKey, MetricValue)],
+        started: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match &self.exporter {
+            MetricsExporter::Statsd { address, prefix } => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address)?;
+                for packet in statsd_packets(prefix, snapshot, &self.sent) {
+                    socket.send(packet.as_bytes())?;
+                }
+                for (key, value) in snapshot {
+                    if let MetricValue::Counter(total) = value {
+                        self.sent.insert(key.clone(), *total);
+                    }
+                }
+            }
+            MetricsExporter::Otlp { url } => {
+                let body = serde_json::to_vec(&otlp_body(snapshot, started, now))?;
+                self.agent
+                    .post(url)
+                    .header("content-type", "application/json")
+                    .send(&body[..])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The statsd lines of the metrics, grouped in packets: the counters that moved since they were sent and all the gauges.
+fn statsd_packets(
+    prefix: &str,
+    snapshot: &[(MetricKey, MetricValue)],
+    sent: &HashMap<MetricKey, u64>,
+) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for (key, value) in snapshot {
+        let (value, kind) = match value {
+            MetricValue::Counter(total) => {
+                let increment = total - sent.get(key).copied().unwrap_or_default();
+                if increment == 0 {
+                    continue;
+                }
+                (increment.to_string(), "c")
+            }
+            MetricValue::Gauge(value) => (value.to_string(), "g"),
+        };
+        let mut line = match prefix {
+            "" => format!("{}:{}|{}", key.name, value, kind),
+            _ => format!("{}.{}:{}|{}", prefix, key.name, value, kind),
+        };
+        if !key.labels.is_empty() {
+            let tags: Vec<_> = key
+                .labels
+                .iter()
+                .map(|(label, value)| format!("{}:{}", label, value))
+                .collect();
+            line = format!("{}|#{}", line, tags.join(","));
+        }
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= STATSD_PACKET => {
+                packet.push('\n');
+                packet.push_str(&line);
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
+
+/// The HTTP URL of an OTLP collector written as `otlp://<HOST>:<PORT>[/<PATH>]`, with the default path of the signal if
+/// none is given.
+pub(crate) fn otlp_url(value: &str, default_path: &str) -> Option<String> {
+    let rest = value.strip_prefix("otlp://")?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, default_path),
+    };
+    (!host.is_empty()).then(|| format!("http://{}{}", host, path))
+}
+
+/// The resource of everything the engine sends to an OTLP collector.
+pub(crate) fn otlp_resource() -> serde_json::Value {
+    json!({ "attributes": otlp_attributes(&[("service.name", "payments-engine".into())]) })
+}
+
+/// The instrumentation scope of everything the engine sends to an OTLP collector.
+pub(crate) fn otlp_scope() -> serde_json::Value {
+    json!({ "name": "payments-engine", "version": env!("CARGO_PKG_VERSION") })
+}
+
+/// String attributes in OTLP JSON.
+pub(crate) fn otlp_attributes(attributes: &[(&'static str, String)]) -> serde_json::Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+/// A time in OTLP JSON, nanoseconds since the Unix epoch as a string.
+pub(crate) fn unix_nanos(time: DateTime<Utc>) -> String {
+    time.timestamp_nanos_opt().unwrap_or_default().to_string()
+}
+
+// An OTLP export request with the metrics, in the JSON encoding of the protobuf messages.
+fn otlp_body(
+    snapshot: &[(MetricKey, MetricValue)],
+    started: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> serde_json::Value {
+    // The data points of a metric are grouped under its name, the snapshot being sorted by name.
+    let mut metrics: Vec<serde_json::Value> = Vec::new();
+    let mut last_name = None;
+    for (key, value) in snapshot {
+        let attributes = otlp_attributes(&key.labels);
+        let (kind, point) = match value {
+            MetricValue::Counter(total) => (
+                "sum",
+                json!({
+                    "attributes": attributes,
+                    "startTimeUnixNano": unix_nanos(started),
+                    "timeUnixNano": unix_nanos(now),
+                    "asInt": total.to_string(),
+                }),
+            ),
+            MetricValue::Gauge(value) => (
+                "gauge",
+                json!({ "attributes": attributes, "timeUnixNano": unix_nanos(now), "asDouble": value }),
+            ),
+        };
+        if last_name != Some(key.name) {
+            let mut metric = json!({
+                "name": format!("{}.{}", DEFAULT_PREFIX, key.name),
+                kind: { "dataPoints": [] },
+            });
+            if kind == "sum" {
+                // Cumulative.
+                metric["sum"]["aggregationTemporality"] = json!(2);
+                metric["sum"]["isMonotonic"] = json!(true);
+            }
+            metrics.push(metric);
+            last_name = Some(key.name);
+        }
+        if let Some(points) = metrics
+            .last_mut()
+            .and_then(|metric| metric[kind]["dataPoints"].as_array_mut())
+        {
+            points.push(point);
+        }
+    }
+
+    json!({
+        "resourceMetrics": [{
+            "resource": otlp_resource(),
+            "scopeMetrics": [{
+                "scope": otlp_scope(),
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_metrics_exporters() {
+        assert_eq!(
+            "statsd://localhost:8125"
+                .parse::<MetricsExporter>()
+                .unwrap(),
+            MetricsExporter::Statsd {
+                address: "localhost:8125".into(),
+                prefix: DEFAULT_PREFIX.into()
+            }
+        );
+        assert_eq!(
+            "statsd://10.0.0.1:8125?prefix=batch"
+                .parse::<MetricsExporter>()
+                .unwrap(),
+            MetricsExporter::Statsd {
+                address: "10.0.0.1:8125".into(),
+                prefix: "batch".into()
+            }
+        );
+        assert_eq!(
+            "otlp://collector:4318".parse::<MetricsExporter>().unwrap(),
+            MetricsExporter::Otlp {
+                url: "http://collector:4318/v1/metrics".into()
+            }
+        );
+        assert_eq!(
+            "otlp://collector:4318/otlp/v1/metrics"
+                .parse::<MetricsExporter>()
+                .unwrap(),
+            MetricsExporter::Otlp {
+                url: "http://collector:4318/otlp/v1/metrics".into()
+            }
+        );
+        assert!("statsd://".parse::<MetricsExporter>().is_err());
+        assert!(
+            "statsd://localhost:8125?tags=a"
+                .parse::<MetricsExporter>()
+                .is_err()
+        );
+        assert!("http://localhost:4318".parse::<MetricsExporter>().is_err());
+    }
+
+    #[test]
+    fn should_send_the_counter_increments_to_statsd() {
+        let metrics = Metrics::recorded();
+        metrics.count("transactions.applied", &[("type", &"deposit")], 3);
+        metrics.count("transactions.applied", &[("type", &"withdrawal")], 1);
+        metrics.gauge("accounts", &[], 2.0);
+
+        let mut sent = HashMap::new();
+        assert_eq!(
+            statsd_packets("pe", &metrics.snapshot(), &sent),
+            vec![
+                "pe.accounts:2|g\npe.transactions.applied:3|c|#type:deposit\npe.transactions.applied:1|c|#type:withdrawal"
+            ]
+        );
+
+        for (key, value) in metrics.snapshot() {
+            if let MetricValue::Counter(total) = value {
+                sent.insert(key, total);
+            }
+        }
+        metrics.count("transactions.applied", &[("type", &"deposit")], 2);
+        assert_eq!(
+            statsd_packets("", &metrics.snapshot(), &sent),
+            vec!["accounts:2|g\ntransactions.applied:2|c|#type:deposit"]
+        );
+        // Nothing is recorded by default.
+        assert!(Metrics::default().snapshot().is_empty());
+    }
+
+    #[test]
+    fn should_group_the_data_points_of_a_metric_in_otlp() {
+        let metrics = Metrics::recorded();
+        metrics.count("transactions.applied", &[("type", &"deposit")], 3);
+        metrics.count("transactions.applied", &[("type", &"withdrawal")], 1);
+        metrics.gauge("accounts", &[], 2.0);
+        let started = "2024-03-01T12:00:00Z".parse().unwrap();
+        let now = "2024-03-01T12:00:10Z".parse().unwrap();
+
+        let body = otlp_body(&metrics.snapshot(), started, now);
+        let exported = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(exported.as_array().unwrap().len(), 2);
+        assert_eq!(exported[0]["name"], "payments_engine.accounts");
+        assert_eq!(exported[0]["gauge"]["dataPoints"][0]["asDouble"], 2.0);
+        let applied = &exported[1];
+        assert_eq!(applied["name"], "payments_engine.transactions.applied");
+        assert_eq!(applied["sum"]["aggregationTemporality"], 2);
+        let points = applied["sum"]["dataPoints"].as_array().unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0]["asInt"], "3");
+        assert_eq!(
+            points[0]["attributes"][0]["value"]["stringValue"],
+            "deposit"
+        );
+        assert_eq!(points[1]["startTimeUnixNano"], "1709294400000000000");
+        assert_eq!(points[1]["timeUnixNano"], "1709294410000000000");
+    }
+}
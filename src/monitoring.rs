@@ -0,0 +1,427 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    logging::log_event,
+    transaction_types::{ClientId, TransactionType},
+};
+
+// Rates are not checked on fewer transactions than this, otherwise the first chargeback of a client would raise an alert.
+const MIN_SAMPLE: usize = 100;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When to raise chargeback rate alerts and what to do about them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChargebackAlertPolicy {
+    /// Maximum share of chargebacks in the window, e.g. `0.01` for 1%.
+    pub(crate) threshold: Decimal,
+    /// Number of recent applied transactions the rate is computed on.
+    pub(crate) window: usize,
+    /// Stop accepting deposits on accounts whose chargeback rate exceeds the threshold.
+    pub(crate) withdrawal_only: bool,
+}
+
+// The share of chargebacks in the most recent transactions.
+#[derive(Debug, Clone, Default)]
+struct RollingRate {
+    recent: VecDeque<bool>,
+    chargebacks: usize,
+    // Whether the rate is above the threshold. Alerts are raised only when the rate crosses the threshold.
+    exceeded: bool,
+}
+
+impl RollingRate {
+    fn rate(&self) -> Decimal {
+        Decimal::from(self.chargebacks) / Decimal::from(self.recent.len().max(1))
+    }
+
+    // Add a transaction to the window, dropping the oldest one if the window is full. Returns the changes of the
+    // number of transactions and of chargebacks in the window.
+    fn push(&mut self, chargeback: bool, window: usize) -> (isize, isize) {
+        let (mut transactions, mut chargebacks) = (1, isize::from(chargeback));
+        if self.recent.len() == window
+            && let Some(dropped) = self.recent.pop_front()
+        {
+            transactions -= 1;
+            chargebacks -= isize::from(dropped);
+        }
+        self.recent.push_back(chargeback);
+        self.chargebacks = self.chargebacks.wrapping_add_signed(chargebacks);
+        (transactions, chargebacks)
+    }
+
+    // Record a transaction and return the new rate if it just crossed the threshold.
+    fn record(&mut self, chargeback: bool, policy: &ChargebackAlertPolicy) -> Option<Decimal> {
+        self.push(chargeback, policy.window);
+
+        if self.recent.len() < MIN_SAMPLE.min(policy.window) {
+            return None;
+        }
+
+        let rate = self.rate();
+        let exceeded = rate > policy.threshold;
+        let crossed = exceeded && !self.exceeded;
+        self.exceeded = exceeded;
+        crossed.then_some(rate)
+    }
+}
+
+// The chargebacks of all the workers together. Each worker keeps the window of its own recent transactions and adds
+// the changes of its counts to the totals, so recording a transaction never takes a lock: once the window of a worker
+// is full, only its chargebacks entering or leaving the window touch the totals.
+#[derive(Debug, Default)]
+struct OverallRate {
+    transactions: AtomicUsize,
+    chargebacks: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+impl OverallRate {
+    // Apply the changes of the counts of a worker and return the new rate if it just crossed the threshold.
+    fn update(
+        &self,
+        (transactions, chargebacks): (isize, isize),
+        policy: &ChargebackAlertPolicy,
+    ) -> Option<Decimal> {
+        let add = |counter: &AtomicUsize, change: isize| match change {
+            0 => counter.load(Ordering::Relaxed),
+            change if change > 0 => {
+                counter.fetch_add(change.unsigned_abs(), Ordering::Relaxed) + change.unsigned_abs()
+            }
+            change => {
+                counter.fetch_sub(change.unsigned_abs(), Ordering::Relaxed) - change.unsigned_abs()
+            }
+        };
+        let transactions = add(&self.transactions, transactions);
+        let chargebacks = add(&self.chargebacks, chargebacks);
+
+        if transactions < MIN_SAMPLE.min(policy.window) {
+            return None;
+        }
+
+        let rate = Decimal::from(chargebacks) / Decimal::from(transactions);
+        let exceeded = rate > policy.threshold;
+        // Only the worker that flips the flag raises the alert.
+        let crossed = exceeded && !self.exceeded.swap(true, Ordering::Relaxed);
+        if !exceeded {
+            self.exceeded.store(false, Ordering::Relaxed);
+        }
+        crossed.then_some(rate)
+    }
+}
+
+/// Tracks the rolling chargeback rate of each client and of all the clients together.
+/// Every worker has its own clone of a monitor: the clones keep their own counts and add them up to the overall rate,
+/// which is computed on the recent transactions of each worker, up to the window per worker.
+#[derive(Debug, Clone)]
+pub(crate) struct ChargebackMonitor {
+    policy: ChargebackAlertPolicy,
+    clients: HashMap<ClientId, RollingRate>,
+    worker: RollingRate,
+    overall: Arc<OverallRate>,
+    alerts: Option<AlertSink>,
+    clock: SharedClock,
+}
+
+impl ChargebackMonitor {
+    pub(crate) fn new(policy: ChargebackAlertPolicy) -> Self {
+        Self {
+            policy,
+            clients: HashMap::new(),
+            worker: RollingRate::default(),
+            overall: Arc::default(),
+            alerts: None,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Take the times of the alerts from another clock than the system clock.
+    pub(crate) fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Also send the alerts to the alert log and webhook of an alert export.
+    pub(crate) fn with_alerts(mut self, alerts: AlertSink) -> Self {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    pub(crate) fn policy(&self) -> &ChargebackAlertPolicy {
+        &self.policy
+    }
+
+    /// Record an applied transaction and raise alerts for the rates that crossed the threshold.
+    /// Returns true if the rate of the client just crossed the threshold.
+    pub(crate) fn record(&mut self, client: ClientId, transaction_type: TransactionType) -> bool {
+        let chargeback = transaction_type == TransactionType::Chargeback;
+
+        let changes = self.worker.push(chargeback, self.policy.window);
+        if let Some(rate) = self.overall.update(changes, &self.policy) {
+            self.raise(ChargebackAlert {
+                scope: "overall",
+                client: None,
+                rate: rate.round_dp(4),
+                threshold: self.policy.threshold,
+                withdrawal_only: false,
+                raised_at: self.clock.now(),
+            });
+        }
+
+        let client_rate = self
+            .clients
+            .entry(client)
+            .or_default()
+            .record(chargeback, &self.policy);
+        if let Some(rate) = client_rate {
+            self.raise(ChargebackAlert {
+                scope: "client",
+                client: Some(client.into()),
+                rate: rate.round_dp(4),
+                threshold: self.policy.threshold,
+                withdrawal_only: self.policy.withdrawal_only,
+                raised_at: self.clock.now(),
+            });
+        }
+
+        client_rate.is_some()
+    }
+
+    fn raise(&self, alert: ChargebackAlert) {
+        let client = alert
+            .client
+            .map(|client| client.to_string())
+            .unwrap_or_default();
+        log_event(
+            "chargeback_rate_alert",
+            &[
+                ("scope", &alert.scope),
+                ("client", &client),
+                ("rate", &alert.rate),
+                ("threshold", &alert.threshold),
+                ("withdrawal_only", &alert.withdrawal_only),
+            ],
+        );
+        if let Some(alerts) = &self.alerts {
+            alerts.send(alert);
+        }
+    }
+}
+
+/// A chargeback rate that crossed the threshold, as written to the alert log and posted to the webhook.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ChargebackAlert {
+    /// `overall` or `client`.
+    scope: &'static str,
+    client: Option<u16>,
+    #[serde(with = "rust_decimal::serde::str")]
+    rate: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    threshold: Decimal,
+    /// Whether deposits to the accounts of the client are suspended.
+    withdrawal_only: bool,
+    #[serde(serialize_with = "rfc3339")]
+    raised_at: DateTime<Utc>,
+}
+
+fn rfc3339<S: serde::Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+/// Where the workers send their alerts. Sending never waits: the alerts are written and posted by the export task.
+#[derive(Debug, Clone)]
+pub(crate) struct AlertSink(mpsc::UnboundedSender<ChargebackAlert>);
+
+impl AlertSink {
+    fn send(&self, alert: ChargebackAlert) {
+        let _ = self.0.send(alert);
+    }
+}
+
+/// The task that appends the alerts to the alert log, one JSON object per line, and posts each of them as JSON to the
+/// alert webhook. The alert log is the audit trail of the alerts: an alert that can't be posted is still in it.
+pub(crate) struct AlertExport {
+    stop: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl AlertExport {
+    pub(crate) fn start(log: Option<PathBuf>, webhook: Option<String>) -> (AlertSink, Self) {
+        let (sink, alerts) = mpsc::unbounded_channel();
+        let (stop, stopped) = watch::channel(false);
+        let handle = tokio::spawn(export(log, webhook, alerts, stopped));
+        (AlertSink(sink), Self { stop, handle })
+    }
+
+    /// Write and post the alerts that are still queued and stop.
+    pub(crate) async fn finish(self) {
+        let _ = self.stop.send(true);
+        let _ = self.handle.await;
+    }
+}
+
+async fn export(
+    log: Option<PathBuf>,
+    webhook: Option<String>,
+    mut alerts: mpsc::UnboundedReceiver<ChargebackAlert>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let agent = ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .timeout_global(Some(WEBHOOK_TIMEOUT))
+            .build(),
+    );
+    loop {
+        let alert = tokio::select! {
+            alert = alerts.recv() => alert,
+            _ = stopped.wait_for(|stopped| *stopped) => alerts.try_recv().ok(),
+        };
+        let Some(alert) = alert else {
+            break;
+        };
+        let Ok(body) = serde_json::to_vec(&alert) else {
+            continue;
+        };
+        if let Some(path) = &log
+            && let Err(err) = append_line(path, &body)
+        {
+            log_event(
+                "alert_log_failed",
+                &[("path", &path.display()), ("error", &err)],
+            );
+        }
+        if let Some(url) = &webhook {
+            let (agent, target) = (agent.clone(), url.clone());
+            let posted = tokio::task::spawn_blocking(move || {
+                agent
+                    .post(&target)
+                    .header("content-type", "application/json")
+                    .send(&body[..])
+                    .map(|_| ())
+            })
+            .await;
+            match posted {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    log_event("alert_webhook_failed", &[("url", &url), ("error", &err)])
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+fn append_line(path: &Path, line: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line)?;
+    file.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn policy(window: usize) -> ChargebackAlertPolicy {
+        ChargebackAlertPolicy {
+            threshold: Decimal::new(1, 2),
+            window,
+            withdrawal_only: false,
+        }
+    }
+
+    #[test]
+    fn should_alert_once_when_rate_crosses_threshold() {
+        let mut monitor = ChargebackMonitor::new(policy(100));
+
+        for _ in 0..99 {
+            assert!(!monitor.record(1.into(), TransactionType::Deposit));
+        }
+        // 1 chargeback in 100 transactions is exactly 1%.
+        assert!(!monitor.record(1.into(), TransactionType::Chargeback));
+        assert!(monitor.record(1.into(), TransactionType::Chargeback));
+        assert!(!monitor.record(1.into(), TransactionType::Chargeback));
+    }
+
+    #[test]
+    fn should_forget_chargebacks_outside_of_the_window() {
+        let mut rate = RollingRate::default();
+        let policy = policy(100);
+
+        rate.record(true, &policy);
+        rate.record(true, &policy);
+        for _ in 0..98 {
+            rate.record(false, &policy);
+        }
+        assert_eq!(rate.rate(), Decimal::new(2, 2));
+
+        rate.record(false, &policy);
+        assert_eq!(rate.rate(), Decimal::new(1, 2));
+    }
+
+    #[test]
+    fn should_add_up_the_counts_of_the_clones() {
+        let monitor = ChargebackMonitor::new(policy(2));
+        let mut first = monitor.clone();
+        let mut second = monitor.clone();
+
+        first.record(1.into(), TransactionType::Chargeback);
+        second.record(2.into(), TransactionType::Deposit);
+        second.record(2.into(), TransactionType::Deposit);
+        // The chargeback of the second worker pushes its first deposit out of its window.
+        second.record(2.into(), TransactionType::Chargeback);
+
+        assert_eq!(monitor.overall.transactions.load(Ordering::Relaxed), 3);
+        assert_eq!(monitor.overall.chargebacks.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn should_write_the_alerts_to_the_alert_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alerts.jsonl");
+        let (sink, export) = AlertExport::start(Some(path.clone()), None);
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut monitor = ChargebackMonitor::new(policy(100))
+            .with_alerts(sink)
+            .with_clock(clock.shared());
+
+        for _ in 0..98 {
+            monitor.record(1.into(), TransactionType::Deposit);
+        }
+        monitor.record(1.into(), TransactionType::Chargeback);
+        monitor.record(1.into(), TransactionType::Chargeback);
+        drop(monitor);
+        export.finish().await;
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["scope"], "overall");
+        assert_eq!(lines[1]["scope"], "client");
+        assert_eq!(lines[1]["client"], 1);
+        assert_eq!(lines[1]["rate"], "0.02");
+        assert_eq!(lines[1]["threshold"], "0.01");
+        assert_eq!(lines[1]["raised_at"], "2024-03-01T12:00:00.000Z");
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    io::{self, Cursor, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+#[cfg(feature = "object_store")]
+use futures_util::StreamExt;
+#[cfg(feature = "object_store")]
+use object_store::{
+    ClientOptions, ObjectStoreExt,
+    aws::{AmazonS3Builder, AmazonS3ConfigKey},
+};
+
+// Input files that are objects of an S3 bucket rather than local files (`s3://<BUCKET>/<KEY>`), so a batch run over an
+// export kept in S3 doesn't start with downloading gigabytes to a local disk. The object is streamed into the reader
+// while it's parsed, and goes through the same checks and decompression as a file.
+//
+// The reader of the input files is synchronous, so the object is downloaded on a thread of its own, with a runtime of
+// its own, and its chunks are handed over to the reader through a bounded channel: the download is at most
+// `PREFETCH_CHUNKS` chunks ahead of the parsing, and waits for the reader when the workers are behind. The download is
+// a single request without a timeout, since it lasts as long as the run.
+
+const SCHEME: &str = "s3://";
+// Number of downloaded chunks that can wait for the reader.
+#[cfg(feature = "object_store")]
+const PREFETCH_CHUNKS: usize = 16;
+
+#[derive(Debug, Error)]
+pub(crate) enum ObjectInputError {
+    #[error("'{0}' is not an S3 object (e.g. s3://exports/2024-06/transactions.csv.gz).")]
+    InvalidInput(String),
+    #[cfg(not(feature = "object_store"))]
+    #[error("S3 input is not supported by this build. Build with the object_store feature.")]
+    Unsupported,
+}
+
+/// An object of an S3 bucket, written as `s3://<BUCKET>/<KEY>`. The credentials, the region and the endpoint are taken
+/// from the usual AWS environment variables (e.g. `AWS_ACCESS_KEY_ID`, `AWS_REGION`, `AWS_ENDPOINT`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ObjectInput {
+    bucket: String,
+    key: String,
+}
+
+impl FromStr for ObjectInput {
+    type Err = ObjectInputError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || ObjectInputError::InvalidInput(value.to_string());
+        let rest = value.strip_prefix(SCHEME).ok_or_else(invalid)?;
+        let (bucket, key) = rest.split_once('/').ok_or_else(invalid)?;
+        if bucket.is_empty() || key.is_empty() || key.ends_with('/') {
+            return Err(invalid());
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl ObjectInput {
+    /// The object an input path stands for, or None if the path is a local file.
+    pub(crate) fn from_path(path: &Path) -> Option<Result<Self, ObjectInputError>> {
+        let path = path.to_str()?;
+        path.starts_with(SCHEME).then(|| path.parse())
+    }
+
+    /// Check that the object can be read by this build, without connecting to S3.
+    pub(crate) fn check(&self) -> Result<(), ObjectInputError> {
+        #[cfg(not(feature = "object_store"))]
+        return Err(ObjectInputError::Unsupported);
+        #[cfg(feature = "object_store")]
+        Ok(())
+    }
+
+    /// Start the download of the object and return its size and a reader of its contents. Fails with
+    /// `io::ErrorKind::NotFound` if there's no such object.
+    pub(crate) fn open(&self) -> io::Result<(u64, Box<dyn Read + Send>)> {
+        #[cfg(not(feature = "object_store"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            ObjectInputError::Unsupported,
+        ));
+        #[cfg(feature = "object_store")]
+        self.download()
+    }
+
+    #[cfg(feature = "object_store")]
+    fn download(&self) -> io::Result<(u64, Box<dyn Read + Send>)> {
+        // Like `AmazonS3Builder::from_env`, on top of options without the timeout, so the client settings of the
+        // environment (e.g. `AWS_ALLOW_HTTP`, `AWS_TIMEOUT`) still apply.
+        let mut builder = AmazonS3Builder::new()
+            .with_client_options(ClientOptions::new().with_timeout_disabled());
+        for (key, value) in std::env::vars() {
+            if key.starts_with("AWS_")
+                && let Ok(key) = key.to_ascii_lowercase().parse::<AmazonS3ConfigKey>()
+            {
+                builder = builder.with_config(key, value);
+            }
+        }
+        let store = builder
+            .with_bucket_name(&self.bucket)
+            .build()
+            .map_err(store_error)?;
+        let location = object_store::path::Path::from(self.key.as_str());
+        let (opened_tx, opened) = std::sync::mpsc::sync_channel(1);
+        let (chunks_tx, chunks) = std::sync::mpsc::sync_channel(PREFETCH_CHUNKS);
+        std::thread::Builder::new()
+            .name("object-download".to_string())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        let _ = opened_tx.send(Err(err));
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let object = match store.get(&location).await {
+                        Ok(object) => object,
+                        Err(err) => {
+                            let _ = opened_tx.send(Err(store_error(err)));
+                            return;
+                        }
+                    };
+                    let _ = opened_tx.send(Ok(object.meta.size));
+                    let mut stream = object.into_stream();
+                    while let Some(chunk) = stream.next().await {
+                        let failed = chunk.is_err();
+                        let chunk = chunk.map(Vec::from).map_err(store_error);
+                        // The reader is gone when the run stopped early.
+                        if chunks_tx.send(chunk).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+            })?;
+        let size = opened
+            .recv()
+            .map_err(|_| io::Error::other("The download of the object stopped."))??;
+        let reader = ObjectReader {
+            chunks,
+            chunk: Cursor::default(),
+        };
+        Ok((size, Box::new(reader)))
+    }
+}
+
+#[cfg(feature = "object_store")]
+fn store_error(err: object_store::Error) -> io::Error {
+    let kind = match &err {
+        object_store::Error::NotFound { .. } => io::ErrorKind::NotFound,
+        object_store::Error::PermissionDenied { .. }
+        | object_store::Error::Unauthenticated { .. } => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+// Reads the chunks of an object as they are downloaded. The end of the object is when the download thread is done.
+#[cfg_attr(not(feature = "object_store"), allow(dead_code))]
+struct ObjectReader {
+    chunks: std::sync::mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn should_parse_objects() {
+        assert_eq!(
+            "s3://exports/2024-06/transactions.csv"
+                .parse::<ObjectInput>()
+                .unwrap(),
+            ObjectInput {
+                bucket: "exports".to_string(),
+                key: "2024-06/transactions.csv".to_string(),
+            }
+        );
+        for invalid in [
+            "s3://exports",
+            "s3:///transactions.csv",
+            "s3://exports/",
+            "gs://a/b",
+        ] {
+            assert!(invalid.parse::<ObjectInput>().is_err(), "{}", invalid);
+        }
+        assert!(ObjectInput::from_path(&PathBuf::from("transactions.csv")).is_none());
+        assert!(
+            ObjectInput::from_path(&PathBuf::from("s3://exports/a.csv"))
+                .is_some_and(|object| object.is_ok())
+        );
+    }
+
+    #[test]
+    fn should_read_the_chunks_in_order() {
+        let (tx, chunks) = std::sync::mpsc::sync_channel(4);
+        for chunk in ["type,client", "", ",tx,amount\n", "deposit,1,1,1.0\n"] {
+            tx.send(Ok(chunk.as_bytes().to_vec())).unwrap();
+        }
+        drop(tx);
+        let mut reader = ObjectReader {
+            chunks,
+            chunk: Cursor::default(),
+        };
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    }
+}
@@ -0,0 +1,63 @@
+use std::{fmt::Display, sync::Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::{rejects::ResponseCode, transaction_types::Transaction};
+
+// The outcome of a transaction, for the sources that answer every transaction with what became of it (the gRPC
+// service). Such a source attaches an `OutcomeReply` to the extensions of the transaction, and whoever handles the
+// transaction last reports the outcome on it: the worker once it applied, rejected or skipped it, or the dispatcher when
+// it forwarded it to a peer in cluster mode. A dispute operation that waits for the transaction it references is only
+// reported once it was retried, and the transactions held back by a paused worker once they were applied. The channel
+// is closed without an outcome if the transaction is dropped, e.g. because the engine stopped.
+
+/// What became of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TransactionOutcome {
+    Applied,
+    /// Rejected by the validator chain, the limits or the account, with the response code of the rejects report.
+    Rejected {
+        reason: String,
+        response_code: &'static str,
+    },
+    /// Already applied by a previous run.
+    Duplicate,
+    /// Sent to the peer that owns the client in cluster mode.
+    Forwarded,
+}
+
+impl TransactionOutcome {
+    pub(crate) fn rejected<E: Display + ResponseCode>(err: &E) -> Self {
+        Self::Rejected {
+            reason: err.to_string(),
+            response_code: err.response_code(),
+        }
+    }
+}
+
+/// The channel on which the outcome of a transaction is reported. Only the first outcome is sent.
+pub(crate) struct OutcomeReply(Mutex<Option<oneshot::Sender<TransactionOutcome>>>);
+
+impl OutcomeReply {
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub(crate) fn new() -> (Self, oneshot::Receiver<TransactionOutcome>) {
+        let (reply, outcome) = oneshot::channel();
+        (Self(Mutex::new(Some(reply))), outcome)
+    }
+}
+
+/// Report the outcome of a transaction, if its source asked for it.
+pub(crate) fn report(transaction: &Transaction, outcome: impl FnOnce() -> TransactionOutcome) {
+    let Some(reply) = transaction.extensions().get::<OutcomeReply>() else {
+        return;
+    };
+    let reply = reply
+        .0
+        .lock()
+        .expect("Outcome lock is never poisoned.")
+        .take();
+    // The source may have given up waiting. There's nothing to do in that case.
+    if let Some(reply) = reply {
+        let _ = reply.send(outcome());
+    }
+}
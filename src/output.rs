@@ -0,0 +1,436 @@
+use std::{fmt::Display, io::Write, str::FromStr};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    account::{Account, AccountSnapshot},
+    transaction_types::{AccountName, Amount, AmountFormat, ClientId},
+};
+
+// The columns of the account output are selected by a schema rather than fixed by a struct, so that new columns can be
+// added without changing the output of the existing schemas. A new column is added to `Column` and to the schemas
+// that should have it: `v1` never changes, `v2` has all the columns known when it was introduced, and `custom` lets
+// the consumers pick the columns and their order.
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum SchemaError {
+    #[error("'{0}' is not an output schema (v1, v2 or custom(<COLUMNS>)).")]
+    InvalidSchema(String),
+    #[error("'{0}' is not a column of the output.")]
+    UnknownColumn(String),
+}
+
+/// A column of the account output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Column {
+    Client,
+    Account,
+    Available,
+    Held,
+    Escrow,
+    Pending,
+    Reserve,
+    Total,
+    Locked,
+}
+
+impl Column {
+    const ALL: [Column; 9] = [
+        Column::Client,
+        Column::Account,
+        Column::Available,
+        Column::Held,
+        Column::Escrow,
+        Column::Pending,
+        Column::Reserve,
+        Column::Total,
+        Column::Locked,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Account => "account",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Escrow => "escrow",
+            Column::Pending => "pending",
+            Column::Reserve => "reserve",
+            Column::Total => "total",
+            Column::Locked => "locked",
+        }
+    }
+
+    fn field<'a>(&self, snapshot: &'a AccountSnapshot) -> Field<'a> {
+        match self {
+            Column::Client => Field::Client(snapshot.client),
+            Column::Account => Field::Account(&snapshot.account),
+            Column::Available => Field::Amount(snapshot.available),
+            Column::Held => Field::Amount(snapshot.held),
+            Column::Escrow => Field::Amount(snapshot.escrow),
+            Column::Pending => Field::Amount(snapshot.pending),
+            Column::Reserve => Field::Amount(snapshot.reserve),
+            Column::Total => Field::Amount(snapshot.total),
+            Column::Locked => Field::Flag(snapshot.locked),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = SchemaError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let name = value.trim();
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name() == name)
+            .ok_or_else(|| SchemaError::UnknownColumn(name.to_string()))
+    }
+}
+
+// A field of a record. Untagged so each field is written the same way as in a serialized struct.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Field<'a> {
+    Client(ClientId),
+    Account(&'a AccountName),
+    Amount(Amount),
+    Flag(bool),
+}
+
+/// The optional columns of the `v1` output.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OutputColumns {
+    /// The name of the sub-account. Enabled when any client has sub-accounts.
+    pub(crate) account: bool,
+    /// The funds held in escrow. Enabled by the extended report.
+    pub(crate) escrow: bool,
+    /// The funds of the deposits that didn't settle yet. Enabled by a settlement delay.
+    pub(crate) pending: bool,
+    /// The rolling reserve. Enabled by the extended report when clients have a rolling reserve.
+    pub(crate) reserve: bool,
+}
+
+/// The columns of the account output.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) enum OutputSchema {
+    /// `client,available,held,total,locked`, with `account` after `client` when any client has sub-accounts,
+    /// `escrow` after `held` with the extended report, `pending` before `total` with a settlement delay and `reserve`
+    /// before `total` with the extended report when clients have a rolling reserve.
+    #[default]
+    V1,
+    /// All the columns, whatever the accounts: `client,account,available,held,escrow,total,locked`. The columns that
+    /// came after it, like `pending` and `reserve`, are only in custom schemas.
+    V2,
+    /// The listed columns, in the listed order.
+    Custom(Vec<Column>),
+}
+
+impl OutputSchema {
+    pub(crate) fn columns(&self, optional: &OutputColumns) -> Vec<Column> {
+        match self {
+            OutputSchema::V1 => Column::ALL
+                .into_iter()
+                .filter(|column| match column {
+                    Column::Account => optional.account,
+                    Column::Escrow => optional.escrow,
+                    Column::Pending => optional.pending,
+                    Column::Reserve => optional.reserve,
+                    _ => true,
+                })
+                .collect(),
+            OutputSchema::V2 => Column::ALL
+                .into_iter()
+                .filter(|column| !matches!(column, Column::Pending | Column::Reserve))
+                .collect(),
+            OutputSchema::Custom(columns) => columns.clone(),
+        }
+    }
+}
+
+impl FromStr for OutputSchema {
+    type Err = SchemaError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "v1" => Ok(OutputSchema::V1),
+            "v2" => Ok(OutputSchema::V2),
+            _ => {
+                let columns = value
+                    .strip_prefix("custom(")
+                    .and_then(|columns| columns.strip_suffix(')'))
+                    .filter(|columns| !columns.trim().is_empty())
+                    .ok_or_else(|| SchemaError::InvalidSchema(value.to_string()))?;
+                columns
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map(OutputSchema::Custom)
+            }
+        }
+    }
+}
+
+impl Display for OutputSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputSchema::V1 => f.write_str("v1"),
+            OutputSchema::V2 => f.write_str("v2"),
+            OutputSchema::Custom(columns) => {
+                let names: Vec<_> = columns.iter().map(Column::name).collect();
+                write!(f, "custom({})", names.join(","))
+            }
+        }
+    }
+}
+
+/// Writes the accounts as CSV records with the selected columns. The header is written with the first record, so
+/// nothing is written when there are no accounts.
+pub(crate) struct AccountWriter<W: Write> {
+    writer: csv::Writer<W>,
+    columns: Vec<Column>,
+    header_written: bool,
+}
+
+impl<W: Write> AccountWriter<W> {
+    pub(crate) fn new(writer: W, columns: Vec<Column>) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+            columns,
+            header_written: false,
+        }
+    }
+
+    pub(crate) fn write(&mut self, snapshot: &AccountSnapshot) -> csv::Result<()> {
+        if !self.header_written {
+            self.writer
+                .write_record(self.columns.iter().map(Column::name))?;
+            self.header_written = true;
+        }
+        let fields: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| column.field(snapshot))
+            .collect();
+        AmountFormat::output(|| self.writer.serialize(fields))
+    }
+
+    /// Flush the records and return the writer.
+    pub(crate) fn finish(self) -> std::io::Result<W> {
+        self.writer.into_inner().map_err(|err| err.into_error())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn into_inner(self) -> W {
+        self.writer
+            .into_inner()
+            .expect("Flushing a test buffer doesn't fail.")
+    }
+}
+
+/// Filters applied to the accounts before they are written out.
+/// All enabled filters must match for an account to be written.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AccountFilter {
+    /// Skip accounts that have no funds and are not locked.
+    pub(crate) omit_empty: bool,
+    /// Only write locked accounts.
+    pub(crate) only_locked: bool,
+    /// Only write accounts with a negative available or total balance.
+    pub(crate) only_negative: bool,
+}
+
+impl AccountFilter {
+    pub(crate) fn matches(&self, account: &Account) -> bool {
+        self.matches_balances(
+            account.available(),
+            account.held(),
+            account.total(),
+            account.is_locked(),
+        )
+    }
+
+    /// Whether an account that was read from a worker while it's running matches.
+    pub(crate) fn matches_snapshot(&self, snapshot: &AccountSnapshot) -> bool {
+        self.matches_balances(
+            snapshot.available,
+            snapshot.held,
+            snapshot.total,
+            snapshot.locked,
+        )
+    }
+
+    fn matches_balances(
+        &self,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    ) -> bool {
+        if self.omit_empty && total.is_zero() && held.is_zero() && !locked {
+            return false;
+        }
+
+        if self.only_locked && !locked {
+            return false;
+        }
+
+        if self.only_negative && !available.is_negative() && !total.is_negative() {
+            return false;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(held: f64, total: f64, locked: bool) -> Account {
+        Account::from_snapshot(
+            1.into(),
+            held.into(),
+            Amount::zero(),
+            Amount::zero(),
+            Amount::zero(),
+            total.into(),
+            locked,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn should_match_everything_by_default() {
+        let filter = AccountFilter::default();
+
+        assert!(filter.matches(&account(0.0, 0.0, false)));
+        assert!(filter.matches(&account(0.0, 10.0, true)));
+    }
+
+    #[test]
+    fn should_omit_empty_accounts() {
+        let filter = AccountFilter {
+            omit_empty: true,
+            ..Default::default()
+        };
+
+        assert!(!filter.matches(&account(0.0, 0.0, false)));
+        assert!(filter.matches(&account(0.0, 0.0, true)));
+        assert!(filter.matches(&account(0.0, 1.0, false)));
+    }
+
+    #[test]
+    fn should_only_match_locked_accounts() {
+        let filter = AccountFilter {
+            only_locked: true,
+            ..Default::default()
+        };
+
+        assert!(!filter.matches(&account(0.0, 10.0, false)));
+        assert!(filter.matches(&account(0.0, 10.0, true)));
+    }
+
+    #[test]
+    fn should_only_match_negative_accounts() {
+        let filter = AccountFilter {
+            only_negative: true,
+            ..Default::default()
+        };
+
+        assert!(!filter.matches(&account(0.0, 10.0, false)));
+        assert!(filter.matches(&account(0.0, -10.0, true)));
+        // Available is negative when more than the total is held.
+        assert!(filter.matches(&account(20.0, 10.0, false)));
+    }
+
+    fn write(schema: &str, optional: OutputColumns, accounts: &[Account]) -> String {
+        let schema: OutputSchema = schema.parse().unwrap();
+        let mut writer = AccountWriter::new(vec![], schema.columns(&optional));
+        for account in accounts {
+            writer.write(&account.snapshot()).unwrap();
+        }
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn should_only_write_enabled_columns() {
+        let accounts = [account(1.0, 3.0, false)];
+
+        assert_eq!(
+            write("v1", OutputColumns::default(), &accounts),
+            "client,available,held,total,locked\n1,2,1,3,false\n"
+        );
+        assert_eq!(
+            write(
+                "v1",
+                OutputColumns {
+                    account: true,
+                    escrow: true,
+                    pending: false,
+                    reserve: false,
+                },
+                &accounts
+            ),
+            "client,account,available,held,escrow,total,locked\n1,main,2,1,0,3,false\n"
+        );
+        assert_eq!(write("v1", OutputColumns::default(), &[]), "");
+    }
+
+    #[test]
+    fn should_write_the_columns_of_the_schema() {
+        let accounts = [account(1.0, 3.0, false), account(0.0, 5.0, true)];
+
+        assert_eq!(
+            write("v2", OutputColumns::default(), &accounts[..1]),
+            "client,account,available,held,escrow,total,locked\n1,main,2,1,0,3,false\n"
+        );
+        assert_eq!(
+            write(
+                "custom(locked, total,client)",
+                OutputColumns::default(),
+                &accounts
+            ),
+            "locked,total,client\nfalse,3,1\ntrue,5,1\n"
+        );
+    }
+
+    #[test]
+    fn should_parse_output_schemas() {
+        assert_eq!("v1".parse(), Ok(OutputSchema::V1));
+        assert_eq!(
+            "custom(client,held)".parse(),
+            Ok(OutputSchema::Custom(vec![Column::Client, Column::Held]))
+        );
+        assert_eq!(
+            "custom(client,balance)".parse::<OutputSchema>(),
+            Err(SchemaError::UnknownColumn("balance".to_string()))
+        );
+        assert_eq!(
+            "custom()".parse::<OutputSchema>(),
+            Err(SchemaError::InvalidSchema("custom()".to_string()))
+        );
+        assert_eq!(
+            "v3".parse::<OutputSchema>(),
+            Err(SchemaError::InvalidSchema("v3".to_string()))
+        );
+        assert_eq!(
+            OutputSchema::Custom(vec![Column::Total]).to_string(),
+            "custom(total)"
+        );
+    }
+
+    #[test]
+    fn should_combine_filters() {
+        let filter = AccountFilter {
+            only_locked: true,
+            only_negative: true,
+            ..Default::default()
+        };
+
+        assert!(!filter.matches(&account(0.0, -10.0, false)));
+        assert!(!filter.matches(&account(0.0, 10.0, true)));
+        assert!(filter.matches(&account(0.0, -10.0, true)));
+    }
+}
@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::{
+    account::AccountSnapshot,
+    engine::ShardedEngine,
+    logging::log_event,
+    state::{PeriodSnapshots, StateError},
+    transaction_processor::ProcessorMessage,
+};
+
+// Accounting periods are numbered from 1. Closing a period freezes the balances of all the accounts,
+// resets the period-scoped aggregates (e.g. the windows of the validation limits) and starts the next period.
+// The applied transactions are tagged with the period in which they were applied.
+
+#[derive(Debug, Error)]
+pub(crate) enum PeriodError {
+    #[error("Cannot write the period snapshot: {0}")]
+    State(#[from] StateError),
+    #[error("The engine is shutting down.")]
+    Unavailable,
+}
+
+/// The request sent through the worker queues to close the current period.
+/// Each worker replies with the closing balances of its accounts and starts the next period.
+#[derive(Debug)]
+pub(crate) struct ClosePeriodRequest {
+    pub(crate) next: u32,
+    pub(crate) reply: oneshot::Sender<Vec<AccountSnapshot>>,
+}
+
+/// The frozen balances of a closed period.
+#[derive(Debug, Serialize)]
+pub(crate) struct ClosedPeriod {
+    pub(crate) period: u32,
+    pub(crate) accounts: Vec<AccountSnapshot>,
+    /// The file in the state directory where the balances were written.
+    pub(crate) snapshot: Option<PathBuf>,
+}
+
+/// Keeps track of the current accounting period.
+pub(crate) struct Periods {
+    current: u32,
+    snapshots: Option<PeriodSnapshots>,
+}
+
+impl Periods {
+    /// Continue after the last period that was closed in the state directory, if any.
+    pub(crate) fn new(snapshots: Option<PeriodSnapshots>) -> Self {
+        let last_closed = snapshots
+            .as_ref()
+            .map_or(0, |snapshots| snapshots.last_closed());
+        Self {
+            current: last_closed + 1,
+            snapshots,
+        }
+    }
+
+    pub(crate) fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Close the current period. The request is queued behind the transactions that were already sent to the workers,
+    /// so the snapshot includes all of them and none of the transactions sent afterwards.
+    pub(crate) async fn close(
+        &mut self,
+        workers: &ShardedEngine,
+    ) -> Result<ClosedPeriod, PeriodError> {
+        let period = self.current;
+        let mut replies = Vec::new();
+        workers
+            .broadcast(|| {
+                let (reply, accounts) = oneshot::channel();
+                replies.push(accounts);
+                ProcessorMessage::ClosePeriod(ClosePeriodRequest {
+                    next: period + 1,
+                    reply,
+                })
+            })
+            .await
+            .map_err(|_| PeriodError::Unavailable)?;
+
+        let mut accounts = Vec::new();
+        for reply in replies {
+            accounts.extend(reply.await.map_err(|_| PeriodError::Unavailable)?);
+        }
+        accounts.sort_by(|a, b| (a.client, &a.account).cmp(&(b.client, &b.account)));
+
+        // The workers already started the next period at this point, but the period is only closed once its snapshot
+        // is written. If the write fails, closing again writes the snapshot of the same period.
+        let snapshot = match &mut self.snapshots {
+            Some(snapshots) => Some(snapshots.write(period, &accounts)?),
+            None => None,
+        };
+        self.current = period + 1;
+        log_event(
+            "period_closed",
+            &[
+                ("period", &period),
+                ("accounts", &accounts.len()),
+                (
+                    "snapshot",
+                    &snapshot
+                        .as_ref()
+                        .map(|path| path.display().to_string())
+                        .unwrap_or_default(),
+                ),
+            ],
+        );
+
+        Ok(ClosedPeriod {
+            period,
+            accounts,
+            snapshot,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        state::StateDir,
+        transaction_processor::{ProcessorOptions, TransactionProcessor},
+        transaction_types::{Transaction, TransactionType},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_close_period_and_continue_numbering_between_runs() {
+        let dir = TempDir::new().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let mut periods = Periods::new(Some(state.period_snapshots().unwrap()));
+        assert_eq!(periods.current(), 1);
+
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(TransactionProcessor::new(ProcessorOptions::default()).run(rx));
+        for (client, amount) in [(2u16, 1.5), (1, 3.0)] {
+            tx.send(ProcessorMessage::process_transaction(Transaction::new(
+                TransactionType::Deposit,
+                client.into(),
+                u32::from(client).into(),
+                Some(amount.into()),
+            )))
+            .await
+            .unwrap();
+        }
+
+        let closed = periods
+            .close(&ShardedEngine::new(vec![tx.clone()]))
+            .await
+            .unwrap();
+        assert_eq!(closed.period, 1);
+        assert_eq!(periods.current(), 2);
+        let clients: Vec<_> = closed.accounts.iter().map(|a| a.client).collect();
+        assert_eq!(clients, vec![1.into(), 2.into()]);
+        let snapshot = std::fs::read_to_string(closed.snapshot.unwrap()).unwrap();
+        assert_eq!(
+            snapshot,
+            "client,account,available,held,escrow,pending,reserve,total,locked\n1,main,3,0,0,0,0,3,false\n2,main,1.5,0,0,0,0,1.5,false\n"
+        );
+
+        tx.send(ProcessorMessage::shutdown()).await.unwrap();
+        worker.await.unwrap();
+
+        let periods = Periods::new(Some(state.period_snapshots().unwrap()));
+        assert_eq!(periods.current(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_period_open_when_the_snapshot_cannot_be_written() {
+        let dir = TempDir::new().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let mut periods = Periods::new(Some(state.period_snapshots().unwrap()));
+        // A snapshot of the period that is in the way, e.g. written by another engine.
+        std::fs::write(dir.path().join("periods/period-1.csv"), "").unwrap();
+
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(TransactionProcessor::new(ProcessorOptions::default()).run(rx));
+
+        assert!(
+            periods
+                .close(&ShardedEngine::new(vec![tx.clone()]))
+                .await
+                .is_err()
+        );
+        assert_eq!(periods.current(), 1);
+        assert!(!dir.path().join("periods/period-1.csv.tmp").exists());
+
+        tx.send(ProcessorMessage::shutdown()).await.unwrap();
+        worker.await.unwrap();
+    }
+}
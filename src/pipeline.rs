@@ -0,0 +1,987 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Display},
+};
+
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::{
+    blocklist::Blocklist,
+    client_trace::ClientTrace,
+    cluster::ShardMap,
+    engine::WorkerId,
+    enrichment::Currency,
+    id_allocator::IdRange,
+    logging::RecordLog,
+    metrics::Metrics,
+    outcome::{self, TransactionOutcome},
+    profiling::Profiler,
+    rejects::{RejectStage, RejectsReport},
+    summary::{self, Summary},
+    transaction_processor::ProcessorMessage,
+    transaction_types::{Amount, ClientId, Transaction, TransactionId, TransactionType},
+};
+
+// The processing of a transaction is split into three stages that are connected by channels:
+//   Parser -> Validator chain -> Applier
+// Each stage can be parallelized and instrumented independently of the others.
+
+/// First stage of the pipeline. Produces transactions out of some input.
+pub(crate) trait Parser {
+    type Error: Debug;
+
+    /// Returns an iterator over the parsed transactions.
+    fn transactions(&mut self) -> impl Iterator<Item = Result<Transaction, Self::Error>>;
+}
+
+/// Second stage of the pipeline. Checks a transaction before it's applied to an account.
+/// Validators look at the transaction and the recent activity of the client, the account state is checked by the applier.
+/// The activity is only known in the apply stage: the context is empty for the validators of the chain, the ones that
+/// look at it are `ActivityLimits`.
+pub(crate) trait Validator: Send {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        context: &ValidationContext,
+    ) -> Result<(), ValidationError>;
+}
+
+/// Last stage of the pipeline. Applies a valid transaction to the account state.
+pub(crate) trait Applier {
+    type Error: Display;
+
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), Self::Error>;
+}
+
+// A error describing why a transaction was rejected by the validator chain.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub(crate) enum ValidationError {
+    #[error("Client is blocked.")]
+    ClientBlocked,
+    #[error("An amount is required for this transaction type.")]
+    AmountRequired,
+    #[error("An amount is not allowed for this transaction type.")]
+    AmountNotAllowed,
+    #[error("Specified ammount is invalid.")]
+    InvalidAmount,
+    #[error("Specified amount exceeds the maximum transaction amount of {0}.")]
+    AmountTooLarge(Amount),
+    #[error("Withdrawals would exceed {max} in the last {window} transactions of the client.")]
+    WithdrawalLimitExceeded { max: Amount, window: usize },
+    #[error("More than {max} disputes in the last {window} transactions of the client.")]
+    TooManyDisputes { max: usize, window: usize },
+    #[error("The client belongs to shard {0}, which is not owned by this node.")]
+    ForeignShard(u16),
+    #[error("Transaction id {0} is reserved for the entries posted by the engine.")]
+    ReservedTransactionId(TransactionId),
+    #[error("The transaction is in {found}, the engine only processes {expected}.")]
+    CurrencyMismatch { expected: Currency, found: Currency },
+    #[error("'{0}' is not a currency code.")]
+    InvalidCurrency(String),
+    #[error(
+        "Transaction id {id}{} is not above {previous}{}, the previous transaction id of the client.",
+        on_line(.line),
+        on_line(.previous_line)
+    )]
+    OutOfOrder {
+        id: TransactionId,
+        line: Option<u64>,
+        previous: TransactionId,
+        previous_line: Option<u64>,
+    },
+}
+
+// Where a transaction is in the input, e.g. ` (line 12)`, if it was read from a file.
+fn on_line(line: &Option<u64>) -> String {
+    line.map(|line| format!(" (line {})", line))
+        .unwrap_or_default()
+}
+
+/// Default number of recent transactions of a client that are kept in the validation context.
+pub(crate) const DEFAULT_VALIDATION_WINDOW: usize = 100;
+
+/// A rolling window with the most recent applied transactions of a client.
+/// The activity limits maintain one context per client so that validators don't have to duplicate this state.
+#[derive(Debug, Default)]
+pub(crate) struct ValidationContext {
+    recent: VecDeque<(TransactionType, Option<Amount>)>,
+}
+
+impl ValidationContext {
+    /// Number of transactions in the window.
+    pub(crate) fn len(&self) -> usize {
+        self.recent.len()
+    }
+
+    /// Number of transactions of the given type in the window.
+    pub(crate) fn count(&self, transaction_type: TransactionType) -> usize {
+        self.recent
+            .iter()
+            .filter(|(recent_type, _)| *recent_type == transaction_type)
+            .count()
+    }
+
+    /// Sum of the amounts of the transactions of the given type in the window. Returns `None` if the sum overflows.
+    pub(crate) fn sum(&self, transaction_type: TransactionType) -> Option<Amount> {
+        self.recent
+            .iter()
+            .filter(|(recent_type, _)| *recent_type == transaction_type)
+            .filter_map(|(_, amount)| *amount)
+            .try_fold(Amount::zero(), Amount::checked_add)
+    }
+
+    fn record(&mut self, transaction: &Transaction, window: usize) {
+        if self.recent.len() == window {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back((transaction.transaction_type(), transaction.amount()));
+    }
+}
+
+/// Rejects all the transactions of blocked clients.
+pub(crate) struct BlocklistValidator {
+    blocklist: Blocklist,
+}
+
+impl BlocklistValidator {
+    pub(crate) fn new(blocklist: Blocklist) -> Self {
+        Self { blocklist }
+    }
+}
+
+impl Validator for BlocklistValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        if self.blocklist.contains(transaction.client()) {
+            Err(ValidationError::ClientBlocked)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Checks that an amount is specified only for deposits, withdrawals, moves and escrow holds and that it's not zero.
+/// Disputes can also have one if partial disputes are allowed, which the account checks against the disputed
+/// transaction.
+pub(crate) struct AmountValidator {
+    partial_disputes: bool,
+}
+
+impl Validator for AmountValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        match (transaction.transaction_type(), transaction.amount()) {
+            (
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Move
+                | TransactionType::EscrowHold,
+                None,
+            ) => Err(ValidationError::AmountRequired),
+            (
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Move
+                | TransactionType::EscrowHold,
+                Some(amount),
+            ) => {
+                // Zero amount funding transactions are just spam.
+                if amount.is_zero() {
+                    Err(ValidationError::InvalidAmount)
+                } else {
+                    Ok(())
+                }
+            }
+            (TransactionType::Dispute, Some(_)) if self.partial_disputes => Ok(()),
+            (
+                TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+                | TransactionType::EscrowRelease,
+                Some(_),
+            ) => Err(ValidationError::AmountNotAllowed),
+            (
+                TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+                | TransactionType::EscrowRelease,
+                None,
+            ) => Ok(()),
+        }
+    }
+}
+
+/// Rejects the upstream transactions that would take an id reserved for the entries posted by the engine. Disputes,
+/// resolves, chargebacks and escrow releases refer to an existing transaction, so they can use a reserved id.
+pub(crate) struct ReservedIdValidator {
+    reserved: IdRange,
+}
+
+impl ReservedIdValidator {
+    pub(crate) fn new(reserved: IdRange) -> Self {
+        Self { reserved }
+    }
+}
+
+impl Validator for ReservedIdValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        match transaction.transaction_type() {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Move
+            | TransactionType::EscrowHold
+                if self.reserved.contains(transaction.id().into()) =>
+            {
+                Err(ValidationError::ReservedTransactionId(transaction.id()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rejects the deposits, withdrawals, moves and escrow holds whose id is not above the id of the previous one of the
+/// client, which upstream systems usually guarantee. Disputes, resolves, chargebacks and escrow releases refer to an
+/// earlier transaction, so their ids are not checked. The previous ids are kept for the whole run.
+#[derive(Default)]
+pub(crate) struct OrderingValidator {
+    // The id of the last transaction of each client that was in order and the line it was read from.
+    last: HashMap<ClientId, (TransactionId, Option<u64>)>,
+}
+
+impl Validator for OrderingValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        if !transaction.transaction_type().is_funding() {
+            return Ok(());
+        }
+        let id = transaction.id();
+        match self.last.get(&transaction.client()) {
+            Some(&(previous, previous_line)) if u32::from(id) <= u32::from(previous) => {
+                Err(ValidationError::OutOfOrder {
+                    id,
+                    line: transaction.line(),
+                    previous,
+                    previous_line,
+                })
+            }
+            _ => {
+                self.last
+                    .insert(transaction.client(), (id, transaction.line()));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Rejects the transactions of clients whose shard is not owned by this node in cluster mode.
+pub(crate) struct ShardValidator {
+    shards: ShardMap,
+}
+
+impl ShardValidator {
+    pub(crate) fn new(shards: ShardMap) -> Self {
+        Self { shards }
+    }
+}
+
+impl Validator for ShardValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        if self.shards.owns(transaction.client()) {
+            Ok(())
+        } else {
+            Err(ValidationError::ForeignShard(
+                self.shards.shard(transaction.client()),
+            ))
+        }
+    }
+}
+
+/// Rejects the transactions in another currency than the one of the engine. Relies on the `Currency` attached by the
+/// `CurrencyNormalizer` enricher. Transactions without a currency are in the currency of the engine.
+pub(crate) struct CurrencyValidator {
+    expected: Currency,
+}
+
+impl CurrencyValidator {
+    pub(crate) fn new(expected: Currency) -> Self {
+        Self { expected }
+    }
+}
+
+impl Validator for CurrencyValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        let Some(raw) = transaction.currency() else {
+            return Ok(());
+        };
+        match transaction.extensions().get::<Currency>() {
+            Some(currency) if *currency == self.expected => Ok(()),
+            Some(currency) => Err(ValidationError::CurrencyMismatch {
+                expected: self.expected.clone(),
+                found: currency.clone(),
+            }),
+            None => Err(ValidationError::InvalidCurrency(raw.to_string())),
+        }
+    }
+}
+
+/// Rejects deposits and withdrawals above a configured amount.
+pub(crate) struct MaxAmountValidator {
+    max: Amount,
+}
+
+impl MaxAmountValidator {
+    pub(crate) fn new(max: Amount) -> Self {
+        Self { max }
+    }
+}
+
+impl Validator for MaxAmountValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        _context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        match transaction.amount() {
+            Some(amount) if amount > self.max => Err(ValidationError::AmountTooLarge(self.max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rejects withdrawals that would bring the total withdrawn by a client in the validation window above a limit.
+pub(crate) struct WithdrawalLimitValidator {
+    max: Amount,
+}
+
+impl WithdrawalLimitValidator {
+    pub(crate) fn new(max: Amount) -> Self {
+        Self { max }
+    }
+}
+
+impl Validator for WithdrawalLimitValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        if transaction.transaction_type() != TransactionType::Withdrawal {
+            return Ok(());
+        }
+
+        let withdrawn = context
+            .sum(TransactionType::Withdrawal)
+            .zip(transaction.amount())
+            .and_then(|(withdrawn, amount)| withdrawn.checked_add(amount));
+        match withdrawn {
+            Some(withdrawn) if withdrawn <= self.max => Ok(()),
+            _ => Err(ValidationError::WithdrawalLimitExceeded {
+                max: self.max,
+                window: context.len(),
+            }),
+        }
+    }
+}
+
+/// Rejects disputes of a client that already has the maximum number of disputes in the validation window.
+pub(crate) struct DisputeRateValidator {
+    max: usize,
+}
+
+impl DisputeRateValidator {
+    pub(crate) fn new(max: usize) -> Self {
+        Self { max }
+    }
+}
+
+impl Validator for DisputeRateValidator {
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        context: &ValidationContext,
+    ) -> Result<(), ValidationError> {
+        if transaction.transaction_type() == TransactionType::Dispute
+            && context.count(TransactionType::Dispute) >= self.max
+        {
+            return Err(ValidationError::TooManyDisputes {
+                max: self.max,
+                window: context.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The validators that look at the recent activity of the clients. Unlike the validator chain they run in the apply
+/// stage, right before a transaction is applied, and only the transactions that were applied are recorded in the
+/// contexts: e.g. a withdrawal refused for lack of funds doesn't count towards the withdrawal limit.
+pub(crate) struct ActivityLimits {
+    validators: Vec<Box<dyn Validator>>,
+    contexts: HashMap<ClientId, ValidationContext>,
+    window: usize,
+    log: RecordLog<ValidationError>,
+}
+
+impl ActivityLimits {
+    pub(crate) fn new() -> Self {
+        Self {
+            validators: Vec::new(),
+            contexts: HashMap::new(),
+            window: DEFAULT_VALIDATION_WINDOW,
+            log: RecordLog::new(),
+        }
+    }
+
+    /// Set the number of recent transactions of a client that are kept in the validation context.
+    pub(crate) fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Append a validator to the end of the limits.
+    pub(crate) fn with<V: Validator + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Check a transaction against the transactions of its client that were applied.
+    pub(crate) fn validate(&mut self, transaction: &Transaction) -> Result<(), ValidationError> {
+        let context = self.contexts.entry(transaction.client()).or_default();
+        self.validators
+            .iter_mut()
+            .try_for_each(|validator| validator.validate(transaction, context))
+    }
+
+    /// Record a transaction that was applied.
+    pub(crate) fn record(&mut self, transaction: &Transaction) {
+        self.contexts
+            .entry(transaction.client())
+            .or_default()
+            .record(transaction, self.window);
+    }
+
+    /// Start over, e.g. since the limits are scoped to the accounting period.
+    pub(crate) fn clear(&mut self) {
+        self.contexts.clear();
+    }
+
+    /// Whether a rejected transaction should be logged.
+    pub(crate) fn should_log(&mut self, err: &ValidationError) -> bool {
+        self.log.should_log(err)
+    }
+}
+
+/// A list of validators that are run in order. The first failing validator rejects the transaction.
+pub(crate) struct ValidatorChain {
+    validators: Vec<Box<dyn Validator>>,
+    rejects: Option<RejectsReport>,
+    summary: Summary,
+    metrics: Metrics,
+    profiler: Profiler,
+    log: RecordLog<ValidationError>,
+    // The worker the chain validates the transactions of, which is in its logs.
+    worker: WorkerId,
+    // Where the transactions of a traced client are recorded.
+    trace: Option<ClientTrace>,
+}
+
+impl ValidatorChain {
+    pub(crate) fn new() -> Self {
+        Self {
+            validators: Vec::new(),
+            rejects: None,
+            summary: Summary::default(),
+            metrics: Metrics::default(),
+            profiler: Profiler::disabled(),
+            log: RecordLog::new(),
+            worker: WorkerId::default(),
+            trace: None,
+        }
+    }
+
+    /// Validate the transactions of a worker of a sharded engine.
+    pub(crate) fn with_worker(mut self, worker: WorkerId) -> Self {
+        self.worker = worker;
+        self
+    }
+
+    /// Time the validation stage, including the time spent waiting on the queues before and after it.
+    pub(crate) fn with_profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    pub(crate) fn take_profiler(&mut self) -> Profiler {
+        std::mem::take(&mut self.profiler)
+    }
+
+    fn profiled_validate(&mut self, transaction: &Transaction) -> Result<(), ValidationError> {
+        self.profiler.enter("validate");
+        let validated = self.validate(transaction);
+        self.profiler.exit();
+        validated
+    }
+
+    /// Record the transactions of a client, and whether they passed, to its trace.
+    pub(crate) fn with_trace(mut self, trace: ClientTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Add the rejected transactions to a rejects report.
+    pub(crate) fn with_rejects(mut self, rejects: RejectsReport) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+
+    /// Count the rejected transactions in the exported metrics.
+    pub(crate) fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// A chain with the validators that are always enabled. The blocklist is checked first. Disputes can have an amount
+    /// if `partial_disputes` is set.
+    pub(crate) fn with_builtin_validators(blocklist: Blocklist, partial_disputes: bool) -> Self {
+        Self::new()
+            .with(BlocklistValidator::new(blocklist))
+            .with(AmountValidator { partial_disputes })
+    }
+
+    /// The counters of the rejected transactions.
+    pub(crate) fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Append a validator to the end of the chain.
+    pub(crate) fn with<V: Validator + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    pub(crate) fn validate(&mut self, transaction: &Transaction) -> Result<(), ValidationError> {
+        let context = ValidationContext::default();
+        self.validators
+            .iter_mut()
+            .try_for_each(|validator| validator.validate(transaction, &context))
+    }
+
+    // Run the validation stage. Valid transactions and control messages are forwarded to the next stage.
+    pub(crate) async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<ProcessorMessage>,
+        tx: mpsc::Sender<ProcessorMessage>,
+    ) -> Self {
+        self.profiler.enter("validation");
+        loop {
+            self.profiler.enter("recv");
+            let message = rx.recv().await;
+            self.profiler.exit();
+            let Some(message) = message else {
+                break;
+            };
+
+            let shutdown = matches!(message, ProcessorMessage::Shutdown);
+            let validated = match &message {
+                ProcessorMessage::ProcessTransaction(transaction) => {
+                    let validated = self.profiled_validate(transaction);
+                    if let Some(trace) = &self.trace
+                        && trace.traces(transaction)
+                    {
+                        trace.received(self.worker, transaction);
+                        trace.validated(
+                            self.worker,
+                            transaction,
+                            validated.as_ref().err().map(ToString::to_string),
+                        );
+                    }
+                    validated
+                }
+                _ => Ok(()),
+            };
+            if let ProcessorMessage::ProcessTransaction(transaction) = &message
+                && let Err(err) = validated
+            {
+                if self.log.should_log(&err) {
+                    eprintln!(
+                        "{}: Rejected transaction {} for client {}: {}",
+                        self.worker,
+                        transaction.id(),
+                        transaction.client(),
+                        err
+                    );
+                }
+                if let Some(rejects) = &self.rejects {
+                    rejects.record(transaction, RejectStage::Validation, &err);
+                }
+                outcome::report(transaction, || TransactionOutcome::rejected(&err));
+                self.summary
+                    .count_rejected(transaction.transaction_type(), &err);
+                self.metrics.count(
+                    "transactions.rejected",
+                    &[
+                        ("type", &transaction.transaction_type()),
+                        ("reason", &summary::reason_name(&err)),
+                    ],
+                    1,
+                );
+                if err == ValidationError::ClientBlocked {
+                    *self
+                        .summary
+                        .blocked
+                        .entry(transaction.client())
+                        .or_default() += 1;
+                }
+                continue;
+            }
+
+            self.profiler.enter("send");
+            let sent = tx.send(message).await;
+            self.profiler.exit();
+            if sent.is_err() {
+                eprintln!(
+                    "{}: Validation stage cannot forward messages: apply stage is gone.",
+                    self.worker
+                );
+                break;
+            }
+
+            if shutdown {
+                break;
+            }
+        }
+        self.profiler.exit();
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::enrichment::{CurrencyNormalizer, Enrichers};
+
+    use super::*;
+
+    #[test]
+    fn should_require_amount_for_deposits_and_withdrawals() {
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false);
+
+        let deposit = Transaction::new(TransactionType::Deposit, 1.into(), 1.into(), None);
+        let withdrawal = Transaction::new(TransactionType::Withdrawal, 1.into(), 2.into(), None);
+
+        assert_eq!(
+            chain.validate(&deposit),
+            Err(ValidationError::AmountRequired)
+        );
+        assert_eq!(
+            chain.validate(&withdrawal),
+            Err(ValidationError::AmountRequired)
+        );
+    }
+
+    #[test]
+    fn should_reject_amounts_on_dispute_records() {
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false);
+
+        let dispute = Transaction::new(
+            TransactionType::Dispute,
+            1.into(),
+            1.into(),
+            Some(1.0.into()),
+        );
+
+        assert_eq!(
+            chain.validate(&dispute),
+            Err(ValidationError::AmountNotAllowed)
+        );
+    }
+
+    #[test]
+    fn should_reject_zero_amounts() {
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false);
+
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(0.0.into()),
+        );
+
+        assert_eq!(
+            chain.validate(&deposit),
+            Err(ValidationError::InvalidAmount)
+        );
+    }
+
+    #[test]
+    fn should_accept_valid_transactions() {
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false);
+
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(1.5.into()),
+        );
+        let resolve = Transaction::new(TransactionType::Resolve, 1.into(), 1.into(), None);
+
+        assert!(chain.validate(&deposit).is_ok());
+        assert!(chain.validate(&resolve).is_ok());
+    }
+
+    #[test]
+    fn should_reject_transactions_whose_id_is_not_above_the_previous_one_of_the_client() {
+        let mut chain = ValidatorChain::new().with(OrderingValidator::default());
+        let deposit = |client: u16, tx: u32, line: u64| {
+            Transaction::new(
+                TransactionType::Deposit,
+                client.into(),
+                tx.into(),
+                Some(1.0.into()),
+            )
+            .with_line(Some(line))
+        };
+
+        assert!(chain.validate(&deposit(1, 5, 2)).is_ok());
+        assert!(chain.validate(&deposit(2, 3, 3)).is_ok());
+        let reordered = chain.validate(&deposit(1, 4, 4)).unwrap_err();
+        assert_eq!(
+            reordered.to_string(),
+            "Transaction id 4 (line 4) is not above 5 (line 2), the previous transaction id of the client."
+        );
+        assert!(chain.validate(&deposit(1, 5, 5)).is_err());
+        // Disputes refer to earlier transactions.
+        let dispute = Transaction::new(TransactionType::Dispute, 1.into(), 5.into(), None);
+        assert!(chain.validate(&dispute).is_ok());
+        assert!(chain.validate(&deposit(1, 6, 7)).is_ok());
+    }
+
+    #[test]
+    fn should_reject_upstream_transactions_with_reserved_ids() {
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false)
+            .with(ReservedIdValidator::new(IdRange::new(1000, 2000).unwrap()));
+
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1500.into(),
+            Some(1.0.into()),
+        );
+        let dispute = Transaction::new(TransactionType::Dispute, 1.into(), 1500.into(), None);
+
+        assert_eq!(
+            chain.validate(&deposit),
+            Err(ValidationError::ReservedTransactionId(1500.into()))
+        );
+        assert!(chain.validate(&dispute).is_ok());
+    }
+
+    #[test]
+    fn should_reject_transactions_in_another_currency() {
+        let enrichers = Enrichers::default().with(CurrencyNormalizer);
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false)
+            .with(CurrencyValidator::new("USD".parse().unwrap()));
+        let mut deposit = |id: u32, currency: Option<&str>| {
+            let mut deposit = Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            )
+            .with_currency(currency);
+            enrichers.enrich(&mut deposit);
+            chain.validate(&deposit)
+        };
+
+        assert!(deposit(1, None).is_ok());
+        assert!(deposit(2, Some(" usd")).is_ok());
+        assert_eq!(
+            deposit(3, Some("EUR")),
+            Err(ValidationError::CurrencyMismatch {
+                expected: "USD".parse().unwrap(),
+                found: "EUR".parse().unwrap()
+            })
+        );
+        assert_eq!(
+            deposit(4, Some("dollars")),
+            Err(ValidationError::InvalidCurrency("dollars".to_string()))
+        );
+    }
+
+    #[test]
+    fn should_reject_amounts_above_maximum() {
+        let mut chain = ValidatorChain::with_builtin_validators(Blocklist::default(), false)
+            .with(MaxAmountValidator::new(100.0.into()));
+
+        let deposit = |amount: f64| {
+            Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                1.into(),
+                Some(amount.into()),
+            )
+        };
+
+        assert!(chain.validate(&deposit(100.0)).is_ok());
+        assert_eq!(
+            chain.validate(&deposit(100.0001)),
+            Err(ValidationError::AmountTooLarge(100.0.into()))
+        );
+    }
+
+    #[test]
+    fn should_keep_rolling_context_per_client() {
+        let mut limits = ActivityLimits::new().with_window(3);
+
+        for (id, amount) in [(1, 1.0), (2, 2.0), (3, 3.0), (4, 4.0)] {
+            let deposit = Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(amount.into()),
+            );
+            limits.validate(&deposit).unwrap();
+            limits.record(&deposit);
+        }
+        let dispute = Transaction::new(TransactionType::Dispute, 2.into(), 5.into(), None);
+        limits.validate(&dispute).unwrap();
+        limits.record(&dispute);
+        // A transaction that is not applied is not recorded.
+        let refused = Transaction::new(TransactionType::Dispute, 1.into(), 6.into(), None);
+        limits.validate(&refused).unwrap();
+
+        let context = &limits.contexts[&1.into()];
+        assert_eq!(context.len(), 3);
+        assert_eq!(context.count(TransactionType::Deposit), 3);
+        assert_eq!(context.sum(TransactionType::Deposit), Some(9.0.into()));
+        assert_eq!(context.count(TransactionType::Dispute), 0);
+        assert_eq!(
+            limits.contexts[&2.into()].count(TransactionType::Dispute),
+            1
+        );
+    }
+
+    // Validate a transaction and record it if it passes, as if it was applied.
+    fn apply(
+        limits: &mut ActivityLimits,
+        transaction: &Transaction,
+    ) -> Result<(), ValidationError> {
+        limits.validate(transaction)?;
+        limits.record(transaction);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_disputes_above_rate() {
+        let mut limits = ActivityLimits::new()
+            .with(DisputeRateValidator::new(2))
+            .with_window(4);
+
+        let dispute =
+            |id: u32| Transaction::new(TransactionType::Dispute, 1.into(), id.into(), None);
+
+        assert!(apply(&mut limits, &dispute(1)).is_ok());
+        assert!(apply(&mut limits, &dispute(2)).is_ok());
+        assert_eq!(
+            apply(&mut limits, &dispute(3)),
+            Err(ValidationError::TooManyDisputes { max: 2, window: 2 })
+        );
+
+        // Once the disputes fall out of the window, the client can dispute again.
+        for id in 4..7 {
+            let deposit = Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            );
+            apply(&mut limits, &deposit).unwrap();
+        }
+        assert!(apply(&mut limits, &dispute(3)).is_ok());
+    }
+
+    #[test]
+    fn should_reject_withdrawals_above_window_limit() {
+        let mut limits = ActivityLimits::new()
+            .with(WithdrawalLimitValidator::new(10.0.into()))
+            .with_window(2);
+
+        let withdrawal = |id: u32, amount: f64| {
+            Transaction::new(
+                TransactionType::Withdrawal,
+                1.into(),
+                id.into(),
+                Some(amount.into()),
+            )
+        };
+
+        assert!(apply(&mut limits, &withdrawal(1, 6.0)).is_ok());
+        assert_eq!(
+            apply(&mut limits, &withdrawal(2, 5.0)),
+            Err(ValidationError::WithdrawalLimitExceeded {
+                max: 10.0.into(),
+                window: 1
+            })
+        );
+        assert!(apply(&mut limits, &withdrawal(3, 4.0)).is_ok());
+
+        // The first withdrawal falls out of the window.
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            4.into(),
+            Some(1.0.into()),
+        );
+        apply(&mut limits, &deposit).unwrap();
+        assert!(apply(&mut limits, &withdrawal(5, 6.0)).is_ok());
+    }
+
+    #[test]
+    fn should_reject_blocked_clients_first() {
+        let blocklist = Blocklist::default();
+        let mut chain = ValidatorChain::with_builtin_validators(blocklist.clone(), false);
+
+        // A malformed transaction of a blocked client is reported as blocked.
+        let deposit = Transaction::new(TransactionType::Deposit, 1.into(), 1.into(), None);
+        blocklist.insert(1.into());
+        assert_eq!(
+            chain.validate(&deposit),
+            Err(ValidationError::ClientBlocked)
+        );
+
+        blocklist.remove(1.into());
+        assert_eq!(
+            chain.validate(&deposit),
+            Err(ValidationError::AmountRequired)
+        );
+    }
+}
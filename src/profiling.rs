@@ -0,0 +1,170 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// Lightweight timing scopes for finding out where a run spends its time without attaching a profiler.
+// Each task owns its profiler, so there's no synchronization on the hot path. The time of every scope is attributed
+// to the stack of scopes that were open when it ran, minus the time of its nested scopes, and the totals are written
+// in the folded stack format (`reader;parse 1234`, in microseconds) that flame graph tools like `inferno` or
+// `flamegraph.pl` read directly.
+
+// An open scope.
+#[derive(Debug)]
+struct Frame {
+    name: &'static str,
+    started: Instant,
+    // Time spent in the nested scopes, which is not counted as time of this scope.
+    nested: Duration,
+}
+
+/// Timing scopes of a task. A disabled profiler does nothing, so the scopes can stay in place when profiling is off.
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    enabled: bool,
+    // The name of the task, used as the outermost scope of every stack.
+    root: String,
+    stack: Vec<Frame>,
+    // The time spent directly in each stack of scopes, keyed by the `;` separated scope names.
+    folded: BTreeMap<String, Duration>,
+}
+
+impl Profiler {
+    /// A profiler whose stacks start with the name of the task (e.g. `worker-0`).
+    pub(crate) fn new(root: impl Into<String>) -> Self {
+        Self {
+            enabled: true,
+            root: root.into(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Open a scope nested in the currently open one.
+    pub(crate) fn enter(&mut self, name: &'static str) {
+        if self.enabled {
+            self.stack.push(Frame {
+                name,
+                started: Instant::now(),
+                nested: Duration::ZERO,
+            });
+        }
+    }
+
+    /// Close the scope that was opened last.
+    pub(crate) fn exit(&mut self) {
+        if let Some(frame) = self.stack.last() {
+            let elapsed = frame.started.elapsed();
+            self.add(None, elapsed.saturating_sub(frame.nested));
+            self.stack.pop();
+            if let Some(parent) = self.stack.last_mut() {
+                parent.nested += elapsed;
+            }
+        }
+    }
+
+    /// Add time that was measured elsewhere as a scope nested in the currently open one,
+    /// e.g. the time spent in the transaction store during an operation.
+    pub(crate) fn record(&mut self, name: &'static str, elapsed: Duration) {
+        if self.enabled && !elapsed.is_zero() {
+            self.add(Some(name), elapsed);
+            if let Some(parent) = self.stack.last_mut() {
+                parent.nested += elapsed;
+            }
+        }
+    }
+
+    fn add(&mut self, leaf: Option<&'static str>, elapsed: Duration) {
+        let names = self.stack.iter().map(|frame| frame.name).chain(leaf);
+        let key = std::iter::once(self.root.as_str())
+            .chain(names)
+            .collect::<Vec<_>>()
+            .join(";");
+        *self.folded.entry(key).or_default() += elapsed;
+    }
+
+    /// Add the scopes of another task, e.g. the validation stage of the same worker.
+    pub(crate) fn merge(&mut self, other: Profiler) {
+        for (stack, elapsed) in other.into_folded() {
+            *self.folded.entry(stack).or_default() += elapsed;
+        }
+    }
+
+    // The totals of all the scopes, including the ones that are still open.
+    fn into_folded(mut self) -> BTreeMap<String, Duration> {
+        while !self.stack.is_empty() {
+            self.exit();
+        }
+        self.folded
+    }
+
+    /// Write the totals in the folded stack format, one line per stack of scopes.
+    pub(crate) fn write_folded<W: Write>(self, mut writer: W) -> io::Result<()> {
+        for (stack, elapsed) in self.into_folded() {
+            writeln!(writer, "{} {}", stack, elapsed.as_micros())?;
+        }
+        writer.flush()
+    }
+
+    /// Write the totals to `<name>.folded` in the given directory.
+    pub(crate) fn write_to_dir<P: AsRef<Path>>(self, dir: P, name: &str) -> io::Result<()> {
+        let file = File::create(dir.as_ref().join(format!("{}.folded", name)))?;
+        self.write_folded(BufWriter::new(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folded(profiler: Profiler) -> Vec<String> {
+        let mut output = Vec::new();
+        profiler.write_folded(&mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn should_fold_nested_scopes() {
+        let mut profiler = Profiler::new("worker-0");
+        profiler.enter("processing");
+        profiler.enter("apply");
+        profiler.record("store", Duration::from_millis(5));
+        profiler.exit();
+        profiler.exit();
+
+        let mut validation = Profiler::new("worker-0");
+        validation.enter("validation");
+        validation.exit();
+        profiler.merge(validation);
+
+        assert_eq!(
+            folded(profiler),
+            vec![
+                "worker-0;processing",
+                "worker-0;processing;apply",
+                "worker-0;processing;apply;store",
+                "worker-0;validation",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_not_record_when_disabled() {
+        let mut profiler = Profiler::disabled();
+        profiler.enter("apply");
+        profiler.record("store", Duration::from_millis(5));
+        profiler.exit();
+
+        assert!(folded(profiler).is_empty());
+    }
+}
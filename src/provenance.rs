@@ -0,0 +1,99 @@
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    sync::OnceLock,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::cli::Cli;
+
+// Which binary produced an output, for audits: the version of the engine, the optional features it was built with and
+// a hash of the options of the run. The run manifest always records it. With `--provenance` the outputs start with a
+// comment line that records it too, so it travels with the file. The outputs that are read back by the engine (the
+// rejects and dead letters, the history archive) don't get the comment, and the readers of the account outputs
+// (`--bootstrap`, `merge`) skip comment lines.
+//
+// Whether the comment is written is a process wide setting, like the amount format, so that the writers of the outputs
+// don't each have to be told.
+
+// The optional features of the build.
+const FEATURES: [(&str, bool); 5] = [
+    ("object_store", cfg!(feature = "object_store")),
+    ("rdkafka", cfg!(feature = "rdkafka")),
+    ("rocksdb", cfg!(feature = "rocksdb")),
+    ("sled", cfg!(feature = "sled")),
+    ("tokio-postgres", cfg!(feature = "tokio-postgres")),
+];
+
+static PROVENANCE: OnceLock<Provenance> = OnceLock::new();
+
+/// The version and the configuration of the engine that produced an output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Provenance {
+    version: &'static str,
+    features: Vec<&'static str>,
+    /// The SHA-256 hash of the options of the run.
+    config_sha256: String,
+}
+
+impl Provenance {
+    pub(crate) fn of(cli: &Cli) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| *feature)
+                .collect(),
+            config_sha256: format!("{:x}", Sha256::digest(format!("{:?}", cli))),
+        }
+    }
+
+    /// Start the outputs with a comment line that records this provenance. Can only be set once, at startup.
+    pub(crate) fn set_global(self) -> Result<(), Provenance> {
+        PROVENANCE.set(self)
+    }
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "payments-engine {}", self.version)?;
+        if !self.features.is_empty() {
+            write!(f, " features={}", self.features.join(","))?;
+        }
+        write!(f, " config-sha256={}", self.config_sha256)
+    }
+}
+
+/// Write the provenance comment line, starting with `prefix` (e.g. `#`), if the outputs get one.
+pub(crate) fn write_comment<W: Write>(writer: &mut W, prefix: &str) -> io::Result<()> {
+    match PROVENANCE.get() {
+        Some(provenance) => writeln!(writer, "{} {}", prefix, provenance),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    #[test]
+    fn should_identify_the_engine_and_the_options() {
+        let cli = Cli::try_parse_from(["payments-engine", "input.csv"]).unwrap();
+        let other = Cli::try_parse_from(["payments-engine", "input.csv", "--quiet"]).unwrap();
+        let provenance = Provenance::of(&cli);
+
+        assert_eq!(provenance, Provenance::of(&cli));
+        assert_ne!(
+            provenance.config_sha256,
+            Provenance::of(&other).config_sha256
+        );
+        let comment = provenance.to_string();
+        assert!(comment.starts_with(&format!("payments-engine {} ", env!("CARGO_PKG_VERSION"))));
+        assert!(comment.ends_with(&format!("config-sha256={}", provenance.config_sha256)));
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::{account::Account, transaction_types::Amount};
+
+// Engine-wide aggregates of the accounts for the live dashboards of the daemon. The accounts are owned by the workers,
+// so the totals over all of them used to be computed by joining the workers at the end of the run. Instead, every
+// worker keeps the totals of its own accounts up to date in a slot of the registry as it applies transactions, and
+// reading the registry adds up the slots. Nothing is stopped or queued behind the transactions to read it.
+//
+// A slot is only written by its worker, so its lock is never contended by the workers, only briefly by the readers. The
+// totals of a worker are always those of a state its accounts were in, but the slots are read one after the other, so
+// the sum may mix states a few transactions apart.
+
+/// The balances of one account, as far as the registry is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Balances {
+    available: Amount,
+    held: Amount,
+    escrow: Amount,
+    pending: Amount,
+    reserve: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl Balances {
+    pub(crate) fn of(account: &Account) -> Self {
+        Self {
+            available: account.available(),
+            held: account.held(),
+            escrow: account.escrow(),
+            pending: account.pending(),
+            reserve: account.reserve(),
+            total: account.total(),
+            locked: account.is_locked(),
+        }
+    }
+}
+
+/// Totals over a set of accounts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct AccountTotals {
+    pub(crate) accounts: u64,
+    pub(crate) locked: u64,
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+    pub(crate) escrow: Amount,
+    pub(crate) pending: Amount,
+    pub(crate) reserve: Amount,
+    pub(crate) total: Amount,
+}
+
+impl AccountTotals {
+    fn add(&mut self, balances: &Balances) {
+        self.locked += u64::from(balances.locked);
+        self.available = self.available.saturating_add(balances.available);
+        self.held = self.held.saturating_add(balances.held);
+        self.escrow = self.escrow.saturating_add(balances.escrow);
+        self.pending = self.pending.saturating_add(balances.pending);
+        self.reserve = self.reserve.saturating_add(balances.reserve);
+        self.total = self.total.saturating_add(balances.total);
+    }
+
+    fn remove(&mut self, balances: &Balances) {
+        self.locked -= u64::from(balances.locked);
+        self.available = self.available.saturating_sub(balances.available);
+        self.held = self.held.saturating_sub(balances.held);
+        self.escrow = self.escrow.saturating_sub(balances.escrow);
+        self.pending = self.pending.saturating_sub(balances.pending);
+        self.reserve = self.reserve.saturating_sub(balances.reserve);
+        self.total = self.total.saturating_sub(balances.total);
+    }
+
+    fn merge(&mut self, other: &AccountTotals) {
+        self.accounts += other.accounts;
+        self.locked += other.locked;
+        self.available = self.available.saturating_add(other.available);
+        self.held = self.held.saturating_add(other.held);
+        self.escrow = self.escrow.saturating_add(other.escrow);
+        self.pending = self.pending.saturating_add(other.pending);
+        self.reserve = self.reserve.saturating_add(other.reserve);
+        self.total = self.total.saturating_add(other.total);
+    }
+}
+
+/// The totals of the accounts of all the workers. Clones share the same slots.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AccountRegistry {
+    slots: Arc<Mutex<Vec<Arc<Mutex<AccountTotals>>>>>,
+}
+
+impl AccountRegistry {
+    /// Add the slot of a worker.
+    pub(crate) fn worker(&self) -> RegistrySlot {
+        let slot = Arc::new(Mutex::new(AccountTotals::default()));
+        self.slots
+            .lock()
+            .expect("Registry lock is never poisoned.")
+            .push(Arc::clone(&slot));
+        RegistrySlot(slot)
+    }
+
+    /// The totals of the accounts of all the workers.
+    pub(crate) fn totals(&self) -> AccountTotals {
+        let slots = self.slots.lock().expect("Registry lock is never poisoned.");
+        let mut totals = AccountTotals::default();
+        for slot in slots.iter() {
+            totals.merge(&slot.lock().expect("Registry lock is never poisoned."));
+        }
+        totals
+    }
+}
+
+/// The totals of the accounts of one worker, which only that worker updates.
+#[derive(Debug)]
+pub(crate) struct RegistrySlot(Arc<Mutex<AccountTotals>>);
+
+impl RegistrySlot {
+    /// Record the new balances of an account. `before` is `None` for an account that was just created.
+    pub(crate) fn update(&self, before: Option<Balances>, after: Balances) {
+        if before == Some(after) {
+            return;
+        }
+        let mut totals = self.0.lock().expect("Registry lock is never poisoned.");
+        match before {
+            Some(before) => totals.remove(&before),
+            None => totals.accounts += 1,
+        }
+        totals.add(&after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balances(available: f64, held: f64, locked: bool) -> Balances {
+        Balances {
+            available: available.into(),
+            held: held.into(),
+            escrow: Amount::zero(),
+            pending: Amount::zero(),
+            reserve: Amount::zero(),
+            total: (available + held).into(),
+            locked,
+        }
+    }
+
+    #[test]
+    fn should_add_up_the_totals_of_the_workers() {
+        let registry = AccountRegistry::default();
+        let (first, second) = (registry.worker(), registry.worker());
+
+        first.update(None, balances(10.0, 0.0, false));
+        first.update(Some(balances(10.0, 0.0, false)), balances(4.0, 6.0, false));
+        second.update(None, balances(5.0, 0.0, false));
+        second.update(None, balances(0.0, 0.0, false));
+        second.update(Some(balances(5.0, 0.0, false)), balances(0.0, 0.0, true));
+
+        assert_eq!(
+            registry.totals(),
+            AccountTotals {
+                accounts: 3,
+                locked: 1,
+                available: 4.0.into(),
+                held: 6.0.into(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: 10.0.into(),
+            }
+        );
+    }
+}
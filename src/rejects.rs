@@ -0,0 +1,269 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    account::{AccountError, InternalError},
+    cluster::ForwardError,
+    pipeline::ValidationError,
+    transaction_types::Transaction,
+};
+
+/// A standard-ish ISO 8583 authorization response code for a rejection reason (e.g. `51` for insufficient funds).
+pub(crate) trait ResponseCode {
+    fn response_code(&self) -> &'static str;
+}
+
+impl ResponseCode for ValidationError {
+    fn response_code(&self) -> &'static str {
+        match self {
+            // Restricted card.
+            ValidationError::ClientBlocked => "62",
+            // Invalid amount.
+            ValidationError::AmountRequired
+            | ValidationError::AmountNotAllowed
+            | ValidationError::InvalidAmount => "13",
+            // Exceeds withdrawal amount limit.
+            ValidationError::AmountTooLarge(_)
+            | ValidationError::WithdrawalLimitExceeded { .. } => "61",
+            // Exceeds withdrawal frequency limit.
+            ValidationError::TooManyDisputes { .. } => "65",
+            // No such issuer: the client is handled by another node of the cluster.
+            ValidationError::ForeignShard(_) => "15",
+            // Invalid transaction: the id is reserved for the entries posted by the engine.
+            ValidationError::ReservedTransactionId(_) => "12",
+            // Invalid transaction: the engine doesn't process this currency.
+            ValidationError::CurrencyMismatch { .. } => "12",
+            // Format error: the currency is not a currency code.
+            ValidationError::InvalidCurrency(_) => "30",
+            // Invalid transaction: upstream sent the transactions of the client out of order.
+            ValidationError::OutOfOrder { .. } => "12",
+        }
+    }
+}
+
+impl ResponseCode for AccountError {
+    fn response_code(&self) -> &'static str {
+        match self {
+            // Restricted card.
+            AccountError::AccountLocked => "62",
+            // Insufficient funds.
+            AccountError::InsufficientFunds => "51",
+            // Exceeds amount limit.
+            AccountError::DepositLimitReached
+            | AccountError::BalanceLimitExceeded(_)
+            | AccountError::BalanceOutOfRange => "61",
+            // Transaction not permitted to cardholder.
+            AccountError::DepositsSuspended | AccountError::WithdrawalDisputeNotSupported => "57",
+            // Unable to locate record.
+            AccountError::TransactionMissing => "25",
+            // No such account.
+            AccountError::UnknownClient => "14",
+            // Invalid transaction.
+            AccountError::TransactionCannotBeDisputed
+            | AccountError::DisputeWindowExpired(_)
+            | AccountError::TransactionNotDisputed
+            | AccountError::DisputeAlreadyResolved
+            | AccountError::TransactionWasChargedBack
+            | AccountError::TransactionNotChargedBack
+            | AccountError::ChargebackAlreadyReversed
+            | AccountError::StaleDisputeState { .. }
+            | AccountError::InvalidMove
+            | AccountError::TransactionNotInEscrow
+            | AccountError::EscrowAlreadyReleased
+            | AccountError::EscrowPartyRequired
+            | AccountError::DisputeSourceRequired
+            | AccountError::DisputeSourceMismatch { .. } => "12",
+            // Duplicate transmission.
+            AccountError::DuplicateTransaction | AccountError::TransactionIdReused(_) => "94",
+            // Invalid amount.
+            AccountError::InvalidAmount => "13",
+            // System malfunction.
+            AccountError::Internal(InternalError::TransactionCache(_)) => "96",
+            // Issuer or switch inoperative: the transaction can be sent again later.
+            AccountError::Internal(InternalError::ProcessingPaused)
+            | AccountError::DiskLookupThrottled(_) => "91",
+        }
+    }
+}
+
+impl ResponseCode for ForwardError {
+    fn response_code(&self) -> &'static str {
+        // Issuer or switch inoperative: the peer that owns the client can't be reached.
+        "91"
+    }
+}
+
+/// The stage of the pipeline that rejected a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RejectStage {
+    Validation,
+    Apply,
+    /// Not processed because of a failure of the engine, see `AccountError::Internal`.
+    Internal,
+    /// Not applied to protect the transaction store, see `ProcessorOptions::max_disk_lookups`.
+    Throttled,
+    /// Not delivered to the peer that owns the client in cluster mode.
+    Forward,
+}
+
+impl RejectStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RejectStage::Validation => "validation",
+            RejectStage::Apply => "apply",
+            RejectStage::Internal => "internal",
+            RejectStage::Throttled => "throttled",
+            RejectStage::Forward => "forward",
+        }
+    }
+}
+
+/// A CSV report with the transactions that were rejected by the validator chain or could not be applied.
+/// Clones of the report write to the same file so that every worker can have its own clone.
+#[derive(Clone)]
+pub(crate) struct RejectsReport {
+    writer: Arc<Mutex<csv::Writer<Box<dyn Write + Send>>>>,
+    response_codes: bool,
+}
+
+impl RejectsReport {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, response_codes: bool) -> io::Result<Self> {
+        Self::new(Box::new(File::create(path)?), response_codes)
+    }
+
+    fn new(writer: Box<dyn Write + Send>, response_codes: bool) -> io::Result<Self> {
+        let mut writer = csv::Writer::from_writer(writer);
+        let mut header = vec!["type", "client", "tx", "amount", "stage", "reason"];
+        if response_codes {
+            header.push("response_code");
+        }
+        writer.write_record(header)?;
+
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            response_codes,
+        })
+    }
+
+    /// Add a rejected transaction to the report.
+    pub(crate) fn record<E>(&self, transaction: &Transaction, stage: RejectStage, reason: &E)
+    where
+        E: std::fmt::Display + ResponseCode,
+    {
+        let transaction_type = transaction.transaction_type().to_string();
+        let client = transaction.client().to_string();
+        let tx = transaction.id().to_string();
+        let amount = transaction
+            .amount()
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        let reason_text = reason.to_string();
+        let mut record = vec![
+            transaction_type.as_str(),
+            &client,
+            &tx,
+            &amount,
+            stage.as_str(),
+            &reason_text,
+        ];
+        if self.response_codes {
+            record.push(reason.response_code());
+        }
+
+        let mut writer = self.writer.lock().expect("Rejects lock is never poisoned.");
+        if let Err(err) = writer.write_record(record) {
+            eprintln!(
+                "Cannot write transaction {} to the rejects report: {}",
+                tx, err
+            );
+        }
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .expect("Rejects lock is never poisoned.")
+            .flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_types::TransactionType;
+
+    use super::*;
+
+    // A writer that can be inspected after the report was written.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_write_response_codes_when_enabled() {
+        let buffer = SharedBuffer::default();
+        let report = RejectsReport::new(Box::new(buffer.clone()), true).unwrap();
+
+        let withdrawal = Transaction::new(
+            TransactionType::Withdrawal,
+            1.into(),
+            2.into(),
+            Some(10.0.into()),
+        );
+        let dispute = Transaction::new(TransactionType::Dispute, 1.into(), 3.into(), None);
+        report.record(
+            &withdrawal,
+            RejectStage::Apply,
+            &AccountError::InsufficientFunds,
+        );
+        report.record(
+            &dispute,
+            RejectStage::Validation,
+            &ValidationError::TooManyDisputes { max: 1, window: 5 },
+        );
+        report.flush().unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "type,client,tx,amount,stage,reason,response_code
+withdrawal,1,2,10,apply,Account has insufficient funds to satisfy this transaction.,51
+dispute,1,3,,validation,More than 1 disputes in the last 5 transactions of the client.,65
+"
+        );
+    }
+
+    #[test]
+    fn should_omit_response_codes_by_default() {
+        let buffer = SharedBuffer::default();
+        let report = RejectsReport::new(Box::new(buffer.clone()), false).unwrap();
+
+        let deposit = Transaction::new(TransactionType::Deposit, 1.into(), 1.into(), None);
+        report.record(
+            &deposit,
+            RejectStage::Validation,
+            &ValidationError::AmountRequired,
+        );
+        report.flush().unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "type,client,tx,amount,stage,reason
+deposit,1,1,,validation,An amount is required for this transaction type.
+"
+        );
+    }
+}
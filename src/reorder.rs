@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::transaction_types::{AccountName, ClientId, Transaction, TransactionType};
+
+// Streaming sources sometimes deliver a dispute a few events before the deposit it references, because the two were
+// produced by different upstream systems. Instead of rejecting such a dispute right away, it's parked with its account
+// and tried again after every later transaction of the account, until the transaction it references arrived or it
+// waited too long. The other dispute operations of a transaction that has a parked operation are parked behind it,
+// so a resolve never overtakes its dispute. The buffer of an account is bounded by the number of transactions an
+// operation waits for: an account that has that many operations waiting gets the next ones rejected right away.
+
+/// How long a dispute operation waits for the transaction it references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReorderWindow {
+    /// The number of later transactions of the account after which the operation is rejected.
+    pub(crate) transactions: usize,
+    /// How long after it arrived the operation is rejected, if set.
+    pub(crate) timeout: Option<TimeDelta>,
+}
+
+/// A dispute operation waiting for the transaction it references.
+pub(crate) struct Parked {
+    pub(crate) transaction: Transaction,
+    parked_at: DateTime<Utc>,
+    // The number of transactions of the account that arrived since.
+    waited: usize,
+}
+
+/// The dispute operations that wait for their transaction, by account.
+pub(crate) struct ReorderBuffer {
+    window: ReorderWindow,
+    parked: BTreeMap<(ClientId, AccountName), VecDeque<Parked>>,
+}
+
+impl ReorderBuffer {
+    pub(crate) fn new(window: ReorderWindow) -> Self {
+        Self {
+            window,
+            parked: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the transaction can wait for the transaction it references, which is the case of the dispute operations.
+    pub(crate) fn can_wait(transaction: &Transaction) -> bool {
+        matches!(
+            transaction.transaction_type(),
+            TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+        )
+    }
+
+    /// Whether another operation of the same transaction is waiting, in which case the transaction has to wait behind it.
+    pub(crate) fn is_waiting(&self, transaction: &Transaction) -> bool {
+        Self::can_wait(transaction)
+            && self
+                .parked
+                .get(&(transaction.client(), transaction.account().clone()))
+                .is_some_and(|parked| {
+                    parked
+                        .iter()
+                        .any(|parked| parked.transaction.id() == transaction.id())
+                })
+    }
+
+    /// Park a dispute operation. The transaction is given back if its account has no room left.
+    pub(crate) fn park(
+        &mut self,
+        transaction: Transaction,
+        now: DateTime<Utc>,
+    ) -> Option<Transaction> {
+        let parked = self
+            .parked
+            .entry((transaction.client(), transaction.account().clone()))
+            .or_default();
+        if parked.len() >= self.window.transactions {
+            return Some(transaction);
+        }
+        parked.push_back(Parked {
+            transaction,
+            parked_at: now,
+            waited: 0,
+        });
+        None
+    }
+
+    /// Take the operations waiting in an account, in the order they arrived, to try them again.
+    pub(crate) fn take(&mut self, key: &(ClientId, AccountName)) -> VecDeque<Parked> {
+        self.parked.remove(key).unwrap_or_default()
+    }
+
+    /// Put back an operation that is still missing its transaction after another transaction of its account, unless
+    /// it waited long enough. The operation is given back if it has to be rejected.
+    pub(crate) fn wait_longer(
+        &mut self,
+        mut parked: Parked,
+        now: DateTime<Utc>,
+    ) -> Option<Transaction> {
+        parked.waited += 1;
+        if self.expired(&parked, now) {
+            return Some(parked.transaction);
+        }
+        self.parked
+            .entry((
+                parked.transaction.client(),
+                parked.transaction.account().clone(),
+            ))
+            .or_default()
+            .push_back(parked);
+        None
+    }
+
+    /// Take all the operations that are still waiting, by account, e.g. at the end of the input.
+    pub(crate) fn drain(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.parked)
+            .into_values()
+            .flatten()
+            .map(|parked| parked.transaction)
+            .collect()
+    }
+
+    fn expired(&self, parked: &Parked, now: DateTime<Utc>) -> bool {
+        parked.waited >= self.window.transactions
+            || self
+                .window
+                .timeout
+                .is_some_and(|timeout| now - parked.parked_at >= timeout)
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::{
+    cli::Cli,
+    clock::SharedClock,
+    engine::{Shard, WorkerId},
+    memory::PhaseMemory,
+    object_input::ObjectInput,
+    provenance::Provenance,
+    state,
+    summary::Summary,
+};
+
+// A machine readable record of a run for the systems that schedule the runs, so they don't have to scrape stderr. It's
+// written once the run is over: the options of the run and the engine that ran them, the input files with their hashes, the counts of the summary
+// (by transaction type and by rejection reason), when the run started and ended, where the outputs are and the exit
+// status. The workers are listed with the shard of the clients they processed, so the worker ids in the logs and in the
+// account updates can be traced back to the clients.
+
+// A file that was read by the run.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct InputFile {
+    /// What the file is, e.g. `transactions` or `blocklist`.
+    role: &'static str,
+    path: PathBuf,
+    /// The SHA-256 hash of the contents of the file when the run started. The objects of an object store are not
+    /// hashed, since that would download them twice.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+// A file or directory written by the run. The accounts are written to stdout.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct Output {
+    /// The option of the output without the dashes, e.g. `rejects`.
+    role: &'static str,
+    path: PathBuf,
+}
+
+// The worker that processed a shard of the clients.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct WorkerShard {
+    /// The clients of this shard, out of as many shards as there are workers.
+    shard: usize,
+    worker: WorkerId,
+    /// The first and the last client of the shard, with `--partition range`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clients: Option<[u16; 2]>,
+}
+
+/// What is known about a run when it starts.
+#[derive(Debug)]
+pub(crate) struct RunManifest {
+    run_id: Option<String>,
+    /// The command line of the run. Passwords in connection strings are masked.
+    arguments: Vec<String>,
+    inputs: Vec<InputFile>,
+    outputs: Vec<Output>,
+    workers: Vec<WorkerShard>,
+    engine: Provenance,
+    started_at: DateTime<Utc>,
+    /// The memory used by each phase, with `--report-memory`.
+    memory: Option<Vec<PhaseMemory>>,
+}
+
+// The record of a run, as it's written.
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<&'a str>,
+    arguments: &'a [String],
+    inputs: &'a [InputFile],
+    outputs: &'a [Output],
+    workers: &'a [WorkerShard],
+    engine: &'a Provenance,
+    started_at: String,
+    finished_at: String,
+    duration_ms: i64,
+    summary: &'a Summary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory: Option<&'a [PhaseMemory]>,
+    exit_code: i32,
+}
+
+impl RunManifest {
+    /// Start the record of a run with `workers` workers, hashing its input files.
+    pub(crate) fn start(cli: &Cli, workers: usize, clock: &SharedClock) -> io::Result<Self> {
+        let arguments = std::env::args_os()
+            .map(|argument| mask_password(&argument.to_string_lossy()))
+            .collect();
+        // The patterns of the transactions files are expanded by then, so every file is listed.
+        let files = [
+            ("bootstrap", &cli.bootstrap),
+            ("blocklist", &cli.blocklist),
+            ("dispute-policy", &cli.dispute_policy),
+        ];
+        let files = cli
+            .transactions_files
+            .iter()
+            .map(|path| ("transactions", path))
+            .chain(
+                files
+                    .into_iter()
+                    .filter_map(|(role, path)| path.as_ref().map(|path| (role, path))),
+            );
+        let mut inputs = Vec::new();
+        for (role, path) in files {
+            inputs.push(InputFile {
+                role,
+                path: path.clone(),
+                sha256: match ObjectInput::from_path(path) {
+                    Some(_) => None,
+                    None => Some(state::file_digest(path)?),
+                },
+            });
+        }
+        Ok(Self {
+            run_id: cli.run_id.clone(),
+            arguments,
+            inputs,
+            outputs: outputs(cli),
+            workers: (0..workers)
+                .map(|index| {
+                    let shard = Shard::new(index, workers, cli.partition);
+                    WorkerShard {
+                        shard: index,
+                        worker: shard.worker(),
+                        clients: shard
+                            .clients()
+                            .map(|clients| [*clients.start(), *clients.end()]),
+                    }
+                })
+                .collect(),
+            engine: Provenance::of(cli),
+            started_at: clock.now(),
+            memory: None,
+        })
+    }
+
+    /// Add the memory used by the phases of the run.
+    pub(crate) fn record_memory(&mut self, phases: Vec<PhaseMemory>) {
+        self.memory = Some(phases);
+    }
+
+    /// Complete the record with the outcome of the run and write it to a file.
+    pub(crate) fn finish(
+        &self,
+        path: &Path,
+        summary: &Summary,
+        exit_code: i32,
+        clock: &SharedClock,
+    ) -> io::Result<()> {
+        let finished_at = clock.now();
+        let record = Record {
+            run_id: self.run_id.as_deref(),
+            arguments: &self.arguments,
+            inputs: &self.inputs,
+            outputs: &self.outputs,
+            workers: &self.workers,
+            engine: &self.engine,
+            started_at: self.started_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            finished_at: finished_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            duration_ms: (finished_at - self.started_at).num_milliseconds(),
+            summary,
+            memory: self.memory.as_deref(),
+            exit_code,
+        };
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &record)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+// The outputs that were asked for.
+fn outputs(cli: &Cli) -> Vec<Output> {
+    let trace = cli.trace_client.map(|_| cli.trace_file.clone());
+    [
+        ("rejects", &cli.rejects),
+        ("dead-letters", &cli.dead_letters),
+        ("settlement-report", &cli.settlement_report),
+        ("account-updates", &cli.account_updates),
+        ("ledger-export", &cli.ledger_export),
+        ("history-archive", &cli.history_archive),
+        ("archive-dir", &cli.archive_dir),
+        ("state-dir", &cli.state_dir),
+        ("backup-dir", &cli.backup_dir),
+        ("watch-report", &cli.watch_report),
+        ("profile", &cli.profile),
+        ("trace-file", &trace),
+    ]
+    .into_iter()
+    .filter_map(|(role, path)| path.clone().map(|path| Output { role, path }))
+    .collect()
+}
+
+// Mask the password of a connection string, e.g. `postgres://engine:***@db/payments`.
+fn mask_password(argument: &str) -> String {
+    if let Some(scheme) = argument.find("://")
+        && let Some(at) = argument[scheme..].find('@').map(|at| scheme + at)
+        && let Some(colon) = argument[scheme + 3..at]
+            .find(':')
+            .map(|colon| scheme + 3 + colon)
+    {
+        format!("{}:***{}", &argument[..colon], &argument[at..])
+    } else {
+        argument.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use crate::{clock::ManualClock, transaction_types::TransactionType};
+
+    use super::*;
+
+    #[test]
+    fn should_mask_passwords_of_connection_strings() {
+        assert_eq!(
+            mask_password("db:postgres://engine:secret@db/payments?table=t"),
+            "db:postgres://engine:***@db/payments?table=t"
+        );
+        assert_eq!(
+            mask_password("db:postgres://engine@db/payments?table=t"),
+            "db:postgres://engine@db/payments?table=t"
+        );
+        assert_eq!(mask_password("input.csv"), "input.csv");
+    }
+
+    #[test]
+    fn should_record_the_inputs_outputs_and_outcome_of_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.csv");
+        std::fs::write(&input, "type,client,tx,amount\n").unwrap();
+        let next = dir.path().join("next.csv");
+        std::fs::write(&next, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let cli = Cli::try_parse_from([
+            "payments-engine",
+            input.to_str().unwrap(),
+            next.to_str().unwrap(),
+            "--rejects",
+            "rejects.csv",
+            "--run-id",
+            "batch-7",
+            "--partition",
+            "range",
+        ])
+        .unwrap();
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+
+        let mut manifest = RunManifest::start(&cli, 2, &clock.shared()).unwrap();
+        assert_eq!(
+            manifest.inputs,
+            vec![
+                InputFile {
+                    role: "transactions",
+                    path: input.clone(),
+                    sha256: Some(state::file_digest(&input).unwrap()),
+                },
+                InputFile {
+                    role: "transactions",
+                    path: next.clone(),
+                    sha256: Some(state::file_digest(&next).unwrap()),
+                }
+            ]
+        );
+        assert_eq!(
+            manifest.outputs,
+            vec![Output {
+                role: "rejects",
+                path: "rejects.csv".into()
+            }]
+        );
+
+        let mut summary = Summary::default();
+        summary.count_applied(TransactionType::Deposit);
+        clock.advance(chrono::Duration::milliseconds(1500));
+        manifest.record_memory(vec![PhaseMemory {
+            phase: "parsing",
+            peak_allocated_bytes: 2048,
+            allocated_bytes: 1024,
+            peak_rss_bytes: None,
+        }]);
+        let path = dir.path().join("run.json");
+        manifest
+            .finish(&path, &summary, 0, &clock.shared())
+            .unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["run_id"], "batch-7");
+        assert_eq!(written["duration_ms"], 1500);
+        assert_eq!(written["finished_at"], "2024-03-01T12:00:01.500Z");
+        assert_eq!(written["summary"]["by_type"]["deposit"]["applied"], 1);
+        assert_eq!(written["exit_code"], 0);
+        assert_eq!(
+            written["memory"],
+            serde_json::json!([{ "phase": "parsing", "peak_allocated_bytes": 2048, "allocated_bytes": 1024 }])
+        );
+        assert_eq!(
+            written["workers"],
+            serde_json::json!([
+                { "shard": 0, "worker": "worker-0", "clients": [0, 32767] },
+                { "shard": 1, "worker": "worker-1", "clients": [32768, 65535] }
+            ])
+        );
+    }
+}
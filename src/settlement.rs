@@ -0,0 +1,207 @@
+use std::{collections::BTreeMap, fs::File, io, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    events::AppliedEvent,
+    provenance,
+    transaction_types::{Amount, AmountFormat, ClientId, EscrowParty, TransactionType},
+};
+
+// Net positions for settling each client with a single wire at the end of a run.
+// Money that came in (deposits) is netted against money that went out (withdrawals, chargebacks and escrow
+// releases to a beneficiary). Funds that are held by disputes or in escrow are not settled yet, so they are
+// reported separately and left out of the net. Moves between sub-accounts never leave the client and are ignored.
+
+/// The money that moved in and out of the accounts of a client and the funds that are still held.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub(crate) inflow: Amount,
+    pub(crate) outflow: Amount,
+    pub(crate) held: Amount,
+}
+
+impl Position {
+    /// The amount owed to the client, or owed by the client when negative.
+    pub(crate) fn net(&self) -> Amount {
+        self.inflow
+            .saturating_sub(self.outflow)
+            .saturating_sub(self.held)
+    }
+
+    fn merge(&mut self, other: &Position) {
+        self.inflow = self.inflow.saturating_add(other.inflow);
+        self.outflow = self.outflow.saturating_add(other.outflow);
+        self.held = self.held.saturating_add(other.held);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inflow.is_zero() && self.outflow.is_zero() && self.held.is_zero()
+    }
+}
+
+/// The positions of the clients. Each worker keeps the positions of its clients and they are merged at the end.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Settlement {
+    positions: BTreeMap<ClientId, Position>,
+}
+
+impl Settlement {
+    /// Account for the money moved by an applied transaction.
+    pub(crate) fn record(&mut self, event: &AppliedEvent) {
+        let position = self.positions.entry(event.account.client).or_default();
+        let withdrawal = event.disputed == Some(TransactionType::Withdrawal);
+        match (event.transaction_type, event.release_to) {
+            (TransactionType::Deposit, _) => {
+                position.inflow = position.inflow.saturating_add(event.amount)
+            }
+            // The funds of a withdrawal that was charged back came back to the client, and leave again if the
+            // chargeback is reversed.
+            (TransactionType::Chargeback, _) if withdrawal => {
+                position.inflow = position.inflow.saturating_add(event.amount)
+            }
+            (TransactionType::ChargebackReversal, _) if !withdrawal => {
+                position.inflow = position.inflow.saturating_add(event.amount)
+            }
+            (
+                TransactionType::Withdrawal
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal,
+                _,
+            )
+            | (TransactionType::EscrowRelease, Some(EscrowParty::Beneficiary)) => {
+                position.outflow = position.outflow.saturating_add(event.amount)
+            }
+            _ => {}
+        }
+    }
+
+    /// Add the funds that are still held by a client, e.g. by the disputes that are open at the end of the run.
+    pub(crate) fn add_held(&mut self, client: ClientId, held: Amount) {
+        let position = self.positions.entry(client).or_default();
+        position.held = position.held.saturating_add(held);
+    }
+
+    pub(crate) fn merge(&mut self, other: &Settlement) {
+        for (client, position) in &other.positions {
+            self.positions.entry(*client).or_default().merge(position);
+        }
+    }
+
+    /// The sum of the positions of all the clients.
+    pub(crate) fn total(&self) -> Position {
+        let mut total = Position::default();
+        for position in self.positions.values() {
+            total.merge(position);
+        }
+        total
+    }
+
+    /// Write one row per client followed by the engine-wide totals. Clients without any position are left out.
+    pub(crate) fn write_csv<W: io::Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for (client, position) in &self.positions {
+            if !position.is_empty() {
+                AmountFormat::output(|| {
+                    writer.serialize(SettlementRow::new(client.to_string(), position))
+                })?;
+            }
+        }
+        AmountFormat::output(|| {
+            writer.serialize(SettlementRow::new("all".to_string(), &self.total()))
+        })?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), csv::Error> {
+        let mut file = File::create(path)?;
+        provenance::write_comment(&mut file, "#")?;
+        self.write_csv(file)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SettlementRow {
+    client: String,
+    inflow: Amount,
+    outflow: Amount,
+    held: Amount,
+    net: Amount,
+}
+
+impl SettlementRow {
+    fn new(client: String, position: &Position) -> Self {
+        Self {
+            client,
+            inflow: position.inflow,
+            outflow: position.outflow,
+            held: position.held,
+            net: position.net(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use crate::account::AccountSnapshot;
+
+    use super::*;
+
+    fn event(transaction_type: TransactionType, client: u16, amount: f64) -> AppliedEvent {
+        AppliedEvent {
+            transaction_type,
+            transaction_id: 1.into(),
+            amount: amount.into(),
+            release_to: None,
+            disputed: None,
+            worker: Default::default(),
+            account: AccountSnapshot {
+                client: client.into(),
+                account: Default::default(),
+                available: Amount::zero(),
+                held: Amount::zero(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: Amount::zero(),
+                locked: false,
+            },
+            period: 1,
+            applied_at: DateTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn should_net_positions_per_client_and_engine_wide() {
+        let mut first = Settlement::default();
+        first.record(&event(TransactionType::Deposit, 1, 10.0));
+        first.record(&event(TransactionType::Withdrawal, 1, 3.0));
+        first.record(&event(TransactionType::Dispute, 1, 2.0));
+        first.add_held(1.into(), 2.0.into());
+        first.record(&event(TransactionType::EscrowRelease, 1, 1.0));
+
+        let mut second = Settlement::default();
+        second.record(&event(TransactionType::Deposit, 2, 5.0));
+        second.record(&event(TransactionType::Chargeback, 2, 5.0));
+        second.record(&AppliedEvent {
+            release_to: Some(EscrowParty::Beneficiary),
+            ..event(TransactionType::EscrowRelease, 2, 1.5)
+        });
+        second.add_held(3.into(), Amount::zero());
+        first.merge(&second);
+
+        let mut output = Vec::new();
+        first.write_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,inflow,outflow,held,net
+1,10,3,2,5
+2,5,6.5,0,-1.5
+all,15,9.5,2,3.5
+"
+        );
+    }
+}
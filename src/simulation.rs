@@ -0,0 +1,414 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::PathBuf,
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    blocklist::Blocklist,
+    clock::ManualClock,
+    engine::{Partitioner, Shard, ShardedEngine},
+    pipeline::ValidatorChain,
+    transaction_processor::{ProcessorMessage, ProcessorOptions, TransactionProcessor},
+    transaction_types::{Amount, ClientId, Transaction, TransactionId, TransactionType},
+};
+
+// Deterministic simulation of the engine. A seed generates a random workload that leans on the edge cases of disputes
+// (disputes of unknown, withdrawn or foreign transactions, disputes that are opened twice, resolves and chargebacks
+// without a dispute, anything on a locked account). The workload is run through the whole engine, i.e. the workers
+// of a `ShardedEngine` with their validator chains and processors, and through a reference model that is written
+// from the specification and shares no code with the engine. The final accounts of both must be the same.
+//
+// A failing workload is shrunk to a minimal one that still fails and written as an input file to
+// `target/simulation/seed-<SEED>.csv`, so it can be replayed with the binary or added to a unit test. A seed always
+// generates the same workload, and the engine always sees the same time, so a failure can be replayed with
+// `SIMULATION_SEED=<SEED> cargo test simulation`. `SIMULATION_RUNS` sets the number of seeds that are tried.
+
+const WORKERS: usize = 4;
+const CLIENTS: u16 = 6;
+const DEFAULT_RUNS: u64 = 64;
+const WORKLOAD_LENGTH: usize = 200;
+
+// SplitMix64. The workload of a seed must never change, so the generator doesn't depend on an external crate.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> Option<T> {
+        (!items.is_empty()).then(|| items[self.below(items.len() as u64) as usize])
+    }
+}
+
+/// A record of a workload, turned into a transaction when it's sent to the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    transaction_type: TransactionType,
+    client: ClientId,
+    id: TransactionId,
+    amount: Option<Amount>,
+}
+
+impl Record {
+    fn new(
+        transaction_type: TransactionType,
+        client: ClientId,
+        id: TransactionId,
+        amount: Option<Amount>,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client,
+            id,
+            amount,
+        }
+    }
+
+    fn transaction(&self) -> Transaction {
+        Transaction::new(self.transaction_type, self.client, self.id, self.amount)
+    }
+}
+
+/// Generate the workload of a seed.
+fn generate(seed: u64, length: usize) -> Vec<Record> {
+    let mut rng = Rng(seed);
+    let mut funded: Vec<(ClientId, TransactionId)> = Vec::new();
+    let mut next_id = 1u32;
+    let mut workload = Vec::with_capacity(length);
+
+    for _ in 0..length {
+        let client = ClientId::from(1 + rng.below(CLIENTS as u64) as u16);
+        let record = match rng.below(100) {
+            0..35 => {
+                // A few deposits replay the id of an earlier transaction.
+                let id = match rng.pick(&funded) {
+                    Some((_, id)) if rng.chance(3) => id,
+                    _ => {
+                        next_id += 1;
+                        next_id.into()
+                    }
+                };
+                funded.push((client, id));
+                let amount =
+                    Amount::from(rust_decimal::Decimal::new(1 + rng.below(100_000) as i64, 2));
+                Record::new(TransactionType::Deposit, client, id, Some(amount))
+            }
+            35..50 => {
+                next_id += 1;
+                let id = next_id.into();
+                funded.push((client, id));
+                let amount =
+                    Amount::from(rust_decimal::Decimal::new(1 + rng.below(50_000) as i64, 2));
+                Record::new(TransactionType::Withdrawal, client, id, Some(amount))
+            }
+            roll => {
+                let transaction_type = match roll {
+                    50..75 => TransactionType::Dispute,
+                    75..90 => TransactionType::Resolve,
+                    _ => TransactionType::Chargeback,
+                };
+                // Mostly a transaction of the same client, sometimes one of another client or one that doesn't exist.
+                let (client, id) = match rng.pick(&funded) {
+                    Some((owner, id)) if rng.chance(85) => (owner, id),
+                    Some((_, id)) if rng.chance(50) => (client, id),
+                    _ => (client, (next_id + 1 + rng.below(10) as u32).into()),
+                };
+                Record::new(transaction_type, client, id, None)
+            }
+        };
+        workload.push(record);
+    }
+    workload
+}
+
+/// The final state of the accounts, as `(available, held, total, locked)` by client, and the number of applied
+/// transactions.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Outcome {
+    accounts: BTreeMap<ClientId, (Amount, Amount, Amount, bool)>,
+    applied: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelState {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+#[derive(Debug, Default)]
+struct ModelAccount {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+}
+
+/// The reference model: the rules of the specification, applied one transaction at a time.
+fn model(workload: &[Record]) -> Outcome {
+    let mut accounts: BTreeMap<ClientId, ModelAccount> = BTreeMap::new();
+    // Transaction ids are unique per account. Only deposits can be disputed.
+    let mut funding: HashMap<(ClientId, TransactionId), (Option<Amount>, ModelState)> =
+        HashMap::new();
+    let mut applied = 0;
+
+    for record in workload {
+        let client = record.client;
+        let key = (client, record.id);
+        // Every transaction opens the account of its client, whether it's applied or not.
+        let account = accounts.entry(client).or_default();
+        if account.locked {
+            continue;
+        }
+        let ok = match (record.transaction_type, funding.get_mut(&key)) {
+            (TransactionType::Deposit, None) => {
+                let amount = record.amount.unwrap();
+                account.available = account.available.checked_add(amount).unwrap();
+                funding.insert(key, (Some(amount), ModelState::Settled));
+                true
+            }
+            (TransactionType::Withdrawal, None) => {
+                let amount = record.amount.unwrap();
+                if account.available < amount {
+                    false
+                } else {
+                    account.available = account.available.checked_sub(amount).unwrap();
+                    funding.insert(key, (None, ModelState::Settled));
+                    true
+                }
+            }
+            (TransactionType::Dispute, Some((Some(amount), state)))
+                if *state == ModelState::Settled =>
+            {
+                account.available = account.available.checked_sub(*amount).unwrap();
+                account.held = account.held.checked_add(*amount).unwrap();
+                *state = ModelState::Disputed;
+                true
+            }
+            (TransactionType::Resolve, Some((Some(amount), state)))
+                if *state == ModelState::Disputed =>
+            {
+                account.available = account.available.checked_add(*amount).unwrap();
+                account.held = account.held.checked_sub(*amount).unwrap();
+                *state = ModelState::Resolved;
+                true
+            }
+            (TransactionType::Chargeback, Some((Some(amount), state)))
+                if *state == ModelState::Disputed =>
+            {
+                account.held = account.held.checked_sub(*amount).unwrap();
+                account.locked = true;
+                *state = ModelState::ChargedBack;
+                true
+            }
+            _ => false,
+        };
+        if ok {
+            applied += 1;
+        }
+    }
+
+    Outcome {
+        accounts: accounts
+            .into_iter()
+            .map(|(client, account)| {
+                let total = account.available.checked_add(account.held).unwrap();
+                (
+                    client,
+                    (account.available, account.held, total, account.locked),
+                )
+            })
+            .collect(),
+        applied,
+    }
+}
+
+/// Run a workload through the workers of a `ShardedEngine`, the same way `main` runs the input.
+fn run_engine(workload: &[Record]) -> Outcome {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("The simulation runtime can be built.");
+    runtime.block_on(async {
+        let clock = ManualClock::at("2024-01-01T00:00:00Z");
+        let mut queues = Vec::new();
+        let mut workers = Vec::new();
+        for index in 0..WORKERS {
+            let processor = TransactionProcessor::new(ProcessorOptions::default())
+                .with_shard(Shard::new(index, WORKERS, Partitioner::Hash))
+                .with_clock(clock.shared());
+            let (tx, rx) = mpsc::channel(16);
+            let (validated_tx, validated_rx) = mpsc::channel(16);
+            let validation = ValidatorChain::with_builtin_validators(Blocklist::default(), false);
+            tokio::spawn(validation.run(rx, validated_tx));
+            workers.push(tokio::spawn(processor.run(validated_rx)));
+            queues.push(tx);
+        }
+
+        let engine = ShardedEngine::new(queues);
+        for record in workload {
+            engine
+                .send(
+                    record.client,
+                    ProcessorMessage::process_transaction(record.transaction()),
+                )
+                .await
+                .expect("The workers run until the queues are closed.");
+        }
+        drop(engine);
+
+        let mut outcome = Outcome::default();
+        for worker in workers {
+            let processor = worker.await.expect("The workers don't panic.");
+            outcome.applied += processor.summary().applied;
+            for account in processor.snapshots() {
+                outcome.accounts.insert(
+                    account.client,
+                    (
+                        account.available,
+                        account.held,
+                        account.total,
+                        account.locked,
+                    ),
+                );
+            }
+        }
+        outcome
+    })
+}
+
+// The difference between the engine and the model, if any.
+fn check(workload: &[Record]) -> Option<String> {
+    let (engine, model) = (run_engine(workload), model(workload));
+    (engine != model).then(|| format!("engine: {:?}\nmodel:  {:?}", engine, model))
+}
+
+/// Remove as many transactions as possible from a failing workload while it keeps failing: first in large chunks,
+/// then in smaller ones down to single transactions.
+fn shrink(mut workload: Vec<Record>, mut fails: impl FnMut(&[Record]) -> bool) -> Vec<Record> {
+    let mut chunk = workload.len().div_ceil(2);
+    while chunk > 0 {
+        let mut start = 0;
+        while start < workload.len() {
+            let end = (start + chunk).min(workload.len());
+            let candidate: Vec<_> = workload[..start]
+                .iter()
+                .chain(&workload[end..])
+                .copied()
+                .collect();
+            if fails(&candidate) {
+                workload = candidate;
+            } else {
+                start = end;
+            }
+        }
+        chunk /= 2;
+    }
+    workload
+}
+
+// Write a workload as an input file of the engine.
+fn write_reproducer(seed: u64, workload: &[Record]) -> PathBuf {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for record in workload {
+        let amount = record
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        writeln!(
+            csv,
+            "{},{},{},{}",
+            record.transaction_type, record.client, record.id, amount
+        )
+        .unwrap();
+    }
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/simulation");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("seed-{}.csv", seed));
+    std::fs::write(&path, csv).unwrap();
+    path
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number.", name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_the_reference_model_on_random_workloads() {
+        let seeds = match env_number("SIMULATION_SEED") {
+            Some(seed) => seed..seed + 1,
+            None => 0..env_number("SIMULATION_RUNS").unwrap_or(DEFAULT_RUNS),
+        };
+        for seed in seeds {
+            let workload = generate(seed, WORKLOAD_LENGTH);
+            if check(&workload).is_none() {
+                continue;
+            }
+            let minimal = shrink(workload, |candidate| check(candidate).is_some());
+            let path = write_reproducer(seed, &minimal);
+            panic!(
+                "Seed {} diverges from the model with {} transactions, written to {}:\n{}",
+                seed,
+                minimal.len(),
+                path.display(),
+                check(&minimal).unwrap_or_default()
+            );
+        }
+    }
+
+    #[test]
+    fn should_generate_the_same_workload_for_a_seed() {
+        assert_eq!(generate(7, 50), generate(7, 50));
+        assert_ne!(generate(7, 50), generate(8, 50));
+    }
+
+    #[test]
+    fn should_shrink_a_failing_workload_to_the_transactions_that_fail() {
+        let workload = generate(3, WORKLOAD_LENGTH);
+        // A made up bug: charging back a transaction that was disputed.
+        let fails = |workload: &[Record]| {
+            workload.iter().enumerate().any(|(index, chargeback)| {
+                chargeback.transaction_type == TransactionType::Chargeback
+                    && workload[..index].iter().any(|dispute| {
+                        dispute.transaction_type == TransactionType::Dispute
+                            && (dispute.client, dispute.id) == (chargeback.client, chargeback.id)
+                    })
+            })
+        };
+        assert!(fails(&workload));
+
+        let minimal = shrink(workload, fails);
+
+        let types: Vec<_> = minimal
+            .iter()
+            .map(|record| record.transaction_type)
+            .collect();
+        assert_eq!(
+            types,
+            [TransactionType::Dispute, TransactionType::Chargeback]
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use memmap2::Mmap;
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::{
+    account::AccountSnapshot,
+    transaction_types::{AccountName, Amount, ClientId},
+};
+
+// A compact binary format for the account snapshots, for resuming with tens of millions of accounts where parsing the
+// CSV output takes longer than the run. The file is a header followed by fixed-width records, so it's read in place
+// through a memory map and record `i` is at a known offset. All the numbers are little endian and the amounts are the
+// 16 bytes of their decimal, so nothing is rounded or parsed on the way. A CRC-32 of the header fields and of the
+// records is checked when the file is opened, which catches truncated and corrupted files before any account is loaded.
+//
+// Header, 32 bytes:
+//   0..8    magic, `PESNAP\0\x01`
+//   8..12   version, currently 2
+//   12..16  size of a record, in bytes
+//   16..24  number of records
+//   24..28  CRC-32 of the bytes 0..24 and of the records
+//   28..32  reserved, zero
+//
+// Record, 132 bytes:
+//   0..2    client
+//   2       locked, 0 or 1
+//   3       length of the name of the sub-account, 0 for the main one
+//   4..36   name of the sub-account, padded with zeros
+//   36..132 available, held, escrow, pending, reserve and total
+//
+// Version 1 had no pending and reserve amounts and can't be read anymore.
+
+const MAGIC: &[u8; 8] = b"PESNAP\0\x01";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 32;
+const RECORD_SIZE: usize = 132;
+const NAME_SIZE: usize = 32;
+const AMOUNTS_OFFSET: usize = 4 + NAME_SIZE;
+const AMOUNT_SIZE: usize = 16;
+
+#[derive(Debug, Error)]
+pub(crate) enum SnapshotError {
+    #[error("Cannot access the snapshot file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a binary account snapshot.")]
+    NotASnapshot,
+    #[error("Unsupported version {0} of the binary account snapshot.")]
+    UnsupportedVersion(u32),
+    #[error("The binary account snapshot is truncated: {expected} bytes expected, {actual} found.")]
+    Truncated { expected: u64, actual: u64 },
+    #[error("The checksum of the binary account snapshot doesn't match, the file is corrupted.")]
+    ChecksumMismatch,
+    #[error("Record {0} of the binary account snapshot is invalid.")]
+    InvalidRecord(usize),
+    #[error("Cannot write the CSV snapshot: {0}")]
+    Csv(#[from] csv::Error),
+}
+
+/// The formats the account snapshots can be converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SnapshotFormat {
+    /// The fixed-width records of this module.
+    Binary,
+    /// The format of the output with sub-accounts and escrow, like the period snapshots.
+    Csv,
+}
+
+/// Whether a file is a binary account snapshot, from its first bytes.
+pub(crate) fn is_binary<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write the accounts to a binary snapshot.
+pub(crate) fn write_binary<P: AsRef<Path>>(
+    path: P,
+    accounts: &[AccountSnapshot],
+) -> Result<(), SnapshotError> {
+    let mut records = Vec::with_capacity(accounts.len() * RECORD_SIZE);
+    for account in accounts {
+        records.extend_from_slice(&encode(account));
+    }
+    let mut header = [0; HEADER_SIZE];
+    header[0..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&(accounts.len() as u64).to_le_bytes());
+    let checksum = checksum(&header[0..24], &records);
+    header[24..28].copy_from_slice(&checksum.to_le_bytes());
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header)?;
+    writer.write_all(&records)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the accounts in the CSV format of the period snapshots, which can be passed to `--bootstrap`.
+pub(crate) fn write_csv<P: AsRef<Path>>(
+    path: P,
+    accounts: &[AccountSnapshot],
+) -> Result<(), SnapshotError> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for account in accounts {
+        writer.serialize(account)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A binary snapshot, mapped in memory. The records are decoded when they are read.
+pub(crate) struct BinarySnapshot {
+    map: Mmap,
+    len: usize,
+}
+
+impl BinarySnapshot {
+    /// Map a binary snapshot and check its header and checksum.
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        let file = File::open(path)?;
+        // Safety: the snapshots are written once and never modified in place. A file that is truncated by another
+        // process while it's mapped could still fault, like with any memory map.
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_SIZE || &map[0..8] != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u32::from_le_bytes(map[8..12].try_into().unwrap());
+        let record_size = u32::from_le_bytes(map[12..16].try_into().unwrap());
+        if version != VERSION || record_size as usize != RECORD_SIZE {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let len = u64::from_le_bytes(map[16..24].try_into().unwrap());
+        let expected = HEADER_SIZE as u64 + len.saturating_mul(RECORD_SIZE as u64);
+        if map.len() as u64 != expected {
+            return Err(SnapshotError::Truncated {
+                expected,
+                actual: map.len() as u64,
+            });
+        }
+        let stored = u32::from_le_bytes(map[24..28].try_into().unwrap());
+        if checksum(&map[0..24], &map[HEADER_SIZE..]) != stored {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        Ok(Self {
+            map,
+            len: len as usize,
+        })
+    }
+
+    /// The account of record `index`.
+    pub(crate) fn get(&self, index: usize) -> Result<AccountSnapshot, SnapshotError> {
+        let start = HEADER_SIZE + index * RECORD_SIZE;
+        let record = self
+            .map
+            .get(start..start + RECORD_SIZE)
+            .ok_or(SnapshotError::InvalidRecord(index))?;
+        decode(record.try_into().unwrap()).ok_or(SnapshotError::InvalidRecord(index))
+    }
+
+    /// The accounts in the order they were written.
+    pub(crate) fn accounts(&self) -> impl Iterator<Item = Result<AccountSnapshot, SnapshotError>> {
+        (0..self.len).map(|index| self.get(index))
+    }
+}
+
+fn checksum(header: &[u8], records: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(records);
+    hasher.finalize()
+}
+
+fn encode(account: &AccountSnapshot) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[0..2].copy_from_slice(&u16::from(account.client).to_le_bytes());
+    record[2] = account.locked.into();
+    // The names of the sub-accounts are at most 32 ASCII characters.
+    if !account.account.is_main() {
+        let name = account.account.to_string();
+        record[3] = name.len() as u8;
+        record[4..4 + name.len()].copy_from_slice(name.as_bytes());
+    }
+    let amounts = [
+        account.available,
+        account.held,
+        account.escrow,
+        account.pending,
+        account.reserve,
+        account.total,
+    ];
+    for (i, amount) in amounts.into_iter().enumerate() {
+        let offset = AMOUNTS_OFFSET + i * AMOUNT_SIZE;
+        record[offset..offset + AMOUNT_SIZE].copy_from_slice(&Decimal::from(amount).serialize());
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> Option<AccountSnapshot> {
+    let locked = match record[2] {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    let name_len = record[3] as usize;
+    let account = match name_len {
+        0 => AccountName::default(),
+        1..=NAME_SIZE => std::str::from_utf8(&record[4..4 + name_len])
+            .ok()?
+            .parse()
+            .ok()?,
+        _ => return None,
+    };
+    let amount = |i: usize| -> Amount {
+        let offset = AMOUNTS_OFFSET + i * AMOUNT_SIZE;
+        Decimal::deserialize(record[offset..offset + AMOUNT_SIZE].try_into().unwrap()).into()
+    };
+    Some(AccountSnapshot {
+        client: ClientId::from(u16::from_le_bytes([record[0], record[1]])),
+        account,
+        available: amount(0),
+        held: amount(1),
+        escrow: amount(2),
+        pending: amount(3),
+        reserve: amount(4),
+        total: amount(5),
+        locked,
+    })
+}
+
+/// Convert the accounts to a snapshot in the given format, replacing the file only once it's fully written.
+pub(crate) fn convert<P: AsRef<Path>>(
+    accounts: &[AccountSnapshot],
+    path: P,
+    format: SnapshotFormat,
+) -> Result<(), SnapshotError> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    match format {
+        SnapshotFormat::Binary => write_binary(&temporary, accounts)?,
+        SnapshotFormat::Csv => write_csv(&temporary, accounts)?,
+    }
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts() -> Vec<AccountSnapshot> {
+        vec![
+            AccountSnapshot {
+                client: 1.into(),
+                account: AccountName::default(),
+                available: 1.5.into(),
+                held: 0.25.into(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: 1.75.into(),
+                locked: false,
+            },
+            AccountSnapshot {
+                client: u16::MAX.into(),
+                account: "savings_account_with_32_chars_xx".parse().unwrap(),
+                available: (-200.1234).into(),
+                held: Amount::zero(),
+                escrow: 3.0.into(),
+                pending: 1.0.into(),
+                reserve: 0.5.into(),
+                total: (-195.6234).into(),
+                locked: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn should_read_back_the_accounts_of_a_binary_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.snap");
+        convert(&accounts(), &path, SnapshotFormat::Binary).unwrap();
+
+        assert!(is_binary(&path).unwrap());
+        let snapshot = BinarySnapshot::open(&path).unwrap();
+        assert_eq!(snapshot.accounts().count(), 2);
+        assert_eq!(snapshot.get(1).unwrap(), accounts()[1]);
+        assert_eq!(
+            snapshot.accounts().collect::<Result<Vec<_>, _>>().unwrap(),
+            accounts()
+        );
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            HEADER_SIZE + 2 * RECORD_SIZE
+        );
+    }
+
+    #[test]
+    fn should_keep_the_pending_funds_and_the_reserve_through_conversions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (binary, csv) = (
+            dir.path().join("accounts.snap"),
+            dir.path().join("accounts.csv"),
+        );
+        convert(&accounts(), &binary, SnapshotFormat::Binary).unwrap();
+        let accounts_of_binary = crate::bootstrap::read_snapshots(&binary).unwrap();
+        convert(&accounts_of_binary, &csv, SnapshotFormat::Csv).unwrap();
+
+        assert_eq!(crate::bootstrap::read_snapshots(&csv).unwrap(), accounts());
+    }
+
+    #[test]
+    fn should_reject_corrupted_and_truncated_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.snap");
+        write_binary(&path, &accounts()).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+
+        bytes[HEADER_SIZE + 40] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            BinarySnapshot::open(&path),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(
+            BinarySnapshot::open(&path),
+            Err(SnapshotError::Truncated { .. })
+        ));
+
+        let csv = dir.path().join("accounts.csv");
+        write_csv(&csv, &accounts()).unwrap();
+        assert!(!is_binary(&csv).unwrap());
+        assert!(matches!(
+            BinarySnapshot::open(&csv),
+            Err(SnapshotError::NotASnapshot)
+        ));
+    }
+}
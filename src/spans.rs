@@ -0,0 +1,406 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    logging::log_event,
+    metrics::{otlp_attributes, otlp_resource, otlp_scope, otlp_url, unix_nanos},
+};
+
+// OpenTelemetry traces of the requests of the daemon, to follow a slow request from the gateway down to the worker
+// that applied its transactions and the time they spent in the transaction store. The trace context of a request
+// comes from its `traceparent` header (W3C Trace Context); a request without one starts a new trace. The context is
+// attached to the extensions of the transactions of the request, so it travels with them through the ingress, the
+// validator chain and the worker queues, and the worker records a span for every traced transaction it applies.
+//
+// The spans are queued to a task that posts them in batches to an OTLP collector, as OTLP JSON over HTTP. Recording
+// a span never waits: when the queue is full the span is dropped and counted.
+
+// Where an OTLP collector receives traces over HTTP, unless the exporter gives a path.
+const OTLP_PATH: &str = "/v1/traces";
+// Number of spans that can wait for the exporter before new spans are dropped.
+const SPAN_QUEUE: usize = 8192;
+// Largest number of spans posted at once.
+const SPAN_BATCH: usize = 512;
+const OTLP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub(crate) enum SpansError {
+    #[error("'{0}' is not a span exporter (e.g. otlp://localhost:4318).")]
+    InvalidExporter(String),
+}
+
+/// An OpenTelemetry collector that receives the spans, written as `otlp://<HOST>:<PORT>[/<PATH>]`. The spans are
+/// posted as OTLP JSON over HTTP, to `/v1/traces` by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SpanExporter {
+    url: String,
+}
+
+impl FromStr for SpanExporter {
+    type Err = SpansError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let url = otlp_url(value, OTLP_PATH)
+            .ok_or_else(|| SpansError::InvalidExporter(value.to_string()))?;
+        Ok(Self { url })
+    }
+}
+
+/// The span a piece of work belongs to: the spans recorded for it are its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+}
+
+impl TraceContext {
+    /// The context of a `traceparent` header, e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    /// `Err` if the header is not valid, `Ok(None)` if the caller doesn't sample the trace.
+    pub(crate) fn from_traceparent(header: &str) -> Result<Option<Self>, ()> {
+        let mut fields = header.trim().split('-');
+        let (Some(version), Some(trace_id), Some(span_id), Some(flags)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(());
+        };
+        let hex = |field: &str, len: usize| {
+            (field.len() == len
+                && field
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')))
+            .then_some(())
+            .ok_or(())
+        };
+        hex(version, 2)?;
+        hex(trace_id, 32)?;
+        hex(span_id, 16)?;
+        hex(flags, 2)?;
+        // Later versions may add fields, version 00 has exactly four.
+        if version == "ff" || (version == "00" && fields.next().is_some()) {
+            return Err(());
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).map_err(|_| ())?;
+        let span_id = u64::from_str_radix(span_id, 16).map_err(|_| ())?;
+        let flags = u8::from_str_radix(flags, 16).map_err(|_| ())?;
+        if trace_id == 0 || span_id == 0 {
+            return Err(());
+        }
+        Ok((flags & 1 == 1).then_some(Self { trace_id, span_id }))
+    }
+
+    /// The context of a new trace.
+    pub(crate) fn root() -> Self {
+        Self {
+            trace_id: u128::from(random_id()) << 64 | u128::from(random_id()),
+            span_id: random_id(),
+        }
+    }
+
+    /// The context of a new span of the same trace.
+    pub(crate) fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: random_id(),
+        }
+    }
+}
+
+// A random non-zero id. The ids only have to be unique, so they come from the random keys of the standard library
+// rather than a random number generator.
+fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish().max(1)
+}
+
+/// What a span stands for, as in OTLP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpanKind {
+    Internal = 1,
+    Server = 2,
+}
+
+/// A unit of work of a trace, from its start to its end.
+#[derive(Debug, Clone)]
+pub(crate) struct Span {
+    context: TraceContext,
+    parent: Option<u64>,
+    name: &'static str,
+    kind: SpanKind,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// A span that starts now, as a child of `parent` if there is one.
+    pub(crate) fn start(
+        name: &'static str,
+        kind: SpanKind,
+        parent: Option<&TraceContext>,
+        start: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            context: parent.map_or_else(TraceContext::root, TraceContext::child),
+            parent: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start,
+            end: start,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// The context of the work done as part of the span.
+    pub(crate) fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    pub(crate) fn with_attribute(mut self, key: &'static str, value: &dyn Display) -> Self {
+        self.attributes.push((key, value.to_string()));
+        self
+    }
+
+    /// End the span, as failed if there's an error.
+    pub(crate) fn end(mut self, end: DateTime<Utc>, error: Option<&dyn Display>) -> Self {
+        self.end = end;
+        self.error = error.map(ToString::to_string);
+        self
+    }
+
+    fn to_otlp(&self) -> serde_json::Value {
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.context.trace_id),
+            "spanId": format!("{:016x}", self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": otlp_attributes(&self.attributes),
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        if let Some(error) = &self.error {
+            span["status"] = json!({ "code": 2, "message": error });
+        }
+        span
+    }
+}
+
+/// Where the spans are recorded. Clones record to the same exporter. The default records nothing, so the stages don't
+/// have to check whether the spans are exported.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tracer(Option<(mpsc::Sender<Span>, Arc<AtomicU64>)>);
+
+impl Tracer {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Queue a span for the exporter, or drop it if the exporter is behind.
+    pub(crate) fn record(&self, span: Span) {
+        if let Some((spans, dropped)) = &self.0
+            && spans.try_send(span).is_err()
+        {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Posts the recorded spans to an OTLP collector until the run is over.
+pub(crate) struct SpanExport {
+    stop: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl SpanExport {
+    pub(crate) fn start(exporter: SpanExporter) -> (Tracer, Self) {
+        let (spans, queued) = mpsc::channel(SPAN_QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (stop, stopped) = watch::channel(false);
+        let handle = tokio::spawn(export(exporter, queued, Arc::clone(&dropped), stopped));
+        (Tracer(Some((spans, dropped))), Self { stop, handle })
+    }
+
+    /// Post the spans that are still queued and stop.
+    pub(crate) async fn finish(self) {
+        let _ = self.stop.send(true);
+        let _ = self.handle.await;
+    }
+}
+
+async fn export(
+    exporter: SpanExporter,
+    mut queued: mpsc::Receiver<Span>,
+    dropped: Arc<AtomicU64>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let agent = ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .timeout_global(Some(OTLP_TIMEOUT))
+            .build(),
+    );
+    let (mut exported, mut failed) = (0, 0);
+    let mut batch = Vec::with_capacity(SPAN_BATCH);
+    loop {
+        let last = tokio::select! {
+            received = queued.recv_many(&mut batch, SPAN_BATCH) => received == 0,
+            _ = stopped.wait_for(|stopped| *stopped) => true,
+        };
+        if last {
+            while batch.len() < SPAN_BATCH
+                && let Ok(span) = queued.try_recv()
+            {
+                batch.push(span);
+            }
+        }
+        if !batch.is_empty() {
+            let spans = std::mem::take(&mut batch);
+            let count = spans.len();
+            let (agent, url) = (agent.clone(), exporter.url.clone());
+            let posted = tokio::task::spawn_blocking(move || {
+                let body = serde_json::to_vec(&otlp_body(&spans))?;
+                agent
+                    .post(url)
+                    .header("content-type", "application/json")
+                    .send(&body[..])?;
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+            })
+            .await;
+            match posted {
+                Ok(Ok(())) => exported += count,
+                Ok(Err(err)) => {
+                    failed += count;
+                    log_event(
+                        "span_export_failed",
+                        &[
+                            ("exporter", &exporter.url),
+                            ("spans", &count),
+                            ("error", &err),
+                        ],
+                    );
+                }
+                Err(_) => break,
+            }
+        }
+        // The spans that are still queued after the stop go out in the next batches.
+        if last && queued.is_empty() {
+            break;
+        }
+    }
+    log_event(
+        "spans_exported",
+        &[
+            ("exporter", &exporter.url),
+            ("exported", &exported),
+            ("failed", &failed),
+            ("dropped", &dropped.load(Ordering::Relaxed)),
+        ],
+    );
+}
+
+// An OTLP export request with the spans, in the JSON encoding of the protobuf messages.
+fn otlp_body(spans: &[Span]) -> serde_json::Value {
+    json!({
+        "resourceSpans": [{
+            "resource": otlp_resource(),
+            "scopeSpans": [{
+                "scope": otlp_scope(),
+                "spans": spans.iter().map(Span::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_continue_the_trace_of_a_traceparent() {
+        let context = TraceContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap()
+        .unwrap();
+        let started = "2024-03-01T12:00:00Z".parse().unwrap();
+        let span = Span::start("apply", SpanKind::Internal, Some(&context), started)
+            .with_attribute("transaction.type", &"deposit")
+            .end(
+                "2024-03-01T12:00:01Z".parse().unwrap(),
+                Some(&"Insufficient funds"),
+            );
+
+        let otlp = span.to_otlp();
+        assert_eq!(otlp["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(otlp["parentSpanId"], "00f067aa0ba902b7");
+        assert_ne!(otlp["spanId"], "00f067aa0ba902b7");
+        assert_eq!(otlp["kind"], 1);
+        assert_eq!(otlp["startTimeUnixNano"], "1709294400000000000");
+        assert_eq!(otlp["endTimeUnixNano"], "1709294401000000000");
+        assert_eq!(otlp["attributes"][0]["value"]["stringValue"], "deposit");
+        assert_eq!(otlp["status"]["code"], 2);
+
+        // Not sampled by the caller.
+        assert_eq!(
+            TraceContext::from_traceparent(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+            ),
+            Ok(None)
+        );
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(
+                TraceContext::from_traceparent(invalid),
+                Err(()),
+                "{}",
+                invalid
+            );
+        }
+        // A future version can have more fields.
+        assert!(
+            TraceContext::from_traceparent(
+                "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"
+            )
+            .is_ok_and(|context| context.is_some())
+        );
+    }
+
+    #[test]
+    fn should_start_a_new_trace_without_a_parent() {
+        let started = Utc::now();
+        let root = Span::start("POST /transactions", SpanKind::Server, None, started);
+        let child = Span::start("apply", SpanKind::Internal, Some(&root.context()), started);
+
+        assert_ne!(root.context().trace_id, 0);
+        assert_eq!(child.context().trace_id, root.context().trace_id);
+        assert_eq!(child.parent, Some(root.context().span_id));
+        assert!(root.to_otlp().get("parentSpanId").is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    account::AccountSnapshot,
+    account_notes::{AccountNotes, AccountNotesError},
+    drift::DriftBaseline,
+    id_allocator::{IdAllocator, IdAllocatorError, IdRange},
+    id_history::IdHistory,
+};
+
+const CLOSING_BALANCES_FILE: &str = "closing-balances.csv";
+const ID_HISTORY_FILE: &str = "transaction-ids.csv";
+const MANIFEST_FILE: &str = "manifest.csv";
+const NOTES_FILE: &str = "notes.csv";
+const PERIODS_DIR: &str = "periods";
+const SYNTHETIC_IDS_FILE: &str = "synthetic-ids";
+
+#[derive(Debug, Error)]
+pub(crate) enum StateError {
+    #[error("Cannot access the state directory: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot read or write a state file: {0}")]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    SyntheticIds(#[from] IdAllocatorError),
+    #[error(transparent)]
+    Notes(#[from] AccountNotesError),
+}
+
+/// A directory where the engine keeps the state that has to survive between runs.
+pub(crate) struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    /// Open the state directory. The directory is created if it doesn't exist.
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, StateError> {
+        fs::create_dir_all(&path)?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    pub(crate) fn manifest(&self) -> Result<Manifest, StateError> {
+        Manifest::load(self.path.join(MANIFEST_FILE))
+    }
+
+    pub(crate) fn period_snapshots(&self) -> Result<PeriodSnapshots, StateError> {
+        PeriodSnapshots::load(self.path.join(PERIODS_DIR))
+    }
+
+    /// The closing balances of the previous run, to compare the balances of this run with.
+    pub(crate) fn drift_baseline(&self) -> Result<DriftBaseline, StateError> {
+        DriftBaseline::load(self.path.join(CLOSING_BALANCES_FILE))
+    }
+
+    /// The notes that operators attached to the accounts.
+    pub(crate) fn notes(&self) -> Result<AccountNotes, StateError> {
+        Ok(AccountNotes::open(self.path.join(NOTES_FILE))?)
+    }
+
+    /// The ids of the funding transactions applied by the previous runs.
+    pub(crate) fn id_history(&self) -> Result<IdHistory, StateError> {
+        IdHistory::load(self.path.join(ID_HISTORY_FILE))
+    }
+
+    /// The allocator of the transaction ids of the entries posted by the engine, continuing after the previous runs.
+    pub(crate) fn synthetic_ids(&self, range: IdRange) -> Result<IdAllocator, StateError> {
+        Ok(IdAllocator::open(
+            self.path.join(SYNTHETIC_IDS_FILE),
+            range,
+        )?)
+    }
+}
+
+/// The closing balances of the accounting periods, one file per period (e.g. `periods/period-1.csv`).
+/// The files have the same format as the output so they can be used to bootstrap a later run.
+pub(crate) struct PeriodSnapshots {
+    path: PathBuf,
+    last_closed: u32,
+}
+
+impl PeriodSnapshots {
+    fn load(path: PathBuf) -> Result<Self, StateError> {
+        fs::create_dir_all(&path)?;
+        let mut last_closed = 0;
+        for entry in fs::read_dir(&path)? {
+            let name = entry?.file_name();
+            let period = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("period-"))
+                .and_then(|name| name.strip_suffix(".csv"))
+                .and_then(|period| period.parse::<u32>().ok());
+            if let Some(period) = period {
+                last_closed = last_closed.max(period);
+            }
+        }
+        Ok(Self { path, last_closed })
+    }
+
+    pub(crate) fn last_closed(&self) -> u32 {
+        self.last_closed
+    }
+
+    /// Write the closing balances of a period. A snapshot is never overwritten and is made read-only once written.
+    /// The balances are written to a temporary file that is renamed once complete, so a snapshot is never partial.
+    pub(crate) fn write(
+        &mut self,
+        period: u32,
+        accounts: &[AccountSnapshot],
+    ) -> Result<PathBuf, StateError> {
+        let path = self.path.join(format!("period-{}.csv", period));
+        if path.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary)?;
+        let mut writer = csv::Writer::from_writer(file);
+        for account in accounts {
+            writer.serialize(account)?;
+        }
+        writer.flush()?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(true);
+        file.set_permissions(permissions)?;
+        fs::rename(&temporary, &path)?;
+
+        self.last_closed = self.last_closed.max(period);
+        Ok(path)
+    }
+}
+
+/// A record of an input file that was already processed.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    sha256: String,
+    name: String,
+    processed_at: String,
+}
+
+/// The input files that were already processed, identified by the hash of their contents.
+/// It's used to avoid applying the same transactions twice when a file is dropped again.
+pub(crate) struct Manifest {
+    path: PathBuf,
+    digests: HashSet<String>,
+}
+
+impl Manifest {
+    fn load(path: PathBuf) -> Result<Self, StateError> {
+        let mut digests = HashSet::new();
+        if path.exists() {
+            let mut reader = csv::Reader::from_path(&path)?;
+            for entry in reader.deserialize::<ManifestEntry>() {
+                digests.insert(entry?.sha256);
+            }
+        }
+        Ok(Self { path, digests })
+    }
+
+    pub(crate) fn contains(&self, digest: &str) -> bool {
+        self.digests.contains(digest)
+    }
+
+    /// Add a file processed at the given time to the manifest.
+    pub(crate) fn record(
+        &mut self,
+        digest: &str,
+        name: &Path,
+        processed_at: DateTime<Utc>,
+    ) -> Result<(), StateError> {
+        let new_file = !self.path.exists();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(new_file)
+            .from_writer(file);
+        writer.serialize(ManifestEntry {
+            sha256: digest.to_string(),
+            name: name.display().to_string(),
+            processed_at: processed_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        })?;
+        writer.flush()?;
+
+        self.digests.insert(digest.to_string());
+        Ok(())
+    }
+}
+
+/// The SHA-256 hash of the contents of a file as a hex string.
+pub(crate) fn file_digest<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+
+    #[test]
+    fn should_remember_processed_files_between_runs() {
+        let dir = TempDir::new().unwrap();
+        let mut input = NamedTempFile::new().unwrap();
+        input.write_all(b"type,client,tx,amount\n").unwrap();
+        input.flush().unwrap();
+        let digest = file_digest(input.path()).unwrap();
+
+        let state = StateDir::open(dir.path().join("state")).unwrap();
+        let mut manifest = state.manifest().unwrap();
+        assert!(!manifest.contains(&digest));
+        manifest
+            .record(&digest, input.path(), DateTime::UNIX_EPOCH)
+            .unwrap();
+        manifest
+            .record("other", Path::new("other.csv"), DateTime::UNIX_EPOCH)
+            .unwrap();
+
+        let manifest = state.manifest().unwrap();
+        assert!(manifest.contains(&digest));
+        assert!(manifest.contains("other"));
+    }
+
+    #[test]
+    fn should_never_overwrite_period_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let mut snapshots = state.period_snapshots().unwrap();
+        assert_eq!(snapshots.last_closed(), 0);
+
+        let path = snapshots.write(1, &[]).unwrap();
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+        assert!(matches!(snapshots.write(1, &[]), Err(StateError::Io(_))));
+        assert_eq!(state.period_snapshots().unwrap().last_closed(), 1);
+    }
+
+    #[test]
+    fn should_hash_file_contents() {
+        let mut input = NamedTempFile::new().unwrap();
+        input.write_all(b"abc").unwrap();
+        input.flush().unwrap();
+
+        assert_eq!(
+            file_digest(input.path()).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Display},
+};
+
+use serde::Serialize;
+
+use crate::{
+    amount_stats::AmountSummary,
+    drift::Drift,
+    transaction_types::{ClientId, TransactionType},
+};
+
+/// Counters of a run that are printed on stderr once all the transactions were processed.
+/// Each stage keeps its own summary and the summaries are merged at the end.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub(crate) struct Summary {
+    pub(crate) parse_errors: u64,
+    pub(crate) rejected: u64,
+    pub(crate) failed: u64,
+    pub(crate) applied: u64,
+    /// Rejected transactions of blocked clients, by client.
+    pub(crate) blocked: BTreeMap<ClientId, u64>,
+    /// Transactions that failed because of an internal error rather than being rejected, e.g. because the transaction
+    /// store failed. Counted in `failed` too.
+    pub(crate) internal: u64,
+    /// Disputes, resolves and chargebacks that were not applied because the client had too many lookups of
+    /// transactions on disk, by client. Counted in `failed` too.
+    pub(crate) throttled: BTreeMap<ClientId, u64>,
+    /// Disputes, resolves and chargebacks that were applied after waiting for the transaction they reference. Counted
+    /// in `applied` too.
+    pub(crate) reordered: u64,
+    /// Funding transactions that were not applied because a previous run already applied them, with
+    /// `--id-collision-policy accept-if-identical`.
+    pub(crate) redelivered: u64,
+    /// The outcome of the transactions of each type.
+    pub(crate) by_type: BTreeMap<TransactionType, TypeCounts>,
+    /// The rejected and failed transactions by reason, the name of the error (e.g. `InsufficientFunds`).
+    pub(crate) reasons: BTreeMap<String, u64>,
+    /// The amounts of the applied transactions, with the largest ones and the ones flagged for a review.
+    #[serde(skip_serializing_if = "AmountSummary::is_empty")]
+    pub(crate) amounts: AmountSummary,
+    /// How the closing balances changed since the previous run, when there is a state directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) drift: Option<Drift>,
+}
+
+/// The outcome of the transactions of a type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct TypeCounts {
+    pub(crate) applied: u64,
+    pub(crate) rejected: u64,
+    pub(crate) failed: u64,
+}
+
+impl Summary {
+    pub(crate) fn count_applied(&mut self, transaction_type: TransactionType) {
+        self.applied += 1;
+        self.by_type.entry(transaction_type).or_default().applied += 1;
+    }
+
+    /// Count a transaction rejected by the validator chain.
+    pub(crate) fn count_rejected(
+        &mut self,
+        transaction_type: TransactionType,
+        reason: &impl Debug,
+    ) {
+        self.rejected += 1;
+        self.by_type.entry(transaction_type).or_default().rejected += 1;
+        *self.reasons.entry(reason_name(reason)).or_default() += 1;
+    }
+
+    /// Count a transaction that could not be applied.
+    pub(crate) fn count_failed(&mut self, transaction_type: TransactionType, reason: &impl Debug) {
+        self.failed += 1;
+        self.by_type.entry(transaction_type).or_default().failed += 1;
+        *self.reasons.entry(reason_name(reason)).or_default() += 1;
+    }
+
+    pub(crate) fn merge(&mut self, other: &Summary) {
+        self.parse_errors += other.parse_errors;
+        self.rejected += other.rejected;
+        self.failed += other.failed;
+        self.applied += other.applied;
+        self.internal += other.internal;
+        self.reordered += other.reordered;
+        self.redelivered += other.redelivered;
+        for (client, count) in &other.blocked {
+            *self.blocked.entry(*client).or_default() += count;
+        }
+        for (client, count) in &other.throttled {
+            *self.throttled.entry(*client).or_default() += count;
+        }
+        for (transaction_type, counts) in &other.by_type {
+            let merged = self.by_type.entry(*transaction_type).or_default();
+            merged.applied += counts.applied;
+            merged.rejected += counts.rejected;
+            merged.failed += counts.failed;
+        }
+        for (reason, count) in &other.reasons {
+            *self.reasons.entry(reason.clone()).or_default() += count;
+        }
+        self.amounts.merge(&other.amounts);
+        // The drift is computed once for the whole run.
+        if other.drift.is_some() {
+            self.drift.clone_from(&other.drift);
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Summary: {} applied, {} rejected by validation, {} failed to apply, {} could not be parsed",
+            self.applied, self.rejected, self.failed, self.parse_errors
+        )?;
+
+        if !self.blocked.is_empty() {
+            write!(
+                f,
+                "\n!!! BLOCKED CLIENTS: {} transactions of {} blocked clients were rejected: {}",
+                self.blocked.values().sum::<u64>(),
+                self.blocked.len(),
+                by_client(&self.blocked)
+            )?;
+        }
+        if self.internal > 0 {
+            write!(
+                f,
+                "\n!!! INTERNAL ERRORS: {} transactions failed because of internal errors and can be processed again",
+                self.internal
+            )?;
+        }
+        if !self.throttled.is_empty() {
+            write!(
+                f,
+                "\nThrottled disk lookups: {} disputes of {} clients were not applied: {}",
+                self.throttled.values().sum::<u64>(),
+                self.throttled.len(),
+                by_client(&self.throttled)
+            )?;
+        }
+        if self.reordered > 0 {
+            write!(
+                f,
+                "\nReordered disputes: {} disputes were applied after the transaction they reference arrived",
+                self.reordered
+            )?;
+        }
+        if self.redelivered > 0 {
+            write!(
+                f,
+                "\nRedelivered transactions: {} transactions were already applied by a previous run and were skipped",
+                self.redelivered
+            )?;
+        }
+        if !self.amounts.is_empty() {
+            write!(f, "\n{}", self.amounts)?;
+        }
+        if let Some(drift) = &self.drift {
+            write!(f, "\n{}", drift)?;
+        }
+        Ok(())
+    }
+}
+
+// The name of the variant of an error, e.g. `AmountTooLarge` for `AmountTooLarge(Amount(100))`. Unlike the message, it
+// doesn't depend on the transaction, so the reasons can be counted.
+/// The name of a rejection or failure reason, the name of its variant.
+pub(crate) fn reason_name(reason: &impl Debug) -> String {
+    let debug = format!("{:?}", reason);
+    debug
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The counts of the clients, e.g. `2 (1), 7 (2)`.
+fn by_client(counts: &BTreeMap<ClientId, u64>) -> String {
+    counts
+        .iter()
+        .map(|(client, count)| format!("{} ({})", client, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{account::AccountError, pipeline::ValidationError};
+
+    use super::*;
+
+    #[test]
+    fn should_count_by_type_and_reason() {
+        let mut summary = Summary::default();
+        summary.count_applied(TransactionType::Deposit);
+        summary.count_failed(
+            TransactionType::Withdrawal,
+            &AccountError::InsufficientFunds,
+        );
+        let mut other = Summary::default();
+        other.count_rejected(
+            TransactionType::Deposit,
+            &ValidationError::AmountTooLarge(100.0.into()),
+        );
+        other.count_failed(
+            TransactionType::Withdrawal,
+            &AccountError::InsufficientFunds,
+        );
+
+        summary.merge(&other);
+
+        assert_eq!(
+            summary.by_type,
+            BTreeMap::from([
+                (
+                    TransactionType::Deposit,
+                    TypeCounts {
+                        applied: 1,
+                        rejected: 1,
+                        failed: 0
+                    }
+                ),
+                (
+                    TransactionType::Withdrawal,
+                    TypeCounts {
+                        applied: 0,
+                        rejected: 0,
+                        failed: 2
+                    }
+                ),
+            ])
+        );
+        assert_eq!(
+            summary.reasons,
+            BTreeMap::from([
+                ("AmountTooLarge".to_string(), 1),
+                ("InsufficientFunds".to_string(), 2)
+            ])
+        );
+        assert_eq!(
+            (summary.applied, summary.rejected, summary.failed),
+            (1, 1, 2)
+        );
+    }
+
+    #[test]
+    fn should_merge_and_display_blocked_clients() {
+        let mut summary = Summary {
+            applied: 3,
+            blocked: BTreeMap::from([(7.into(), 1)]),
+            ..Default::default()
+        };
+        summary.merge(&Summary {
+            rejected: 2,
+            blocked: BTreeMap::from([(7.into(), 1), (2.into(), 1)]),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            summary.to_string(),
+            "Summary: 3 applied, 2 rejected by validation, 0 failed to apply, 0 could not be parsed
+!!! BLOCKED CLIENTS: 3 transactions of 2 blocked clients were rejected: 2 (1), 7 (2)"
+        );
+    }
+}
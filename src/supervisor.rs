@@ -0,0 +1,338 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::FutureExt;
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::logging::log_event;
+
+// Supervision of the long running components of the engine: the validators and workers, the watched directory, the
+// signal handlers. Every component reports its health to the supervisor, which restarts the components that failed
+// according to their restart policy. Components that hold state that would be lost on a restart, like a worker and
+// its accounts, are never restarted. When a critical component fails and can't be restarted, the supervisor escalates
+// and the process exits rather than keep running without it.
+
+/// What to do when a component fails, i.e. returns an error or panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RestartPolicy {
+    Never,
+    /// Restart the component up to `max_restarts` times. The first restart waits `backoff` and every next one waits
+    /// twice as long as the previous one.
+    OnFailure {
+        max_restarts: u32,
+        backoff: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ComponentState {
+    Running,
+    /// Failed and waiting to be restarted.
+    Restarting,
+    /// Finished its work.
+    Stopped,
+    /// Failed and won't be restarted.
+    Failed,
+}
+
+impl ComponentState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ComponentState::Running => "running",
+            ComponentState::Restarting => "restarting",
+            ComponentState::Stopped => "stopped",
+            ComponentState::Failed => "failed",
+        }
+    }
+}
+
+/// The health of a component, as last reported to the supervisor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ComponentHealth {
+    pub(crate) component: String,
+    /// Whether the process exits when the component fails and can't be restarted.
+    pub(crate) critical: bool,
+    pub(crate) state: ComponentState,
+    pub(crate) restarts: u32,
+    pub(crate) last_error: Option<String>,
+}
+
+/// A critical component failed and could not be restarted.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Critical component {component} failed: {error}")]
+pub(crate) struct Escalation {
+    pub(crate) component: String,
+    pub(crate) error: String,
+}
+
+/// Supervises the components of the engine. Clones supervise the same components.
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    components: Arc<Mutex<BTreeMap<String, ComponentHealth>>>,
+    escalation: Arc<watch::Sender<Option<Escalation>>>,
+}
+
+impl Supervisor {
+    pub(crate) fn new() -> Self {
+        Self {
+            components: Arc::default(),
+            escalation: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Run a component that is never restarted, e.g. because it owns state, and report its health. The output and
+    /// panics of the component are passed on to whoever awaits it.
+    pub(crate) fn watch<F>(
+        &self,
+        component: String,
+        critical: bool,
+        future: F,
+    ) -> impl Future<Output = F::Output> + use<F>
+    where
+        F: Future,
+    {
+        let supervisor = self.clone();
+        async move {
+            supervisor.report(&component, critical, ComponentState::Running, None);
+            match AssertUnwindSafe(future).catch_unwind().await {
+                Ok(output) => {
+                    supervisor.report(&component, critical, ComponentState::Stopped, None);
+                    output
+                }
+                Err(panic) => {
+                    supervisor.fail(&component, critical, panic_message(panic.as_ref()));
+                    std::panic::resume_unwind(panic)
+                }
+            }
+        }
+    }
+
+    /// Spawn a component that is started again by `start` when it fails, according to its restart policy.
+    pub(crate) fn spawn<S, F>(
+        &self,
+        component: &str,
+        policy: RestartPolicy,
+        critical: bool,
+        mut start: S,
+    ) -> JoinHandle<()>
+    where
+        S: FnMut() -> F + Send + 'static,
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let (supervisor, component) = (self.clone(), component.to_string());
+        tokio::spawn(async move {
+            let mut restarts = 0;
+            loop {
+                supervisor.report(&component, critical, ComponentState::Running, None);
+                let error = match tokio::spawn(start()).await {
+                    Ok(Ok(())) => None,
+                    Ok(Err(error)) => Some(error),
+                    Err(err) if err.is_panic() => Some(panic_message(err.into_panic().as_ref())),
+                    Err(_) => None,
+                };
+                let Some(error) = error else {
+                    supervisor.report(&component, critical, ComponentState::Stopped, None);
+                    return;
+                };
+                match policy {
+                    RestartPolicy::OnFailure {
+                        max_restarts,
+                        backoff,
+                    } if restarts < max_restarts => {
+                        supervisor.report(
+                            &component,
+                            critical,
+                            ComponentState::Restarting,
+                            Some(error),
+                        );
+                        tokio::time::sleep(backoff * 2u32.saturating_pow(restarts)).await;
+                        restarts += 1;
+                    }
+                    _ => {
+                        supervisor.fail(&component, critical, error);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// The health of every component, by name.
+    pub(crate) fn health(&self) -> Vec<ComponentHealth> {
+        self.components
+            .lock()
+            .expect("Components lock is never poisoned.")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// The first critical component that failed for good, if any did.
+    pub(crate) fn escalation(&self) -> Option<Escalation> {
+        self.escalation.borrow().clone()
+    }
+
+    /// Wait until a critical component fails for good.
+    pub(crate) async fn escalated(&self) -> Escalation {
+        let mut escalation = self.escalation.subscribe();
+        let escalated = escalation
+            .wait_for(Option::is_some)
+            .await
+            .expect("The supervisor holds the sender.");
+        escalated.clone().expect("Waited for an escalation.")
+    }
+
+    fn fail(&self, component: &str, critical: bool, error: String) {
+        self.report(
+            component,
+            critical,
+            ComponentState::Failed,
+            Some(error.clone()),
+        );
+        if critical {
+            self.escalation.send_if_modified(|escalation| {
+                let first = escalation.is_none();
+                if first {
+                    *escalation = Some(Escalation {
+                        component: component.to_string(),
+                        error,
+                    });
+                }
+                first
+            });
+        }
+    }
+
+    fn report(
+        &self,
+        component: &str,
+        critical: bool,
+        state: ComponentState,
+        error: Option<String>,
+    ) {
+        // Only the failures are logged, a run starts and stops every component.
+        if let Some(error) = &error {
+            log_event(
+                "component_state",
+                &[
+                    ("component", &component),
+                    ("state", &state.as_str()),
+                    ("error", error),
+                ],
+            );
+        }
+        let mut components = self
+            .components
+            .lock()
+            .expect("Components lock is never poisoned.");
+        let health = components
+            .entry(component.to_string())
+            .or_insert_with(|| ComponentHealth {
+                component: component.to_string(),
+                critical,
+                state,
+                restarts: 0,
+                last_error: None,
+            });
+        health.state = state;
+        if state == ComponentState::Restarting {
+            health.restarts += 1;
+        }
+        if error.is_some() {
+            health.last_error = error;
+        }
+    }
+}
+
+// The message of a panic, which is a string unless the panic was raised with another payload.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "panicked".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_restart_failed_components_with_backoff_and_escalate() {
+        let supervisor = Supervisor::new();
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&attempts);
+        let component = supervisor.spawn(
+            "reader",
+            RestartPolicy::OnFailure {
+                max_restarts: 2,
+                backoff: Duration::from_millis(1),
+            },
+            true,
+            move || {
+                let attempt = counted.fetch_add(1, Ordering::SeqCst);
+                async move { Err(format!("attempt {}", attempt)) }
+            },
+        );
+        component.await.unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            supervisor.health(),
+            vec![ComponentHealth {
+                component: "reader".to_string(),
+                critical: true,
+                state: ComponentState::Failed,
+                restarts: 2,
+                last_error: Some("attempt 2".to_string()),
+            }]
+        );
+        assert_eq!(
+            supervisor.escalated().await,
+            Escalation {
+                component: "reader".to_string(),
+                error: "attempt 2".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn should_report_panics_of_watched_components() {
+        let supervisor = Supervisor::new();
+        let stopped = supervisor
+            .watch("sink".to_string(), false, async { 1 })
+            .await;
+        let panicked = tokio::spawn(supervisor.watch("worker-0".to_string(), true, async {
+            panic!("store is gone")
+        }))
+        .await;
+
+        assert_eq!(stopped, 1);
+        assert!(panicked.unwrap_err().is_panic());
+        let states: Vec<_> = supervisor
+            .health()
+            .into_iter()
+            .map(|health| (health.component, health.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("sink".to_string(), ComponentState::Stopped),
+                ("worker-0".to_string(), ComponentState::Failed),
+            ]
+        );
+        assert_eq!(supervisor.escalation().unwrap().component, "worker-0");
+    }
+}
@@ -0,0 +1,300 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::{
+    account::AccountSnapshot,
+    csv_reader::LineParser,
+    engine::ShardedEngine,
+    ingest::{Ingress, SourceHandle},
+    json,
+    logging::log_event,
+    transaction_types::{ClientId, Transaction},
+};
+
+// Input from TCP connections, to drive the engine from a test harness or another process without writing files. Every
+// line sent on a connection is either a transaction, as a CSV record (the first line can be a header) or as a JSON
+// object with the fields of the CSV input, or a query: `query <CLIENT>` for the accounts of a client and `query` for
+// all the accounts. A query is answered with the accounts as a JSON array on a line, once the transactions sent before
+// it on the same connection were applied. A line that can't be parsed is answered with `{"error": ...}`, the
+// transactions are not acknowledged.
+//
+// Every connection is a source of its own, so the transactions of a connection reach the workers in the order they
+// were sent, like the rows of a file.
+
+// A line of a connection.
+#[derive(Debug, PartialEq, Eq)]
+enum Request<'a> {
+    Query(Option<ClientId>),
+    Json(&'a str),
+    Csv(&'a str),
+}
+
+impl<'a> Request<'a> {
+    fn parse(line: &'a str) -> Result<Self, String> {
+        let line = line.trim();
+        let mut words = line.split_whitespace();
+        if words
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case("query"))
+        {
+            let client = match (words.next(), words.next()) {
+                (None, _) => None,
+                (Some(client), None) => Some(
+                    client
+                        .parse::<u16>()
+                        .map_err(|err| format!("Invalid client id: {}", err))?
+                        .into(),
+                ),
+                (Some(_), Some(_)) => return Err("A query takes at most one client id.".into()),
+            };
+            Ok(Self::Query(client))
+        } else if line.starts_with('{') {
+            Ok(Self::Json(line))
+        } else {
+            Ok(Self::Csv(line))
+        }
+    }
+}
+
+/// Accept connections on an address and queue the transactions they send until `stop` completes. The connections that
+/// are still open are closed once their transactions were handed over to the workers.
+pub(crate) async fn serve(
+    address: SocketAddr,
+    ingress: &Ingress,
+    workers: ShardedEngine,
+    lenient_amounts: bool,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    log_event("tcp_listening", &[("address", &listener.local_addr()?)]);
+    accept(listener, ingress, workers, lenient_amounts, stop).await
+}
+
+async fn accept(
+    listener: TcpListener,
+    ingress: &Ingress,
+    workers: ShardedEngine,
+    lenient_amounts: bool,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut stop = std::pin::pin!(stop);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let connection = Connection {
+                    source: ingress.source("tcp"),
+                    workers: workers.clone(),
+                    parser: LineParser::new(lenient_amounts),
+                    queries: 0,
+                };
+                connections.spawn(connection.serve(stream, peer, shutdown.clone()));
+            }
+            // Reap the connections that were closed by their peer.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            () = &mut stop => break,
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+// The state of a connection.
+struct Connection {
+    source: SourceHandle,
+    workers: ShardedEngine,
+    parser: LineParser,
+    queries: u64,
+}
+
+impl Connection {
+    async fn serve(
+        mut self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = tokio::select! {
+                line = lines.next_line() => line,
+                _ = shutdown.wait_for(|stopped| *stopped) => break,
+            };
+            let reply = match line {
+                Ok(Some(line)) => self.handle(&line).await,
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("Connection from {} encountered an error: {}", peer, err);
+                    break;
+                }
+            };
+            if let Some(mut reply) = reply {
+                reply.push(b'\n');
+                if let Err(err) = writer.write_all(&reply).await {
+                    eprintln!("Cannot reply to {}: {}", peer, err);
+                    break;
+                }
+            }
+        }
+
+        let stats = self.source.finish().await;
+        log_event(
+            "tcp_connection_closed",
+            &[
+                ("peer", &peer),
+                ("transactions", &stats.received),
+                ("parse_errors", &stats.parse_errors),
+                ("queries", &self.queries),
+            ],
+        );
+    }
+
+    // Handle a line and return the reply, if there is one.
+    async fn handle(&mut self, line: &str) -> Option<Vec<u8>> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let parsed = match Request::parse(line) {
+            Ok(Request::Query(client)) => {
+                self.queries += 1;
+                let accounts = self.query(client).await;
+                return Some(json::to_vec(&accounts).expect("Accounts can be serialized."));
+            }
+            Ok(Request::Json(line)) => json::from_slice::<Transaction>(line.as_bytes())
+                .map(Some)
+                .map_err(|err| err.to_string()),
+            Ok(Request::Csv(line)) => self
+                .parser
+                .parse(line)
+                .transpose()
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err),
+        };
+        match parsed {
+            Ok(Some(transaction)) => {
+                // The workers are only gone when the engine is stopping.
+                let _ = self.source.send(transaction).await;
+                None
+            }
+            // The header of the CSV records.
+            Ok(None) => None,
+            Err(err) => {
+                self.source.parse_error();
+                let error = serde_json::json!({ "error": err });
+                Some(json::to_vec(&error).expect("Errors can be serialized."))
+            }
+        }
+    }
+
+    // The accounts of a client, or of all the clients, once the transactions queued before were handed over to the
+    // workers. The query is queued behind them, so they are applied when it's answered.
+    async fn query(&self, client: Option<ClientId>) -> Vec<AccountSnapshot> {
+        self.source.settled().await;
+        self.workers.query_accounts(client).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::{
+        NUM_WORKERS,
+        enrichment::Enrichers,
+        transaction_processor::{ProcessorMessage, ProcessorOptions, TransactionProcessor},
+    };
+
+    use super::*;
+
+    #[test]
+    fn should_parse_requests() {
+        assert_eq!(
+            Request::parse(" QUERY 7 "),
+            Ok(Request::Query(Some(7.into())))
+        );
+        assert_eq!(Request::parse("query"), Ok(Request::Query(None)));
+        assert!(Request::parse("query 1 2").is_err());
+        assert!(Request::parse("query x").is_err());
+        assert_eq!(
+            Request::parse(r#"{"type": "deposit"}"#),
+            Ok(Request::Json(r#"{"type": "deposit"}"#))
+        );
+        assert_eq!(
+            Request::parse("deposit, 1, 1, 1.0"),
+            Ok(Request::Csv("deposit, 1, 1, 1.0"))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_answer_queries_after_the_transactions_sent_before() {
+        let mut senders = Vec::new();
+        let mut processors = Vec::new();
+        for _ in 0..NUM_WORKERS {
+            let (tx, rx) = mpsc::channel(1024);
+            senders.push(tx);
+            processors.push(tokio::spawn(
+                TransactionProcessor::new(ProcessorOptions::default()).run(rx),
+            ));
+        }
+        let workers = ShardedEngine::new(senders);
+        let (ingress, dispatcher) = Ingress::start(workers.clone(), None, Enrichers::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop_tx, stop) = oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let ingress = ingress.clone();
+            let workers = workers.clone();
+            async move {
+                let stop = async {
+                    let _ = stop.await;
+                };
+                accept(listener, &ingress, workers, false, stop).await
+            }
+        });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut replies = BufReader::new(reader).lines();
+        writer
+            .write_all(
+                b"type,client,tx,amount\ndeposit,1,1,2.5\n{\"type\": \"withdrawal\", \"client\": 1, \"tx\": 2, \"amount\": 1}\ndeposit,2,3,4.0\nbogus\nquery 1\n",
+            )
+            .await
+            .unwrap();
+
+        let error: serde_json::Value =
+            serde_json::from_str(&replies.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(error.get("error").is_some());
+        let accounts: serde_json::Value =
+            serde_json::from_str(&replies.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(accounts.as_array().unwrap().len(), 1);
+        assert_eq!(accounts[0]["client"], 1);
+        assert_eq!(accounts[0]["available"], "1.5");
+
+        writer.write_all(b"query\n").await.unwrap();
+        let accounts: serde_json::Value =
+            serde_json::from_str(&replies.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(accounts.as_array().unwrap().len(), 2);
+
+        stop_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(ingress.parse_errors(), 1);
+        drop(ingress);
+        dispatcher.await.unwrap();
+        workers.broadcast(ProcessorMessage::shutdown).await.unwrap();
+        for processor in processors {
+            processor.await.unwrap();
+        }
+    }
+}
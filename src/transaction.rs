@@ -0,0 +1,390 @@
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Transactions built by the programs that embed the engine rather than read from an input file. The input goes
+// through the validator chain, which rejects the records that don't make sense, but a program that builds its
+// transactions in code should learn about a deposit without an amount when it builds it, not from a rejects file.
+// `TransactionBuilder` checks the shape of a transaction when it's built: which fields its type needs and which it
+// cannot have, and that its amount is a positive amount of at most 4 decimal places. Whether the transaction can be
+// applied to the account (enough funds, a disputed transaction that exists) is still up to the engine: the built
+// transactions are handed to it with `ShardedEngine::submit`.
+
+/// The name of the default sub-account of a client.
+pub const MAIN_ACCOUNT: &str = "main";
+// Maximum length of a sub-account name.
+const MAX_ACCOUNT_NAME_LEN: usize = 32;
+// Number of decimal places that are kept for amounts.
+const AMOUNT_SCALE: u32 = 4;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Move funds between two sub-accounts of the same client.
+    Move,
+    /// Put funds aside until they are released to one of the parties.
+    #[serde(rename = "escrow_hold")]
+    EscrowHold,
+    /// Release the funds of a previous escrow hold.
+    #[serde(rename = "escrow_release")]
+    EscrowRelease,
+    /// Reverse a chargeback after the merchant won the representment.
+    #[serde(rename = "chargeback_reversal")]
+    ChargebackReversal,
+}
+
+/// The names used for the transaction types in the input.
+impl Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Move => "move",
+            TransactionType::EscrowHold => "escrow_hold",
+            TransactionType::EscrowRelease => "escrow_release",
+            TransactionType::ChargebackReversal => "chargeback_reversal",
+        };
+        f.write_str(name)
+    }
+}
+
+impl TransactionType {
+    /// Deposits, withdrawals, moves and escrow holds move funds. All the other types reference a previous transaction.
+    pub fn is_funding(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Move
+                | TransactionType::EscrowHold
+        )
+    }
+
+    // Whether the type says who opened or closed a dispute.
+    fn has_source(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+        )
+    }
+}
+
+/// The party that receives the funds when an escrow hold is released.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EscrowParty {
+    /// The funds go back to the available funds of the client.
+    Client,
+    /// The funds are paid out to the third party.
+    Beneficiary,
+}
+
+/// Who opened a dispute, and so who is allowed to resolve it or charge it back.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisputeSource {
+    /// The card issuer of the client.
+    Issuer,
+    /// An operator of the platform.
+    Internal,
+    /// A partner that the client was onboarded through.
+    Partner,
+}
+
+impl Display for DisputeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DisputeSource::Issuer => "issuer",
+            DisputeSource::Internal => "internal",
+            DisputeSource::Partner => "partner",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Check the name of a sub-account other than the main one. Names are case sensitive and can contain ASCII letters,
+/// digits, `_` and `-`.
+pub fn check_account_name(name: &str) -> Result<(), &'static str> {
+    if name.len() > MAX_ACCOUNT_NAME_LEN {
+        return Err("account name is longer than 32 characters");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("account name can only contain letters, digits, '_' and '-'");
+    }
+    Ok(())
+}
+
+/// Why a transaction could not be built.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TransactionError {
+    #[error("A {0} needs an amount.")]
+    AmountRequired(TransactionType),
+    #[error("A {0} refers to a previous transaction and cannot have an amount.")]
+    AmountNotAllowed(TransactionType),
+    #[error("Amount {0} is not positive.")]
+    NotPositive(Decimal),
+    #[error("Amount {0} has more than 4 decimal places.")]
+    TooPrecise(Decimal),
+    #[error("Invalid sub-account '{name}': {reason}.")]
+    InvalidAccount { name: String, reason: &'static str },
+    #[error("A move needs another sub-account of the client to receive the funds.")]
+    ToAccountRequired,
+    #[error("Only a move has a sub-account that receives the funds.")]
+    ToAccountNotAllowed,
+    #[error("An escrow release needs the party that receives the funds.")]
+    ReleaseToRequired,
+    #[error("Only an escrow release has a party that receives the funds.")]
+    ReleaseToNotAllowed,
+    #[error("A {0} doesn't open or close a dispute and cannot have a dispute source.")]
+    SourceNotAllowed(TransactionType),
+}
+
+/// A transaction whose shape was checked by `TransactionBuilder`, to be applied with
+/// [`ShardedEngine::submit`](crate::engine::ShardedEngine::submit).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewTransaction {
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+    account: Option<String>,
+    to_account: Option<String>,
+    release_to: Option<EscrowParty>,
+    source: Option<DisputeSource>,
+}
+
+impl NewTransaction {
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn id(&self) -> u32 {
+        self.tx
+    }
+
+    /// The amount of a deposit, withdrawal, move or escrow hold.
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    /// The sub-account of the client, if it's not the main one.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// The sub-account that receives the funds of a move.
+    pub fn to_account(&self) -> Option<&str> {
+        self.to_account.as_deref()
+    }
+
+    pub fn release_to(&self) -> Option<EscrowParty> {
+        self.release_to
+    }
+
+    pub fn source(&self) -> Option<DisputeSource> {
+        self.source
+    }
+}
+
+/// Builds a transaction and checks its shape, e.g.
+/// `TransactionBuilder::new(TransactionType::Deposit, 1, 1).with_amount(Decimal::new(15, 1)).build()`.
+#[derive(Debug, Clone)]
+pub struct TransactionBuilder {
+    transaction: NewTransaction,
+}
+
+impl TransactionBuilder {
+    pub fn new(transaction_type: TransactionType, client: u16, tx: u32) -> Self {
+        Self {
+            transaction: NewTransaction {
+                transaction_type,
+                client,
+                tx,
+                amount: None,
+                account: None,
+                to_account: None,
+                release_to: None,
+                source: None,
+            },
+        }
+    }
+
+    pub fn with_amount(mut self, amount: Decimal) -> Self {
+        self.transaction.amount = Some(amount);
+        self
+    }
+
+    /// The sub-account of the client. The main sub-account is used if it's not set.
+    pub fn with_account(mut self, account: &str) -> Self {
+        self.transaction.account = Some(account.to_string());
+        self
+    }
+
+    /// The sub-account that receives the funds of a move.
+    pub fn with_to_account(mut self, account: &str) -> Self {
+        self.transaction.to_account = Some(account.to_string());
+        self
+    }
+
+    pub fn with_release_to(mut self, party: EscrowParty) -> Self {
+        self.transaction.release_to = Some(party);
+        self
+    }
+
+    /// Who opens or closes the dispute.
+    pub fn with_source(mut self, source: DisputeSource) -> Self {
+        self.transaction.source = Some(source);
+        self
+    }
+
+    pub fn build(self) -> Result<NewTransaction, TransactionError> {
+        let mut transaction = self.transaction;
+        let transaction_type = transaction.transaction_type;
+        match (transaction_type.is_funding(), transaction.amount) {
+            (true, None) => return Err(TransactionError::AmountRequired(transaction_type)),
+            (true, Some(amount)) => {
+                if amount <= Decimal::ZERO {
+                    return Err(TransactionError::NotPositive(amount));
+                }
+                if amount.normalize().scale() > AMOUNT_SCALE {
+                    return Err(TransactionError::TooPrecise(amount));
+                }
+            }
+            (false, Some(_)) => return Err(TransactionError::AmountNotAllowed(transaction_type)),
+            (false, None) => {}
+        }
+
+        transaction.account = main_as_none(transaction.account)?;
+        let to_account = main_as_none(transaction.to_account.clone())?;
+        let moved = transaction_type == TransactionType::Move;
+        if moved && (transaction.to_account.is_none() || to_account == transaction.account) {
+            return Err(TransactionError::ToAccountRequired);
+        }
+        if !moved && transaction.to_account.is_some() {
+            return Err(TransactionError::ToAccountNotAllowed);
+        }
+        transaction.to_account = transaction
+            .to_account
+            .map(|_| to_account.unwrap_or_else(|| MAIN_ACCOUNT.to_string()));
+
+        let released = transaction_type == TransactionType::EscrowRelease;
+        match (released, transaction.release_to) {
+            (true, None) => return Err(TransactionError::ReleaseToRequired),
+            (false, Some(_)) => return Err(TransactionError::ReleaseToNotAllowed),
+            _ => {}
+        }
+        if transaction.source.is_some() && !transaction_type.has_source() {
+            return Err(TransactionError::SourceNotAllowed(transaction_type));
+        }
+        Ok(transaction)
+    }
+}
+
+// Check a sub-account name, the main sub-account being represented by no name.
+fn main_as_none(name: Option<String>) -> Result<Option<String>, TransactionError> {
+    match name {
+        Some(name) if name.is_empty() || name == MAIN_ACCOUNT => Ok(None),
+        Some(name) => match check_account_name(&name) {
+            Ok(()) => Ok(Some(name)),
+            Err(reason) => Err(TransactionError::InvalidAccount { name, reason }),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_build_transactions_of_a_valid_shape() {
+        let deposit = TransactionBuilder::new(TransactionType::Deposit, 1, 2)
+            .with_amount(Decimal::new(15, 1))
+            .with_account("main")
+            .build()
+            .unwrap();
+        assert_eq!(deposit.amount(), Some(Decimal::new(15, 1)));
+        assert_eq!(deposit.account(), None);
+
+        let moved = TransactionBuilder::new(TransactionType::Move, 1, 3)
+            .with_amount(Decimal::ONE)
+            .with_to_account("savings")
+            .build()
+            .unwrap();
+        assert_eq!(moved.to_account(), Some("savings"));
+
+        let dispute = TransactionBuilder::new(TransactionType::Dispute, 1, 2)
+            .with_source(DisputeSource::Issuer)
+            .build()
+            .unwrap();
+        assert_eq!(dispute.source(), Some(DisputeSource::Issuer));
+    }
+
+    #[test]
+    fn should_refuse_transactions_that_make_no_sense() {
+        let build = |builder: TransactionBuilder| builder.build().unwrap_err();
+        let deposit = TransactionBuilder::new(TransactionType::Deposit, 1, 2);
+        let dispute = TransactionBuilder::new(TransactionType::Dispute, 1, 2);
+
+        assert_eq!(
+            build(deposit.clone()),
+            TransactionError::AmountRequired(TransactionType::Deposit)
+        );
+        assert_eq!(
+            build(deposit.clone().with_amount(Decimal::new(-1, 0))),
+            TransactionError::NotPositive(Decimal::new(-1, 0))
+        );
+        assert_eq!(
+            build(deposit.clone().with_amount(Decimal::new(12345, 5))),
+            TransactionError::TooPrecise(Decimal::new(12345, 5))
+        );
+        assert!(matches!(
+            build(deposit.with_amount(Decimal::ONE).with_account("a b")),
+            TransactionError::InvalidAccount { .. }
+        ));
+        assert_eq!(
+            build(dispute.clone().with_amount(Decimal::ONE)),
+            TransactionError::AmountNotAllowed(TransactionType::Dispute)
+        );
+        assert_eq!(
+            build(dispute.with_release_to(EscrowParty::Client)),
+            TransactionError::ReleaseToNotAllowed
+        );
+        assert_eq!(
+            build(
+                TransactionBuilder::new(TransactionType::Move, 1, 3)
+                    .with_amount(Decimal::ONE)
+                    .with_to_account("main")
+            ),
+            TransactionError::ToAccountRequired
+        );
+        assert_eq!(
+            build(TransactionBuilder::new(
+                TransactionType::EscrowRelease,
+                1,
+                4
+            )),
+            TransactionError::ReleaseToRequired
+        );
+    }
+}
@@ -1,29 +1,306 @@
 use std::{
-    collections::{HashMap, hash_map::Entry},
-    error::Error,
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    fmt::Display,
+    io,
 };
 
-use tokio::sync::mpsc;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use clap::ValueEnum;
+
+use rust_decimal::Decimal;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::{
-    account::Account,
-    transaction_types::{ClientId, Transaction, TransactionType},
+    account::{
+        Account, AccountError, AccountSnapshot, Compaction, InternalError, LockedOperations,
+        RollingReserve,
+    },
+    amount_stats::AmountReview,
+    archive::HistoryArchive,
+    backup::{self, BackupRequest},
+    client_trace::{CacheLookup, ClientTrace},
+    clock::{SharedClock, SystemClock},
+    dispute::DisputeState,
+    dispute_policy::DisputePolicy,
+    engine::{Shard, WorkerId},
+    events::{AppliedEvent, EventSink},
+    id_history::{CollisionGuard, Guarded, HistoryRecord},
+    logging::{RecordLog, log_event},
+    metrics::Metrics,
+    monitoring::ChargebackMonitor,
+    outcome::{self, TransactionOutcome},
+    output::{AccountFilter, AccountWriter},
+    period::ClosePeriodRequest,
+    pipeline::{ActivityLimits, Applier, ValidationError},
+    profiling::Profiler,
+    registry::{Balances, RegistrySlot},
+    rejects::{RejectStage, RejectsReport},
+    reorder::{ReorderBuffer, ReorderWindow},
+    settlement::Settlement,
+    spans::{Span, SpanKind, TraceContext, Tracer},
+    summary::{self, Summary},
+    transaction_types::{
+        AccountName, Amount, ClientId, DisputeSource, Transaction, TransactionId, TransactionType,
+    },
+    transactions_cache,
 };
 
 // Processor that handles transactions for a set of clients.
-// Each client has only one associated account.
+// Each client has a main account and any number of named sub-accounts. All of them are handled by the same processor.
 pub(crate) struct TransactionProcessor {
-    accounts: HashMap<ClientId, Account>,
+    accounts: HashMap<(ClientId, AccountName), Account>,
+    // The named sub-accounts of the clients that have any, to check the ids of the funding transactions of a client
+    // across all its sub-accounts.
+    sub_accounts: HashMap<ClientId, Vec<AccountName>>,
+    options: ProcessorOptions,
+    // Consumers of the events published after each successfully applied transaction.
+    sinks: Vec<Box<dyn EventSink>>,
+    chargeback_monitor: Option<ChargebackMonitor>,
+    rejects: Option<RejectsReport>,
+    // Where the transactions that failed because of an internal error go, so they can be processed again later.
+    dead_letters: Option<RejectsReport>,
+    // Where the transactions that fell out of the dispute window are archived.
+    history_archive: Option<HistoryArchive>,
+    // Checks the funding transactions against the ids used by the previous runs.
+    id_guard: Option<CollisionGuard>,
+    // The limits on the recent activity of the clients, which only count the transactions that were applied.
+    limits: Option<ActivityLimits>,
+    // Where what happens to the transactions of a traced client is recorded.
+    trace: Option<ClientTrace>,
+    summary: Summary,
+    metrics: Metrics,
+    // Where the spans of the transactions of traced requests are recorded.
+    tracer: Tracer,
+    // The money that moved in and out of the accounts of the clients.
+    settlement: Settlement,
+    // The accounting period of the transactions that are applied.
+    period: u32,
+    profiler: Profiler,
+    log: RecordLog<AccountError>,
+    // Set while an operator paused the processing, with the messages held back until it's resumed.
+    paused: bool,
+    held: VecDeque<ProcessorMessage>,
+    // The clients this processor is responsible for, when it's one of the workers of a `ShardedEngine`.
+    shard: Option<Shard>,
+    // Where the time at which the transactions are applied comes from.
+    clock: SharedClock,
+    // The dispute lookups of the clients that had to go to the transaction store on disk.
+    disk_lookups: DiskLookups,
+    // Where the totals of the accounts of this processor are kept up to date for the rest of the engine.
+    registry: Option<RegistrySlot>,
+    // The dispute operations that wait for the transaction they reference, if they can wait.
+    reorder: Option<ReorderBuffer>,
+}
+
+// Options that change how the processor handles transactions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProcessorOptions {
+    // Reject disputes, resolves and chargebacks for clients that don't have an account instead of creating an empty one.
+    pub(crate) reject_unknown_clients: bool,
+    // Number of recent transactions of an account that can be disputed. Older transactions are archived once the
+    // transaction log of the account grew by a whole window. The log is never compacted if not set.
+    pub(crate) dispute_window: Option<usize>,
+    // What happens to the transactions that arrive while the processing is paused.
+    pub(crate) pause_policy: PausePolicy,
+    // The maximum total of the accounts.
+    pub(crate) balance_limits: BalanceLimits,
+    // Reject disputes, resolves and chargebacks that don't say who they come from.
+    pub(crate) require_dispute_source: bool,
+    // The maximum number of disputes, resolves and chargebacks of a client per minute whose transaction is not in
+    // memory. Loading a transaction from disk is much slower, so a storm of disputes of old transactions could stall
+    // the worker. Not limited if not set.
+    pub(crate) max_disk_lookups: Option<usize>,
+    // The operations that are still allowed on locked accounts.
+    pub(crate) locked_operations: LockedOperations,
+    // Which transactions can be disputed and how.
+    pub(crate) dispute_policy: DisputePolicy,
+    // Unlock the accounts whose chargebacks were all reversed.
+    pub(crate) unlock_on_chargeback_reversal: bool,
+    // How long after their last chargeback the accounts locked by chargebacks are unlocked, if they had no other
+    // chargeback since. Accounts are never unlocked automatically if not set.
+    pub(crate) auto_unlock_after: Option<TimeDelta>,
+    // How long after they were applied the deposits become available. Their funds count towards the total but are
+    // pending until then. Available right away if not set.
+    pub(crate) settlement_delay: Option<TimeDelta>,
+    // The share of the deposits of the high-risk clients that is withheld in a rolling reserve.
+    pub(crate) reserves: ReservePolicy,
+    // How long the dispute operations that reference a transaction the account doesn't have yet wait for it. They are
+    // rejected right away if not set.
+    pub(crate) dispute_reorder: Option<ReorderWindow>,
+    // Which transactions are listed and flagged for their amount in the summary.
+    pub(crate) amount_review: AmountReview,
+}
+
+impl ProcessorOptions {
+    // The source of a dispute operation, which must be given if required.
+    fn dispute_source(
+        &self,
+        source: Option<DisputeSource>,
+    ) -> Result<Option<DisputeSource>, AccountError> {
+        match source {
+            None if self.require_dispute_source => Err(AccountError::DisputeSourceRequired),
+            source => Ok(source),
+        }
+    }
+}
+
+// The times of the recent dispute lookups of each client that were not served from memory.
+#[derive(Debug, Default)]
+struct DiskLookups {
+    max: Option<usize>,
+    recent: HashMap<ClientId, VecDeque<DateTime<Utc>>>,
+}
+
+impl DiskLookups {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            recent: HashMap::new(),
+        }
+    }
+
+    // Count a lookup of a transaction of the account if it's not in memory, or reject it if the client already had
+    // the maximum number of such lookups in the last minute. Rejected lookups don't count.
+    fn check(
+        &mut self,
+        account: &Account,
+        transaction_id: TransactionId,
+        clock: &SharedClock,
+    ) -> Result<(), AccountError> {
+        let Some(max) = self.max else {
+            return Ok(());
+        };
+        if account.is_in_memory(transaction_id) {
+            return Ok(());
+        }
+        let now = clock.now();
+        let recent = self.recent.entry(account.client()).or_default();
+        while recent
+            .front()
+            .is_some_and(|at| now - *at >= chrono::Duration::minutes(1))
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= max {
+            return Err(AccountError::DiskLookupThrottled(max));
+        }
+        recent.push_back(now);
+        Ok(())
+    }
+}
+
+/// The maximum total of the accounts, globally and for some clients. The limit of a client applies to each of its
+/// sub-accounts and takes precedence over the global limit.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BalanceLimits {
+    pub(crate) default: Option<Amount>,
+    pub(crate) clients: HashMap<ClientId, Amount>,
+}
+
+impl BalanceLimits {
+    fn for_client(&self, client: ClientId) -> Option<Amount> {
+        self.clients.get(&client).copied().or(self.default)
+    }
+}
+
+/// The rolling reserves of the high-risk clients. The deposits of the other clients are not withheld.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReservePolicy {
+    /// The percentage of the deposits that is withheld, per client.
+    pub(crate) clients: HashMap<ClientId, Decimal>,
+    /// How long the share of a deposit is withheld.
+    pub(crate) period: TimeDelta,
+}
+
+impl ReservePolicy {
+    fn for_client(&self, client: ClientId) -> Option<RollingReserve> {
+        self.clients.get(&client).map(|&percent| RollingReserve {
+            percent,
+            period: self.period,
+        })
+    }
+}
+
+/// What happens to the transactions that arrive while an operator paused the processing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum PausePolicy {
+    /// Keep them in memory, in order, and apply them once the processing is resumed.
+    #[default]
+    Buffer,
+    /// Reject them.
+    Reject,
 }
 
 // The message type used to control the processing.
 pub(crate) enum ProcessorMessage {
     // Transaction processing request.
     ProcessTransaction(Transaction),
+    // A dispute operation requested directly by an operator rather than through the input. The outcome is sent back on the reply channel.
+    ManageDispute(DisputeRequest),
+    // Freeze the balances of the current accounting period and start the next one.
+    ClosePeriod(ClosePeriodRequest),
+    // Copy the transaction stores and the balances of the accounts to a directory. Nothing is applied in the meantime,
+    // so the copy is consistent.
+    Backup(BackupRequest),
+    // A readiness probe of the daemon. The worker replies once it got to the message, with the outcome of a write to
+    // its transaction store.
+    HealthCheck(oneshot::Sender<Result<(), AccountError>>),
+    // Read the balances of the accounts of a client, or of all the clients of the worker. Nothing is applied.
+    QueryAccounts(AccountQuery),
+    // The end of stream barrier. The worker replies once everything it received before was applied and written out by
+    // its sinks and reports, with the first error it got while flushing them.
+    Flush(oneshot::Sender<io::Result<()>>),
+    // Stop applying new transactions until `Resume`, e.g. during incident response. What happens to the transactions
+    // that arrive in the meantime depends on the pause policy. Operator requests are still served.
+    Pause,
+    Resume,
     // A shutdown request for the processor. A shutdown message should be issued only after all transactions have been pushed to the queue.
     Shutdown,
 }
 
+/// A dispute operation on a previous transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DisputeAction {
+    /// Only read the dispute state.
+    Status,
+    Open,
+    Resolve,
+    Chargeback,
+}
+
+/// A dispute operation together with the channel on which the outcome is sent back.
+#[derive(Debug)]
+pub(crate) struct DisputeRequest {
+    pub(crate) action: DisputeAction,
+    pub(crate) client: ClientId,
+    /// The sub-account of the client that holds the transaction.
+    pub(crate) account: AccountName,
+    pub(crate) transaction_id: TransactionId,
+    /// The dispute version the caller last read. The operation is rejected if the dispute changed since then.
+    pub(crate) expected_version: Option<u32>,
+    /// Who opens or closes the dispute.
+    pub(crate) source: Option<DisputeSource>,
+    pub(crate) reply: oneshot::Sender<Result<DisputeOutcome, AccountError>>,
+}
+
+/// A request for the balances of the accounts of a worker, ordered by client and sub-account.
+#[derive(Debug)]
+pub(crate) struct AccountQuery {
+    /// The client whose accounts are read. All the accounts of the worker if missing.
+    pub(crate) client: Option<ClientId>,
+    pub(crate) reply: oneshot::Sender<Vec<AccountSnapshot>>,
+}
+
+/// The dispute state and the account balances after a dispute operation.
+/// A transaction can be disputed only once so the id of the disputed transaction also identifies the dispute.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct DisputeOutcome {
+    pub(crate) dispute_id: TransactionId,
+    pub(crate) state: DisputeState,
+    pub(crate) version: u32,
+    pub(crate) account: AccountSnapshot,
+}
+
 impl ProcessorMessage {
     pub(crate) fn process_transaction(transaction: Transaction) -> Self {
         Self::ProcessTransaction(transaction)
@@ -32,136 +309,2240 @@ impl ProcessorMessage {
     pub(crate) fn shutdown() -> Self {
         Self::Shutdown
     }
+
+    /// The client the message is about, if it's about one. Only the worker of the client can handle it.
+    pub(crate) fn client(&self) -> Option<ClientId> {
+        match self {
+            Self::ProcessTransaction(transaction) => Some(transaction.client()),
+            Self::ManageDispute(request) => Some(request.client),
+            Self::QueryAccounts(query) => query.client,
+            _ => None,
+        }
+    }
 }
 
 impl TransactionProcessor {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(options: ProcessorOptions) -> Self {
         Self {
             accounts: HashMap::new(),
+            sub_accounts: HashMap::new(),
+            disk_lookups: DiskLookups::new(options.max_disk_lookups),
+            reorder: options.dispute_reorder.map(ReorderBuffer::new),
+            options,
+            sinks: Vec::new(),
+            chargeback_monitor: None,
+            rejects: None,
+            dead_letters: None,
+            history_archive: None,
+            id_guard: None,
+            limits: None,
+            trace: None,
+            summary: Summary::default(),
+            metrics: Metrics::default(),
+            tracer: Tracer::default(),
+            settlement: Settlement::default(),
+            period: 1,
+            profiler: Profiler::disabled(),
+            log: RecordLog::new(),
+            paused: false,
+            held: VecDeque::new(),
+            shard: None,
+            clock: SystemClock::shared(),
+            registry: None,
         }
     }
 
-    // Process a single transaction. This would be called by the prcessing task when a transaction processing message is received.
-    // This function will propagate the error up the call stack.
-    fn process_transaction(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
-        let client = transaction.client();
-        let transaction_id = transaction.id();
+    // Take the time from another clock than the system clock, e.g. a manual clock in tests.
+    pub(crate) fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
 
-        let account = match self.accounts.entry(client) {
-            Entry::Occupied(occupied_entry) => occupied_entry.into_mut(),
-            Entry::Vacant(vacant_entry) => vacant_entry.insert(Account::new(client)?),
-        };
+    // Process the clients of a shard only. Checked in debug builds.
+    pub(crate) fn with_shard(mut self, shard: Shard) -> Self {
+        self.shard = Some(shard);
+        self
+    }
 
-        match transaction.transaction_type() {
-            TransactionType::Deposit => {
-                let amount = transaction.amount().unwrap();
-                account.deposit(amount, transaction_id)?;
+    /// The id of the worker the processor runs on, which is in its logs and events.
+    pub(crate) fn worker(&self) -> WorkerId {
+        self.shard.map(|shard| shard.worker()).unwrap_or_default()
+    }
+
+    // Start in a period other than the first one, e.g. when previous periods were closed in an earlier run.
+    pub(crate) fn with_period(mut self, period: u32) -> Self {
+        self.period = period;
+        self
+    }
+
+    // Time the processing stage, e.g. how long applying the transactions takes compared to waiting for them.
+    pub(crate) fn with_profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    pub(crate) fn take_profiler(&mut self) -> Profiler {
+        std::mem::take(&mut self.profiler)
+    }
+
+    // The counters of the applied and failed transactions.
+    pub(crate) fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    // Add the transactions that can't be applied to a rejects report.
+    pub(crate) fn with_rejects(mut self, rejects: RejectsReport) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+
+    // Send the transactions that fail because of an internal error to their own report instead of the rejects report.
+    pub(crate) fn with_dead_letters(mut self, dead_letters: RejectsReport) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    // Archive the transactions that fell out of the dispute window. Needs `ProcessorOptions::dispute_window`.
+    pub(crate) fn with_history_archive(mut self, archive: HistoryArchive) -> Self {
+        self.history_archive = Some(archive);
+        self
+    }
+
+    // Check the funding transactions against the ids of the transactions of the previous runs before applying them.
+    pub(crate) fn with_id_guard(mut self, guard: CollisionGuard) -> Self {
+        self.id_guard = Some(guard);
+        self
+    }
+
+    // Check the transactions against the limits on the recent activity of their client right before applying them.
+    pub(crate) fn with_limits(mut self, limits: ActivityLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    // Record how the transactions of a client are applied, with the balances before and after, to its trace.
+    pub(crate) fn with_trace(mut self, trace: ClientTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    // The funding transactions applied by this run, for the id history of the next runs.
+    pub(crate) fn take_applied_ids(&mut self) -> Vec<HistoryRecord> {
+        self.id_guard
+            .as_mut()
+            .map(CollisionGuard::take_applied)
+            .unwrap_or_default()
+    }
+
+    // Track the chargeback rates of the clients and raise alerts when they are too high.
+    pub(crate) fn with_chargeback_monitor(mut self, monitor: ChargebackMonitor) -> Self {
+        self.chargeback_monitor = Some(monitor);
+        self
+    }
+
+    // Count the applied and failed transactions in the exported metrics.
+    pub(crate) fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Record a span for every transaction that carries the trace context of a request.
+    pub(crate) fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    // Keep the totals of the accounts up to date in a slot of the engine-wide registry.
+    pub(crate) fn with_registry(mut self, slot: RegistrySlot) -> Self {
+        self.registry = Some(slot);
+        self
+    }
+
+    // Add a consumer of the applied transaction events.
+    pub(crate) fn with_sink<S: EventSink + 'static>(mut self, sink: S) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    // The net positions of the clients of this processor, including the funds that are still held.
+    pub(crate) fn settlement(&self) -> Settlement {
+        let mut settlement = self.settlement.clone();
+        for account in self.accounts.values().map(Account::snapshot) {
+            settlement.add_held(account.client, account.held.saturating_add(account.escrow));
+        }
+        settlement
+    }
+
+    fn publish(&mut self, event: AppliedEvent) {
+        self.profiler.enter("publish");
+        self.settlement.record(&event);
+        for sink in self.sinks.iter_mut() {
+            sink.publish(&event);
+        }
+        self.profiler.exit();
+    }
+
+    // Add an already existing account to the processor, e.g. when bootstrapping from a snapshot.
+    pub(crate) fn insert_account(&mut self, account: Account) {
+        let client = account.client();
+        self.check_owner(client);
+        let account = account
+            .with_dispute_window(self.options.dispute_window)
+            .with_max_total(self.options.balance_limits.for_client(client))
+            .with_locked_operations(self.options.locked_operations)
+            .with_dispute_policy(self.options.dispute_policy)
+            .with_settlement_delay(self.options.settlement_delay)
+            .with_rolling_reserve(self.options.reserves.for_client(client))
+            .with_clock(self.clock.clone())
+            .withhold_loaded_funds();
+        let key = (account.client(), account.name().clone());
+        let before = self.balances_before([key.clone()]);
+        if !self.accounts.contains_key(&key) {
+            add_sub_account(&mut self.sub_accounts, client, &key.1);
+        }
+        self.accounts.insert(key, account);
+        self.update_registry(before);
+    }
+
+    // Whether another sub-account of the client than the one of the transaction has a transaction with its id.
+    fn used_by_another_sub_account(&self, transaction: &Transaction) -> Result<bool, AccountError> {
+        let client = transaction.client();
+        let Some(names) = self.sub_accounts.get(&client) else {
+            return Ok(false);
+        };
+        let main = AccountName::default();
+        for name in std::iter::once(&main).chain(names) {
+            if name == transaction.account() {
+                continue;
             }
-            TransactionType::Withdrawal => {
-                let amount = transaction.amount().unwrap();
-                account.withdraw(amount, transaction_id)?;
+            if let Some(account) = self.accounts.get(&(client, name.clone()))
+                && account.has_transaction(transaction.id())?
+            {
+                return Ok(true);
             }
-            TransactionType::Dispute => {
-                account.dispute(transaction_id)?;
+        }
+        Ok(false)
+    }
+
+    // The balances of the accounts that are about to change, if there's a registry to update with the difference.
+    fn balances_before(
+        &self,
+        keys: impl IntoIterator<Item = (ClientId, AccountName)>,
+    ) -> Vec<((ClientId, AccountName), Option<Balances>)> {
+        if self.registry.is_none() {
+            return Vec::new();
+        }
+        keys.into_iter()
+            .map(|key| {
+                let before = self.accounts.get(&key).map(Balances::of);
+                (key, before)
+            })
+            .collect()
+    }
+
+    // Record the new balances of the accounts in the registry. The accounts are changed in place, so the registry
+    // doesn't have to go through them all again.
+    fn update_registry(&self, before: Vec<((ClientId, AccountName), Option<Balances>)>) {
+        let Some(registry) = &self.registry else {
+            return;
+        };
+        for (key, before) in before {
+            if let Some(account) = self.accounts.get(&key) {
+                registry.update(before, Balances::of(account));
             }
-            TransactionType::Resolve => {
-                account.resolve_dispute(transaction_id)?;
+        }
+    }
+
+    // Move the settled transactions that fell out of the dispute window of an account to the archive.
+    // The transactions are removed from the log only once they were archived. Failures are logged and the transactions
+    // are kept in the log, the transaction that triggered the compaction was already applied.
+    fn compact(&mut self, key: &(ClientId, AccountName)) {
+        let (Some(archive), Some(account)) = (&self.history_archive, self.accounts.get_mut(key))
+        else {
+            return;
+        };
+        match archive_history(archive, account) {
+            Ok(Some(compaction)) => log_event(
+                "history_compacted",
+                &[
+                    ("worker", &self.worker()),
+                    ("client", &key.0),
+                    ("account", &key.1),
+                    ("archived", &compaction.transactions.len()),
+                    ("last_tx", &compaction.last_transaction),
+                ],
+            ),
+            Ok(None) => {}
+            Err(err) => eprintln!(
+                "{}: Cannot compact the transactions of client {} account {}: {}",
+                self.worker(),
+                key.0,
+                key.1,
+                err
+            ),
+        }
+    }
+
+    // Unlock the accounts whose clean period is over even if they had no transaction since, so the balances that are
+    // written out or reported don't keep them locked.
+    fn unlock_clean_accounts(&mut self) {
+        let Some(clean_period) = self.options.auto_unlock_after else {
+            return;
+        };
+        let worker = self.worker();
+        for account in self
+            .accounts
+            .values_mut()
+            .filter(|account| account.is_locked())
+        {
+            let before = Balances::of(account);
+            if unlock_if_clean(worker, clean_period, account)
+                && let Some(registry) = &self.registry
+            {
+                registry.update(Some(before), Balances::of(account));
             }
-            TransactionType::Chargeback => {
-                account.chargeback(transaction_id)?;
+        }
+    }
+
+    // Release the deposits whose settlement delay is over and the reserves whose period is over even if their account
+    // had no transaction since, so the balances that are written out or reported have them available.
+    fn release_due_funds(&mut self) {
+        if self.options.settlement_delay.is_none() && self.options.reserves.clients.is_empty() {
+            return;
+        }
+        for account in self.accounts.values_mut() {
+            let before = Balances::of(account);
+            if account.release_due_funds() > 0
+                && let Some(registry) = &self.registry
+            {
+                registry.update(Some(before), Balances::of(account));
             }
         }
-        Ok(())
+    }
+
+    // The state of all the accounts of the processor.
+    pub(crate) fn snapshots(&self) -> Vec<AccountSnapshot> {
+        self.accounts.values().map(Account::snapshot).collect()
+    }
+
+    // Whether any client has a sub-account other than the main one.
+    pub(crate) fn has_sub_accounts(&self) -> bool {
+        self.accounts.keys().any(|(_, name)| !name.is_main())
     }
 
     // Run the processing task.
     pub(crate) async fn run(mut self, mut rx: mpsc::Receiver<ProcessorMessage>) -> Self {
-        while let Some(message) = rx.recv().await {
+        self.profiler.enter("processing");
+        loop {
+            self.profiler.enter("recv");
+            let message = rx.recv().await;
+            self.profiler.exit();
+            let Some(message) = message else {
+                break;
+            };
+
             match message {
-                ProcessorMessage::ProcessTransaction(transaction) => {
-                    if let Err(err) = self.process_transaction(&transaction) {
-                        // We just print out the error on stderr. We don't stop processing on any error.
-                        eprintln!("Error processing transaction: {}", err);
-                    }
-                }
+                ProcessorMessage::Pause => self.paused = true,
+                ProcessorMessage::Resume => self.resume(),
                 ProcessorMessage::Shutdown => {
+                    // Nothing that was received is lost by stopping while paused.
+                    self.resume();
                     break;
                 }
+                // Closing a period is held back too, so the held transactions are applied in the period they were
+                // received in.
+                ProcessorMessage::ProcessTransaction(_)
+                | ProcessorMessage::ClosePeriod(_)
+                | ProcessorMessage::Flush(_)
+                    if self.paused && self.options.pause_policy == PausePolicy::Buffer =>
+                {
+                    self.held.push_back(message);
+                }
+                ProcessorMessage::ProcessTransaction(transaction) if self.paused => {
+                    let err = InternalError::ProcessingPaused.into();
+                    self.trace_dropped(&transaction, &err);
+                    self.fail(&transaction, &err);
+                }
+                message => self.handle(message),
             }
         }
+        self.profiler.exit();
 
         self
     }
 
-    // Write out the account records to the csv writer.
-    pub(crate) fn write_csv_records<W: std::io::Write>(&self, writer: &mut csv::Writer<W>) {
-        for account in self.accounts.values() {
-            if let Err(err) = writer.serialize(account) {
-                eprintln!(
-                    "Cannot serialize account with client_id: {}; {}",
-                    account.client(),
-                    err
+    // Apply the messages that were held back while paused.
+    fn resume(&mut self) {
+        self.paused = false;
+        while let Some(message) = self.held.pop_front() {
+            self.handle(message);
+        }
+    }
+
+    fn handle(&mut self, message: ProcessorMessage) {
+        match message {
+            ProcessorMessage::ProcessTransaction(transaction) => {
+                let Some(transaction) = self.guard_id(transaction) else {
+                    return;
+                };
+                // A dispute operation of a transaction that already has an operation waiting waits behind it.
+                if self
+                    .reorder
+                    .as_ref()
+                    .is_some_and(|reorder| reorder.is_waiting(&transaction))
+                {
+                    return self.park(transaction, AccountError::TransactionMissing);
+                }
+                if let Some(limits) = &mut self.limits
+                    && let Err(err) = limits.validate(&transaction)
+                {
+                    return self.reject(&transaction, err);
+                }
+                match self.process(&transaction) {
+                    Ok(()) => self.count_applied(&transaction),
+                    Err(err) if self.may_wait(&transaction, &err) => {
+                        return self.park(transaction, err);
+                    }
+                    Err(err) => self.fail(&transaction, &err),
+                }
+                self.retry_parked(&(transaction.client(), transaction.account().clone()));
+            }
+            ProcessorMessage::ManageDispute(request) => {
+                let before = self.balances_before([(request.client, request.account.clone())]);
+                let outcome = self.manage_dispute(
+                    request.action,
+                    request.client,
+                    &request.account,
+                    request.transaction_id,
+                    request.expected_version,
+                    request.source,
                 );
+                self.update_registry(before);
+                // The requester may have given up waiting. There's nothing to do in that case.
+                let _ = request.reply.send(outcome);
             }
+            ProcessorMessage::ClosePeriod(request) => {
+                // The limits on the activity of the clients are scoped to the accounting period.
+                if let Some(limits) = &mut self.limits {
+                    limits.clear();
+                }
+                self.unlock_clean_accounts();
+                self.release_due_funds();
+                let _ = request.reply.send(self.close_period(request.next));
+            }
+            ProcessorMessage::Backup(request) => {
+                let backup =
+                    backup::back_up_accounts(self.worker(), self.accounts.values(), &request.dir);
+                let _ = request.reply.send(backup);
+            }
+            ProcessorMessage::HealthCheck(reply) => {
+                let _ = reply.send(Self::check_store());
+            }
+            ProcessorMessage::QueryAccounts(query) => {
+                self.release_due_funds();
+                let mut accounts: Vec<_> = self
+                    .accounts
+                    .values()
+                    .filter(|account| query.client.is_none_or(|client| account.client() == client))
+                    .map(Account::snapshot)
+                    .collect();
+                accounts.sort_by(|a, b| (a.client, &a.account).cmp(&(b.client, &b.account)));
+                let _ = query.reply.send(accounts);
+            }
+            ProcessorMessage::Flush(reply) => {
+                // The transactions the waiting operations reference can't arrive anymore.
+                if let Some(reorder) = &mut self.reorder {
+                    for transaction in reorder.drain() {
+                        let err = AccountError::TransactionMissing;
+                        self.trace_dropped(&transaction, &err);
+                        self.fail(&transaction, &err);
+                    }
+                }
+                self.unlock_clean_accounts();
+                self.release_due_funds();
+                let _ = reply.send(self.flush());
+            }
+            // Control messages are handled by the run loop.
+            ProcessorMessage::Pause | ProcessorMessage::Resume | ProcessorMessage::Shutdown => {}
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Apply a transaction, keeping the registry and the profile up to date.
+    fn process(&mut self, transaction: &Transaction) -> Result<(), AccountError> {
+        // The apply operation is synchronous so the store time of the thread only grows by its own store calls.
+        self.profiler.enter("apply");
+        let store_time = transactions_cache::store_time();
+        let before = self.balances_before(
+            std::iter::once(transaction.account())
+                .chain(transaction.to_account())
+                .map(|name| (transaction.client(), name.clone())),
+        );
+        let traced = self.trace_before(transaction);
+        let span = self.span_before(transaction);
+        let applied = self.apply(transaction);
+        if let Some((trace, balances, cache)) = traced {
+            trace.applied(
+                self.worker(),
+                transaction,
+                applied.as_ref().err().map(ToString::to_string),
+                cache,
+                (&balances, &self.traced_balances(transaction)),
+            );
+        }
+        self.update_registry(before);
+        let store_time = transactions_cache::store_time() - store_time;
+        if let Some(span) = span {
+            let error = applied.as_ref().err().map(|err| err as &dyn Display);
+            self.tracer.record(
+                span.with_attribute("store.time_us", &store_time.as_micros())
+                    .end(self.clock.now(), error),
+            );
+        }
+        self.profiler.record("store", store_time);
+        self.profiler.exit();
+        applied
+    }
 
-    #[test]
-    fn can_process_multiple_deposits_and_withdrawals() {
-        let transactions = vec![
-            Transaction::new(
-                TransactionType::Deposit,
-                1.into(),
-                1.into(),
-                Some(100.0.into()),
-            ),
-            // 1 deposit 100
-            Transaction::new(
-                TransactionType::Deposit,
-                2.into(),
-                2.into(),
-                Some(200.0.into()),
-            ),
-            // 2 deposit 200
-            Transaction::new(
-                TransactionType::Deposit,
-                1.into(),
-                3.into(),
-                Some(200.0.into()),
-            ),
-            // 1 deposit 200, total 300
-            Transaction::new(
-                TransactionType::Withdrawal,
-                2.into(),
-                4.into(),
-                Some(150.0.into()),
-            ), // 2 withdrawal 150, total 50
-            Transaction::new(
-                TransactionType::Withdrawal,
-                1.into(),
-                5.into(),
-                Some(300.0.into()),
-            ), // 1 withdraw 300, total 0
-        ];
+    // The span of a transaction that is part of a traced request, started with what is known before it's applied.
+    fn span_before(&self, transaction: &Transaction) -> Option<Span> {
+        if !self.tracer.is_enabled() {
+            return None;
+        }
+        let parent = transaction.extensions().get::<TraceContext>()?;
+        let mut span = Span::start("apply", SpanKind::Internal, Some(parent), self.clock.now())
+            .with_attribute("transaction.type", &transaction.transaction_type())
+            .with_attribute("transaction.client", &transaction.client())
+            .with_attribute("transaction.tx", &transaction.id())
+            .with_attribute("transaction.account", transaction.account())
+            .with_attribute("worker", &self.worker());
+        if let Some(cache) = self.cache_lookup(transaction) {
+            span = span.with_attribute("cache", &cache);
+        }
+        Some(span)
+    }
 
-        let mut processor = TransactionProcessor::new();
+    // The trace of the client of the transaction with the balances of the accounts it's about to touch and whether the
+    // transaction it references is in memory, if the client is traced.
+    fn trace_before(
+        &self,
+        transaction: &Transaction,
+    ) -> Option<(ClientTrace, Vec<AccountSnapshot>, Option<CacheLookup>)> {
+        let trace = self
+            .trace
+            .as_ref()
+            .filter(|trace| trace.traces(transaction))?;
+        Some((
+            trace.clone(),
+            self.traced_balances(transaction),
+            self.cache_lookup(transaction),
+        ))
+    }
 
-        for transaction in transactions.iter() {
-            assert!(processor.process_transaction(transaction).is_ok());
+    // Whether the transaction a dispute operation references is in memory or has to be loaded from the store.
+    fn cache_lookup(&self, transaction: &Transaction) -> Option<CacheLookup> {
+        if transaction.transaction_type().is_funding() {
+            return None;
         }
+        self.accounts
+            .get(&(transaction.client(), transaction.account().clone()))
+            .map(|account| {
+                if account.is_in_memory(transaction.id()) {
+                    CacheLookup::Hit
+                } else {
+                    CacheLookup::Fault
+                }
+            })
+    }
 
-        assert_eq!(
-            processor.accounts.get(&1.into()).unwrap().available(),
-            0.0.into()
-        );
-        assert_eq!(
-            processor.accounts.get(&2.into()).unwrap().available(),
-            50.0.into()
+    // The balances of the accounts a transaction touches.
+    fn traced_balances(&self, transaction: &Transaction) -> Vec<AccountSnapshot> {
+        std::iter::once(transaction.account())
+            .chain(transaction.to_account())
+            .filter_map(|name| self.accounts.get(&(transaction.client(), name.clone())))
+            .map(Account::snapshot)
+            .collect()
+    }
+
+    // Record a transaction of the traced client that was not applied before it got to its account.
+    fn trace_dropped(&self, transaction: &Transaction, reason: &dyn Display) {
+        if let Some(trace) = &self.trace
+            && trace.traces(transaction)
+        {
+            trace.dropped(self.worker(), transaction, reason.to_string());
+        }
+    }
+
+    // Check a transaction against the ids of the previous runs. The transactions that are not to be applied are counted
+    // and reported.
+    fn guard_id(&mut self, transaction: Transaction) -> Option<Transaction> {
+        let worker = self.worker();
+        let Some(guard) = &mut self.id_guard else {
+            return Some(transaction);
+        };
+        match guard.check(transaction, worker) {
+            Guarded::Apply(transaction) => Some(transaction),
+            Guarded::Skip(transaction) => {
+                self.trace_dropped(&transaction, &"already applied by a previous run");
+                outcome::report(&transaction, || TransactionOutcome::Duplicate);
+                self.summary.redelivered += 1;
+                None
+            }
+            Guarded::Reject(transaction, err) => {
+                self.trace_dropped(&transaction, &err);
+                self.fail(&transaction, &err);
+                None
+            }
+        }
+    }
+
+    fn count_applied(&mut self, transaction: &Transaction) {
+        outcome::report(transaction, || TransactionOutcome::Applied);
+        if let Some(guard) = &mut self.id_guard {
+            guard.record(transaction);
+        }
+        if let Some(limits) = &mut self.limits {
+            limits.record(transaction);
+        }
+        if self.log.is_verbose() {
+            eprintln!(
+                "{}: Applied {} {} for client {}",
+                self.worker(),
+                transaction.transaction_type(),
+                transaction.id(),
+                transaction.client()
+            );
+        }
+        self.summary.count_applied(transaction.transaction_type());
+        self.metrics.count(
+            "transactions.applied",
+            &[("type", &transaction.transaction_type())],
+            1,
         );
+        if let Some(flagged) = self
+            .summary
+            .amounts
+            .record(transaction, &self.options.amount_review)
+        {
+            let worker = self.worker();
+            let z_score = flagged.z_score.map(|z_score| format!("{:.2}", z_score));
+            let mut fields: Vec<(&str, &dyn Display)> = vec![
+                ("worker", &worker),
+                ("client", &flagged.client),
+                ("tx", &flagged.tx),
+                ("type", &flagged.transaction_type),
+                ("amount", &flagged.amount),
+            ];
+            if let Some(z_score) = &z_score {
+                fields.push(("z_score", z_score));
+            }
+            log_event("amount_outlier", &fields);
+        }
+    }
+
+    // Whether a dispute operation failed because the transaction it references may still arrive. The account doesn't
+    // exist yet when the unknown clients are rejected.
+    fn may_wait(&self, transaction: &Transaction, err: &AccountError) -> bool {
+        self.reorder.is_some()
+            && ReorderBuffer::can_wait(transaction)
+            && matches!(
+                err,
+                AccountError::TransactionMissing | AccountError::UnknownClient
+            )
+    }
+
+    // Let a dispute operation wait for its transaction, or reject it with the error if its account has too many
+    // operations waiting already.
+    fn park(&mut self, transaction: Transaction, err: AccountError) {
+        let now = self.clock.now();
+        let worker = self.worker();
+        let Some(reorder) = &mut self.reorder else {
+            return self.fail(&transaction, &err);
+        };
+        let traced = self
+            .trace
+            .as_ref()
+            .filter(|trace| trace.traces(&transaction))
+            .map(|trace| (trace, transaction.id(), transaction.transaction_type()));
+        match reorder.park(transaction, now) {
+            Some(transaction) => self.fail(&transaction, &err),
+            None => {
+                if let Some((trace, tx, transaction_type)) = traced {
+                    trace.parked(worker, tx, transaction_type);
+                }
+            }
+        }
+    }
+
+    // Try the operations waiting in an account again after another transaction of the account, in the order they
+    // arrived. The ones still missing their transaction wait longer, unless they waited long enough.
+    fn retry_parked(&mut self, key: &(ClientId, AccountName)) {
+        let Some(reorder) = &mut self.reorder else {
+            return;
+        };
+        let parked = reorder.take(key);
+        let now = self.clock.now();
+        for parked in parked {
+            let transaction = &parked.transaction;
+            match self.process(transaction) {
+                Ok(()) => {
+                    self.summary.reordered += 1;
+                    self.count_applied(transaction);
+                }
+                Err(err) if self.may_wait(transaction, &err) => {
+                    if let Some(reorder) = &mut self.reorder
+                        && let Some(transaction) = reorder.wait_longer(parked, now)
+                    {
+                        self.fail(&transaction, &err);
+                    }
+                }
+                Err(err) => self.fail(transaction, &err),
+            }
+        }
+    }
+
+    // A client that is processed by two workers would have its accounts split between them, so a message about a
+    // client that was sent to the wrong worker is a bug in the routing.
+    fn check_owner(&self, client: ClientId) {
+        debug_assert!(
+            self.shard.is_none_or(|shard| shard.owns(client)),
+            "Client {} was sent to worker {:?}, which doesn't own it.",
+            client,
+            self.shard
+        );
+    }
+
+    // Count and report a transaction that the limits on the activity of its client rejected, like the validator chain
+    // does.
+    fn reject(&mut self, transaction: &Transaction, err: ValidationError) {
+        self.trace_dropped(transaction, &err);
+        if let Some(limits) = &mut self.limits
+            && limits.should_log(&err)
+        {
+            eprintln!(
+                "{}: Rejected transaction {} for client {}: {}",
+                self.worker(),
+                transaction.id(),
+                transaction.client(),
+                err
+            );
+        }
+        if let Some(rejects) = &self.rejects {
+            rejects.record(transaction, RejectStage::Validation, &err);
+        }
+        outcome::report(transaction, || TransactionOutcome::rejected(&err));
+        self.summary
+            .count_rejected(transaction.transaction_type(), &err);
+        self.metrics.count(
+            "transactions.rejected",
+            &[
+                ("type", &transaction.transaction_type()),
+                ("reason", &summary::reason_name(&err)),
+            ],
+            1,
+        );
+    }
+
+    // Count and report a transaction that could not be applied. Business rejections go to the rejects report, internal
+    // failures go to the dead letters if there are any.
+    fn fail(&mut self, transaction: &Transaction, err: &AccountError) {
+        outcome::report(transaction, || TransactionOutcome::rejected(err));
+        // We just print out the error on stderr. We don't stop processing on any error.
+        if self.log.should_log(err) {
+            eprintln!("{}: Error processing transaction: {}", self.worker(), err);
+        }
+        self.summary
+            .count_failed(transaction.transaction_type(), err);
+        self.metrics.count(
+            "transactions.failed",
+            &[
+                ("type", &transaction.transaction_type()),
+                ("reason", &summary::reason_name(err)),
+            ],
+            1,
+        );
+        if err.is_internal() {
+            self.summary.internal += 1;
+            if let Some(report) = self.dead_letters.as_ref().or(self.rejects.as_ref()) {
+                report.record(transaction, RejectStage::Internal, err);
+            }
+            return;
+        }
+        let stage = match err {
+            AccountError::DiskLookupThrottled(_) => {
+                *self
+                    .summary
+                    .throttled
+                    .entry(transaction.client())
+                    .or_default() += 1;
+                RejectStage::Throttled
+            }
+            _ => RejectStage::Apply,
+        };
+        if let Some(rejects) = &self.rejects {
+            rejects.record(transaction, stage, err);
+        }
+    }
+
+    // Write out what the sinks and the reports still buffer. Everything is flushed even if one of them fails.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut flushed = Ok(());
+        for sink in self.sinks.iter_mut() {
+            flushed = flushed.and(sink.flush());
+        }
+        for report in self.rejects.iter().chain(&self.dead_letters) {
+            flushed = flushed.and(report.flush());
+        }
+        if let Some(trace) = &self.trace {
+            flushed = flushed.and(trace.flush());
+        }
+        flushed
+    }
+
+    // The transaction stores of all the accounts are created in the same temporary directory, so a new store tells
+    // whether they can be written to without probing every account.
+    fn check_store() -> Result<(), AccountError> {
+        Account::new(ClientId::from(0))?.check_store()
+    }
+
+    // The closing balances of the accounts, ordered by client. Subsequent transactions are tagged with the next period.
+    fn close_period(&mut self, next: u32) -> Vec<AccountSnapshot> {
+        let mut accounts: Vec<_> = self.accounts.values().map(Account::snapshot).collect();
+        accounts.sort_by(|a, b| (a.client, &a.account).cmp(&(b.client, &b.account)));
+        self.period = next;
+        accounts
+    }
+
+    // Apply a dispute operation that doesn't come from the input. Accounts are never created by these operations. The
+    // operations are counted, traced and monitored like the dispute operations of the input, but the limits on the
+    // activity of the client don't apply to what an operator does.
+    pub(crate) fn manage_dispute(
+        &mut self,
+        action: DisputeAction,
+        client: ClientId,
+        name: &AccountName,
+        transaction_id: TransactionId,
+        expected_version: Option<u32>,
+        source: Option<DisputeSource>,
+    ) -> Result<DisputeOutcome, AccountError> {
+        let transaction_type = match action {
+            DisputeAction::Status => None,
+            DisputeAction::Open => Some(TransactionType::Dispute),
+            DisputeAction::Resolve => Some(TransactionType::Resolve),
+            DisputeAction::Chargeback => Some(TransactionType::Chargeback),
+        };
+        let Some(transaction) = transaction_type.map(|transaction_type| {
+            Transaction::operation(
+                transaction_type,
+                client,
+                name.clone(),
+                transaction_id,
+                source,
+            )
+        }) else {
+            return self.apply_dispute_action(
+                action,
+                client,
+                name,
+                transaction_id,
+                expected_version,
+                source,
+            );
+        };
+
+        let traced = self.trace_before(&transaction);
+        let outcome = self.apply_dispute_action(
+            action,
+            client,
+            name,
+            transaction_id,
+            expected_version,
+            source,
+        );
+        if let Some((trace, balances, cache)) = traced {
+            trace.applied(
+                self.worker(),
+                &transaction,
+                outcome.as_ref().err().map(ToString::to_string),
+                cache,
+                (&balances, &self.traced_balances(&transaction)),
+            );
+        }
+        match &outcome {
+            Ok(_) => {
+                self.monitor_chargebacks(client, transaction.transaction_type());
+                self.count_applied(&transaction);
+            }
+            Err(err) => self.fail(&transaction, err),
+        }
+        outcome
+    }
+
+    fn apply_dispute_action(
+        &mut self,
+        action: DisputeAction,
+        client: ClientId,
+        name: &AccountName,
+        transaction_id: TransactionId,
+        expected_version: Option<u32>,
+        source: Option<DisputeSource>,
+    ) -> Result<DisputeOutcome, AccountError> {
+        self.check_owner(client);
+        if action != DisputeAction::Status {
+            self.options.dispute_source(source)?;
+        }
+        let account = self
+            .accounts
+            .get_mut(&(client, name.clone()))
+            .ok_or(AccountError::UnknownClient)?;
+
+        self.disk_lookups
+            .check(account, transaction_id, &self.clock)?;
+        let status = account.dispute_status(transaction_id)?;
+        if let Some(expected) = expected_version
+            && expected != status.version
+        {
+            return Err(AccountError::StaleDisputeState {
+                expected,
+                current: status.version,
+            });
+        }
+
+        let applied = match action {
+            DisputeAction::Status => None,
+            DisputeAction::Open => Some((
+                TransactionType::Dispute,
+                account.dispute(transaction_id, None, source)?,
+            )),
+            DisputeAction::Resolve => Some((
+                TransactionType::Resolve,
+                account.resolve_dispute(transaction_id, source)?,
+            )),
+            DisputeAction::Chargeback => Some((
+                TransactionType::Chargeback,
+                account.chargeback(transaction_id, source)?,
+            )),
+        };
+
+        let status = account.dispute_status(transaction_id)?;
+        let outcome = DisputeOutcome {
+            dispute_id: transaction_id,
+            state: status.state,
+            version: status.version,
+            account: account.snapshot(),
+        };
+        if let Some((transaction_type, funds)) = applied {
+            self.publish(AppliedEvent {
+                transaction_type,
+                transaction_id,
+                amount: funds.amount,
+                release_to: None,
+                disputed: Some(funds.disputed),
+                worker: self.worker(),
+                account: outcome.account.clone(),
+                period: self.period,
+                applied_at: self.clock.now(),
+            });
+        }
+        Ok(outcome)
+    }
+
+    // Write out the account records that match the filter with the account writer, which picks the columns.
+    pub(crate) fn write_csv_records<W: std::io::Write>(
+        &self,
+        writer: &mut AccountWriter<W>,
+        filter: &AccountFilter,
+    ) {
+        for account in self
+            .accounts
+            .values()
+            .filter(|account| filter.matches(account))
+        {
+            if let Err(err) = writer.write(&account.snapshot()) {
+                eprintln!(
+                    "{}: Cannot serialize account with client_id: {}; {}",
+                    self.worker(),
+                    account.client(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+fn archive_history(
+    archive: &HistoryArchive,
+    account: &mut Account,
+) -> Result<Option<Compaction>, Box<dyn std::error::Error>> {
+    let Some(compaction) = account.compaction()? else {
+        return Ok(None);
+    };
+    archive.record(&compaction)?;
+    account.compact(&compaction)?;
+    Ok(Some(compaction))
+}
+
+// Unlock an account whose last chargeback is older than the clean period and record it in the audit trail.
+fn unlock_if_clean(worker: WorkerId, clean_period: TimeDelta, account: &mut Account) -> bool {
+    let Some(last_chargeback) = account.unlock_if_clean(clean_period) else {
+        return false;
+    };
+    log_event(
+        "account_unlocked",
+        &[
+            ("worker", &worker),
+            ("client", &account.client()),
+            ("account", account.name()),
+            (
+                "last_chargeback",
+                &last_chargeback.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+            ("clean_days", &clean_period.num_days()),
+        ],
+    );
+    true
+}
+
+// A new sub-account of a client, with the options of the processor.
+fn new_account(
+    options: &ProcessorOptions,
+    clock: &SharedClock,
+    client: ClientId,
+    name: AccountName,
+) -> Result<Account, AccountError> {
+    Ok(Account::new(client)?
+        .with_name(name)
+        .with_dispute_window(options.dispute_window)
+        .with_max_total(options.balance_limits.for_client(client))
+        .with_locked_operations(options.locked_operations)
+        .with_dispute_policy(options.dispute_policy)
+        .with_settlement_delay(options.settlement_delay)
+        .with_rolling_reserve(options.reserves.for_client(client))
+        .with_clock(clock.clone()))
+}
+
+// Record a new sub-account of a client, if it's a named one.
+fn add_sub_account(
+    sub_accounts: &mut HashMap<ClientId, Vec<AccountName>>,
+    client: ClientId,
+    name: &AccountName,
+) {
+    if !name.is_main() {
+        sub_accounts.entry(client).or_default().push(name.clone());
+    }
+}
+
+impl Applier for TransactionProcessor {
+    type Error = AccountError;
+
+    // Apply a single transaction. This would be called by the processing task when a transaction processing message is received.
+    // The transaction is expected to have passed the validator chain already.
+    // This function will propagate the error up the call stack.
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), AccountError> {
+        let client = transaction.client();
+        self.check_owner(client);
+        let transaction_id = transaction.id();
+        let worker = self.worker();
+
+        // The ids of the funding transactions are unique across all the sub-accounts of a client.
+        if transaction.transaction_type().is_funding()
+            && self.used_by_another_sub_account(transaction)?
+        {
+            return Err(AccountError::DuplicateTransaction);
+        }
+        // A move creates its sub-accounts only once it succeeded.
+        if transaction.transaction_type() == TransactionType::Move {
+            return self.apply_move(transaction);
+        }
+
+        let account = match self.accounts.entry((client, transaction.account().clone())) {
+            Entry::Occupied(occupied_entry) => occupied_entry.into_mut(),
+            // Records that only reference a previous transaction can't succeed on a new account.
+            // Don't leave behind an empty account for them if requested.
+            Entry::Vacant(_)
+                if self.options.reject_unknown_clients
+                    && !transaction.transaction_type().is_funding() =>
+            {
+                return Err(AccountError::UnknownClient);
+            }
+            Entry::Vacant(vacant_entry) => {
+                let account = vacant_entry.insert(new_account(
+                    &self.options,
+                    &self.clock,
+                    client,
+                    transaction.account().clone(),
+                )?);
+                add_sub_account(&mut self.sub_accounts, client, transaction.account());
+                account
+            }
+        };
+        // The clean period is checked before the transaction, which may be refused by a locked account.
+        if let Some(clean_period) = self.options.auto_unlock_after {
+            unlock_if_clean(worker, clean_period, account);
+        }
+        // The deposits that settled and the reserves released in the meantime are available to the transaction.
+        account.release_due_funds();
+
+        let funds = match transaction.transaction_type() {
+            TransactionType::Deposit => {
+                let amount = transaction.amount().ok_or(AccountError::InvalidAmount)?;
+                account.deposit(amount, transaction_id)?;
+                (amount, None)
+            }
+            TransactionType::Withdrawal => {
+                let amount = transaction.amount().ok_or(AccountError::InvalidAmount)?;
+                account.withdraw(amount, transaction_id)?;
+                (amount, None)
+            }
+            TransactionType::Dispute => {
+                let source = self.options.dispute_source(transaction.source())?;
+                self.disk_lookups
+                    .check(account, transaction_id, &self.clock)?;
+                account
+                    .dispute(transaction_id, transaction.amount(), source)?
+                    .into()
+            }
+            TransactionType::Resolve => {
+                let source = self.options.dispute_source(transaction.source())?;
+                self.disk_lookups
+                    .check(account, transaction_id, &self.clock)?;
+                account.resolve_dispute(transaction_id, source)?.into()
+            }
+            TransactionType::Chargeback => {
+                let source = self.options.dispute_source(transaction.source())?;
+                self.disk_lookups
+                    .check(account, transaction_id, &self.clock)?;
+                account.chargeback(transaction_id, source)?.into()
+            }
+            TransactionType::ChargebackReversal => {
+                let source = self.options.dispute_source(transaction.source())?;
+                self.disk_lookups
+                    .check(account, transaction_id, &self.clock)?;
+                account
+                    .reverse_chargeback(
+                        transaction_id,
+                        source,
+                        self.options.unlock_on_chargeback_reversal,
+                    )?
+                    .into()
+            }
+            TransactionType::Move => {
+                unreachable!("Moves are applied before the account is looked up.")
+            }
+            TransactionType::EscrowHold => {
+                let amount = transaction.amount().ok_or(AccountError::InvalidAmount)?;
+                account.escrow_hold(amount, transaction_id)?;
+                (amount, None)
+            }
+            TransactionType::EscrowRelease => {
+                let party = transaction
+                    .release_to()
+                    .ok_or(AccountError::EscrowPartyRequired)?;
+                (account.escrow_release(transaction_id, party)?, None)
+            }
+        };
+        let (amount, disputed) = funds;
+
+        let event = AppliedEvent {
+            transaction_type: transaction.transaction_type(),
+            transaction_id,
+            amount,
+            release_to: transaction.release_to(),
+            disputed,
+            worker,
+            account: account.snapshot(),
+            period: self.period,
+            applied_at: self.clock.now(),
+        };
+
+        self.monitor_chargebacks(client, transaction.transaction_type());
+        self.publish(event);
+        self.compact(&(client, transaction.account().clone()));
+        Ok(())
+    }
+}
+
+impl TransactionProcessor {
+    // Track the chargeback rate of a client after one of its transactions was applied. Deposits are suspended on all the
+    // sub-accounts of the client once it's too high.
+    fn monitor_chargebacks(&mut self, client: ClientId, transaction_type: TransactionType) {
+        if let Some(monitor) = &mut self.chargeback_monitor
+            && monitor.record(client, transaction_type)
+            && monitor.policy().withdrawal_only
+        {
+            for (_, account) in self.accounts.iter_mut().filter(|((c, _), _)| *c == client) {
+                account.set_withdrawal_only();
+            }
+        }
+    }
+
+    // Move funds between two sub-accounts of a client. The move never leaves the processor since all the sub-accounts
+    // of a client are handled by the same processor. The destination sub-account is created if needed, once the move
+    // succeeded, so that a move that is refused (e.g. to a mistyped sub-account) doesn't leave an empty one behind.
+    fn apply_move(&mut self, transaction: &Transaction) -> Result<(), AccountError> {
+        let client = transaction.client();
+        let amount = transaction.amount().ok_or(AccountError::InvalidAmount)?;
+        let source = (client, transaction.account().clone());
+        let destination = match transaction.to_account() {
+            Some(name) if name != transaction.account() => (client, name.clone()),
+            _ => return Err(AccountError::InvalidMove),
+        };
+
+        let worker = self.worker();
+        let mut created = None;
+        let (from, to) = match self.accounts.get_disjoint_mut([&source, &destination]) {
+            [Some(from), Some(to)] => (from, to),
+            [Some(from), None] => {
+                let to = new_account(&self.options, &self.clock, client, destination.1.clone())?;
+                (from, created.insert(to))
+            }
+            // Without a source there are no funds to move.
+            [None, _] => return Err(AccountError::InsufficientFunds),
+        };
+        for account in [&mut *from, &mut *to] {
+            if let Some(clean_period) = self.options.auto_unlock_after {
+                unlock_if_clean(worker, clean_period, account);
+            }
+        }
+        // The deposits that settled and the reserves released in the meantime can be moved.
+        from.release_due_funds();
+        from.move_to(to, amount, transaction.id())?;
+
+        let events = [from.snapshot(), to.snapshot()].map(|account| AppliedEvent {
+            transaction_type: TransactionType::Move,
+            transaction_id: transaction.id(),
+            amount,
+            release_to: None,
+            disputed: None,
+            worker: self.worker(),
+            account,
+            period: self.period,
+            applied_at: self.clock.now(),
+        });
+        if let Some(account) = created {
+            self.accounts.insert(destination.clone(), account);
+            add_sub_account(&mut self.sub_accounts, client, &destination.1);
+        }
+        for event in events {
+            self.publish(event);
+        }
+        self.compact(&source);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        clock::ManualClock,
+        engine::Partitioner,
+        monitoring::ChargebackAlertPolicy,
+        output::{OutputColumns, OutputSchema},
+        registry::{AccountRegistry, AccountTotals},
+        transaction_types::EscrowParty,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_reply_to_health_checks_in_order() {
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(TransactionProcessor::new(ProcessorOptions::default()).run(rx));
+        tx.send(ProcessorMessage::process_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(1.0.into()),
+        )))
+        .await
+        .unwrap();
+
+        let (reply, health) = oneshot::channel();
+        tx.send(ProcessorMessage::HealthCheck(reply)).await.unwrap();
+        assert!(health.await.unwrap().is_ok());
+
+        tx.send(ProcessorMessage::shutdown()).await.unwrap();
+        assert_eq!(worker.await.unwrap().summary().applied, 1);
+    }
+
+    #[tokio::test]
+    async fn should_hold_or_reject_transactions_while_paused() {
+        let deposit = |id: u32| {
+            ProcessorMessage::process_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            ))
+        };
+        for (policy, applied, failed) in [(PausePolicy::Buffer, 2, 0), (PausePolicy::Reject, 1, 1)]
+        {
+            let (tx, rx) = mpsc::channel(16);
+            let worker = tokio::spawn(
+                TransactionProcessor::new(ProcessorOptions {
+                    pause_policy: policy,
+                    ..Default::default()
+                })
+                .run(rx),
+            );
+            tx.send(ProcessorMessage::Pause).await.unwrap();
+            tx.send(deposit(1)).await.unwrap();
+            // Health checks are answered while paused.
+            let (reply, health) = oneshot::channel();
+            tx.send(ProcessorMessage::HealthCheck(reply)).await.unwrap();
+            assert!(health.await.unwrap().is_ok());
+            tx.send(ProcessorMessage::Resume).await.unwrap();
+            tx.send(deposit(2)).await.unwrap();
+
+            tx.send(ProcessorMessage::shutdown()).await.unwrap();
+            let worker = worker.await.unwrap();
+            let summary = worker.summary();
+            assert_eq!((summary.applied, summary.failed), (applied, failed));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_send_internal_failures_to_the_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let (rejects, dead_letters) = (dir.path().join("rejects.csv"), dir.path().join("dead.csv"));
+        let (tx, rx) = mpsc::channel(16);
+        let worker = tokio::spawn(
+            TransactionProcessor::new(ProcessorOptions {
+                pause_policy: PausePolicy::Reject,
+                ..Default::default()
+            })
+            .with_rejects(RejectsReport::create(&rejects, false).unwrap())
+            .with_dead_letters(RejectsReport::create(&dead_letters, false).unwrap())
+            .run(rx),
+        );
+        let withdrawal = |id: u32| {
+            ProcessorMessage::process_transaction(Transaction::new(
+                TransactionType::Withdrawal,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            ))
+        };
+        tx.send(withdrawal(1)).await.unwrap();
+        tx.send(ProcessorMessage::Pause).await.unwrap();
+        tx.send(withdrawal(2)).await.unwrap();
+        tx.send(ProcessorMessage::shutdown()).await.unwrap();
+
+        let summary = worker.await.unwrap().summary().clone();
+        assert_eq!((summary.failed, summary.internal), (2, 1));
+        assert_eq!(
+            std::fs::read_to_string(&rejects).unwrap(),
+            "type,client,tx,amount,stage,reason
+withdrawal,1,1,1,apply,Account has insufficient funds to satisfy this transaction.
+"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&dead_letters).unwrap(),
+            "type,client,tx,amount,stage,reason
+withdrawal,1,2,1,internal,Processing is paused by an operator.
+"
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "which doesn't own it")]
+    fn should_catch_clients_sent_to_the_wrong_worker() {
+        let client = ClientId::from(1);
+        let owner = Shard::of(client, 4, Partitioner::Range);
+        let other = Shard::new((owner.index() + 1) % 4, 4, Partitioner::Range);
+        let mut processor =
+            TransactionProcessor::new(ProcessorOptions::default()).with_shard(other);
+
+        let _ = processor.apply(&Transaction::new(
+            TransactionType::Deposit,
+            client,
+            1.into(),
+            Some(1.0.into()),
+        ));
+    }
+
+    #[test]
+    fn should_require_disputes_to_be_closed_by_their_source() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            require_dispute_source: true,
+            ..Default::default()
+        });
+        let record =
+            |transaction_type| Transaction::new(transaction_type, 1.into(), 1.into(), None);
+        assert!(
+            processor
+                .apply(&Transaction::new(
+                    TransactionType::Deposit,
+                    1.into(),
+                    1.into(),
+                    Some(10.0.into()),
+                ))
+                .is_ok()
+        );
+
+        assert!(matches!(
+            processor.apply(&record(TransactionType::Dispute)),
+            Err(AccountError::DisputeSourceRequired)
+        ));
+        assert!(
+            processor
+                .apply(&record(TransactionType::Dispute).with_source(DisputeSource::Issuer))
+                .is_ok()
+        );
+        assert!(matches!(
+            processor.apply(&record(TransactionType::Chargeback)),
+            Err(AccountError::DisputeSourceRequired)
+        ));
+        assert!(matches!(
+            processor
+                .apply(&record(TransactionType::Chargeback).with_source(DisputeSource::Partner)),
+            Err(AccountError::DisputeSourceMismatch {
+                opened: DisputeSource::Issuer,
+                closing: DisputeSource::Partner
+            })
+        ));
+        // The API follows the same rules.
+        assert!(matches!(
+            processor.manage_dispute(
+                DisputeAction::Resolve,
+                1.into(),
+                &AccountName::default(),
+                1.into(),
+                None,
+                Some(DisputeSource::Internal),
+            ),
+            Err(AccountError::DisputeSourceMismatch { .. })
+        ));
+        assert!(
+            processor
+                .apply(&record(TransactionType::Chargeback).with_source(DisputeSource::Issuer))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn should_limit_account_totals_per_client() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            balance_limits: BalanceLimits {
+                default: Some(10.0.into()),
+                clients: HashMap::from([(2.into(), 100.0.into())]),
+            },
+            ..Default::default()
+        });
+        let deposit = |client: u16, id: u32| {
+            Transaction::new(
+                TransactionType::Deposit,
+                client.into(),
+                id.into(),
+                Some(50.0.into()),
+            )
+        };
+
+        assert!(matches!(
+            processor.apply(&deposit(1, 1)),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+        assert!(processor.apply(&deposit(2, 2)).is_ok());
+        assert!(processor.apply(&deposit(2, 3)).is_ok());
+        assert!(matches!(
+            processor.apply(&deposit(2, 4)),
+            Err(AccountError::BalanceLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn can_process_multiple_deposits_and_withdrawals() {
+        let transactions = [
+            Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                1.into(),
+                Some(100.0.into()),
+            ),
+            // 1 deposit 100
+            Transaction::new(
+                TransactionType::Deposit,
+                2.into(),
+                2.into(),
+                Some(200.0.into()),
+            ),
+            // 2 deposit 200
+            Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                3.into(),
+                Some(200.0.into()),
+            ),
+            // 1 deposit 200, total 300
+            Transaction::new(
+                TransactionType::Withdrawal,
+                2.into(),
+                4.into(),
+                Some(150.0.into()),
+            ), // 2 withdrawal 150, total 50
+            Transaction::new(
+                TransactionType::Withdrawal,
+                1.into(),
+                5.into(),
+                Some(300.0.into()),
+            ), // 1 withdraw 300, total 0
+        ];
+
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default());
+
+        for transaction in transactions.iter() {
+            assert!(processor.apply(transaction).is_ok());
+        }
+
+        assert_eq!(
+            processor
+                .accounts
+                .get(&(1.into(), AccountName::default()))
+                .unwrap()
+                .available(),
+            0.0.into()
+        );
+        assert_eq!(
+            processor
+                .accounts
+                .get(&(2.into(), AccountName::default()))
+                .unwrap()
+                .available(),
+            50.0.into()
+        );
+    }
+
+    #[test]
+    fn should_not_create_accounts_for_unknown_client_references() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            reject_unknown_clients: true,
+            ..Default::default()
+        });
+
+        let dispute = Transaction::new(TransactionType::Dispute, 1.into(), 1.into(), None);
+        assert!(matches!(
+            processor.apply(&dispute),
+            Err(AccountError::UnknownClient)
+        ));
+        assert!(processor.accounts.is_empty());
+
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            2.into(),
+            Some(10.0.into()),
+        );
+        assert!(processor.apply(&deposit).is_ok());
+        assert!(matches!(
+            processor.apply(&dispute),
+            Err(AccountError::TransactionMissing)
+        ));
+        assert_eq!(processor.accounts.len(), 1);
+    }
+
+    #[test]
+    fn should_create_accounts_for_unknown_client_references_by_default() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default());
+
+        let dispute = Transaction::new(TransactionType::Dispute, 1.into(), 1.into(), None);
+        assert!(matches!(
+            processor.apply(&dispute),
+            Err(AccountError::TransactionMissing)
+        ));
+        assert_eq!(processor.accounts.len(), 1);
+    }
+
+    #[test]
+    fn should_manage_disputes_with_optimistic_concurrency() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default());
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(10.0.into()),
+        );
+        assert!(processor.apply(&deposit).is_ok());
+
+        let status = processor
+            .manage_dispute(
+                DisputeAction::Status,
+                1.into(),
+                &AccountName::default(),
+                1.into(),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(status.version, 0);
+
+        let opened = processor
+            .manage_dispute(
+                DisputeAction::Open,
+                1.into(),
+                &AccountName::default(),
+                1.into(),
+                Some(status.version),
+                None,
+            )
+            .unwrap();
+        assert_eq!(opened.dispute_id, 1.into());
+        assert_eq!(opened.state, DisputeState::DisputeInitiated);
+        assert_eq!(opened.version, 1);
+        assert_eq!(opened.account.held, 10.0.into());
+        assert_eq!(opened.account.available, 0.0.into());
+
+        // The dispute changed since the status was read.
+        assert!(matches!(
+            processor.manage_dispute(
+                DisputeAction::Chargeback,
+                1.into(),
+                &AccountName::default(),
+                1.into(),
+                Some(status.version),
+                None,
+            ),
+            Err(AccountError::StaleDisputeState {
+                expected: 0,
+                current: 1
+            })
+        ));
+
+        let resolved = processor
+            .manage_dispute(
+                DisputeAction::Resolve,
+                1.into(),
+                &AccountName::default(),
+                1.into(),
+                Some(1),
+                None,
+            )
+            .unwrap();
+        assert_eq!(resolved.state, DisputeState::DisputeResolved);
+        assert_eq!(resolved.account.available, 10.0.into());
+    }
+
+    #[test]
+    fn should_count_and_monitor_the_chargebacks_of_the_api() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default())
+            .with_chargeback_monitor(ChargebackMonitor::new(ChargebackAlertPolicy {
+                threshold: Decimal::new(5, 1),
+                window: 1,
+                withdrawal_only: true,
+            }));
+        let deposit = |tx: u32, account: &str| {
+            Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                tx.into(),
+                Some(10.0.into()),
+            )
+            .with_accounts(account, None)
+        };
+        assert!(processor.apply(&deposit(1, "main")).is_ok());
+        assert!(processor.apply(&deposit(2, "savings")).is_ok());
+
+        for action in [DisputeAction::Open, DisputeAction::Chargeback] {
+            processor
+                .manage_dispute(
+                    action,
+                    1.into(),
+                    &AccountName::default(),
+                    1.into(),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(processor.summary().applied, 2);
+        // The chargeback rate of the client crossed the threshold, so its deposits are suspended.
+        assert!(matches!(
+            processor.apply(&deposit(3, "savings")),
+            Err(AccountError::DepositsSuspended)
+        ));
+    }
+
+    #[test]
+    fn should_not_create_accounts_on_dispute_requests() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default());
+
+        assert!(matches!(
+            processor.manage_dispute(
+                DisputeAction::Open,
+                1.into(),
+                &AccountName::default(),
+                1.into(),
+                None,
+                None,
+            ),
+            Err(AccountError::UnknownClient)
+        ));
+        assert!(processor.accounts.is_empty());
+    }
+
+    struct RecordingSink(std::sync::Arc<std::sync::Mutex<Vec<AppliedEvent>>>);
+
+    impl EventSink for RecordingSink {
+        fn publish(&mut self, event: &AppliedEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    // A sink that only writes out its events when flushed.
+    struct BufferingSink {
+        buffered: usize,
+        written: std::sync::Arc<std::sync::Mutex<usize>>,
+    }
+
+    impl EventSink for BufferingSink {
+        fn publish(&mut self, _: &AppliedEvent) {
+            self.buffered += 1;
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            *self.written.lock().unwrap() += std::mem::take(&mut self.buffered);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn should_flush_the_sinks_before_acknowledging_a_flush() {
+        let written = std::sync::Arc::default();
+        let (tx, rx) = mpsc::channel(16);
+        let processor = TransactionProcessor::new(ProcessorOptions {
+            pause_policy: PausePolicy::Buffer,
+            ..Default::default()
+        })
+        .with_sink(BufferingSink {
+            buffered: 0,
+            written: std::sync::Arc::clone(&written),
+        });
+        let worker = tokio::spawn(processor.run(rx));
+
+        // The transactions held back by a pause are applied before the flush is acknowledged.
+        tx.send(ProcessorMessage::Pause).await.unwrap();
+        for id in 1..=3 {
+            tx.send(ProcessorMessage::process_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            )))
+            .await
+            .unwrap();
+        }
+        let (reply, mut flushed) = oneshot::channel();
+        tx.send(ProcessorMessage::Flush(reply)).await.unwrap();
+        tokio::task::yield_now().await;
+        assert!(flushed.try_recv().is_err());
+        assert_eq!(*written.lock().unwrap(), 0);
+
+        tx.send(ProcessorMessage::Resume).await.unwrap();
+        assert!(flushed.await.unwrap().is_ok());
+        assert_eq!(*written.lock().unwrap(), 3);
+
+        tx.send(ProcessorMessage::shutdown()).await.unwrap();
+        worker.await.unwrap();
+    }
+
+    #[test]
+    fn should_publish_events_for_applied_transactions_only() {
+        let events = std::sync::Arc::default();
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default())
+            .with_sink(RecordingSink(std::sync::Arc::clone(&events)));
+
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(10.0.into()),
+        );
+        let withdrawal = Transaction::new(
+            TransactionType::Withdrawal,
+            1.into(),
+            2.into(),
+            Some(20.0.into()),
+        );
+        assert!(processor.apply(&deposit).is_ok());
+        assert!(processor.apply(&withdrawal).is_err());
+        assert!(
+            processor
+                .manage_dispute(
+                    DisputeAction::Open,
+                    1.into(),
+                    &AccountName::default(),
+                    1.into(),
+                    None,
+                    None,
+                )
+                .is_ok()
+        );
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].transaction_type, TransactionType::Deposit);
+        assert_eq!(events[0].account.total, 10.0.into());
+        assert_eq!(events[1].transaction_type, TransactionType::Dispute);
+        assert_eq!(events[1].account.held, 10.0.into());
+    }
+
+    #[test]
+    fn should_stamp_events_with_the_time_of_the_clock() {
+        let events = std::sync::Arc::default();
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default())
+            .with_clock(clock.shared())
+            .with_sink(RecordingSink(std::sync::Arc::clone(&events)));
+
+        for id in 1..=2u32 {
+            let deposit = Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            );
+            processor.apply(&deposit).unwrap();
+            clock.advance(chrono::Duration::hours(25));
+        }
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events[0].applied_at.to_rfc3339(),
+            "2024-03-01T12:00:00+00:00"
+        );
+        assert_eq!(
+            events[1].applied_at.to_rfc3339(),
+            "2024-03-02T13:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn should_throttle_disputes_of_transactions_on_disk() {
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            max_disk_lookups: Some(1),
+            ..Default::default()
+        })
+        .with_clock(clock.shared());
+        // The first deposits no longer fit in memory.
+        for id in 1..=130u32 {
+            processor.handle(ProcessorMessage::process_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                id.into(),
+                Some(1.0.into()),
+            )));
+        }
+        let mut dispute = |id: u32| {
+            let dispute = Transaction::new(TransactionType::Dispute, 1.into(), id.into(), None);
+            processor.handle(ProcessorMessage::process_transaction(dispute));
+            processor.summary().clone()
+        };
+
+        assert_eq!(dispute(1).applied, 131);
+        assert_eq!(dispute(2).throttled, BTreeMap::from([(1.into(), 1)]));
+        // Transactions that don't exist are looked up on disk too.
+        assert_eq!(dispute(1000).throttled, BTreeMap::from([(1.into(), 2)]));
+        // Transactions in memory are never throttled.
+        assert_eq!(dispute(130).applied, 132);
+        clock.advance(chrono::Duration::minutes(1));
+        let summary = dispute(2);
+
+        assert_eq!(summary.applied, 133);
+        assert_eq!(summary.failed, 2);
+    }
+
+    #[test]
+    fn should_settle_the_deposits_after_the_settlement_delay() {
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            settlement_delay: Some(TimeDelta::days(2)),
+            ..Default::default()
+        })
+        .with_clock(clock.shared());
+        let mut send = |transaction_type: TransactionType, id: u32, amount: f64| {
+            processor.handle(ProcessorMessage::process_transaction(Transaction::new(
+                transaction_type,
+                1.into(),
+                id.into(),
+                Some(amount.into()),
+            )));
+            processor.summary().clone()
+        };
+
+        send(TransactionType::Deposit, 1, 10.0);
+        assert_eq!(send(TransactionType::Withdrawal, 2, 4.0).failed, 1);
+        clock.advance(TimeDelta::days(2));
+        // The deposit settled before the withdrawal is applied.
+        assert_eq!(send(TransactionType::Withdrawal, 3, 4.0).applied, 2);
+        send(TransactionType::Deposit, 4, 5.0);
+
+        // The accounts are swept before they are read.
+        clock.advance(TimeDelta::days(2));
+        let (reply, accounts) = oneshot::channel();
+        processor.handle(ProcessorMessage::QueryAccounts(AccountQuery {
+            client: None,
+            reply,
+        }));
+        let accounts = accounts.blocking_recv().unwrap();
+        assert_eq!(
+            (accounts[0].available, accounts[0].pending),
+            (11.0.into(), Amount::zero())
+        );
+    }
+
+    #[test]
+    fn should_only_count_the_applied_transactions_towards_the_limits() {
+        let limits =
+            ActivityLimits::new().with(crate::pipeline::WithdrawalLimitValidator::new(10.0.into()));
+        let mut processor =
+            TransactionProcessor::new(ProcessorOptions::default()).with_limits(limits);
+        let mut send = |transaction_type: TransactionType, id: u32, amount: f64| {
+            processor.handle(ProcessorMessage::process_transaction(Transaction::new(
+                transaction_type,
+                1.into(),
+                id.into(),
+                Some(amount.into()),
+            )));
+            processor.summary().clone()
+        };
+
+        // The withdrawal is refused for lack of funds, so it doesn't count towards the limit.
+        assert_eq!(send(TransactionType::Withdrawal, 1, 8.0).failed, 1);
+        send(TransactionType::Deposit, 2, 20.0);
+        let summary = send(TransactionType::Withdrawal, 3, 8.0);
+        assert_eq!((summary.applied, summary.rejected), (2, 0));
+        let summary = send(TransactionType::Withdrawal, 4, 5.0);
+        assert_eq!((summary.applied, summary.rejected), (2, 1));
+    }
+
+    #[test]
+    fn should_only_withhold_a_reserve_of_the_clients_with_a_reserve_policy() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            reserves: ReservePolicy {
+                clients: HashMap::from([(2.into(), Decimal::from(25))]),
+                period: TimeDelta::days(90),
+            },
+            ..Default::default()
+        });
+        for client in [1u16, 2] {
+            processor.handle(ProcessorMessage::process_transaction(Transaction::new(
+                TransactionType::Deposit,
+                client.into(),
+                u32::from(client).into(),
+                Some(10.0.into()),
+            )));
+        }
+
+        let (reply, accounts) = oneshot::channel();
+        processor.handle(ProcessorMessage::QueryAccounts(AccountQuery {
+            client: None,
+            reply,
+        }));
+        let mut accounts = accounts.blocking_recv().unwrap();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|account| (account.available, account.reserve))
+                .collect::<Vec<_>>(),
+            [(10.0.into(), Amount::zero()), (7.5.into(), 2.5.into())]
+        );
+    }
+
+    #[test]
+    fn should_apply_disputes_that_arrive_before_their_deposit() {
+        let clock = ManualClock::at("2024-03-01T12:00:00Z");
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            dispute_reorder: Some(ReorderWindow {
+                transactions: 2,
+                timeout: Some(TimeDelta::seconds(30)),
+            }),
+            ..Default::default()
+        })
+        .with_clock(clock.shared());
+        let mut send = |transaction_type: TransactionType, id: u32, amount: Option<f64>| {
+            processor.handle(ProcessorMessage::process_transaction(Transaction::new(
+                transaction_type,
+                1.into(),
+                id.into(),
+                amount.map(Into::into),
+            )));
+            processor.summary().clone()
+        };
+
+        // The resolve waits behind its dispute, which waits for the deposit.
+        send(TransactionType::Dispute, 1, None);
+        send(TransactionType::Resolve, 1, None);
+        send(TransactionType::Deposit, 2, Some(5.0));
+        let summary = send(TransactionType::Deposit, 1, Some(10.0));
+        assert_eq!(
+            (summary.applied, summary.reordered, summary.failed),
+            (4, 2, 0)
+        );
+
+        // A dispute is rejected once 2 more transactions arrived without its deposit, or after 30 seconds.
+        send(TransactionType::Dispute, 7, None);
+        send(TransactionType::Deposit, 3, Some(1.0));
+        assert_eq!(send(TransactionType::Deposit, 4, Some(1.0)).failed, 1);
+        send(TransactionType::Dispute, 8, None);
+        clock.advance(chrono::Duration::seconds(30));
+        assert_eq!(send(TransactionType::Deposit, 5, Some(1.0)).failed, 2);
+
+        // The operations still waiting at the end of the input are rejected.
+        send(TransactionType::Dispute, 9, None);
+        let (reply, _flushed) = oneshot::channel();
+        processor.handle(ProcessorMessage::Flush(reply));
+        let summary = processor.summary();
+        assert_eq!(summary.failed, 3);
+        assert_eq!(summary.reasons["TransactionMissing"], 3);
+        assert_eq!(processor.snapshots()[0].available, Amount::from(18.0));
+    }
+
+    #[test]
+    fn should_keep_the_registry_up_to_date() {
+        let registry = AccountRegistry::default();
+        let mut processor =
+            TransactionProcessor::new(ProcessorOptions::default()).with_registry(registry.worker());
+        let transactions = [
+            Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                1.into(),
+                Some(10.0.into()),
+            ),
+            Transaction::new(
+                TransactionType::Deposit,
+                2.into(),
+                2.into(),
+                Some(5.0.into()),
+            ),
+            Transaction::new(TransactionType::Move, 1.into(), 3.into(), Some(4.0.into()))
+                .with_accounts("main", Some("savings")),
+            Transaction::new(TransactionType::Dispute, 2.into(), 2.into(), None),
+            Transaction::new(TransactionType::Dispute, 1.into(), 1.into(), None),
+            Transaction::new(TransactionType::Chargeback, 1.into(), 1.into(), None),
+            // Fails, but leaves an empty account behind.
+            Transaction::new(
+                TransactionType::Withdrawal,
+                3.into(),
+                4.into(),
+                Some(1.0.into()),
+            ),
+        ];
+        for transaction in transactions {
+            processor.handle(ProcessorMessage::process_transaction(transaction));
+        }
+
+        assert_eq!(
+            registry.totals(),
+            AccountTotals {
+                accounts: 4,
+                locked: 1,
+                available: Amount::zero(),
+                held: 5.0.into(),
+                escrow: Amount::zero(),
+                pending: Amount::zero(),
+                reserve: Amount::zero(),
+                total: 5.0.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn should_move_funds_between_sub_accounts_of_a_client() {
+        let events = std::sync::Arc::default();
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default())
+            .with_sink(RecordingSink(std::sync::Arc::clone(&events)));
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(10.0.into()),
+        );
+        let move_to_savings =
+            Transaction::new(TransactionType::Move, 1.into(), 2.into(), Some(4.0.into()))
+                .with_accounts("main", Some("savings"));
+        let move_to_itself =
+            Transaction::new(TransactionType::Move, 1.into(), 3.into(), Some(1.0.into()))
+                .with_accounts("savings", Some("savings"));
+        assert!(!processor.has_sub_accounts());
+
+        assert!(processor.apply(&deposit).is_ok());
+        assert!(processor.apply(&move_to_savings).is_ok());
+        assert!(matches!(
+            processor.apply(&move_to_itself),
+            Err(AccountError::InvalidMove)
+        ));
+
+        assert!(processor.has_sub_accounts());
+        let savings = (1.into(), "savings".parse().unwrap());
+        assert_eq!(processor.accounts[&savings].total(), 4.0.into());
+        assert_eq!(
+            processor.accounts[&(1.into(), AccountName::default())].total(),
+            6.0.into()
+        );
+
+        // Both sub-accounts publish their balances.
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events[1].account.account.is_main());
+        assert_eq!(events[2].account.account, savings.1);
+        assert_eq!(events[2].account.total, 4.0.into());
+
+        let columns = OutputSchema::V1.columns(&OutputColumns {
+            account: true,
+            ..Default::default()
+        });
+        let mut writer = AccountWriter::new(vec![], columns);
+        processor.write_csv_records(&mut writer, &AccountFilter::default());
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(output.starts_with("client,account,available,held,total,locked\n"));
+        assert!(output.contains("1,savings,4,0,4,false\n"));
+        assert!(output.contains("1,main,6,0,6,false\n"));
+    }
+
+    #[test]
+    fn should_not_create_sub_accounts_for_refused_moves() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default());
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(10.0.into()),
+        );
+        let too_large =
+            Transaction::new(TransactionType::Move, 1.into(), 2.into(), Some(20.0.into()))
+                .with_accounts("main", Some("savingz"));
+        let from_nowhere =
+            Transaction::new(TransactionType::Move, 1.into(), 3.into(), Some(1.0.into()))
+                .with_accounts("nowhere", Some("savings"));
+
+        assert!(processor.apply(&deposit).is_ok());
+        assert!(matches!(
+            processor.apply(&too_large),
+            Err(AccountError::InsufficientFunds)
+        ));
+        assert!(matches!(
+            processor.apply(&from_nowhere),
+            Err(AccountError::InsufficientFunds)
+        ));
+
+        assert!(!processor.has_sub_accounts());
+        assert_eq!(processor.accounts.len(), 1);
+    }
+
+    #[test]
+    fn should_reject_ids_used_by_another_sub_account_of_the_client() {
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default());
+        let deposit = |tx: u32, account: &str| {
+            Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                tx.into(),
+                Some(10.0.into()),
+            )
+            .with_accounts(account, None)
+        };
+        let move_to_savings =
+            Transaction::new(TransactionType::Move, 1.into(), 2.into(), Some(4.0.into()))
+                .with_accounts("main", Some("savings"));
+
+        assert!(processor.apply(&deposit(1, "main")).is_ok());
+        assert!(processor.apply(&move_to_savings).is_ok());
+        for tx in [1, 2] {
+            assert!(matches!(
+                processor.apply(&deposit(tx, "savings")),
+                Err(AccountError::DuplicateTransaction)
+            ));
+        }
+        assert!(processor.apply(&deposit(3, "savings")).is_ok());
+        assert!(matches!(
+            processor.apply(&deposit(3, "main")),
+            Err(AccountError::DuplicateTransaction)
+        ));
+
+        let savings = (1.into(), "savings".parse().unwrap());
+        assert_eq!(processor.accounts[&savings].total(), 14.0.into());
+    }
+
+    #[test]
+    fn should_hold_and_release_escrow() {
+        let events = std::sync::Arc::default();
+        let mut processor = TransactionProcessor::new(ProcessorOptions::default())
+            .with_sink(RecordingSink(std::sync::Arc::clone(&events)));
+        let deposit = Transaction::new(
+            TransactionType::Deposit,
+            1.into(),
+            1.into(),
+            Some(10.0.into()),
+        );
+        let hold = Transaction::new(
+            TransactionType::EscrowHold,
+            1.into(),
+            2.into(),
+            Some(4.0.into()),
+        );
+        let release_without_party =
+            Transaction::new(TransactionType::EscrowRelease, 1.into(), 2.into(), None);
+        let release = Transaction::new(TransactionType::EscrowRelease, 1.into(), 2.into(), None)
+            .with_release_to(EscrowParty::Beneficiary);
+
+        assert!(processor.apply(&deposit).is_ok());
+        assert!(processor.apply(&hold).is_ok());
+        assert!(matches!(
+            processor.apply(&release_without_party),
+            Err(AccountError::EscrowPartyRequired)
+        ));
+        assert!(processor.apply(&release).is_ok());
+
+        let account = &processor.accounts[&(1.into(), AccountName::default())];
+        assert_eq!(account.total(), 6.0.into());
+        assert_eq!(account.available(), 6.0.into());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].account.escrow, 4.0.into());
+        assert_eq!(events[2].amount, 4.0.into());
+        assert_eq!(events[2].release_to, Some(EscrowParty::Beneficiary));
+    }
+
+    #[test]
+    fn should_archive_transactions_outside_the_dispute_window() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("archive.csv");
+        let mut processor = TransactionProcessor::new(ProcessorOptions {
+            dispute_window: Some(2),
+            ..Default::default()
+        })
+        .with_clock(ManualClock::at("2024-03-01T12:00:00Z").shared())
+        .with_history_archive(HistoryArchive::open(&path).unwrap());
+
+        for tx in 1..=4 {
+            let deposit = Transaction::new(
+                TransactionType::Deposit,
+                1.into(),
+                tx.into(),
+                Some(1.0.into()),
+            );
+            assert!(processor.apply(&deposit).is_ok());
+        }
+
+        let archive = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(archive.lines().count(), 4);
+        assert!(archive.contains(
+            "transaction,1,main,1,deposit,1,none,,,,,,0,2024-03-01T12:00:00.000Z,2024-03-01T12:00:00.000Z\n"
+        ));
+        assert!(archive.contains("checkpoint,1,main,4,,,,,4,0,0,4,,,\n"));
+        // The archived transactions can no longer be disputed, the ones in the window still can.
+        let dispute =
+            |tx: u32| Transaction::new(TransactionType::Dispute, 1.into(), tx.into(), None);
+        assert!(matches!(
+            processor.apply(&dispute(2)),
+            Err(AccountError::TransactionMissing)
+        ));
+        assert!(processor.apply(&dispute(3)).is_ok());
     }
 }
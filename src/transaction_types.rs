@@ -1,10 +1,20 @@
-use std::fmt::Display;
+use std::{cell::Cell, fmt::Display, str::FromStr, sync::OnceLock};
 
-use rust_decimal::{Decimal, prelude::Zero};
-use serde::{Deserialize, Serialize, de::Error};
+use rust_decimal::{Decimal, RoundingStrategy, prelude::Zero};
+use serde::{Deserialize, Serialize, de::Visitor};
+use thiserror::Error;
 
-/// Transaction definition as specified in the CSV file.
-#[derive(Debug, Deserialize)]
+pub(crate) use crate::transaction::{DisputeSource, EscrowParty, TransactionType};
+
+use crate::{
+    enrichment::Extensions,
+    json::{self, JsonAmounts},
+    transaction::{MAIN_ACCOUNT, NewTransaction, check_account_name},
+};
+
+/// Transaction definition as specified in the CSV file. Written with the same columns, so a transaction that is written
+/// (e.g. forwarded to a peer) reads back the same, apart from the enrichments and the line.
+#[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Transaction {
     /// Transaction type.
     #[serde(rename = "type")]
@@ -13,8 +23,29 @@ pub(crate) struct Transaction {
     client: ClientId,
     /// Transaction id.
     tx: TransactionId,
-    /// Amount which is only specified for deposits and withdrawals.
+    /// Amount which is only specified for deposits, withdrawals, moves and escrow holds.
     amount: Option<Amount>,
+    /// The sub-account of the client. The `main` sub-account is used if the column is missing or empty.
+    #[serde(default)]
+    account: AccountName,
+    /// The sub-account that receives the funds of a move.
+    #[serde(default)]
+    to_account: Option<AccountName>,
+    /// The party that receives the funds of an escrow release.
+    #[serde(default)]
+    release_to: Option<EscrowParty>,
+    /// Who opened or closed the dispute, for disputes, resolves and chargebacks.
+    #[serde(default)]
+    source: Option<DisputeSource>,
+    /// The currency of the amount as given in the input, normalized by the `CurrencyNormalizer` enricher.
+    #[serde(default)]
+    currency: Option<String>,
+    /// Data derived from the transaction by the enrichers of the ingress.
+    #[serde(skip)]
+    extensions: Extensions,
+    /// The line of the input file the transaction was read from, if it was read from a file.
+    #[serde(skip)]
+    line: Option<u64>,
 }
 
 impl Transaction {
@@ -33,20 +64,78 @@ impl Transaction {
     pub(crate) fn id(&self) -> TransactionId {
         self.tx
     }
-}
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
-pub(crate) enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+    pub(crate) fn account(&self) -> &AccountName {
+        &self.account
+    }
+
+    pub(crate) fn to_account(&self) -> Option<&AccountName> {
+        self.to_account.as_ref()
+    }
+
+    pub(crate) fn release_to(&self) -> Option<EscrowParty> {
+        self.release_to
+    }
+
+    pub(crate) fn source(&self) -> Option<DisputeSource> {
+        self.source
+    }
+
+    pub(crate) fn currency(&self) -> Option<&str> {
+        self.currency
+            .as_deref()
+            .filter(|currency| !currency.is_empty())
+    }
+
+    pub(crate) fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub(crate) fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    pub(crate) fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    pub(crate) fn with_line(mut self, line: Option<u64>) -> Self {
+        self.line = line;
+        self
+    }
+
+    /// A dispute operation that doesn't come from the input, e.g. one requested through the daemon API.
+    pub(crate) fn operation(
+        transaction_type: TransactionType,
+        client: ClientId,
+        account: AccountName,
+        tx: TransactionId,
+        source: Option<DisputeSource>,
+    ) -> Self {
+        Transaction {
+            transaction_type,
+            client,
+            tx,
+            amount: None,
+            account,
+            to_account: None,
+            release_to: None,
+            source,
+            currency: None,
+            extensions: Extensions::default(),
+            line: None,
+        }
+    }
+
+    /// The same transaction under another id, e.g. when its id was already used by a previous run.
+    pub(crate) fn with_id(mut self, id: TransactionId) -> Self {
+        self.tx = id;
+        self
+    }
 }
 
 /// Newtype that wraps a u16 for client id safety.
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub(crate) struct ClientId(u16);
 
 impl Display for ClientId {
@@ -61,6 +150,77 @@ impl From<u16> for ClientId {
     }
 }
 
+impl From<ClientId> for u16 {
+    fn from(value: ClientId) -> Self {
+        value.0
+    }
+}
+
+/// Name of a sub-account of a client (e.g. `savings`). Every client has a `main` sub-account which is used when
+/// a transaction doesn't name one. The main sub-account is represented without an allocation since it's the common case.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct AccountName(Option<Box<str>>);
+
+impl AccountName {
+    pub(crate) fn is_main(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl Display for AccountName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.as_deref().unwrap_or(MAIN_ACCOUNT))
+    }
+}
+
+/// Sub-account names are case sensitive and can contain ASCII letters, digits, `_` and `-`.
+impl FromStr for AccountName {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.is_empty() || value == MAIN_ACCOUNT {
+            return Ok(Self::default());
+        }
+        check_account_name(value)?;
+        Ok(Self(Some(value.into())))
+    }
+}
+
+impl Serialize for AccountName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+struct AccountNameVisitor;
+
+impl Visitor<'_> for AccountNameVisitor {
+    type Value = AccountName;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a sub-account name")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        value.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AccountNameVisitor)
+    }
+}
+
 /// Newtype that wraps a u32 for transaction id safety.
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
 pub(crate) struct TransactionId(u32);
@@ -77,38 +237,213 @@ impl From<u32> for TransactionId {
     }
 }
 
+impl From<TransactionId> for u32 {
+    fn from(value: TransactionId) -> Self {
+        value.0
+    }
+}
+
 /// Newtype to handle decimal ammounts.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct Amount(Decimal);
 
-/// Custom deserializer for Amount. Ensures that the amount is non-negative and rounded to 4 decimal places.
+impl Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.normalize())
+    }
+}
+
+/// A error describing why an amount was rejected when parsing it.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum AmountError {
+    #[error("amount cannot be negative")]
+    Negative,
+    #[error("amount in scientific notation is not supported")]
+    ScientificNotation,
+    #[error("amount is smaller than the smallest supported unit (0.0001)")]
+    BelowPrecision,
+    #[error("amount is out of the supported range")]
+    OutOfRange,
+    #[error("amount is not a valid decimal number")]
+    Invalid,
+}
+
+// Number of decimal places that are kept for amounts.
+const AMOUNT_SCALE: u32 = 4;
+
+/// Parsing policy for amounts. Ensures that the amount is non-negative and rounded to 4 decimal places.
+/// Amounts in scientific notation (e.g. 1e10) or that round down to zero (e.g. 0.00001) are rejected with an explicit reason
+/// rather than relying on what the decimal parser happens to accept.
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.contains(['e', 'E']) {
+            return Err(AmountError::ScientificNotation);
+        }
+
+        let decimal = Decimal::from_str(value).map_err(|_| {
+            // A syntactically valid number can only fail to parse because it doesn't fit in a decimal.
+            let digits = value.trim_start_matches('-');
+            if digits.chars().any(|c| c.is_ascii_digit())
+                && digits.matches('.').count() <= 1
+                && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+            {
+                AmountError::OutOfRange
+            } else {
+                AmountError::Invalid
+            }
+        })?;
+        if decimal.is_sign_negative() {
+            return Err(AmountError::Negative);
+        }
+
+        // round up to 4 decimal points.
+        let rounded = decimal.round_dp_with_strategy(AMOUNT_SCALE, RoundingStrategy::ToZero);
+        if rounded.is_zero() && !decimal.is_zero() {
+            return Err(AmountError::BelowPrecision);
+        }
+
+        Ok(rounded.into())
+    }
+}
+
+struct AmountVisitor;
+
+impl Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a non-negative decimal amount")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Amount, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+/// Custom deserializer for Amount. Applies the parsing policy of Amount.
 /// This ensures that all inputs to the system ar normalized so all values are correct by construction.
 impl<'de> Deserialize<'de> for Amount {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let decimal = rust_decimal::serde::str::deserialize(deserializer)?;
-        if decimal.is_sign_negative() {
-            return Err(D::Error::custom("amount cannot be negative"));
+        if json::amounts().is_some() {
+            json_amount(deserializer)?
+                .parse()
+                .map_err(serde::de::Error::custom)
+        } else {
+            deserializer.deserialize_str(AmountVisitor)
         }
+    }
+}
 
-        // round up to 4 decimal points.
-        Ok(decimal
-            .round_dp_with_strategy(4, rust_decimal::RoundingStrategy::ToZero)
-            .into())
+// The text of an amount written either as a JSON string or as a JSON number. The digits of a number are kept as they
+// were written so it never goes through a float.
+fn json_amount<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(text) => Ok(text),
+        serde_json::Value::Number(number) => Ok(number.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected an amount, found {}",
+            other
+        ))),
     }
 }
 
-/// Custom serializer so that the amount is normalized when outputed as a string. (e.g. 1.0 is displayed as 1)
+/// Deserializer for account balances. Unlike transaction amounts, balances can be negative (e.g. after a chargeback).
+pub(crate) fn deserialize_balance<'de, D>(deserializer: D) -> Result<Amount, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let decimal = if json::amounts().is_some() {
+        json_amount(deserializer)?
+            .parse::<Decimal>()
+            .map_err(serde::de::Error::custom)?
+    } else {
+        rust_decimal::serde::str::deserialize(deserializer)?
+    };
+    Ok(decimal
+        .round_dp_with_strategy(AMOUNT_SCALE, RoundingStrategy::ToZero)
+        .into())
+}
+
+/// How amounts are formatted when they are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum AmountFormat {
+    /// Remove trailing zeros (e.g. 1.0 is displayed as 1).
+    #[default]
+    Normalized,
+    /// Always display the given number of decimal places (e.g. 1.0 is displayed as 1.0000 with a scale of 4).
+    FixedScale(u32),
+}
+
+// The amount format is a process wide setting so that it's applied consistently to every output. Like the JSON amount
+// style, the amount serde layer checks whether it's running inside `AmountFormat::output`: everything else, e.g. the
+// transactions spilled to the store, the snapshots and the forwarded batches, is serialized exactly.
+static AMOUNT_FORMAT: OnceLock<AmountFormat> = OnceLock::new();
+
+thread_local! {
+    static FORMAT: Cell<Option<AmountFormat>> = const { Cell::new(None) };
+}
+
+impl AmountFormat {
+    /// Set the format used when serializing amounts. Can only be set once, at startup.
+    pub(crate) fn set_global(format: AmountFormat) -> Result<(), AmountFormat> {
+        AMOUNT_FORMAT.set(format)
+    }
+
+    fn global() -> AmountFormat {
+        AMOUNT_FORMAT.get().copied().unwrap_or_default()
+    }
+
+    /// Run a serde call that writes an output of the engine, so the amounts are written in the output format.
+    pub(crate) fn output<T>(call: impl FnOnce() -> T) -> T {
+        Self::global().scoped(call)
+    }
+
+    // Run a serde call with this format, restoring the previous one after (calls can be nested).
+    fn scoped<T>(self, call: impl FnOnce() -> T) -> T {
+        let outer = FORMAT.with(|format| format.replace(Some(self)));
+        let result = call();
+        FORMAT.with(|format| format.set(outer));
+        result
+    }
+
+    fn apply(&self, value: Decimal) -> Decimal {
+        match self {
+            AmountFormat::Normalized => value.normalize(),
+            AmountFormat::FixedScale(scale) => {
+                let mut rounded = value.round_dp(*scale);
+                rounded.rescale(*scale);
+                rounded
+            }
+        }
+    }
+}
+
+/// Custom serializer so that the amount is formatted according to the output format when written to an output, and
+/// written exactly otherwise.
+/// In JSON, the amount is written as a number instead if `--json-amounts number` is set.
 impl Serialize for Amount {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let normalized = self.0.normalize();
-
-        rust_decimal::serde::str::serialize(&normalized, serializer)
+        let formatted = FORMAT.with(Cell::get).unwrap_or_default().apply(self.0);
+
+        if json::amounts() == Some(JsonAmounts::Number) {
+            let number: serde_json::Number = formatted
+                .to_string()
+                .parse()
+                .map_err(serde::ser::Error::custom)?;
+            return number.serialize(serializer);
+        }
+        rust_decimal::serde::str::serialize(&formatted, serializer)
     }
 }
 
@@ -117,6 +452,23 @@ impl Amount {
         Self(Decimal::zero())
     }
 
+    pub(crate) fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub(crate) fn is_negative(&self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    /// A percentage of the amount, rounded towards zero to the precision of the amounts.
+    pub(crate) fn percent(self, percent: Decimal) -> Amount {
+        let share = self
+            .0
+            .checked_mul(percent / Decimal::ONE_HUNDRED)
+            .unwrap_or_default();
+        Amount(share.round_dp_with_strategy(AMOUNT_SCALE, RoundingStrategy::ToZero))
+    }
+
     /// Add with overflow check.
     pub(crate) fn checked_add(self, other: Amount) -> Option<Amount> {
         self.0.checked_add(other.0).map(Amount)
@@ -130,6 +482,16 @@ impl Amount {
         self.0.checked_sub(other.0).map(Amount)
         //        }
     }
+
+    /// Add, saturating at the largest representable amount. Only used for reporting.
+    pub(crate) fn saturating_add(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_add(other.0))
+    }
+
+    /// Subtract, saturating at the smallest representable amount. Only used for reporting.
+    pub(crate) fn saturating_sub(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_sub(other.0))
+    }
 }
 
 impl From<Decimal> for Amount {
@@ -138,8 +500,41 @@ impl From<Decimal> for Amount {
     }
 }
 
+impl From<Amount> for Decimal {
+    fn from(value: Amount) -> Self {
+        value.0
+    }
+}
+
+/// A transaction built in code by a program that embeds the engine. The builder already checked its shape.
+impl From<NewTransaction> for Transaction {
+    fn from(built: NewTransaction) -> Self {
+        let account_name = |name: Option<&str>| {
+            name.map_or_else(AccountName::default, |name| {
+                name.parse()
+                    .expect("The builder checked the sub-account names.")
+            })
+        };
+        Transaction {
+            transaction_type: built.transaction_type(),
+            client: built.client().into(),
+            tx: built.id().into(),
+            amount: built.amount().map(Amount::from),
+            account: account_name(built.account()),
+            to_account: built.to_account().map(|name| account_name(Some(name))),
+            release_to: built.release_to(),
+            source: built.source(),
+            currency: None,
+            extensions: Extensions::default(),
+            line: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     impl Transaction {
@@ -154,8 +549,36 @@ mod tests {
                 client,
                 tx,
                 amount,
+                account: AccountName::default(),
+                to_account: None,
+                release_to: None,
+                source: None,
+                currency: None,
+                extensions: Extensions::default(),
+                line: None,
             }
         }
+
+        pub(crate) fn with_currency(mut self, currency: Option<&str>) -> Self {
+            self.currency = currency.map(str::to_string);
+            self
+        }
+
+        pub(crate) fn with_source(mut self, source: DisputeSource) -> Self {
+            self.source = Some(source);
+            self
+        }
+
+        pub(crate) fn with_release_to(mut self, party: EscrowParty) -> Self {
+            self.release_to = Some(party);
+            self
+        }
+
+        pub(crate) fn with_accounts(mut self, account: &str, to_account: Option<&str>) -> Self {
+            self.account = account.parse().unwrap();
+            self.to_account = to_account.map(|name| name.parse().unwrap());
+            self
+        }
     }
 
     impl Amount {
@@ -171,6 +594,133 @@ mod tests {
         }
     }
 
+    // The fields that are written, to compare transactions.
+    type Fields = (
+        TransactionType,
+        ClientId,
+        TransactionId,
+        Option<Amount>,
+        AccountName,
+        Option<AccountName>,
+        Option<EscrowParty>,
+        Option<DisputeSource>,
+        Option<String>,
+    );
+
+    fn fields(transaction: &Transaction) -> Fields {
+        (
+            transaction.transaction_type,
+            transaction.client,
+            transaction.tx,
+            transaction.amount,
+            transaction.account.clone(),
+            transaction.to_account.clone(),
+            transaction.release_to,
+            transaction.source,
+            transaction.currency.clone(),
+        )
+    }
+
+    fn any_transaction_type() -> impl Strategy<Value = TransactionType> {
+        prop_oneof![
+            Just(TransactionType::Deposit),
+            Just(TransactionType::Withdrawal),
+            Just(TransactionType::Dispute),
+            Just(TransactionType::Resolve),
+            Just(TransactionType::Chargeback),
+            Just(TransactionType::Move),
+            Just(TransactionType::EscrowHold),
+            Just(TransactionType::EscrowRelease),
+            Just(TransactionType::ChargebackReversal),
+        ]
+    }
+
+    fn any_transaction() -> impl Strategy<Value = Transaction> {
+        (
+            any_transaction_type(),
+            any::<u16>(),
+            any::<u32>(),
+            proptest::option::of((0..=i64::MAX).prop_map(|units| Amount(Decimal::new(units, 4)))),
+            "[a-z0-9_-]{1,32}",
+            proptest::option::of("[a-zA-Z0-9_-]{1,32}"),
+            proptest::option::of(prop_oneof![
+                Just(EscrowParty::Client),
+                Just(EscrowParty::Beneficiary),
+            ]),
+            proptest::option::of(prop_oneof![
+                Just(DisputeSource::Issuer),
+                Just(DisputeSource::Internal),
+                Just(DisputeSource::Partner),
+            ]),
+            proptest::option::of("[A-Z]{3}"),
+        )
+            .prop_map(
+                |(
+                    transaction_type,
+                    client,
+                    tx,
+                    amount,
+                    account,
+                    to_account,
+                    release_to,
+                    source,
+                    currency,
+                )| {
+                    let mut transaction =
+                        Transaction::new(transaction_type, client.into(), tx.into(), amount)
+                            .with_accounts(&account, to_account.as_deref())
+                            .with_currency(currency.as_deref());
+                    transaction.release_to = release_to;
+                    transaction.source = source;
+                    transaction
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn should_read_back_written_transactions(
+            transactions in proptest::collection::vec(any_transaction(), 1..16),
+        ) {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for transaction in &transactions {
+                writer.serialize(transaction).unwrap();
+            }
+            let written = writer.into_inner().unwrap();
+            let read: Vec<Transaction> = csv::Reader::from_reader(written.as_slice())
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            prop_assert_eq!(
+                read.iter().map(fields).collect::<Vec<_>>(),
+                transactions.iter().map(fields).collect::<Vec<_>>()
+            );
+
+            for transaction in &transactions {
+                let encoded = json::to_vec(transaction).unwrap();
+                let decoded: Transaction = json::from_slice(&encoded).unwrap();
+                prop_assert_eq!(fields(&decoded), fields(transaction));
+            }
+        }
+    }
+
+    #[test]
+    fn should_convert_built_transactions() {
+        use crate::transaction::TransactionBuilder;
+
+        let built = TransactionBuilder::new(TransactionType::Move, 1, 7)
+            .with_amount(Decimal::new(25, 1))
+            .with_account("savings")
+            .with_to_account("main")
+            .build()
+            .unwrap();
+        let transaction = Transaction::from(built);
+
+        assert_eq!(transaction.amount(), Some(2.5.into()));
+        assert_eq!(transaction.account().to_string(), "savings");
+        assert_eq!(transaction.to_account(), Some(&AccountName::default()));
+    }
+
     #[test]
     fn amount_add_overflow_not_allowed() {
         let a: Amount = Decimal::MAX.into();
@@ -194,4 +744,90 @@ mod tests {
 
         assert_eq!(a.checked_sub(b), Some(8.5.into()))
     }
+
+    #[test]
+    fn normalized_format_removes_trailing_zeros() {
+        let format = AmountFormat::Normalized;
+
+        assert_eq!(format.apply(Decimal::new(10000, 4)).to_string(), "1");
+        assert_eq!(format.apply(Decimal::new(15000, 4)).to_string(), "1.5");
+    }
+
+    #[test]
+    fn fixed_format_pads_to_scale() {
+        let format = AmountFormat::FixedScale(4);
+
+        assert_eq!(format.apply(Decimal::new(1, 0)).to_string(), "1.0000");
+        assert_eq!(format.apply(Decimal::new(-25, 1)).to_string(), "-2.5000");
+        assert_eq!(format.apply(Decimal::new(12345, 4)).to_string(), "1.2345");
+    }
+
+    #[test]
+    fn fixed_format_rounds_to_scale() {
+        let format = AmountFormat::FixedScale(2);
+
+        assert_eq!(format.apply(Decimal::new(12345, 4)).to_string(), "1.23");
+        assert_eq!(format.apply(Decimal::new(12355, 4)).to_string(), "1.24");
+        assert_eq!(
+            AmountFormat::FixedScale(0)
+                .apply(Decimal::new(15, 1))
+                .to_string(),
+            "2"
+        );
+    }
+
+    #[test]
+    fn should_only_apply_the_output_format_to_outputs() {
+        let amount: Amount = 4.938.into();
+        let output = AmountFormat::FixedScale(2)
+            .scoped(|| serde_json::to_string(&amount))
+            .unwrap();
+        assert_eq!(output, "\"4.94\"");
+
+        // E.g. a transaction spilled to the store while the outputs are rounded.
+        let config = bincode::config::standard();
+        let stored = bincode::serde::encode_to_vec(amount, config).unwrap();
+        let (restored, _): (Amount, _) =
+            bincode::serde::decode_from_slice(&stored, config).unwrap();
+        assert_eq!(restored, amount);
+    }
+
+    #[test]
+    fn amount_parsing_rounds_to_four_decimal_places() {
+        assert_eq!("1.99999".parse::<Amount>(), Ok(1.9999.into()));
+        assert_eq!("0.0001".parse::<Amount>(), Ok(0.0001.into()));
+        assert_eq!("0".parse::<Amount>(), Ok(Amount::zero()));
+    }
+
+    #[test]
+    fn amount_parsing_rejects_scientific_notation() {
+        assert_eq!(
+            "1e10".parse::<Amount>(),
+            Err(AmountError::ScientificNotation)
+        );
+        assert_eq!(
+            "1.5E-3".parse::<Amount>(),
+            Err(AmountError::ScientificNotation)
+        );
+    }
+
+    #[test]
+    fn amount_parsing_rejects_amounts_below_precision() {
+        assert_eq!(
+            "0.00001".parse::<Amount>(),
+            Err(AmountError::BelowPrecision)
+        );
+    }
+
+    #[test]
+    fn amount_parsing_rejects_extreme_values() {
+        assert_eq!(
+            "100000000000000000000000000000".parse::<Amount>(),
+            Err(AmountError::OutOfRange)
+        );
+        assert_eq!("-1".parse::<Amount>(), Err(AmountError::Negative));
+        assert_eq!("1.2.3".parse::<Amount>(), Err(AmountError::Invalid));
+        assert_eq!("".parse::<Amount>(), Err(AmountError::Invalid));
+        assert_eq!(".".parse::<Amount>(), Err(AmountError::Invalid));
+    }
 }
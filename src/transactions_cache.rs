@@ -1,4 +1,9 @@
-use std::{num::NonZeroUsize, path::Path};
+use std::{
+    cell::Cell,
+    num::NonZeroUsize,
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use lru::LruCache;
 #[cfg(feature = "rocksdb")]
@@ -13,6 +18,24 @@ use std::hash::Hash;
 use tempfile::{TempDir, tempdir};
 use thiserror::Error;
 
+thread_local! {
+    static STORE_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// The total time the current thread spent in backing store calls. Used for profiling: the difference between two
+/// readings around a synchronous operation is the time that operation spent in the backing store.
+pub fn store_time() -> Duration {
+    STORE_TIME.with(Cell::get)
+}
+
+// Run a backing store call and add its duration to the store time of the thread.
+fn timed<T>(call: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = call();
+    STORE_TIME.with(|time| time.set(time.get() + started.elapsed()));
+    result
+}
+
 #[derive(Debug, Error)]
 pub enum BackingStoreError {
     #[error("Can't create backing store.")]
@@ -37,6 +60,12 @@ pub trait BackingStore {
 
     /// Check if the database has the key.
     fn contains_key(&self, key: &[u8]) -> Result<bool, BackingStoreError>;
+
+    /// Remove a value from the database. Removing a missing key is not an error.
+    fn delete(&self, key: &[u8]) -> Result<(), BackingStoreError>;
+
+    /// Write a consistent copy of the database to a path that doesn't exist yet.
+    fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(), BackingStoreError>;
 }
 
 /// A simple key-value store using Sqlite.
@@ -96,6 +125,24 @@ impl BackingStore for SqliteKvStore {
         stmt.exists(params![key])
             .map_err(|e| BackingStoreError::InternalError(e.to_string()))
     }
+
+    fn delete(&self, key: &[u8]) -> Result<(), BackingStoreError> {
+        self.conn
+            .execute("DELETE FROM kv WHERE key = ?1", params![key])
+            .map_err(|e| BackingStoreError::InternalError(e.to_string()))?;
+        Ok(())
+    }
+
+    // `VACUUM INTO` copies the database in a single read transaction, including what's still in the WAL.
+    fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(), BackingStoreError> {
+        let path = path.as_ref().to_str().ok_or_else(|| {
+            BackingStoreError::InternalError("The backup path is not valid UTF-8.".to_string())
+        })?;
+        self.conn
+            .execute("VACUUM INTO ?1", params![path])
+            .map_err(|e| BackingStoreError::InternalError(e.to_string()))?;
+        Ok(())
+    }
 }
 
 use rusqlite::{Connection, OptionalExtension, params};
@@ -152,6 +199,23 @@ impl BackingStore for RocksDbStore {
             None => Ok(false),
         }
     }
+
+    fn delete(&self, key: &[u8]) -> Result<(), BackingStoreError> {
+        self.db
+            .delete(key)
+            .map_err(|e| BackingStoreError::InternalError(e.to_string()))
+    }
+
+    // The backup engine flushes the memtable and copies the SST files into a backup directory.
+    fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(), BackingStoreError> {
+        let internal = |e: rocksdb::Error| BackingStoreError::InternalError(e.to_string());
+        let options = backup::BackupEngineOptions::new(path).map_err(internal)?;
+        let env = rocksdb::Env::new().map_err(internal)?;
+        let mut engine = backup::BackupEngine::open(&options, &env).map_err(internal)?;
+        engine
+            .create_new_backup_flush(&self.db, true)
+            .map_err(internal)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -186,7 +250,7 @@ pub struct TransactionCache<
     /// Database where transactions are evicted when memory cache gets full.
     db: S,
     /// We need to hold on to the temporary directory for as long as the cache is active.
-    _db_dir: TempDir,
+    _db_dir: Option<TempDir>,
 }
 
 #[cfg(feature = "rocksdb")]
@@ -203,7 +267,7 @@ impl<'de, K: Hash + Eq + Serialize + Copy, V: Serialize + DeserializeOwned, cons
         Ok(Self {
             cache,
             db,
-            _db_dir: db_dir,
+            _db_dir: Some(db_dir),
         })
     }
 }
@@ -216,7 +280,7 @@ impl<K: Hash + Eq + Serialize + Copy, V: Serialize + DeserializeOwned, const CAP
 
         let cache = LruCache::new(NonZeroUsize::new(CAP).ok_or(CacheError::InvalidCapacity)?);
         let db_dir = tempdir()?;
-        let sqlite = SqliteKvStore::new(format!("{}/my_db.db", db_dir.path().to_str().unwrap()))?;
+        let sqlite = SqliteKvStore::new(db_dir.path().join("my_db.db"))?;
 
         /*
         let db = sled::Config::default()
@@ -228,7 +292,7 @@ impl<K: Hash + Eq + Serialize + Copy, V: Serialize + DeserializeOwned, const CAP
         Ok(Self {
             cache,
             db: sqlite,
-            _db_dir: db_dir,
+            _db_dir: Some(db_dir),
         })
     }
 }
@@ -240,6 +304,17 @@ impl<
     const CAP: usize,
 > TransactionCache<S, K, V, CAP>
 {
+    /// Create a cache on top of an already opened backing store.
+    pub fn with_store(db: S) -> Result<Self, CacheError> {
+        let cache = LruCache::new(NonZeroUsize::new(CAP).ok_or(CacheError::InvalidCapacity)?);
+
+        Ok(Self {
+            cache,
+            db,
+            _db_dir: None,
+        })
+    }
+
     /// Put a value in the cache. If the cache is full, the least recently used object will be evicted to the disk DB.
     pub fn put(&mut self, tx_id: K, entry: V) -> Result<(), CacheError> {
         // transaction already in cache; only need to update and promote its usage
@@ -251,15 +326,17 @@ impl<
         // cache is already full, the transaction is not in the cache so this put will evict the least recently used value.
         // we want to make sure the entry is evicted on disk rather than lost.
         // TOOD: as an improvement it probably would make more sense to evict more objects to disk instead of just one.
-        if self.cache.len() == CAP {
-            if let Some((tx_id_to_evict, entry_to_evict)) = self.cache.pop_lru() {
-                let id_to_evict_bytes =
-                    bincode::serde::encode_to_vec(tx_id_to_evict, bincode::config::standard())?;
-                let entry_to_evict_bytes =
-                    bincode::serde::encode_to_vec(&entry_to_evict, bincode::config::standard())?;
-                self.db.put(&id_to_evict_bytes, &entry_to_evict_bytes)?;
-                //self.db.flush()?;
-            }
+        // the entry is removed from memory only once it's safely on disk so that a failed write doesn't lose it.
+        if self.cache.len() == CAP
+            && let Some((tx_id_to_evict, entry_to_evict)) = self.cache.peek_lru()
+        {
+            let id_to_evict_bytes =
+                bincode::serde::encode_to_vec(tx_id_to_evict, bincode::config::standard())?;
+            let entry_to_evict_bytes =
+                bincode::serde::encode_to_vec(entry_to_evict, bincode::config::standard())?;
+            timed(|| self.db.put(&id_to_evict_bytes, &entry_to_evict_bytes))?;
+            //self.db.flush()?;
+            self.cache.pop_lru();
         }
 
         // the old item was evicted so there is room for the new one now.
@@ -277,17 +354,63 @@ impl<
         // the transaction is not in the cache. It's either on disk or doesn't exist. Check the db first.
         let tx_id_bytes = bincode::serde::encode_to_vec(tx_id, bincode::config::standard())?;
 
-        if let Ok(Some(entry_bytes)) = self.db.get(&tx_id_bytes) {
-            let (entry, _): (V, usize) =
-                bincode::serde::decode_from_slice(&entry_bytes, bincode::config::standard())?;
-            self.put(*tx_id, entry)?;
-            Ok(self.cache.get_mut(tx_id))
-        } else {
+        match timed(|| self.db.get(&tx_id_bytes))? {
+            Some(entry_bytes) => {
+                let (entry, _): (V, usize) =
+                    bincode::serde::decode_from_slice(&entry_bytes, bincode::config::standard())?;
+                self.put(*tx_id, entry)?;
+                Ok(self.cache.get_mut(tx_id))
+            }
             // not in the db. Return None.
-            Ok(None)
+            None => Ok(None),
         }
     }
 
+    /// Remove a value from the cache and from the disk database. Returns the removed value, if there was one.
+    /// The value is removed from memory only once it was deleted from the disk database.
+    pub fn remove(&mut self, tx_id: &K) -> Result<Option<V>, CacheError> {
+        let tx_id_bytes = bincode::serde::encode_to_vec(tx_id, bincode::config::standard())?;
+        if self.cache.contains(tx_id) {
+            timed(|| self.db.delete(&tx_id_bytes))?;
+            return Ok(self.cache.pop(tx_id));
+        }
+
+        match timed(|| self.db.get(&tx_id_bytes))? {
+            Some(entry_bytes) => {
+                let (entry, _): (V, usize) =
+                    bincode::serde::decode_from_slice(&entry_bytes, bincode::config::standard())?;
+                timed(|| self.db.delete(&tx_id_bytes))?;
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Check that the disk database can be written to by writing and deleting a probe entry.
+    /// The probe key is empty, which no encoded key is, so it never overwrites an entry.
+    pub fn check_writable(&self) -> Result<(), CacheError> {
+        timed(|| self.db.put(&[], &[]))?;
+        timed(|| self.db.delete(&[]))?;
+        Ok(())
+    }
+
+    /// Check if an entry is in memory, i.e. if it can be read without going to the disk database. Doesn't change the
+    /// usage of the entry.
+    pub fn is_in_memory(&self, tx_id: &K) -> bool {
+        self.cache.contains(tx_id)
+    }
+
+    /// Write a copy of all the entries to a path that doesn't exist yet. The entries that are only in memory are
+    /// written to the disk database first, which doesn't change what the cache reads since memory is checked first.
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(), CacheError> {
+        for (tx_id, entry) in self.cache.iter() {
+            let tx_id_bytes = bincode::serde::encode_to_vec(tx_id, bincode::config::standard())?;
+            let entry_bytes = bincode::serde::encode_to_vec(entry, bincode::config::standard())?;
+            timed(|| self.db.put(&tx_id_bytes, &entry_bytes))?;
+        }
+        Ok(timed(|| self.db.backup(path))?)
+    }
+
     // Check if there's an entry in the cache.
     pub fn contains_key(&self, tx_id: &K) -> Result<bool, CacheError> {
         if self.cache.contains(tx_id) {
@@ -295,7 +418,7 @@ impl<
         }
 
         let tx_id_bytes = bincode::serde::encode_to_vec(tx_id, bincode::config::standard())?;
-        Ok(self.db.contains_key(&tx_id_bytes)?)
+        Ok(timed(|| self.db.contains_key(&tx_id_bytes))?)
     }
 }
 
@@ -315,12 +438,15 @@ mod tests {
     #[test]
     fn should_evict_entries() {
         let mut cache = TransactionCache::<SqliteKvStore, u16, u32, 16>::new().unwrap();
+        let before = store_time();
 
         for i in 0..128 {
             cache.put(i, i as u32).unwrap();
         }
 
         assert_eq!(cache.cache.len(), 16);
+        // The evictions are accounted to the store time of the thread.
+        assert!(store_time() > before);
 
         for i in 112..=127 {
             assert!(cache.cache.get(&i).is_some());
@@ -331,6 +457,72 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_remove_entries_from_memory_and_disk() {
+        let mut cache = TransactionCache::<SqliteKvStore, u16, u32, 16>::new().unwrap();
+
+        for i in 0..32 {
+            cache.put(i, i as u32).unwrap();
+        }
+
+        // 0 was evicted to disk and 31 is still in memory.
+        assert_eq!(cache.remove(&0).unwrap(), Some(0));
+        assert_eq!(cache.remove(&31).unwrap(), Some(31));
+        assert_eq!(cache.remove(&31).unwrap(), None);
+        assert!(!cache.contains_key(&0).unwrap());
+        assert!(!cache.contains_key(&31).unwrap());
+        assert_eq!(*cache.get(&1).unwrap().unwrap(), 1);
+    }
+
+    #[test]
+    fn should_tell_which_entries_are_in_memory() {
+        let mut cache = TransactionCache::<SqliteKvStore, u16, u32, 2>::new().unwrap();
+        for i in 0..3 {
+            cache.put(i, i as u32).unwrap();
+        }
+
+        assert!(!cache.is_in_memory(&0));
+        assert!(cache.is_in_memory(&2));
+        assert!(!cache.is_in_memory(&3));
+
+        // Reading an evicted entry loads it back into memory.
+        cache.get(&0).unwrap();
+        assert!(cache.is_in_memory(&0));
+    }
+
+    #[test]
+    fn should_check_that_the_store_is_writable() {
+        let mut cache = TransactionCache::<SqliteKvStore, u16, u32, 1>::new().unwrap();
+        cache.put(0, 0).unwrap();
+        cache.put(1, 1).unwrap();
+
+        cache.check_writable().unwrap();
+
+        // The entry evicted to disk is untouched by the probe.
+        assert_eq!(*cache.get(&0).unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    fn should_back_up_the_entries_in_memory_and_on_disk() {
+        let mut cache = TransactionCache::<SqliteKvStore, u16, u32, 4>::new().unwrap();
+        for i in 0..8 {
+            cache.put(i, i as u32).unwrap();
+        }
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("backup.db");
+
+        cache.backup(&path).unwrap();
+
+        let mut restored =
+            TransactionCache::<_, u16, u32, 4>::with_store(SqliteKvStore::new(&path).unwrap())
+                .unwrap();
+        for i in 0..8 {
+            assert_eq!(*restored.get(&i).unwrap().unwrap(), i as u32);
+        }
+        // The path of a backup has to be new.
+        assert!(cache.backup(&path).is_err());
+    }
+
     #[test]
     fn should_read_evicted_entries() {
         let mut cache = TransactionCache::<SqliteKvStore, u16, u32, 16>::new().unwrap();
@@ -0,0 +1,505 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    path::Path,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    csv_reader::{CsvFileReader, ReaderError},
+    pipeline::Parser,
+    transaction_types::{
+        AccountName, Amount, ClientId, DisputeSource, EscrowParty, Transaction, TransactionId,
+        TransactionType, deserialize_balance,
+    },
+};
+
+// An independent check of the output of a run for release qualification. The balances are recomputed from the input
+// by a reference implementation that is kept as simple as possible: a single thread, every account in memory and
+// none of the caches, stores, workers and pipelines of the engine. The reference applies the rules of a run with the
+// default options, so only the outputs of such runs can be verified. Nothing is written, the differences are reported.
+
+#[derive(Debug, Error)]
+pub(crate) enum VerifyError {
+    #[error(transparent)]
+    Input(#[from] ReaderError),
+    #[error("Cannot read the account output: {0}")]
+    Output(#[from] csv::Error),
+    #[error("Account {1} of client {0} appears more than once in the account output.")]
+    DuplicateAccount(ClientId, AccountName),
+}
+
+// A row of the account output. The escrow column is only compared if the output has it.
+#[derive(Debug, Deserialize)]
+struct OutputRecord {
+    client: ClientId,
+    #[serde(default)]
+    account: AccountName,
+    #[serde(deserialize_with = "deserialize_balance")]
+    available: Amount,
+    #[serde(deserialize_with = "deserialize_balance")]
+    held: Amount,
+    #[serde(default, deserialize_with = "deserialize_optional_balance")]
+    escrow: Option<Amount>,
+    #[serde(deserialize_with = "deserialize_balance")]
+    total: Amount,
+    locked: bool,
+}
+
+fn deserialize_optional_balance<'de, D>(deserializer: D) -> Result<Option<Amount>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_balance(deserializer).map(Some)
+}
+
+// Where a deposit is in its disputes, with the source that opened the dispute. Resolved and charged back deposits
+// can't be disputed again, and a chargeback can only be reversed once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DepositState {
+    Settled,
+    Disputed(Option<DisputeSource>),
+    ChargedBack(Option<DisputeSource>),
+    Closed,
+}
+
+// A transaction in the log of an account. Only deposits and escrow holds can be referenced by later transactions.
+#[derive(Debug)]
+enum Logged {
+    Deposit(Amount, DepositState),
+    Escrow(Amount, bool),
+    Other,
+}
+
+#[derive(Debug, Default)]
+struct ReferenceAccount {
+    held: Amount,
+    escrow: Amount,
+    total: Amount,
+    locked: bool,
+    log: HashMap<TransactionId, Logged>,
+}
+
+impl ReferenceAccount {
+    fn available(&self) -> Option<Amount> {
+        self.total.checked_sub(self.held)?.checked_sub(self.escrow)
+    }
+
+    // Take funds out of the available funds of the account, if it has enough.
+    fn debit(&self, amount: Amount) -> Option<Amount> {
+        if self.available()? < amount {
+            return None;
+        }
+        self.total.checked_sub(amount)
+    }
+
+    // Apply a transaction that passed validation. Nothing changes if it's rejected.
+    fn apply(&mut self, transaction: &Transaction) -> Option<()> {
+        // Chargeback reversals are applied to locked accounts, since the chargeback locked them.
+        if self.locked && transaction.transaction_type() != TransactionType::ChargebackReversal {
+            return None;
+        }
+        let id = transaction.id();
+        let amount = transaction.amount();
+        match transaction.transaction_type() {
+            TransactionType::Deposit if !self.log.contains_key(&id) => {
+                let amount = amount?;
+                self.total = self.total.checked_add(amount)?;
+                self.log
+                    .insert(id, Logged::Deposit(amount, DepositState::Settled));
+            }
+            TransactionType::Withdrawal if !self.log.contains_key(&id) => {
+                self.total = self.debit(amount?)?;
+                self.log.insert(id, Logged::Other);
+            }
+            TransactionType::EscrowHold if !self.log.contains_key(&id) => {
+                let amount = amount?;
+                self.debit(amount)?;
+                self.escrow = self.escrow.checked_add(amount)?;
+                self.log.insert(id, Logged::Escrow(amount, false));
+            }
+            TransactionType::Dispute => {
+                let Some(Logged::Deposit(amount, state @ DepositState::Settled)) =
+                    self.log.get_mut(&id)
+                else {
+                    return None;
+                };
+                self.held = self.held.checked_add(*amount)?;
+                *state = DepositState::Disputed(transaction.source());
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                let Some(Logged::Deposit(amount, state)) = self.log.get_mut(&id) else {
+                    return None;
+                };
+                let DepositState::Disputed(opened) = *state else {
+                    return None;
+                };
+                if !same_source(opened, transaction.source()) {
+                    return None;
+                }
+                self.held = self.held.checked_sub(*amount)?;
+                *state = if transaction.transaction_type() == TransactionType::Chargeback {
+                    self.total = self.total.checked_sub(*amount)?;
+                    self.locked = true;
+                    DepositState::ChargedBack(opened)
+                } else {
+                    DepositState::Closed
+                };
+            }
+            TransactionType::ChargebackReversal => {
+                let Some(Logged::Deposit(amount, state)) = self.log.get_mut(&id) else {
+                    return None;
+                };
+                let DepositState::ChargedBack(opened) = *state else {
+                    return None;
+                };
+                if !same_source(opened, transaction.source()) {
+                    return None;
+                }
+                self.total = self.total.checked_add(*amount)?;
+                *state = DepositState::Closed;
+            }
+            TransactionType::EscrowRelease => {
+                let party = transaction.release_to()?;
+                let Some(Logged::Escrow(amount, released @ false)) = self.log.get_mut(&id) else {
+                    return None;
+                };
+                self.escrow = self.escrow.checked_sub(*amount)?;
+                if party == EscrowParty::Beneficiary {
+                    self.total = self.total.checked_sub(*amount)?;
+                }
+                *released = true;
+            }
+            // Duplicates of funding transactions and moves, which involve two accounts.
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+// Whether a dispute can be closed by a source: only the source that opened it can, if both are known.
+fn same_source(opened: Option<DisputeSource>, closing: Option<DisputeSource>) -> bool {
+    !matches!((opened, closing), (Some(opened), Some(closing)) if opened != closing)
+}
+
+// The reference implementation.
+#[derive(Debug, Default)]
+struct Reference {
+    accounts: HashMap<(ClientId, AccountName), ReferenceAccount>,
+}
+
+impl Reference {
+    // The checks of the default validators, which reject a transaction before any account is created for it.
+    fn is_valid(&self, transaction: &Transaction) -> bool {
+        let funding = transaction.transaction_type().is_funding();
+        match transaction.amount() {
+            Some(amount) => funding && !amount.is_zero(),
+            None => !funding,
+        }
+    }
+
+    fn apply(&mut self, transaction: &Transaction) {
+        if !self.is_valid(transaction) {
+            return;
+        }
+        let source = (transaction.client(), transaction.account().clone());
+        // The ids of the funding transactions are unique across the sub-accounts of a client.
+        let reused = self.accounts.iter().any(|(key, account)| {
+            key.0 == source.0 && key.1 != source.1 && account.log.contains_key(&transaction.id())
+        });
+        if transaction.transaction_type().is_funding() && reused {
+            return;
+        }
+        if transaction.transaction_type() == TransactionType::Move {
+            self.apply_move(source, transaction);
+        } else {
+            self.accounts.entry(source).or_default().apply(transaction);
+        }
+    }
+
+    // The sub-accounts of a move are only created once it succeeded, as the engine does.
+    fn apply_move(&mut self, source: (ClientId, AccountName), transaction: &Transaction) {
+        let destination = match transaction.to_account() {
+            Some(name) if *name != source.1 => (source.0, name.clone()),
+            _ => return,
+        };
+        let mut created = ReferenceAccount::default();
+        let (from, to) = match self.accounts.get_disjoint_mut([&source, &destination]) {
+            [Some(from), Some(to)] => (from, to),
+            [Some(from), None] => (from, &mut created),
+            [None, _] => return,
+        };
+        let id = transaction.id();
+        let Some(amount) = transaction.amount() else {
+            return;
+        };
+        if from.locked || to.locked || from.log.contains_key(&id) {
+            return;
+        }
+        if let Some(total) = from.debit(amount)
+            && let Some(destination_total) = to.total.checked_add(amount)
+        {
+            from.total = total;
+            to.total = destination_total;
+            from.log.insert(id, Logged::Other);
+            self.accounts.entry(destination).or_insert(created);
+        }
+    }
+}
+
+/// A difference between the account output and the recomputed balances.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Difference {
+    /// The account was recomputed but is not in the output.
+    Missing(ClientId, AccountName),
+    /// The account is in the output but has no transactions in the input.
+    Unexpected(ClientId, AccountName),
+    Balance {
+        client: ClientId,
+        account: AccountName,
+        column: &'static str,
+        output: String,
+        recomputed: String,
+    },
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Missing(client, account) => write!(
+                f,
+                "Account {} of client {} is missing from the output.",
+                account, client
+            ),
+            Difference::Unexpected(client, account) => write!(
+                f,
+                "Account {} of client {} is in the output but not in the input.",
+                account, client
+            ),
+            Difference::Balance {
+                client,
+                account,
+                column,
+                output,
+                recomputed,
+            } => write!(
+                f,
+                "Account {} of client {}: {} is {} in the output but {} when recomputed.",
+                account, client, column, output, recomputed
+            ),
+        }
+    }
+}
+
+/// The outcome of a verification.
+#[derive(Debug, Default)]
+pub(crate) struct Verification {
+    /// The accounts that were recomputed.
+    pub(crate) accounts: usize,
+    pub(crate) differences: Vec<Difference>,
+}
+
+impl Verification {
+    pub(crate) fn matches(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for difference in &self.differences {
+            writeln!(f, "{}", difference)?;
+        }
+        if self.matches() {
+            write!(
+                f,
+                "Verified {} accounts: the output matches the recomputed balances.",
+                self.accounts
+            )
+        } else {
+            write!(
+                f,
+                "Verified {} accounts: {} differences with the recomputed balances.",
+                self.accounts,
+                self.differences.len()
+            )
+        }
+    }
+}
+
+/// Recompute the balances of the accounts from an input file and compare them with the account output of a run.
+pub(crate) fn verify<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+) -> Result<Verification, VerifyError> {
+    let mut reference = Reference::default();
+    let mut reader = CsvFileReader::from_path_with_encoding(input, None)?;
+    // Records that can't be parsed are rejected by the engine too.
+    for transaction in reader.transactions().flatten() {
+        reference.apply(&transaction);
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_path(output)?;
+    let mut outputs = BTreeMap::new();
+    for record in reader.deserialize::<OutputRecord>() {
+        let record = record?;
+        let key = (record.client, record.account.clone());
+        if outputs.contains_key(&key) {
+            return Err(VerifyError::DuplicateAccount(key.0, key.1));
+        }
+        outputs.insert(key, record);
+    }
+
+    let mut verification = Verification {
+        accounts: reference.accounts.len(),
+        differences: Vec::new(),
+    };
+    let keys: BTreeSet<_> = reference.accounts.keys().chain(outputs.keys()).collect();
+    for key in keys {
+        let (client, account) = key.clone();
+        let (recomputed, output) = match (reference.accounts.get(key), outputs.get(key)) {
+            (Some(recomputed), Some(output)) => (recomputed, output),
+            (Some(_), None) => {
+                verification
+                    .differences
+                    .push(Difference::Missing(client, account));
+                continue;
+            }
+            (None, _) => {
+                verification
+                    .differences
+                    .push(Difference::Unexpected(client, account));
+                continue;
+            }
+        };
+        let balances = [
+            ("available", Some(output.available), recomputed.available()),
+            ("held", Some(output.held), Some(recomputed.held)),
+            ("escrow", output.escrow, Some(recomputed.escrow)),
+            ("total", Some(output.total), Some(recomputed.total)),
+        ];
+        for (column, output, recomputed) in balances {
+            // Columns that are not in the output are not compared.
+            let Some(output) = output else {
+                continue;
+            };
+            if Some(output) != recomputed {
+                verification.differences.push(Difference::Balance {
+                    client,
+                    account: account.clone(),
+                    column,
+                    output: output.to_string(),
+                    recomputed: recomputed
+                        .map_or_else(|| "out of range".to_string(), |amount| amount.to_string()),
+                });
+            }
+        }
+        if output.locked != recomputed.locked {
+            verification.differences.push(Difference::Balance {
+                client,
+                account,
+                column: "locked",
+                output: output.locked.to_string(),
+                recomputed: recomputed.locked.to_string(),
+            });
+        }
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    const INPUT: &str = "type,client,tx,amount,account,to_account,release_to
+deposit,1,1,10,,,
+deposit,1,2,5,,,
+dispute,1,2,,,,
+withdrawal,1,3,20,,,
+move,1,4,3,,savings,
+deposit,1,1,3,savings,,
+escrow_hold,1,5,2,,,
+escrow_release,1,5,,,,beneficiary
+deposit,2,6,7,,,
+dispute,2,6,,,,
+chargeback,2,6,,,,
+deposit,2,7,1,,,
+deposit,3,4000000001,1,,,
+dispute,4,1,,,,
+move,5,8,1,,,
+deposit,6,9,4,,,
+dispute,6,9,,,,
+chargeback,6,9,,,,
+chargeback_reversal,6,9,,,,
+chargeback_reversal,6,9,,,,
+";
+
+    #[test]
+    fn should_match_the_balances_of_the_engine() {
+        let input = file(INPUT);
+        let output = file(
+            "client,account,available,held,escrow,total,locked
+1,main,5,5,0,10,false
+1,savings,3,0,0,3,false
+2,main,0,0,0,0,true
+3,main,1,0,0,1,false
+4,main,0,0,0,0,false
+6,main,4,0,0,4,true
+",
+        );
+
+        let verification = verify(input.path(), output.path()).unwrap();
+
+        assert_eq!(verification.differences, vec![]);
+        assert_eq!(verification.accounts, 6);
+    }
+
+    #[test]
+    fn should_report_the_differences_with_the_output() {
+        let input = file(INPUT);
+        let output = file(
+            "client,account,available,held,total,locked
+1,main,5,0,10,false
+2,main,0,0,0,true
+3,main,1,0,1,false
+4,main,0,0,0,false
+6,main,4,0,4,true
+7,main,1,0,1,false
+",
+        );
+
+        let verification = verify(input.path(), output.path()).unwrap();
+
+        assert!(!verification.matches());
+        assert_eq!(
+            verification.differences,
+            vec![
+                Difference::Balance {
+                    client: 1.into(),
+                    account: AccountName::default(),
+                    column: "held",
+                    output: "0".to_string(),
+                    recomputed: "5".to_string(),
+                },
+                Difference::Missing(1.into(), "savings".parse().unwrap()),
+                Difference::Unexpected(7.into(), AccountName::default()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures_util::future::join_all;
+use tokio::sync::watch;
+
+use crate::{
+    account::AccountSnapshot,
+    csv_reader::CsvFileReader,
+    engine::ShardedEngine,
+    ingest::{self, ReaderOptions, SourceHandle},
+    logging::log_event,
+    output::{AccountFilter, AccountWriter, OutputColumns, OutputSchema},
+    profiling::Profiler,
+    provenance,
+    transaction_types::ClientId,
+};
+
+// Ingestion of the CSV files that are dropped into a directory, so the operators can drop the files of the branches
+// into a folder instead of scheduling runs. The directory is watched by the daemon, or on its own until Ctrl-C is
+// received, and the outputs are then written as for a file.
+//
+// The accounts can also be written to a report file that is rewritten once the transactions of every batch of files
+// were applied, so the report follows the files as they are dropped. The report is written to a temporary file next to
+// it and renamed over it, so a reader never sees half a report.
+
+// How often the watched directory is checked for new files.
+const WATCH_DIR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the report of the accounts of a watched directory is written.
+#[derive(Debug, Clone)]
+pub(crate) struct ReportOptions {
+    pub(crate) path: PathBuf,
+    pub(crate) schema: OutputSchema,
+    /// Whether the escrow column is added to the v1 schema.
+    pub(crate) extended: bool,
+    /// Whether the pending column is added, with a settlement delay.
+    pub(crate) pending: bool,
+    /// Whether the reserve column is added to the extended report, when clients have a rolling reserve.
+    pub(crate) reserve: bool,
+    pub(crate) filter: AccountFilter,
+}
+
+/// The report of the accounts of a watched directory.
+#[derive(Clone)]
+pub(crate) struct AccountReport {
+    options: ReportOptions,
+    workers: ShardedEngine,
+}
+
+impl AccountReport {
+    pub(crate) fn new(options: ReportOptions, workers: ShardedEngine) -> Self {
+        Self { options, workers }
+    }
+
+    // Rewrite the report once the transactions queued on the source so far were applied.
+    async fn update(&self, source: &SourceHandle) -> io::Result<()> {
+        source.settled().await;
+        // The queries are queued behind the transactions that were handed over to the workers.
+        let accounts = self.workers.query_accounts(None).await;
+        let options = self.options.clone();
+        tokio::task::spawn_blocking(move || write_report(&options, &accounts))
+            .await
+            .expect("Writing the report doesn't panic.")
+    }
+}
+
+fn write_report(options: &ReportOptions, accounts: &[AccountSnapshot]) -> io::Result<()> {
+    let columns = options.schema.columns(&OutputColumns {
+        account: accounts.iter().any(|account| !account.account.is_main()),
+        escrow: options.extended,
+        pending: options.pending,
+        reserve: options.extended && options.reserve,
+    });
+    let mut name = options.path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temporary = options.path.with_file_name(name);
+    let mut file = fs::File::create(&temporary)?;
+    provenance::write_comment(&mut file, "#")?;
+    let mut writer = AccountWriter::new(file, columns);
+    let mut written = 0;
+    for account in accounts
+        .iter()
+        .filter(|account| options.filter.matches_snapshot(account))
+    {
+        writer.write(account).map_err(io::Error::other)?;
+        written += 1;
+    }
+    writer.finish()?.sync_all()?;
+    fs::rename(&temporary, &options.path)?;
+    log_event(
+        "report_written",
+        &[("path", &options.path.display()), ("accounts", &written)],
+    );
+    Ok(())
+}
+
+/// Ingest the CSV files that appear in a directory until `shutdown` is set. Files are moved to the `ingested`
+/// subdirectory once read, or to the `failed` subdirectory if they could not be read. The report, if there's one, is
+/// written when the watch starts and after every batch of files.
+pub(crate) async fn watch(
+    dir: PathBuf,
+    concurrency: usize,
+    reader: ReaderOptions,
+    source: SourceHandle,
+    mut shutdown: watch::Receiver<bool>,
+    report: Option<AccountReport>,
+) {
+    update_report(report.as_ref(), &source).await;
+    loop {
+        match pending_files(&dir) {
+            Ok(files) => {
+                let files = scan_clients(files, concurrency, reader).await;
+                for batch in independent_batches(files, concurrency) {
+                    if batch.len() > 1 {
+                        log_event("files_batched", &[("files", &batch.len())]);
+                    }
+                    let ingests = batch.into_iter().map(|path| {
+                        tokio::spawn(ingest_watched(path, dir.clone(), reader, source.clone()))
+                    });
+                    for ingested in join_all(ingests).await {
+                        if let Err(err) = ingested {
+                            eprintln!("Ingesting a watched file encountered an error: {}", err);
+                        }
+                    }
+                    update_report(report.as_ref(), &source).await;
+                }
+            }
+            Err(err) => eprintln!(
+                "Could not read the watched directory {}: {}",
+                dir.display(),
+                err
+            ),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(WATCH_DIR_INTERVAL) => {}
+            _ = shutdown.wait_for(|stopped| *stopped) => break,
+        }
+    }
+}
+
+async fn update_report(report: Option<&AccountReport>, source: &SourceHandle) {
+    if let Some(report) = report
+        && let Err(err) = report.update(source).await
+    {
+        eprintln!(
+            "Could not write the report {}: {}",
+            report.options.path.display(),
+            err
+        );
+    }
+}
+
+// Ingest a file of the watched directory and move it out of the way.
+async fn ingest_watched(path: PathBuf, dir: PathBuf, reader: ReaderOptions, source: SourceHandle) {
+    let ingested = ingest::ingest_file(&path, reader, &source, &mut Profiler::disabled())
+        .await
+        .map_err(|err| err.to_string());
+    let target = match &ingested {
+        Ok(_) => "ingested",
+        Err(err) => {
+            eprintln!("Could not ingest {}: {}", path.display(), err);
+            "failed"
+        }
+    };
+    if let Err(err) = move_to(&path, &dir.join(target)) {
+        eprintln!("Could not move {}: {}", path.display(), err);
+    }
+}
+
+// The clients of each file, when several files are waiting and they can be ingested concurrently. A file that
+// cannot be opened has no client set and is ingested on its own, which reports the error.
+async fn scan_clients(
+    files: Vec<PathBuf>,
+    concurrency: usize,
+    reader: ReaderOptions,
+) -> Vec<(PathBuf, Option<HashSet<ClientId>>)> {
+    if concurrency < 2 || files.len() < 2 {
+        return files.into_iter().map(|path| (path, None)).collect();
+    }
+    let scans = files.into_iter().map(|path| {
+        tokio::task::spawn_blocking(move || {
+            let clients = reader.open(&path).ok().map(CsvFileReader::scan_clients);
+            (path, clients)
+        })
+    });
+    join_all(scans)
+        .await
+        .into_iter()
+        .map(|scan| scan.expect("Scanning a file doesn't panic."))
+        .collect()
+}
+
+// Split the files, in order, into batches of up to `max` files that have no client in common. The files of a batch can
+// be ingested concurrently without changing the order of the transactions of any client, while the batches are
+// ingested one after the other. Files without a client set are ingested on their own.
+fn independent_batches(
+    files: Vec<(PathBuf, Option<HashSet<ClientId>>)>,
+    max: usize,
+) -> Vec<Vec<PathBuf>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut clients = HashSet::new();
+    for (path, file_clients) in files {
+        let fits = batch.len() < max
+            && file_clients
+                .as_ref()
+                .is_some_and(|file_clients| file_clients.is_disjoint(&clients));
+        if !fits && !batch.is_empty() {
+            batches.push(std::mem::take(&mut batch));
+            clients.clear();
+        }
+        batch.push(path);
+        match file_clients {
+            Some(file_clients) => clients.extend(file_clients),
+            None => batches.push(std::mem::take(&mut batch)),
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+// The CSV files of a directory, compressed or not, in the order of their names.
+fn pending_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_csv = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                [".csv", ".csv.gz", ".csv.zst"]
+                    .iter()
+                    .any(|extension| name.ends_with(extension))
+            });
+        if path.is_file() && is_csv {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn move_to(path: &Path, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let name = path.file_name().expect("Listed files have a name.");
+    fs::rename(path, dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_types::{AccountName, Amount};
+
+    #[test]
+    fn should_replace_the_report_with_the_filtered_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ReportOptions {
+            path: dir.path().join("accounts.csv"),
+            schema: OutputSchema::V1,
+            extended: false,
+            pending: false,
+            reserve: false,
+            filter: AccountFilter {
+                omit_empty: true,
+                ..AccountFilter::default()
+            },
+        };
+        let account = |client: u16, total: f64| AccountSnapshot {
+            client: client.into(),
+            account: AccountName::default(),
+            available: total.into(),
+            held: Amount::zero(),
+            escrow: Amount::zero(),
+            pending: Amount::zero(),
+            reserve: Amount::zero(),
+            total: total.into(),
+            locked: false,
+        };
+        fs::write(&options.path, "stale").unwrap();
+
+        write_report(&options, &[account(1, 5.0), account(2, 0.0)]).unwrap();
+
+        let report = fs::read_to_string(&options.path).unwrap();
+        let records: Vec<_> = report
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            records,
+            vec!["client,available,held,total,locked", "1,5,0,5,false"]
+        );
+        // The temporary file was renamed over the report.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn should_batch_files_without_common_clients() {
+        let file = |name: &str, clients: Option<&[u16]>| {
+            let clients = clients.map(|clients| clients.iter().map(|&c| c.into()).collect());
+            (PathBuf::from(name), clients)
+        };
+        let files = vec![
+            file("1.csv", Some(&[1, 2])),
+            file("2.csv", Some(&[3])),
+            // Shares client 2 with the first file, so it waits for it.
+            file("3.csv", Some(&[2, 4])),
+            file("4.csv", None),
+            file("5.csv", Some(&[5])),
+            file("6.csv", Some(&[6])),
+            file("7.csv", Some(&[7])),
+        ];
+
+        let batches: Vec<Vec<_>> = independent_batches(files, 2)
+            .into_iter()
+            .map(|batch| {
+                batch
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                vec!["1.csv", "2.csv"],
+                vec!["3.csv"],
+                vec!["4.csv"],
+                vec!["5.csv", "6.csv"],
+                vec!["7.csv"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use std::{io, net::SocketAddr};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::{
+    csv_reader::LineParser,
+    ingest::{Ingress, SourceHandle},
+    json,
+    logging::log_event,
+    transaction_types::Transaction,
+};
+
+// Input from WebSocket connections, for the clients that stream transactions from a browser or a replay tool rather
+// than over a raw socket. Every text message holds transactions, one per line, as CSV records (the first line of a
+// connection can be a header) or as JSON objects with the fields of the CSV input. A line that can't be parsed is
+// answered with a text message `{"error": ...}`.
+//
+// Every connection is a source of its own, so the transactions of a connection reach the workers in the order they
+// were sent. A connection is only read once the transactions of its previous message were queued, so when the workers
+// fall behind and the queue of the source is full, the socket isn't read anymore and TCP pushes back on the client.
+//
+// Only the server side of RFC 6455 that a client needs is implemented: the opening handshake, fragmented messages,
+// pings and the closing handshake, without extensions. There is no WebSocket crate in the dependencies, and the
+// handshake needs SHA-1, which the other hashes of the engine (SHA-256) don't provide, so it's implemented here.
+
+// The largest message accepted, once its fragments are put together.
+const MAX_MESSAGE: usize = 1 << 20;
+// The largest opening handshake accepted.
+const MAX_HANDSHAKE: usize = 8192;
+// Appended to the key of the client to prove that the server understood the handshake.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Frame opcodes.
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Close codes.
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Error)]
+enum WsError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Invalid opening handshake: {0}.")]
+    Handshake(&'static str),
+    #[error("Protocol error: {0}.")]
+    Protocol(&'static str),
+    #[error("The message is not valid UTF-8.")]
+    InvalidText,
+    #[error("The message is larger than {} bytes.", MAX_MESSAGE)]
+    TooLarge,
+}
+
+impl WsError {
+    // The close code sent to the client before closing the connection because of the error.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WsError::Io(_) | WsError::Handshake(_) => None,
+            WsError::Protocol(_) => Some(PROTOCOL_ERROR),
+            WsError::InvalidText => Some(INVALID_DATA),
+            WsError::TooLarge => Some(MESSAGE_TOO_BIG),
+        }
+    }
+}
+
+/// Accept WebSocket connections on an address and queue the transactions they send until `stop` completes. The
+/// connections that are still open are closed once their transactions were handed over to the workers.
+pub(crate) async fn serve(
+    address: SocketAddr,
+    ingress: &Ingress,
+    lenient_amounts: bool,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    log_event("ws_listening", &[("address", &listener.local_addr()?)]);
+    accept(listener, ingress, lenient_amounts, stop).await
+}
+
+async fn accept(
+    listener: TcpListener,
+    ingress: &Ingress,
+    lenient_amounts: bool,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut stop = std::pin::pin!(stop);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let connection = Connection {
+                    source: ingress.source("websocket"),
+                    parser: LineParser::new(lenient_amounts),
+                    messages: 0,
+                };
+                connections.spawn(connection.serve(stream, peer, shutdown.clone()));
+            }
+            // Reap the connections that were closed by their peer.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            () = &mut stop => break,
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+// The state of a connection.
+struct Connection {
+    source: SourceHandle,
+    parser: LineParser,
+    messages: u64,
+}
+
+impl Connection {
+    async fn serve(mut self, stream: TcpStream, peer: SocketAddr, shutdown: watch::Receiver<bool>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let served = match handshake(&mut reader).await {
+            Ok(accept) => {
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    accept
+                );
+                match writer.write_all(response.as_bytes()).await {
+                    Ok(()) => self.read_messages(&mut reader, &mut writer, shutdown).await,
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Err(err) => {
+                let _ = writer
+                    .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+                Err(err)
+            }
+        };
+        if let Err(err) = &served {
+            if let Some(code) = err.close_code() {
+                let _ = writer.write_all(&close_frame(code)).await;
+            }
+            eprintln!(
+                "WebSocket connection from {} encountered an error: {}",
+                peer, err
+            );
+        }
+
+        let stats = self.source.finish().await;
+        log_event(
+            "ws_connection_closed",
+            &[
+                ("peer", &peer),
+                ("messages", &self.messages),
+                ("transactions", &stats.received),
+                ("parse_errors", &stats.parse_errors),
+            ],
+        );
+    }
+
+    // Read the messages of the connection until the client or the engine closes it.
+    async fn read_messages(
+        &mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        writer: &mut (impl AsyncWriteExt + Unpin),
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), WsError> {
+        // The fragments of the message being received, with the opcode of its first frame.
+        let mut message: Option<Vec<u8>> = None;
+        loop {
+            // A frame that was partly read when the engine stops is dropped with the connection.
+            let frame = tokio::select! {
+                frame = read_frame(reader) => Some(frame?),
+                _ = shutdown.wait_for(|stopped| *stopped) => None,
+            };
+            let Some(frame) = frame else {
+                writer.write_all(&close_frame(GOING_AWAY)).await?;
+                return Ok(());
+            };
+            match frame.opcode {
+                TEXT | BINARY if message.is_some() => {
+                    return Err(WsError::Protocol(
+                        "new message before the end of the previous one",
+                    ));
+                }
+                TEXT | BINARY => message = Some(frame.payload),
+                CONTINUATION => match &mut message {
+                    Some(fragments) if fragments.len() + frame.payload.len() <= MAX_MESSAGE => {
+                        fragments.extend_from_slice(&frame.payload)
+                    }
+                    Some(_) => return Err(WsError::TooLarge),
+                    None => return Err(WsError::Protocol("continuation without a message")),
+                },
+                PING => {
+                    writer
+                        .write_all(&encode_frame(PONG, &frame.payload))
+                        .await?;
+                    continue;
+                }
+                PONG => continue,
+                CLOSE => {
+                    writer.write_all(&close_frame(NORMAL_CLOSURE)).await?;
+                    return Ok(());
+                }
+                _ => return Err(WsError::Protocol("unknown opcode")),
+            }
+            if frame.fin
+                && let Some(payload) = message.take()
+            {
+                let text = String::from_utf8(payload).map_err(|_| WsError::InvalidText)?;
+                self.messages += 1;
+                for reply in self.handle(&text).await {
+                    writer.write_all(&encode_frame(TEXT, &reply)).await?;
+                }
+            }
+        }
+    }
+
+    // Queue the transactions of a message and return the replies to its lines that can't be parsed.
+    async fn handle(&mut self, text: &str) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let parsed = if line.starts_with('{') {
+                json::from_slice::<Transaction>(line.as_bytes())
+                    .map(Some)
+                    .map_err(|err| err.to_string())
+            } else {
+                self.parser
+                    .parse(line)
+                    .transpose()
+                    .map_err(|err| err.to_string())
+            };
+            match parsed {
+                // The workers are only gone when the engine is stopping.
+                Ok(Some(transaction)) => {
+                    let _ = self.source.send(transaction).await;
+                }
+                // The header of the CSV records.
+                Ok(None) => {}
+                Err(err) => {
+                    self.source.parse_error();
+                    let error = serde_json::json!({ "error": err });
+                    replies.push(json::to_vec(&error).expect("Errors can be serialized."));
+                }
+            }
+        }
+        replies
+    }
+}
+
+// Read the opening handshake of a client and return the value of its `Sec-WebSocket-Accept` header.
+async fn handshake(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Result<String, WsError> {
+    let mut request = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(WsError::Handshake("the connection was closed"));
+        }
+        if request.len() + line.len() > MAX_HANDSHAKE {
+            return Err(WsError::Handshake("the request is too large"));
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        request.push_str(&line);
+    }
+
+    let mut lines = request.lines();
+    if !lines
+        .next()
+        .is_some_and(|request_line| request_line.starts_with("GET "))
+    {
+        return Err(WsError::Handshake("not a GET request"));
+    }
+    let (mut upgrade, mut connection, mut version, mut key) = (false, false, false, None);
+    for header in lines {
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+        let has_token = |token: &str| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        };
+        match name.as_str() {
+            "upgrade" => upgrade = has_token("websocket"),
+            "connection" => connection = has_token("upgrade"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if !upgrade || !connection {
+        return Err(WsError::Handshake("not a WebSocket upgrade"));
+    }
+    if !version {
+        return Err(WsError::Handshake("only version 13 is supported"));
+    }
+    let key = key
+        .filter(|key| BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16))
+        .ok_or(WsError::Handshake("missing or invalid key"))?;
+    Ok(accept_key(&key))
+}
+
+// The value of `Sec-WebSocket-Accept` for the key of a client.
+fn accept_key(key: &str) -> String {
+    BASE64.encode(sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Read a frame sent by a client, unmasking its payload.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Frame, WsError> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return Err(WsError::Protocol("reserved bits are set"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(WsError::Protocol("the frames of a client must be masked"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    if opcode >= CLOSE && (len > 125 || !fin) {
+        return Err(WsError::Protocol("invalid control frame"));
+    }
+    if len > MAX_MESSAGE as u64 {
+        return Err(WsError::TooLarge);
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// A frame sent by the server, which is not masked.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn close_frame(code: u16) -> Vec<u8> {
+    encode_frame(CLOSE, &code.to_be_bytes())
+}
+
+// SHA-1 (FIPS 180-4), only used for the opening handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::{
+        NUM_WORKERS,
+        engine::ShardedEngine,
+        enrichment::Enrichers,
+        transaction_processor::{ProcessorMessage, ProcessorOptions, TransactionProcessor},
+    };
+
+    use super::*;
+
+    // A frame sent by a client, masked with a fixed mask.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = encode_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        let start = frame.len() - payload.len();
+        let masked: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4])
+            .collect();
+        frame.truncate(start);
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&masked);
+        frame
+    }
+
+    #[test]
+    fn should_compute_the_accept_key_of_a_handshake() {
+        // The example of RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let hex = |digest: [u8; 20]| -> String {
+            digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+        };
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_queue_the_transactions_of_the_messages() {
+        let mut senders = Vec::new();
+        let mut processors = Vec::new();
+        for _ in 0..NUM_WORKERS {
+            let (tx, rx) = mpsc::channel(1024);
+            senders.push(tx);
+            processors.push(tokio::spawn(
+                TransactionProcessor::new(ProcessorOptions::default()).run(rx),
+            ));
+        }
+        let workers = ShardedEngine::new(senders);
+        let (ingress, dispatcher) = Ingress::start(workers.clone(), None, Enrichers::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop_tx, stop) = oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let ingress = ingress.clone();
+            async move {
+                let stop = async {
+                    let _ = stop.await;
+                };
+                accept(listener, &ingress, false, stop).await
+            }
+        });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        writer
+            .write_all(b"GET /transactions HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            response.push_str(&line);
+        }
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let mut frames = client_frame(true, TEXT, b"type,client,tx,amount\ndeposit,1,1,2.5");
+        // A message in two fragments with a ping in between.
+        frames.extend(client_frame(
+            false,
+            TEXT,
+            b"{\"type\": \"withdrawal\", \"client\": 1,",
+        ));
+        frames.extend(client_frame(true, PING, b"hello"));
+        frames.extend(client_frame(
+            true,
+            CONTINUATION,
+            b" \"tx\": 2, \"amount\": 1}\nbogus",
+        ));
+        frames.extend(client_frame(true, CLOSE, &NORMAL_CLOSURE.to_be_bytes()));
+        writer.write_all(&frames).await.unwrap();
+
+        let pong = read_server_frame(&mut reader).await;
+        assert_eq!(
+            (pong.opcode, pong.payload.as_slice()),
+            (PONG, &b"hello"[..])
+        );
+        let error = read_server_frame(&mut reader).await;
+        assert_eq!(error.opcode, TEXT);
+        let error: serde_json::Value = serde_json::from_slice(&error.payload).unwrap();
+        assert!(error.get("error").is_some());
+        let close = read_server_frame(&mut reader).await;
+        assert_eq!(close.opcode, CLOSE);
+
+        stop_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(ingress.parse_errors(), 1);
+        drop(ingress);
+        dispatcher.await.unwrap();
+        let accounts = workers.query_accounts(Some(1.into())).await;
+        let accounts = serde_json::to_value(accounts).unwrap();
+        assert_eq!(accounts[0]["available"], "1.5");
+        workers.broadcast(ProcessorMessage::shutdown).await.unwrap();
+        for processor in processors {
+            processor.await.unwrap();
+        }
+    }
+
+    // Read a frame sent by the server, which is not masked.
+    async fn read_server_frame(reader: &mut (impl AsyncRead + Unpin)) -> Frame {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).await.unwrap();
+        let len = match head[1] & 0x7F {
+            126 => usize::from(reader.read_u16().await.unwrap()),
+            len => usize::from(len),
+        };
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await.unwrap();
+        Frame {
+            fin: head[0] & 0x80 != 0,
+            opcode: head[0] & 0x0F,
+            payload,
+        }
+    }
+}
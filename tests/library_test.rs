@@ -0,0 +1,42 @@
+use payments_engine::{
+    engine::ShardedEngine,
+    transaction::{DisputeSource, TransactionBuilder, TransactionType},
+};
+use rust_decimal::Decimal;
+
+// The engine driven through the library only, the way a program that embeds it does.
+#[tokio::test]
+async fn built_transactions_should_be_applied_by_the_engine() {
+    let engine = ShardedEngine::start(2);
+
+    let transactions = [
+        TransactionBuilder::new(TransactionType::Deposit, 1, 1)
+            .with_amount(Decimal::new(25, 1))
+            .build(),
+        TransactionBuilder::new(TransactionType::Deposit, 2, 2)
+            .with_amount(Decimal::new(4, 0))
+            .build(),
+        TransactionBuilder::new(TransactionType::Withdrawal, 1, 3)
+            .with_amount(Decimal::new(5, 1))
+            .build(),
+        TransactionBuilder::new(TransactionType::Dispute, 2, 2)
+            .with_source(DisputeSource::Issuer)
+            .build(),
+    ];
+    for transaction in transactions {
+        engine.submit(transaction.unwrap()).await.unwrap();
+    }
+
+    let client_1 = engine.query(1).await.unwrap();
+    assert_eq!(client_1.len(), 1);
+    assert_eq!(client_1[0].account, "main");
+    assert_eq!(client_1[0].available, Decimal::new(2, 0));
+    assert_eq!(client_1[0].total, Decimal::new(2, 0));
+
+    let client_2 = engine.query(2).await.unwrap();
+    assert_eq!(client_2[0].available, Decimal::ZERO);
+    assert_eq!(client_2[0].held, Decimal::new(4, 0));
+
+    engine.shutdown().await.unwrap();
+    assert!(engine.query(1).await.is_err());
+}
@@ -27,6 +27,7 @@ static GLOBAL: CountingAllocator = CountingAllocator;
 
 #[ignore = "used for debugging"]
 #[test]
+#[allow(clippy::unnecessary_cast)]
 fn test_cache_memory_usage() {
     let mut cache = TransactionCache::<SqliteKvStore, u32, u32, 65536>::new().unwrap();
 